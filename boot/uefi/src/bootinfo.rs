@@ -0,0 +1,57 @@
+//! BootInfo v2 as the kernel reads it (kernel/src/bootinfo.rs); the layout
+//! and flag values must stay in step with that file and with stage2.
+
+pub const MAGIC: u64 = 0x4F46_4E49_544F_4F42;
+pub const VERSION: u32 = 2;
+
+pub const FLAG_INITRD: u64 = 1 << 0;
+pub const FLAG_CMDLINE: u64 = 1 << 1;
+pub const FLAG_RSDP: u64 = 1 << 2;
+pub const FLAG_FRAMEBUFFER: u64 = 1 << 3;
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct BootInfo {
+    pub magic: u64,
+    pub version: u32,
+    pub size: u32,
+    pub flags: u64,
+    pub memory_map: u64,
+    pub memory_map_entries: u64,
+    pub memory_map_entry_size: u64,
+    pub initrd_base: u64,
+    pub initrd_len: u64,
+    pub cmdline: u64,
+    pub cmdline_len: u64,
+    pub rsdp: u64,
+    pub fb_addr: u64,
+    pub fb_width: u32,
+    pub fb_height: u32,
+    pub fb_pitch: u32,
+    pub fb_bpp: u32,
+}
+
+impl BootInfo {
+    pub fn new() -> Self {
+        BootInfo { magic: MAGIC, version: VERSION, size: core::mem::size_of::<Self>() as u32, ..Default::default() }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+
+    #[test]
+    fn layout_matches_the_kernel() {
+        assert_eq!(core::mem::size_of::<BootInfo>(), 112);
+        assert_eq!(offset_of!(BootInfo, flags), 16);
+        assert_eq!(offset_of!(BootInfo, memory_map), 24);
+        assert_eq!(offset_of!(BootInfo, initrd_base), 48);
+        assert_eq!(offset_of!(BootInfo, cmdline), 64);
+        assert_eq!(offset_of!(BootInfo, rsdp), 80);
+        assert_eq!(offset_of!(BootInfo, fb_addr), 88);
+        assert_eq!(offset_of!(BootInfo, fb_bpp), 108);
+        assert_eq!(&BootInfo::new().magic.to_le_bytes(), b"BOOTINFO");
+    }
+}
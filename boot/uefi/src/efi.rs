@@ -0,0 +1,197 @@
+//! The few UEFI tables and protocols the shim calls (UEFI 2.10), declared by
+//! hand. Function slots the shim never calls are kept as `usize` so the
+//! layouts still line up.
+
+use core::ffi::c_void;
+
+pub type Handle = *mut c_void;
+pub type Status = usize;
+
+pub const SUCCESS: Status = 0;
+const ERROR_BIT: Status = 1 << (usize::BITS - 1);
+pub const INVALID_PARAMETER: Status = ERROR_BIT | 2;
+pub const BUFFER_TOO_SMALL: Status = ERROR_BIT | 5;
+pub const LOAD_ERROR: Status = ERROR_BIT | 1;
+pub const NOT_FOUND: Status = ERROR_BIT | 14;
+
+// EFI_MEMORY_TYPE
+pub const LOADER_DATA: u32 = 2;
+pub const BOOT_SERVICES_CODE: u32 = 3;
+pub const BOOT_SERVICES_DATA: u32 = 4;
+pub const CONVENTIONAL_MEMORY: u32 = 7;
+pub const UNUSABLE_MEMORY: u32 = 8;
+pub const ACPI_RECLAIM_MEMORY: u32 = 9;
+pub const ACPI_MEMORY_NVS: u32 = 10;
+
+// EFI_ALLOCATE_TYPE
+pub const ALLOCATE_MAX_ADDRESS: u32 = 1;
+pub const ALLOCATE_ADDRESS: u32 = 2;
+
+pub const FILE_MODE_READ: u64 = 1;
+
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub u32, pub u16, pub u16, pub [u8; 8]);
+
+pub const LOADED_IMAGE_GUID: Guid = Guid(0x5B1B_31A1, 0x9562, 0x11D2, [0x8E, 0x3F, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B]);
+pub const SIMPLE_FILE_SYSTEM_GUID: Guid =
+    Guid(0x964E_5B22, 0x6459, 0x11D2, [0x8E, 0x39, 0x00, 0xA0, 0xC9, 0x69, 0x72, 0x3B]);
+pub const GRAPHICS_OUTPUT_GUID: Guid = Guid(0x9042_A9DE, 0x23DC, 0x4A38, [0x96, 0xFB, 0x7A, 0xDE, 0xD0, 0x80, 0x51, 0x6A]);
+pub const ACPI_20_TABLE_GUID: Guid = Guid(0x8868_E871, 0xE4F1, 0x11D3, [0xBC, 0x22, 0x00, 0x80, 0xC7, 0x3C, 0x88, 0x81]);
+pub const ACPI_10_TABLE_GUID: Guid = Guid(0xEB9D_2D30, 0x2D88, 0x11D3, [0x9A, 0x16, 0x00, 0x90, 0x27, 0x3F, 0xC1, 0x4D]);
+
+#[repr(C)]
+pub struct TableHeader {
+    pub signature: u64,
+    pub revision: u32,
+    pub header_size: u32,
+    pub crc32: u32,
+    pub reserved: u32,
+}
+
+#[repr(C)]
+pub struct SystemTable {
+    pub hdr: TableHeader,
+    pub firmware_vendor: *const u16,
+    pub firmware_revision: u32,
+    pub console_in_handle: Handle,
+    pub con_in: *mut c_void,
+    pub console_out_handle: Handle,
+    pub con_out: *mut SimpleTextOutput,
+    pub standard_error_handle: Handle,
+    pub std_err: *mut SimpleTextOutput,
+    pub runtime_services: *mut c_void,
+    pub boot_services: *mut BootServices,
+    pub number_of_table_entries: usize,
+    pub configuration_table: *const ConfigurationTable,
+}
+
+#[repr(C)]
+pub struct ConfigurationTable {
+    pub vendor_guid: Guid,
+    pub vendor_table: *const c_void,
+}
+
+#[repr(C)]
+pub struct SimpleTextOutput {
+    pub reset: usize,
+    pub output_string: unsafe extern "efiapi" fn(*mut SimpleTextOutput, *const u16) -> Status,
+}
+
+#[repr(C)]
+pub struct BootServices {
+    pub hdr: TableHeader,
+    raise_tpl: usize,
+    restore_tpl: usize,
+    pub allocate_pages: unsafe extern "efiapi" fn(u32, u32, usize, *mut u64) -> Status,
+    pub free_pages: unsafe extern "efiapi" fn(u64, usize) -> Status,
+    pub get_memory_map: unsafe extern "efiapi" fn(*mut usize, *mut u8, *mut usize, *mut usize, *mut u32) -> Status,
+    pub allocate_pool: unsafe extern "efiapi" fn(u32, usize, *mut *mut u8) -> Status,
+    pub free_pool: unsafe extern "efiapi" fn(*mut u8) -> Status,
+    create_event: usize,
+    set_timer: usize,
+    wait_for_event: usize,
+    signal_event: usize,
+    close_event: usize,
+    check_event: usize,
+    install_protocol_interface: usize,
+    reinstall_protocol_interface: usize,
+    uninstall_protocol_interface: usize,
+    pub handle_protocol: unsafe extern "efiapi" fn(Handle, *const Guid, *mut *mut c_void) -> Status,
+    reserved: usize,
+    register_protocol_notify: usize,
+    locate_handle: usize,
+    locate_device_path: usize,
+    install_configuration_table: usize,
+    load_image: usize,
+    start_image: usize,
+    exit: usize,
+    unload_image: usize,
+    pub exit_boot_services: unsafe extern "efiapi" fn(Handle, usize) -> Status,
+    get_next_monotonic_count: usize,
+    stall: usize,
+    pub set_watchdog_timer: unsafe extern "efiapi" fn(usize, u64, usize, *const u16) -> Status,
+    connect_controller: usize,
+    disconnect_controller: usize,
+    open_protocol: usize,
+    close_protocol: usize,
+    open_protocol_information: usize,
+    protocols_per_handle: usize,
+    locate_handle_buffer: usize,
+    pub locate_protocol: unsafe extern "efiapi" fn(*const Guid, *mut c_void, *mut *mut c_void) -> Status,
+}
+
+#[repr(C)]
+pub struct MemoryDescriptor {
+    pub ty: u32,
+    pub physical_start: u64,
+    pub virtual_start: u64,
+    pub number_of_pages: u64,
+    pub attribute: u64,
+}
+
+#[repr(C)]
+pub struct LoadedImage {
+    pub revision: u32,
+    pub parent_handle: Handle,
+    pub system_table: *mut SystemTable,
+    pub device_handle: Handle,
+    pub file_path: *mut c_void,
+    reserved: *mut c_void,
+    pub load_options_size: u32,
+    pub load_options: *const u16,
+    pub image_base: *mut c_void,
+    pub image_size: u64,
+    pub image_code_type: u32,
+    pub image_data_type: u32,
+    unload: usize,
+}
+
+#[repr(C)]
+pub struct SimpleFileSystem {
+    pub revision: u64,
+    pub open_volume: unsafe extern "efiapi" fn(*mut SimpleFileSystem, *mut *mut File) -> Status,
+}
+
+#[repr(C)]
+pub struct File {
+    pub revision: u64,
+    pub open: unsafe extern "efiapi" fn(*mut File, *mut *mut File, *const u16, u64, u64) -> Status,
+    pub close: unsafe extern "efiapi" fn(*mut File) -> Status,
+    delete: usize,
+    pub read: unsafe extern "efiapi" fn(*mut File, *mut usize, *mut u8) -> Status,
+    write: usize,
+    pub get_position: unsafe extern "efiapi" fn(*mut File, *mut u64) -> Status,
+    pub set_position: unsafe extern "efiapi" fn(*mut File, u64) -> Status,
+}
+
+#[repr(C)]
+pub struct GraphicsOutput {
+    query_mode: usize,
+    set_mode: usize,
+    blt: usize,
+    pub mode: *const GraphicsMode,
+}
+
+#[repr(C)]
+pub struct GraphicsMode {
+    pub max_mode: u32,
+    pub mode: u32,
+    pub info: *const GraphicsModeInfo,
+    pub size_of_info: usize,
+    pub frame_buffer_base: u64,
+    pub frame_buffer_size: usize,
+}
+
+/// `PixelBltOnly`: no linear framebuffer.
+pub const PIXEL_BLT_ONLY: u32 = 3;
+
+#[repr(C)]
+pub struct GraphicsModeInfo {
+    pub version: u32,
+    pub horizontal_resolution: u32,
+    pub vertical_resolution: u32,
+    pub pixel_format: u32,
+    pub pixel_information: [u32; 4],
+    pub pixels_per_scan_line: u32,
+}
@@ -0,0 +1,126 @@
+//! Just enough ELF64 to place the kernel: the entry point and the PT_LOAD
+//! segments, each copied to its physical address like stage2 does.
+
+const PT_LOAD: u32 = 1;
+const EHDR_LEN: usize = 64;
+const PHDR_LEN: usize = 56;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Segment {
+    /// Offset of the file bytes in the image.
+    pub offset: u64,
+    pub paddr: u64,
+    pub filesz: u64,
+    /// `memsz - filesz` bytes after the file bytes are zeroed (.bss).
+    pub memsz: u64,
+}
+
+pub struct Elf<'a> {
+    bytes: &'a [u8],
+    pub entry: u64,
+    phoff: usize,
+    phentsize: usize,
+    phnum: usize,
+}
+
+fn u16_at(b: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([b[at], b[at + 1]])
+}
+
+fn u32_at(b: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([b[at], b[at + 1], b[at + 2], b[at + 3]])
+}
+
+fn u64_at(b: &[u8], at: usize) -> u64 {
+    u32_at(b, at) as u64 | (u32_at(b, at + 4) as u64) << 32
+}
+
+/// Check the header, and that every loadable segment lies inside the file.
+pub fn parse(bytes: &[u8]) -> Result<Elf<'_>, &'static str> {
+    if bytes.len() < EHDR_LEN || &bytes[..4] != b"\x7FELF" {
+        return Err("not an ELF file");
+    }
+    // ELFCLASS64, little-endian, EM_X86_64
+    if bytes[4] != 2 || bytes[5] != 1 || u16_at(bytes, 18) != 0x3E {
+        return Err("not an x86_64 ELF64 image");
+    }
+    let elf = Elf {
+        bytes,
+        entry: u64_at(bytes, 24),
+        phoff: u64_at(bytes, 32) as usize,
+        phentsize: u16_at(bytes, 54) as usize,
+        phnum: u16_at(bytes, 56) as usize,
+    };
+    let table_end = elf.phnum.checked_mul(elf.phentsize).and_then(|n| n.checked_add(elf.phoff));
+    if elf.phentsize < PHDR_LEN || table_end.is_none_or(|end| end > bytes.len()) {
+        return Err("program headers out of bounds");
+    }
+    for seg in elf.load_segments() {
+        let end = seg.offset.checked_add(seg.filesz);
+        if seg.filesz > seg.memsz || end.is_none_or(|end| end > bytes.len() as u64) {
+            return Err("segment out of bounds");
+        }
+    }
+    Ok(elf)
+}
+
+impl<'a> Elf<'a> {
+    pub fn load_segments(&self) -> impl Iterator<Item = Segment> + '_ {
+        (0..self.phnum)
+            .map(move |i| &self.bytes[self.phoff + i * self.phentsize..])
+            .filter(|ph| u32_at(ph, 0) == PT_LOAD)
+            .map(|ph| Segment { offset: u64_at(ph, 8), paddr: u64_at(ph, 24), filesz: u64_at(ph, 32), memsz: u64_at(ph, 40) })
+    }
+
+    /// File bytes of `seg`; `parse` checked the bounds.
+    pub fn data(&self, seg: &Segment) -> &'a [u8] {
+        &self.bytes[seg.offset as usize..(seg.offset + seg.filesz) as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image(ph_type: u32, filesz: u64) -> [u8; 0x100] {
+        let mut b = [0u8; 0x100];
+        b[..4].copy_from_slice(b"\x7FELF");
+        b[4] = 2;
+        b[5] = 1;
+        b[18] = 0x3E;
+        b[24..32].copy_from_slice(&0x10_0000u64.to_le_bytes());
+        b[32..40].copy_from_slice(&(EHDR_LEN as u64).to_le_bytes());
+        b[54] = PHDR_LEN as u8;
+        b[56] = 1;
+        let ph = &mut b[EHDR_LEN..EHDR_LEN + PHDR_LEN];
+        ph[0..4].copy_from_slice(&ph_type.to_le_bytes());
+        ph[8..16].copy_from_slice(&0xC0u64.to_le_bytes());
+        ph[24..32].copy_from_slice(&0x10_0000u64.to_le_bytes());
+        ph[32..40].copy_from_slice(&filesz.to_le_bytes());
+        ph[40..48].copy_from_slice(&0x1000u64.to_le_bytes());
+        b
+    }
+
+    #[test]
+    fn finds_entry_and_load_segments() {
+        let b = image(PT_LOAD, 0x40);
+        let elf = parse(&b).unwrap();
+        assert_eq!(elf.entry, 0x10_0000);
+        let mut segs = elf.load_segments();
+        let seg = segs.next().unwrap();
+        assert_eq!(seg, Segment { offset: 0xC0, paddr: 0x10_0000, filesz: 0x40, memsz: 0x1000 });
+        assert_eq!(elf.data(&seg).len(), 0x40);
+        assert!(segs.next().is_none());
+        // PT_NOTE and friends are skipped
+        assert_eq!(parse(&image(4, 0x40)).unwrap().load_segments().count(), 0);
+    }
+
+    #[test]
+    fn rejects_truncated_images() {
+        assert!(parse(&image(PT_LOAD, 0x41)).is_err());
+        assert!(parse(&image(PT_LOAD, 0x40)[..0x70]).is_err());
+        let mut b = image(PT_LOAD, 0x40);
+        b[4] = 1;
+        assert!(parse(&b).is_err());
+    }
+}
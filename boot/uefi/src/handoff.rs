@@ -0,0 +1,98 @@
+//! The jump into the kernel, after boot services have exited: the same
+//! machine state stage2 leaves behind, i.e. an identity map of the first
+//! 4 GiB with 2 MiB pages, a flat 64-bit GDT, interrupts off, and the
+//! BootInfo pointer in `rdi`.
+
+use core::arch::asm;
+
+/// PML4, PDPT and one page directory per GiB.
+pub const PAGE_TABLE_PAGES: usize = 6;
+const PRESENT_WRITABLE: u64 = 0x3;
+const HUGE: u64 = 0x80;
+
+/// Null, 64-bit code (0x08), data (0x10).
+static GDT: [u64; 3] = [0, 0x00AF_9A00_0000_FFFF, 0x00CF_9200_0000_FFFF];
+
+#[repr(C, packed)]
+struct GdtPointer {
+    limit: u16,
+    base: u64,
+}
+
+/// Fill `tables` (`PAGE_TABLE_PAGES` page-aligned pages) with the identity
+/// map; returns the PML4 address.
+///
+/// # Safety
+/// `tables` must be writable memory the caller owns.
+pub unsafe fn build_identity_map(tables: *mut [u64; 512]) -> u64 {
+    let page = |i: usize| tables.add(i);
+    for i in 0..PAGE_TABLE_PAGES {
+        *page(i) = [0; 512];
+    }
+    (*page(0))[0] = page(1) as u64 | PRESENT_WRITABLE;
+    for gib in 0..4 {
+        let pd = page(2 + gib);
+        (*page(1))[gib] = pd as u64 | PRESENT_WRITABLE;
+        for (j, entry) in (*pd).iter_mut().enumerate() {
+            *entry = (((gib * 512 + j) as u64) << 21) | PRESENT_WRITABLE | HUGE;
+        }
+    }
+    page(0) as u64
+}
+
+/// Switch to the shim's GDT, page tables and stack, then jump to `entry`.
+///
+/// # Safety
+/// Boot services must have exited; the shim image, `stack_top` and the
+/// tables must be below 4 GiB so they stay mapped across the CR3 switch.
+pub unsafe fn jump(entry: u64, boot_info: u64, pml4: u64, stack_top: u64) -> ! {
+    let gdtr = GdtPointer { limit: (core::mem::size_of_val(&GDT) - 1) as u16, base: GDT.as_ptr() as u64 };
+    asm!(
+        "cli",
+        "lgdt [rsi]",
+        "mov cr3, rdx",
+        "mov ax, 0x10",
+        "mov ds, ax",
+        "mov es, ax",
+        "mov fs, ax",
+        "mov gs, ax",
+        "mov ss, ax",
+        "mov rsp, rcx",
+        // Reload CS with a far return
+        "push 0x08",
+        "lea rax, [rip + 2f]",
+        "push rax",
+        "retfq",
+        "2:",
+        "jmp r8",
+        in("rsi") &gdtr,
+        in("rdx") pml4,
+        in("rcx") stack_top,
+        in("r8") entry,
+        in("rdi") boot_info,
+        options(noreturn),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(4096))]
+    struct Tables([[u64; 512]; PAGE_TABLE_PAGES]);
+
+    #[test]
+    fn identity_map_covers_four_gib() {
+        let mut t = std::boxed::Box::new(Tables([[0xFF; 512]; PAGE_TABLE_PAGES]));
+        let base = t.0.as_mut_ptr();
+        let pml4 = unsafe { build_identity_map(base) };
+        let addr = |i: usize| base as u64 + (i * 4096) as u64;
+        assert_eq!(pml4, addr(0));
+        assert_eq!(t.0[0][0], addr(1) | 3);
+        assert_eq!(t.0[0][1], 0);
+        assert_eq!(t.0[1][3], addr(5) | 3);
+        assert_eq!(t.0[2][1], 0x20_0000 | 0x83);
+        // Last 2 MiB page below 4 GiB
+        assert_eq!(t.0[5][511], 0xFFE0_0000 | 0x83);
+    }
+}
@@ -0,0 +1,319 @@
+//! UEFI boot shim for the mon-os kernel.
+//!
+//! Runs as a UEFI application from the ESP (`\EFI\BOOT\BOOTX64.EFI`). It
+//! loads `\kernel.elf` and, when present, `\initrd.img` from the same
+//! volume, collects what stage2 provides on BIOS machines (memory map, RSDP)
+//! plus the GOP framebuffer and the image's load options as the kernel
+//! command line, exits boot services and enters the kernel with a BootInfo v2.
+
+#![no_std]
+#![cfg_attr(not(test), no_main)]
+// Host tests cover the pure parts; everything reached from efi_main is dead there
+#![cfg_attr(test, allow(dead_code))]
+
+#[cfg(test)]
+extern crate std;
+
+mod bootinfo;
+mod efi;
+mod elf;
+mod handoff;
+mod memmap;
+mod ucs2;
+
+use core::convert::Infallible;
+use core::ffi::c_void;
+use core::fmt::{self, Write};
+use core::ptr;
+use core::sync::atomic::{AtomicPtr, Ordering};
+
+use bootinfo::BootInfo;
+use efi::{BootServices, File, Guid, Handle, LoadedImage, Status, SystemTable};
+use memmap::E820Entry;
+
+const KERNEL_PATH: &str = "\\kernel.elf";
+const INITRD_PATH: &str = "\\initrd.img";
+/// Everything the kernel keeps must sit inside its 4 GiB identity map.
+const BELOW_4G: u64 = 0xFFFF_FFFF;
+const PAGE_SIZE: u64 = 4096;
+const STACK_PAGES: usize = 16;
+const MAX_MAP_ENTRIES: usize = 256;
+const MAX_CMDLINE: usize = 512;
+/// Room for the descriptors the map buffer allocation itself adds.
+const MAP_SLACK: usize = 8;
+
+/// For the panic handler; cleared once boot services are gone.
+static SYSTEM_TABLE: AtomicPtr<SystemTable> = AtomicPtr::new(ptr::null_mut());
+
+struct Error {
+    what: &'static str,
+    status: Status,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} (status {:#x})", self.what, self.status)
+    }
+}
+
+fn check(status: Status, what: &'static str) -> Result<(), Error> {
+    match status {
+        efi::SUCCESS => Ok(()),
+        status => Err(Error { what, status }),
+    }
+}
+
+struct ConOut(*mut SystemTable);
+
+impl Write for ConOut {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let out = unsafe { (*self.0).con_out };
+        let mut buf = [0u16; 128];
+        // Chunks of 60 chars stay within the buffer even with \n doubled
+        let mut rest = s;
+        while !rest.is_empty() {
+            let cut = rest.char_indices().nth(60).map_or(rest.len(), |(i, _)| i);
+            let (chunk, tail) = rest.split_at(cut);
+            ucs2::encode(chunk, &mut buf).ok_or(fmt::Error)?;
+            unsafe { ((*out).output_string)(out, buf.as_ptr()) };
+            rest = tail;
+        }
+        Ok(())
+    }
+}
+
+struct Uefi {
+    image: Handle,
+    st: *mut SystemTable,
+    bs: *mut BootServices,
+}
+
+impl Uefi {
+    fn print(&self, args: fmt::Arguments) {
+        let _ = ConOut(self.st).write_fmt(args);
+    }
+
+    fn protocol<T>(&self, handle: Handle, guid: &Guid, what: &'static str) -> Result<*mut T, Error> {
+        let mut iface: *mut c_void = ptr::null_mut();
+        check(unsafe { ((*self.bs).handle_protocol)(handle, guid, &mut iface) }, what)?;
+        Ok(iface as *mut T)
+    }
+
+    /// Zero-filled loader pages below 4 GiB.
+    fn alloc_pages(&self, pages: usize, what: &'static str) -> Result<u64, Error> {
+        let mut addr = BELOW_4G;
+        check(unsafe { ((*self.bs).allocate_pages)(efi::ALLOCATE_MAX_ADDRESS, efi::LOADER_DATA, pages, &mut addr) }, what)?;
+        unsafe { ptr::write_bytes(addr as *mut u8, 0, pages * PAGE_SIZE as usize) };
+        Ok(addr)
+    }
+
+    /// Whole file at `path` in fresh pages, `None` if there is no such file.
+    fn read_file(&self, root: *mut File, path: &str) -> Result<Option<&'static mut [u8]>, Error> {
+        let mut name = [0u16; 64];
+        ucs2::encode(path, &mut name).ok_or(Error { what: "path too long", status: efi::INVALID_PARAMETER })?;
+        let mut file = ptr::null_mut();
+        match unsafe { ((*root).open)(root, &mut file, name.as_ptr(), efi::FILE_MODE_READ, 0) } {
+            efi::NOT_FOUND => return Ok(None),
+            status => check(status, "open file")?,
+        }
+        let res = self.read_open(file);
+        unsafe { ((*file).close)(file) };
+        res.map(Some)
+    }
+
+    fn read_open(&self, file: *mut File) -> Result<&'static mut [u8], Error> {
+        let mut size = 0u64;
+        unsafe {
+            // Seeking to u64::MAX moves to the end of the file
+            check(((*file).set_position)(file, u64::MAX), "seek")?;
+            check(((*file).get_position)(file, &mut size), "file size")?;
+            check(((*file).set_position)(file, 0), "seek")?;
+        }
+        let base = self.alloc_pages(size.div_ceil(PAGE_SIZE).max(1) as usize, "no memory for a file")?;
+        let buf = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, size as usize) };
+        let mut done = 0;
+        while done < buf.len() {
+            let mut chunk = buf.len() - done;
+            check(unsafe { ((*file).read)(file, &mut chunk, buf[done..].as_mut_ptr()) }, "read file")?;
+            if chunk == 0 {
+                return Err(Error { what: "file shorter than its size", status: efi::LOAD_ERROR });
+            }
+            done += chunk;
+        }
+        Ok(buf)
+    }
+
+    fn free(&self, bytes: &[u8]) {
+        let pages = (bytes.len() as u64).div_ceil(PAGE_SIZE).max(1) as usize;
+        unsafe { ((*self.bs).free_pages)(bytes.as_ptr() as u64, pages) };
+    }
+
+    /// Copy the PT_LOAD segments to their physical addresses; returns the entry point.
+    fn load_kernel(&self, image: &[u8]) -> Result<u64, Error> {
+        let elf = elf::parse(image).map_err(|what| Error { what, status: efi::LOAD_ERROR })?;
+        for seg in elf.load_segments() {
+            let mut start = seg.paddr & !(PAGE_SIZE - 1);
+            let pages = (seg.paddr + seg.memsz - start).div_ceil(PAGE_SIZE) as usize;
+            let status = unsafe { ((*self.bs).allocate_pages)(efi::ALLOCATE_ADDRESS, efi::LOADER_DATA, pages, &mut start) };
+            check(status, "kernel segment address in use")?;
+            let data = elf.data(&seg);
+            unsafe {
+                ptr::copy_nonoverlapping(data.as_ptr(), seg.paddr as *mut u8, data.len());
+                ptr::write_bytes((seg.paddr + seg.filesz) as *mut u8, 0, (seg.memsz - seg.filesz) as usize);
+            }
+            self.print(format_args!("uefi: segment {:#x} {:#x} bytes\n", seg.paddr, seg.memsz));
+        }
+        Ok(elf.entry)
+    }
+
+    fn cmdline(&self, loaded: *mut LoadedImage) -> Result<Option<&'static [u8]>, Error> {
+        let (options, size) = unsafe { ((*loaded).load_options, (*loaded).load_options_size as usize) };
+        if options.is_null() || size < 2 {
+            return Ok(None);
+        }
+        let src = unsafe { core::slice::from_raw_parts(options, size / 2) };
+        let base = self.alloc_pages(1, "no memory for the command line")?;
+        let out = unsafe { core::slice::from_raw_parts_mut(base as *mut u8, MAX_CMDLINE) };
+        let len = ucs2::to_ascii(src, out);
+        Ok((len > 0).then(|| &out[..len]))
+    }
+
+    fn config_table(&self, guid: &Guid) -> Option<u64> {
+        let (table, count) = unsafe { ((*self.st).configuration_table, (*self.st).number_of_table_entries) };
+        let entries = unsafe { core::slice::from_raw_parts(table, count) };
+        entries.iter().find(|e| e.vendor_guid == *guid).map(|e| e.vendor_table as u64)
+    }
+
+    fn framebuffer(&self) -> Option<(u64, &'static efi::GraphicsModeInfo)> {
+        let mut gop: *mut c_void = ptr::null_mut();
+        let status = unsafe { ((*self.bs).locate_protocol)(&efi::GRAPHICS_OUTPUT_GUID, ptr::null_mut(), &mut gop) };
+        if status != efi::SUCCESS || gop.is_null() {
+            return None;
+        }
+        let mode = unsafe { &*(*(gop as *mut efi::GraphicsOutput)).mode };
+        let info = unsafe { &*mode.info };
+        (info.pixel_format != efi::PIXEL_BLT_ONLY).then_some((mode.frame_buffer_base, info))
+    }
+}
+
+#[cfg(not(test))]
+#[no_mangle]
+extern "efiapi" fn efi_main(image: Handle, st: *mut SystemTable) -> Status {
+    SYSTEM_TABLE.store(st, Ordering::Relaxed);
+    let uefi = Uefi { image, st, bs: unsafe { (*st).boot_services } };
+    uefi.print(format_args!("mon-os uefi shim\n"));
+    match boot(&uefi) {
+        Ok(never) => match never {},
+        Err(e) => {
+            uefi.print(format_args!("uefi: {}\n", e));
+            e.status
+        }
+    }
+}
+
+fn boot(uefi: &Uefi) -> Result<Infallible, Error> {
+    // The firmware resets the machine after 5 minutes in an application otherwise
+    unsafe { ((*uefi.bs).set_watchdog_timer)(0, 0, 0, ptr::null()) };
+    let loaded: *mut LoadedImage = uefi.protocol(uefi.image, &efi::LOADED_IMAGE_GUID, "no loaded image protocol")?;
+    let image_end = unsafe { (*loaded).image_base as u64 + (*loaded).image_size };
+    if image_end > BELOW_4G {
+        return Err(Error { what: "shim loaded above 4 GiB", status: efi::LOAD_ERROR });
+    }
+    let device = unsafe { (*loaded).device_handle };
+    let fs: *mut efi::SimpleFileSystem = uefi.protocol(device, &efi::SIMPLE_FILE_SYSTEM_GUID, "no file system")?;
+    let mut root = ptr::null_mut();
+    check(unsafe { ((*fs).open_volume)(fs, &mut root) }, "open volume")?;
+
+    let kernel = uefi
+        .read_file(root, KERNEL_PATH)?
+        .ok_or(Error { what: "no \\kernel.elf on the boot volume", status: efi::NOT_FOUND })?;
+    let entry = uefi.load_kernel(kernel)?;
+    uefi.free(kernel);
+
+    let mut info = BootInfo::new();
+    if let Some(initrd) = uefi.read_file(root, INITRD_PATH)? {
+        info.flags |= bootinfo::FLAG_INITRD;
+        info.initrd_base = initrd.as_ptr() as u64;
+        info.initrd_len = initrd.len() as u64;
+        uefi.print(format_args!("uefi: initrd {:#x} {} bytes\n", info.initrd_base, info.initrd_len));
+    }
+    if let Some(cmdline) = uefi.cmdline(loaded)? {
+        info.flags |= bootinfo::FLAG_CMDLINE;
+        info.cmdline = cmdline.as_ptr() as u64;
+        info.cmdline_len = cmdline.len() as u64;
+    }
+    if let Some(rsdp) = uefi.config_table(&efi::ACPI_20_TABLE_GUID).or_else(|| uefi.config_table(&efi::ACPI_10_TABLE_GUID)) {
+        info.flags |= bootinfo::FLAG_RSDP;
+        info.rsdp = rsdp;
+    }
+    if let Some((addr, mode)) = uefi.framebuffer() {
+        info.flags |= bootinfo::FLAG_FRAMEBUFFER;
+        info.fb_addr = addr;
+        info.fb_width = mode.horizontal_resolution;
+        info.fb_height = mode.vertical_resolution;
+        info.fb_pitch = mode.pixels_per_scan_line * 4;
+        info.fb_bpp = 32;
+        uefi.print(format_args!("uefi: framebuffer {}x{} at {:#x}\n", info.fb_width, info.fb_height, addr));
+    }
+
+    // What the kernel keeps, allocated before the final memory map is taken
+    let tables = uefi.alloc_pages(handoff::PAGE_TABLE_PAGES, "no memory for page tables")?;
+    let stack = uefi.alloc_pages(STACK_PAGES, "no memory for the stack")?;
+    let info_len = core::mem::size_of::<BootInfo>() + MAX_MAP_ENTRIES * core::mem::size_of::<E820Entry>();
+    let info_page = uefi.alloc_pages(info_len.div_ceil(PAGE_SIZE as usize), "no memory for the boot info")?;
+    let e820_at = info_page + core::mem::size_of::<BootInfo>() as u64;
+
+    let (map, desc_size) = exit_boot_services(uefi)?;
+    SYSTEM_TABLE.store(ptr::null_mut(), Ordering::Relaxed);
+
+    let e820 = unsafe { core::slice::from_raw_parts_mut(e820_at as *mut E820Entry, MAX_MAP_ENTRIES) };
+    let descriptors = map.chunks_exact(desc_size).map(|d| {
+        let d = unsafe { &*(d.as_ptr() as *const efi::MemoryDescriptor) };
+        (d.ty, d.physical_start, d.number_of_pages)
+    });
+    info.memory_map = e820_at;
+    info.memory_map_entries = memmap::convert(descriptors, e820) as u64;
+    info.memory_map_entry_size = core::mem::size_of::<E820Entry>() as u64;
+    unsafe {
+        (info_page as *mut BootInfo).write(info);
+        let pml4 = handoff::build_identity_map(tables as *mut [u64; 512]);
+        handoff::jump(entry, info_page, pml4, stack + (STACK_PAGES as u64) * PAGE_SIZE)
+    }
+}
+
+/// Take the final memory map and leave boot services; returns the raw
+/// descriptors and their stride. A map key gone stale between the two calls
+/// gets one more try, with no allocation in between.
+fn exit_boot_services(uefi: &Uefi) -> Result<(&'static [u8], usize), Error> {
+    let bs = uefi.bs;
+    let (mut size, mut key, mut desc_size, mut version) = (0usize, 0usize, 0usize, 0u32);
+    let status = unsafe { ((*bs).get_memory_map)(&mut size, ptr::null_mut(), &mut key, &mut desc_size, &mut version) };
+    if status != efi::BUFFER_TOO_SMALL {
+        check(status, "memory map size")?;
+    }
+    let capacity = size + MAP_SLACK * desc_size;
+    let mut buf: *mut u8 = ptr::null_mut();
+    check(unsafe { ((*bs).allocate_pool)(efi::LOADER_DATA, capacity, &mut buf) }, "no memory for the memory map")?;
+    for attempt in 0..2 {
+        size = capacity;
+        check(unsafe { ((*bs).get_memory_map)(&mut size, buf, &mut key, &mut desc_size, &mut version) }, "memory map")?;
+        match unsafe { ((*bs).exit_boot_services)(uefi.image, key) } {
+            efi::SUCCESS => return Ok((unsafe { core::slice::from_raw_parts(buf, size) }, desc_size)),
+            efi::INVALID_PARAMETER if attempt == 0 => continue,
+            status => check(status, "exit boot services")?,
+        }
+    }
+    Err(Error { what: "exit boot services", status: efi::INVALID_PARAMETER })
+}
+
+#[cfg(not(test))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    let st = SYSTEM_TABLE.load(Ordering::Relaxed);
+    if !st.is_null() {
+        let _ = writeln!(ConOut(st), "uefi: panic: {}", info);
+    }
+    loop {
+        unsafe { core::arch::asm!("cli; hlt") };
+    }
+}
@@ -0,0 +1,99 @@
+//! The firmware memory map as the E820-style entries BootInfo carries.
+//!
+//! Once boot services have exited, their code and data are free memory like
+//! `EfiConventionalMemory`. Loader memory holds the kernel, the initrd, the
+//! BootInfo and the page tables the kernel keeps running on, so it is handed
+//! over as reserved; runtime services, MMIO and anything unknown are too.
+
+use crate::efi;
+
+pub const E820_USABLE: u32 = 1;
+pub const E820_RESERVED: u32 = 2;
+pub const E820_ACPI_RECLAIMABLE: u32 = 3;
+pub const E820_ACPI_NVS: u32 = 4;
+pub const E820_BAD: u32 = 5;
+const PAGE_SIZE: u64 = 4096;
+
+/// Same layout as the kernel's `bootinfo::MemoryMapEntry`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct E820Entry {
+    pub base: u64,
+    pub length: u64,
+    pub region_type: u32,
+    pub attributes: u32,
+}
+
+pub fn e820_type(efi_type: u32) -> u32 {
+    match efi_type {
+        efi::BOOT_SERVICES_CODE | efi::BOOT_SERVICES_DATA | efi::CONVENTIONAL_MEMORY => E820_USABLE,
+        efi::ACPI_RECLAIM_MEMORY => E820_ACPI_RECLAIMABLE,
+        efi::ACPI_MEMORY_NVS => E820_ACPI_NVS,
+        efi::UNUSABLE_MEMORY => E820_BAD,
+        _ => E820_RESERVED,
+    }
+}
+
+/// Convert `(efi type, start, pages)` descriptors into `out`, merging
+/// neighbours that touch and end up with the same type, so the kernel sees
+/// one usable region where the firmware listed many. Returns the entries
+/// written; descriptors past the end of `out` are dropped.
+pub fn convert(descriptors: impl Iterator<Item = (u32, u64, u64)>, out: &mut [E820Entry]) -> usize {
+    let mut n = 0;
+    for (ty, base, pages) in descriptors {
+        let region_type = e820_type(ty);
+        let length = pages * PAGE_SIZE;
+        if n > 0 {
+            let prev = &mut out[n - 1];
+            if prev.region_type == region_type && prev.base + prev.length == base {
+                prev.length += length;
+                continue;
+            }
+        }
+        if n == out.len() {
+            break;
+        }
+        out[n] = E820Entry { base, length, region_type, attributes: 1 };
+        n += 1;
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boot_services_memory_merges_into_usable() {
+        let map = [
+            (efi::CONVENTIONAL_MEMORY, 0x0, 0xA0),
+            (efi::BOOT_SERVICES_DATA, 0x10_0000, 0x100),
+            (efi::CONVENTIONAL_MEMORY, 0x20_0000, 0x100),
+            (efi::LOADER_DATA, 0x30_0000, 0x10),
+            (efi::ACPI_RECLAIM_MEMORY, 0x31_0000, 0x4),
+            (efi::BOOT_SERVICES_CODE, 0x31_4000, 0x4),
+        ];
+        let mut out = [E820Entry::default(); 8];
+        let n = convert(map.into_iter(), &mut out);
+        let got: [(u64, u64, u32); 5] = core::array::from_fn(|i| (out[i].base, out[i].length, out[i].region_type));
+        assert_eq!(n, 5);
+        assert_eq!(
+            got,
+            [
+                (0x0, 0xA_0000, E820_USABLE),
+                (0x10_0000, 0x20_0000, E820_USABLE),
+                (0x30_0000, 0x1_0000, E820_RESERVED),
+                (0x31_0000, 0x4000, E820_ACPI_RECLAIMABLE),
+                (0x31_4000, 0x4000, E820_USABLE),
+            ]
+        );
+    }
+
+    #[test]
+    fn full_output_drops_the_rest() {
+        let map = [(efi::CONVENTIONAL_MEMORY, 0x0, 1), (efi::LOADER_DATA, 0x1000, 1), (efi::CONVENTIONAL_MEMORY, 0x2000, 1)];
+        let mut out = [E820Entry::default(); 2];
+        assert_eq!(convert(map.into_iter(), &mut out), 2);
+        assert_eq!(out[1].region_type, E820_RESERVED);
+    }
+}
@@ -0,0 +1,52 @@
+//! UCS-2 strings, as UEFI takes and returns them.
+
+/// `s` as a NUL-terminated UCS-2 string in `out`, `\n` expanded to `\r\n`
+/// for the console. Returns the units written before the NUL, or `None` if
+/// `out` is too small.
+pub fn encode(s: &str, out: &mut [u16]) -> Option<usize> {
+    let mut n = 0;
+    for c in s.chars() {
+        if c == '\n' {
+            *out.get_mut(n)? = b'\r' as u16;
+            n += 1;
+        }
+        // Outside the BMP there is no UCS-2 form
+        *out.get_mut(n)? = if (c as u32) < 0x1_0000 { c as u16 } else { b'?' as u16 };
+        n += 1;
+    }
+    *out.get_mut(n)? = 0;
+    Some(n)
+}
+
+/// ASCII copy of `src` up to its first NUL, other characters as `?`.
+/// Returns the bytes written; the rest of a too-long `src` is dropped.
+pub fn to_ascii(src: &[u16], out: &mut [u8]) -> usize {
+    let mut n = 0;
+    for (&unit, slot) in src.iter().take_while(|&&u| u != 0).zip(out.iter_mut()) {
+        *slot = if unit < 0x80 { unit as u8 } else { b'?' };
+        n += 1;
+    }
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_with_nul_and_crlf() {
+        let mut out = [0xFFFFu16; 8];
+        assert_eq!(encode("a\nb", &mut out), Some(4));
+        assert_eq!(out[..5], [b'a' as u16, b'\r' as u16, b'\n' as u16, b'b' as u16, 0]);
+        assert_eq!(encode("\\kernel.elf", &mut out), None);
+    }
+
+    #[test]
+    fn load_options_become_ascii() {
+        let src: [u16; 8] = [b'p' as u16, b'=' as u16, b'1' as u16, 0xE9, b' ' as u16, 0, b'x' as u16, 0];
+        let mut out = [0u8; 16];
+        let n = to_ascii(&src, &mut out);
+        assert_eq!(&out[..n], b"p=1? ");
+        assert_eq!(to_ascii(&src, &mut out[..2]), 2);
+    }
+}
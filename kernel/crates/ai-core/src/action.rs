@@ -0,0 +1,122 @@
+#![allow(dead_code)]
+#![allow(non_camel_case_types)]
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Action {
+    pub kind: u8,
+    pub flags: u8,
+    /// Spells out what would be padding before `param1`, so every byte of
+    /// an `Action` is a field and copying one to user space leaks nothing.
+    pub _r: [u8; 6],
+    pub param1: u64,
+    pub param2: u64,
+    pub param3: u64,
+}
+
+pub mod actf {
+    pub const REQUIRES_SNAPSHOT: u8 = 1 << 0;
+    pub const HIGH_RISK: u8 = 1 << 1;
+    pub const NEEDS_MANUAL_CONFIRM: u8 = 1 << 2;
+    /// Proposed by the heuristic controller rather than the model.
+    pub const HEURISTIC: u8 = 1 << 3;
+}
+
+/// What a `SetPollingInterval` action retimes.
+pub mod poll_target {
+    /// How often the agent runs inference (0 = every idle pass).
+    pub const AI_INFERENCE: u64 = 0;
+    /// HID interrupt-transfer pacing (0 = repost immediately).
+    pub const HID: u64 = 1;
+}
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug)]
+pub enum ActionType {
+    None = 0,
+    SetQuantum = 1,
+    SetAffinity = 2,
+    MigrateTask = 3,
+    TrimCache = 4,
+    /// param1 = `klog::Level` (0 = error .. 3 = debug).
+    SetLogLevel = 5,
+    /// param1 = `poll_target`, param2 = interval in ms.
+    SetPollingInterval = 6,
+    /// param1 = task name (`pack_name`), param2 = duration in ms.
+    BoostTask = 7,
+    /// param1 = percent of each second the agent task may use.
+    SetCpuBudget = 8,
+    Reboot = 254,
+    Halt = 255,
+}
+
+/// Short name of an `ActionType` code, for logs and the dashboard.
+pub fn name(kind: u8) -> &'static str {
+    match kind {
+        0 => "none",
+        1 => "set_quantum",
+        2 => "set_affinity",
+        3 => "migrate_task",
+        4 => "trim_cache",
+        5 => "set_log_level",
+        6 => "set_polling",
+        7 => "boost_task",
+        8 => "set_cpu_budget",
+        254 => "reboot",
+        255 => "halt",
+        _ => "unknown",
+    }
+}
+
+/// Longest task name an action parameter carries.
+pub const NAME_LEN: usize = 8;
+
+/// `name` as an action parameter: its ASCII bytes little-endian, NUL
+/// padded. `None` if it is empty, too long or not ASCII.
+pub fn pack_name(name: &str) -> Option<u64> {
+    if name.is_empty() || name.len() > NAME_LEN || !name.is_ascii() || name.contains('\0') {
+        return None;
+    }
+    let mut bytes = [0u8; NAME_LEN];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// Inverse of `pack_name`, into `buf`.
+pub fn unpack_name(param: u64, buf: &mut [u8; NAME_LEN]) -> Option<&str> {
+    *buf = param.to_le_bytes();
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(NAME_LEN);
+    // Nothing but padding may follow the name
+    if len == 0 || buf[len..].iter().any(|&b| b != 0) {
+        return None;
+    }
+    core::str::from_utf8(&buf[..len]).ok().filter(|n| n.is_ascii())
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ActionOutcome {
+    pub result: u8, // 0=ACCEPTED,1=REJECTED,2=ROLLED_BACK,3=ERROR
+    _rsv: [u8; 3],
+    pub selftest_code: u32,
+    pub snapshot_id: u64,
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn task_names_round_trip() {
+        let mut buf = [0u8; NAME_LEN];
+        let shell = pack_name("shell").unwrap();
+        assert_eq!(unpack_name(shell, &mut buf), Some("shell"));
+        assert_eq!(unpack_name(pack_name("12345678").unwrap(), &mut buf), Some("12345678"));
+        assert_eq!(pack_name("123456789"), None);
+        assert_eq!(pack_name(""), None);
+        assert_eq!(unpack_name(0, &mut buf), None);
+        // A hole in the middle is not a name
+        assert_eq!(unpack_name(u64::from_le_bytes(*b"ab\0cd\0\0\0"), &mut buf), None);
+    }
+}
@@ -0,0 +1,217 @@
+//! Records of the action journal, and the check that pairs every INTENT
+//! with its outcome. The ring, its checksums and persistence across a
+//! reboot are the kernel's.
+
+use crate::action::actf;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RecordKind {
+    Intent,
+    ApplyOk,
+    ApplyFail,
+    Reject,
+    /// A decision taken on replayed telemetry; nothing was applied. `seq` is
+    /// the sample index and `code` the action's first parameter.
+    DryRun,
+    /// An inference step ran over its cycle budget and was abandoned. `seq`
+    /// is the violation count and `code` the cycles spent, in µs.
+    ModelTooSlow,
+    /// A proposal made with `ai.mode = observe`; nothing was applied. `seq`
+    /// is the agent's step count and `code` the action's first parameter.
+    Observed,
+    /// The agent loaded a model that names itself (`model::ModelInfo`);
+    /// the decisions after it are that model's. `seq` is its version as
+    /// `major << 32 | minor << 16 | patch` and `code` the first four bytes
+    /// of its training hash.
+    ModelLoaded,
+    /// The guard disabled the agent. `seq` is the trip count, `code` the
+    /// reason (`ai_guard::Trip::code`) and `action` the kind of the action
+    /// blamed, if any.
+    GuardTrip,
+    /// The records before it are from an earlier boot, whose seqs were
+    /// counted from 0 like this one's: nothing pairs across it.
+    Boot,
+}
+
+impl RecordKind {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => RecordKind::Intent,
+            1 => RecordKind::ApplyOk,
+            2 => RecordKind::ApplyFail,
+            3 => RecordKind::Reject,
+            4 => RecordKind::DryRun,
+            5 => RecordKind::ModelTooSlow,
+            6 => RecordKind::Observed,
+            7 => RecordKind::ModelLoaded,
+            8 => RecordKind::GuardTrip,
+            9 => RecordKind::Boot,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            RecordKind::Intent => "INTENT",
+            RecordKind::ApplyOk => "APPLY_OK",
+            RecordKind::ApplyFail => "APPLY_FAIL",
+            RecordKind::Reject => "REJECT",
+            RecordKind::DryRun => "DRYRUN",
+            RecordKind::ModelTooSlow => "MODEL_TOO_SLOW",
+            RecordKind::Observed => "OBSERVED",
+            RecordKind::ModelLoaded => "MODEL_LOADED",
+            RecordKind::GuardTrip => "GUARD_TRIP",
+            RecordKind::Boot => "BOOT",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Record {
+    /// TSC cycles since boot when the record was written.
+    pub tsc: u64,
+    pub seq: u64,
+    pub kind: RecordKind,
+    pub action: u8,
+    /// `actf` flags of the action, which say which controller proposed it.
+    pub flags: u8,
+    pub code: u32,
+}
+
+impl Record {
+    pub const LEN: usize = 23;
+
+    pub fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[..8].copy_from_slice(&self.tsc.to_le_bytes());
+        out[8..16].copy_from_slice(&self.seq.to_le_bytes());
+        out[16..19].copy_from_slice(&[self.kind as u8, self.action, self.flags]);
+        out[19..].copy_from_slice(&self.code.to_le_bytes());
+        out
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap_or_default());
+        (bytes.len() == Self::LEN).then_some(())?;
+        Some(Record {
+            tsc: u64_at(0),
+            seq: u64_at(8),
+            kind: RecordKind::from_u8(bytes[16])?,
+            action: bytes[17],
+            flags: bytes[18],
+            code: u32::from_le_bytes(bytes[19..23].try_into().unwrap_or_default()),
+        })
+    }
+
+    /// Controller that proposed the action.
+    pub fn source(&self) -> &'static str {
+        if self.flags & actf::HEURISTIC != 0 { "heuristic" } else { "model" }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct VerifyReport {
+    pub intents: u32,
+    pub committed: u32,
+    pub failed: u32,
+    pub dangling: u32,
+    pub first_dangling: Option<u64>,
+    /// Records no longer matching the CRC taken when they were written.
+    pub corrupt: u32,
+}
+
+/// Pairs every INTENT with the APPLY_OK/APPLY_FAIL carrying the same seq,
+/// up to the next BOOT record.
+pub fn pair_records(records: &[Record]) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    for (i, rec) in records.iter().enumerate() {
+        match rec.kind {
+            RecordKind::Intent => {
+                report.intents += 1;
+                let closed = records[i + 1..].iter().take_while(|r| r.kind != RecordKind::Boot).any(|r| {
+                    r.seq == rec.seq && matches!(r.kind, RecordKind::ApplyOk | RecordKind::ApplyFail)
+                });
+                if !closed {
+                    report.dangling += 1;
+                    if report.first_dangling.is_none() {
+                        report.first_dangling = Some(rec.seq);
+                    }
+                }
+            }
+            RecordKind::ApplyOk => report.committed += 1,
+            RecordKind::ApplyFail => report.failed += 1,
+            RecordKind::Reject | RecordKind::DryRun | RecordKind::ModelTooSlow | RecordKind::Observed | RecordKind::ModelLoaded | RecordKind::GuardTrip | RecordKind::Boot => {}
+        }
+    }
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(seq: u64, kind: RecordKind) -> Record {
+        Record { tsc: 0, seq, kind, action: 1, flags: 0, code: 0 }
+    }
+
+    #[test]
+    fn records_round_trip() {
+        let rec = Record { tsc: 12_345, seq: 7, kind: RecordKind::ApplyOk, action: 4, flags: 8, code: 64 };
+        let back = Record::decode(&rec.encode()).unwrap();
+        assert_eq!((back.tsc, back.seq, back.kind, back.action, back.flags, back.code), (12_345, 7, RecordKind::ApplyOk, 4, 8, 64));
+        let mut bad = rec.encode();
+        bad[16] = RecordKind::Observed as u8;
+        assert_eq!(Record::decode(&bad).map(|r| r.kind), Some(RecordKind::Observed));
+        bad[16] = 99;
+        assert!(Record::decode(&bad).is_none());
+        assert!(Record::decode(&bad[..8]).is_none());
+    }
+
+    #[test]
+    fn paired_intents_are_clean() {
+        let records = [
+            rec(0, RecordKind::Intent),
+            rec(0, RecordKind::ApplyOk),
+            rec(1, RecordKind::Reject),
+            rec(2, RecordKind::Intent),
+            rec(2, RecordKind::ApplyFail),
+        ];
+        let r = pair_records(&records);
+        assert_eq!(r.intents, 2);
+        assert_eq!(r.committed, 1);
+        assert_eq!(r.failed, 1);
+        assert_eq!(r.dangling, 0);
+        assert_eq!(r.first_dangling, None);
+    }
+
+    #[test]
+    fn intent_without_outcome_is_dangling() {
+        let records = [
+            rec(3, RecordKind::Intent),
+            rec(4, RecordKind::Intent),
+            rec(4, RecordKind::ApplyOk),
+        ];
+        let r = pair_records(&records);
+        assert_eq!(r.dangling, 1);
+        assert_eq!(r.first_dangling, Some(3));
+    }
+
+    #[test]
+    fn outcomes_of_the_next_boot_do_not_close_an_intent() {
+        let records = [
+            rec(1, RecordKind::Intent),
+            rec(0, RecordKind::Boot),
+            rec(1, RecordKind::Intent),
+            rec(1, RecordKind::ApplyOk),
+        ];
+        let r = pair_records(&records);
+        assert_eq!(r.dangling, 1);
+        assert_eq!(r.first_dangling, Some(1));
+    }
+
+    #[test]
+    fn outcome_before_intent_does_not_close_it() {
+        let records = [rec(5, RecordKind::ApplyOk), rec(5, RecordKind::Intent)];
+        assert_eq!(pair_records(&records).dangling, 1);
+    }
+}
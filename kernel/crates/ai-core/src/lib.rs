@@ -0,0 +1,16 @@
+//! The agent's model formats (int8 MLP, decision forest) and the int8 kernels the inference runs on,
+//! the actions it proposes, the transactional gate they go through and the
+//! journal records it leaves. Nothing here touches hardware or kernel
+//! state, so it builds and tests on the host (`sim` drives the gate through
+//! scripted faults); the kernel's `ai_agent` and `apply_action` use it.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod action;
+pub mod journal;
+pub mod matmul;
+pub mod model;
+#[cfg(test)]
+mod sim;
+pub mod tree;
+pub mod txn;
@@ -0,0 +1,46 @@
+/// `out = a * b` for an `m`x`k` by `k`x`n` int8 product, row-major, with
+/// i32 accumulators.
+///
+/// # Safety
+/// `a`, `b` and `out` must be valid for `m * k`, `k * n` and `m * n`
+/// elements.
+pub unsafe fn matmul_int8(
+    a: *const i8,
+    b: *const i8,
+    out: *mut i32,
+    m: usize,
+    n: usize,
+    k: usize,
+) {
+    for i in 0..m {
+        let a_row = a.add(i * k);
+        for j in 0..n {
+            let mut acc: i32 = 0;
+            for p in 0..k {
+                let ai = *a_row.add(p) as i32;
+                let bj = *b.add(p * n + j) as i32;
+                acc += ai * bj;
+            }
+            *out.add(i * n + j) = acc;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn products_accumulate_in_i32() {
+        // [1 2 3; 4 5 6] * [7 8; 9 10; 11 12]
+        let a: [i8; 6] = [1, 2, 3, 4, 5, 6];
+        let b: [i8; 6] = [7, 8, 9, 10, 11, 12];
+        let mut out = [0i32; 4];
+        unsafe { matmul_int8(a.as_ptr(), b.as_ptr(), out.as_mut_ptr(), 2, 2, 3) };
+        assert_eq!(out, [58, 64, 139, 154]);
+        // Past the i8 range without wrapping
+        let big = [-128i8; 4];
+        unsafe { matmul_int8(big.as_ptr(), big.as_ptr(), out.as_mut_ptr(), 1, 1, 4) };
+        assert_eq!(out[0], 4 * 128 * 128);
+    }
+}
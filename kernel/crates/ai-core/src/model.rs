@@ -0,0 +1,267 @@
+#![allow(dead_code)]
+
+use core::fmt;
+use core::mem::size_of;
+
+/// Most layers the agent will evaluate; a deeper model is refused at load.
+pub const MAX_LAYERS: u16 = 16;
+/// Widest layer: the agent's activation buffer holds this many values.
+pub const MAX_DIM: usize = 256;
+
+#[repr(C)]
+#[derive(Copy, Clone)]
+pub struct ModelHeader {
+    pub magic: [u8; 4], // b"AIMD"
+    pub n_layers: u16,
+    pub hidden: u16,
+    pub vocab: u32,
+    pub dtype: u8, // 0=int8, 1=int4
+    /// 0: weights follow the header; 1: a `ModelInfo` block comes first.
+    pub version: u8,
+    pub _res: [u8; 2],
+}
+
+impl ModelHeader {
+    pub const MAGIC: [u8; 4] = *b"AIMD";
+    pub const SIZE: usize = 16;
+    /// Newest header version this build reads.
+    pub const VERSION: u8 = 1;
+
+    #[inline]
+    pub fn valid(&self) -> bool {
+        self.magic == Self::MAGIC
+            && self.n_layers >= 1
+            && self.hidden >= 1
+            && (self.dtype == 0 || self.dtype == 1)
+            && self.version <= Self::VERSION
+    }
+
+    /// Where the weights start: after the metadata block from version 1 on.
+    pub fn payload_offset(&self) -> usize {
+        if self.version >= 1 { Self::SIZE + ModelInfo::LEN } else { Self::SIZE }
+    }
+
+    /// Whether one inference stays within `MAX_LAYERS` layers of at most
+    /// `MAX_DIM` inputs and outputs, so its cost is bounded by the header alone.
+    pub fn within_limits(&self) -> bool {
+        let last_out = if self.vocab != 0 { self.vocab as usize } else { self.hidden as usize };
+        self.n_layers <= MAX_LAYERS && self.hidden as usize <= MAX_DIM && last_out <= MAX_DIM
+    }
+
+    /// Multiply-accumulates in one inference.
+    pub fn macs(&self) -> usize {
+        (0..self.n_layers as usize)
+            .filter_map(|l| layer_dims(self, l))
+            .fold(0usize, |acc, (i, o)| acc.saturating_add(i.saturating_mul(o)))
+    }
+
+    /// # Safety
+    /// `ptr` must be null or valid for `len` bytes.
+    #[inline]
+    pub unsafe fn read_unaligned(ptr: *const u8, len: usize) -> Option<Self> {
+        if ptr.is_null() || len < size_of::<Self>() {
+            return None;
+        }
+        Some((ptr as *const Self).read_unaligned())
+    }
+}
+
+/// Metadata of a version 1 `AIMD` file, right after the header:
+///
+/// ```text
+/// 0x10  name        [u8; 24]  UTF-8, NUL padded
+/// 0x28  major, minor, patch   u16 each, then a reserved u16
+/// 0x30  train_hash  [u8; 32]  hash of the training run (SHA-256)
+/// 0x50  weights
+/// ```
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ModelInfo {
+    name: [u8; Self::NAME_LEN],
+    pub version: [u16; 3],
+    pub train_hash: [u8; 32],
+}
+
+impl ModelInfo {
+    pub const LEN: usize = 64;
+    pub const NAME_LEN: usize = 24;
+
+    /// The metadata of the `AIMD` file `model`; `None` for a version 0 file
+    /// or one too short to hold the block.
+    pub fn parse(model: &[u8]) -> Option<Self> {
+        let hdr = unsafe { ModelHeader::read_unaligned(model.as_ptr(), model.len())? };
+        if !hdr.valid() || hdr.version == 0 {
+            return None;
+        }
+        let b = model.get(ModelHeader::SIZE..ModelHeader::SIZE + Self::LEN)?;
+        let u16_at = |i: usize| u16::from_le_bytes([b[i], b[i + 1]]);
+        let mut info = ModelInfo { name: [0; Self::NAME_LEN], version: [u16_at(24), u16_at(26), u16_at(28)], train_hash: [0; 32] };
+        info.name.copy_from_slice(&b[..Self::NAME_LEN]);
+        info.train_hash.copy_from_slice(&b[32..]);
+        Some(info)
+    }
+
+    /// The name up to its first NUL; `?` if it is not UTF-8.
+    pub fn name(&self) -> &str {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(Self::NAME_LEN);
+        core::str::from_utf8(&self.name[..len]).unwrap_or("?")
+    }
+
+    /// First four bytes of the training hash, big endian, as the journal
+    /// keeps them.
+    pub fn hash_prefix(&self) -> u32 {
+        u32::from_be_bytes([self.train_hash[0], self.train_hash[1], self.train_hash[2], self.train_hash[3]])
+    }
+
+    /// The training hash in hex.
+    pub fn hash(&self) -> Hex<'_> {
+        Hex(&self.train_hash)
+    }
+}
+
+/// `name vMAJOR.MINOR.PATCH` and the hash prefix, the form logs and crash
+/// reports use.
+impl fmt::Display for ModelInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [major, minor, patch] = self.version;
+        write!(f, "{} v{}.{}.{} {:08x}", self.name(), major, minor, patch, self.hash_prefix())
+    }
+}
+
+/// Bytes written as lowercase hex.
+pub struct Hex<'a>(pub &'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.iter().try_for_each(|b| write!(f, "{:02x}", b))
+    }
+}
+
+pub struct WeightsLayout {
+    pub total_bytes: usize,
+}
+
+impl WeightsLayout {
+    pub fn compute(h: &ModelHeader) -> Option<Self> {
+        if h.dtype != 0 { // only int8 supported for now
+            return None;
+        }
+        let nl = h.n_layers as usize;
+        if nl == 0 { return None; }
+        let hidden = h.hidden as usize;
+        let last_out = if h.vocab != 0 { h.vocab as usize } else { hidden };
+        let mut total: usize = 0;
+        for l in 0..nl {
+            let in_dim = hidden;
+            let out_dim = if l + 1 == nl { last_out } else { hidden };
+            // weights int8
+            total = total.saturating_add(in_dim.saturating_mul(out_dim));
+            // biases i32 per output
+            total = total.saturating_add(out_dim.saturating_mul(core::mem::size_of::<i32>()));
+        }
+        Some(Self { total_bytes: total })
+    }
+}
+
+/// Start of `layer`'s int8 weights in the model at `base`.
+///
+/// # Safety
+/// `base` must point to a model whose payload holds `WeightsLayout::compute(h)` bytes.
+pub unsafe fn layer_ptr_int8(base: *const u8, h: &ModelHeader, layer: usize) -> Option<*const i8> {
+    if h.dtype != 0 { return None; }
+    let nl = h.n_layers as usize;
+    if layer >= nl { return None; }
+    let hidden = h.hidden as usize;
+    let last_out = if h.vocab != 0 { h.vocab as usize } else { hidden };
+    let mut offset = 0usize;
+    for l in 0..layer {
+        let in_dim = hidden;
+        let out_dim = if l + 1 == nl { last_out } else { hidden };
+        offset = offset.saturating_add(in_dim.saturating_mul(out_dim));
+        offset = offset.saturating_add(out_dim.saturating_mul(core::mem::size_of::<i32>()));
+    }
+    let wptr = base.add(h.payload_offset() + offset);
+    Some(wptr as *const i8)
+}
+
+pub fn layer_dims(h: &ModelHeader, layer: usize) -> Option<(usize, usize)> {
+    let nl = h.n_layers as usize;
+    if layer >= nl { return None; }
+    let hidden = h.hidden as usize;
+    let last_out = if h.vocab != 0 { h.vocab as usize } else { hidden };
+    let in_dim = hidden;
+    let out_dim = if layer + 1 == nl { last_out } else { hidden };
+    Some((in_dim, out_dim))
+}
+
+/// Start of `layer`'s i32 biases, right after its weights.
+///
+/// # Safety
+/// As `layer_ptr_int8`.
+pub unsafe fn bias_ptr_i32(base: *const u8, h: &ModelHeader, layer: usize) -> Option<*const i32> {
+    if h.dtype != 0 { return None; }
+    let nl = h.n_layers as usize;
+    if layer >= nl { return None; }
+    let hidden = h.hidden as usize;
+    let last_out = if h.vocab != 0 { h.vocab as usize } else { hidden };
+    // compute offset to start of this layer
+    let mut offset = 0usize;
+    for l in 0..layer {
+        let in_dim = hidden;
+        let out_dim = if l + 1 == nl { last_out } else { hidden };
+        offset = offset.saturating_add(in_dim.saturating_mul(out_dim));
+        offset = offset.saturating_add(out_dim.saturating_mul(core::mem::size_of::<i32>()));
+    }
+    // add weights of this layer
+    let (in_dim, out_dim) = layer_dims(h, layer)?;
+    offset = offset.saturating_add(in_dim.saturating_mul(out_dim));
+    let bptr = base.add(h.payload_offset() + offset);
+    Some(bptr as *const i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header(n_layers: u16, hidden: u16, vocab: u32) -> ModelHeader {
+        ModelHeader { magic: ModelHeader::MAGIC, n_layers, hidden, vocab, dtype: 0, version: 0, _res: [0; 2] }
+    }
+
+    #[test]
+    fn limits_bound_layers_and_widths() {
+        assert!(header(2, 64, 4).within_limits());
+        assert!(header(MAX_LAYERS, MAX_DIM as u16, 0).within_limits());
+        assert!(!header(MAX_LAYERS + 1, 8, 0).within_limits());
+        assert!(!header(1, MAX_DIM as u16 + 1, 0).within_limits());
+        assert!(!header(1, 8, 100_000).within_limits());
+        assert_eq!(header(2, 64, 4).macs(), 64 * 64 + 64 * 4);
+    }
+
+    #[test]
+    fn version_1_carries_name_version_and_hash() {
+        let mut model = std::vec::Vec::from(*b"AIMD");
+        model.extend_from_slice(&[1, 0, 5, 0, 1, 0, 0, 0, 0, 1, 0, 0]);
+        let mut name = [0u8; ModelInfo::NAME_LEN];
+        name[..9].copy_from_slice(b"sched-mlp");
+        model.extend_from_slice(&name);
+        for v in [0u16, 3, 12, 0] {
+            model.extend_from_slice(&v.to_le_bytes());
+        }
+        model.extend((0..32u8).map(|i| i * 8));
+        let hdr = unsafe { ModelHeader::read_unaligned(model.as_ptr(), model.len()) }.unwrap();
+        assert!(hdr.valid());
+        assert_eq!(hdr.payload_offset(), 0x50);
+        let info = ModelInfo::parse(&model).unwrap();
+        assert_eq!((info.name(), info.version), ("sched-mlp", [0, 3, 12]));
+        assert_eq!(info.hash_prefix(), 0x0008_1018);
+        assert_eq!(std::format!("{}", info), "sched-mlp v0.3.12 00081018");
+        assert!(std::format!("{}", info.hash()).ends_with("e8f0f8"));
+        assert_eq!(ModelInfo::parse(&model[..0x4F]), None);
+        // Version 0: no metadata, weights right after the header
+        model[13] = 0;
+        assert_eq!(ModelInfo::parse(&model), None);
+        assert_eq!(header(1, 5, 1).payload_offset(), ModelHeader::SIZE);
+        model[13] = 2;
+        let hdr = unsafe { ModelHeader::read_unaligned(model.as_ptr(), model.len()) }.unwrap();
+        assert!(!hdr.valid());
+    }
+}
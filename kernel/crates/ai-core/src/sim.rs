@@ -0,0 +1,407 @@
+//! Host simulator for the transactional gate: `txn::apply` runs against a
+//! model of the knobs the kernel exposes and a journal that survives
+//! reboots, through scripted action sequences with injected faults (a
+//! self-test that fails, the machine going down between INTENT and its
+//! outcome, actions arriving before the system is ready).
+//!
+//! Every step checks the gate's promises: a rejected action leaves no
+//! INTENT and changes nothing, a failed one leaves the knobs as they were,
+//! a committed one shows the effect it journaled. After a script the
+//! journal is paired as the kernel does at boot, and must report exactly
+//! the intents cut short by a crash.
+
+use std::vec::Vec;
+
+use crate::action::{self, poll_target, Action, ActionType};
+use crate::journal::{pair_records, Record, RecordKind};
+use crate::txn::{self, ApplyError, ApplyResult, Caller, Journal, Observed, Target};
+
+/// As `boost::MAX_BOOSTS` in the kernel.
+const MAX_BOOSTS: usize = 4;
+/// Tasks a `BoostTask` may name.
+const TASKS: [&str; 2] = ["shell", "net"];
+
+/// The knobs, as on a fresh boot (`apply_action`, `klog`, `xhci`, `boost`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Knobs {
+    quantum_us: u32,
+    log_level: u8,
+    ai_interval_ms: u32,
+    ai_cpu_pct: u32,
+    hid_pace_ms: Option<u32>,
+    /// Packed task name and ms left.
+    boosts: [Option<(u64, u64)>; MAX_BOOSTS],
+    /// Not a knob: trims raise it and nothing puts it back.
+    free_kib: u64,
+}
+
+impl Knobs {
+    fn boot(has_xhci: bool) -> Self {
+        Knobs {
+            quantum_us: 1000,
+            log_level: 2,
+            ai_interval_ms: 0,
+            ai_cpu_pct: 10,
+            hid_pace_ms: has_xhci.then_some(0),
+            boosts: [None; MAX_BOOSTS],
+            free_kib: 64 * 1024,
+        }
+    }
+
+    fn same_knobs(&self, other: &Knobs) -> bool {
+        Knobs { free_kib: 0, ..*self } == Knobs { free_kib: 0, ..*other }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Fault {
+    None,
+    /// Early boot, or a crash loop: nothing is admitted.
+    NotReady,
+    /// The liveness check fails after the action ran.
+    SelfTestFails,
+    /// Power is lost right after the INTENT record is written.
+    CrashAfterIntent,
+}
+
+struct Machine {
+    knobs: Knobs,
+    has_xhci: bool,
+    fault: Fault,
+}
+
+impl Machine {
+    fn boost(&mut self, param: u64, ms: u64) -> bool {
+        let mut buf = [0u8; action::NAME_LEN];
+        let Some(name) = action::unpack_name(param, &mut buf) else { return false };
+        if !TASKS.contains(&name) {
+            return false;
+        }
+        let boosts = &mut self.knobs.boosts;
+        let slot = boosts.iter().position(|b| b.is_some_and(|(n, _)| n == param)).or_else(|| boosts.iter().position(Option::is_none));
+        match slot {
+            Some(i) => {
+                boosts[i] = Some((param, ms));
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn observe(&self, a: &Action, before: &Knobs) -> Observed {
+        let boost_left_ms = self.knobs.boosts.iter().flatten().find(|(n, _)| *n == a.param1).map(|&(_, ms)| ms);
+        Observed {
+            slice_us: self.knobs.quantum_us as u64,
+            log_level: self.knobs.log_level,
+            ai_interval_ms: self.knobs.ai_interval_ms,
+            ai_cpu_pct: self.knobs.ai_cpu_pct,
+            hid_pace_ms: self.knobs.hid_pace_ms,
+            boost_left_ms: boost_left_ms.filter(|_| a.kind == ActionType::BoostTask as u8),
+            freed_kib: self.knobs.free_kib as i64 - before.free_kib as i64,
+        }
+    }
+}
+
+impl Target for Machine {
+    type Snapshot = Knobs;
+
+    fn ready(&self) -> bool {
+        self.fault != Fault::NotReady
+    }
+
+    fn snapshot(&mut self) -> Knobs {
+        self.knobs
+    }
+
+    fn restore(&mut self, before: Knobs) {
+        self.knobs = Knobs { free_kib: self.knobs.free_kib, ..before };
+    }
+
+    fn execute(&mut self, a: &Action) -> ApplyResult<()> {
+        let ok = match a.kind {
+            x if x == ActionType::SetQuantum as u8 => {
+                self.knobs.quantum_us = a.param1 as u32;
+                true
+            }
+            x if x == ActionType::TrimCache as u8 => {
+                self.knobs.free_kib += a.param1 / 1024 / 2;
+                true
+            }
+            x if x == ActionType::SetLogLevel as u8 => {
+                self.knobs.log_level = a.param1 as u8;
+                true
+            }
+            x if x == ActionType::SetPollingInterval as u8 => match a.param1 {
+                poll_target::AI_INFERENCE => {
+                    self.knobs.ai_interval_ms = a.param2 as u32;
+                    true
+                }
+                // No controller, no pacing
+                _ if self.has_xhci => {
+                    self.knobs.hid_pace_ms = Some(a.param2 as u32);
+                    true
+                }
+                _ => false,
+            },
+            x if x == ActionType::BoostTask as u8 => self.boost(a.param1, a.param2),
+            x if x == ActionType::SetCpuBudget as u8 => {
+                self.knobs.ai_cpu_pct = a.param1 as u32;
+                true
+            }
+            _ => return Err(ApplyError::InvalidParams),
+        };
+        if ok { Ok(()) } else { Err(ApplyError::ExecuteFailed) }
+    }
+
+    fn self_test(&mut self, a: &Action, before: &Knobs) -> ApplyResult<u32> {
+        if self.fault == Fault::SelfTestFails {
+            return Err(ApplyError::SelfTestFailed);
+        }
+        txn::check_effect(a, &self.observe(a, before)).ok_or(ApplyError::SelfTestFailed)
+    }
+}
+
+/// The journal as it would be found on the next boot.
+#[derive(Default)]
+struct Disk {
+    records: Vec<Record>,
+    /// Seq whose INTENT is the last record written before power is lost.
+    crash_at: Option<u64>,
+    down: bool,
+}
+
+impl Disk {
+    fn push(&mut self, seq: u64, kind: RecordKind, a: &Action, code: u32) {
+        if self.down {
+            return;
+        }
+        let tsc = self.records.len() as u64;
+        self.records.push(Record { tsc, seq, kind, action: a.kind, flags: a.flags, code });
+        if kind == RecordKind::Intent && self.crash_at == Some(seq) {
+            self.down = true;
+        }
+    }
+}
+
+impl Journal for Disk {
+    fn reject(&mut self, seq: u64, a: &Action) {
+        self.push(seq, RecordKind::Reject, a, 0);
+    }
+
+    fn intent(&mut self, seq: u64, a: &Action) {
+        self.push(seq, RecordKind::Intent, a, 0);
+    }
+
+    fn commit(&mut self, seq: u64, a: &Action, effect: u32) {
+        self.push(seq, RecordKind::ApplyOk, a, effect);
+    }
+
+    fn fail(&mut self, seq: u64, a: &Action, code: u32) {
+        self.push(seq, RecordKind::ApplyFail, a, code);
+    }
+}
+
+struct Sim {
+    machine: Machine,
+    disk: Disk,
+    seq: u64,
+    /// Seqs whose apply was cut short by an injected crash.
+    crashed: Vec<u64>,
+}
+
+impl Sim {
+    fn new(has_xhci: bool) -> Self {
+        Sim {
+            machine: Machine { knobs: Knobs::boot(has_xhci), has_xhci, fault: Fault::None },
+            disk: Disk::default(),
+            seq: 0,
+            crashed: Vec::new(),
+        }
+    }
+
+    fn reboot(&mut self) {
+        self.machine.knobs = Knobs::boot(self.machine.has_xhci);
+        self.disk.down = false;
+        self.disk.crash_at = None;
+    }
+
+    /// Records written for `seq`.
+    fn records_of(&self, seq: u64) -> Vec<RecordKind> {
+        self.disk.records.iter().filter(|r| r.seq == seq).map(|r| r.kind).collect()
+    }
+
+    /// One action through the gate, checking what it left behind; `None`
+    /// if the machine went down (it is rebooted).
+    fn step(&mut self, a: Action, caller: Caller, fault: Fault) -> Option<ApplyResult<()>> {
+        let seq = self.seq;
+        self.seq += 1;
+        self.machine.fault = fault;
+        if fault == Fault::CrashAfterIntent {
+            self.disk.crash_at = Some(seq);
+        }
+        let before = self.machine.knobs;
+        let result = txn::apply(seq, &a, caller, &mut self.machine, &mut self.disk);
+
+        if self.disk.down {
+            assert_eq!(self.records_of(seq), [RecordKind::Intent], "seq {}", seq);
+            self.crashed.push(seq);
+            self.reboot();
+            return None;
+        }
+        self.disk.crash_at = None;
+        let records = self.records_of(seq);
+        match result {
+            Ok(()) => {
+                assert_eq!(records, [RecordKind::Intent, RecordKind::ApplyOk], "seq {}", seq);
+                let journaled = self.disk.records.last().map(|r| r.code);
+                assert_eq!(txn::check_effect(&a, &self.machine.observe(&a, &before)), journaled, "seq {}", seq);
+            }
+            Err(ApplyError::NotAllowed) => {
+                assert_eq!(records, [RecordKind::Reject], "seq {}", seq);
+                assert_eq!(self.machine.knobs, before, "seq {}", seq);
+            }
+            Err(e) => {
+                assert_eq!(records, [RecordKind::Intent, RecordKind::ApplyFail], "seq {}", seq);
+                assert_eq!(self.disk.records.last().map(|r| r.code), Some(e as u32));
+                assert!(self.machine.knobs.same_knobs(&before), "seq {} changed the knobs", seq);
+            }
+        }
+        Some(result)
+    }
+
+    /// The boot-time journal check: exactly the crashed applies dangle, and
+    /// every record survives the kv encoding.
+    fn verify(&self) {
+        let report = pair_records(&self.disk.records);
+        assert_eq!(report.dangling as usize, self.crashed.len());
+        assert_eq!(report.first_dangling, self.crashed.first().copied());
+        assert_eq!(report.committed + report.failed + report.dangling, report.intents);
+        for rec in &self.disk.records {
+            let back = Record::decode(&rec.encode()).map(|r| (r.seq, r.kind, r.code));
+            assert_eq!(back, Some((rec.seq, rec.kind, rec.code)));
+        }
+    }
+}
+
+fn action(kind: ActionType, param1: u64, param2: u64) -> Action {
+    Action { kind: kind as u8, param1, param2, ..Action::default() }
+}
+
+fn boost(name: &str, ms: u64) -> Action {
+    action(ActionType::BoostTask, action::pack_name(name).unwrap_or(0), ms)
+}
+
+#[test]
+fn clean_script_commits_every_action() {
+    let mut sim = Sim::new(true);
+    let script = [
+        action(ActionType::SetQuantum, 2_000, 0),
+        action(ActionType::TrimCache, 64 * 1024, 0),
+        action(ActionType::SetLogLevel, 3, 0),
+        action(ActionType::SetPollingInterval, poll_target::HID, 8),
+        action(ActionType::SetPollingInterval, poll_target::AI_INFERENCE, 250),
+        boost("shell", 500),
+        action(ActionType::SetCpuBudget, 5, 0),
+    ];
+    for a in script {
+        assert_eq!(sim.step(a, Caller::Kernel, Fault::None), Some(Ok(())));
+    }
+    assert_eq!(sim.machine.knobs.quantum_us, 2_000);
+    assert_eq!(sim.machine.knobs.hid_pace_ms, Some(8));
+    sim.verify();
+    assert_eq!(pair_records(&sim.disk.records).committed, 7);
+}
+
+#[test]
+fn failed_self_test_puts_the_knobs_back() {
+    let mut sim = Sim::new(true);
+    sim.step(action(ActionType::SetQuantum, 2_000, 0), Caller::Kernel, Fault::None);
+    sim.step(boost("net", 300), Caller::Kernel, Fault::None);
+    let before = sim.machine.knobs;
+    let script = [
+        action(ActionType::SetQuantum, 4_000, 0),
+        boost("shell", 800),
+        action(ActionType::SetLogLevel, 0, 0),
+        action(ActionType::SetCpuBudget, 20, 0),
+    ];
+    for a in script {
+        assert_eq!(sim.step(a, Caller::Kernel, Fault::SelfTestFails), Some(Err(ApplyError::SelfTestFailed)));
+    }
+    assert_eq!(sim.machine.knobs, before);
+    sim.verify();
+}
+
+#[test]
+fn execute_failures_are_journaled_and_change_nothing() {
+    let mut sim = Sim::new(false);
+    let hid = action(ActionType::SetPollingInterval, poll_target::HID, 8);
+    assert_eq!(sim.step(hid, Caller::Kernel, Fault::None), Some(Err(ApplyError::ExecuteFailed)));
+    assert_eq!(sim.step(boost("ghost", 100), Caller::Kernel, Fault::None), Some(Err(ApplyError::ExecuteFailed)));
+    sim.verify();
+}
+
+#[test]
+fn crash_between_intent_and_commit_leaves_one_dangling_intent() {
+    let mut sim = Sim::new(true);
+    sim.step(action(ActionType::SetQuantum, 2_000, 0), Caller::Kernel, Fault::None);
+    assert_eq!(sim.step(action(ActionType::SetQuantum, 8_000, 0), Caller::Kernel, Fault::CrashAfterIntent), None);
+    // The knobs are back to their boot values and the gate works again
+    assert_eq!(sim.machine.knobs, Knobs::boot(true));
+    assert_eq!(sim.step(action(ActionType::SetLogLevel, 1, 0), Caller::Kernel, Fault::None), Some(Ok(())));
+    sim.verify();
+    assert_eq!(pair_records(&sim.disk.records).first_dangling, Some(1));
+}
+
+#[test]
+fn rejections_never_reach_the_machine() {
+    let mut sim = Sim::new(true);
+    let quantum = action(ActionType::SetQuantum, 2_000, 0);
+    assert_eq!(sim.step(quantum, Caller::Kernel, Fault::NotReady), Some(Err(ApplyError::NotAllowed)));
+    // User space may only move the quantum, within a narrower range
+    assert_eq!(sim.step(action(ActionType::SetLogLevel, 1, 0), Caller::User, Fault::None), Some(Err(ApplyError::NotAllowed)));
+    assert_eq!(sim.step(action(ActionType::SetQuantum, 200, 0), Caller::User, Fault::None), Some(Err(ApplyError::NotAllowed)));
+    assert_eq!(sim.step(quantum, Caller::User, Fault::None), Some(Ok(())));
+    // Out of range, or a kind the gate does not take
+    assert_eq!(sim.step(action(ActionType::SetQuantum, 60_000, 0), Caller::Kernel, Fault::None), Some(Err(ApplyError::NotAllowed)));
+    assert_eq!(sim.step(action(ActionType::Reboot, 0, 0), Caller::Kernel, Fault::None), Some(Err(ApplyError::NotAllowed)));
+    // A crash on a rejected action writes nothing, so nothing dangles
+    assert_eq!(sim.step(action(ActionType::Halt, 0, 0), Caller::Kernel, Fault::CrashAfterIntent), Some(Err(ApplyError::NotAllowed)));
+    sim.verify();
+}
+
+#[test]
+fn random_scripts_keep_the_invariants() {
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let mut next = move |n: u64| {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state % n
+    };
+    for has_xhci in [true, false] {
+        let mut sim = Sim::new(has_xhci);
+        for _ in 0..2_000 {
+            let a = match next(8) {
+                0 => action(ActionType::SetQuantum, 50 + next(60_000), 0),
+                1 => action(ActionType::TrimCache, next(32 * 1024 * 1024), 0),
+                2 => action(ActionType::SetLogLevel, next(6), 0),
+                3 => action(ActionType::SetPollingInterval, next(3), next(6_000)),
+                4 => boost(["shell", "net", "ghost"][next(3) as usize], next(2_500)),
+                5 => action(ActionType::SetCpuBudget, next(30), 0),
+                6 => action(ActionType::Reboot, 0, 0),
+                _ => action(ActionType::None, next(10), 0),
+            };
+            let caller = if next(4) == 0 { Caller::User } else { Caller::Kernel };
+            let fault = match next(20) {
+                0 => Fault::NotReady,
+                1 | 2 => Fault::SelfTestFails,
+                3 => Fault::CrashAfterIntent,
+                _ => Fault::None,
+            };
+            sim.step(a, caller, fault);
+        }
+        sim.verify();
+        assert!(!sim.crashed.is_empty());
+        assert!(pair_records(&sim.disk.records).committed > 100);
+    }
+}
@@ -0,0 +1,261 @@
+//! The decision-forest model format (`AIDT`), an alternative to the MLP
+//! weights of `model` that a reviewer can read: every split names a
+//! telemetry feature and a threshold, every leaf a score.
+//!
+//! Layout (little endian):
+//!
+//! ```text
+//! 0x00  magic b"AIDT"
+//! 0x04  n_nodes  u16   nodes in all trees together
+//! 0x06  n_trees  u8
+//! 0x07  version  u8    1
+//! 0x08  reserved [u8; 8]
+//! 0x10  roots    [u16; n_trees]   index of each tree's first node
+//!       nodes    [Node; n_nodes]  12 bytes each
+//! ```
+//!
+//! A node is `feature: u8, 0, left: u16, right: u16, 0: u16, value: i32`.
+//! `feature` is `LEAF` for a leaf, whose `value` is its score; otherwise
+//! the walk goes `left` when the feature is at most `value`, `right`
+//! otherwise. Children come after their parent, so a walk always ends and
+//! `parse` can bound its depth. The model's score is the sum of the leaves
+//! its trees reach; the agent turns it into an action as it does the MLP's.
+
+/// Features a split can test, in the order the agent fills them in.
+pub const FEATURES: [&str; 6] = ["runq", "irq_rate", "free_kb", "pf_rate", "input_latency_us", "irq_errors"];
+/// `feature` of a leaf node.
+pub const LEAF: u8 = 0xFF;
+
+pub const MAGIC: [u8; 4] = *b"AIDT";
+pub const VERSION: u8 = 1;
+pub const HEADER_LEN: usize = 16;
+pub const NODE_LEN: usize = 12;
+pub const MAX_TREES: usize = 16;
+pub const MAX_NODES: usize = 512;
+/// Deepest path through one tree, so a step costs at most
+/// `MAX_TREES * MAX_DEPTH` comparisons.
+pub const MAX_DEPTH: usize = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TreeError {
+    BadHeader,
+    Truncated,
+    TooLarge,
+    /// A node tests an unknown feature or points at a node that is not
+    /// after it.
+    BadNode(u16),
+}
+
+impl TreeError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TreeError::BadHeader => "bad header",
+            TreeError::Truncated => "truncated",
+            TreeError::TooLarge => "too large",
+            TreeError::BadNode(_) => "bad node",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Node {
+    pub feature: u8,
+    pub left: u16,
+    pub right: u16,
+    pub value: i32,
+}
+
+impl Node {
+    fn decode(b: &[u8]) -> Self {
+        Node {
+            feature: b[0],
+            left: u16::from_le_bytes([b[2], b[3]]),
+            right: u16::from_le_bytes([b[4], b[5]]),
+            value: i32::from_le_bytes([b[8], b[9], b[10], b[11]]),
+        }
+    }
+}
+
+/// Whether `bytes` is an `AIDT` file (by its magic alone).
+pub fn claims(bytes: &[u8]) -> bool {
+    bytes.get(..4) == Some(&MAGIC[..])
+}
+
+/// A checked `AIDT` file.
+#[derive(Copy, Clone, Debug)]
+pub struct Forest<'a> {
+    bytes: &'a [u8],
+    n_trees: usize,
+    n_nodes: usize,
+}
+
+impl<'a> Forest<'a> {
+    /// Check the whole file: sizes within the caps, every split on a known
+    /// feature with both children after it, no path deeper than `MAX_DEPTH`.
+    pub fn parse(bytes: &'a [u8]) -> Result<Self, TreeError> {
+        if !claims(bytes) || bytes.len() < HEADER_LEN || bytes[7] != VERSION {
+            return Err(TreeError::BadHeader);
+        }
+        let n_nodes = u16::from_le_bytes([bytes[4], bytes[5]]) as usize;
+        let n_trees = bytes[6] as usize;
+        if n_trees == 0 || n_nodes == 0 {
+            return Err(TreeError::BadHeader);
+        }
+        if n_trees > MAX_TREES || n_nodes > MAX_NODES {
+            return Err(TreeError::TooLarge);
+        }
+        let forest = Forest { bytes, n_trees, n_nodes };
+        if bytes.len() < forest.nodes_offset() + n_nodes * NODE_LEN {
+            return Err(TreeError::Truncated);
+        }
+        let mut depth = [0u8; MAX_NODES];
+        for i in 0..n_nodes {
+            let node = forest.node(i);
+            if node.feature == LEAF {
+                continue;
+            }
+            let bad = TreeError::BadNode(i as u16);
+            if node.feature as usize >= FEATURES.len() {
+                return Err(bad);
+            }
+            for child in [node.left as usize, node.right as usize] {
+                if child <= i || child >= n_nodes {
+                    return Err(bad);
+                }
+                depth[child] = depth[child].max(depth[i] + 1);
+                if depth[child] as usize >= MAX_DEPTH {
+                    return Err(TreeError::TooLarge);
+                }
+            }
+        }
+        for t in 0..n_trees {
+            if forest.root(t) >= n_nodes {
+                return Err(TreeError::BadNode(forest.root(t) as u16));
+            }
+        }
+        Ok(forest)
+    }
+
+    fn nodes_offset(&self) -> usize {
+        HEADER_LEN + 2 * self.n_trees
+    }
+
+    fn root(&self, tree: usize) -> usize {
+        let at = HEADER_LEN + 2 * tree;
+        u16::from_le_bytes([self.bytes[at], self.bytes[at + 1]]) as usize
+    }
+
+    pub fn node(&self, index: usize) -> Node {
+        let at = self.nodes_offset() + index * NODE_LEN;
+        Node::decode(&self.bytes[at..at + NODE_LEN])
+    }
+
+    pub fn trees(&self) -> usize {
+        self.n_trees
+    }
+
+    pub fn nodes(&self) -> usize {
+        self.n_nodes
+    }
+
+    /// Sum of the leaves each tree reaches for `features`.
+    pub fn eval(&self, features: &[u32; FEATURES.len()]) -> i32 {
+        let mut score = 0i32;
+        for t in 0..self.n_trees {
+            let mut i = self.root(t);
+            // Children are after their parent: at most `n_nodes` steps
+            loop {
+                let node = self.node(i);
+                if node.feature == LEAF {
+                    score = score.saturating_add(node.value);
+                    break;
+                }
+                let x = features[node.feature as usize] as i64;
+                i = if x <= node.value as i64 { node.left } else { node.right } as usize;
+            }
+        }
+        score
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn node(feature: u8, left: u16, right: u16, value: i32) -> [u8; NODE_LEN] {
+        let mut b = [0u8; NODE_LEN];
+        b[0] = feature;
+        b[2..4].copy_from_slice(&left.to_le_bytes());
+        b[4..6].copy_from_slice(&right.to_le_bytes());
+        b[8..12].copy_from_slice(&value.to_le_bytes());
+        b
+    }
+
+    fn file(roots: &[u16], nodes: &[[u8; NODE_LEN]]) -> Vec<u8> {
+        let mut b = Vec::from(MAGIC);
+        b.extend_from_slice(&(nodes.len() as u16).to_le_bytes());
+        b.extend_from_slice(&[roots.len() as u8, VERSION]);
+        b.extend_from_slice(&[0; 8]);
+        for r in roots {
+            b.extend_from_slice(&r.to_le_bytes());
+        }
+        for n in nodes {
+            b.extend_from_slice(n);
+        }
+        b
+    }
+
+    /// Tree 0: free_kb <= 8192 ? 40 : (runq <= 4 ? 0 : 20). Tree 1: leaf 3.
+    fn forest() -> Vec<u8> {
+        file(
+            &[0, 5],
+            &[node(2, 1, 2, 8192), node(LEAF, 0, 0, 40), node(0, 3, 4, 4), node(LEAF, 0, 0, 0), node(LEAF, 0, 0, 20), node(LEAF, 0, 0, 3)],
+        )
+    }
+
+    #[test]
+    fn forest_sums_the_leaves_reached() {
+        let bytes = forest();
+        let f = Forest::parse(&bytes).unwrap();
+        assert_eq!((f.trees(), f.nodes()), (2, 6));
+        assert_eq!(f.eval(&[0, 0, 4096, 0, 0, 0]), 43);
+        assert_eq!(f.eval(&[2, 0, 65536, 0, 0, 0]), 3);
+        assert_eq!(f.eval(&[9, 0, u32::MAX, 0, 0, 0]), 23);
+    }
+
+    #[test]
+    fn malformed_forests_are_refused() {
+        let bytes = forest();
+        assert_eq!(Forest::parse(&bytes[..bytes.len() - 1]).err(), Some(TreeError::Truncated));
+        let mut loop_back = bytes.clone();
+        // Node 2's left child back to node 0
+        let at = HEADER_LEN + 4 + 2 * NODE_LEN + 2;
+        loop_back[at..at + 2].copy_from_slice(&0u16.to_le_bytes());
+        assert_eq!(Forest::parse(&loop_back).err(), Some(TreeError::BadNode(2)));
+        let unknown = file(&[0], &[node(6, 1, 2, 0), node(LEAF, 0, 0, 1), node(LEAF, 0, 0, 2)]);
+        assert_eq!(Forest::parse(&unknown).err(), Some(TreeError::BadNode(0)));
+        let mut version = bytes.clone();
+        version[7] = 2;
+        assert_eq!(Forest::parse(&version).err(), Some(TreeError::BadHeader));
+        assert!(!claims(b"AIMD"));
+    }
+
+    /// `splits` splits in a row, each with a leaf on its left.
+    fn chain(splits: u16) -> Vec<u8> {
+        let mut nodes = Vec::new();
+        for i in 0..splits {
+            nodes.push(node(0, 2 * i + 1, 2 * i + 2, 0));
+            nodes.push(node(LEAF, 0, 0, 1));
+        }
+        nodes.push(node(LEAF, 0, 0, 2));
+        file(&[0], &nodes)
+    }
+
+    #[test]
+    fn depth_is_capped() {
+        let deepest = chain(MAX_DEPTH as u16 - 1);
+        assert_eq!(Forest::parse(&deepest).unwrap().eval(&[1; FEATURES.len()]), 2);
+        assert_eq!(Forest::parse(&chain(MAX_DEPTH as u16)).err(), Some(TreeError::TooLarge));
+    }
+}
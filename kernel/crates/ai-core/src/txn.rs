@@ -0,0 +1,230 @@
+//! The transactional gate every proposed action goes through: admission
+//! (policy and parameters), INTENT, execute, self-test, then APPLY_OK, or
+//! APPLY_FAIL with the knobs put back as they were.
+//!
+//! What an action touches and how its effect is measured belong to the
+//! kernel (`Target`), where records go to the journal (`Journal`); the
+//! order of the steps and the policy are here, so the host simulator in
+//! `sim` runs the same state machine as the kernel.
+
+use crate::action::{self, poll_target, Action, ActionType};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ApplyError {
+    NotAllowed = 1,
+    InvalidParams = 3,
+    ExecuteFailed = 4,
+    SelfTestFailed = 5,
+}
+
+pub type ApplyResult<T> = core::result::Result<T, ApplyError>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Caller {
+    Kernel,
+    User,
+}
+
+/// Longest polling interval an action may set.
+pub const MAX_POLL_INTERVAL_MS: u64 = 5_000;
+/// Longest a `BoostTask` may last.
+pub const MAX_BOOST_MS: u64 = 2_000;
+/// Largest share of each second `SetCpuBudget` may give the agent; more
+/// is only for the operator (`ai.cpu_pct`).
+pub const MAX_AI_CPU_PCT: u64 = 25;
+/// Highest `SetLogLevel` parameter (`klog::Level::Debug`).
+pub const MAX_LOG_LEVEL: u64 = 3;
+
+// User-space agents get a narrower envelope than the in-kernel one: quantum only, moderate range.
+const USER_QUANTUM_MIN_US: u32 = 500;
+const USER_QUANTUM_MAX_US: u32 = 20_000;
+
+pub fn user_policy_allows(a: &Action) -> bool {
+    match a.kind {
+        x if x == ActionType::SetQuantum as u8 => {
+            let us = a.param1 as u32;
+            (USER_QUANTUM_MIN_US..=USER_QUANTUM_MAX_US).contains(&us)
+        }
+        _ => false,
+    }
+}
+
+pub fn is_allowed(kind: u8) -> bool {
+    match kind {
+        x if x == ActionType::SetQuantum as u8 => true,
+        x if x == ActionType::TrimCache as u8 => true,
+        x if x == ActionType::SetLogLevel as u8 => true,
+        x if x == ActionType::SetPollingInterval as u8 => true,
+        x if x == ActionType::BoostTask as u8 => true,
+        x if x == ActionType::SetCpuBudget as u8 => true,
+        _ => false,
+    }
+}
+
+pub fn validate_params(a: &Action) -> bool {
+    match a.kind {
+        x if x == ActionType::SetQuantum as u8 => {
+            let us = a.param1 as u32;
+            (100..=50_000).contains(&us)
+        }
+        x if x == ActionType::TrimCache as u8 => {
+            let bytes = a.param1;
+            bytes > 0 && bytes <= 16 * 1024 * 1024
+        }
+        x if x == ActionType::SetLogLevel as u8 => a.param1 <= MAX_LOG_LEVEL,
+        x if x == ActionType::SetPollingInterval as u8 => {
+            matches!(a.param1, poll_target::AI_INFERENCE | poll_target::HID) && a.param2 <= MAX_POLL_INTERVAL_MS
+        }
+        x if x == ActionType::BoostTask as u8 => {
+            let mut buf = [0u8; action::NAME_LEN];
+            action::unpack_name(a.param1, &mut buf).is_some() && (1..=MAX_BOOST_MS).contains(&a.param2)
+        }
+        x if x == ActionType::SetCpuBudget as u8 => (1..=MAX_AI_CPU_PCT).contains(&a.param1),
+        _ => false,
+    }
+}
+
+/// Whether `caller` may apply `a`; `ready` is false until the system is
+/// fully up, and while repeated crashes may be the agent's doing.
+pub fn admit(a: &Action, caller: Caller, ready: bool) -> ApplyResult<()> {
+    if !ready || !is_allowed(a.kind) || !validate_params(a) {
+        return Err(ApplyError::NotAllowed);
+    }
+    if caller == Caller::User && !user_policy_allows(a) {
+        return Err(ApplyError::NotAllowed);
+    }
+    Ok(())
+}
+
+/// What the knobs an action touches read back after it ran.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Observed {
+    /// Time slice the scheduler uses now.
+    pub slice_us: u64,
+    pub log_level: u8,
+    pub ai_interval_ms: u32,
+    /// Share of each second the agent task gets, in percent.
+    pub ai_cpu_pct: u32,
+    pub hid_pace_ms: Option<u32>,
+    /// Time left on the boost the action named, if it is live.
+    pub boost_left_ms: Option<u64>,
+    /// Free memory gained since before the action (negative if lost).
+    pub freed_kib: i64,
+}
+
+/// The measured effect of `a` if what was observed shows it took hold:
+/// the slice in µs, the level, the interval in ms, the boost's ms left,
+/// the agent's CPU share or the KiB reclaimed.
+pub fn check_effect(a: &Action, seen: &Observed) -> Option<u32> {
+    match a.kind {
+        x if x == ActionType::SetQuantum as u8 => (seen.slice_us == a.param1).then_some(seen.slice_us as u32),
+        x if x == ActionType::TrimCache as u8 => (seen.freed_kib >= 0).then_some(seen.freed_kib as u32),
+        x if x == ActionType::SetLogLevel as u8 => (seen.log_level as u64 == a.param1).then_some(seen.log_level as u32),
+        x if x == ActionType::SetPollingInterval as u8 => {
+            let now = match a.param1 {
+                poll_target::AI_INFERENCE => Some(seen.ai_interval_ms),
+                _ => seen.hid_pace_ms,
+            };
+            now.filter(|&ms| ms as u64 == a.param2)
+        }
+        x if x == ActionType::BoostTask as u8 => {
+            seen.boost_left_ms.filter(|&left| left <= a.param2).map(|left| left as u32)
+        }
+        x if x == ActionType::SetCpuBudget as u8 => (seen.ai_cpu_pct as u64 == a.param1).then_some(seen.ai_cpu_pct),
+        _ => None,
+    }
+}
+
+/// The machine actions run against.
+pub trait Target {
+    /// Everything an action can change, captured so a failed self-test can
+    /// put it back.
+    type Snapshot: Copy;
+
+    /// Whether actions are accepted at all now.
+    fn ready(&self) -> bool;
+    fn snapshot(&mut self) -> Self::Snapshot;
+    fn restore(&mut self, before: Self::Snapshot);
+    /// Carry out an admitted action. On error nothing may have changed:
+    /// the knobs are not put back.
+    fn execute(&mut self, a: &Action) -> ApplyResult<()>;
+    /// Check the system is healthy and the action took hold; the effect
+    /// measured goes to the journal.
+    fn self_test(&mut self, a: &Action, before: &Self::Snapshot) -> ApplyResult<u32>;
+}
+
+/// Where the gate records what it does.
+pub trait Journal {
+    fn reject(&mut self, seq: u64, a: &Action);
+    fn intent(&mut self, seq: u64, a: &Action);
+    /// `effect` is what the self-test measured once the action took hold.
+    fn commit(&mut self, seq: u64, a: &Action, effect: u32);
+    fn fail(&mut self, seq: u64, a: &Action, code: u32);
+}
+
+/// Run `a` through the gate as action `seq`. Every INTENT is followed by
+/// APPLY_OK or APPLY_FAIL unless the machine goes down in between, which
+/// `journal::pair_records` reports as a dangling intent on the next boot.
+pub fn apply(seq: u64, a: &Action, caller: Caller, target: &mut impl Target, journal: &mut impl Journal) -> ApplyResult<()> {
+    if let Err(e) = admit(a, caller, target.ready()) {
+        journal.reject(seq, a);
+        return Err(e);
+    }
+    let before = target.snapshot();
+    journal.intent(seq, a);
+    if let Err(e) = target.execute(a) {
+        journal.fail(seq, a, e as u32);
+        return Err(e);
+    }
+    match target.self_test(a, &before) {
+        Ok(effect) => {
+            journal.commit(seq, a, effect);
+            Ok(())
+        }
+        Err(e) => {
+            target.restore(before);
+            journal.fail(seq, a, e as u32);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(kind: ActionType, param1: u64, param2: u64) -> Action {
+        Action { kind: kind as u8, param1, param2, ..Action::default() }
+    }
+
+    #[test]
+    fn new_actions_are_validated() {
+        assert!(validate_params(&action(ActionType::SetLogLevel, 3, 0)));
+        assert!(!validate_params(&action(ActionType::SetLogLevel, 4, 0)));
+        assert!(!validate_params(&action(ActionType::SetLogLevel, 256 + 2, 0)));
+        assert!(validate_params(&action(ActionType::SetPollingInterval, poll_target::HID, 0)));
+        assert!(validate_params(&action(ActionType::SetPollingInterval, poll_target::AI_INFERENCE, 5_000)));
+        assert!(!validate_params(&action(ActionType::SetPollingInterval, poll_target::AI_INFERENCE, 5_001)));
+        assert!(!validate_params(&action(ActionType::SetPollingInterval, 7, 10)));
+        assert!(validate_params(&action(ActionType::SetCpuBudget, 25, 0)));
+        assert!(!validate_params(&action(ActionType::SetCpuBudget, 26, 0)));
+        assert!(!validate_params(&action(ActionType::SetCpuBudget, 0, 0)));
+        assert!(!user_policy_allows(&action(ActionType::SetLogLevel, 1, 0)));
+    }
+
+    #[test]
+    fn effect_must_match_the_action() {
+        let seen = Observed { slice_us: 2_000, log_level: 3, ai_cpu_pct: 10, boost_left_ms: Some(900), freed_kib: 64, ..Observed::default() };
+        assert_eq!(check_effect(&action(ActionType::SetQuantum, 2_000, 0), &seen), Some(2_000));
+        assert_eq!(check_effect(&action(ActionType::SetQuantum, 60_000, 0), &seen), None);
+        assert_eq!(check_effect(&action(ActionType::SetLogLevel, 3, 0), &seen), Some(3));
+        assert_eq!(check_effect(&action(ActionType::TrimCache, 4096, 0), &seen), Some(64));
+        assert_eq!(check_effect(&action(ActionType::TrimCache, 4096, 0), &Observed { freed_kib: -4, ..seen }), None);
+        assert_eq!(check_effect(&action(ActionType::SetPollingInterval, poll_target::HID, 8), &seen), None);
+        assert_eq!(check_effect(&action(ActionType::SetPollingInterval, poll_target::AI_INFERENCE, 0), &seen), Some(0));
+        assert_eq!(check_effect(&action(ActionType::BoostTask, 0, 1_000), &seen), Some(900));
+        assert_eq!(check_effect(&action(ActionType::BoostTask, 0, 1_000), &Observed { boost_left_ms: None, ..seen }), None);
+        assert_eq!(check_effect(&action(ActionType::SetCpuBudget, 10, 0), &seen), Some(10));
+        assert_eq!(check_effect(&action(ActionType::SetCpuBudget, 20, 0), &seen), None);
+    }
+}
@@ -0,0 +1,264 @@
+//! USB standard descriptors (USB 2.0 §9.6) as typed structs, and an
+//! iterator over a descriptor blob such as a full configuration descriptor.
+//!
+//! Every parser checks `bLength` and `bDescriptorType` and the bytes
+//! actually available, so a short or lying descriptor yields `None` rather
+//! than an out-of-bounds read.
+
+pub const DESC_DEVICE: u8 = 1;
+pub const DESC_CONFIGURATION: u8 = 2;
+pub const DESC_INTERFACE: u8 = 4;
+pub const DESC_ENDPOINT: u8 = 5;
+
+pub const TRANSFER_CONTROL: u8 = 0;
+pub const TRANSFER_ISOCHRONOUS: u8 = 1;
+pub const TRANSFER_BULK: u8 = 2;
+pub const TRANSFER_INTERRUPT: u8 = 3;
+
+fn le16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+/// `bytes` if it starts with a descriptor of `kind` at least `min_len` long.
+fn checked(bytes: &[u8], kind: u8, min_len: usize) -> Option<&[u8]> {
+    let len = *bytes.first()? as usize;
+    if len < min_len || len > bytes.len() || bytes[1] != kind {
+        return None;
+    }
+    Some(&bytes[..len])
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DeviceDescriptor {
+    pub usb_version: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub max_packet0: u8,
+    pub vendor: u16,
+    pub product: u16,
+    pub device_version: u16,
+    pub num_configurations: u8,
+}
+
+impl DeviceDescriptor {
+    pub const LEN: usize = 18;
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let d = checked(bytes, DESC_DEVICE, Self::LEN)?;
+        Some(DeviceDescriptor {
+            usb_version: le16(d, 2),
+            class: d[4],
+            subclass: d[5],
+            protocol: d[6],
+            max_packet0: d[7],
+            vendor: le16(d, 8),
+            product: le16(d, 10),
+            device_version: le16(d, 12),
+            num_configurations: d[17],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ConfigDescriptor {
+    /// Length of the configuration with all the descriptors that follow it.
+    pub total_length: u16,
+    pub num_interfaces: u8,
+    pub value: u8,
+    pub attributes: u8,
+    /// In 2 mA units (8 mA for SuperSpeed).
+    pub max_power: u8,
+}
+
+impl ConfigDescriptor {
+    pub const LEN: usize = 9;
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let d = checked(bytes, DESC_CONFIGURATION, Self::LEN)?;
+        Some(ConfigDescriptor {
+            total_length: le16(d, 2),
+            num_interfaces: d[4],
+            value: d[5],
+            attributes: d[7],
+            max_power: d[8],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct InterfaceDescriptor {
+    pub number: u8,
+    pub alternate: u8,
+    pub num_endpoints: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+}
+
+impl InterfaceDescriptor {
+    pub const LEN: usize = 9;
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let d = checked(bytes, DESC_INTERFACE, Self::LEN)?;
+        Some(InterfaceDescriptor {
+            number: d[2],
+            alternate: d[3],
+            num_endpoints: d[4],
+            class: d[5],
+            subclass: d[6],
+            protocol: d[7],
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct EndpointDescriptor {
+    pub address: u8,
+    pub attributes: u8,
+    pub max_packet: u16,
+    pub interval: u8,
+}
+
+impl EndpointDescriptor {
+    pub const LEN: usize = 7;
+
+    pub fn is_in(&self) -> bool {
+        self.address & 0x80 != 0
+    }
+
+    pub fn is_interrupt(&self) -> bool {
+        self.transfer_type() == TRANSFER_INTERRUPT
+    }
+
+    /// `bmAttributes` 1:0, one of the `TRANSFER_*` values.
+    pub fn transfer_type(&self) -> u8 {
+        self.attributes & 0x3
+    }
+
+    /// Bytes per packet, `wMaxPacketSize` 10:0.
+    pub fn packet_size(&self) -> u16 {
+        self.max_packet & 0x7FF
+    }
+
+    /// Extra packets per microframe for high-speed periodic endpoints,
+    /// `wMaxPacketSize` 12:11.
+    pub fn additional_transactions(&self) -> u8 {
+        ((self.max_packet >> 11) & 0x3) as u8
+    }
+
+    pub fn parse(bytes: &[u8]) -> Option<Self> {
+        let d = checked(bytes, DESC_ENDPOINT, Self::LEN)?;
+        Some(EndpointDescriptor { address: d[2], attributes: d[3], max_packet: le16(d, 4), interval: d[6] })
+    }
+}
+
+/// One descriptor out of a blob, `bytes` covering exactly its `bLength`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Descriptor<'a> {
+    pub kind: u8,
+    pub bytes: &'a [u8],
+}
+
+/// A descriptor known to this module, or the raw bytes of any other.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Parsed<'a> {
+    Device(DeviceDescriptor),
+    Configuration(ConfigDescriptor),
+    Interface(InterfaceDescriptor),
+    Endpoint(EndpointDescriptor),
+    Other(Descriptor<'a>),
+}
+
+impl<'a> Descriptor<'a> {
+    /// Typed view; a known type that is too short comes back as `Other`.
+    pub fn parse(self) -> Parsed<'a> {
+        let typed = match self.kind {
+            DESC_DEVICE => DeviceDescriptor::parse(self.bytes).map(Parsed::Device),
+            DESC_CONFIGURATION => ConfigDescriptor::parse(self.bytes).map(Parsed::Configuration),
+            DESC_INTERFACE => InterfaceDescriptor::parse(self.bytes).map(Parsed::Interface),
+            DESC_ENDPOINT => EndpointDescriptor::parse(self.bytes).map(Parsed::Endpoint),
+            _ => None,
+        };
+        typed.unwrap_or(Parsed::Other(self))
+    }
+}
+
+/// Iterator over back-to-back descriptors. A `bLength` below 2 or running
+/// past the end of the blob ends the iteration.
+pub struct Descriptors<'a> {
+    rest: &'a [u8],
+}
+
+pub fn iter(blob: &[u8]) -> Descriptors<'_> {
+    Descriptors { rest: blob }
+}
+
+impl<'a> Iterator for Descriptors<'a> {
+    type Item = Descriptor<'a>;
+
+    fn next(&mut self) -> Option<Descriptor<'a>> {
+        if self.rest.len() < 2 {
+            return None;
+        }
+        let len = self.rest[0] as usize;
+        if len < 2 || len > self.rest.len() {
+            self.rest = &[];
+            return None;
+        }
+        let (bytes, rest) = self.rest.split_at(len);
+        self.rest = rest;
+        Some(Descriptor { kind: bytes[1], bytes })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Logitech K120 keyboard (046d:c31c): boot keyboard plus a second HID
+    // interface for the media keys
+    const K120_DEVICE: &[u8] = &[
+        0x12, 0x01, 0x10, 0x01, 0x00, 0x00, 0x00, 0x08, 0x6D, 0x04, 0x1C, 0xC3, 0x10, 0x64, 0x01, 0x02, 0x00, 0x01,
+    ];
+    const K120_CONFIG: &[u8] = &[
+        0x09, 0x02, 0x3B, 0x00, 0x02, 0x01, 0x00, 0xA0, 0x2D, // configuration
+        0x09, 0x04, 0x00, 0x00, 0x01, 0x03, 0x01, 0x01, 0x00, // interface 0: boot keyboard
+        0x09, 0x21, 0x10, 0x01, 0x00, 0x01, 0x22, 0x41, 0x00, // HID
+        0x07, 0x05, 0x81, 0x03, 0x08, 0x00, 0x0A, // EP1 IN interrupt
+        0x09, 0x04, 0x01, 0x00, 0x01, 0x03, 0x00, 0x00, 0x00, // interface 1: HID, no boot protocol
+        0x09, 0x21, 0x10, 0x01, 0x00, 0x01, 0x22, 0xB1, 0x00, // HID
+        0x07, 0x05, 0x82, 0x03, 0x04, 0x00, 0xFF, // EP2 IN interrupt
+    ];
+
+    #[test]
+    fn parses_device_descriptor() {
+        let d = DeviceDescriptor::parse(K120_DEVICE).unwrap();
+        assert_eq!((d.vendor, d.product), (0x046D, 0xC31C));
+        assert_eq!((d.usb_version, d.max_packet0, d.num_configurations), (0x0110, 8, 1));
+        assert_eq!(DeviceDescriptor::parse(&K120_DEVICE[..17]), None);
+        assert_eq!(ConfigDescriptor::parse(K120_DEVICE), None);
+    }
+
+    #[test]
+    fn walks_a_configuration() {
+        let mut it = iter(K120_CONFIG).map(Descriptor::parse);
+        let Some(Parsed::Configuration(c)) = it.next() else { panic!("no configuration") };
+        assert_eq!((c.total_length as usize, c.num_interfaces, c.value), (K120_CONFIG.len(), 2, 1));
+        assert!(matches!(it.next(), Some(Parsed::Interface(InterfaceDescriptor { number: 0, class: 3, subclass: 1, protocol: 1, .. }))));
+        assert!(matches!(it.next(), Some(Parsed::Other(Descriptor { kind: 0x21, .. }))));
+        assert_eq!(it.next(), Some(Parsed::Endpoint(EndpointDescriptor { address: 0x81, attributes: 3, max_packet: 8, interval: 10 })));
+        assert_eq!(it.count(), 3);
+    }
+
+    #[test]
+    fn malformed_lengths_end_the_walk() {
+        // bLength past the end of the blob
+        assert_eq!(iter(&K120_CONFIG[..20]).count(), 2);
+        // bLength of zero would otherwise loop forever
+        assert_eq!(iter(&[9, 2, 0, 0, 0, 0, 0, 0, 0, 0, 4]).count(), 1);
+        // an interface descriptor claiming 7 bytes is not an interface
+        let short = [7, DESC_INTERFACE, 0, 0, 1, 3, 1];
+        assert!(matches!(iter(&short).next().map(Descriptor::parse), Some(Parsed::Other(_))));
+    }
+}
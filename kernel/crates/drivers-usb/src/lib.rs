@@ -0,0 +1,7 @@
+//! USB pieces that do not need the controller: descriptor parsing for now.
+//! The xHCI driver and class drivers stay in the kernel until they reach
+//! the hardware through an interface instead of `crate::` paths.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod desc;
@@ -0,0 +1,333 @@
+//! ACPI tables and the memory they live in.
+//!
+//! Firmware puts most tables in "ACPI reclaimable" memory, which is ours
+//! once the tables have been read. `init` finds the RSDP (from stage2 or by
+//! scanning the BIOS area), copies every table the RSDT/XSDT lists, plus the
+//! DSDT, into one pmm block, and only then hands the reclaimable regions to
+//! the pmm. Later users look tables up with `find`, which returns the copy;
+//! physical pointers inside the copies (FADT -> DSDT) still name the
+//! originals and must not be followed.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::bootinfo::{BootInfo, MemoryRegionKind};
+use crate::{addr, pmm, serial};
+use crate::status::{self, Health};
+
+pub const MAX_TABLES: usize = 32;
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+const RSDP_V1_LEN: usize = 20;
+const RSDP_V2_LEN: usize = 36;
+const SDT_HEADER_LEN: usize = 36;
+/// Tables larger than this are taken as corrupt rather than copied.
+const MAX_TABLE_LEN: u32 = 1 << 20;
+const BIOS_AREA: (u64, u64) = (0xE_0000, 0x10_0000);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Table {
+    pub signature: [u8; 4],
+    /// Where the firmware put it.
+    pub original: u64,
+    /// The copy in pmm memory.
+    pub phys: u64,
+    pub len: u32,
+}
+
+static TABLES: Mutex<[Option<Table>; MAX_TABLES]> = Mutex::new([None; MAX_TABLES]);
+static RECLAIMABLE_BYTES: AtomicU64 = AtomicU64::new(0);
+static RECLAIMED_BYTES: AtomicU64 = AtomicU64::new(0);
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> u64 {
+    read_u32(bytes, at) as u64 | (read_u32(bytes, at + 4) as u64) << 32
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Root table address and whether it is an XSDT (64-bit entries).
+fn parse_rsdp(bytes: &[u8]) -> Option<(u64, bool)> {
+    if bytes.len() < RSDP_V1_LEN || &bytes[..8] != RSDP_SIGNATURE || !checksum_ok(&bytes[..RSDP_V1_LEN]) {
+        return None;
+    }
+    let revision = bytes[15];
+    if revision >= 2 && bytes.len() >= RSDP_V2_LEN && checksum_ok(&bytes[..RSDP_V2_LEN]) {
+        let xsdt = read_u64(bytes, 24);
+        if xsdt != 0 {
+            return Some((xsdt, true));
+        }
+    }
+    Some((read_u32(bytes, 16) as u64, false))
+}
+
+/// Table addresses listed by an RSDT (`wide` false) or XSDT body.
+fn root_entries(table: &[u8], wide: bool) -> impl Iterator<Item = u64> + '_ {
+    let step = if wide { 8 } else { 4 };
+    table
+        .get(SDT_HEADER_LEN..)
+        .unwrap_or(&[])
+        .chunks_exact(step)
+        .map(move |e| if wide { read_u64(e, 0) } else { read_u32(e, 0) as u64 })
+}
+
+/// DSDT address from a FADT, preferring X_DSDT when present.
+fn fadt_dsdt(fadt: &[u8]) -> Option<u64> {
+    if fadt.len() >= 148 {
+        let x = read_u64(fadt, 140);
+        if x != 0 {
+            return Some(x);
+        }
+    }
+    (fadt.len() >= 44).then(|| read_u32(fadt, 40) as u64).filter(|&a| a != 0)
+}
+
+/// The whole table at `phys`, if its header and checksum hold.
+fn table_at(phys: u64) -> Option<&'static [u8]> {
+    let len = read_u32(addr::phys_slice(phys, SDT_HEADER_LEN)?, 4);
+    if (len as usize) < SDT_HEADER_LEN || len > MAX_TABLE_LEN {
+        return None;
+    }
+    let table = addr::phys_slice(phys, len as usize)?;
+    checksum_ok(table).then_some(table)
+}
+
+fn scan_for_rsdp() -> Option<u64> {
+    let area = addr::phys_slice(BIOS_AREA.0, (BIOS_AREA.1 - BIOS_AREA.0) as usize)?;
+    (0..area.len() - RSDP_V1_LEN)
+        .step_by(16)
+        .find(|&at| parse_rsdp(&area[at..]).is_some())
+        .map(|at| BIOS_AREA.0 + at as u64)
+}
+
+/// Copy the tables, then release ACPI reclaimable memory to the pmm if
+/// every one of them could be copied.
+pub fn init(boot: &BootInfo) {
+    let reclaimable: u64 = unsafe { boot.memory_map() }
+        .filter(|r| r.kind() == MemoryRegionKind::AcpiReclaimable)
+        .map(|r| r.length)
+        .sum();
+    RECLAIMABLE_BYTES.store(reclaimable, Ordering::Relaxed);
+
+    let Some(rsdp) = boot.rsdp.or_else(scan_for_rsdp) else {
+        serial::write_str("[acpi] no RSDP, reclaimable memory left alone\r\n");
+        status::set("acpi", Health::Skipped, "no RSDP");
+        return;
+    };
+    let Some((root, wide)) = addr::phys_slice(rsdp, RSDP_V2_LEN).and_then(parse_rsdp) else {
+        serial::write_fmt(format_args!("[acpi] bad RSDP at {:#x}\r\n", rsdp));
+        status::set("acpi", Health::Failed, "bad RSDP");
+        return;
+    };
+    let Some(root_table) = table_at(root) else {
+        serial::write_fmt(format_args!("[acpi] bad root table at {:#x}\r\n", root));
+        status::set("acpi", Health::Failed, "bad root table");
+        return;
+    };
+
+    // Everything to keep: the root table, what it lists, and the DSDT. A
+    // table left behind (past MAX_TABLES, above the identity map, or bad)
+    // may live in reclaimable memory, which then has to stay as it is
+    let mut found = [0u64; MAX_TABLES];
+    let mut count = 0;
+    let mut missed = 0;
+    let mut push = |phys: u64| {
+        if found[..count].contains(&phys) {
+            return;
+        }
+        if count < MAX_TABLES && table_at(phys).is_some() {
+            found[count] = phys;
+            count += 1;
+        } else {
+            missed += 1;
+        }
+    };
+    push(root);
+    for entry in root_entries(root_table, wide) {
+        push(entry);
+        if let Some(dsdt) = table_at(entry).filter(|t| &t[..4] == b"FACP").and_then(fadt_dsdt) {
+            push(dsdt);
+        }
+    }
+
+    if !copy_tables(&found[..count]) {
+        serial::write_str("[acpi] no memory to copy the tables, reclaimable memory left alone\r\n");
+        return;
+    }
+    if missed > 0 {
+        serial::write_fmt(format_args!(
+            "[acpi] {} tables copied, {} not, reclaimable memory left alone\r\n",
+            count, missed
+        ));
+        return;
+    }
+    let mut reclaimed = 0;
+    for region in unsafe { boot.memory_map() } {
+        if region.kind() == MemoryRegionKind::AcpiReclaimable {
+            reclaimed += pmm::add_region(region.base_addr, region.length);
+        }
+    }
+    RECLAIMED_BYTES.store(reclaimed, Ordering::Relaxed);
+    serial::write_fmt(format_args!(
+        "[acpi] {} tables copied, {} of {} KiB reclaimable memory given to the pmm\r\n",
+        count,
+        reclaimed / 1024,
+        reclaimable / 1024
+    ));
+}
+
+fn copy_tables(found: &[u64]) -> bool {
+    let align = |len: u64| (len + 7) & !7;
+    let total: u64 = found.iter().filter_map(|&p| table_at(p)).map(|t| align(t.len() as u64)).sum();
+    let Some(block) = pmm::alloc_for("acpi.tables", total.max(1), 8) else {
+        return false;
+    };
+    let mut tables = TABLES.lock();
+    let mut at = block;
+    for (slot, &original) in tables.iter_mut().zip(found) {
+        let Some(table) = table_at(original) else { continue };
+        let dst = unsafe { core::slice::from_raw_parts_mut(addr::PhysAddr::new(at).as_mut_ptr::<u8>(), table.len()) };
+        dst.copy_from_slice(table);
+        *slot = Some(Table {
+            signature: [table[0], table[1], table[2], table[3]],
+            original,
+            phys: at,
+            len: table.len() as u32,
+        });
+        at += align(table.len() as u64);
+    }
+    true
+}
+
+/// SLP_TYPa and SLP_TYPb of the `\_S5_` package in a DSDT: a name,
+/// PackageOp, its length, the element count, then the two values as
+/// BytePrefix constants or Zero/One.
+fn s5_sleep_types(dsdt: &[u8]) -> Option<(u8, u8)> {
+    let at = dsdt.windows(4).position(|w| w == b"_S5_")? + 4;
+    if *dsdt.get(at)? != 0x12 {
+        return None;
+    }
+    // PkgLength: the top two bits of the lead byte count the bytes after it
+    let mut at = at + 2 + (*dsdt.get(at + 1)? >> 6) as usize + 1;
+    let mut value = || -> Option<u8> {
+        let v = match *dsdt.get(at)? {
+            0x0A => {
+                at += 1;
+                *dsdt.get(at)?
+            }
+            v @ (0x00 | 0x01) => v,
+            _ => return None,
+        };
+        at += 1;
+        Some(v)
+    };
+    Some((value()?, value()?))
+}
+
+const FADT_PM1A_CNT: usize = 64;
+const FADT_PM1B_CNT: usize = 68;
+const SLP_EN: u16 = 1 << 13;
+
+/// Put the machine in S5 (soft off) through the FADT's PM1 control
+/// registers. Only returns if that did not work.
+pub fn enter_s5() -> Result<(), &'static str> {
+    let fadt = find(b"FACP").filter(|f| f.len() >= FADT_PM1B_CNT + 4).ok_or("no FADT")?;
+    let (typ_a, typ_b) = find(b"DSDT").and_then(s5_sleep_types).ok_or("no \\_S5 in the DSDT")?;
+    let pm1a = read_u32(fadt, FADT_PM1A_CNT) as u16;
+    let pm1b = read_u32(fadt, FADT_PM1B_CNT) as u16;
+    if pm1a == 0 {
+        return Err("no PM1a control block");
+    }
+    unsafe {
+        Port::<u16>::new(pm1a).write(((typ_a as u16) << 10) | SLP_EN);
+        if pm1b != 0 {
+            Port::<u16>::new(pm1b).write(((typ_b as u16) << 10) | SLP_EN);
+        }
+    }
+    Err("still running after S5")
+}
+
+/// The copy of the first table with `signature`.
+pub fn find(signature: &[u8; 4]) -> Option<&'static [u8]> {
+    let tables = TABLES.lock();
+    let t = tables.iter().flatten().find(|t| &t.signature == signature)?;
+    addr::phys_slice(t.phys, t.len as usize)
+}
+
+pub fn for_each_table(mut f: impl FnMut(&Table)) {
+    for t in TABLES.lock().iter().flatten() {
+        f(t);
+    }
+}
+
+/// (ACPI reclaimable bytes in the memory map, bytes handed to the pmm)
+pub fn reclaim_stats() -> (u64, u64) {
+    (RECLAIMABLE_BYTES.load(Ordering::Relaxed), RECLAIMED_BYTES.load(Ordering::Relaxed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_checksum<const N: usize>(mut bytes: [u8; N], at: usize, len: usize) -> [u8; N] {
+        bytes[at] = 0;
+        let sum = bytes[..len].iter().fold(0u8, |s, &b| s.wrapping_add(b));
+        bytes[at] = 0u8.wrapping_sub(sum);
+        bytes
+    }
+
+    #[test]
+    fn rsdp_picks_xsdt_when_valid() {
+        let mut v1 = [0u8; RSDP_V2_LEN];
+        v1[..8].copy_from_slice(RSDP_SIGNATURE);
+        v1[16..20].copy_from_slice(&0x7FE_1000u32.to_le_bytes());
+        let v1 = with_checksum(v1, 8, RSDP_V1_LEN);
+        assert_eq!(parse_rsdp(&v1), Some((0x7FE_1000, false)));
+
+        let mut v2 = v1;
+        v2[15] = 2;
+        v2[24..32].copy_from_slice(&0x7FE_2000u64.to_le_bytes());
+        let v2 = with_checksum(with_checksum(v2, 8, RSDP_V1_LEN), 32, RSDP_V2_LEN);
+        assert_eq!(parse_rsdp(&v2), Some((0x7FE_2000, true)));
+
+        let mut bad = v1;
+        bad[16] ^= 1;
+        assert_eq!(parse_rsdp(&bad), None);
+    }
+
+    #[test]
+    fn s5_package_values() {
+        // Name (\_S5_, Package (0x04) { 0x05, Zero, ... })
+        let dsdt = [0x08, b'\\', b'_', b'S', b'5', b'_', 0x12, 0x08, 0x04, 0x0A, 0x05, 0x00, 0x00, 0x00];
+        assert_eq!(s5_sleep_types(&dsdt), Some((5, 0)));
+        let two_byte_len = [b'_', b'S', b'5', b'_', 0x12, 0x40, 0x00, 0x02, 0x01, 0x0A, 0x07];
+        assert_eq!(s5_sleep_types(&two_byte_len), Some((1, 7)));
+        assert_eq!(s5_sleep_types(b"_S5_\x10"), None);
+        assert_eq!(s5_sleep_types(b"no sleep states"), None);
+    }
+
+    #[test]
+    fn root_table_entries() {
+        let mut rsdt = [0u8; SDT_HEADER_LEN + 8];
+        rsdt[SDT_HEADER_LEN..SDT_HEADER_LEN + 4].copy_from_slice(&0x1000u32.to_le_bytes());
+        rsdt[SDT_HEADER_LEN + 4..].copy_from_slice(&0x2000u32.to_le_bytes());
+        let mut out = [0u64; 2];
+        for (o, e) in out.iter_mut().zip(root_entries(&rsdt, false)) {
+            *o = e;
+        }
+        assert_eq!(out, [0x1000, 0x2000]);
+        assert_eq!(root_entries(&rsdt, true).next(), Some(0x2000_0000_1000));
+
+        let mut fadt = [0u8; 148];
+        fadt[40..44].copy_from_slice(&0x3000u32.to_le_bytes());
+        assert_eq!(fadt_dsdt(&fadt), Some(0x3000));
+        fadt[140..148].copy_from_slice(&0x1_0000_3000u64.to_le_bytes());
+        assert_eq!(fadt_dsdt(&fadt), Some(0x1_0000_3000));
+        assert_eq!(fadt_dsdt(&fadt[..40]), None);
+    }
+}
@@ -0,0 +1,184 @@
+//! Physical, DMA and kernel-virtual address types.
+//!
+//! Stage2 identity-maps the first 4 GiB and nothing sits between devices
+//! and RAM, so today every conversion here is the identity. Drivers go
+//! through these helpers instead of casting integers to pointers, so that
+//! assumption lives in this file only: a higher-half direct map or an IOMMU
+//! changes `to_virt`/`to_dma`, not the drivers.
+//!
+//! These are distinct from `x86_64::{PhysAddr, VirtAddr}`, which describe
+//! page-table contents; refer to ours as `addr::PhysAddr` etc.
+
+#![allow(dead_code)]
+
+use core::fmt;
+
+use crate::klog::{self, Level};
+use crate::vmm;
+
+/// End of the boot identity map (PML4[0], 2 MiB pages).
+pub const IDENTITY_LIMIT: u64 = 4 << 30;
+
+/// Address of RAM or MMIO as seen by the CPU's physical bus.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct PhysAddr(u64);
+
+/// Address a device uses to reach memory (bus address).
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct DmaAddr(u64);
+
+/// Kernel virtual address, dereferenceable in the current address space.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+#[repr(transparent)]
+pub struct VirtAddr(u64);
+
+impl PhysAddr {
+    pub const fn new(addr: u64) -> Self {
+        PhysAddr(addr)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub const fn offset(self, bytes: u64) -> Self {
+        PhysAddr(self.0 + bytes)
+    }
+
+    /// Kernel mapping of this physical address, if one exists.
+    pub fn to_virt(self) -> Option<VirtAddr> {
+        if self.0 < IDENTITY_LIMIT {
+            Some(VirtAddr(self.0))
+        } else {
+            None
+        }
+    }
+
+    /// Pointer to this physical address through the kernel mapping, for
+    /// memory an allocator handed out (always below `IDENTITY_LIMIT`).
+    ///
+    /// Anything past the mapping is a kernel bug, checked as `kassert!`
+    /// does: debug builds panic, release builds log it and return the
+    /// unmapped identity pointer, which faults on first use. An address
+    /// from outside (firmware tables, a page-table entry) goes through
+    /// `to_virt` and `kensure!` instead.
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        if let Some(v) = self.to_virt() {
+            return v.as_mut_ptr();
+        }
+        if cfg!(debug_assertions) {
+            panic!("addr: phys {:#x} outside the kernel mapping", self.0);
+        }
+        klog::log(Level::Error, format_args!("[addr] phys {:#x} outside the kernel mapping\r\n", self.0));
+        self.0 as *mut T
+    }
+
+    /// Bus address for programming a device with this buffer.
+    pub const fn to_dma(self) -> DmaAddr {
+        DmaAddr(self.0)
+    }
+}
+
+impl DmaAddr {
+    pub const fn new(addr: u64) -> Self {
+        DmaAddr(addr)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    /// Physical address behind a bus address reported by a device.
+    pub const fn to_phys(self) -> PhysAddr {
+        PhysAddr(self.0)
+    }
+}
+
+impl VirtAddr {
+    pub const fn new(addr: u64) -> Self {
+        VirtAddr(addr)
+    }
+
+    pub fn from_ptr<T>(ptr: *const T) -> Self {
+        VirtAddr(ptr as u64)
+    }
+
+    pub const fn as_u64(self) -> u64 {
+        self.0
+    }
+
+    pub fn as_ptr<T>(self) -> *const T {
+        self.0 as *const T
+    }
+
+    pub fn as_mut_ptr<T>(self) -> *mut T {
+        self.0 as *mut T
+    }
+
+    /// Physical address backing this virtual address. Identity-mapped
+    /// addresses resolve directly; anything else walks the active tables.
+    pub fn to_phys(self) -> Option<PhysAddr> {
+        if self.0 < IDENTITY_LIMIT {
+            return Some(PhysAddr(self.0));
+        }
+        vmm::translate_active(self.0).map(PhysAddr)
+    }
+}
+
+/// `len` bytes of physical memory from outside the allocators (firmware
+/// tables, the BIOS area); `None` for a null address or a range that runs
+/// past the kernel mapping.
+pub fn phys_slice(phys: u64, len: usize) -> Option<&'static [u8]> {
+    let end = phys.checked_add(len as u64)?;
+    if phys == 0 || end > IDENTITY_LIMIT {
+        return None;
+    }
+    let ptr = PhysAddr::new(phys).to_virt()?.as_ptr::<u8>();
+    Some(unsafe { core::slice::from_raw_parts(ptr, len) })
+}
+
+impl fmt::LowerHex for PhysAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::LowerHex for DmaAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+impl fmt::LowerHex for VirtAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::LowerHex::fmt(&self.0, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_region_round_trips() {
+        let p = PhysAddr::new(0x20_0000);
+        assert_eq!(p.to_virt(), Some(VirtAddr::new(0x20_0000)));
+        assert_eq!(p.to_dma().to_phys(), p);
+        assert_eq!(VirtAddr::new(0x20_0000).to_phys(), Some(p));
+    }
+
+    #[test]
+    fn high_physical_is_unmapped() {
+        assert_eq!(PhysAddr::new(IDENTITY_LIMIT).to_virt(), None);
+    }
+
+    #[test]
+    fn phys_slices_stay_inside_the_mapping() {
+        assert!(phys_slice(0, 16).is_none());
+        assert!(phys_slice(IDENTITY_LIMIT - 8, 16).is_none());
+        assert!(phys_slice(u64::MAX - 4, 16).is_none());
+        assert!(phys_slice(IDENTITY_LIMIT - 16, 0).is_some());
+    }
+}
@@ -1,21 +1,18 @@
 #![allow(dead_code)]
 
-use core::ptr::NonNull;
-use core::sync::atomic::{AtomicBool, Ordering};
-
-use crate::ai_action::{actf, Action, ActionOutcome, ActionType};
-use crate::ai_model::{ModelHeader, WeightsLayout, layer_ptr_int8, layer_dims, bias_ptr_i32};
-use crate::ai_link::AI_MODEL_LEN;
-use crate::{idt, pmm};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::ai_action::{self, actf, Action, ActionOutcome, ActionType};
+use crate::ai_backend::{self, InferenceBackend, Invalid};
+use crate::ai_model::ModelInfo;
+use crate::klog::{self, Level};
+pub use ai_core::matmul::matmul_int8;
+use crate::telemetry::{self, Telemetry, TRACE_MAGIC, TRACE_RECORD_LEN};
+use crate::{ai_canary, ai_heuristic, ai_link, apply_action, config, executor, journal, ktrace, kv, ramfs, status, time};
 
 // --- IA config (ajustable via features) ---
-#[cfg(feature = "ai_cfg_aggr")]
-const REQUANT_SHIFT: i32 = 5;
-#[cfg(all(not(feature = "ai_cfg_aggr"), not(feature = "ai_cfg_conservative")))]
-const REQUANT_SHIFT: i32 = 6;
-#[cfg(feature = "ai_cfg_conservative")]
-const REQUANT_SHIFT: i32 = 6;
-
 #[cfg(feature = "ai_cfg_aggr")]
 const QUANTUM_BASE_US: i32 = 800;
 #[cfg(all(not(feature = "ai_cfg_aggr"), not(feature = "ai_cfg_conservative")))]
@@ -42,18 +39,236 @@ const PF_RATE_THRESH: u32 = 0;
 
 const TRIM_BYTES: u64 = 1 * 1024 * 1024;
 
+/// Mean keypress-to-echo latency above which the shell gets boosted.
+const INPUT_LATENCY_THRESH_US: u32 = 50_000;
+const SHELL_BOOST_MS: u64 = 1_000;
+
 static AI_RUNNING: AtomicBool = AtomicBool::new(true);
+/// Set by the shutdown hook: no proposal may start once the system goes down.
+static PARKED: AtomicBool = AtomicBool::new(false);
+/// Set once the agent task is registered; `ai.mode` changes show in
+/// `status` only then.
+static SCHEDULED: AtomicBool = AtomicBool::new(false);
+/// Proposals journaled in observe mode, numbering their records.
+static OBSERVED: AtomicU64 = AtomicU64::new(0);
+/// What the loaded model says it is, for the shell and crash reports.
+static MODEL_INFO: Mutex<Option<ModelInfo>> = Mutex::new(None);
+
+/// Cycle budget of one inference step, in µs (`ai.budget_us`).
+const DEFAULT_BUDGET_US: u64 = 2_000;
+/// Over-budget steps in a row after which the agent stops (`ai.slow_limit`).
+const DEFAULT_SLOW_LIMIT: u64 = 3;
+
+/// Consecutive steps abandoned for running over budget.
+static VIOLATIONS: AtomicU32 = AtomicU32::new(0);
+
+/// kv key of the outcome counters, kept across reboots.
+const STATS_KEY: &str = "ai.stats";
+/// Steps between two writes of the counters to the kv store.
+const STATS_PERSIST_EVERY: u64 = 64;
+
+/// Outcomes of the agent's proposals since the store was created: the
+/// reward signal a learning policy would build on.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RewardStats {
+    pub steps: u64,
+    pub accepted: u64,
+    pub rejected: u64,
+    pub rolled_back: u64,
+    pub errors: u64,
+}
+
+impl RewardStats {
+    const LEN: usize = 40;
+
+    fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        for (chunk, v) in out.chunks_mut(8).zip([self.steps, self.accepted, self.rejected, self.rolled_back, self.errors]) {
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::LEN {
+            return None;
+        }
+        let at = |i: usize| u64::from_le_bytes(bytes[i * 8..i * 8 + 8].try_into().unwrap_or_default());
+        Some(RewardStats { steps: at(0), accepted: at(1), rejected: at(2), rolled_back: at(3), errors: at(4) })
+    }
+
+    /// Count one outcome (`apply_action::propose` codes; 5 is a failed
+    /// self-test, after which the change was undone).
+    fn record(&mut self, result: u8) {
+        self.steps += 1;
+        match result {
+            0 => self.accepted += 1,
+            1 => self.rejected += 1,
+            2 | 5 => self.rolled_back += 1,
+            _ => self.errors += 1,
+        }
+    }
+}
+
+static STATS: Mutex<RewardStats> =
+    Mutex::new(RewardStats { steps: 0, accepted: 0, rejected: 0, rolled_back: 0, errors: 0 });
+/// Outcomes of the heuristic controller this boot, kept apart for A/B
+/// comparison and never persisted.
+static HEURISTIC_STATS: Mutex<RewardStats> =
+    Mutex::new(RewardStats { steps: 0, accepted: 0, rejected: 0, rolled_back: 0, errors: 0 });
+
+pub fn reward_stats() -> RewardStats {
+    *STATS.lock()
+}
+
+pub fn heuristic_stats() -> RewardStats {
+    *HEURISTIC_STATS.lock()
+}
+
+fn load_stats() {
+    if let Some(Some(stats)) = kv::get(STATS_KEY, RewardStats::decode) {
+        klog::log(Level::Info, format_args!("[ai] restored stats: {} steps, {} accepted\r\n", stats.steps, stats.accepted));
+        *STATS.lock() = stats;
+    }
+}
+
+fn record_outcome(result: u8, controller: Controller) {
+    if controller == Controller::Heuristic {
+        HEURISTIC_STATS.lock().record(result);
+        return;
+    }
+    let stats = {
+        let mut stats = STATS.lock();
+        stats.record(result);
+        *stats
+    };
+    if stats.steps.is_multiple_of(STATS_PERSIST_EVERY) {
+        if let Err(e) = kv::set(STATS_KEY, &stats.encode()) {
+            klog::log(Level::Warn, format_args!("[ai] saving stats: {}\r\n", e.as_str()));
+        }
+    }
+}
+
+/// Stop proposing, without touching the store: safe where an allocation
+/// just failed (`oom`).
+pub fn stop() {
+    PARKED.store(true, Ordering::Release);
+}
+
+/// Take the model out and stop proposing from either controller until
+/// reboot: what `ai_guard` does when it trips.
+pub fn disable() {
+    AI_RUNNING.store(false, Ordering::Release);
+    PARKED.store(true, Ordering::Release);
+}
+
+/// Shutdown hook: stop proposing and keep the outcome counters, which are
+/// otherwise only saved every `STATS_PERSIST_EVERY` steps.
+pub fn park() -> Result<(), &'static str> {
+    PARKED.store(true, Ordering::Release);
+    let stats = *STATS.lock();
+    kv::set(STATS_KEY, &stats.encode()).map_err(|e| e.as_str())
+}
+
+/// Which controller turns telemetry into a proposal.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Controller {
+    Model,
+    Heuristic,
+}
+
+impl Controller {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Controller::Model => "model",
+            Controller::Heuristic => "heuristic",
+        }
+    }
+}
+
+/// What the agent may do (`ai.mode`).
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    /// No step runs and the model is not loaded.
+    Off,
+    /// Steps run and their proposals are journaled; nothing is applied.
+    Observe,
+    /// Proposals go through the gate and are applied under policy.
+    Act,
+}
+
+impl Mode {
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "off" => Some(Mode::Off),
+            "observe" => Some(Mode::Observe),
+            "act" => Some(Mode::Act),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Off => "off",
+            Mode::Observe => "observe",
+            Mode::Act => "act",
+        }
+    }
+}
+
+/// The `ai.mode` setting; `act` when it is not one of the three.
+pub fn mode() -> Mode {
+    config::with("ai.mode", Mode::parse).flatten().unwrap_or(Mode::Act)
+}
+
+/// Switch to `mode` from the next step on, and keep it for later boots.
+/// The boot command line still wins over the saved value.
+pub fn set_mode(mode: Mode) -> Result<(), &'static str> {
+    config::set("ai.mode", mode.as_str()).map_err(|_| "config full")?;
+    config::save("ai.mode").map_err(|e| e.as_str())?;
+    if SCHEDULED.load(Ordering::Acquire) && !PARKED.load(Ordering::Acquire) {
+        report_mode(mode);
+    }
+    Ok(())
+}
+
+/// The agent task is registered: from now on `status` follows `ai.mode`.
+pub fn scheduled() {
+    SCHEDULED.store(true, Ordering::Release);
+    report_mode(mode());
+}
+
+fn report_mode(mode: Mode) {
+    match mode {
+        Mode::Off => status::set("ai", status::Health::Skipped, "ai.mode=off"),
+        Mode::Observe => status::set("ai", status::Health::Ok, "observe: proposals journaled only"),
+        Mode::Act => status::set("ai", status::Health::Ok, ""),
+    }
+}
+
+/// Controller for the `ai.controller` setting when the model is usable or
+/// not; `None` if the setting asks for a model there is none of.
+fn pick(setting: &str, model_ready: bool) -> Option<Controller> {
+    match setting {
+        "heuristic" => Some(Controller::Heuristic),
+        "model" => model_ready.then_some(Controller::Model),
+        _ if model_ready => Some(Controller::Model),
+        _ => Some(Controller::Heuristic),
+    }
+}
 
 // Internal persistent state for step-based agent
 struct AgentState {
-    hdr: ModelHeader,
-    model_ptr: *const u8,
-    prev_ticks: u64,
-    prev_pf: u64,
-    scratch: [i32; 1024],
+    /// Backend that loaded the model, if there is a usable one.
+    model: Option<&'static dyn InferenceBackend>,
+    baseline: telemetry::Baseline,
+    /// TSC of the last inference, for the `SetPollingInterval` cadence.
+    last_step_tsc: u64,
 }
 
-static mut AGENT_STATE: Option<AgentState> = None;
+/// Only the agent task and shell commands use it, never an interrupt
+/// handler, so a plain spin lock is enough.
+static AGENT_STATE: Mutex<Option<AgentState>> = Mutex::new(None);
 
 extern "C" {
     fn ai_propose_action(action: *const Action, outcome: *mut ActionOutcome) -> i32;
@@ -66,134 +281,119 @@ fn idle_hlt() -> ! {
     }
 }
 
-unsafe fn load_model(addr: *const u8) -> Option<NonNull<ModelHeader>> {
-    let hdr = ModelHeader::read_unaligned(addr, core::mem::size_of::<ModelHeader>())?;
-    if !hdr.valid() {
+/// The model file at `addr`, `len` bytes long.
+unsafe fn model_bytes(addr: *const u8, len: usize) -> Option<&'static [u8]> {
+    (!addr.is_null() && len > 0).then(|| core::slice::from_raw_parts(addr, len))
+}
+
+/// The backend for `model`, loaded, if the agent can run it; otherwise
+/// says why not.
+fn usable_model(model: &'static [u8]) -> Option<&'static dyn InferenceBackend> {
+    let Some(backend) = ai_backend::select(model) else {
+        klog::log(Level::Info, format_args!("[ai] no backend for this model file; heuristic controller\r\n"));
+        return None;
+    };
+    if let Err(e) = backend.validate(model) {
+        klog::log(Level::Warn, format_args!("[ai] model refused by {}: {}; heuristic controller\r\n", backend.name(), e.as_str()));
+        if e == Invalid::TooLarge {
+            // Refused once and for all: its cost per step is not bounded
+            status::set("ai", status::Health::Failed, "model over size cap");
+        }
         return None;
     }
-    NonNull::new(addr as *mut ModelHeader)
-}
-
-#[allow(unused_variables)]
-pub unsafe fn matmul_int8(
-    a: *const i8,
-    b: *const i8,
-    out: *mut i32,
-    m: usize,
-    n: usize,
-    k: usize,
-) {
-    for i in 0..m {
-        let a_row = a.add(i * k);
-        for j in 0..n {
-            let mut acc: i32 = 0;
-            for p in 0..k {
-                let ai = *a_row.add(p) as i32;
-                let bj = *b.add(p * n + j) as i32;
-                acc += ai * bj;
-            }
-            *out.add(i * n + j) = acc;
+    backend.load(model);
+    let info = backend.info(model);
+    match &info {
+        Some(info) => {
+            klog::log(Level::Info, format_args!("[ai] model {} loaded by {}\r\n", info, backend.name()));
+            journal::journal_model_loaded(info);
         }
+        None => klog::log(Level::Info, format_args!("[ai] model loaded by {} (unnamed)\r\n", backend.name())),
     }
+    *MODEL_INFO.lock() = info;
+    Some(backend)
 }
 
-#[derive(Copy, Clone, Default)]
-pub struct Telemetry {
-    pub irq_errors: u32,
-    pub runq: u32,
-    pub irq_rate: u32,   // approx ticks per loop
-    pub free_kb: u32,
-    pub pf_rate: u32,
-}
-
-fn gather_telemetry(prev_ticks: &mut u64, prev_pf: &mut u64) -> Telemetry {
-    let ticks = idt::timer_ticks();
-    let rate = (ticks.saturating_sub(*prev_ticks)) as u32;
-    *prev_ticks = ticks;
-    let pf = idt::page_faults();
-    let pf_rate = (pf.saturating_sub(*prev_pf)) as u32;
-    *prev_pf = pf;
-    let free_kb = pmm::free_kib() as u32;
-    let runq = crate::task::runqueue_len() as u32;
-    Telemetry { irq_errors: 0, runq, irq_rate: rate, free_kb, pf_rate }
-}
-
-fn infer_and_propose(hdr: &ModelHeader, tel: &Telemetry, scratch: &mut [i32; 1024], model_addr: *const u8) -> Action {
-    // Build input vector of length hidden
-    let hidden = hdr.hidden as usize;
-    let mut inbuf_i8 = [0i8; 256];
-    let cap = inbuf_i8.len();
-    let slice_len = core::cmp::min(hidden, cap);
-    let in_slice = &mut inbuf_i8[..slice_len];
-    // Very simple features: runq, irq_rate, free_kb (scaled)
-    if !in_slice.is_empty() {
-        in_slice[0] = tel.runq.min(127) as i8;
-    }
-    if in_slice.len() > 1 { in_slice[1] = tel.irq_rate.min(127) as i8; }
-    if in_slice.len() > 2 { in_slice[2] = ((tel.free_kb / 1024).min(127)) as i8; } // MB approx
-    if in_slice.len() > 3 { in_slice[3] = tel.pf_rate.min(127) as i8; }
-
-    // Check model length for weights availability
-    let model_len = unsafe { AI_MODEL_LEN };
-    let need = WeightsLayout::compute(hdr).map(|w| w.total_bytes + ModelHeader::PAYLOAD_OFFSET).unwrap_or(0);
-    let has_weights = need > ModelHeader::PAYLOAD_OFFSET && model_len >= need;
-
-    // Buffer courant (int8) pour les couches, sans allocation
-    let mut xbuf = [0i8; 256];
-    let len = in_slice.len();
-    xbuf[..len].copy_from_slice(in_slice);
-    let mut x_len = len;
-
-    if has_weights && hdr.dtype == 0 {
-        let nl = hdr.n_layers as usize;
-        for l in 0..nl {
-            let (in_dim, out_dim) = match layer_dims(hdr, l) { Some(d) => d, None => break };
-            if in_dim > x_len || out_dim > 256 || in_dim == 0 || out_dim == 0 { break; }
-            // Prepare i32 output in scratch
-            let out_ptr = scratch.as_mut_ptr();
-            let w_ptr = unsafe { layer_ptr_int8(model_addr, hdr, l).unwrap_or(core::ptr::null()) };
-            let b_ptr = unsafe { bias_ptr_i32(model_addr, hdr, l).unwrap_or(core::ptr::null()) };
-            if w_ptr.is_null() { break; }
-            // Do matmul: out = W (out_dim x in_dim) * x (in_dim)
-            unsafe {
-                for oi in 0..out_dim {
-                    let mut acc: i32 = 0;
-                    let w_row = w_ptr.add(oi * in_dim);
-                    for p in 0..in_dim {
-                        let a = *w_row.add(p) as i32;
-                        let b = xbuf[p] as i32;
-                        acc += a * b;
-                    }
-                    if !b_ptr.is_null() {
-                        acc = acc.saturating_add(*b_ptr.add(oi));
-                    }
-                    *out_ptr.add(oi) = acc;
-                }
-            }
-            // ReLU + requantize by >> REQUANT_SHIFT
-            for oi in 0..out_dim {
-                let mut v = scratch[oi];
-                if v < 0 { v = 0; }
-                v >>= REQUANT_SHIFT; // crude scale configurable
-                if v > 127 { v = 127; }
-                xbuf[oi] = v as i8;
-            }
-            x_len = out_dim;
+/// Run `model` instead of the current model from the next step on; with
+/// `None`, run none. The current model stays if `model` is not usable.
+pub fn swap_model(model: Option<&'static [u8]>) -> Result<(), &'static str> {
+    ensure_init();
+    let backend = match model {
+        Some(model) => Some(usable_model(model).ok_or("model not usable")?),
+        None => {
+            *MODEL_INFO.lock() = None;
+            None
         }
+    };
+    if let Some(st) = AGENT_STATE.lock().as_mut() {
+        st.model = backend;
+    }
+    let (addr, len) = model.map_or((core::ptr::null(), 0), |m| (m.as_ptr(), m.len()));
+    ai_link::set_model(addr, len);
+    // A new model gets a fresh budget record
+    VIOLATIONS.store(0, Ordering::Relaxed);
+    AI_RUNNING.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Name, version and training hash of the loaded model, if it has them.
+pub fn model_info() -> Option<ModelInfo> {
+    *MODEL_INFO.lock()
+}
+
+/// `model_info` for the panic path: nothing if there is none or the lock
+/// is held.
+pub fn try_write_model(out: &mut impl Write) -> fmt::Result {
+    match MODEL_INFO.try_lock().as_deref() {
+        Some(Some(info)) => write!(out, "model {}", info),
+        _ => Ok(()),
     }
+}
+
+/// TSC past which the inference step starting now is abandoned.
+fn step_deadline(start: u64) -> u64 {
+    let budget_us = config::get_u64("ai.budget_us").unwrap_or(DEFAULT_BUDGET_US);
+    match time::tsc_per_ms() {
+        // Not calibrated yet: nothing to measure against
+        0 => u64::MAX,
+        per_ms => start.saturating_add(budget_us.saturating_mul(per_ms) / 1000),
+    }
+}
 
-    // Score = premier neurone ou 0
-    let mut score = if x_len > 0 { xbuf[0] as i32 } else { 0 };
-    // Fallback heuristic influence if no weights (or weak): penalize page faults, reward free memory
-    if !has_weights {
-        let free_mb = (tel.free_kb / 1024) as i32;
-        score = tel.runq as i32 + (tel.irq_rate as i32)/2 - (tel.pf_rate as i32) - free_mb/8;
-        if score < -127 { score = -127; }
-        if score > 127 { score = 127; }
+/// A step was abandoned after `elapsed` cycles: journal it, and stop the
+/// agent once too many steps in a row did not fit.
+fn over_budget(elapsed: u64) {
+    let n = VIOLATIONS.fetch_add(1, Ordering::Relaxed) + 1;
+    let us = time::cycles_to_us(elapsed).unwrap_or(0).min(u32::MAX as u64) as u32;
+    journal::journal_model_too_slow(n as u64, us);
+    klog::log(Level::Warn, format_args!("[ai] step over budget after {} us ({} in a row)\r\n", us, n));
+    if n as u64 >= config::get_u64("ai.slow_limit").unwrap_or(DEFAULT_SLOW_LIMIT) {
+        AI_RUNNING.store(false, Ordering::Release);
+        status::set("ai", status::Health::Failed, "model too slow");
     }
+}
+
+/// Steps abandoned in a row for running over budget.
+pub fn budget_violations() -> u32 {
+    VIOLATIONS.load(Ordering::Relaxed)
+}
+
+/// Turn a controller's score into an action; the same rules apply to both
+/// controllers.
+pub fn decide(mut score: i32, tel: &Telemetry) -> Action {
+    // Intents left dangling by an unclean shutdown: damp the score so the quantum stays near base
+    let caution = journal::dangling_intents().min(4);
+    score >>= caution;
     // Si mémoire faible (< 8 MiB) ou fautes de page fréquentes → proposer TRIM_CACHE
     if tel.free_kb < MEM_LOW_KB || tel.pf_rate > PF_RATE_THRESH {
-        return Action { kind: ActionType::TrimCache as u8, flags: actf::REQUIRES_SNAPSHOT, _r: [0;2], param1: TRIM_BYTES, param2: 0, param3: 0 };
+        return Action { kind: ActionType::TrimCache as u8, flags: actf::REQUIRES_SNAPSHOT, _r: [0; 6], param1: TRIM_BYTES, param2: 0, param3: 0 };
+    }
+
+    // Keys wait too long for the shell: trade some throughput for interactivity
+    if tel.input_latency_us > INPUT_LATENCY_THRESH_US && !crate::boost::active(crate::boost::SHELL) {
+        if let Some(shell) = ai_action::pack_name(crate::boost::SHELL) {
+            return Action { kind: ActionType::BoostTask as u8, flags: 0, _r: [0; 6], param1: shell, param2: SHELL_BOOST_MS, param3: 0 };
+        }
     }
 
     // Map score to quantum (100..50_000 µs)
@@ -201,21 +401,55 @@ fn infer_and_propose(hdr: &ModelHeader, tel: &Telemetry, scratch: &mut [i32; 102
     if quantum < 100 { quantum = 100; }
     if quantum > 50_000 { quantum = 50_000; }
 
-    Action { kind: ActionType::SetQuantum as u8, flags: actf::REQUIRES_SNAPSHOT, _r: [0; 2], param1: quantum as u64, param2: 0, param3: 0 }
+    Action { kind: ActionType::SetQuantum as u8, flags: actf::REQUIRES_SNAPSHOT, _r: [0; 6], param1: quantum as u64, param2: 0, param3: 0 }
+}
+
+/// `controller`'s proposal for `tel`, or the cycles spent if the model ran
+/// over its budget.
+fn propose(st: &mut AgentState, controller: Controller, tel: &Telemetry) -> Result<Action, u64> {
+    let backend = match (controller, st.model) {
+        (Controller::Model, Some(backend)) => backend,
+        _ => {
+            let mut action = decide(ai_heuristic::score(tel), tel);
+            action.flags |= actf::HEURISTIC;
+            return Ok(action);
+        }
+    };
+    let start = time::rdtsc();
+    backend.infer(tel, step_deadline(start)).ok_or_else(|| time::rdtsc().wrapping_sub(start))
+}
+
+/// Name of the backend running the model, if one is loaded.
+pub fn backend() -> Option<&'static str> {
+    AGENT_STATE.lock().as_ref().and_then(|st| st.model).map(|b| b.name())
+}
+
+/// Controller the next step will use.
+pub fn controller() -> Option<Controller> {
+    let model_ready = AI_RUNNING.load(Ordering::Acquire)
+        && AGENT_STATE.lock().as_ref().is_some_and(|st| st.model.is_some());
+    if model_ready && ai_canary::active() {
+        return Some(Controller::Model);
+    }
+    config::with("ai.controller", |v| pick(v, model_ready)).unwrap_or_else(|| pick("auto", model_ready))
 }
 
 #[no_mangle]
 pub extern "C" fn ai_agent_main(model_addr: *const u8) -> ! {
-    let model = match unsafe { load_model(model_addr) } { Some(m) => m, None => return idle_hlt(), };
-    let hdr = unsafe { core::ptr::read_unaligned(model.as_ptr()) };
+    let model = unsafe { model_bytes(model_addr, ai_link::model().1) };
+    let Some(backend) = model.and_then(usable_model) else { return idle_hlt() };
 
-    let mut scratch: [i32; 1024] = [0; 1024];
-    let mut prev_ticks: u64 = idt::timer_ticks();
-    let mut prev_pf: u64 = idt::page_faults();
+    let mut baseline = telemetry::Baseline::now();
 
     while AI_RUNNING.load(Ordering::Acquire) {
-        let tel = gather_telemetry(&mut prev_ticks, &mut prev_pf);
-        let action = infer_and_propose(&hdr, &tel, &mut scratch, model.as_ptr() as *const u8);
+        let tel = telemetry::gather(&mut baseline);
+        let start = time::rdtsc();
+        let Some(action) = backend.infer(&tel, step_deadline(start)) else {
+            over_budget(time::rdtsc().wrapping_sub(start));
+            unsafe { core::arch::asm!("hlt"); }
+            continue;
+        };
+        VIOLATIONS.store(0, Ordering::Relaxed);
 
         if (action.flags & actf::NEEDS_MANUAL_CONFIRM) != 0 {
             unsafe { core::arch::asm!("hlt"); }
@@ -230,37 +464,207 @@ pub extern "C" fn ai_agent_main(model_addr: *const u8) -> ! {
     idle_hlt()
 }
 
-fn ensure_init() -> bool {
-    unsafe {
-        if AGENT_STATE.is_some() {
-            return true;
-        }
-        let model = match load_model(AI_MODEL_ADDR) { Some(m) => m, None => return false };
-        let hdr = core::ptr::read_unaligned(model.as_ptr());
-        AGENT_STATE = Some(AgentState {
-            hdr,
-            model_ptr: model.as_ptr() as *const u8,
-            prev_ticks: idt::timer_ticks(),
-            prev_pf: idt::page_faults(),
-            scratch: [0; 1024],
-        });
-        true
+fn ensure_init() {
+    let mut state = AGENT_STATE.lock();
+    if state.is_some() {
+        return;
+    }
+    // Not looked for at boot with `ai.mode = off`
+    crate::ai_initrd::try_set_model_from_initrd();
+    let (addr, len) = ai_link::model();
+    let model = unsafe { model_bytes(addr, len) }.and_then(usable_model);
+    if model.is_some() {
+        load_stats();
     }
+    *state = Some(AgentState {
+        model,
+        baseline: telemetry::Baseline::now(),
+        last_step_tsc: 0,
+    });
 }
 
+/// `ktrace` outcome of a step that proposed nothing.
+const NOT_PROPOSED: u16 = 0xFF;
+
 pub fn step() {
-    if !ensure_init() { return; }
-    if !AI_RUNNING.load(Ordering::Acquire) { return; }
-    let (hdr, model_ptr, prev_ticks, prev_pf) = unsafe {
-        let st = AGENT_STATE.as_mut().unwrap();
-        (st.hdr, st.model_ptr, &mut st.prev_ticks, &mut st.prev_pf)
-    };
-    let tel = gather_telemetry(prev_ticks, prev_pf);
-    let action = unsafe {
-        let st = AGENT_STATE.as_mut().unwrap();
-        infer_and_propose(&hdr, &tel, &mut st.scratch, model_ptr)
+    if PARKED.load(Ordering::Acquire) { return; }
+    ai_canary::tick();
+    // A model on trial acts, whatever the setting
+    let mode = if ai_canary::active() { Mode::Act } else { mode() };
+    if mode == Mode::Off { return; }
+    ensure_init();
+    let Some(controller) = controller() else { return };
+    let now = time::rdtsc();
+    let interval = (apply_action::get_ai_interval_ms() as u64).saturating_mul(time::tsc_per_ms());
+    let action = {
+        let mut state = AGENT_STATE.lock();
+        let Some(st) = state.as_mut() else { return };
+        if st.last_step_tsc != 0 && now.wrapping_sub(st.last_step_tsc) < interval { return; }
+        st.last_step_tsc = now;
+        ktrace::event(ktrace::Kind::AiStepStart, 0, 0);
+        let tel = telemetry::gather(&mut st.baseline);
+        record_sample(&tel);
+        if controller == Controller::Model {
+            ai_canary::sample(tel.pf_rate);
+        }
+        match propose(st, controller, &tel) {
+            Ok(action) => action,
+            Err(elapsed) => {
+                over_budget(elapsed);
+                ktrace::event(ktrace::Kind::AiStepEnd, NOT_PROPOSED, 0);
+                return;
+            }
+        }
     };
-    if (action.flags & actf::NEEDS_MANUAL_CONFIRM) != 0 { return; }
+    if controller == Controller::Model {
+        VIOLATIONS.store(0, Ordering::Relaxed);
+    }
+    if mode == Mode::Observe {
+        journal::journal_observed(OBSERVED.fetch_add(1, Ordering::Relaxed), &action);
+        ktrace::event(ktrace::Kind::AiStepEnd, NOT_PROPOSED, 0);
+        return;
+    }
+    let mut result = NOT_PROPOSED;
     let mut outcome = ActionOutcome::default();
-    let _ = unsafe { ai_propose_action(&action as *const _, &mut outcome as *mut _) };
+    if (action.flags & actf::NEEDS_MANUAL_CONFIRM) == 0
+        && unsafe { ai_propose_action(&action as *const _, &mut outcome as *mut _) } == 0
+    {
+        record_outcome(outcome.result, controller);
+        result = outcome.result as u16;
+        if controller == Controller::Model {
+            ai_canary::outcome(outcome.result);
+        }
+    }
+    ktrace::event(ktrace::Kind::AiStepEnd, result, 0);
+}
+
+/// Trace size limit: about 3000 samples.
+const RECORD_MAX_BYTES: usize = 64 * 1024;
+/// Samples per second assumed when sizing a recording.
+const RECORD_RATE_HINT: usize = 50;
+
+/// Telemetry being written to a ramfs trace by `step`.
+struct Recording {
+    path: [u8; ramfs::MAX_NAME],
+    path_len: usize,
+    /// Executor deadline (TSC) at which the recording ends.
+    until: u64,
+    samples: u32,
+}
+
+impl Recording {
+    fn path(&self) -> &str {
+        core::str::from_utf8(&self.path[..self.path_len]).unwrap_or("")
+    }
+}
+
+static RECORDING: Mutex<Option<Recording>> = Mutex::new(None);
+
+/// Start writing every sampled telemetry struct to `path` for `seconds`.
+pub fn record(path: &str, seconds: u64) -> Result<(), ramfs::WriteError> {
+    if path.len() > ramfs::MAX_NAME {
+        return Err(ramfs::WriteError::BadName);
+    }
+    let cap = (TRACE_MAGIC.len() + seconds as usize * RECORD_RATE_HINT * TRACE_RECORD_LEN).min(RECORD_MAX_BYTES);
+    ramfs::create(path, cap)?;
+    ramfs::append(path, &TRACE_MAGIC)?;
+    let mut rec = Recording {
+        path: [0; ramfs::MAX_NAME],
+        path_len: path.len(),
+        until: executor::deadline_after_ms(seconds.saturating_mul(1000)),
+        samples: 0,
+    };
+    rec.path[..path.len()].copy_from_slice(path.as_bytes());
+    if let Some(old) = RECORDING.lock().replace(rec) {
+        klog::log(Level::Info, format_args!("[ai] recording to {} replaced after {} samples\r\n", old.path(), old.samples));
+    }
+    Ok(())
+}
+
+/// (path, samples so far) of the recording in progress, if any.
+pub fn recording(f: impl FnOnce(&str, u32)) -> bool {
+    match RECORDING.lock().as_ref() {
+        Some(rec) => {
+            f(rec.path(), rec.samples);
+            true
+        }
+        None => false,
+    }
+}
+
+fn record_sample(tel: &Telemetry) {
+    let mut guard = RECORDING.lock();
+    let Some(rec) = guard.as_mut() else { return };
+    let res = if executor::expired(rec.until) {
+        Err("done")
+    } else {
+        ramfs::append(rec.path(), &tel.encode()).map_err(|e| e.as_str())
+    };
+    match res {
+        Ok(()) => rec.samples += 1,
+        Err(why) => {
+            klog::log(Level::Info, format_args!("[ai] recording to {} stopped ({}): {} samples\r\n", rec.path(), why, rec.samples));
+            *guard = None;
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ReplayError {
+    NoModel,
+    BadTrace,
+    /// A sample took longer than one step's budget.
+    TooSlow,
+}
+
+/// Run the current controller over every sample of a telemetry trace
+/// without applying anything: each decision is logged as a dry run (on
+/// debugcon, not in the journal ring) and handed to `f`.
+/// Returns the number of samples.
+pub fn replay(trace: &[u8], mut f: impl FnMut(usize, &Telemetry, &Action)) -> Result<usize, ReplayError> {
+    ensure_init();
+    let controller = controller().ok_or(ReplayError::NoModel)?;
+    let samples = telemetry::trace_samples(trace).ok_or(ReplayError::BadTrace)?;
+    let mut state = AGENT_STATE.lock();
+    let Some(st) = state.as_mut() else { return Err(ReplayError::NoModel) };
+    let mut count = 0;
+    for (i, tel) in samples.enumerate() {
+        let action = propose(st, controller, &tel).map_err(|_| ReplayError::TooSlow)?;
+        journal::journal_dry_run(i as u64, &action);
+        f(i, &tel, &action);
+        count += 1;
+    }
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reward_stats_round_trip() {
+        let mut stats = RewardStats::default();
+        for result in [0, 0, 1, 5, 3] {
+            stats.record(result);
+        }
+        assert_eq!(stats, RewardStats { steps: 5, accepted: 2, rejected: 1, rolled_back: 1, errors: 1 });
+        assert_eq!(RewardStats::decode(&stats.encode()), Some(stats));
+        assert_eq!(RewardStats::decode(&[0; 8]), None);
+    }
+
+    #[test]
+    fn controller_follows_setting_and_model() {
+        assert_eq!(pick("auto", true), Some(Controller::Model));
+        assert_eq!(pick("auto", false), Some(Controller::Heuristic));
+        assert_eq!(pick("heuristic", true), Some(Controller::Heuristic));
+        assert_eq!(pick("model", false), None);
+    }
+
+    #[test]
+    fn modes_parse_back() {
+        for mode in [Mode::Off, Mode::Observe, Mode::Act] {
+            assert_eq!(Mode::parse(mode.as_str()), Some(mode));
+        }
+        assert_eq!(Mode::parse("on"), None);
+    }
 }
@@ -0,0 +1,323 @@
+//! Inference backends: what turns telemetry into a proposed action for one
+//! kind of model file.
+//!
+//! The agent hands the model to the first backend in `BACKENDS` that
+//! claims its file type, has it validated and loaded once, then calls
+//! `infer` every step. Cadence, budget and the gate stay in `ai_agent`, so
+//! another format (a decision tree compiled on the host, a table policy, an
+//! int4 or SIMD engine) only needs an implementation listed here.
+
+use spin::Mutex;
+
+use crate::ai_action::Action;
+use crate::ai_agent;
+use crate::ai_model::{bias_ptr_i32, layer_dims, layer_ptr_int8, ModelHeader, ModelInfo, WeightsLayout};
+use crate::ai_tree::{self, Forest, TreeError};
+use crate::telemetry::Telemetry;
+use crate::time;
+
+// --- IA config (ajustable via features) ---
+#[cfg(feature = "ai_cfg_aggr")]
+const REQUANT_SHIFT: i32 = 5;
+#[cfg(all(not(feature = "ai_cfg_aggr"), not(feature = "ai_cfg_conservative")))]
+const REQUANT_SHIFT: i32 = 6;
+#[cfg(feature = "ai_cfg_conservative")]
+const REQUANT_SHIFT: i32 = 6;
+
+/// Why a backend will not run a model it claimed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Invalid {
+    /// The cost of a step is not bounded: refused for good.
+    TooLarge,
+    /// The file is shorter than its header says.
+    Incomplete,
+    /// The file does not hold together (bad header, dangling references).
+    Malformed,
+}
+
+impl Invalid {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Invalid::TooLarge => "over the size cap",
+            Invalid::Incomplete => "payload incomplete",
+            Invalid::Malformed => "malformed",
+        }
+    }
+}
+
+pub trait InferenceBackend: Sync {
+    fn name(&self) -> &'static str;
+    /// Whether `model` is this backend's file type.
+    fn claims(&self, model: &[u8]) -> bool;
+    /// Whether a claimed `model` can run within the agent's limits.
+    fn validate(&self, model: &[u8]) -> Result<(), Invalid>;
+    /// Take a validated `model` for the following `infer` calls.
+    fn load(&self, model: &'static [u8]);
+    /// The proposal for `tel`, or `None` if the `deadline` TSC passed first
+    /// or nothing is loaded.
+    fn infer(&self, tel: &Telemetry, deadline: u64) -> Option<Action>;
+    /// Name, version and training hash, for formats that carry them.
+    fn info(&self, _model: &[u8]) -> Option<ModelInfo> {
+        None
+    }
+}
+
+/// In the order they are offered a model.
+static BACKENDS: &[&dyn InferenceBackend] = &[&INT8_MLP, &DECISION_TREE];
+
+/// The backend for `model`'s file type, if one is built in.
+pub fn select(model: &[u8]) -> Option<&'static dyn InferenceBackend> {
+    BACKENDS.iter().copied().find(|b| b.claims(model))
+}
+
+/// `AIMD` files with int8 weights: a stack of fully connected layers with
+/// ReLU, requantized to int8 between layers; the first output is the score
+/// `ai_agent::decide` turns into an action.
+pub struct Int8Mlp {
+    loaded: Mutex<Option<Mlp>>,
+}
+
+struct Mlp {
+    hdr: ModelHeader,
+    ptr: *const u8,
+    scratch: [i32; 1024],
+}
+
+// `ptr` points into the initrd, which is never freed or written.
+unsafe impl Send for Mlp {}
+
+static INT8_MLP: Int8Mlp = Int8Mlp { loaded: Mutex::new(None) };
+
+fn header(model: &[u8]) -> Option<ModelHeader> {
+    unsafe { ModelHeader::read_unaligned(model.as_ptr(), model.len()) }
+}
+
+impl InferenceBackend for Int8Mlp {
+    fn name(&self) -> &'static str {
+        "int8-mlp"
+    }
+
+    fn claims(&self, model: &[u8]) -> bool {
+        header(model).is_some_and(|h| h.valid() && h.dtype == 0)
+    }
+
+    fn validate(&self, model: &[u8]) -> Result<(), Invalid> {
+        let hdr = header(model).ok_or(Invalid::Incomplete)?;
+        if !hdr.within_limits() {
+            return Err(Invalid::TooLarge);
+        }
+        let need = WeightsLayout::compute(&hdr).map(|w| w.total_bytes + hdr.payload_offset()).unwrap_or(0);
+        if need <= hdr.payload_offset() || model.len() < need {
+            return Err(Invalid::Incomplete);
+        }
+        Ok(())
+    }
+
+    fn load(&self, model: &'static [u8]) {
+        *self.loaded.lock() = header(model).map(|hdr| Mlp { hdr, ptr: model.as_ptr(), scratch: [0; 1024] });
+    }
+
+    fn infer(&self, tel: &Telemetry, deadline: u64) -> Option<Action> {
+        let mut loaded = self.loaded.lock();
+        let score = score(loaded.as_mut()?, tel, deadline)?;
+        Some(ai_agent::decide(score, tel))
+    }
+
+    fn info(&self, model: &[u8]) -> Option<ModelInfo> {
+        ModelInfo::parse(model)
+    }
+}
+
+/// `AIDT` files: a decision forest over telemetry features
+/// (`ai_core::tree`), whose summed leaves are the score. A step is at most
+/// a few hundred comparisons, well inside any budget.
+pub struct DecisionTree {
+    loaded: Mutex<Option<Forest<'static>>>,
+}
+
+static DECISION_TREE: DecisionTree = DecisionTree { loaded: Mutex::new(None) };
+
+/// Feature values in `ai_tree::FEATURES` order.
+fn features(tel: &Telemetry) -> [u32; ai_tree::FEATURES.len()] {
+    [tel.runq, tel.irq_rate, tel.free_kb, tel.pf_rate, tel.input_latency_us, tel.irq_errors]
+}
+
+impl InferenceBackend for DecisionTree {
+    fn name(&self) -> &'static str {
+        "decision-tree"
+    }
+
+    fn claims(&self, model: &[u8]) -> bool {
+        ai_tree::claims(model)
+    }
+
+    fn validate(&self, model: &[u8]) -> Result<(), Invalid> {
+        Forest::parse(model).map(|_| ()).map_err(|e| match e {
+            TreeError::TooLarge => Invalid::TooLarge,
+            TreeError::Truncated => Invalid::Incomplete,
+            TreeError::BadHeader | TreeError::BadNode(_) => Invalid::Malformed,
+        })
+    }
+
+    fn load(&self, model: &'static [u8]) {
+        *self.loaded.lock() = Forest::parse(model).ok();
+    }
+
+    fn infer(&self, tel: &Telemetry, _deadline: u64) -> Option<Action> {
+        let score = self.loaded.lock().as_ref()?.eval(&features(tel));
+        Some(ai_agent::decide(score, tel))
+    }
+}
+
+/// The model's score for `tel`, or `None` if the layers did not finish
+/// before the `deadline` TSC.
+fn score(model: &mut Mlp, tel: &Telemetry, deadline: u64) -> Option<i32> {
+    let (hdr, model_addr, scratch) = (&model.hdr, model.ptr, &mut model.scratch);
+    // Build input vector of length hidden
+    let hidden = hdr.hidden as usize;
+    let mut inbuf_i8 = [0i8; 256];
+    let cap = inbuf_i8.len();
+    let slice_len = core::cmp::min(hidden, cap);
+    let in_slice = &mut inbuf_i8[..slice_len];
+    // Very simple features: runq, irq_rate, free_kb (scaled)
+    if !in_slice.is_empty() {
+        in_slice[0] = tel.runq.min(127) as i8;
+    }
+    if in_slice.len() > 1 { in_slice[1] = tel.irq_rate.min(127) as i8; }
+    if in_slice.len() > 2 { in_slice[2] = ((tel.free_kb / 1024).min(127)) as i8; } // MB approx
+    if in_slice.len() > 3 { in_slice[3] = tel.pf_rate.min(127) as i8; }
+    if in_slice.len() > 4 { in_slice[4] = (tel.input_latency_us / 1000).min(127) as i8; } // ms
+
+    // Buffer courant (int8) pour les couches, sans allocation
+    let mut xbuf = [0i8; 256];
+    let len = in_slice.len();
+    xbuf[..len].copy_from_slice(in_slice);
+    let mut x_len = len;
+
+    let nl = hdr.n_layers as usize;
+    for l in 0..nl {
+        let (in_dim, out_dim) = match layer_dims(hdr, l) { Some(d) => d, None => break };
+        if in_dim > x_len || out_dim > 256 || in_dim == 0 || out_dim == 0 { break; }
+        // Prepare i32 output in scratch
+        let out_ptr = scratch.as_mut_ptr();
+        let w_ptr = unsafe { layer_ptr_int8(model_addr, hdr, l).unwrap_or(core::ptr::null()) };
+        let b_ptr = unsafe { bias_ptr_i32(model_addr, hdr, l).unwrap_or(core::ptr::null()) };
+        if w_ptr.is_null() { break; }
+        // Do matmul: out = W (out_dim x in_dim) * x (in_dim)
+        unsafe {
+            for oi in 0..out_dim {
+                let mut acc: i32 = 0;
+                let w_row = w_ptr.add(oi * in_dim);
+                for p in 0..in_dim {
+                    let a = *w_row.add(p) as i32;
+                    let b = xbuf[p] as i32;
+                    acc += a * b;
+                }
+                if !b_ptr.is_null() {
+                    acc = acc.saturating_add(b_ptr.add(oi).read_unaligned());
+                }
+                *out_ptr.add(oi) = acc;
+            }
+        }
+        // ReLU + requantize by >> REQUANT_SHIFT
+        for oi in 0..out_dim {
+            let mut v = scratch[oi];
+            if v < 0 { v = 0; }
+            v >>= REQUANT_SHIFT; // crude scale configurable
+            if v > 127 { v = 127; }
+            xbuf[oi] = v as i8;
+        }
+        x_len = out_dim;
+        if time::rdtsc() > deadline {
+            return None;
+        }
+    }
+
+    // Score = premier neurone ou 0
+    Some(if x_len > 0 { xbuf[0] as i32 } else { 0 })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    /// One layer, 5 inputs to 1 output: weight 2 on the run-queue input,
+    /// bias 64.
+    fn model() -> Vec<u8> {
+        let mut bytes = Vec::from(*b"AIMD");
+        bytes.extend_from_slice(&1u16.to_le_bytes());
+        bytes.extend_from_slice(&5u16.to_le_bytes());
+        bytes.extend_from_slice(&1u32.to_le_bytes());
+        bytes.extend_from_slice(&[0, 0, 0, 0]);
+        bytes.extend_from_slice(&[2, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&64i32.to_le_bytes());
+        bytes
+    }
+
+    #[test]
+    fn mlp_claims_and_validates_aimd_int8() {
+        let model = model();
+        assert_eq!(select(&model).map(|b| b.name()), Some("int8-mlp"));
+        assert_eq!(INT8_MLP.validate(&model), Ok(()));
+        assert_eq!(INT8_MLP.validate(&model[..model.len() - 1]), Err(Invalid::Incomplete));
+        let mut wide = model.clone();
+        wide[6] = 0x01;
+        wide[7] = 0x01;
+        assert_eq!(INT8_MLP.validate(&wide), Err(Invalid::TooLarge));
+        let mut int4 = model.clone();
+        int4[12] = 1;
+        assert!(select(&int4).is_none());
+        assert!(select(b"TREE").is_none());
+    }
+
+    #[test]
+    fn versioned_model_keeps_its_weights_after_the_metadata() {
+        let legacy = model();
+        let mut model = legacy[..ModelHeader::SIZE].to_vec();
+        model[13] = 1;
+        model.extend_from_slice(b"probe");
+        model.resize(ModelHeader::SIZE + ModelInfo::LEN, 0);
+        model.extend_from_slice(&legacy[ModelHeader::SIZE..]);
+        assert_eq!(INT8_MLP.validate(&model), Ok(()));
+        assert_eq!(INT8_MLP.validate(&model[..model.len() - 1]), Err(Invalid::Incomplete));
+        assert_eq!(INT8_MLP.info(&model).map(|i| i.version), Some([0, 0, 0]));
+        assert_eq!(INT8_MLP.info(&legacy), None);
+        let mut mlp = Mlp { hdr: header(&model).unwrap(), ptr: model.as_ptr(), scratch: [0; 1024] };
+        let tel = Telemetry { runq: 100, ..Telemetry::default() };
+        assert_eq!(score(&mut mlp, &tel, u64::MAX), Some(4));
+    }
+
+    #[test]
+    fn forest_goes_to_the_tree_backend() {
+        // One tree: free_kb <= 8192 ? 40 : 0
+        let mut forest = Vec::from(*b"AIDT");
+        forest.extend_from_slice(&[3, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        for (feature, left, right, value) in [(2u8, 1u16, 2u16, 8192i32), (0xFF, 0, 0, 40), (0xFF, 0, 0, 0)] {
+            forest.extend_from_slice(&[feature, 0]);
+            forest.extend_from_slice(&left.to_le_bytes());
+            forest.extend_from_slice(&right.to_le_bytes());
+            forest.extend_from_slice(&[0, 0]);
+            forest.extend_from_slice(&value.to_le_bytes());
+        }
+        let backend = select(&forest).unwrap();
+        assert_eq!(backend.name(), "decision-tree");
+        assert_eq!(backend.validate(&forest), Ok(()));
+        assert_eq!(backend.validate(&forest[..forest.len() - 4]), Err(Invalid::Incomplete));
+        forest[22] = 0;
+        assert_eq!(backend.validate(&forest), Err(Invalid::Malformed));
+        let tel = Telemetry { free_kb: 4096, ..Telemetry::default() };
+        assert_eq!(features(&tel)[2], 4096);
+    }
+
+    #[test]
+    fn mlp_scores_the_first_output() {
+        let model = model();
+        let hdr = header(&model).unwrap();
+        let mut mlp = Mlp { hdr, ptr: model.as_ptr(), scratch: [0; 1024] };
+        let tel = Telemetry { runq: 100, ..Telemetry::default() };
+        // (2 * 100 + 64) >> 6
+        assert_eq!(score(&mut mlp, &tel, u64::MAX), Some(4));
+        assert_eq!(score(&mut mlp, &Telemetry::default(), u64::MAX), Some(1));
+    }
+}
@@ -0,0 +1,318 @@
+//! `ai canary <path> <seconds>`: a new model on trial, with the one it
+//! replaces kept to go back to.
+//!
+//! The file is copied out of ramfs, so a later write to it cannot change
+//! the model under the agent, checked by its backend and swapped in. For
+//! the window the agent acts (whatever `ai.mode` says) through the model
+//! controller, and every step's outcome and page-fault rate are counted
+//! against what the previous model did this boot. When the window ends the
+//! previous model is put back and the two are compared, unless
+//! `ai canary promote` kept the new one first; `ai canary abort` reverts
+//! early, and `MAX_SELFTEST_FAILURES` failed self-tests revert at once.
+//!
+//! The trial is marked in the kv store (`ai.canary`) until it ends: finding
+//! the mark at boot means it ended in a panic or a reset. That boot loads
+//! the previous model anyway, since nothing of the trial is persisted; the
+//! mark is reported and dropped. A promoted model also lasts until reboot.
+
+use spin::Mutex;
+
+use crate::klog::{self, Level};
+use crate::{ai_agent, ai_backend, ai_link, executor, kv, pmm, status};
+
+/// kv key marking a trial in progress.
+const MARK_KEY: &str = "ai.canary";
+/// Failed self-tests during a trial that end it before the window does.
+pub const MAX_SELFTEST_FAILURES: u64 = 3;
+/// Mean page-fault rate over the previous model's by more than this many
+/// percent (and `PF_SLACK` faults per sample) counts as a regression.
+const PF_REGRESSION_PCT: u64 = 50;
+const PF_SLACK: u64 = 2;
+/// `apply_action::propose` result of an action undone by a failed self-test.
+const SELFTEST_FAILED: u8 = 5;
+
+/// What one model did while it ran.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Metrics {
+    pub steps: u64,
+    pub accepted: u64,
+    pub selftest_failures: u64,
+    pub rolled_back: u64,
+    pub errors: u64,
+    pf_total: u64,
+    samples: u64,
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics { steps: 0, accepted: 0, selftest_failures: 0, rolled_back: 0, errors: 0, pf_total: 0, samples: 0 }
+    }
+
+    fn sample(&mut self, pf_rate: u32) {
+        self.pf_total += pf_rate as u64;
+        self.samples += 1;
+    }
+
+    /// Count one `apply_action::propose` result.
+    fn outcome(&mut self, result: u8) {
+        self.steps += 1;
+        match result {
+            0 => self.accepted += 1,
+            SELFTEST_FAILED => self.selftest_failures += 1,
+            1 | 2 => self.rolled_back += 1,
+            _ => self.errors += 1,
+        }
+    }
+
+    /// Mean page faults per sampled step.
+    pub fn pf_mean(&self) -> u64 {
+        self.pf_total.checked_div(self.samples).unwrap_or(0)
+    }
+}
+
+/// Where the new model did worse than the previous one.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Regressions {
+    /// A larger share of its actions failed their self-test.
+    pub selftest: bool,
+    pub pf_rate: bool,
+}
+
+impl Regressions {
+    pub fn any(self) -> bool {
+        self.selftest || self.pf_rate
+    }
+}
+
+pub fn compare(before: &Metrics, during: &Metrics) -> Regressions {
+    // failures / steps, cross-multiplied; no steps before counts as none failed
+    let selftest = during.selftest_failures > 0
+        && during.selftest_failures * before.steps.max(1) > before.selftest_failures * during.steps.max(1);
+    let pf_rate = during.samples > 0 && during.pf_mean() > before.pf_mean() * (100 + PF_REGRESSION_PCT) / 100 + PF_SLACK;
+    Regressions { selftest, pf_rate }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum End {
+    /// Kept by `ai canary promote`.
+    Promoted,
+    /// The window ran out.
+    WindowOver,
+    Aborted,
+    SelfTests,
+}
+
+impl End {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            End::Promoted => "promoted",
+            End::WindowOver => "reverted: window over",
+            End::Aborted => "reverted: aborted",
+            End::SelfTests => "reverted: self-tests failed",
+        }
+    }
+}
+
+/// How the last trial went.
+#[derive(Copy, Clone, Debug)]
+pub struct Report {
+    pub before: Metrics,
+    pub during: Metrics,
+    pub regressions: Regressions,
+    pub end: End,
+}
+
+#[derive(Copy, Clone)]
+struct Trial {
+    /// The model to go back to, as `ai_link::model` had it; 0 if none.
+    prev: (usize, usize),
+    /// The copy the new model runs from.
+    buf: Buffer,
+    /// Executor deadline (TSC) of the window.
+    until: u64,
+    before: Metrics,
+}
+
+#[derive(Copy, Clone)]
+struct Buffer {
+    base: u64,
+    cap: usize,
+}
+
+static TRIAL: Mutex<Option<Trial>> = Mutex::new(None);
+static LAST: Mutex<Option<Report>> = Mutex::new(None);
+/// What the running model did since it was loaded.
+static CURRENT: Mutex<Metrics> = Mutex::new(Metrics::new());
+/// The copy of a reverted model, reused by the next trial it is big enough
+/// for. A promoted model's copy is never reclaimed.
+static SPARE: Mutex<Option<Buffer>> = Mutex::new(None);
+
+/// Report a trial the previous boot did not finish.
+pub fn init() {
+    let found = kv::get(MARK_KEY, |name| {
+        klog::log(Level::Warn, format_args!(
+            "[ai] canary {} ended in a panic or reset; previous model kept\r\n",
+            core::str::from_utf8(name).unwrap_or("?")
+        ))
+    });
+    if found.is_some() {
+        status::set("canary", status::Health::Failed, "last trial ended in a panic or reset");
+        let _ = kv::remove(MARK_KEY);
+    }
+}
+
+pub fn active() -> bool {
+    TRIAL.lock().is_some()
+}
+
+/// Seconds left in the window, if a trial runs.
+pub fn remaining_s() -> Option<u64> {
+    let until = TRIAL.lock().as_ref()?.until;
+    let per_s = crate::time::tsc_per_ms().max(1) * 1000;
+    Some(until.saturating_sub(crate::time::rdtsc()) / per_s)
+}
+
+/// (previous model, new model so far) of the running trial.
+pub fn progress() -> Option<(Metrics, Metrics)> {
+    let before = TRIAL.lock().as_ref()?.before;
+    Some((before, *CURRENT.lock()))
+}
+
+pub fn last() -> Option<Report> {
+    *LAST.lock()
+}
+
+/// Put the model file `model` (named `path`) on trial for `seconds`.
+pub fn start(path: &str, model: &[u8], seconds: u64) -> Result<(), &'static str> {
+    if active() {
+        return Err("a trial is already running");
+    }
+    let backend = ai_backend::select(model).ok_or("no backend for this model file")?;
+    backend.validate(model).map_err(|e| e.as_str())?;
+    let spare = SPARE.lock().take();
+    let buf = match spare {
+        Some(spare) if spare.cap >= model.len() => spare,
+        _ => match pmm::alloc_for("ai.model", model.len() as u64, 4096) {
+            Some(base) => Buffer { base, cap: model.len() },
+            None => {
+                *SPARE.lock() = spare;
+                return Err("out of memory");
+            }
+        },
+    };
+    let copy = unsafe {
+        let ptr = crate::addr::PhysAddr::new(buf.base).as_mut_ptr::<u8>();
+        crate::fastmem::copy(ptr, model.as_ptr(), model.len());
+        core::slice::from_raw_parts(ptr as *const u8, model.len())
+    };
+    let (addr, len) = ai_link::model();
+    let prev = (addr as usize, len);
+    if let Err(e) = ai_agent::swap_model(Some(copy)) {
+        *SPARE.lock() = Some(buf);
+        return Err(e);
+    }
+    let before = core::mem::take(&mut *CURRENT.lock());
+    *TRIAL.lock() = Some(Trial { prev, buf, until: executor::deadline_after_ms(seconds.saturating_mul(1000)), before });
+    if let Err(e) = kv::set(MARK_KEY, path.as_bytes()) {
+        klog::log(Level::Warn, format_args!("[ai] canary mark not saved: {}\r\n", e.as_str()));
+    }
+    klog::log(Level::Info, format_args!("[ai] canary {} for {} s\r\n", path, seconds));
+    Ok(())
+}
+
+/// End the running trial; `None` if there is none.
+pub fn finish(end: End) -> Option<Report> {
+    let trial = TRIAL.lock().take()?;
+    let during = *CURRENT.lock();
+    if end != End::Promoted {
+        let (addr, len) = trial.prev;
+        let prev = (addr != 0).then(|| unsafe { core::slice::from_raw_parts(addr as *const u8, len) });
+        if let Err(e) = ai_agent::swap_model(prev) {
+            klog::log(Level::Warn, format_args!("[ai] canary: previous model not restored: {}\r\n", e));
+        }
+        *CURRENT.lock() = trial.before;
+        let mut spare = SPARE.lock();
+        if spare.is_none_or(|s| s.cap < trial.buf.cap) {
+            *spare = Some(trial.buf);
+        }
+    }
+    let _ = kv::remove(MARK_KEY);
+    let report = Report { before: trial.before, during, regressions: compare(&trial.before, &during), end };
+    klog::log(Level::Info, format_args!(
+        "[ai] canary {}: self-test failures {}/{} steps (was {}/{}), pf rate {} (was {}){}\r\n",
+        end.as_str(),
+        during.selftest_failures,
+        during.steps,
+        trial.before.selftest_failures,
+        trial.before.steps,
+        during.pf_mean(),
+        trial.before.pf_mean(),
+        if report.regressions.any() { ", regressed" } else { "" }
+    ));
+    status::set(
+        "canary",
+        if report.regressions.any() { status::Health::Failed } else { status::Health::Ok },
+        end.as_str(),
+    );
+    *LAST.lock() = Some(report);
+    Some(report)
+}
+
+/// Agent step hook: end the trial once its window is over.
+pub fn tick() {
+    let over = TRIAL.lock().as_ref().is_some_and(|t| executor::expired(t.until));
+    if over {
+        finish(End::WindowOver);
+    }
+}
+
+/// Agent step hook: the model controller saw `pf_rate` faults.
+pub fn sample(pf_rate: u32) {
+    CURRENT.lock().sample(pf_rate);
+}
+
+/// Agent step hook: the model's action ended with `result`. Reverts a
+/// trial whose self-tests keep failing.
+pub fn outcome(result: u8) {
+    let failures = {
+        let mut current = CURRENT.lock();
+        current.outcome(result);
+        current.selftest_failures
+    };
+    if failures >= MAX_SELFTEST_FAILURES && active() {
+        finish(End::SelfTests);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(outcomes: &[u8], pf: &[u32]) -> Metrics {
+        let mut m = Metrics::new();
+        outcomes.iter().for_each(|&r| m.outcome(r));
+        pf.iter().for_each(|&p| m.sample(p));
+        m
+    }
+
+    #[test]
+    fn outcomes_and_page_faults_are_counted() {
+        let m = metrics(&[0, 0, 5, 2, 1, 3], &[4, 6, 11]);
+        assert_eq!((m.steps, m.accepted, m.selftest_failures, m.rolled_back, m.errors), (6, 2, 1, 2, 1));
+        assert_eq!(m.pf_mean(), 7);
+        assert_eq!(Metrics::new().pf_mean(), 0);
+    }
+
+    #[test]
+    fn regressions_are_relative_to_the_previous_model() {
+        let before = metrics(&[0, 0, 0, 5], &[10, 10]);
+        // Same failure share, page faults within the margin
+        let same = metrics(&[0, 0, 0, 5], &[16, 16]);
+        assert!(!compare(&before, &same).any());
+        let worse = metrics(&[0, 5], &[18]);
+        assert_eq!(compare(&before, &worse), Regressions { selftest: true, pf_rate: true });
+        // A model with no history: any failed self-test counts
+        assert!(compare(&Metrics::new(), &metrics(&[0, 5], &[])).selftest);
+        assert!(!compare(&Metrics::new(), &metrics(&[0, 0], &[1])).any());
+    }
+}
@@ -0,0 +1,260 @@
+//! A watchdog over the agent, run as a task of its own so the check does
+//! not depend on the component it checks.
+//!
+//! Every `CHECK_MS` the guard reads the journal records written since its
+//! last look and the page-fault counter. It trips when `ai.guard.rollbacks`
+//! actions were rolled back (APPLY_FAIL) within `ai.guard.window_s`
+//! seconds, or when the page-fault rate jumps after an applied action:
+//! over `PF_FACTOR` times the rate before it, plus `PF_FLOOR`, within
+//! `PF_WATCH_MS`. A trip ends any `ai canary` trial, disables the agent
+//! (no model, no proposal from either controller) until reboot, journals a
+//! GUARD_TRIP record and raises an alert on the console, in the views'
+//! status bar and in `status`.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::journal::{self, Record, RecordKind};
+use crate::klog::{self, Level};
+use crate::theme::{self, Slot};
+use crate::{ai_agent, ai_canary, config, idt, status, time, vconsole, vga};
+
+const CHECK_MS: u64 = 250;
+/// Most rollbacks the window can be asked to hold.
+const MAX_ROLLBACKS: usize = 16;
+const DEFAULT_ROLLBACKS: u64 = 3;
+const DEFAULT_WINDOW_S: u64 = 60;
+/// How long after an applied action its page faults are watched.
+const PF_WATCH_MS: u64 = 5_000;
+const PF_FACTOR: u64 = 4;
+/// Faults per check period above the scaled rate before a trip, so a quiet
+/// system does not trip on a handful.
+const PF_FLOOR: u64 = 25;
+/// Assumed TSC rate when calibration never ran (1 GHz).
+const FALLBACK_TSC_PER_MS: u64 = 1_000_000;
+
+/// Thresholds, with times in the unit the guard is fed (TSC cycles).
+#[derive(Copy, Clone, Debug)]
+pub struct Limits {
+    pub rollbacks: usize,
+    pub window: u64,
+    pub pf_watch: u64,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Trip {
+    /// This many rollbacks within the window.
+    Rollbacks(usize),
+    /// Faults per period before and after an action of kind `kind`.
+    PfSpike { kind: u8, before: u64, after: u64 },
+}
+
+impl Trip {
+    /// The GUARD_TRIP record's `code`.
+    pub fn code(self) -> u32 {
+        match self {
+            Trip::Rollbacks(_) => 1,
+            Trip::PfSpike { .. } => 2,
+        }
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Trip::Rollbacks(_) => "guard: too many rollbacks",
+            Trip::PfSpike { .. } => "guard: page faults spiked after an action",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Watch {
+    kind: u8,
+    until: u64,
+    before: u64,
+}
+
+pub struct Guard {
+    limits: Limits,
+    /// When the latest rollbacks were seen, as a ring.
+    rollbacks: [u64; MAX_ROLLBACKS],
+    n_rollbacks: usize,
+    /// Faults per period while no action is watched, smoothed.
+    pf_baseline: u64,
+    watch: Option<Watch>,
+}
+
+impl Guard {
+    pub fn new(mut limits: Limits) -> Self {
+        limits.rollbacks = limits.rollbacks.clamp(1, MAX_ROLLBACKS);
+        Guard { limits, rollbacks: [0; MAX_ROLLBACKS], n_rollbacks: 0, pf_baseline: 0, watch: None }
+    }
+
+    /// A journal record seen at `now`.
+    pub fn record(&mut self, rec: &Record, now: u64) -> Option<Trip> {
+        match rec.kind {
+            RecordKind::ApplyFail => {
+                self.rollbacks[self.n_rollbacks % MAX_ROLLBACKS] = now;
+                self.n_rollbacks += 1;
+                let window = self.limits.window;
+                let recent = self.rollbacks[..self.n_rollbacks.min(MAX_ROLLBACKS)]
+                    .iter()
+                    .filter(|&&t| now.saturating_sub(t) <= window)
+                    .count();
+                (recent >= self.limits.rollbacks).then_some(Trip::Rollbacks(recent))
+            }
+            RecordKind::ApplyOk => {
+                self.watch = Some(Watch { kind: rec.action, until: now.saturating_add(self.limits.pf_watch), before: self.pf_baseline });
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// `faults` page faults in the period ending at `now`.
+    pub fn pf(&mut self, faults: u64, now: u64) -> Option<Trip> {
+        match self.watch {
+            Some(w) if now <= w.until => {
+                let limit = w.before.saturating_mul(PF_FACTOR).saturating_add(PF_FLOOR);
+                (faults > limit).then_some(Trip::PfSpike { kind: w.kind, before: w.before, after: faults })
+            }
+            _ => {
+                self.watch = None;
+                self.pf_baseline = (self.pf_baseline * 3 + faults) / 4;
+                None
+            }
+        }
+    }
+}
+
+struct State {
+    guard: Guard,
+    /// `journal::written` at the last look.
+    seen: u64,
+    /// `idt::page_faults` at the last look.
+    faults: u64,
+    next_check: u64,
+}
+
+static STATE: Mutex<Option<State>> = Mutex::new(None);
+static TRIPPED: AtomicBool = AtomicBool::new(false);
+static TRIPS: AtomicU64 = AtomicU64::new(0);
+
+fn cycles_per_ms() -> u64 {
+    match time::tsc_per_ms() {
+        0 => FALLBACK_TSC_PER_MS,
+        per_ms => per_ms,
+    }
+}
+
+/// Whether the guard disabled the agent this boot.
+pub fn tripped() -> bool {
+    TRIPPED.load(Ordering::Relaxed)
+}
+
+/// The guard task. Records written before its first run are not its
+/// business (they include the previous boot's).
+pub fn run() {
+    if tripped() {
+        return;
+    }
+    let now = time::rdtsc();
+    let per_ms = cycles_per_ms();
+    let mut state = STATE.lock();
+    let Some(st) = state.as_mut() else {
+        let limits = Limits {
+            rollbacks: config::get_u64("ai.guard.rollbacks").unwrap_or(DEFAULT_ROLLBACKS) as usize,
+            window: config::get_u64("ai.guard.window_s").unwrap_or(DEFAULT_WINDOW_S).saturating_mul(1000 * per_ms),
+            pf_watch: PF_WATCH_MS * per_ms,
+        };
+        *state = Some(State {
+            guard: Guard::new(limits),
+            seen: journal::written(),
+            faults: idt::page_faults(),
+            next_check: now + CHECK_MS * per_ms,
+        });
+        return;
+    };
+    if now < st.next_check {
+        return;
+    }
+    st.next_check = now + CHECK_MS * per_ms;
+    let mut trip = None;
+    let guard = &mut st.guard;
+    st.seen = journal::for_each_since(st.seen, |rec| trip = trip.or(guard.record(rec, now)));
+    let faults = idt::page_faults();
+    let delta = faults.saturating_sub(st.faults);
+    st.faults = faults;
+    let trip = trip.or(st.guard.pf(delta, now));
+    drop(state);
+    if let Some(trip) = trip {
+        trip_agent(trip);
+    }
+}
+
+fn trip_agent(trip: Trip) {
+    TRIPPED.store(true, Ordering::Relaxed);
+    let n = TRIPS.fetch_add(1, Ordering::Relaxed) + 1;
+    // The trial's model goes first, so the one restored is what stays off
+    ai_canary::finish(ai_canary::End::Aborted);
+    ai_agent::disable();
+    let kind = match trip {
+        Trip::PfSpike { kind, .. } => kind,
+        Trip::Rollbacks(_) => 0,
+    };
+    journal::journal_guard_trip(n, trip.code(), kind);
+    match trip {
+        Trip::Rollbacks(n) => klog::log(Level::Error, format_args!("[guard] {} rollbacks in the window; agent disabled\r\n", n)),
+        Trip::PfSpike { kind, before, after } => klog::log(Level::Error, format_args!(
+            "[guard] page faults {} -> {} per {} ms after action kind {}; agent disabled\r\n",
+            before, after, CHECK_MS, kind
+        )),
+    }
+    status::set("ai", status::Health::Failed, trip.as_str());
+    vga::set_style(theme::get(Slot::Panic));
+    vga::fmt(format_args!("AI GUARD: {}; agent disabled until reboot\n", trip.as_str()));
+    vga::set_style(theme::get(Slot::Text));
+    vconsole::alert("AI GUARD TRIPPED: agent disabled");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(kind: RecordKind, action: u8) -> Record {
+        Record { tsc: 0, seq: 0, kind, action, flags: 0, code: 0 }
+    }
+
+    fn guard() -> Guard {
+        Guard::new(Limits { rollbacks: 3, window: 60, pf_watch: 5 })
+    }
+
+    #[test]
+    fn rollbacks_trip_only_within_the_window() {
+        let mut g = guard();
+        let fail = rec(RecordKind::ApplyFail, 1);
+        assert_eq!(g.record(&fail, 0), None);
+        assert_eq!(g.record(&fail, 30), None);
+        // The first one is out of the window by now
+        assert_eq!(g.record(&fail, 61), None);
+        assert_eq!(g.record(&rec(RecordKind::Reject, 1), 62), None);
+        assert_eq!(g.record(&fail, 62), Some(Trip::Rollbacks(3)));
+    }
+
+    #[test]
+    fn page_fault_spike_after_an_action_trips() {
+        let mut g = guard();
+        for t in 0..8 {
+            assert_eq!(g.pf(10, t), None);
+        }
+        g.record(&rec(RecordKind::ApplyOk, 2), 8);
+        // Within the factor and floor of the rate before
+        assert_eq!(g.pf(50, 9), None);
+        let trip = g.pf(200, 10);
+        assert!(matches!(trip, Some(Trip::PfSpike { kind: 2, after: 200, .. })));
+        // Past the watch a spike is not blamed on the action
+        let mut g = guard();
+        g.record(&rec(RecordKind::ApplyOk, 2), 0);
+        assert_eq!(g.pf(500, 6), None);
+        assert_eq!(Trip::Rollbacks(3).code(), 1);
+    }
+}
@@ -0,0 +1,46 @@
+//! Fixed-rule controller: what the agent does without a usable model.
+//!
+//! It scores the same telemetry the model sees and its score goes through
+//! the same mapping to an action, so the two can be compared on equal terms:
+//! `ai.controller = heuristic` forces it, `model` never falls back to it,
+//! and the default `auto` uses it when no valid model is loaded or the model
+//! was stopped. Its actions carry `actf::HEURISTIC`, which the journal keeps.
+
+use crate::telemetry::Telemetry;
+
+// Tunables: contention pushes the score (and the quantum) up, page faults
+// and free memory pull it down.
+const RUNQ_WEIGHT: i32 = 1;
+const IRQ_RATE_DIV: i32 = 2;
+const PF_RATE_WEIGHT: i32 = 1;
+const FREE_MB_DIV: i32 = 8;
+
+/// Score in -127..=127, on the model's output scale.
+pub fn score(tel: &Telemetry) -> i32 {
+    let runq = tel.runq.min(i32::MAX as u32) as i32;
+    let irq_rate = tel.irq_rate.min(i32::MAX as u32) as i32;
+    let pf_rate = tel.pf_rate.min(i32::MAX as u32) as i32;
+    let free_mb = (tel.free_kb / 1024) as i32;
+    runq.saturating_mul(RUNQ_WEIGHT)
+        .saturating_add(irq_rate / IRQ_RATE_DIV)
+        .saturating_sub(pf_rate.saturating_mul(PF_RATE_WEIGHT))
+        .saturating_sub(free_mb / FREE_MB_DIV)
+        .clamp(-127, 127)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn score_follows_load_and_memory() {
+        let idle = Telemetry { free_kb: 64 * 1024, ..Telemetry::default() };
+        assert_eq!(score(&idle), -8);
+        let busy = Telemetry { runq: 10, irq_rate: 20, free_kb: 64 * 1024, ..Telemetry::default() };
+        assert_eq!(score(&busy), 12);
+        let faulting = Telemetry { pf_rate: 1_000, ..Telemetry::default() };
+        assert_eq!(score(&faulting), -127);
+        let flooded = Telemetry { runq: u32::MAX, ..Telemetry::default() };
+        assert_eq!(score(&flooded), 127);
+    }
+}
@@ -1,13 +1,15 @@
 #![allow(dead_code)]
 
-use crate::ai_link::{AI_MODEL_ADDR, AI_MODEL_LEN, INITRD_BASE, INITRD_LEN};
+use crate::{ai_backend, ai_link};
 use crate::ai_model::ModelHeader;
+use crate::klog::{self, Level};
+use crate::{config, hash};
 
 // Boot/loader can set these to point to an initrd image in RAM (cpio newc).
 // INITRD_BASE/INITRD_LEN are defined in ai_link.rs
 
-// Minimal cpio newc parser (no alloc). Returns pointer to file data if found.
-pub unsafe fn cpio_find(base: *const u8, len: usize, name: &str) -> Option<*const u8> {
+// Minimal cpio newc parser (no alloc). Returns pointer to file data and its size if found.
+pub unsafe fn cpio_find(base: *const u8, len: usize, name: &str) -> Option<(*const u8, usize)> {
     if base.is_null() || len < 110 { return None; }
     let mut off: usize = 0;
     while off + 110 <= len {
@@ -44,7 +46,7 @@ pub unsafe fn cpio_find(base: *const u8, len: usize, name: &str) -> Option<*cons
             && &fname[2..] == want;
         if is_exact || is_dot_slash {
             if data_off + filesize <= len {
-                return Some(base.add(data_off));
+                return Some((base.add(data_off), filesize));
             } else {
                 return None;
             }
@@ -58,21 +60,28 @@ pub unsafe fn cpio_find(base: *const u8, len: usize, name: &str) -> Option<*cons
 }
 
 // Try to locate /ai.mod in initrd and set AI_MODEL_ADDR if valid.
-pub unsafe fn try_set_model_from_initrd() {
-    if AI_MODEL_ADDR.is_null() && !INITRD_BASE.is_null() && INITRD_LEN >= ModelHeader::SIZE {
-        if let Some(ptr) = cpio_find(INITRD_BASE, INITRD_LEN, "ai.mod") {
-            // Validate AIMD header
-            if let Some(h) = ModelHeader::read_unaligned(ptr, ModelHeader::SIZE) { if h.valid() {
-                // Set global symbol
-                let p: *const u8 = ptr;
-                AI_MODEL_ADDR = p;
-                // Read filesize from cpio header (8 hex at offset 54)
-                // We are in the scope where `ptr` points to file start; to get size we need to re-parse header.
-                // The caller already knows INITRD_LEN; we conservatively set length to INITRD_LEN - (ptr-INITRD_BASE).
-                let base = INITRD_BASE as usize;
-                let off = (ptr as usize).saturating_sub(base);
-                AI_MODEL_LEN = INITRD_LEN.saturating_sub(off);
-            }}
+pub fn try_set_model_from_initrd() {
+    let (initrd, initrd_len) = ai_link::initrd();
+    if ai_link::model().0.is_null() && !initrd.is_null() && initrd_len >= ModelHeader::SIZE {
+        if let Some((ptr, size)) = unsafe { cpio_find(initrd, initrd_len, "ai.mod") } {
+            let model = unsafe { core::slice::from_raw_parts(ptr, size) };
+            // A file type some inference backend runs
+            if ai_backend::select(model).is_some() && verify_model(model) {
+                ai_link::set_model(ptr, size);
+            }
+        }
+    }
+}
+
+/// Log the model's digest; with `ai.model_sha256` set it must match.
+fn verify_model(model: &[u8]) -> bool {
+    let digest = hash::sha256(model);
+    klog::log(Level::Info, format_args!("[ai] ai.mod {} bytes, sha256 {}\r\n", model.len(), hash::Hex(&digest)));
+    match config::with("ai.model_sha256", |want| want.trim().is_empty() || hash::matches_hex(&digest, want)) {
+        Some(false) => {
+            klog::log(Level::Error, format_args!("[ai] ai.mod does not match ai.model_sha256; model not loaded\r\n"));
+            false
         }
+        _ => true,
     }
 }
@@ -1,16 +1,38 @@
 #![allow(dead_code)]
 
+use core::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+
 // Weak symbol that a future loader/boot stage can set to point to the model in RAM.
 // Default is null, which disables the agent startup path.
 #[no_mangle]
-pub static mut AI_MODEL_ADDR: *const u8 = core::ptr::null();
+pub static AI_MODEL_ADDR: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
 
 #[no_mangle]
-pub static mut AI_MODEL_LEN: usize = 0;
+pub static AI_MODEL_LEN: AtomicUsize = AtomicUsize::new(0);
 
 // Initrd (cpio newc) base pointer and length provided by stage2/boot.
 // Always available so that RAMFS and shell can read from it.
 #[no_mangle]
-pub static mut INITRD_BASE: *const u8 = core::ptr::null();
+pub static INITRD_BASE: AtomicPtr<u8> = AtomicPtr::new(core::ptr::null_mut());
 #[no_mangle]
-pub static mut INITRD_LEN: usize = 0;
+pub static INITRD_LEN: AtomicUsize = AtomicUsize::new(0);
+
+/// (address, length) of the model; null when none was found.
+pub fn model() -> (*const u8, usize) {
+    (AI_MODEL_ADDR.load(Ordering::Acquire), AI_MODEL_LEN.load(Ordering::Acquire))
+}
+
+pub fn set_model(addr: *const u8, len: usize) {
+    AI_MODEL_LEN.store(len, Ordering::Release);
+    AI_MODEL_ADDR.store(addr as *mut u8, Ordering::Release);
+}
+
+/// (base, length) of the initrd; null when stage2 loaded none.
+pub fn initrd() -> (*const u8, usize) {
+    (INITRD_BASE.load(Ordering::Acquire), INITRD_LEN.load(Ordering::Acquire))
+}
+
+pub fn set_initrd(base: *const u8, len: usize) {
+    INITRD_LEN.store(len, Ordering::Release);
+    INITRD_BASE.store(base as *mut u8, Ordering::Release);
+}
@@ -2,115 +2,211 @@
 
 use spin::Mutex;
 
-use crate::ai_action::{Action, ActionOutcome, ActionType};
+pub use ai_core::txn::{ApplyError, ApplyResult, Caller};
+
+use ai_core::txn;
+
+use crate::ai_action::{self, poll_target, Action, ActionOutcome, ActionType};
+use crate::boost;
 use crate::journal;
 use crate::idt;
-use core::sync::atomic::{AtomicBool, Ordering};
+use crate::klog;
+use crate::pmm;
+use crate::xhci;
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 static APPLY_LOCK: Mutex<()> = Mutex::new(());
-static mut QUANTUM_US: u32 = 1000;
-static mut SEQ: u64 = 0;
+static QUANTUM_US: AtomicU32 = AtomicU32::new(1000);
+static AI_INTERVAL_MS: AtomicU32 = AtomicU32::new(0);
+static AI_CPU_PCT: AtomicU32 = AtomicU32::new(DEFAULT_AI_CPU_PCT);
+static SEQ: AtomicU64 = AtomicU64::new(0);
 static SYSTEM_READY: AtomicBool = AtomicBool::new(false);
 
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
-pub enum ApplyError {
-    NotAllowed = 1,
-    InvalidParams = 3,
-    ExecuteFailed = 4,
-    SelfTestFailed = 5,
-}
+/// Share of each second the agent task gets until `ai.cpu_pct` or a
+/// `SetCpuBudget` says otherwise.
+pub const DEFAULT_AI_CPU_PCT: u32 = 10;
 
-pub type ApplyResult<T> = core::result::Result<T, ApplyError>;
+const _: () = assert!(txn::MAX_LOG_LEVEL == klog::Level::Debug as u64);
 
-fn is_allowed(kind: u8) -> bool {
-    match kind {
-        x if x == ActionType::SetQuantum as u8 => true,
-        x if x == ActionType::TrimCache as u8 => true,
-        _ => false,
-    }
+/// Everything an action can change, captured so a failed self-test can put it back.
+#[derive(Copy, Clone)]
+struct Knobs {
+    quantum_us: u32,
+    log_level: klog::Level,
+    ai_interval_ms: u32,
+    ai_cpu_pct: u32,
+    hid_pace_ms: Option<u32>,
+    boosts: boost::Boosts,
+    /// Not restored: the self-test measures a trim against it.
+    free_kib: u64,
 }
 
-fn validate_params(a: &Action) -> bool {
-    match a.kind {
-        x if x == ActionType::SetQuantum as u8 => {
-            let us = a.param1 as u32;
-            (100..=50_000).contains(&us)
-        }
-        x if x == ActionType::TrimCache as u8 => {
-            let bytes = a.param1 as u64;
-            bytes > 0 && bytes <= 16 * 1024 * 1024
+/// Only the shell and registered round-robin tasks can be boosted; fails
+/// when every boost slot is taken.
+fn boost_task(param: u64, ms: u64) -> bool {
+    let mut buf = [0u8; ai_action::NAME_LEN];
+    let Some(name) = ai_action::unpack_name(param, &mut buf) else { return false };
+    #[cfg(feature = "ai_agent")]
+    let known = name == boost::SHELL || crate::task::find(name);
+    #[cfg(not(feature = "ai_agent"))]
+    let known = name == boost::SHELL;
+    known && boost::set(name, ms)
+}
+
+fn set_polling_interval(target: u64, ms: u32) -> bool {
+    match target {
+        poll_target::AI_INFERENCE => {
+            AI_INTERVAL_MS.store(ms, Ordering::Relaxed);
+            true
         }
+        // Fails without a controller, so the action is journaled as failed
+        poll_target::HID => xhci::set_hid_pacing(ms),
         _ => false,
     }
 }
 
-fn read_before_state() -> u32 {
-    unsafe { QUANTUM_US }
-}
-
 fn write_quantum(us: u32) -> bool {
-    unsafe { QUANTUM_US = us; }
+    QUANTUM_US.store(us, Ordering::Relaxed);
     true
 }
 
-fn self_test_ok() -> bool {
-    // Basic liveness check: timer tick advances and no page fault spike within short window
-    let start_ticks = idt::timer_ticks();
-    let start_pf = idt::page_faults();
-    // busy-wait a little (no sleep available here); allow IRQs to fire
-    for _ in 0..50_000 {
-        core::hint::spin_loop();
-        if idt::timer_ticks().saturating_sub(start_ticks) >= 1 {
-            break;
-        }
+fn observe(a: &Action, free_before: u64) -> txn::Observed {
+    #[cfg(feature = "ai_agent")]
+    let slice_us = crate::task::slice_us();
+    #[cfg(not(feature = "ai_agent"))]
+    let slice_us = QUANTUM_US.load(Ordering::Relaxed) as u64;
+    let mut buf = [0u8; ai_action::NAME_LEN];
+    let mut boost_left_ms = None;
+    if let Some(name) = ai_action::unpack_name(a.param1, &mut buf).filter(|_| a.kind == ActionType::BoostTask as u8) {
+        boost::for_each(|n, left| {
+            if n == name {
+                boost_left_ms = Some(left);
+            }
+        });
+    }
+    txn::Observed {
+        slice_us,
+        log_level: klog::level() as u8,
+        ai_interval_ms: AI_INTERVAL_MS.load(Ordering::Relaxed),
+        ai_cpu_pct: AI_CPU_PCT.load(Ordering::Relaxed),
+        hid_pace_ms: xhci::hid_pacing(),
+        boost_left_ms,
+        freed_kib: pmm::free_kib() as i64 - free_before as i64,
     }
-    let dt = idt::timer_ticks().saturating_sub(start_ticks);
-    let dp = idt::page_faults().saturating_sub(start_pf);
-    dt >= 1 && dp == 0
 }
 
-fn trim_cache(bytes: u64) -> bool {
-    // Stub: no real cache subsystem yet. Simulate quick success.
+/// Also the first thing `oom` tries when an allocation fails. A no-op
+/// that reports success: there is no cache to trim yet.
+pub fn trim_cache(bytes: u64) -> bool {
     let _ = bytes;
     true
 }
 
-pub fn apply_action_atomic(seq: u64, a: &Action) -> ApplyResult<()> {
-    // Gate actions until the system is fully initialized
-    if !SYSTEM_READY.load(Ordering::Acquire) {
-        journal::journal_reject(seq, a);
-        return Err(ApplyError::NotAllowed);
+/// The running kernel as the gate's `Target`.
+struct Kernel;
+
+impl txn::Target for Kernel {
+    type Snapshot = Knobs;
+
+    fn ready(&self) -> bool {
+        // Repeated crashes may be our doing; stay hands-off until a clean boot
+        SYSTEM_READY.load(Ordering::Acquire) && !crate::bootreason::crash_loop()
     }
-    if !is_allowed(a.kind) || !validate_params(a) {
-        journal::journal_reject(seq, a);
-        return Err(ApplyError::NotAllowed);
+
+    fn snapshot(&mut self) -> Knobs {
+        Knobs {
+            quantum_us: QUANTUM_US.load(Ordering::Relaxed),
+            log_level: klog::level(),
+            ai_interval_ms: AI_INTERVAL_MS.load(Ordering::Relaxed),
+            ai_cpu_pct: AI_CPU_PCT.load(Ordering::Relaxed),
+            hid_pace_ms: xhci::hid_pacing(),
+            boosts: boost::snapshot(),
+            free_kib: pmm::free_kib(),
+        }
     }
 
-    let _g = APPLY_LOCK.lock();
-    let before = read_before_state();
-    journal::journal_intent(seq, a);
+    fn restore(&mut self, k: Knobs) {
+        let _ = write_quantum(k.quantum_us);
+        klog::set_level(k.log_level);
+        AI_INTERVAL_MS.store(k.ai_interval_ms, Ordering::Relaxed);
+        AI_CPU_PCT.store(k.ai_cpu_pct, Ordering::Relaxed);
+        if let Some(ms) = k.hid_pace_ms {
+            xhci::set_hid_pacing(ms);
+        }
+        boost::restore(k.boosts);
+    }
 
-    let ok = match a.kind {
-        x if x == ActionType::SetQuantum as u8 => write_quantum(a.param1 as u32),
-        x if x == ActionType::TrimCache as u8 => trim_cache(a.param1 as u64),
-        _ => false,
-    };
+    fn execute(&mut self, a: &Action) -> ApplyResult<()> {
+        let ok = match a.kind {
+            x if x == ActionType::SetQuantum as u8 => write_quantum(a.param1 as u32),
+            x if x == ActionType::TrimCache as u8 => trim_cache(a.param1),
+            x if x == ActionType::SetLogLevel as u8 => klog::Level::from_u8(a.param1 as u8)
+                .map(klog::set_level)
+                .is_some(),
+            x if x == ActionType::SetPollingInterval as u8 => set_polling_interval(a.param1, a.param2 as u32),
+            x if x == ActionType::BoostTask as u8 => boost_task(a.param1, a.param2),
+            x if x == ActionType::SetCpuBudget as u8 => {
+                AI_CPU_PCT.store(a.param1 as u32, Ordering::Relaxed);
+                true
+            }
+            // `admit` only lets through kinds handled above; one that gets
+            // here anyway is refused, not fatal
+            _ => return Err(ApplyError::InvalidParams),
+        };
+        kensure!(ok, ApplyError::ExecuteFailed);
+        Ok(())
+    }
 
-    if !ok {
-        journal::journal_fail(seq, a, ApplyError::ExecuteFailed as u32);
-        return Err(ApplyError::ExecuteFailed);
+    /// Liveness over a short window, then the action's own effect.
+    fn self_test(&mut self, a: &Action, before: &Knobs) -> ApplyResult<u32> {
+        // Basic liveness check: timer tick advances and no page fault spike within short window
+        let start_ticks = idt::timer_ticks();
+        let start_pf = idt::page_faults();
+        // busy-wait a little (no sleep available here); allow IRQs to fire
+        for _ in 0..50_000 {
+            core::hint::spin_loop();
+            if idt::timer_ticks().saturating_sub(start_ticks) >= 1 {
+                break;
+            }
+        }
+        let dt = idt::timer_ticks().saturating_sub(start_ticks);
+        let dp = idt::page_faults().saturating_sub(start_pf);
+        kensure!(dt >= 1, ApplyError::SelfTestFailed);
+        kensure!(dp == 0, ApplyError::SelfTestFailed);
+        // Read back what the action changed: a quantum clamped by the scheduler,
+        // a pacing the controller did not take, memory lost by a trim all fail here
+        let effect = txn::check_effect(a, &observe(a, before.free_kib));
+        kensure!(effect.is_some(), ApplyError::SelfTestFailed);
+        Ok(effect.unwrap_or_default())
     }
+}
 
-    if self_test_ok() {
-        journal::journal_commit(seq, a);
-        Ok(())
-    } else {
-        let _ = write_quantum(before);
-        journal::journal_fail(seq, a, ApplyError::SelfTestFailed as u32);
-        Err(ApplyError::SelfTestFailed)
+/// The journal ring and the debugcon log.
+struct Recorder;
+
+impl txn::Journal for Recorder {
+    fn reject(&mut self, seq: u64, a: &Action) {
+        journal::journal_reject(seq, a);
+    }
+
+    fn intent(&mut self, seq: u64, a: &Action) {
+        journal::journal_intent(seq, a);
+    }
+
+    fn commit(&mut self, seq: u64, a: &Action, effect: u32) {
+        journal::journal_commit(seq, a, effect);
+    }
+
+    fn fail(&mut self, seq: u64, a: &Action, code: u32) {
+        journal::journal_fail(seq, a, code);
     }
 }
 
+pub fn apply_action_atomic(seq: u64, a: &Action, caller: Caller) -> ApplyResult<()> {
+    let _g = APPLY_LOCK.lock();
+    txn::apply(seq, a, caller, &mut Kernel, &mut Recorder)
+}
+
 #[no_mangle]
 pub extern "C" fn ai_propose_action(
     action: *const Action,
@@ -120,13 +216,18 @@ pub extern "C" fn ai_propose_action(
         return -1;
     }
     let a = unsafe { &*action };
-    let seq = unsafe {
-        let s = SEQ;
-        SEQ = SEQ.wrapping_add(1);
-        s
-    };
+    let res = propose(a, Caller::Kernel);
+    unsafe {
+        (*outcome).result = res;
+    }
+    0
+}
+
+/// Runs the transactional gate for `caller` and returns the outcome result code.
+pub fn propose(a: &Action, caller: Caller) -> u8 {
+    let seq = SEQ.fetch_add(1, Ordering::Relaxed);
 
-    let res = match apply_action_atomic(seq, a) {
+    match apply_action_atomic(seq, a, caller) {
         Ok(()) => 0u8,
         Err(e) => match e {
             ApplyError::NotAllowed => 1u8,
@@ -134,12 +235,7 @@ pub extern "C" fn ai_propose_action(
             ApplyError::ExecuteFailed => 4u8,
             ApplyError::SelfTestFailed => 5u8,
         },
-    };
-
-    unsafe {
-        (*outcome).result = res;
     }
-    0
 }
 
 pub fn set_system_ready() {
@@ -151,5 +247,33 @@ pub fn is_system_ready() -> bool {
 }
 
 pub fn get_quantum_us() -> u32 {
-    unsafe { QUANTUM_US }
+    QUANTUM_US.load(Ordering::Relaxed)
+}
+
+/// Minimum time between two agent inference steps (0 = every pass).
+pub fn get_ai_interval_ms() -> u32 {
+    AI_INTERVAL_MS.load(Ordering::Relaxed)
+}
+
+/// Percent of each second the agent task may use.
+pub fn get_ai_cpu_pct() -> u32 {
+    AI_CPU_PCT.load(Ordering::Relaxed)
+}
+
+/// Operator setting (`ai.cpu_pct`), not bound by the policy cap on
+/// `SetCpuBudget`; 0 is taken as 1 so the agent still gets turns.
+pub fn set_ai_cpu_pct(pct: u32) {
+    AI_CPU_PCT.store(pct.clamp(1, 100), Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ai_core::txn::Target;
+
+    #[test]
+    fn unknown_kind_is_refused_not_fatal() {
+        let a = Action { kind: 0xEE, ..Action::default() };
+        assert_eq!(Kernel.execute(&a), Err(ApplyError::InvalidParams));
+    }
 }
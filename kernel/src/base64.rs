@@ -0,0 +1,152 @@
+//! Base64 (RFC 4648, standard alphabet, `=` padding), for moving small
+//! binaries over a plain text console: `base64 <path>` prints a file,
+//! `base64 -d <path>` turns pasted text back into one.
+//!
+//! Decoding is incremental and skips whitespace, so text can be fed line
+//! by line as it arrives.
+
+const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+const PAD: u8 = b'=';
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    InvalidChar,
+    /// Data after the padding, or padding where none fits.
+    BadPadding,
+    /// The text stopped inside a group of four.
+    Truncated,
+}
+
+impl Error {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Error::InvalidChar => "invalid character",
+            Error::BadPadding => "bad padding",
+            Error::Truncated => "truncated input",
+        }
+    }
+}
+
+pub const fn encoded_len(n: usize) -> usize {
+    n.div_ceil(3) * 4
+}
+
+/// Encode `bytes` into `out`, which must hold `encoded_len(bytes.len())`;
+/// returns the length written.
+pub fn encode(bytes: &[u8], out: &mut [u8]) -> usize {
+    let mut n = 0;
+    for chunk in bytes.chunks(3) {
+        let b = [chunk[0], *chunk.get(1).unwrap_or(&0), *chunk.get(2).unwrap_or(&0)];
+        let v = (b[0] as u32) << 16 | (b[1] as u32) << 8 | b[2] as u32;
+        for i in 0..4 {
+            out[n + i] = if i <= chunk.len() { ALPHABET[(v >> (18 - 6 * i)) as usize & 63] } else { PAD };
+        }
+        n += 4;
+    }
+    n
+}
+
+fn value(c: u8) -> Option<u32> {
+    ALPHABET.iter().position(|&a| a == c).map(|v| v as u32)
+}
+
+#[derive(Default)]
+pub struct Decoder {
+    acc: u32,
+    /// Characters in the current group of four, padding included.
+    chars: usize,
+    /// Padding seen; nothing but more padding and whitespace may follow.
+    pad: usize,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decode `text` into `out`, which must hold `text.len() / 4 * 3 + 3`
+    /// bytes; returns how many were written.
+    pub fn feed(&mut self, text: &[u8], out: &mut [u8]) -> Result<usize, Error> {
+        let mut n = 0;
+        for &c in text {
+            if c.is_ascii_whitespace() {
+                continue;
+            }
+            if c == PAD {
+                // Only the last one or two characters of a group
+                if self.chars < 2 {
+                    return Err(Error::BadPadding);
+                }
+                self.pad += 1;
+            } else if self.pad > 0 {
+                return Err(Error::BadPadding);
+            } else {
+                self.acc = self.acc << 6 | value(c).ok_or(Error::InvalidChar)?;
+            }
+            self.chars += 1;
+            if self.chars == 4 {
+                let v = self.acc << (6 * self.pad);
+                let bytes = [(v >> 16) as u8, (v >> 8) as u8, v as u8];
+                let len = 3 - self.pad;
+                out[n..n + len].copy_from_slice(&bytes[..len]);
+                n += len;
+                self.acc = 0;
+                self.chars = 0;
+            }
+        }
+        Ok(n)
+    }
+
+    /// Check the text ended on a group boundary.
+    pub fn finish(self) -> Result<(), Error> {
+        match self.chars {
+            0 => Ok(()),
+            _ => Err(Error::Truncated),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(bytes: &[u8], text: &[u8]) {
+        let mut enc = [0u8; 32];
+        let n = encode(bytes, &mut enc);
+        assert_eq!(&enc[..n], text);
+        assert_eq!(n, encoded_len(bytes.len()));
+        let mut dec = [0u8; 32];
+        let mut d = Decoder::new();
+        let m = d.feed(text, &mut dec).unwrap();
+        assert_eq!(&dec[..m], bytes);
+        assert_eq!(d.finish(), Ok(()));
+    }
+
+    #[test]
+    fn rfc4648_vectors() {
+        round_trip(b"", b"");
+        round_trip(b"f", b"Zg==");
+        round_trip(b"fo", b"Zm8=");
+        round_trip(b"foo", b"Zm9v");
+        round_trip(b"foob", b"Zm9vYg==");
+        round_trip(b"fooba", b"Zm9vYmE=");
+        round_trip(b"foobar", b"Zm9vYmFy");
+    }
+
+    #[test]
+    fn decodes_across_lines_and_rejects_garbage() {
+        let mut out = [0u8; 16];
+        let mut d = Decoder::new();
+        let a = d.feed(b"Zm9v\r\nYm", &mut out).unwrap();
+        let b = d.feed(b"Fy\n", &mut out[a..]).unwrap();
+        assert_eq!(&out[..a + b], b"foobar");
+        assert_eq!(d.finish(), Ok(()));
+
+        assert_eq!(Decoder::new().feed(b"Zm9*", &mut out), Err(Error::InvalidChar));
+        assert_eq!(Decoder::new().feed(b"Zg==Zg==", &mut out), Err(Error::BadPadding));
+        assert_eq!(Decoder::new().feed(b"Z===", &mut out), Err(Error::BadPadding));
+        let mut d = Decoder::new();
+        d.feed(b"Zm9", &mut out).unwrap();
+        assert_eq!(d.finish(), Err(Error::Truncated));
+    }
+}
@@ -0,0 +1,204 @@
+//! On-target micro-benchmarks (`bench`), timed with the TSC so builds can
+//! be compared on the same machine.
+//!
+//! Each run prints one line: `bench <name>`, its parameters, then
+//! `cycles=<total>` and the rates derived from it as `key=value` pairs. The
+//! keys only ever get added to, so a script can diff two builds' output.
+//! Interrupts stay on; run a benchmark a few times and compare the best.
+//!
+//! `memcpy` and `memset` run once per `fastmem` strategy (`impl=`), so the
+//! one picked at boot can be checked against the others.
+//!
+//! `matmul` and `switch` time the agent's int8 kernel and its task stack
+//! switch, and need the `ai_agent` feature.
+
+use crate::fastmem::{self, Strategy};
+use crate::{pmm, time, vmm};
+
+pub const PMM_ITERS: u64 = 10_000;
+pub const DEFAULT_COPY_KIB: u64 = 1024;
+pub const MAX_COPY_KIB: u64 = 64 * 1024;
+/// Bytes copied per memcpy run, whatever the buffer size.
+const COPY_TOTAL: u64 = 64 * 1024 * 1024;
+pub const DEFAULT_MATMUL_N: usize = 64;
+#[cfg(feature = "ai_agent")]
+pub const MAX_MATMUL_N: usize = 256;
+/// Multiply-adds per matmul run, whatever the size.
+#[cfg(feature = "ai_agent")]
+const MATMUL_TOTAL_MACS: u64 = 128 * 1024 * 1024;
+#[cfg(feature = "ai_agent")]
+pub const SWITCH_ITERS: u64 = 10_000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BenchError {
+    OutOfRange,
+    NoMemory,
+    #[allow(dead_code)]
+    NotBuilt,
+}
+
+impl BenchError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BenchError::OutOfRange => "size out of range",
+            BenchError::NoMemory => "not enough free memory",
+            BenchError::NotBuilt => "needs the ai_agent feature",
+        }
+    }
+}
+
+/// What a run did: `ops` units of work in `cycles`.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Sample {
+    pub ops: u64,
+    pub cycles: u64,
+}
+
+impl Sample {
+    /// Average cycles per op.
+    pub fn cycles_per_op(&self) -> u64 {
+        self.cycles / self.ops.max(1)
+    }
+
+    /// Ops per second scaled by `scale` (1000 for milli-units), or `None`
+    /// before the TSC is calibrated.
+    pub fn per_second(&self, scale: u64, tsc_per_ms: u64) -> Option<u64> {
+        if tsc_per_ms == 0 {
+            return None;
+        }
+        let per_s = (self.ops as u128 * scale as u128).saturating_mul(tsc_per_ms as u128 * 1000) / self.cycles.max(1) as u128;
+        Some(per_s.min(u64::MAX as u128) as u64)
+    }
+}
+
+fn timed(ops: u64, f: impl FnOnce()) -> Sample {
+    let start = time::rdtsc();
+    f();
+    Sample { ops, cycles: time::rdtsc().wrapping_sub(start).max(1) }
+}
+
+/// Page allocations, each given back at once so the run leaks nothing.
+pub fn pmm_alloc() -> Result<Sample, BenchError> {
+    let mut result = Ok(());
+    let sample = timed(PMM_ITERS, || {
+        for _ in 0..PMM_ITERS {
+            let Some(page) = pmm::alloc_aligned("bench", vmm::PAGE_SIZE, vmm::PAGE_SIZE) else {
+                result = Err(BenchError::NoMemory);
+                return;
+            };
+            core::hint::black_box(page);
+            pmm::free_last(page, vmm::PAGE_SIZE);
+        }
+    });
+    result.map(|()| sample)
+}
+
+/// Copies between two `kib` KiB buffers with `strategy`; ops are bytes.
+pub fn memcpy(kib: u64, strategy: Strategy) -> Result<Sample, BenchError> {
+    with_buffers(kib, 2, |base, len| {
+        let src = crate::addr::PhysAddr::new(base).as_mut_ptr::<u8>();
+        let dst = crate::addr::PhysAddr::new(base + len).as_mut_ptr::<u8>();
+        unsafe { fastmem::fill(src, 0x5A, len as usize) };
+        let passes = passes(len, strategy);
+        timed(passes * len, || {
+            for _ in 0..passes {
+                unsafe { fastmem::copy_with(strategy, dst, core::hint::black_box(src), len as usize) };
+            }
+        })
+    })
+}
+
+/// Fills of a `kib` KiB buffer with `strategy`; ops are bytes.
+pub fn memset(kib: u64, strategy: Strategy) -> Result<Sample, BenchError> {
+    with_buffers(kib, 1, |base, len| {
+        let dst = crate::addr::PhysAddr::new(base).as_mut_ptr::<u8>();
+        let passes = passes(len, strategy);
+        timed(passes * len, || {
+            for pass in 0..passes {
+                unsafe { fastmem::fill_with(strategy, core::hint::black_box(dst), pass as u8, len as usize) };
+            }
+        })
+    })
+}
+
+/// Passes over a `len`-byte buffer; the byte loop gets fewer so it does not
+/// hold the shell for seconds.
+fn passes(len: u64, strategy: Strategy) -> u64 {
+    let total = if strategy == Strategy::Bytes { COPY_TOTAL / 16 } else { COPY_TOTAL };
+    (total / len).max(1)
+}
+
+/// Run `f` on `count` buffers of `kib` KiB, allocated back to back.
+fn with_buffers(kib: u64, count: u64, f: impl FnOnce(u64, u64) -> Sample) -> Result<Sample, BenchError> {
+    if kib == 0 || kib > MAX_COPY_KIB {
+        return Err(BenchError::OutOfRange);
+    }
+    let len = kib * 1024;
+    let base = pmm::alloc_aligned("bench", count * len, vmm::PAGE_SIZE).ok_or(BenchError::NoMemory)?;
+    let sample = f(base, len);
+    pmm::free_last(base, count * len);
+    Ok(sample)
+}
+
+/// `n`x`n` int8 products through the agent's kernel; ops are multiply-adds
+/// (two operations each in GOPS terms).
+#[cfg(feature = "ai_agent")]
+pub fn matmul(n: usize) -> Result<Sample, BenchError> {
+    if n == 0 || n > MAX_MATMUL_N {
+        return Err(BenchError::OutOfRange);
+    }
+    let (ab, out) = (n * n, n * n * 4);
+    let size = (2 * ab + out) as u64;
+    let base = pmm::alloc_aligned("bench", size, vmm::PAGE_SIZE).ok_or(BenchError::NoMemory)?;
+    let a = crate::addr::PhysAddr::new(base).as_mut_ptr::<i8>();
+    let b = unsafe { a.add(ab) };
+    let c = unsafe { b.add(ab) } as *mut i32;
+    for i in 0..2 * ab {
+        unsafe { a.add(i).write((i % 15) as i8 - 7) };
+    }
+    let macs = (n * n * n) as u64;
+    let reps = (MATMUL_TOTAL_MACS / macs).max(1);
+    let sample = timed(reps * macs, || {
+        for _ in 0..reps {
+            unsafe { crate::ai_agent::matmul_int8(core::hint::black_box(a), b, c, n, n, n) };
+        }
+    });
+    pmm::free_last(base, size);
+    Ok(sample)
+}
+
+#[cfg(not(feature = "ai_agent"))]
+pub fn matmul(_n: usize) -> Result<Sample, BenchError> {
+    Err(BenchError::NotBuilt)
+}
+
+/// Round trips onto a task stack and back, the switch `task::run_once`
+/// makes for every turn.
+#[cfg(feature = "ai_agent")]
+pub fn switch() -> Result<Sample, BenchError> {
+    let cycles = crate::task::switch_cycles(SWITCH_ITERS).ok_or(BenchError::NoMemory)?;
+    Ok(Sample { ops: SWITCH_ITERS, cycles: cycles.max(1) })
+}
+
+#[cfg(not(feature = "ai_agent"))]
+pub fn switch() -> Result<Sample, BenchError> {
+    Err(BenchError::NotBuilt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_scale_with_the_tsc() {
+        // 1000 ops in 2 ms at 1 GHz
+        let s = Sample { ops: 1000, cycles: 2_000_000 };
+        assert_eq!(s.cycles_per_op(), 2000);
+        assert_eq!(s.per_second(1, 1_000_000), Some(500_000));
+        assert_eq!(s.per_second(1000, 1_000_000), Some(500_000_000));
+        assert_eq!(s.per_second(1, 0), None);
+        assert_eq!(Sample { ops: 0, cycles: 0 }.cycles_per_op(), 0);
+        let huge = Sample { ops: u64::MAX, cycles: 1 };
+        assert_eq!(huge.per_second(1000, u64::MAX), Some(u64::MAX));
+    }
+}
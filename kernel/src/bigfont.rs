@@ -0,0 +1,109 @@
+//! Large text for `vga::banner`: a 5x7 font drawn with the code page 437
+//! half-block characters, two font rows per text row, so each letter takes
+//! 6 columns by 4 rows and reads from across the room.
+//!
+//! Letters are upper case only (lower case maps onto them); characters the
+//! font lacks show as `?`.
+
+/// Text rows of a rendered line.
+pub const ROWS: usize = 4;
+/// Columns per character, the gap included.
+pub const ADVANCE: usize = 6;
+
+const GLYPH_ROWS: usize = 7;
+const FULL_BLOCK: u8 = 0xDB;
+const UPPER_HALF: u8 = 0xDF;
+const LOWER_HALF: u8 = 0xDC;
+
+/// Font rows top to bottom, bit 4 the leftmost pixel.
+fn glyph(c: u8) -> [u8; GLYPH_ROWS] {
+    match c.to_ascii_uppercase() {
+        b' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        b'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        b'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        b'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        b'D' => [0x1E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x1E],
+        b'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        b'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        b'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        b'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        b'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        b'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        b'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        b'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        b'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        b'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        b'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        b'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        b'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        b'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        b'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        b'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        b'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        b'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        b'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        b'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        b'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        b'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        b'0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        b'1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        b'2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        b'3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        b'4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        b'5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        b'6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        b'7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        b'8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        b'9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        b'!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        b'-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        b'.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        b':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        b'/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+/// `text` as `ROWS` rows of `width` code page 437 cells, centred; what does
+/// not fit is cut off.
+pub fn render<const W: usize>(text: &[u8]) -> [[u8; W]; ROWS] {
+    let mut out = [[b' '; W]; ROWS];
+    let chars = text.len().min(W / ADVANCE);
+    // The last character's gap column is not part of the width
+    let used = (chars * ADVANCE).saturating_sub(1);
+    let left = W.saturating_sub(used) / 2;
+    for (i, &c) in text[..chars].iter().enumerate() {
+        let rows = glyph(c);
+        for (row, line) in out.iter_mut().enumerate() {
+            let top = rows[2 * row];
+            let bottom = rows.get(2 * row + 1).copied().unwrap_or(0);
+            for px in 0..5 {
+                let bit = 0x10 >> px;
+                line[left + i * ADVANCE + px] = match (top & bit != 0, bottom & bit != 0) {
+                    (true, true) => FULL_BLOCK,
+                    (true, false) => UPPER_HALF,
+                    (false, true) => LOWER_HALF,
+                    (false, false) => b' ',
+                };
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_half_blocks_centred() {
+        let rows = render::<13>(b"hi");
+        // 11 columns used, one free on each side
+        assert_eq!(rows[0], [b' ', 0xDB, b' ', b' ', b' ', 0xDB, b' ', b' ', 0xDF, 0xDB, 0xDF, b' ', b' ']);
+        assert_eq!(rows[1][1..6], [0xDB, 0xDC, 0xDC, 0xDC, 0xDB]);
+        assert_eq!(rows[3][1..12], [0xDF, b' ', b' ', b' ', 0xDF, b' ', b' ', 0xDF, 0xDF, 0xDF, b' ']);
+        // Too long: only what fits is drawn
+        assert_eq!(render::<6>(b"AB")[0], render::<6>(b"A")[0]);
+        assert_eq!(render::<7>(b"~")[0], render::<7>(b"?")[0]);
+    }
+}
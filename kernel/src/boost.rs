@@ -0,0 +1,87 @@
+//! Temporary priority boosts, set by the agent's `BoostTask` action.
+//!
+//! A boost names a task and lasts until its deadline; nothing needs to undo
+//! it. The shell is boosted by name too: while it is, the idle loop lets it
+//! read pending keys before the round robin gets the CPU. A boosted
+//! round-robin task gets every other pass.
+
+use spin::Mutex;
+
+use crate::ai_action::NAME_LEN;
+use crate::executor;
+
+/// The shell as a boost target; it is not a registered task.
+pub const SHELL: &str = "shell";
+pub const MAX_BOOSTS: usize = 4;
+
+#[derive(Copy, Clone)]
+pub struct Boost {
+    name: [u8; NAME_LEN],
+    len: usize,
+    /// TSC deadline.
+    until: u64,
+}
+
+impl Boost {
+    fn name(&self) -> &str {
+        core::str::from_utf8(&self.name[..self.len]).unwrap_or("")
+    }
+}
+
+/// Live boosts, as saved and put back around an action.
+pub type Boosts = [Option<Boost>; MAX_BOOSTS];
+
+static BOOSTS: Mutex<Boosts> = Mutex::new([None; MAX_BOOSTS]);
+
+fn expire(boosts: &mut Boosts) {
+    for slot in boosts.iter_mut() {
+        if slot.is_some_and(|b| executor::expired(b.until)) {
+            *slot = None;
+        }
+    }
+}
+
+/// Boost `name` for `ms`, replacing a boost it already has. Returns false
+/// when every slot holds a live boost for another task.
+pub fn set(name: &str, ms: u64) -> bool {
+    if name.is_empty() || name.len() > NAME_LEN {
+        return false;
+    }
+    let mut boosts = BOOSTS.lock();
+    expire(&mut boosts);
+    let slot = match boosts.iter().position(|b| b.is_some_and(|b| b.name() == name)) {
+        Some(i) => i,
+        None => match boosts.iter().position(Option::is_none) {
+            Some(i) => i,
+            None => return false,
+        },
+    };
+    let mut bytes = [0u8; NAME_LEN];
+    bytes[..name.len()].copy_from_slice(name.as_bytes());
+    boosts[slot] = Some(Boost { name: bytes, len: name.len(), until: executor::deadline_after_ms(ms) });
+    true
+}
+
+#[cfg(feature = "ai_agent")]
+pub fn active(name: &str) -> bool {
+    BOOSTS.lock().iter().flatten().any(|b| b.name() == name && !executor::expired(b.until))
+}
+
+pub fn snapshot() -> Boosts {
+    *BOOSTS.lock()
+}
+
+pub fn restore(saved: Boosts) {
+    *BOOSTS.lock() = saved;
+}
+
+/// Live boosts with their remaining time in ms.
+pub fn for_each(mut f: impl FnMut(&str, u64)) {
+    let mut boosts = BOOSTS.lock();
+    expire(&mut boosts);
+    let now = crate::time::rdtsc();
+    for b in boosts.iter().flatten() {
+        let left = crate::time::cycles_to_us(b.until.saturating_sub(now)).unwrap_or(0) / 1000;
+        f(b.name(), left);
+    }
+}
@@ -1,12 +1,175 @@
+//! Boot contract with stage2.
+//!
+//! Stage2 passes a pointer to a structure in its own image. Since v2 it
+//! starts with `MAGIC`, the version and the structure's size, followed by
+//! `flags` telling which optional fields were filled in; fields past `size`
+//! are treated as absent, so either side can grow the structure without
+//! breaking the other. A v1 stage2 passes the memory map and initrd fields
+//! alone, recognised by the missing magic (its first field is a pointer into
+//! low memory, which never spells the magic).
+//!
+//! v2 layout (offsets in bytes, all little-endian):
+//! `0` magic u64, `8` version u32, `12` size u32, `16` flags u64,
+//! `24` memory map ptr, `32` entry count, `40` entry size, `48` initrd base,
+//! `56` initrd length, `64` cmdline ptr, `72` cmdline length, `80` RSDP ptr,
+//! `88` framebuffer address u64, `96` width, `100` height, `104` pitch,
+//! `108` bits per pixel (u32 each).
+
 use core::{cmp::max, mem};
+use spin::Once;
 
-#[repr(C)]
+/// "BOOTINFO"
+pub const MAGIC: u64 = 0x4F46_4E49_544F_4F42;
+pub const VERSION: u32 = 2;
+
+pub const FLAG_INITRD: u64 = 1 << 0;
+pub const FLAG_CMDLINE: u64 = 1 << 1;
+pub const FLAG_RSDP: u64 = 1 << 2;
+pub const FLAG_FRAMEBUFFER: u64 = 1 << 3;
+
+/// Bytes a v1 stage2 passes: five u64 fields.
+const V1_LEN: usize = 40;
+const V2_HEADER_LEN: usize = 24;
+/// Larger sizes are not trusted; a corrupt header should not make us read
+/// far past stage2's data.
+const MAX_LEN: usize = 4096;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Framebuffer {
+    pub addr: u64,
+    pub width: u32,
+    pub height: u32,
+    pub pitch: u32,
+    pub bpp: u32,
+}
+
+/// What the kernel knows about the boot, normalised from either layout.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct BootInfo {
-    pub memory_map: *const MemoryMapEntry,
-    pub memory_map_len: u64,
-    pub memory_map_entry_size: u64,
-    pub initrd_base: u64,
-    pub initrd_len: u64,
+    /// 1 for a stage2 that predates the header.
+    pub version: u32,
+    /// Bytes of the structure stage2 passed.
+    pub size: u32,
+    memory_map: u64,
+    memory_map_len: u64,
+    memory_map_entry_size: u64,
+    initrd: Option<(u64, u64)>,
+    cmdline: Option<(u64, u64)>,
+    pub rsdp: Option<u64>,
+    pub framebuffer: Option<Framebuffer>,
+}
+
+static BOOT_INFO: Once<BootInfo> = Once::new();
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(bytes.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(bytes.get(at..at + 8)?.try_into().ok()?))
+}
+
+impl BootInfo {
+    /// Decode what stage2 passed; `bytes` may run past the structure.
+    fn parse(bytes: &[u8]) -> Option<BootInfo> {
+        if read_u64(bytes, 0)? != MAGIC {
+            let v1 = bytes.get(..V1_LEN)?;
+            let (initrd_base, initrd_len) = (read_u64(v1, 24)?, read_u64(v1, 32)?);
+            return Some(BootInfo {
+                version: 1,
+                size: V1_LEN as u32,
+                memory_map: read_u64(v1, 0)?,
+                memory_map_len: read_u64(v1, 8)?,
+                memory_map_entry_size: read_u64(v1, 16)?,
+                initrd: (initrd_len != 0).then_some((initrd_base, initrd_len)),
+                cmdline: None,
+                rsdp: None,
+                framebuffer: None,
+            });
+        }
+        let version = read_u32(bytes, 8)?;
+        let size = read_u32(bytes, 12)? as usize;
+        if version < 2 || !(V2_HEADER_LEN + 24..=MAX_LEN).contains(&size) {
+            return None;
+        }
+        // Only what stage2 says it wrote
+        let b = bytes.get(..size)?;
+        let flags = read_u64(b, 16)?;
+        let present = |flag: u64| flags & flag != 0;
+        let initrd = match (read_u64(b, 48), read_u64(b, 56)) {
+            (Some(base), Some(len)) if present(FLAG_INITRD) => Some((base, len)),
+            _ => None,
+        };
+        let cmdline = match (read_u64(b, 64), read_u64(b, 72)) {
+            (Some(ptr), Some(len)) if present(FLAG_CMDLINE) => Some((ptr, len)),
+            _ => None,
+        };
+        let framebuffer = match (read_u64(b, 88), read_u32(b, 108)) {
+            (Some(addr), Some(bpp)) if present(FLAG_FRAMEBUFFER) => Some(Framebuffer {
+                addr,
+                width: read_u32(b, 96)?,
+                height: read_u32(b, 100)?,
+                pitch: read_u32(b, 104)?,
+                bpp,
+            }),
+            _ => None,
+        };
+        Some(BootInfo {
+            version,
+            size: size as u32,
+            memory_map: read_u64(b, 24)?,
+            memory_map_len: read_u64(b, 32)?,
+            memory_map_entry_size: read_u64(b, 40)?,
+            initrd,
+            cmdline,
+            rsdp: read_u64(b, 80).filter(|_| present(FLAG_RSDP)),
+            framebuffer,
+        })
+    }
+
+    /// Decode the structure at `ptr` and keep it for `get`.
+    ///
+    /// # Safety
+    /// `ptr` must be what stage2 passed in `rdi`.
+    pub unsafe fn init(ptr: *const u8) -> Option<&'static BootInfo> {
+        if ptr.is_null() {
+            return None;
+        }
+        let first = core::slice::from_raw_parts(ptr, 16);
+        let len = if read_u64(first, 0) == Some(MAGIC) {
+            (read_u32(first, 12)? as usize).clamp(16, MAX_LEN)
+        } else {
+            V1_LEN
+        };
+        let info = Self::parse(core::slice::from_raw_parts(ptr, len))?;
+        Some(BOOT_INFO.call_once(|| info))
+    }
+
+    pub fn get() -> Option<&'static BootInfo> {
+        BOOT_INFO.get()
+    }
+
+    pub unsafe fn memory_map(&self) -> MemoryMapIter {
+        let stride = max(
+            self.memory_map_entry_size,
+            mem::size_of::<MemoryMapEntry>() as u64,
+        ) as usize;
+        MemoryMapIter {
+            ptr: self.memory_map as *const u8,
+            remaining: self.memory_map_len,
+            stride,
+        }
+    }
+
+    pub fn initrd_base(&self) -> u64 { self.initrd.map_or(0, |(base, _)| base) }
+    pub fn initrd_len(&self) -> u64 { self.initrd.map_or(0, |(_, len)| len) }
+
+    /// Kernel command line, if stage2 passed one that is valid UTF-8.
+    pub fn cmdline(&self) -> Option<&'static str> {
+        let (ptr, len) = self.cmdline?;
+        let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, len.min(MAX_LEN as u64) as usize) };
+        core::str::from_utf8(bytes).ok()
+    }
 }
 
 #[repr(C)]
@@ -29,6 +192,27 @@ pub enum MemoryRegionKind {
 }
 
 impl MemoryRegionKind {
+    /// One of each kind, in `index` order (`Unknown` stands for all types).
+    pub const ALL: [MemoryRegionKind; 6] = [
+        MemoryRegionKind::Usable,
+        MemoryRegionKind::Reserved,
+        MemoryRegionKind::AcpiReclaimable,
+        MemoryRegionKind::AcpiNvs,
+        MemoryRegionKind::BadMemory,
+        MemoryRegionKind::Unknown(0),
+    ];
+
+    pub fn index(self) -> usize {
+        match self {
+            MemoryRegionKind::Usable => 0,
+            MemoryRegionKind::Reserved => 1,
+            MemoryRegionKind::AcpiReclaimable => 2,
+            MemoryRegionKind::AcpiNvs => 3,
+            MemoryRegionKind::BadMemory => 4,
+            MemoryRegionKind::Unknown(_) => 5,
+        }
+    }
+
     pub fn as_str(self) -> &'static str {
         match self {
             MemoryRegionKind::Usable => "Usable",
@@ -58,29 +242,23 @@ impl MemoryMapEntry {
     }
 }
 
+/// Per kind (`MemoryRegionKind::index`): number of entries and bytes.
+pub fn region_totals<'a>(entries: impl Iterator<Item = &'a MemoryMapEntry>) -> [(u64, u64); 6] {
+    let mut totals = [(0u64, 0u64); 6];
+    for entry in entries {
+        let total = &mut totals[entry.kind().index()];
+        total.0 += 1;
+        total.1 = total.1.saturating_add(entry.length);
+    }
+    totals
+}
+
 pub struct MemoryMapIter {
     ptr: *const u8,
     remaining: u64,
     stride: usize,
 }
 
-impl BootInfo {
-    pub unsafe fn memory_map(&self) -> MemoryMapIter {
-        let stride = max(
-            self.memory_map_entry_size,
-            mem::size_of::<MemoryMapEntry>() as u64,
-        ) as usize;
-        MemoryMapIter {
-            ptr: self.memory_map.cast(),
-            remaining: self.memory_map_len,
-            stride,
-        }
-    }
-
-    pub fn initrd_base(&self) -> u64 { self.initrd_base }
-    pub fn initrd_len(&self) -> u64 { self.initrd_len }
-}
-
 impl Iterator for MemoryMapIter {
     type Item = &'static MemoryMapEntry;
 
@@ -95,3 +273,89 @@ impl Iterator for MemoryMapIter {
         Some(entry)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn put(buf: &mut [u8], at: usize, bytes: &[u8]) {
+        buf[at..at + bytes.len()].copy_from_slice(bytes);
+    }
+
+    fn v2(size: u32, flags: u64) -> [u8; 112] {
+        let mut b = [0u8; 112];
+        put(&mut b, 0, &MAGIC.to_le_bytes());
+        put(&mut b, 8, &2u32.to_le_bytes());
+        put(&mut b, 12, &size.to_le_bytes());
+        put(&mut b, 16, &flags.to_le_bytes());
+        put(&mut b, 24, &0x8_1000u64.to_le_bytes());
+        put(&mut b, 32, &6u64.to_le_bytes());
+        put(&mut b, 40, &24u64.to_le_bytes());
+        put(&mut b, 48, &0x100_0000u64.to_le_bytes());
+        put(&mut b, 56, &4096u64.to_le_bytes());
+        put(&mut b, 80, &0xF_6A20u64.to_le_bytes());
+        put(&mut b, 88, &0xFD00_0000u64.to_le_bytes());
+        for (i, v) in [1024u32, 768, 4096, 32].iter().enumerate() {
+            put(&mut b, 96 + 4 * i, &v.to_le_bytes());
+        }
+        b
+    }
+
+    #[test]
+    fn totals_group_by_kind() {
+        let entry = |base_addr, length, region_type| MemoryMapEntry { base_addr, length, region_type, attributes: 1 };
+        let map = [
+            entry(0, 0x9_FC00, 1),
+            entry(0x9_FC00, 0x400, 2),
+            entry(0x10_0000, 0x7FE_0000, 1),
+            entry(0x7FE_0000, 0x2_0000, 3),
+            entry(0xFFFC_0000, 0x4_0000, 2),
+            entry(0x1_0000_0000, 0x1000, 12),
+        ];
+        let totals = region_totals(map.iter());
+        assert_eq!(totals[MemoryRegionKind::Usable.index()], (2, 0x9_FC00 + 0x7FE_0000));
+        assert_eq!(totals[MemoryRegionKind::Reserved.index()], (2, 0x4_0400));
+        assert_eq!(totals[MemoryRegionKind::AcpiReclaimable.index()], (1, 0x2_0000));
+        assert_eq!(totals[MemoryRegionKind::Unknown(12).index()], (1, 0x1000));
+        for (i, kind) in MemoryRegionKind::ALL.iter().enumerate() {
+            assert_eq!(kind.index(), i);
+        }
+    }
+
+    #[test]
+    fn v1_layout_is_still_accepted() {
+        let mut b = [0u8; 48];
+        put(&mut b, 0, &0x8_1000u64.to_le_bytes());
+        put(&mut b, 8, &6u64.to_le_bytes());
+        put(&mut b, 16, &24u64.to_le_bytes());
+        let info = BootInfo::parse(&b).unwrap();
+        assert_eq!((info.version, info.memory_map_len, info.initrd), (1, 6, None));
+        put(&mut b, 24, &0x100_0000u64.to_le_bytes());
+        put(&mut b, 32, &4096u64.to_le_bytes());
+        let info = BootInfo::parse(&b).unwrap();
+        assert_eq!((info.initrd_base(), info.initrd_len()), (0x100_0000, 4096));
+        assert_eq!(info.rsdp, None);
+    }
+
+    #[test]
+    fn v2_optional_fields_follow_flags() {
+        let info = BootInfo::parse(&v2(112, FLAG_INITRD | FLAG_RSDP)).unwrap();
+        assert_eq!((info.version, info.size, info.memory_map_len), (2, 112, 6));
+        assert_eq!(info.initrd, Some((0x100_0000, 4096)));
+        assert_eq!(info.rsdp, Some(0xF_6A20));
+        assert_eq!((info.framebuffer, info.cmdline), (None, None));
+        let fb = BootInfo::parse(&v2(112, FLAG_FRAMEBUFFER)).unwrap().framebuffer.unwrap();
+        assert_eq!((fb.width, fb.height, fb.pitch, fb.bpp), (1024, 768, 4096, 32));
+    }
+
+    #[test]
+    fn fields_past_size_are_absent() {
+        // An older v2 that ends before the RSDP field
+        let info = BootInfo::parse(&v2(80, FLAG_INITRD | FLAG_RSDP)).unwrap();
+        assert_eq!((info.initrd_len(), info.rsdp), (4096, None));
+        assert_eq!(BootInfo::parse(&v2(16, 0)), None);
+        let mut old = v2(112, 0);
+        put(&mut old, 8, &1u32.to_le_bytes());
+        assert_eq!(BootInfo::parse(&old), None);
+    }
+}
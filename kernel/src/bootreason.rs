@@ -0,0 +1,175 @@
+//! Why the previous boot ended, kept across warm reboots in CMOS RAM.
+//!
+//! At boot the record is read, the consecutive-crash counter updated, and the
+//! reason set to `Running`; orderly exits overwrite it with `Clean`, fatal
+//! handlers with their cause. Finding `Running` at the next boot means the
+//! machine was reset without either (triple fault, hard reset).
+//!
+//! Every write is mirrored to pstore, whose copy stands in when the CMOS
+//! bytes do not decode (a firmware that clears that bank on reset).
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::rtc::{cmos_read, cmos_write};
+use crate::pstore::{self, Section};
+use crate::{config, serial};
+
+// Bytes 0x78..0x7B of the extended CMOS bank: unused by SeaBIOS and QEMU.
+const CMOS_BASE: u8 = 0x78;
+const MAGIC: u8 = 0xB7;
+const DEFAULT_CRASH_LIMIT: u64 = 3;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum BootReason {
+    /// No valid record: cold boot or CMOS cleared.
+    Unknown = 0,
+    Clean = 1,
+    Panic = 2,
+    Watchdog = 3,
+    DoubleFault = 4,
+    /// The kernel was still running when the machine reset.
+    Running = 5,
+}
+
+impl BootReason {
+    fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => BootReason::Unknown,
+            1 => BootReason::Clean,
+            2 => BootReason::Panic,
+            3 => BootReason::Watchdog,
+            4 => BootReason::DoubleFault,
+            5 => BootReason::Running,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BootReason::Unknown => "unknown",
+            BootReason::Clean => "clean",
+            BootReason::Panic => "panic",
+            BootReason::Watchdog => "watchdog",
+            BootReason::DoubleFault => "double fault",
+            BootReason::Running => "reset while running",
+        }
+    }
+
+    pub fn is_crash(self) -> bool {
+        matches!(
+            self,
+            BootReason::Panic | BootReason::Watchdog | BootReason::DoubleFault | BootReason::Running
+        )
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Record {
+    reason: BootReason,
+    crashes: u8,
+}
+
+impl Record {
+    fn encode(self) -> [u8; 4] {
+        let reason = self.reason as u8;
+        [MAGIC, reason, self.crashes, !(MAGIC ^ reason ^ self.crashes)]
+    }
+
+    fn decode(bytes: [u8; 4]) -> Option<Self> {
+        let [magic, reason, crashes, check] = bytes;
+        if magic != MAGIC || check != !(magic ^ reason ^ crashes) {
+            return None;
+        }
+        Some(Record { reason: BootReason::from_u8(reason)?, crashes })
+    }
+
+    /// Record for the boot that follows one which left `self`.
+    fn next_boot(prev: Option<Self>) -> Self {
+        let crashes = match prev {
+            Some(p) if p.reason.is_crash() => p.crashes.saturating_add(1),
+            _ => 0,
+        };
+        Record { reason: BootReason::Running, crashes }
+    }
+}
+
+static PREVIOUS: AtomicU8 = AtomicU8::new(BootReason::Unknown as u8);
+static CRASHES: AtomicU8 = AtomicU8::new(0);
+
+fn store(record: Record) {
+    interrupts::without_interrupts(|| {
+        for (i, b) in record.encode().iter().enumerate() {
+            cmos_write(CMOS_BASE + i as u8, *b);
+        }
+    });
+    let _ = pstore::try_write(Section::BootReason, &record.encode());
+}
+
+/// Read what the last boot left behind and mark this one as running.
+pub fn init() {
+    let bytes = interrupts::without_interrupts(|| core::array::from_fn(|i| cmos_read(CMOS_BASE + i as u8)));
+    let prev = Record::decode(bytes).or_else(|| {
+        pstore::read(Section::BootReason, |b| b.try_into().ok().and_then(Record::decode)).flatten()
+    });
+    let next = Record::next_boot(prev);
+    PREVIOUS.store(prev.map_or(BootReason::Unknown, |p| p.reason) as u8, Ordering::Relaxed);
+    CRASHES.store(next.crashes, Ordering::Relaxed);
+    store(next);
+    serial::write_fmt(format_args!(
+        "[boot] last boot: {}, consecutive crashes: {}\r\n",
+        previous().as_str(),
+        next.crashes
+    ));
+    if crash_loop() {
+        serial::write_str("[boot] crash loop detected, AI policy disabled\r\n");
+    }
+}
+
+/// Record how this boot is ending. Called from the panic and fault paths,
+/// so it takes no locks.
+pub fn record(reason: BootReason) {
+    store(Record { reason, crashes: CRASHES.load(Ordering::Relaxed) });
+}
+
+pub fn previous() -> BootReason {
+    BootReason::from_u8(PREVIOUS.load(Ordering::Relaxed)).unwrap_or(BootReason::Unknown)
+}
+
+/// Crashes in a row before this boot (0 after a clean shutdown).
+pub fn consecutive_crashes() -> u8 {
+    CRASHES.load(Ordering::Relaxed)
+}
+
+/// Whether recent boots kept crashing (`ai.crash_limit`, default 3, 0 = never).
+pub fn crash_loop() -> bool {
+    let limit = config::get_u64("ai.crash_limit").unwrap_or(DEFAULT_CRASH_LIMIT);
+    limit != 0 && consecutive_crashes() as u64 >= limit
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_round_trips_and_rejects_garbage() {
+        let r = Record { reason: BootReason::Panic, crashes: 2 };
+        assert_eq!(Record::decode(r.encode()), Some(r));
+        assert_eq!(Record::decode([0; 4]), None);
+        let mut bytes = r.encode();
+        bytes[2] ^= 1;
+        assert_eq!(Record::decode(bytes), None);
+    }
+
+    #[test]
+    fn crashes_count_until_a_clean_exit() {
+        let crashed = Record { reason: BootReason::DoubleFault, crashes: 1 };
+        assert_eq!(Record::next_boot(Some(crashed)).crashes, 2);
+        let reset = Record { reason: BootReason::Running, crashes: 0 };
+        assert_eq!(Record::next_boot(Some(reset)).crashes, 1);
+        let clean = Record { reason: BootReason::Clean, crashes: 4 };
+        assert_eq!(Record::next_boot(Some(clean)).crashes, 0);
+        assert_eq!(Record::next_boot(None), Record { reason: BootReason::Running, crashes: 0 });
+    }
+}
@@ -0,0 +1,345 @@
+#![allow(dead_code)]
+
+use spin::Mutex;
+
+use crate::{kv, ramfs, serial};
+
+/// Path of the boot configuration inside the initrd (cpio names have no leading slash).
+pub const CONFIG_PATH: &str = "cfg/kernel.toml";
+/// Settings kept by `config save` live in the kv store under this prefix.
+const SAVED_PREFIX: &str = "cfg.";
+
+const MAX_ENTRIES: usize = 32;
+const KEY_LEN: usize = 32;
+pub const VAL_LEN: usize = 64;
+
+// Keys every build understands, with their defaults. Unknown keys from the file are kept too.
+const DEFAULTS: &[(&str, &str)] = &[
+    ("log.level", "info"),
+    ("keymap", "us"),
+    ("timer.hz", "18"),
+    ("timer.source", "apic"),
+    ("irq.unmask", "0,1,8"),
+    ("ai.mode", "act"),
+    ("ai.crash_limit", "3"),
+    ("ai.model_sha256", ""),
+    ("ai.budget_us", "2000"),
+    ("ai.cpu_pct", "10"),
+    ("ai.slow_limit", "3"),
+    ("ai.controller", "auto"),
+    ("ai.guard.rollbacks", "3"),
+    ("ai.guard.window_s", "60"),
+    ("xhci.imod_us", "1000"),
+    ("hid.pace_ms", "0"),
+    ("console.blank_min", "10"),
+    ("console.lock", ""),
+    ("panic", "halt"),
+    ("panic.delay_s", "5"),
+    ("shell.output", "human"),
+];
+
+#[derive(Copy, Clone)]
+struct Entry {
+    key: [u8; KEY_LEN],
+    key_len: u8,
+    val: [u8; VAL_LEN],
+    val_len: u8,
+}
+
+impl Entry {
+    fn key(&self) -> &str {
+        core::str::from_utf8(&self.key[..self.key_len as usize]).unwrap_or("")
+    }
+
+    fn val(&self) -> &str {
+        core::str::from_utf8(&self.val[..self.val_len as usize]).unwrap_or("")
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ConfigError {
+    KeyTooLong,
+    ValueTooLong,
+    Full,
+}
+
+static REGISTRY: Mutex<[Option<Entry>; MAX_ENTRIES]> = Mutex::new([None; MAX_ENTRIES]);
+
+/// `line` up to its first `#` outside double quotes.
+fn strip_comment(line: &str) -> &str {
+    let mut quoted = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '#' if !quoted => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+/// Parses `key = value` lines; `[section]` headers prefix keys as `section.key`,
+/// `#` outside quotes starts a comment, and surrounding double quotes are
+/// stripped from values.
+pub fn parse<'a>(text: &'a str, mut f: impl FnMut(&'a str, &'a str, &'a str)) {
+    let mut section = "";
+    for raw in text.lines() {
+        let line = strip_comment(raw).trim();
+        if line.is_empty() {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim();
+            continue;
+        }
+        if let Some(eq) = line.find('=') {
+            let key = line[..eq].trim();
+            let mut val = line[eq + 1..].trim();
+            if val.len() >= 2 && val.starts_with('"') && val.ends_with('"') {
+                val = &val[1..val.len() - 1];
+            }
+            if !key.is_empty() {
+                f(section, key, val);
+            }
+        }
+    }
+}
+
+pub fn set(key: &str, val: &str) -> Result<(), ConfigError> {
+    if key.len() > KEY_LEN {
+        return Err(ConfigError::KeyTooLong);
+    }
+    if val.len() > VAL_LEN {
+        return Err(ConfigError::ValueTooLong);
+    }
+    let mut reg = REGISTRY.lock();
+    let idx = match reg.iter().position(|e| e.map(|e| e.key() == key).unwrap_or(false)) {
+        Some(i) => i,
+        None => reg.iter().position(|e| e.is_none()).ok_or(ConfigError::Full)?,
+    };
+    let mut e = Entry { key: [0; KEY_LEN], key_len: key.len() as u8, val: [0; VAL_LEN], val_len: val.len() as u8 };
+    e.key[..key.len()].copy_from_slice(key.as_bytes());
+    e.val[..val.len()].copy_from_slice(val.as_bytes());
+    reg[idx] = Some(e);
+    Ok(())
+}
+
+/// Calls `f` with the value of `key` while the registry is locked.
+pub fn with<R>(key: &str, f: impl FnOnce(&str) -> R) -> Option<R> {
+    let reg = REGISTRY.lock();
+    reg.iter().flatten().find(|e| e.key() == key).map(|e| f(e.val()))
+}
+
+pub fn get_u64(key: &str) -> Option<u64> {
+    with(key, |v| v.parse::<u64>().ok()).flatten()
+}
+
+pub fn get_bool(key: &str) -> Option<bool> {
+    with(key, |v| match v {
+        "1" | "true" | "on" | "yes" => Some(true),
+        "0" | "false" | "off" | "no" => Some(false),
+        _ => None,
+    })
+    .flatten()
+}
+
+pub fn for_each(mut f: impl FnMut(&str, &str)) {
+    let reg = REGISTRY.lock();
+    for e in reg.iter().flatten() {
+        f(e.key(), e.val());
+    }
+}
+
+/// `key=value` words of a boot command line; words without `=` are skipped.
+pub fn parse_cmdline<'a>(cmdline: &'a str, mut f: impl FnMut(&'a str, &'a str)) {
+    for word in cmdline.split_ascii_whitespace() {
+        if let Some((key, val)) = word.split_once('=') {
+            if !key.is_empty() {
+                f(key, val);
+            }
+        }
+    }
+}
+
+/// Installs defaults, overlays the initrd config file if present, the
+/// settings saved in the kv store, then the boot command line.
+pub fn init(cmdline: Option<&str>) {
+    for (k, v) in DEFAULTS {
+        let _ = set(k, v);
+    }
+    load_file();
+    load_saved();
+    if let Some(cmdline) = cmdline {
+        load_cmdline(cmdline);
+    }
+}
+
+fn load_file() {
+    let (ptr, size) = match ramfs::find(CONFIG_PATH) {
+        Some(f) => f,
+        None => {
+            serial::write_str("[cfg] no cfg/kernel.toml, using defaults\r\n");
+            return;
+        }
+    };
+    let bytes = unsafe { core::slice::from_raw_parts(ptr, size) };
+    let text = match core::str::from_utf8(bytes) {
+        Ok(t) => t,
+        Err(_) => {
+            serial::write_str("[cfg] kernel.toml is not utf-8, ignored\r\n");
+            return;
+        }
+    };
+    let mut loaded = 0u32;
+    parse(text, |section, key, val| {
+        let mut buf = [0u8; KEY_LEN];
+        let full = match join_key(&mut buf, section, key) {
+            Some(k) => k,
+            None => {
+                serial::write_fmt(format_args!("[cfg] key too long: {}\r\n", key));
+                return;
+            }
+        };
+        match set(full, val) {
+            Ok(()) => loaded += 1,
+            Err(e) => serial::write_fmt(format_args!("[cfg] {}: {:?}\r\n", full, e)),
+        }
+    });
+    serial::write_fmt(format_args!("[cfg] loaded {} entries from {}\r\n", loaded, CONFIG_PATH));
+}
+
+fn load_saved() {
+    let mut loaded = 0u32;
+    kv::for_each(|key, val| {
+        let (Some(key), Ok(val)) = (key.strip_prefix(SAVED_PREFIX), core::str::from_utf8(val)) else { return };
+        match set(key, val) {
+            Ok(()) => loaded += 1,
+            Err(e) => serial::write_fmt(format_args!("[cfg] saved {}: {:?}\r\n", key, e)),
+        }
+    });
+    if loaded != 0 {
+        serial::write_fmt(format_args!("[cfg] applied {} saved entries\r\n", loaded));
+    }
+}
+
+fn load_cmdline(cmdline: &str) {
+    let mut loaded = 0u32;
+    parse_cmdline(cmdline, |key, val| match set(key, val) {
+        Ok(()) => loaded += 1,
+        Err(e) => serial::write_fmt(format_args!("[cfg] cmdline {}: {:?}\r\n", key, e)),
+    });
+    if loaded != 0 {
+        serial::write_fmt(format_args!("[cfg] applied {} entries from the command line\r\n", loaded));
+    }
+}
+
+fn saved_key<'a>(buf: &'a mut [u8; KEY_LEN + SAVED_PREFIX.len()], key: &str) -> Option<&'a str> {
+    let end = SAVED_PREFIX.len() + key.len();
+    if key.len() > KEY_LEN {
+        return None;
+    }
+    buf[..SAVED_PREFIX.len()].copy_from_slice(SAVED_PREFIX.as_bytes());
+    buf[SAVED_PREFIX.len()..end].copy_from_slice(key.as_bytes());
+    core::str::from_utf8(&buf[..end]).ok()
+}
+
+/// Persist the current value of `key` so it is applied on the next boot.
+/// `Ok(false)` if the key is not set.
+pub fn save(key: &str) -> Result<bool, kv::KvError> {
+    let mut buf = [0u8; KEY_LEN + SAVED_PREFIX.len()];
+    let full = saved_key(&mut buf, key).ok_or(kv::KvError::KeyTooLong)?;
+    let mut val = [0u8; VAL_LEN];
+    let Some(len) = with(key, |v| {
+        val[..v.len()].copy_from_slice(v.as_bytes());
+        v.len()
+    }) else {
+        return Ok(false);
+    };
+    kv::set(full, &val[..len]).map(|()| true)
+}
+
+/// Drop a saved setting; the file or default value applies from the next boot.
+pub fn forget(key: &str) -> Result<bool, kv::KvError> {
+    let mut buf = [0u8; KEY_LEN + SAVED_PREFIX.len()];
+    let full = saved_key(&mut buf, key).ok_or(kv::KvError::KeyTooLong)?;
+    kv::remove(full)
+}
+
+fn join_key<'a>(buf: &'a mut [u8; KEY_LEN], section: &str, key: &str) -> Option<&'a str> {
+    let mut n = 0usize;
+    let mut push = |s: &str, n: &mut usize| -> Option<()> {
+        let end = *n + s.len();
+        if end > KEY_LEN {
+            return None;
+        }
+        buf[*n..end].copy_from_slice(s.as_bytes());
+        *n = end;
+        Some(())
+    };
+    if !section.is_empty() {
+        push(section, &mut n)?;
+        push(".", &mut n)?;
+    }
+    push(key, &mut n)?;
+    core::str::from_utf8(&buf[..n]).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_sections_comments_and_quotes() {
+        let text = "# boot config\nkeymap = fr\n\n[ai]\nenabled = false # off for now\nname = \"a # b\"\n[log]\nlevel=debug\n";
+        let mut seen = [("", "", ""); 4];
+        let mut n = 0;
+        parse(text, |s, k, v| {
+            seen[n] = (s, k, v);
+            n += 1;
+        });
+        assert_eq!(n, 4);
+        assert_eq!(seen[0], ("", "keymap", "fr"));
+        assert_eq!(seen[1], ("ai", "enabled", "false"));
+        assert_eq!(seen[2], ("ai", "name", "a # b"));
+        assert_eq!(seen[3], ("log", "level", "debug"));
+    }
+
+    #[test]
+    fn comment_after_a_quoted_value_is_dropped() {
+        let mut seen = [("", ""); 3];
+        let mut n = 0;
+        parse("keymap = \"us\"  # qwerty\nname = \"a # b\" # c\nmotd = \"#1\"\n", |_, k, v| {
+            seen[n] = (k, v);
+            n += 1;
+        });
+        assert_eq!(n, 3);
+        assert_eq!(seen, [("keymap", "us"), ("name", "a # b"), ("motd", "#1")]);
+    }
+
+    #[test]
+    fn ignores_lines_without_key() {
+        let mut n = 0;
+        parse("garbage\n= value\n[]\n", |_, _, _| n += 1);
+        assert_eq!(n, 0);
+    }
+
+    #[test]
+    fn cmdline_words_are_key_value_pairs() {
+        let mut seen = [("", ""); 3];
+        let mut n = 0;
+        parse_cmdline("  panic=reboot quiet log.level=debug =x empty= ", |k, v| {
+            seen[n] = (k, v);
+            n += 1;
+        });
+        assert_eq!(n, 3);
+        assert_eq!(seen, [("panic", "reboot"), ("log.level", "debug"), ("empty", "")]);
+    }
+
+    #[test]
+    fn joins_section_and_key() {
+        let mut buf = [0u8; KEY_LEN];
+        assert_eq!(join_key(&mut buf, "ai", "enabled"), Some("ai.enabled"));
+        let mut buf = [0u8; KEY_LEN];
+        assert_eq!(join_key(&mut buf, "", "keymap"), Some("keymap"));
+    }
+}
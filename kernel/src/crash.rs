@@ -0,0 +1,188 @@
+//! What the kernel does once a panic has been reported.
+//!
+//! `panic` (config file or boot command line) is `halt`, the default, which
+//! leaves the machine stopped for a debugger; `reboot`, which counts down
+//! `panic.delay_s` seconds and resets through port 0xCF9; or `dump`, which
+//! first saves the panic message, prefixed with the machine's SMBIOS
+//! identity and the agent's model (name, version, training hash), and the
+//! tail of the serial log in pstore (it survives the warm reset) and then
+//! reboots. The setting is read once at boot so the
+//! panic path takes no config lock.
+
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::instructions::{hlt, interrupts};
+
+use crate::pstore::{self, Section};
+use crate::{config, power, serial, smbios, time, vga};
+
+/// Longest message kept, the size of its pstore section.
+const MSG_LEN: usize = Section::Crash.capacity();
+const DEFAULT_DELAY_S: u64 = 5;
+const MAX_DELAY_S: u64 = 600;
+/// Assumed TSC rate when calibration never ran (1 GHz).
+const FALLBACK_TSC_PER_MS: u64 = 1_000_000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum PanicMode {
+    Halt = 0,
+    Reboot = 1,
+    Dump = 2,
+}
+
+impl PanicMode {
+    fn from_u8(v: u8) -> Self {
+        match v {
+            1 => PanicMode::Reboot,
+            2 => PanicMode::Dump,
+            _ => PanicMode::Halt,
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "halt" => PanicMode::Halt,
+            "reboot" => PanicMode::Reboot,
+            "dump" => PanicMode::Dump,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PanicMode::Halt => "halt",
+            PanicMode::Reboot => "reboot",
+            PanicMode::Dump => "dump",
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(PanicMode::Halt as u8);
+static DELAY_S: AtomicU64 = AtomicU64::new(DEFAULT_DELAY_S);
+
+pub fn init() {
+    match config::with("panic", PanicMode::parse) {
+        Some(Some(mode)) => MODE.store(mode as u8, Ordering::Relaxed),
+        Some(None) => serial::write_str("[panic] unknown panic mode, keeping halt\r\n"),
+        None => {}
+    }
+    if let Some(delay) = config::get_u64("panic.delay_s") {
+        DELAY_S.store(delay.min(MAX_DELAY_S), Ordering::Relaxed);
+    }
+    pstore::read(Section::Crash, |msg| {
+        serial::write_fmt(format_args!(
+            "[panic] dump from an earlier boot: {}\r\n",
+            core::str::from_utf8(msg).unwrap_or("?")
+        ))
+    });
+    if mode() != PanicMode::Halt {
+        serial::write_fmt(format_args!("[panic] on panic: {} after {} s\r\n", mode().as_str(), delay_s()));
+    }
+}
+
+pub fn mode() -> PanicMode {
+    PanicMode::from_u8(MODE.load(Ordering::Relaxed))
+}
+
+pub fn delay_s() -> u64 {
+    DELAY_S.load(Ordering::Relaxed)
+}
+
+/// Runs after the panic has been printed; never returns.
+pub fn after_panic(info: &PanicInfo) -> ! {
+    interrupts::disable();
+    #[cfg(feature = "ai_agent")]
+    report_model();
+    let mode = mode();
+    if mode == PanicMode::Dump {
+        dump(info);
+    }
+    if mode != PanicMode::Halt {
+        countdown(delay_s());
+        power::reset();
+    }
+    loop {
+        hlt();
+    }
+}
+
+/// Which model the agent was running, so the report names it.
+#[cfg(feature = "ai_agent")]
+fn report_model() {
+    let mut line = Truncating { buf: [0; MSG_LEN], len: 0 };
+    let _ = crate::ai_agent::try_write_model(&mut line);
+    if line.len > 0 {
+        serial::write_fmt(format_args!("[panic] {}\r\n", core::str::from_utf8(&line.buf[..line.len]).unwrap_or("?")));
+    }
+}
+
+/// Fixed buffer that drops what does not fit.
+struct Truncating {
+    buf: [u8; MSG_LEN],
+    len: usize,
+}
+
+impl Write for Truncating {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn dump(info: &PanicInfo) {
+    let mut msg = Truncating { buf: [0; MSG_LEN], len: 0 };
+    // Machine and model first: a long panic message must not push them out
+    let _ = smbios::try_write_summary(&mut msg);
+    #[cfg(feature = "ai_agent")]
+    {
+        let (before, sep) = if msg.len == 0 { (0, "") } else { (msg.len, ", ") };
+        let _ = msg.write_str(sep);
+        let _ = crate::ai_agent::try_write_model(&mut msg);
+        if msg.len == before + sep.len() {
+            msg.len = before;
+        }
+    }
+    let sep = if msg.len == 0 { "" } else { ": " };
+    let _ = write!(msg, "{}{}", sep, info);
+    // The log tail goes straight into its section: no room for it on the stack
+    let saved = pstore::try_write(Section::Crash, &msg.buf[..msg.len])
+        .and_then(|_| pstore::try_fill(Section::Log, serial::tail));
+    match saved {
+        Ok(log_len) => serial::write_fmt(format_args!("[panic] dump saved ({} log bytes)\r\n", log_len)),
+        Err(e) => serial::write_fmt(format_args!("[panic] dump not saved: {}\r\n", e.as_str())),
+    }
+}
+
+fn countdown(seconds: u64) {
+    let per_s = match time::tsc_per_ms() {
+        0 => FALLBACK_TSC_PER_MS,
+        per_ms => per_ms,
+    } * 1000;
+    for left in (1..=seconds).rev() {
+        serial::write_fmt(format_args!("[panic] rebooting in {} s\r\n", left));
+        vga::fmt(format_args!("rebooting in {} s\n", left));
+        let start = time::rdtsc();
+        while time::rdtsc().wrapping_sub(start) < per_s {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn modes_parse_and_round_trip() {
+        for mode in [PanicMode::Halt, PanicMode::Reboot, PanicMode::Dump] {
+            assert_eq!(PanicMode::parse(mode.as_str()), Some(mode));
+            assert_eq!(PanicMode::from_u8(mode as u8), mode);
+        }
+        assert_eq!(PanicMode::parse("poweroff"), None);
+        assert_eq!(PanicMode::from_u8(9), PanicMode::Halt);
+    }
+}
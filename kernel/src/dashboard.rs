@@ -0,0 +1,228 @@
+//! The AI dashboard (virtual console Alt+F3).
+//!
+//! Once per second of timer ticks the idle loop samples the page-fault rate
+//! and free memory into a short history, whether or not the dashboard is on
+//! screen, so the sparklines have a past when it is opened. The screen shows
+//! the agent's knobs, the two sparklines and the last actions with their
+//! outcome from the journal.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::journal::RecordKind;
+use crate::theme::{self, Slot};
+use crate::vconsole::Lines;
+use crate::{ai_action, apply_action, idt, journal, klog, pmm, time, vga};
+
+/// Samples kept, one per column of the sparklines.
+const HISTORY_LEN: usize = 64;
+const SAMPLE_MS: u64 = 1000;
+const ACTION_ROWS: usize = 10;
+/// CP437 half and full blocks; a sparkline is two rows of them.
+const LOWER_HALF: u8 = 0xDC;
+const FULL: u8 = 0xDB;
+
+struct History {
+    pf_rate: [u32; HISTORY_LEN],
+    free_kib: [u32; HISTORY_LEN],
+    next: usize,
+    len: usize,
+    last_pf: u64,
+}
+
+impl History {
+    /// Indices oldest first.
+    fn order(&self) -> impl Iterator<Item = usize> + '_ {
+        let start = (self.next + HISTORY_LEN - self.len) % HISTORY_LEN;
+        (0..self.len).map(move |i| (start + i) % HISTORY_LEN)
+    }
+}
+
+static HISTORY: Mutex<History> =
+    Mutex::new(History { pf_rate: [0; HISTORY_LEN], free_kib: [0; HISTORY_LEN], next: 0, len: 0, last_pf: 0 });
+/// Timer tick at which the next sample is due.
+static NEXT_SAMPLE: AtomicU64 = AtomicU64::new(0);
+
+/// Called from the idle loop; returns whether a sample was taken.
+pub fn tick() -> bool {
+    let now = idt::timer_ticks();
+    if now < NEXT_SAMPLE.load(Ordering::Relaxed) {
+        return false;
+    }
+    NEXT_SAMPLE.store(now + time::ticks_for_ms(SAMPLE_MS).max(1), Ordering::Relaxed);
+    let pf = idt::page_faults();
+    let mut h = HISTORY.lock();
+    let i = h.next;
+    // The first sample has no interval before it
+    h.pf_rate[i] = if h.len == 0 { 0 } else { pf.saturating_sub(h.last_pf) as u32 };
+    h.free_kib[i] = pmm::free_kib() as u32;
+    h.last_pf = pf;
+    h.next = (i + 1) % HISTORY_LEN;
+    h.len = (h.len + 1).min(HISTORY_LEN);
+    true
+}
+
+/// Column height in half rows (0..=4) of `value` between `lo` and `hi`.
+fn level(value: u32, lo: u32, hi: u32) -> usize {
+    if hi <= lo {
+        return if value > 0 { 2 } else { 0 };
+    }
+    let span = (hi - lo) as u64;
+    (((value.saturating_sub(lo)) as u64 * 4 + span / 2) / span).min(4) as usize
+}
+
+/// Two rows of block characters for `values`, right-aligned so the newest
+/// value is in the last column. A zero-height column shows a baseline.
+fn sparkline(values: &[u32], lo: u32, hi: u32, top: &mut [u8], bottom: &mut [u8]) {
+    const TOP: [u8; 5] = [b' ', b' ', b' ', LOWER_HALF, FULL];
+    const BOTTOM: [u8; 5] = [b'_', LOWER_HALF, FULL, FULL, FULL];
+    top.fill(b' ');
+    bottom.fill(b' ');
+    let width = top.len().min(bottom.len());
+    let shown = &values[values.len().saturating_sub(width)..];
+    let offset = width - shown.len();
+    for (i, &v) in shown.iter().enumerate() {
+        let h = level(v, lo, hi);
+        top[offset + i] = TOP[h];
+        bottom[offset + i] = BOTTOM[h];
+    }
+}
+
+fn draw_sparkline(out: &mut Lines, values: &[u32], lo: u32, hi: u32) {
+    let mut top = [b' '; HISTORY_LEN];
+    let mut bottom = [b' '; HISTORY_LEN];
+    sparkline(values, lo, hi, &mut top, &mut bottom);
+    for row in [&top, &bottom] {
+        if let Some(r) = out.next_row() {
+            vga::clear_row(r, theme::get(Slot::Info));
+            vga::write_at(r, 2, row, theme::get(Slot::Info));
+        }
+    }
+}
+
+fn outcome(kind: RecordKind) -> &'static str {
+    match kind {
+        RecordKind::Intent => "pending",
+        RecordKind::ApplyOk => "applied",
+        RecordKind::ApplyFail => "failed",
+        RecordKind::Reject => "rejected",
+        RecordKind::DryRun => "dry run",
+        RecordKind::ModelTooSlow => "too slow",
+        RecordKind::Observed => "observed",
+        RecordKind::ModelLoaded => "model loaded",
+        RecordKind::GuardTrip => "guard trip",
+        RecordKind::Boot => "reboot",
+    }
+}
+
+pub fn draw() {
+    let mut out = Lines::new();
+    out.line(theme::get(Slot::Headline), format_args!(" AI dashboard - up {}", time::Duration(time::since_boot())));
+    out.line(
+        theme::get(Slot::Info),
+        format_args!(
+            " quantum {} us  ai interval {} ms  system_ready={}  log {}",
+            apply_action::get_quantum_us(),
+            apply_action::get_ai_interval_ms(),
+            apply_action::is_system_ready() as u8,
+            klog::level().as_str()
+        ),
+    );
+    #[cfg(feature = "ai_agent")]
+    {
+        let st = crate::ai_agent::reward_stats();
+        out.line(
+            theme::get(Slot::Info),
+            format_args!(
+                " steps={} accepted={} rejected={} rolled_back={} errors={}",
+                st.steps, st.accepted, st.rejected, st.rolled_back, st.errors
+            ),
+        );
+    }
+    #[cfg(not(feature = "ai_agent"))]
+    out.line(theme::get(Slot::Info), format_args!(" agent not built (ai_agent feature off)"));
+
+    let mut pf = [0u32; HISTORY_LEN];
+    let mut free = [0u32; HISTORY_LEN];
+    let n = {
+        let h = HISTORY.lock();
+        for (slot, i) in h.order().enumerate() {
+            pf[slot] = h.pf_rate[i];
+            free[slot] = h.free_kib[i];
+        }
+        h.len
+    };
+    let (pf, free) = (&pf[..n], &free[..n]);
+    let pf_max = pf.iter().copied().max().unwrap_or(0);
+    let (free_min, free_max) = (free.iter().copied().min().unwrap_or(0), free.iter().copied().max().unwrap_or(0));
+    out.line(
+        theme::get(Slot::Info),
+        format_args!(" page faults/s  now {}  max {}  ({} s)", pf.last().copied().unwrap_or(0), pf_max, n),
+    );
+    draw_sparkline(&mut out, pf, 0, pf_max);
+    out.line(
+        theme::get(Slot::Info),
+        format_args!(
+            " free memory     now {} KiB  min {}  max {}",
+            free.last().copied().unwrap_or(0),
+            free_min,
+            free_max
+        ),
+    );
+    draw_sparkline(&mut out, free, free_min, free_max);
+
+    out.line(theme::get(Slot::Headline), format_args!(" {:>14} {:>6} {:<14} {:<9} {:>6}", "last actions", "seq", "action", "outcome", "code"));
+    let mut total = 0;
+    journal::for_each(|rec| total += (rec.kind != RecordKind::Intent) as usize);
+    let mut index = 0;
+    journal::for_each(|rec| {
+        // An intent is followed by its outcome, which says more
+        if rec.kind == RecordKind::Intent {
+            return;
+        }
+        if index + ACTION_ROWS >= total {
+            out.line(
+                theme::get(Slot::Info),
+                format_args!(
+                    " {:>14} {:>6} {:<14} {:<9} {:>6}",
+                    time::Duration(rec.tsc),
+                    rec.seq,
+                    ai_action::name(rec.action),
+                    outcome(rec.kind),
+                    rec.code
+                ),
+            );
+        }
+        index += 1;
+    });
+    if total == 0 {
+        out.line(theme::get(Slot::Info), format_args!(" no actions yet"));
+    }
+    out.finish();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_scale_between_bounds() {
+        assert_eq!(level(0, 0, 8), 0);
+        assert_eq!(level(4, 0, 8), 2);
+        assert_eq!(level(8, 0, 8), 4);
+        assert_eq!(level(100, 50, 150), 2);
+        assert_eq!(level(7, 7, 7), 2);
+        assert_eq!(level(0, 0, 0), 0);
+    }
+
+    #[test]
+    fn sparkline_is_right_aligned() {
+        let (mut top, mut bottom) = ([0u8; 4], [0u8; 4]);
+        sparkline(&[0, 4, 8], 0, 8, &mut top, &mut bottom);
+        assert_eq!(top, [b' ', b' ', b' ', FULL]);
+        assert_eq!(bottom, [b' ', b'_', FULL, FULL]);
+        // Only the newest values fit
+        sparkline(&[8, 8, 8, 8, 0], 0, 8, &mut top, &mut bottom);
+        assert_eq!(bottom, [FULL, FULL, FULL, b'_']);
+    }
+}
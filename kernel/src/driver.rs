@@ -0,0 +1,169 @@
+//! PCI driver binding.
+//!
+//! Drivers declare match criteria (class/subclass/prog_if or vendor/device)
+//! and a probe function. `probe_all` walks the PCI bus, offers each
+//! unbound function to the registered drivers in registration order and
+//! records which driver took it.
+
+use crate::pci::{self, PciAddress};
+use crate::klog::{self, Level};
+use spin::Mutex;
+
+pub const MAX_DRIVERS: usize = 16;
+pub const MAX_BINDINGS: usize = 32;
+
+/// Identification read once per PCI function.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PciIds {
+    pub vendor: u16,
+    pub device: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+}
+
+impl PciIds {
+    pub fn read(addr: PciAddress) -> Self {
+        PciIds {
+            vendor: pci::vendor_id(addr),
+            device: pci::device_id(addr),
+            class: pci::class_code(addr),
+            subclass: pci::subclass(addr),
+            prog_if: pci::prog_if(addr),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Match {
+    /// `prog_if: None` matches any programming interface.
+    Class { class: u8, subclass: u8, prog_if: Option<u8> },
+    /// Chip-specific drivers (NICs, virtio) match on vendor/device.
+    #[allow(dead_code)]
+    Id { vendor: u16, device: u16 },
+}
+
+impl Match {
+    pub fn matches(&self, ids: &PciIds) -> bool {
+        match *self {
+            Match::Class { class, subclass, prog_if } => {
+                ids.class == class
+                    && ids.subclass == subclass
+                    && prog_if.is_none_or(|p| p == ids.prog_if)
+            }
+            Match::Id { vendor, device } => ids.vendor == vendor && ids.device == device,
+        }
+    }
+}
+
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [Match],
+    /// Take ownership of the device; an error leaves it unbound.
+    pub probe: fn(PciAddress, &PciIds) -> Result<(), &'static str>,
+}
+
+impl PciDriver {
+    pub fn supports(&self, ids: &PciIds) -> bool {
+        self.matches.iter().any(|m| m.matches(ids))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    Full,
+    Duplicate,
+}
+
+#[derive(Clone, Copy)]
+pub struct Binding {
+    pub addr: PciAddress,
+    pub ids: PciIds,
+    pub driver: &'static str,
+}
+
+static DRIVERS: Mutex<[Option<&'static PciDriver>; MAX_DRIVERS]> = Mutex::new([None; MAX_DRIVERS]);
+static BINDINGS: Mutex<[Option<Binding>; MAX_BINDINGS]> = Mutex::new([None; MAX_BINDINGS]);
+
+pub fn register(driver: &'static PciDriver) -> Result<(), RegisterError> {
+    let mut drivers = DRIVERS.lock();
+    if drivers.iter().flatten().any(|d| d.name == driver.name) {
+        return Err(RegisterError::Duplicate);
+    }
+    let slot = drivers.iter_mut().find(|d| d.is_none()).ok_or(RegisterError::Full)?;
+    *slot = Some(driver);
+    Ok(())
+}
+
+fn is_bound(addr: PciAddress) -> bool {
+    BINDINGS.lock().iter().flatten().any(|b| {
+        b.addr.bus == addr.bus && b.addr.device == addr.device && b.addr.function == addr.function
+    })
+}
+
+/// Bind every unbound PCI function to the first driver that matches and
+/// probes successfully. Returns the number of new bindings.
+pub fn probe_all() -> usize {
+    let drivers = *DRIVERS.lock();
+    let mut bound = 0;
+    pci::enumerate(|addr| {
+        if is_bound(addr) {
+            return;
+        }
+        let ids = PciIds::read(addr);
+        for driver in drivers.iter().flatten().filter(|d| d.supports(&ids)) {
+            match (driver.probe)(addr, &ids) {
+                Ok(()) => {
+                    let mut bindings = BINDINGS.lock();
+                    match bindings.iter_mut().find(|b| b.is_none()) {
+                        Some(slot) => *slot = Some(Binding { addr, ids, driver: driver.name }),
+                        None => klog::log(Level::Warn, format_args!("[drv] binding table full\r\n")),
+                    }
+                    bound += 1;
+                    klog::log(Level::Info, format_args!("[drv] {} bound to {}\r\n", addr, driver.name));
+                    break;
+                }
+                Err(e) => klog::log(Level::Warn, format_args!(
+                    "[drv] {} probe by {} failed: {}\r\n",
+                    addr, driver.name, e
+                )),
+            }
+        }
+    });
+    bound
+}
+
+pub fn for_each_binding(mut f: impl FnMut(&Binding)) {
+    for b in BINDINGS.lock().iter().flatten() {
+        f(b);
+    }
+}
+
+pub fn for_each_driver(mut f: impl FnMut(&PciDriver)) {
+    for d in DRIVERS.lock().iter().flatten() {
+        f(d);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const XHCI: PciIds = PciIds { vendor: 0x1b36, device: 0x000d, class: 0x0C, subclass: 0x03, prog_if: 0x30 };
+
+    #[test]
+    fn class_match_honours_prog_if() {
+        let any = Match::Class { class: 0x0C, subclass: 0x03, prog_if: None };
+        let xhci = Match::Class { class: 0x0C, subclass: 0x03, prog_if: Some(0x30) };
+        let ehci = Match::Class { class: 0x0C, subclass: 0x03, prog_if: Some(0x20) };
+        assert!(any.matches(&XHCI));
+        assert!(xhci.matches(&XHCI));
+        assert!(!ehci.matches(&XHCI));
+    }
+
+    #[test]
+    fn id_match() {
+        assert!(Match::Id { vendor: 0x1b36, device: 0x000d }.matches(&XHCI));
+        assert!(!Match::Id { vendor: 0x8086, device: 0x000d }.matches(&XHCI));
+    }
+}
@@ -0,0 +1,382 @@
+//! Full-screen text editor on the VGA console (`edit <path>`), for small
+//! on-target changes to scripts and config files.
+//!
+//! Keys: printable keys insert, Enter splits the line, Backspace/Del
+//! delete, arrows move, Home/End go to the ends of the line, PgUp/PgDn move
+//! by a screen, Ctrl+S saves, Ctrl+Q quits (twice if there are unsaved
+//! changes). Long lines scroll sideways.
+//!
+//! Saving writes a ramfs file of the same name, which shadows an initrd
+//! file and lasts until reboot. The config file is only read at boot, so
+//! an edited `cfg/kernel.toml` takes effect through `config set` or on the
+//! next image.
+
+use spin::Mutex;
+
+use crate::keyboard::{self, Focus, Key};
+use crate::theme::{self, Slot};
+use crate::viewer::{fmt_into, wait_key};
+use crate::{procfs, ramfs, vga};
+
+/// Largest file the editor takes.
+pub const MAX_TEXT: usize = 16 * 1024;
+/// Saved files get room to grow by this much, so the ramfs buffer is reused
+/// across saves.
+const SAVE_ROUND: usize = 4096;
+const FIRST_TEXT_ROW: usize = 1;
+const TEXT_ROWS: usize = vga::ROWS - 2;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum EditError {
+    ReadOnly,
+    TooLarge,
+    /// Another `edit` is open (from a job, say).
+    Busy,
+}
+
+impl EditError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EditError::ReadOnly => "read-only file",
+            EditError::TooLarge => "file too large",
+            EditError::Busy => "editor already open",
+        }
+    }
+}
+
+/// Text being edited, with the cursor and the part of it on screen; all
+/// positions are byte offsets.
+struct Buffer<const N: usize> {
+    bytes: [u8; N],
+    len: usize,
+    cursor: usize,
+    /// Start of the first line on screen.
+    top: usize,
+    /// First column on screen.
+    left: usize,
+    modified: bool,
+}
+
+impl<const N: usize> Buffer<N> {
+    const fn new() -> Self {
+        Buffer { bytes: [0; N], len: 0, cursor: 0, top: 0, left: 0, modified: false }
+    }
+
+    /// Replace the text; `false` if it does not fit.
+    fn load(&mut self, text: &[u8]) -> bool {
+        if text.len() > N {
+            return false;
+        }
+        self.bytes[..text.len()].copy_from_slice(text);
+        self.len = text.len();
+        self.cursor = 0;
+        self.top = 0;
+        self.left = 0;
+        self.modified = false;
+        true
+    }
+
+    fn text(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    fn line_start(&self, at: usize) -> usize {
+        self.bytes[..at].iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1)
+    }
+
+    fn line_end(&self, at: usize) -> usize {
+        self.bytes[at..self.len].iter().position(|&b| b == b'\n').map_or(self.len, |i| at + i)
+    }
+
+    fn column(&self) -> usize {
+        self.cursor - self.line_start(self.cursor)
+    }
+
+    /// 1-based line of the cursor.
+    fn line_number(&self) -> usize {
+        self.bytes[..self.cursor].iter().filter(|&&b| b == b'\n').count() + 1
+    }
+
+    fn insert(&mut self, b: u8) -> bool {
+        if self.len == N {
+            return false;
+        }
+        self.bytes.copy_within(self.cursor..self.len, self.cursor + 1);
+        self.bytes[self.cursor] = b;
+        self.len += 1;
+        self.cursor += 1;
+        self.modified = true;
+        true
+    }
+
+    /// Remove the byte after the cursor.
+    fn delete(&mut self) -> bool {
+        if self.cursor == self.len {
+            return false;
+        }
+        self.bytes.copy_within(self.cursor + 1..self.len, self.cursor);
+        self.len -= 1;
+        self.modified = true;
+        true
+    }
+
+    fn backspace(&mut self) -> bool {
+        if self.cursor == 0 {
+            return false;
+        }
+        self.cursor -= 1;
+        self.delete()
+    }
+
+    fn left(&mut self) {
+        self.cursor = self.cursor.saturating_sub(1);
+    }
+
+    fn right(&mut self) {
+        self.cursor = (self.cursor + 1).min(self.len);
+    }
+
+    /// Previous line, same column or its end if shorter.
+    fn up(&mut self) {
+        let start = self.line_start(self.cursor);
+        if start == 0 {
+            return;
+        }
+        let col = self.cursor - start;
+        let prev = self.line_start(start - 1);
+        self.cursor = prev + col.min(start - 1 - prev);
+    }
+
+    fn down(&mut self) {
+        let end = self.line_end(self.cursor);
+        if end == self.len {
+            return;
+        }
+        let col = self.column();
+        let next = end + 1;
+        self.cursor = next + col.min(self.line_end(next) - next);
+    }
+
+    fn home(&mut self) {
+        self.cursor = self.line_start(self.cursor);
+    }
+
+    fn end(&mut self) {
+        self.cursor = self.line_end(self.cursor);
+    }
+
+    /// Screen row of the cursor, counted from `top`.
+    fn screen_row(&self) -> usize {
+        self.bytes[self.top..self.cursor].iter().filter(|&&b| b == b'\n').count()
+    }
+
+    /// Scroll so the cursor is inside a `rows` by `cols` window.
+    fn follow(&mut self, rows: usize, cols: usize) {
+        if self.cursor < self.top {
+            self.top = self.line_start(self.cursor);
+        }
+        for _ in rows..=self.screen_row() {
+            self.top = self.line_end(self.top) + 1;
+        }
+        let col = self.column();
+        if col < self.left {
+            self.left = col;
+        } else if col >= self.left + cols {
+            self.left = col + 1 - cols;
+        }
+    }
+}
+
+static BUFFER: Mutex<Buffer<MAX_TEXT>> = Mutex::new(Buffer::new());
+
+fn render(buf: &Buffer<MAX_TEXT>, path: &str, status: &str) {
+    let headline = theme::get(Slot::Headline);
+    let text = theme::get(Slot::Text);
+    let mut title = [0u8; vga::COLUMNS];
+    let n = fmt_into(&mut title, format_args!(
+        " edit {}{}  line {} col {}",
+        path,
+        if buf.modified { " *" } else { "" },
+        buf.line_number(),
+        buf.column() + 1,
+    ));
+    vga::clear_row(0, headline);
+    vga::write_at(0, 0, &title[..n], headline);
+
+    let mut at = buf.top;
+    let mut line = [0u8; vga::COLUMNS];
+    for row in 0..TEXT_ROWS {
+        let screen_row = FIRST_TEXT_ROW + row;
+        vga::clear_row(screen_row, text);
+        if at > buf.len {
+            continue;
+        }
+        let end = buf.line_end(at);
+        let shown = &buf.bytes[(at + buf.left).min(end)..end];
+        let n = shown.len().min(vga::COLUMNS);
+        for (cell, &b) in line.iter_mut().zip(&shown[..n]) {
+            *cell = match b {
+                b'\t' => b' ',
+                32..=126 => b,
+                _ => b'?',
+            };
+        }
+        vga::write_at(screen_row, 0, &line[..n], text);
+        at = end + 1;
+    }
+
+    // The cursor is the cell under it in reverse video
+    let col = buf.column() - buf.left;
+    let under = match buf.bytes.get(buf.cursor) {
+        Some(&b) if buf.cursor < buf.len && (32..=126).contains(&b) => b,
+        _ => b' ',
+    };
+    let reverse = text.rotate_left(4) & 0x7F;
+    vga::write_at(FIRST_TEXT_ROW + buf.screen_row(), col, &[under], reverse);
+
+    vga::clear_row(vga::ROWS - 1, headline);
+    vga::write_at(vga::ROWS - 1, 0, status.as_bytes(), headline);
+}
+
+fn save(path: &str, text: &[u8]) -> Result<(), ramfs::WriteError> {
+    ramfs::create(path, text.len().div_ceil(SAVE_ROUND).max(1) * SAVE_ROUND)?;
+    ramfs::append(path, text)
+}
+
+const HELP: &str = " ^S save  ^Q quit  arrows/PgUp/PgDn/Home/End move  Del/Backspace delete";
+
+/// Edit `path` until the user quits, then put the console back. A missing
+/// file starts empty and is created on the first save.
+pub fn run(path: &str) -> Result<(), EditError> {
+    if procfs::is_proc(path) {
+        return Err(EditError::ReadOnly);
+    }
+    let mut buf = BUFFER.try_lock().ok_or(EditError::Busy)?;
+    let mut status = match ramfs::find(path) {
+        Some((data, len)) => {
+            if !buf.load(unsafe { core::slice::from_raw_parts(data, len) }) {
+                return Err(EditError::TooLarge);
+            }
+            HELP
+        }
+        None => {
+            buf.load(&[]);
+            " new file"
+        }
+    };
+
+    let saved = vga::snapshot();
+    let focus = keyboard::grab_focus(Focus::Editor);
+    let mut quit_armed = false;
+    loop {
+        buf.follow(TEXT_ROWS, vga::COLUMNS);
+        render(&buf, path, status);
+        status = HELP;
+        let key = wait_key(Focus::Editor);
+        if key != Key::Ctrl('q') {
+            quit_armed = false;
+        }
+        match key {
+            Key::Ctrl('q') if buf.modified && !quit_armed => {
+                quit_armed = true;
+                status = " unsaved changes: ^S saves, ^Q again quits without saving";
+            }
+            Key::Ctrl('q') => break,
+            Key::Ctrl('s') => {
+                status = match save(path, buf.text()) {
+                    Ok(()) => {
+                        buf.modified = false;
+                        " saved"
+                    }
+                    Err(e) => e.as_str(),
+                };
+            }
+            Key::Char(c) if (' '..='~').contains(&c) => {
+                if !buf.insert(c as u8) {
+                    status = " file full";
+                }
+            }
+            Key::Enter => {
+                if !buf.insert(b'\n') {
+                    status = " file full";
+                }
+            }
+            Key::Backspace => {
+                buf.backspace();
+            }
+            Key::Delete => {
+                buf.delete();
+            }
+            Key::Left => buf.left(),
+            Key::Right => buf.right(),
+            Key::Up => buf.up(),
+            Key::Down => buf.down(),
+            Key::Home => buf.home(),
+            Key::End => buf.end(),
+            Key::PageUp => (0..TEXT_ROWS).for_each(|_| buf.up()),
+            Key::PageDown => (0..TEXT_ROWS).for_each(|_| buf.down()),
+            _ => {}
+        }
+    }
+    drop(focus);
+    vga::restore(&saved);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(text: &[u8]) -> Buffer<64> {
+        let mut buf = Buffer::new();
+        assert!(buf.load(text));
+        buf
+    }
+
+    #[test]
+    fn edits_shift_the_rest_of_the_text() {
+        let mut buf = buffer(b"ac\n");
+        buf.right();
+        assert!(buf.insert(b'b'));
+        assert_eq!(buf.text(), b"abc\n");
+        assert!(buf.delete());
+        assert!(buf.backspace());
+        assert_eq!((buf.text(), buf.cursor), (&b"a\n"[..], 1));
+        buf.cursor = 0;
+        assert!(!buf.backspace());
+        assert!(buf.modified);
+        let mut full = buffer(&[b'x'; 64]);
+        assert!(!full.insert(b'y'));
+        assert!(!Buffer::<4>::new().load(b"12345"));
+    }
+
+    #[test]
+    fn vertical_moves_keep_the_column_where_they_can() {
+        let mut buf = buffer(b"long line\nab\nlonger line");
+        buf.cursor = 6;
+        buf.down();
+        assert_eq!((buf.line_number(), buf.column()), (2, 2));
+        buf.down();
+        assert_eq!((buf.line_number(), buf.column()), (3, 2));
+        buf.end();
+        buf.down();
+        assert_eq!(buf.cursor, buf.len);
+        buf.up();
+        buf.up();
+        assert_eq!((buf.line_number(), buf.column()), (1, 2));
+        buf.up();
+        buf.home();
+        assert_eq!(buf.cursor, 0);
+    }
+
+    #[test]
+    fn window_follows_the_cursor() {
+        let mut buf = buffer(b"0\n1\n2\n3\n4\n0123456789");
+        buf.cursor = buf.len;
+        buf.follow(3, 4);
+        assert_eq!((buf.top, buf.screen_row(), buf.left), (6, 2, 7));
+        buf.cursor = 2;
+        buf.follow(3, 4);
+        assert_eq!((buf.top, buf.screen_row(), buf.left), (2, 0, 0));
+    }
+}
@@ -0,0 +1,300 @@
+//! A small cooperative executor for driver state machines.
+//!
+//! Futures live in a fixed number of inline slots (no heap), so `spawn`
+//! fails rather than allocating when they are all taken or a future is too
+//! big. A task's waker sets its bit in a ready mask; `run_ready`, called from
+//! the idle loop, polls the tasks whose bits are set. Timers are checked on
+//! each pass, which the PIT tick guarantees happens at least once per tick.
+//! `Event` lets interrupt handlers and event-ring code wake a waiting task.
+
+use core::cell::UnsafeCell;
+use core::future::Future;
+use core::mem::{align_of, size_of, MaybeUninit};
+use core::pin::Pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::time;
+
+const MAX_TASKS: usize = 8;
+/// Inline storage per task; enough for the USB enumeration future.
+const TASK_BYTES: usize = 2048;
+const TASK_ALIGN: usize = 16;
+const MAX_TIMERS: usize = 16;
+/// Rate assumed if the TSC was never calibrated, so deadlines stay finite.
+const FALLBACK_TSC_PER_MS: u64 = 1_000_000;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SpawnError {
+    Full,
+    TooLarge,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TaskId(usize);
+
+#[repr(C, align(16))]
+struct Storage(UnsafeCell<MaybeUninit<[u8; TASK_BYTES]>>);
+
+// Only the executor touches a slot's storage, and only while the slot is
+// claimed in `SLOTS`.
+unsafe impl Sync for Storage {}
+
+#[derive(Copy, Clone)]
+struct Slot {
+    name: &'static str,
+    poll: unsafe fn(*mut u8, &mut Context<'_>) -> Poll<()>,
+    drop: unsafe fn(*mut u8),
+}
+
+static STORAGE: [Storage; MAX_TASKS] = [const { Storage(UnsafeCell::new(MaybeUninit::uninit())) }; MAX_TASKS];
+static SLOTS: Mutex<[Option<Slot>; MAX_TASKS]> = Mutex::new([None; MAX_TASKS]);
+static READY: AtomicU32 = AtomicU32::new(0);
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static TIMERS: Mutex<[Option<(u64, Waker)>; MAX_TIMERS]> = Mutex::new([const { None }; MAX_TIMERS]);
+
+unsafe fn poll_future<F: Future<Output = ()>>(ptr: *mut u8, cx: &mut Context<'_>) -> Poll<()> {
+    Pin::new_unchecked(&mut *(ptr as *mut F)).poll(cx)
+}
+
+unsafe fn drop_future<F>(ptr: *mut u8) {
+    core::ptr::drop_in_place(ptr as *mut F);
+}
+
+fn fits<F>() -> bool {
+    size_of::<F>() <= TASK_BYTES && align_of::<F>() <= TASK_ALIGN
+}
+
+/// Start `fut` as a task; it is first polled on the next `run_ready`.
+pub fn spawn<F: Future<Output = ()> + 'static>(name: &'static str, fut: F) -> Result<TaskId, SpawnError> {
+    if !fits::<F>() {
+        return Err(SpawnError::TooLarge);
+    }
+    let mut slots = SLOTS.lock();
+    let index = slots.iter().position(Option::is_none).ok_or(SpawnError::Full)?;
+    // The slot is free, so nothing else holds a pointer into its storage
+    unsafe { (STORAGE[index].0.get() as *mut F).write(fut) };
+    slots[index] = Some(Slot { name, poll: poll_future::<F>, drop: drop_future::<F> });
+    READY.fetch_or(1 << index, Ordering::AcqRel);
+    Ok(TaskId(index))
+}
+
+const VTABLE: RawWakerVTable = RawWakerVTable::new(clone_waker, wake_task, wake_task, drop_waker);
+
+unsafe fn clone_waker(data: *const ()) -> RawWaker {
+    RawWaker::new(data, &VTABLE)
+}
+
+unsafe fn wake_task(data: *const ()) {
+    READY.fetch_or(1 << (data as usize), Ordering::AcqRel);
+}
+
+unsafe fn drop_waker(_data: *const ()) {}
+
+fn task_waker(index: usize) -> Waker {
+    // The data pointer is just the slot index; the vtable never dereferences it
+    unsafe { Waker::from_raw(RawWaker::new(index as *const (), &VTABLE)) }
+}
+
+/// Poll every task that has been woken, after firing expired timers.
+/// Returns the number of polls. Re-entrant calls (from inside a task) do nothing.
+pub fn run_ready() -> usize {
+    if RUNNING.swap(true, Ordering::Acquire) {
+        return 0;
+    }
+    fire_timers(time::rdtsc());
+    let mut polled = 0;
+    let mut ready = READY.swap(0, Ordering::AcqRel);
+    while ready != 0 {
+        let index = ready.trailing_zeros() as usize;
+        ready &= ready - 1;
+        let Some(slot) = SLOTS.lock()[index] else { continue };
+        let waker = task_waker(index);
+        let mut cx = Context::from_waker(&waker);
+        let ptr = STORAGE[index].0.get() as *mut u8;
+        polled += 1;
+        if unsafe { (slot.poll)(ptr, &mut cx) }.is_ready() {
+            unsafe { (slot.drop)(ptr) };
+            SLOTS.lock()[index] = None;
+        }
+    }
+    RUNNING.store(false, Ordering::Release);
+    polled
+}
+
+/// Whether any task has not finished yet.
+pub fn has_tasks() -> bool {
+    SLOTS.lock().iter().any(Option::is_some)
+}
+
+/// Whether a woken task is waiting to be polled (the idle loop skips `hlt`).
+pub fn has_ready() -> bool {
+    READY.load(Ordering::Acquire) != 0
+}
+
+/// Woken tasks not polled yet. Safe to call from an interrupt handler.
+pub fn ready_count() -> usize {
+    READY.load(Ordering::Acquire).count_ones() as usize
+}
+
+pub fn for_each_task(mut f: impl FnMut(TaskId, &'static str)) {
+    for (i, slot) in SLOTS.lock().iter().enumerate() {
+        if let Some(slot) = slot {
+            f(TaskId(i), slot.name);
+        }
+    }
+}
+
+impl TaskId {
+    pub fn index(self) -> usize {
+        self.0
+    }
+}
+
+/// TSC value `ms` milliseconds from now.
+pub fn deadline_after_ms(ms: u64) -> u64 {
+    let per_ms = match time::tsc_per_ms() {
+        0 => FALLBACK_TSC_PER_MS,
+        n => n,
+    };
+    time::rdtsc().saturating_add(ms.saturating_mul(per_ms))
+}
+
+pub fn expired(deadline: u64) -> bool {
+    time::rdtsc() >= deadline
+}
+
+/// Wake `waker` once the TSC passes `deadline`. A task keeps one entry: a
+/// second registration moves it to the earlier deadline. With the table
+/// full the task is woken right away and simply polls again.
+pub fn wake_at(deadline: u64, waker: &Waker) {
+    let mut timers = TIMERS.lock();
+    if let Some((when, _)) = timers.iter_mut().flatten().find(|(_, w)| w.will_wake(waker)) {
+        *when = (*when).min(deadline);
+        return;
+    }
+    match timers.iter_mut().find(|t| t.is_none()) {
+        Some(free) => *free = Some((deadline, waker.clone())),
+        None => waker.wake_by_ref(),
+    }
+}
+
+fn fire_timers(now: u64) {
+    let mut timers = TIMERS.lock();
+    for entry in timers.iter_mut() {
+        if entry.as_ref().is_some_and(|(when, _)| *when <= now) {
+            if let Some((_, waker)) = entry.take() {
+                waker.wake();
+            }
+        }
+    }
+}
+
+/// Future returned by `sleep_ms`.
+pub struct Sleep {
+    deadline: u64,
+}
+
+pub fn sleep_ms(ms: u64) -> Sleep {
+    Sleep { deadline: deadline_after_ms(ms) }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if expired(self.deadline) {
+            return Poll::Ready(());
+        }
+        wake_at(self.deadline, cx.waker());
+        Poll::Pending
+    }
+}
+
+/// A one-shot flag with a single waiter. `signal` may be called from an
+/// interrupt handler: the waiter registers with interrupts off, so the
+/// handler never finds the lock held.
+pub struct Event {
+    set: AtomicBool,
+    waker: Mutex<Option<Waker>>,
+}
+
+impl Event {
+    pub const fn new() -> Self {
+        Event { set: AtomicBool::new(false), waker: Mutex::new(None) }
+    }
+
+    pub fn signal(&self) {
+        self.set.store(true, Ordering::Release);
+        if let Some(waker) = self.waker.lock().take() {
+            waker.wake();
+        }
+    }
+
+    /// Consume a pending signal.
+    pub fn take(&self) -> bool {
+        self.set.swap(false, Ordering::AcqRel)
+    }
+
+    /// Wake `waker` on the next `signal`. Check the condition again after
+    /// registering, since the signal may have arrived in between.
+    pub fn register(&self, waker: &Waker) {
+        interrupts::without_interrupts(|| {
+            let mut slot = self.waker.lock();
+            if !slot.as_ref().is_some_and(|w| w.will_wake(waker)) {
+                *slot = Some(waker.clone());
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::AtomicUsize;
+
+    static POLLS: AtomicUsize = AtomicUsize::new(0);
+
+    struct Yield(u8);
+
+    impl Future for Yield {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            POLLS.fetch_add(1, Ordering::Relaxed);
+            if self.0 == 0 {
+                return Poll::Ready(());
+            }
+            self.0 -= 1;
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    #[test]
+    fn woken_tasks_run_until_done() {
+        let id = spawn("yield", Yield(2)).unwrap();
+        let mut passes = 0;
+        while has_ready() {
+            run_ready();
+            passes += 1;
+        }
+        assert_eq!(passes, 3);
+        assert_eq!(POLLS.load(Ordering::Relaxed), 3);
+        assert!(SLOTS.lock()[id.index()].is_none());
+    }
+
+    #[test]
+    fn oversized_futures_are_refused() {
+        assert!(fits::<Yield>());
+        assert!(!fits::<[u8; TASK_BYTES + 1]>());
+        let big = async {
+            let buf = [0u8; TASK_BYTES];
+            sleep_ms(0).await;
+            core::hint::black_box(&buf);
+        };
+        assert_eq!(spawn("big", big), Err(SpawnError::TooLarge));
+    }
+}
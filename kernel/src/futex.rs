@@ -0,0 +1,120 @@
+//! Futex-style wait queues: a user-space lock sleeps on the address of its
+//! word and the unlocker wakes it, with no spinning in between.
+//!
+//! `FUTEX` (syscall 9) `WAIT` parks the caller if the u32 at the address
+//! still holds the value it expects (else EAGAIN, the lock moved on); `WAKE`
+//! makes up to n waiters runnable again, oldest first. Waiters are keyed by
+//! the physical address of the word, so two processes sharing the page meet
+//! on the same queue. A parked process is `State::Blocked`: `switch_to`
+//! will not run it until it is woken, killed or exits, which also drops it
+//! from the queue, and the round robin passes over its tasks.
+//!
+//! Only kernel-mode callers can `WAIT` for now: a ring-3 caller would
+//! `iretq` back into its blocked process, so the syscall answers it ENOSYS.
+
+use spin::Mutex;
+
+use crate::process::{self, Pid};
+
+/// Processes parked at once, across all addresses.
+const MAX_WAITERS: usize = 32;
+
+pub const WAIT: u64 = 0;
+pub const WAKE: u64 = 1;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Waiter {
+    key: u64,
+    pid: Pid,
+    /// Order of arrival, so `wake` takes the oldest.
+    seq: u64,
+}
+
+pub struct Queue {
+    waiters: [Option<Waiter>; MAX_WAITERS],
+    next_seq: u64,
+}
+
+impl Queue {
+    pub const fn new() -> Self {
+        Queue { waiters: [None; MAX_WAITERS], next_seq: 0 }
+    }
+
+    /// Park `pid` on `key`; false if the queue is full.
+    pub fn wait(&mut self, key: u64, pid: Pid) -> bool {
+        let Some(slot) = self.waiters.iter_mut().find(|w| w.is_none()) else { return false };
+        *slot = Some(Waiter { key, pid, seq: self.next_seq });
+        self.next_seq += 1;
+        true
+    }
+
+    /// Take up to `n` waiters on `key`, oldest first, handing each to `f`.
+    pub fn wake(&mut self, key: u64, n: usize, mut f: impl FnMut(Pid)) -> usize {
+        let mut woken = 0;
+        while woken < n {
+            let oldest = self
+                .waiters
+                .iter_mut()
+                .filter(|w| w.is_some_and(|w| w.key == key))
+                .min_by_key(|w| w.map_or(u64::MAX, |w| w.seq));
+            let Some(waiter) = oldest.and_then(|w| w.take()) else { break };
+            f(waiter.pid);
+            woken += 1;
+        }
+        woken
+    }
+
+    /// Drop whatever `pid` waits on.
+    pub fn forget(&mut self, pid: Pid) {
+        for w in self.waiters.iter_mut().filter(|w| w.is_some_and(|w| w.pid == pid)) {
+            *w = None;
+        }
+    }
+}
+
+static QUEUE: Mutex<Queue> = Mutex::new(Queue::new());
+
+/// Park `pid` on `key`. The CPU goes back to the kernel's tables if `pid`
+/// was running; false if too many processes wait already.
+pub fn wait(key: u64, pid: Pid) -> bool {
+    if !QUEUE.lock().wait(key, pid) {
+        return false;
+    }
+    process::block(pid);
+    true
+}
+
+/// Make up to `n` waiters on `key` runnable; returns how many were.
+pub fn wake(key: u64, n: usize) -> usize {
+    QUEUE.lock().wake(key, n, process::unblock)
+}
+
+/// A process that exits stops waiting.
+pub fn forget(pid: Pid) {
+    QUEUE.lock().forget(pid);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    #[test]
+    fn wake_takes_the_oldest_waiters_on_the_key() {
+        let mut q = Queue::new();
+        assert!(q.wait(0x1000, 3));
+        assert!(q.wait(0x2000, 4));
+        assert!(q.wait(0x1000, 5));
+        assert!(q.wait(0x1000, 6));
+        let mut woken = Vec::new();
+        assert_eq!(q.wake(0x1000, 2, |pid| woken.push(pid)), 2);
+        assert_eq!(woken, [3, 5]);
+        q.forget(6);
+        assert_eq!(q.wake(0x1000, 8, |_| ()), 0);
+        assert_eq!(q.wake(0x2000, 8, |_| ()), 1);
+        for pid in 0..MAX_WAITERS as u32 {
+            assert!(q.wait(0x3000, pid));
+        }
+        assert!(!q.wait(0x3000, 99));
+    }
+}
@@ -6,10 +6,16 @@ use x86_64::structures::tss::TaskStateSegment;
 use x86_64::PrivilegeLevel;
 use x86_64::VirtAddr;
 
+use crate::stack::{GuardedStack, StackBounds};
+
 const DOUBLE_FAULT_STACK_SIZE: usize = 4096;
+const PAGE_FAULT_STACK_SIZE: usize = 4096 * 2;
 const KERNEL_STACK_SIZE: usize = 4096 * 4;
 
 pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+/// Page faults get their own stack so a kernel stack overflowing into its
+/// guard page can still be reported.
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
 
 #[allow(dead_code)]
 #[derive(Clone, Copy)]
@@ -24,12 +30,9 @@ pub struct Selectors {
 static GDT: Once<(GlobalDescriptorTable, Selectors)> = Once::new();
 static TSS: Once<TaskStateSegment> = Once::new();
 
-#[repr(align(16))]
-struct AlignedStack<const SIZE: usize>([u8; SIZE]);
-
-static mut DOUBLE_FAULT_STACK: AlignedStack<DOUBLE_FAULT_STACK_SIZE> =
-    AlignedStack([0; DOUBLE_FAULT_STACK_SIZE]);
-static mut KERNEL_STACK: AlignedStack<KERNEL_STACK_SIZE> = AlignedStack([0; KERNEL_STACK_SIZE]);
+static DOUBLE_FAULT_STACK: GuardedStack<DOUBLE_FAULT_STACK_SIZE> = GuardedStack::new();
+static PAGE_FAULT_STACK: GuardedStack<PAGE_FAULT_STACK_SIZE> = GuardedStack::new();
+static KERNEL_STACK: GuardedStack<KERNEL_STACK_SIZE> = GuardedStack::new();
 
 pub fn init() {
     let tss = TSS.call_once(init_tss);
@@ -69,18 +72,22 @@ pub fn init() {
 
 fn init_tss() -> TaskStateSegment {
     let mut tss = TaskStateSegment::new();
-    let df_stack_start =
-        unsafe { VirtAddr::from_ptr(core::ptr::addr_of!(DOUBLE_FAULT_STACK.0) as *const u8) };
-    let df_stack_end = df_stack_start + DOUBLE_FAULT_STACK_SIZE as u64;
-    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = df_stack_end;
-
-    let kernel_stack_start =
-        unsafe { VirtAddr::from_ptr(core::ptr::addr_of!(KERNEL_STACK.0) as *const u8) };
-    let kernel_stack_end = kernel_stack_start + KERNEL_STACK_SIZE as u64;
-    tss.privilege_stack_table[0] = kernel_stack_end;
+    let [double_fault, page_fault, kernel] = stacks().map(|(_, b)| VirtAddr::new(b.top));
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault;
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = page_fault;
+    tss.privilege_stack_table[0] = kernel;
     tss
 }
 
+/// The TSS stacks, for `stack::init` to guard and track.
+pub fn stacks() -> [(&'static str, StackBounds); 3] {
+    [
+        ("double-fault", DOUBLE_FAULT_STACK.bounds()),
+        ("page-fault", PAGE_FAULT_STACK.bounds()),
+        ("kernel", KERNEL_STACK.bounds()),
+    ]
+}
+
 #[allow(dead_code)]
 pub fn selectors() -> Selectors {
     GDT.get().expect("GDT not initialized").1
@@ -89,6 +96,5 @@ pub fn selectors() -> Selectors {
 #[allow(dead_code)]
 pub fn kernel_stack_top() -> VirtAddr {
     selectors();
-    let start = unsafe { VirtAddr::from_ptr(core::ptr::addr_of!(KERNEL_STACK.0) as *const u8) };
-    start + KERNEL_STACK_SIZE as u64
+    VirtAddr::new(stacks()[2].1.top)
 }
@@ -4,7 +4,7 @@ use x86_64::instructions::hlt;
 use x86_64::registers::control::Cr2;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
-use crate::{gdt, keyboard, pic, serial, syscall};
+use crate::{gdt, ipi, irq, keyboard, lapic, ktrace, pic, serial, syscall, telemetry, time};
 use x86_64::PrivilegeLevel;
 
 static IDT: Once<InterruptDescriptorTable> = Once::new();
@@ -12,6 +12,29 @@ static IRQ_COUNT: AtomicU64 = AtomicU64::new(0);
 static PAGE_FAULTS: AtomicU64 = AtomicU64::new(0);
 static TIMER_TICKS: AtomicU64 = AtomicU64::new(0);
 
+/// Number of legacy PIC lines tracked for latency.
+pub const IRQ_LINES: usize = 16;
+
+/// Per-line entry-to-EOI latency in TSC cycles.
+struct IrqLatency {
+    count: AtomicU64,
+    total: AtomicU64,
+    max: AtomicU64,
+}
+
+#[allow(clippy::declare_interior_mutable_const)]
+const LATENCY_INIT: IrqLatency = IrqLatency {
+    count: AtomicU64::new(0),
+    total: AtomicU64::new(0),
+    max: AtomicU64::new(0),
+};
+
+static IRQ_LATENCY: [IrqLatency; IRQ_LINES] = [LATENCY_INIT; IRQ_LINES];
+/// Handlers currently executing; >1 means an IRQ interrupted another one.
+static IRQ_DEPTH: AtomicU64 = AtomicU64::new(0);
+static IRQ_NESTED: AtomicU64 = AtomicU64::new(0);
+static IRQ_MAX_DEPTH: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Clone, Copy)]
 #[repr(u8)]
 enum InterruptIndex {
@@ -69,7 +92,11 @@ pub fn init() {
             .set_handler_fn(handlers::stack_segment_fault);
         idt.general_protection_fault
             .set_handler_fn(handlers::general_protection_fault);
-        idt.page_fault.set_handler_fn(handlers::page_fault);
+        unsafe {
+            idt.page_fault
+                .set_handler_fn(handlers::page_fault)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
         idt.x87_floating_point
             .set_handler_fn(handlers::x87_floating_point);
         idt.alignment_check
@@ -105,11 +132,30 @@ pub fn init() {
         idt[InterruptIndex::SecondaryAta.as_usize()].set_handler_fn(handlers::secondary_ata);
 
         syscall::configure_idt(&mut idt, PrivilegeLevel::Ring3);
+        ipi::configure_idt(&mut idt);
+        idt[lapic::TIMER_VECTOR as usize].set_handler_fn(handlers::apic_timer);
 
         idt
     });
 
     idt.load();
+
+    // The lines the kernel itself always drives; drivers attach their own
+    for (line, name, handler) in [(0, "timer", tick as irq::Handler), (1, "keyboard", keyboard::on_interrupt)] {
+        if let Err(e) = irq::register_handler(line, name, handler) {
+            serial::write_fmt(format_args!("[idt] irq{} {}: {}\r\n", line, name, e.as_str()));
+        }
+    }
+}
+
+/// IRQ 0: the tick everything timed in ticks counts on.
+fn tick() -> bool {
+    let ticks = TIMER_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    telemetry::sample_runq();
+    if ticks % 1000 == 0 {
+        handlers::debug_line("[irq] timer\n");
+    }
+    true
 }
 
 mod handlers {
@@ -136,7 +182,13 @@ mod handlers {
     macro_rules! irq_handler {
         ($fn_name:ident, $index:expr) => {
             pub extern "x86-interrupt" fn $fn_name(_stack: InterruptStackFrame) {
+                let line = $index.as_u8() - pic::PIC_1_OFFSET;
+                let start = irq_enter();
+                ktrace::event(ktrace::Kind::IrqEnter, line as u16, 0);
                 IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
+                irq::dispatch(line);
+                ktrace::event(ktrace::Kind::IrqExit, line as u16, 0);
+                irq_exit($index, start);
                 pic::notify_end_of_interrupt($index.as_u8());
             }
         };
@@ -169,6 +221,7 @@ mod handlers {
     error_handler!(security_exception, "Security");
 
     pub extern "x86-interrupt" fn double_fault(stack: InterruptStackFrame, error_code: u64) -> ! {
+        crate::bootreason::record(crate::bootreason::BootReason::DoubleFault);
         report("Double Fault", &stack, Some(error_code));
     }
 
@@ -178,6 +231,15 @@ mod handlers {
     ) {
         PAGE_FAULTS.fetch_add(1, Ordering::Relaxed);
         let addr = Cr2::read();
+        let not_present = !error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION);
+        if crate::process::handle_page_fault(addr.as_u64(), not_present) {
+            return;
+        }
+        if let Some(name) = crate::stack::guard_owner(addr.as_u64()) {
+            serial::write_fmt(format_args!(
+                "[EXCEPTION] Kernel stack overflow: '{name}' stack hit its guard page at {addr:?}\r\n"
+            ));
+        }
         serial::write_fmt(format_args!(
             "[EXCEPTION] Page Fault\r\n  address: {addr:?}\r\n  error: {error_code:?}\r\n  bits: {:#06b}\r\n",
             error_code.bits()
@@ -186,32 +248,15 @@ mod handlers {
         halt_loop();
     }
 
-    pub extern "x86-interrupt" fn timer(_stack: InterruptStackFrame) {
-        let ticks = super::TIMER_TICKS.fetch_add(1, Ordering::Relaxed) + 1;
-        IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
-        if ticks % 1000 == 0 {
-            debug_line("[irq] timer\n");
-        }
-        pic::notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
-
-    pub extern "x86-interrupt" fn keyboard(_stack: InterruptStackFrame) {
-        let mut port = Port::new(0x60);
-        let scancode: u8 = unsafe { port.read() };
-        let trigger = keyboard::handle_scancode(scancode);
-        pic::notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-        if let Some(combo) = trigger {
-            keyboard::shutdown_via_keyboard(combo);
-        }
-    }
-
+    irq_handler!(timer, InterruptIndex::Timer);
+    irq_handler!(keyboard, InterruptIndex::Keyboard);
+    irq_handler!(rtc, InterruptIndex::Rtc);
     irq_handler!(cascade, InterruptIndex::Cascade);
     irq_handler!(serial2, InterruptIndex::Serial2);
     irq_handler!(serial1, InterruptIndex::Serial1);
     irq_handler!(lpt2, InterruptIndex::Lpt2);
     irq_handler!(floppy, InterruptIndex::Floppy);
     irq_handler!(lpt1, InterruptIndex::Lpt1);
-    irq_handler!(rtc, InterruptIndex::Rtc);
     irq_handler!(acpi, InterruptIndex::Acpi);
     irq_handler!(available1, InterruptIndex::Available1);
     irq_handler!(available2, InterruptIndex::Available2);
@@ -220,7 +265,38 @@ mod handlers {
     irq_handler!(primary_ata, InterruptIndex::PrimaryAta);
     irq_handler!(secondary_ata, InterruptIndex::SecondaryAta);
 
-    fn debug_line(message: &str) {
+    /// The tick from the APIC timer (`lapic::start_tick`): IRQ 0's handlers
+    /// and accounting, with the EOI going to the local APIC.
+    pub extern "x86-interrupt" fn apic_timer(_stack: InterruptStackFrame) {
+        let start = irq_enter();
+        ktrace::event(ktrace::Kind::IrqEnter, 0, 0);
+        IRQ_COUNT.fetch_add(1, Ordering::Relaxed);
+        irq::dispatch(0);
+        ktrace::event(ktrace::Kind::IrqExit, 0, 0);
+        irq_exit(InterruptIndex::Timer, start);
+        lapic::eoi();
+    }
+
+    fn irq_enter() -> u64 {
+        let depth = IRQ_DEPTH.fetch_add(1, Ordering::Relaxed) + 1;
+        if depth > 1 {
+            IRQ_NESTED.fetch_add(1, Ordering::Relaxed);
+        }
+        IRQ_MAX_DEPTH.fetch_max(depth, Ordering::Relaxed);
+        time::rdtsc()
+    }
+
+    /// Account the time since `irq_enter`; call right before EOI.
+    fn irq_exit(index: InterruptIndex, start: u64) {
+        let cycles = time::rdtsc().wrapping_sub(start);
+        let line = &IRQ_LATENCY[(index.as_u8() - pic::PIC_1_OFFSET) as usize];
+        line.count.fetch_add(1, Ordering::Relaxed);
+        line.total.fetch_add(cycles, Ordering::Relaxed);
+        line.max.fetch_max(cycles, Ordering::Relaxed);
+        IRQ_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(super) fn debug_line(message: &str) {
         unsafe {
             let mut port = Port::new(0xE9);
             for byte in message.bytes() {
@@ -254,3 +330,42 @@ pub fn irq_count() -> u64 {
 pub fn page_faults() -> u64 {
     PAGE_FAULTS.load(Ordering::Relaxed)
 }
+
+#[derive(Clone, Copy)]
+pub struct LatencyStats {
+    pub irq: u8,
+    pub count: u64,
+    pub mean_cycles: u64,
+    pub max_cycles: u64,
+}
+
+/// Visit latency stats for every IRQ line that has fired at least once.
+pub fn for_each_irq_latency(mut f: impl FnMut(&LatencyStats)) {
+    for (irq, line) in IRQ_LATENCY.iter().enumerate() {
+        let count = line.count.load(Ordering::Relaxed);
+        if count == 0 {
+            continue;
+        }
+        f(&LatencyStats {
+            irq: irq as u8,
+            count,
+            mean_cycles: line.total.load(Ordering::Relaxed) / count,
+            max_cycles: line.max.load(Ordering::Relaxed),
+        });
+    }
+}
+
+/// (nested entries, deepest nesting seen) since boot.
+pub fn irq_nesting() -> (u64, u64) {
+    (IRQ_NESTED.load(Ordering::Relaxed), IRQ_MAX_DEPTH.load(Ordering::Relaxed))
+}
+
+pub fn reset_irq_latency() {
+    for line in IRQ_LATENCY.iter() {
+        line.count.store(0, Ordering::Relaxed);
+        line.total.store(0, Ordering::Relaxed);
+        line.max.store(0, Ordering::Relaxed);
+    }
+    IRQ_NESTED.store(0, Ordering::Relaxed);
+    IRQ_MAX_DEPTH.store(0, Ordering::Relaxed);
+}
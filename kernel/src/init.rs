@@ -0,0 +1,229 @@
+//! Boot-time init framework.
+//!
+//! Subsystems are described by `Initcall` entries declaring their
+//! dependencies by name and a priority. `run` orders them (dependencies
+//! first, then lowest priority, then declaration order), executes them and
+//! records a boot report with per-stage TSC timings.
+
+use core::fmt;
+
+use crate::bootinfo::BootInfo;
+use crate::serial;
+use crate::status;
+use crate::time;
+use spin::Mutex;
+
+pub const MAX_INITCALLS: usize = 32;
+
+pub struct Initcall {
+    pub name: &'static str,
+    pub deps: &'static [&'static str],
+    /// Tie-breaker among ready stages; lower runs first.
+    pub priority: u8,
+    pub func: fn(&BootInfo),
+}
+
+#[derive(Clone, Copy)]
+pub enum StageStatus {
+    Ran,
+    /// Not run: a dependency is missing or part of a cycle.
+    Skipped,
+}
+
+#[derive(Clone, Copy)]
+pub struct StageReport {
+    pub name: &'static str,
+    pub status: StageStatus,
+    pub cycles: u64,
+}
+
+struct Report {
+    stages: [Option<StageReport>; MAX_INITCALLS],
+    len: usize,
+    /// Time spent in the `between` hook (agent steps), summed over stages.
+    between_cycles: u64,
+    /// Kernel entry to the end of `run`.
+    total_cycles: u64,
+}
+
+static REPORT: Mutex<Report> = Mutex::new(Report {
+    stages: [None; MAX_INITCALLS],
+    len: 0,
+    between_cycles: 0,
+    total_cycles: 0,
+});
+
+/// Compute the execution order of `calls` into `order` (indices into
+/// `calls`). Returns how many were placed; stages left out have an
+/// unresolvable dependency (unknown name or cycle).
+pub fn resolve(calls: &[Initcall], order: &mut [usize; MAX_INITCALLS]) -> usize {
+    let n = calls.len().min(MAX_INITCALLS);
+    let mut placed = [false; MAX_INITCALLS];
+    let mut count = 0;
+    loop {
+        let mut pick: Option<usize> = None;
+        for i in 0..n {
+            if placed[i] || !deps_met(calls, &placed, n, i) {
+                continue;
+            }
+            match pick {
+                Some(p) if calls[p].priority <= calls[i].priority => {}
+                _ => pick = Some(i),
+            }
+        }
+        match pick {
+            Some(i) => {
+                placed[i] = true;
+                order[count] = i;
+                count += 1;
+            }
+            None => return count,
+        }
+    }
+}
+
+fn deps_met(calls: &[Initcall], placed: &[bool; MAX_INITCALLS], n: usize, i: usize) -> bool {
+    calls[i].deps.iter().all(|dep| {
+        (0..n).any(|j| placed[j] && calls[j].name == *dep)
+    })
+}
+
+/// Run every initcall in dependency order and record the boot report.
+/// `between` is invoked after each stage (the agent uses it to get early
+/// scheduling opportunities).
+pub fn run(calls: &[Initcall], boot_info: &BootInfo, between: fn()) {
+    let mut order = [0usize; MAX_INITCALLS];
+    let count = resolve(calls, &mut order);
+    let n = calls.len().min(MAX_INITCALLS);
+    if calls.len() > MAX_INITCALLS {
+        serial::write_str("[init] too many initcalls; extra ones ignored\r\n");
+    }
+
+    for &idx in &order[..count] {
+        let call = &calls[idx];
+        crate::debug_out("init: ");
+        crate::debug_out(call.name);
+        crate::debug_out("\n");
+        let start = time::rdtsc();
+        (call.func)(boot_info);
+        let cycles = time::rdtsc().wrapping_sub(start);
+        status::ran(call.name);
+        record(StageReport { name: call.name, status: StageStatus::Ran, cycles });
+        let start = time::rdtsc();
+        between();
+        REPORT.lock().between_cycles += time::rdtsc().wrapping_sub(start);
+    }
+
+    for (i, call) in calls[..n].iter().enumerate() {
+        if !order[..count].contains(&i) {
+            serial::write_fmt(format_args!(
+                "[init] {} skipped: unresolved dependency\r\n",
+                call.name
+            ));
+            record(StageReport { name: call.name, status: StageStatus::Skipped, cycles: 0 });
+            status::set(call.name, status::Health::Skipped, "unresolved dependency");
+        }
+    }
+    REPORT.lock().total_cycles = time::since_boot();
+}
+
+fn record(stage: StageReport) {
+    let mut r = REPORT.lock();
+    if r.len < MAX_INITCALLS {
+        let len = r.len;
+        r.stages[len] = Some(stage);
+        r.len += 1;
+    }
+}
+
+/// Visit the boot report in execution order (skipped stages last).
+pub fn for_each_stage(mut f: impl FnMut(usize, &StageReport)) {
+    let r = REPORT.lock();
+    for (i, stage) in r.stages[..r.len].iter().enumerate() {
+        if let Some(s) = stage {
+            f(i, s);
+        }
+    }
+}
+
+/// Format the boot time breakdown, one line per call to `out`.
+pub fn write_report(mut out: impl FnMut(fmt::Arguments)) {
+    let (total, between) = {
+        let r = REPORT.lock();
+        (r.total_cycles, r.between_cycles)
+    };
+    out(format_args!("boot: {} total\n", time::Duration(total)));
+    let line = |out: &mut dyn FnMut(fmt::Arguments), i: usize, name: &str, cycles: u64| {
+        let permille = cycles.saturating_mul(1000).checked_div(total).unwrap_or(0);
+        out(format_args!(
+            "  {:>2} {:<10} {:>12} {:>3}.{}%\n",
+            i, name, time::Duration(cycles), permille / 10, permille % 10
+        ));
+    };
+    let mut ran = 0;
+    for_each_stage(|i, s| match s.status {
+        StageStatus::Ran => {
+            line(&mut out, i, s.name, s.cycles);
+            ran += 1;
+        }
+        StageStatus::Skipped => out(format_args!("  {:>2} {:<10} skipped\n", i, s.name)),
+    });
+    if between > 0 {
+        line(&mut out, ran, "ai-steps", between);
+    }
+}
+
+/// Dump the boot report to serial.
+pub fn log_report() {
+    write_report(|args| {
+        serial::write_str("[init] ");
+        serial::write_fmt(args);
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nop(_: &BootInfo) {}
+
+    const fn call(name: &'static str, deps: &'static [&'static str], priority: u8) -> Initcall {
+        Initcall { name, deps, priority, func: nop }
+    }
+
+    fn names(calls: &[Initcall]) -> ([&'static str; MAX_INITCALLS], usize) {
+        let mut order = [0usize; MAX_INITCALLS];
+        let count = resolve(calls, &mut order);
+        let mut out = [""; MAX_INITCALLS];
+        for (k, &i) in order[..count].iter().enumerate() {
+            out[k] = calls[i].name;
+        }
+        (out, count)
+    }
+
+    #[test]
+    fn dependencies_run_first() {
+        let calls = [call("vmm", &["pmm"], 0), call("pmm", &[], 5), call("gdt", &[], 9)];
+        let (order, count) = names(&calls);
+        assert_eq!(&order[..count], &["pmm", "vmm", "gdt"]);
+    }
+
+    #[test]
+    fn priority_breaks_ties_then_declaration_order() {
+        let calls = [call("a", &[], 2), call("b", &[], 1), call("c", &[], 1)];
+        let (order, count) = names(&calls);
+        assert_eq!(&order[..count], &["b", "c", "a"]);
+    }
+
+    #[test]
+    fn cycles_and_missing_deps_are_left_out() {
+        let calls = [
+            call("x", &["y"], 0),
+            call("y", &["x"], 0),
+            call("z", &["nope"], 0),
+            call("ok", &[], 0),
+        ];
+        let (order, count) = names(&calls);
+        assert_eq!(&order[..count], &["ok"]);
+    }
+}
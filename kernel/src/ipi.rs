@@ -0,0 +1,175 @@
+#![allow(dead_code)]
+
+//! Inter-processor interrupts over the local APIC: a cross-CPU function
+//! call, a reschedule nudge and TLB shootdown.
+//!
+//! `call_on` leaves a function in the target CPU's mailbox (`PerCpu::call`)
+//! and waits for that CPU to run it from `CALL_VECTOR`. `flush_tlb` flushes
+//! here, then has every other online CPU flush the same page (or
+//! everything) and waits for all of them, so a page-table change is seen
+//! everywhere before the caller goes on; the VMM goes through it for each
+//! unmap and remap. Waits are bounded: a CPU that never answers (interrupts
+//! off for too long) is reported, not waited on forever.
+//!
+//! With only the boot CPU online every call runs locally and no IPI is sent.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::tlb;
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+use x86_64::VirtAddr;
+
+use crate::lapic::{self, Dest};
+use crate::{percpu, serial};
+
+pub const CALL_VECTOR: u8 = 0xF0;
+pub const RESCHEDULE_VECTOR: u8 = 0xF1;
+pub const TLB_VECTOR: u8 = 0xF2;
+/// Polls before a CPU that has not answered is given up on.
+const WAIT_SPINS: u64 = 10_000_000;
+/// `TLB_ADDR` value asking for a full flush.
+const FLUSH_ALL: u64 = u64::MAX;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IpiError {
+    NoCpu,
+    /// Another call to that CPU has not been taken yet.
+    Busy,
+    NoApic,
+    Timeout,
+}
+
+impl IpiError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IpiError::NoCpu => "no such cpu online",
+            IpiError::Busy => "cpu busy with another call",
+            IpiError::NoApic => "local APIC not up",
+            IpiError::Timeout => "cpu did not answer",
+        }
+    }
+}
+
+/// Held through a shootdown, so there is one at a time.
+static SHOOTDOWN: Mutex<()> = Mutex::new(());
+/// Page of the running shootdown, and the CPUs yet to flush it.
+static TLB_ADDR: AtomicU64 = AtomicU64::new(FLUSH_ALL);
+static TLB_PENDING: AtomicU32 = AtomicU32::new(0);
+
+pub fn configure_idt(idt: &mut InterruptDescriptorTable) {
+    idt[CALL_VECTOR as usize].set_handler_fn(on_call);
+    idt[RESCHEDULE_VECTOR as usize].set_handler_fn(on_reschedule);
+    idt[TLB_VECTOR as usize].set_handler_fn(on_tlb);
+    idt[lapic::SPURIOUS_VECTOR as usize].set_handler_fn(on_spurious);
+}
+
+fn spin_until(mut done: impl FnMut() -> bool) -> bool {
+    (0..WAIT_SPINS).any(|_| {
+        core::hint::spin_loop();
+        done()
+    })
+}
+
+/// Run `f(arg)` on CPU `cpu` and wait for it to finish.
+pub fn call_on(cpu: usize, f: fn(u64), arg: u64) -> Result<(), IpiError> {
+    let here = percpu!(id).load(Ordering::Relaxed) as usize;
+    if cpu == here {
+        f(arg);
+        return Ok(());
+    }
+    let target = percpu::get(cpu).ok_or(IpiError::NoCpu)?;
+    {
+        let mut call = target.call.lock();
+        if call.is_some() {
+            return Err(IpiError::Busy);
+        }
+        *call = Some((f, arg));
+    }
+    let done = target.calls_done.load(Ordering::Acquire);
+    if !lapic::send_ipi(Dest::Cpu(target.apic_id.load(Ordering::Relaxed)), CALL_VECTOR) {
+        target.call.lock().take();
+        return Err(IpiError::NoApic);
+    }
+    if !spin_until(|| target.calls_done.load(Ordering::Acquire) != done) {
+        return Err(IpiError::Timeout);
+    }
+    Ok(())
+}
+
+/// Run `f(arg)` on every other online CPU, one after the other.
+pub fn call_others(f: fn(u64), arg: u64) -> Result<(), IpiError> {
+    let here = percpu!(id).load(Ordering::Relaxed) as usize;
+    let mut result = Ok(());
+    for cpu in 0..percpu::MAX_CPUS {
+        if cpu != here && percpu::get(cpu).is_some() {
+            result = result.and(call_on(cpu, f, arg));
+        }
+    }
+    result
+}
+
+/// Ask `cpu` to pick its next task as soon as it can.
+pub fn reschedule(cpu: usize) -> Result<(), IpiError> {
+    let target = percpu::get(cpu).ok_or(IpiError::NoCpu)?;
+    target.need_resched.store(true, Ordering::Release);
+    if cpu == percpu!(id).load(Ordering::Relaxed) as usize {
+        return Ok(());
+    }
+    match lapic::send_ipi(Dest::Cpu(target.apic_id.load(Ordering::Relaxed)), RESCHEDULE_VECTOR) {
+        true => Ok(()),
+        false => Err(IpiError::NoApic),
+    }
+}
+
+fn flush_local(addr: u64) {
+    match addr {
+        FLUSH_ALL => tlb::flush_all(),
+        addr => tlb::flush(VirtAddr::new(addr)),
+    }
+}
+
+/// Flush the page at `addr` (`None`: every non-global entry) from every
+/// online CPU's TLB.
+pub fn flush_tlb(addr: Option<u64>) {
+    let addr = addr.unwrap_or(FLUSH_ALL);
+    flush_local(addr);
+    let others = percpu::online().saturating_sub(1);
+    if others == 0 || !lapic::ready() {
+        return;
+    }
+    let _one = SHOOTDOWN.lock();
+    TLB_ADDR.store(addr, Ordering::Release);
+    TLB_PENDING.store(others, Ordering::Release);
+    if !lapic::send_ipi(Dest::AllButSelf, TLB_VECTOR) || !spin_until(|| TLB_PENDING.load(Ordering::Acquire) == 0) {
+        serial::write_fmt(format_args!(
+            "[ipi] tlb shootdown of {:#x}: {} cpu(s) did not answer\r\n",
+            addr,
+            TLB_PENDING.load(Ordering::Acquire)
+        ));
+    }
+}
+
+extern "x86-interrupt" fn on_call(_stack: InterruptStackFrame) {
+    let cpu = percpu::this();
+    cpu.ipis.fetch_add(1, Ordering::Relaxed);
+    let call = cpu.call.lock().take();
+    if let Some((f, arg)) = call {
+        f(arg);
+        cpu.calls_done.fetch_add(1, Ordering::Release);
+    }
+    lapic::eoi();
+}
+
+extern "x86-interrupt" fn on_reschedule(_stack: InterruptStackFrame) {
+    percpu!(ipis).fetch_add(1, Ordering::Relaxed);
+    lapic::eoi();
+}
+
+extern "x86-interrupt" fn on_tlb(_stack: InterruptStackFrame) {
+    percpu!(ipis).fetch_add(1, Ordering::Relaxed);
+    flush_local(TLB_ADDR.load(Ordering::Acquire));
+    TLB_PENDING.fetch_sub(1, Ordering::AcqRel);
+    lapic::eoi();
+}
+
+extern "x86-interrupt" fn on_spurious(_stack: InterruptStackFrame) {}
@@ -0,0 +1,166 @@
+//! Handlers for the legacy IRQ lines, registered by the drivers that own
+//! them instead of being wired into idt.rs.
+//!
+//! A line can be shared: every handler attached to it runs, in registration
+//! order, and says whether its device raised the interrupt. An interrupt no
+//! handler claims is counted as unclaimed; the IDT stubs account latency and
+//! send the EOI around `dispatch`.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::idt::IRQ_LINES;
+
+/// Handlers one line can carry.
+pub const MAX_SHARED: usize = 4;
+
+/// Checks its device and services it; true if the interrupt was its own.
+pub type Handler = fn() -> bool;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrqError {
+    BadLine,
+    LineFull,
+}
+
+impl IrqError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IrqError::BadLine => "no such irq line",
+            IrqError::LineFull => "irq line has no free handler slot",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    name: &'static str,
+    handler: Handler,
+}
+
+type Line = [Option<Entry>; MAX_SHARED];
+
+struct Table {
+    lines: [Line; IRQ_LINES],
+}
+
+impl Table {
+    const fn new() -> Self {
+        Table { lines: [[None; MAX_SHARED]; IRQ_LINES] }
+    }
+
+    fn attach(&mut self, line: u8, name: &'static str, handler: Handler) -> Result<(), IrqError> {
+        let entries = self.lines.get_mut(line as usize).ok_or(IrqError::BadLine)?;
+        let slot = entries.iter_mut().find(|e| e.is_none()).ok_or(IrqError::LineFull)?;
+        *slot = Some(Entry { name, handler });
+        Ok(())
+    }
+
+    fn line(&self, line: u8) -> Option<Line> {
+        self.lines.get(line as usize).copied()
+    }
+}
+
+static HANDLERS: Mutex<Table> = Mutex::new(Table::new());
+static UNCLAIMED: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+
+/// Attach `handler` to IRQ `line` (0-15) under `name`.
+pub fn register_handler(line: u8, name: &'static str, handler: Handler) -> Result<(), IrqError> {
+    if line as usize >= IRQ_LINES {
+        return Err(IrqError::BadLine);
+    }
+    // `dispatch` takes the lock from interrupt context
+    interrupts::without_interrupts(|| HANDLERS.lock().attach(line, name, handler))
+}
+
+// Runs every handler on one line, counting the interrupt in `unclaimed` if none took it.
+fn run(entries: &Line, unclaimed: &AtomicU64) -> bool {
+    let mut claimed = false;
+    for entry in entries.iter().flatten() {
+        // No short cut: two devices may be asserting a shared line at once
+        claimed |= (entry.handler)();
+    }
+    if !claimed {
+        unclaimed.fetch_add(1, Ordering::Relaxed);
+    }
+    claimed
+}
+
+/// Run every handler on `line`; false if none claimed the interrupt.
+/// Called by the IDT stubs with interrupts off.
+pub fn dispatch(line: u8) -> bool {
+    let Some(entries) = HANDLERS.lock().line(line) else { return false };
+    run(&entries, &UNCLAIMED[line as usize])
+}
+
+/// Lines with handlers: line, handler names and unclaimed interrupts.
+pub fn for_each(mut f: impl FnMut(u8, &[&'static str], u64)) {
+    let handlers = interrupts::without_interrupts(|| HANDLERS.lock().lines);
+    for (line, entries) in handlers.iter().enumerate() {
+        let mut names = [""; MAX_SHARED];
+        let mut n = 0;
+        for entry in entries.iter().flatten() {
+            names[n] = entry.name;
+            n += 1;
+        }
+        if n != 0 {
+            f(line as u8, &names[..n], UNCLAIMED[line].load(Ordering::Relaxed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static CALLS: AtomicU64 = AtomicU64::new(0);
+
+    // Each handler leaves its own bit in CALLS
+    fn first() -> bool {
+        CALLS.fetch_or(1, Ordering::Relaxed);
+        false
+    }
+
+    fn second() -> bool {
+        CALLS.fetch_or(2, Ordering::Relaxed);
+        true
+    }
+
+    fn third() -> bool {
+        CALLS.fetch_or(4, Ordering::Relaxed);
+        false
+    }
+
+    #[test]
+    fn lines_refuse_bad_numbers_and_a_fifth_handler() {
+        let mut table = Table::new();
+        assert_eq!(table.attach(IRQ_LINES as u8, "nic", first), Err(IrqError::BadLine));
+        assert_eq!(register_handler(IRQ_LINES as u8, "nic", first), Err(IrqError::BadLine));
+        assert!(table.line(IRQ_LINES as u8).is_none());
+        for _ in 0..MAX_SHARED {
+            table.attach(11, "nic", first).unwrap();
+        }
+        assert_eq!(table.attach(11, "nic", first), Err(IrqError::LineFull));
+        // Other lines keep their own slots
+        assert_eq!(table.attach(10, "nic", first), Ok(()));
+    }
+
+    #[test]
+    fn every_handler_on_a_shared_line_runs_and_unclaimed_is_counted() {
+        let mut table = Table::new();
+        let unclaimed = AtomicU64::new(0);
+        table.attach(11, "ahci", first).unwrap();
+        table.attach(11, "xhci", second).unwrap();
+        table.attach(11, "nic", third).unwrap();
+        // The one in the middle claims it; the one after still runs
+        assert!(run(&table.line(11).unwrap(), &unclaimed));
+        assert_eq!(CALLS.load(Ordering::Relaxed), 7);
+        assert_eq!(unclaimed.load(Ordering::Relaxed), 0);
+
+        table.attach(5, "ahci", first).unwrap();
+        assert!(!run(&table.line(5).unwrap(), &unclaimed));
+        assert!(!run(&table.line(9).unwrap(), &unclaimed));
+        assert_eq!(unclaimed.load(Ordering::Relaxed), 2);
+    }
+}
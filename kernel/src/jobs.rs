@@ -0,0 +1,137 @@
+//! Shell commands run later (`at`) or periodically (`every`), timed in
+//! timer ticks and started from the idle loop.
+//!
+//! The table is small and fixed; `jobs` lists it and `cancel` empties a
+//! slot. A periodic job that fell behind (a long command, a busy system)
+//! runs once and is rescheduled from now rather than catching up.
+
+use spin::Mutex;
+
+use crate::{idt, shell, time};
+
+pub const MAX_JOBS: usize = 8;
+pub const COMMAND_CAP: usize = 128;
+/// Shortest `every` period, so a job cannot take over the idle loop.
+pub const MIN_PERIOD_MS: u64 = 100;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum JobError {
+    Full,
+    TooLong,
+    PeriodTooShort,
+}
+
+impl JobError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            JobError::Full => "job table full",
+            JobError::TooLong => "command too long",
+            JobError::PeriodTooShort => "period too short",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Job {
+    id: u32,
+    command: [u8; COMMAND_CAP],
+    len: usize,
+    /// Timer tick at which it runs next.
+    due: u64,
+    /// Ticks between runs of an `every` job.
+    period: Option<u64>,
+}
+
+impl Job {
+    fn command(&self) -> &str {
+        core::str::from_utf8(&self.command[..self.len]).unwrap_or("")
+    }
+}
+
+struct Table {
+    jobs: [Option<Job>; MAX_JOBS],
+    next_id: u32,
+}
+
+static TABLE: Mutex<Table> = Mutex::new(Table { jobs: [None; MAX_JOBS], next_id: 1 });
+
+/// Run `command` in `ms`, and then every `ms` if `repeat`. Returns the job id.
+pub fn schedule(ms: u64, command: &str, repeat: bool) -> Result<u32, JobError> {
+    if command.len() > COMMAND_CAP {
+        return Err(JobError::TooLong);
+    }
+    if repeat && ms < MIN_PERIOD_MS {
+        return Err(JobError::PeriodTooShort);
+    }
+    let ticks = time::ticks_for_ms(ms);
+    let mut table = TABLE.lock();
+    let slot = table.jobs.iter().position(Option::is_none).ok_or(JobError::Full)?;
+    let id = table.next_id;
+    table.next_id += 1;
+    let mut bytes = [0u8; COMMAND_CAP];
+    bytes[..command.len()].copy_from_slice(command.as_bytes());
+    table.jobs[slot] = Some(Job {
+        id,
+        command: bytes,
+        len: command.len(),
+        due: idt::timer_ticks() + ticks,
+        period: repeat.then_some(ticks.max(1)),
+    });
+    Ok(id)
+}
+
+pub fn cancel(id: u32) -> bool {
+    let mut table = TABLE.lock();
+    match table.jobs.iter_mut().find(|j| j.is_some_and(|j| j.id == id)) {
+        Some(slot) => {
+            *slot = None;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Jobs with their id, ticks until the next run, period in ticks (for
+/// `every`) and command.
+pub fn for_each(mut f: impl FnMut(u32, u64, Option<u64>, &str)) {
+    let now = idt::timer_ticks();
+    for job in TABLE.lock().jobs.iter().flatten() {
+        f(job.id, job.due.saturating_sub(now), job.period, job.command());
+    }
+}
+
+/// Tick of the next run after one due at `due` ran at `now`.
+fn next_due(due: u64, period: u64, now: u64) -> u64 {
+    match due + period {
+        next if next > now => next,
+        // Fell behind: no burst of catch-up runs
+        _ => now + period,
+    }
+}
+
+/// Called from the idle loop: run the jobs that are due, one pass each.
+pub fn run_due() {
+    let now = idt::timer_ticks();
+    for slot in 0..MAX_JOBS {
+        let job = {
+            let mut table = TABLE.lock();
+            let Some(job) = table.jobs[slot].filter(|j| j.due <= now) else { continue };
+            table.jobs[slot] = job.period.map(|p| Job { due: next_due(job.due, p, now), ..job });
+            job
+        };
+        // Unlocked: the command may schedule or cancel jobs itself
+        shell::run_job(job.id, job.command());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn periodic_jobs_do_not_catch_up() {
+        assert_eq!(next_due(100, 18, 100), 118);
+        assert_eq!(next_due(100, 18, 117), 118);
+        assert_eq!(next_due(100, 18, 150), 168);
+    }
+}
@@ -1,6 +1,180 @@
 #![allow(dead_code)]
 
-use crate::ai_action::Action;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+
+pub use ai_core::journal::{pair_records, Record, RecordKind, VerifyReport};
+
+use crate::ai_action::{actf, Action};
+use crate::ai_model::ModelInfo;
+use crate::{hash, pstore, serial, time};
+
+/// Records kept, in RAM and in pstore.
+pub const RING_LEN: usize = 64;
+/// Bytes of the pstore image: how many of its records `verify` has been
+/// through (u32), then the records oldest first.
+pub const IMAGE_LEN: usize = 4 + RING_LEN * Record::LEN;
+
+struct Ring {
+    records: [Option<Record>; RING_LEN],
+    /// CRC-32 of each record when it was written; `verify` checks them.
+    sums: [u32; RING_LEN],
+    next: usize,
+    /// Records inserted since boot, restored ones included.
+    written: u64,
+    /// `written` as of the last `verify`: the dangling intents before it
+    /// are in DANGLING already. Seqs start over every boot, so the mark is
+    /// a position in the journal; it survives a reboot in the pstore image.
+    counted: u64,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring { records: [None; RING_LEN], sums: [0; RING_LEN], next: 0, written: 0, counted: 0 }
+    }
+
+    fn push(&mut self, rec: Record) {
+        let i = self.next;
+        self.records[i] = Some(rec);
+        self.sums[i] = crc(&rec);
+        self.next = (i + 1) % RING_LEN;
+        self.written += 1;
+    }
+
+    /// Copy the records into `buf` oldest first; returns how many there are
+    /// and how many fail their CRC.
+    fn snapshot(&self, buf: &mut [Record; RING_LEN]) -> (usize, u32) {
+        let mut n = 0;
+        let mut corrupt = 0;
+        for i in 0..RING_LEN {
+            let at = (self.next + i) % RING_LEN;
+            if let Some(rec) = self.records[at] {
+                corrupt += (crc(&rec) != self.sums[at]) as u32;
+                buf[n] = rec;
+                n += 1;
+            }
+        }
+        (n, corrupt)
+    }
+
+    /// How many of the `n` records in the ring (oldest first) an earlier
+    /// `verify` went through.
+    fn counted_of(&self, n: usize) -> usize {
+        self.counted.saturating_sub(self.written - n as u64).min(n as u64) as usize
+    }
+
+    /// Pair up the records. Also returns the dangling intents no earlier
+    /// call has seen, and marks them seen.
+    fn verify(&mut self) -> (VerifyReport, u32) {
+        let mut buf = [Record { tsc: 0, seq: 0, kind: RecordKind::Reject, action: 0, flags: 0, code: 0 }; RING_LEN];
+        let (n, corrupt) = self.snapshot(&mut buf);
+        let mut report = pair_records(&buf[..n]);
+        report.corrupt = corrupt;
+        // An intent's APPLY_OK/APPLY_FAIL always comes after it
+        let fresh = pair_records(&buf[self.counted_of(n)..n]).dangling;
+        self.counted = self.written;
+        (report, fresh)
+    }
+
+    /// The pstore image: records seen by `verify`, then the records.
+    fn image(&self, out: &mut [u8]) -> usize {
+        let mut len = 4;
+        for i in 0..RING_LEN {
+            if let Some(rec) = &self.records[(self.next + i) % RING_LEN] {
+                out[len..len + Record::LEN].copy_from_slice(&rec.encode());
+                len += Record::LEN;
+            }
+        }
+        let counted = self.counted_of((len - 4) / Record::LEN) as u32;
+        out[..4].copy_from_slice(&counted.to_le_bytes());
+        len
+    }
+
+    /// Append the records of an `image`, keeping its mark, then a BOOT
+    /// record so they do not pair with this boot's seqs; returns how many
+    /// there were.
+    fn restore(&mut self, image: &[u8]) -> usize {
+        let Some((head, records)) = image.split_first_chunk::<4>() else { return 0 };
+        let start = self.written;
+        records.chunks_exact(Record::LEN).filter_map(Record::decode).for_each(|rec| self.push(rec));
+        let n = self.written - start;
+        self.counted = start + (u32::from_le_bytes(*head) as u64).min(n);
+        if n != 0 {
+            self.push(Record { tsc: 0, seq: 0, kind: RecordKind::Boot, action: 0, flags: 0, code: 0 });
+        }
+        n as usize
+    }
+}
+
+static RING: Mutex<Ring> = Mutex::new(Ring::new());
+
+// Intents found without a matching APPLY_OK/APPLY_FAIL (unclean shutdown or crash mid-apply).
+static DANGLING: AtomicU32 = AtomicU32::new(0);
+
+fn push(seq: u64, kind: RecordKind, action: u8, flags: u8, code: u32) {
+    insert(Record { tsc: time::since_boot(), seq, kind, action, flags, code });
+}
+
+fn insert(rec: Record) {
+    let mut ring = RING.lock();
+    ring.push(rec);
+    mirror(&ring);
+}
+
+/// CRC-32 of a record's fields, taken when it enters the ring.
+fn crc(rec: &Record) -> u32 {
+    let mut c = hash::Crc32::new();
+    c.update(&rec.tsc.to_le_bytes());
+    c.update(&rec.seq.to_le_bytes());
+    c.update(&[rec.kind as u8, rec.action, rec.flags]);
+    c.update(&rec.code.to_le_bytes());
+    c.finish()
+}
+
+/// Visits records oldest first.
+pub fn for_each(mut f: impl FnMut(&Record)) {
+    let ring = RING.lock();
+    for i in 0..RING_LEN {
+        if let Some(rec) = &ring.records[(ring.next + i) % RING_LEN] {
+            f(rec);
+        }
+    }
+}
+
+/// Records inserted since boot; a mark for `for_each_since`.
+pub fn written() -> u64 {
+    RING.lock().written
+}
+
+/// Visits records inserted after `mark` (a `written` value) that are still
+/// in the ring, oldest first. Returns the mark to pass next time.
+pub fn for_each_since(mark: u64, mut f: impl FnMut(&Record)) -> u64 {
+    let ring = RING.lock();
+    let new = ring.written.saturating_sub(mark).min(RING_LEN as u64) as usize;
+    for i in RING_LEN - new..RING_LEN {
+        if let Some(rec) = &ring.records[(ring.next + i) % RING_LEN] {
+            f(rec);
+        }
+    }
+    ring.written
+}
+
+/// Write the ring to its pstore section, oldest record first, so it is
+/// still there after a reset that gave no warning.
+fn mirror(ring: &Ring) {
+    let _ = pstore::fill(pstore::Section::Journal, |out| ring.image(out));
+}
+
+/// Put back the records the previous boot left in pstore (their
+/// timestamps are from that boot).
+pub fn init() {
+    let mut ring = RING.lock();
+    if let Some(n) = pstore::read(pstore::Section::Journal, |image| ring.restore(image)) {
+        mirror(&ring);
+        drop(ring);
+        serial::write_fmt(format_args!("[journal] {} records from the previous boot\r\n", n));
+    }
+}
 
 #[inline]
 fn e9(b: u8) {
@@ -32,6 +206,8 @@ fn w_u64(mut v: u64) {
     }
 }
 
+const HEX: &[u8; 16] = b"0123456789abcdef";
+
 fn sp() {
     e9(b' ');
 }
@@ -40,25 +216,38 @@ fn nl() {
     e9(b'\n');
 }
 
+fn src(a: &Action) {
+    if a.flags & actf::HEURISTIC != 0 {
+        w(" src=heuristic");
+    }
+}
+
 pub fn journal_intent(seq: u64, a: &Action) {
+    push(seq, RecordKind::Intent, a.kind, a.flags, 0);
     w("seq=");
     w_u64(seq);
     sp();
     w("INTENT kind=");
     w_u64(a.kind as u64);
+    src(a);
     nl();
 }
 
-pub fn journal_commit(seq: u64, a: &Action) {
+/// `effect` is what the self-test measured once the action took hold.
+pub fn journal_commit(seq: u64, a: &Action, effect: u32) {
+    push(seq, RecordKind::ApplyOk, a.kind, a.flags, effect);
     w("seq=");
     w_u64(seq);
     sp();
     w("APPLY_OK kind=");
     w_u64(a.kind as u64);
+    w(" effect=");
+    w_u64(effect as u64);
     nl();
 }
 
-pub fn journal_fail(seq: u64, _a: &Action, code: u32) {
+pub fn journal_fail(seq: u64, a: &Action, code: u32) {
+    push(seq, RecordKind::ApplyFail, a.kind, a.flags, code);
     w("seq=");
     w_u64(seq);
     sp();
@@ -68,6 +257,7 @@ pub fn journal_fail(seq: u64, _a: &Action, code: u32) {
 }
 
 pub fn journal_reject(seq: u64, a: &Action) {
+    push(seq, RecordKind::Reject, a.kind, a.flags, 0);
     w("seq=");
     w_u64(seq);
     sp();
@@ -76,3 +266,168 @@ pub fn journal_reject(seq: u64, a: &Action) {
     nl();
 }
 
+
+/// A decision on replayed telemetry, as a debugcon line only: a long trace
+/// would push the real records out of the ring and its pstore copy.
+pub fn journal_dry_run(index: u64, a: &Action) {
+    w("replay=");
+    w_u64(index);
+    sp();
+    w("DRYRUN kind=");
+    w_u64(a.kind as u64);
+    w(" p1=");
+    w_u64(a.param1);
+    src(a);
+    nl();
+}
+
+/// A proposal the agent made in observe mode (`ai.mode`), not applied.
+pub fn journal_observed(step: u64, a: &Action) {
+    push(step, RecordKind::Observed, a.kind, a.flags, a.param1 as u32);
+    w("step=");
+    w_u64(step);
+    sp();
+    w("OBSERVED kind=");
+    w_u64(a.kind as u64);
+    w(" p1=");
+    w_u64(a.param1);
+    src(a);
+    nl();
+}
+
+pub fn journal_model_too_slow(violations: u64, elapsed_us: u32) {
+    push(violations, RecordKind::ModelTooSlow, 0, 0, elapsed_us);
+    w("MODEL_TOO_SLOW n=");
+    w_u64(violations);
+    w(" us=");
+    w_u64(elapsed_us as u64);
+    nl();
+}
+
+pub fn journal_model_loaded(info: &ModelInfo) {
+    let [major, minor, patch] = info.version.map(u64::from);
+    push(major << 32 | minor << 16 | patch, RecordKind::ModelLoaded, 0, 0, info.hash_prefix());
+    w("MODEL_LOADED name=");
+    w(info.name());
+    w(" v=");
+    w_u64(major);
+    e9(b'.');
+    w_u64(minor);
+    e9(b'.');
+    w_u64(patch);
+    w(" hash=");
+    for &b in &info.train_hash {
+        e9(HEX[(b >> 4) as usize]);
+        e9(HEX[(b & 0xF) as usize]);
+    }
+    nl();
+}
+
+/// `kind` is the action the trip blames, 0 if none.
+pub fn journal_guard_trip(trips: u64, reason: u32, kind: u8) {
+    push(trips, RecordKind::GuardTrip, kind, 0, reason);
+    w("GUARD_TRIP n=");
+    w_u64(trips);
+    w(" reason=");
+    w_u64(reason as u64);
+    w(" kind=");
+    w_u64(kind as u64);
+    nl();
+}
+
+/// Verifies the journal ring and adds the dangling intents no earlier
+/// `verify` counted (this boot or, through pstore, the one before) to the
+/// unclean counter.
+/// Must not run while an action is being applied (its intent would look dangling).
+pub fn verify() -> VerifyReport {
+    let mut ring = RING.lock();
+    let (report, fresh) = ring.verify();
+    // Keep the mark with the records, or the next boot counts them again
+    mirror(&ring);
+    drop(ring);
+    DANGLING.fetch_add(fresh, Ordering::AcqRel);
+    if report.dangling > 0 {
+        w("VERIFY dangling=");
+        w_u64(report.dangling as u64);
+        nl();
+    }
+    if report.corrupt > 0 {
+        w("VERIFY corrupt=");
+        w_u64(report.corrupt as u64);
+        nl();
+    }
+    report
+}
+
+pub fn dangling_intents() -> u32 {
+    DANGLING.load(Ordering::Acquire)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rec(seq: u64, kind: RecordKind) -> Record {
+        Record { tsc: 0, seq, kind, action: 1, flags: 0, code: 0 }
+    }
+
+    #[test]
+    fn dangling_intents_are_counted_once() {
+        let mut ring = Ring::new();
+        ring.push(rec(0, RecordKind::Intent));
+        ring.push(rec(0, RecordKind::ApplyOk));
+        ring.push(rec(1, RecordKind::Intent));
+        let (report, fresh) = ring.verify();
+        assert_eq!((report.dangling, fresh), (1, 1));
+        // Run again: still dangling, but already counted
+        let (report, fresh) = ring.verify();
+        assert_eq!((report.dangling, fresh), (1, 0));
+        ring.push(rec(2, RecordKind::Intent));
+        assert_eq!(ring.verify().1, 1);
+
+        // Wrapped past the counted records: only the new intent is fresh
+        for seq in 3..RING_LEN as u64 + 2 {
+            ring.push(rec(seq, RecordKind::Reject));
+        }
+        ring.push(rec(90, RecordKind::Intent));
+        assert_eq!(ring.verify().1, 1);
+    }
+
+    #[test]
+    fn the_mark_survives_the_pstore_image() {
+        let mut ring = Ring::new();
+        ring.push(rec(4, RecordKind::Intent));
+        ring.verify();
+        ring.push(rec(5, RecordKind::Intent));
+        let mut image = [0u8; IMAGE_LEN];
+        let len = ring.image(&mut image);
+        assert_eq!(len, 4 + 2 * Record::LEN);
+
+        // Next boot: the intent counted before the reset is not counted again
+        let mut next = Ring::new();
+        assert_eq!(next.restore(&image[..len]), 2);
+        let (report, fresh) = next.verify();
+        assert_eq!((report.dangling, fresh), (2, 1));
+        let len = next.image(&mut image);
+        let mut third = Ring::new();
+        third.restore(&image[..len]);
+        assert_eq!(third.verify().1, 0);
+    }
+
+    #[test]
+    fn seqs_of_the_previous_boot_do_not_pair_with_this_one() {
+        let mut ring = Ring::new();
+        ring.push(rec(1, RecordKind::Intent));
+        let mut image = [0u8; IMAGE_LEN];
+        let len = ring.image(&mut image);
+
+        // Seqs start over: this boot's first action is seq 1 again
+        let mut next = Ring::new();
+        assert_eq!(next.restore(&image[..len]), 1);
+        next.push(rec(1, RecordKind::Intent));
+        next.push(rec(1, RecordKind::ApplyOk));
+        let (report, fresh) = next.verify();
+        assert_eq!((report.dangling, fresh), (1, 1));
+        assert_eq!(report.first_dangling, Some(1));
+    }
+}
@@ -0,0 +1,102 @@
+//! Kernel virtual layout. Kernel stacks and MMIO remappings each get
+//! their own PML4 slot in the higher half; with the `kaslr` feature the base
+//! inside that slot is slid by a random 2 MiB-aligned offset at boot so those
+//! addresses cannot be predicted from the image. Without it the slide is zero.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{rng, serial, vmm};
+
+/// Each region owns one 512 GiB PML4 slot.
+const SLOT_SIZE: u64 = 1 << 39;
+/// Slides stay in the lower half of the slot; the rest is the usable span.
+const SLIDE_RANGE: u64 = SLOT_SIZE / 2;
+const SLIDE_ALIGN: u64 = 2 * 1024 * 1024;
+/// Bytes available to allocators above a region's base.
+pub const WINDOW_SPAN: u64 = SLOT_SIZE - SLIDE_RANGE;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Region {
+    Stacks,
+    Mmio,
+}
+
+impl Region {
+    pub const ALL: [Region; 2] = [Region::Stacks, Region::Mmio];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Region::Stacks => "stacks",
+            Region::Mmio => "mmio",
+        }
+    }
+
+    fn slot_base(self) -> u64 {
+        // PML4[288], [304]: clear of the first higher-half slots
+        let index = match self {
+            Region::Stacks => 288,
+            Region::Mmio => 304,
+        };
+        0xFFFF_0000_0000_0000 | (index << 39)
+    }
+}
+
+static BASES: [AtomicU64; 2] = [AtomicU64::new(0), AtomicU64::new(0)];
+
+fn slide() -> u64 {
+    if cfg!(feature = "kaslr") {
+        rng::below(SLIDE_RANGE / SLIDE_ALIGN) * SLIDE_ALIGN
+    } else {
+        0
+    }
+}
+
+/// Picks the region bases and reserves their PML4 slots. Must run after
+/// `vmm::init` and before any process address space is created.
+pub fn init() {
+    for (i, region) in Region::ALL.iter().enumerate() {
+        let base = region.slot_base() + slide();
+        if let Err(e) = vmm::reserve_kernel_slot(base) {
+            serial::write_fmt(format_args!("[kaslr] {} slot: {:?}\r\n", region.name(), e));
+            continue;
+        }
+        BASES[i].store(base, Ordering::Relaxed);
+    }
+    serial::write_fmt(format_args!(
+        "[kaslr] {} (rng={}) stacks={:#x} mmio={:#x}\r\n",
+        if cfg!(feature = "kaslr") { "randomized" } else { "fixed" },
+        if rng::is_hardware() { "rdrand" } else { "tsc" },
+        base(Region::Stacks),
+        base(Region::Mmio),
+    ));
+}
+
+/// Base of `region`, or 0 before `init` (or if its slot could not be reserved).
+pub fn base(region: Region) -> u64 {
+    let i = Region::ALL.iter().position(|&r| r == region).unwrap_or(0);
+    BASES[i].load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_canonical_and_distinct() {
+        for region in Region::ALL {
+            let base = region.slot_base();
+            assert!(base >= vmm::KERNEL_BASE);
+            assert_eq!(base % SLOT_SIZE, 0);
+        }
+        assert_ne!(Region::Stacks.slot_base(), Region::Mmio.slot_base());
+    }
+
+    #[test]
+    fn slide_keeps_alignment_and_span() {
+        for _ in 0..16 {
+            let s = slide();
+            assert_eq!(s % SLIDE_ALIGN, 0);
+            assert!(s < SLIDE_RANGE);
+        }
+    }
+}
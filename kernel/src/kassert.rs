@@ -0,0 +1,67 @@
+//! Checks that fail soft: instead of panicking they log the condition and
+//! return a typed error from the enclosing function, so a misbehaving
+//! device or a bad agent action degrades one path rather than halting the
+//! kernel.
+//!
+//! `kensure!` is for conditions that can fail at run time (hardware that
+//! does not answer, input from outside); failures are logged at debug level
+//! since callers report the error themselves. `kassert!` is for the kernel's
+//! own invariants: debug builds still panic so tests catch them, release
+//! builds log an error and return.
+
+use crate::klog::{self, Level};
+
+#[doc(hidden)]
+pub fn failed(kind: &str, cond: &str, file: &str, line: u32) {
+    let level = if kind == "kassert" { Level::Error } else { Level::Debug };
+    klog::log(level, format_args!("[{}] {}:{}: {}\r\n", kind, file, line, cond));
+}
+
+/// Return `Err($err.into())` unless `$cond` holds.
+macro_rules! kensure {
+    ($cond:expr, $err:expr) => {
+        if !$cond {
+            $crate::kassert::failed("kensure", stringify!($cond), file!(), line!());
+            return Err($err.into());
+        }
+    };
+}
+
+/// Like `kensure!`, for invariants: panics in debug builds.
+macro_rules! kassert {
+    ($cond:expr, $err:expr) => {
+        if !$cond {
+            if cfg!(debug_assertions) {
+                panic!("kassert failed: {}", stringify!($cond));
+            }
+            $crate::kassert::failed("kassert", stringify!($cond), file!(), line!());
+            return Err($err.into());
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    fn ensure_small(n: u32) -> Result<u32, &'static str> {
+        kensure!(n < 10, "too big");
+        Ok(n)
+    }
+
+    fn assert_nonzero(n: u32) -> Result<u32, &'static str> {
+        kassert!(n != 0, "zero");
+        Ok(n)
+    }
+
+    #[test]
+    fn kensure_returns_the_error() {
+        assert_eq!(ensure_small(3), Ok(3));
+        assert_eq!(ensure_small(12), Err("too big"));
+        assert_eq!(assert_nonzero(4), Ok(4));
+    }
+
+    #[test]
+    #[should_panic(expected = "kassert failed: n != 0")]
+    fn kassert_panics_in_debug_builds() {
+        let _ = assert_nonzero(0);
+    }
+}
@@ -1,37 +1,316 @@
 use core::sync::atomic::{AtomicBool, Ordering};
 
-use crate::{serial, vga};
-use core::sync::atomic::{AtomicUsize};
+use crate::{serial, time, vga};
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize};
+use spin::Mutex;
 
 static CTRL_HELD: AtomicBool = AtomicBool::new(false);
 static SHIFT_HELD: AtomicBool = AtomicBool::new(false);
+static ALT_HELD: AtomicBool = AtomicBool::new(false);
+/// Previous byte was the 0xE0 prefix of an extended (cursor block) key.
+static EXTENDED: AtomicBool = AtomicBool::new(false);
+/// TSC at the last key press, for inactivity timeouts.
+static LAST_INPUT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Who reads the key queue. The shell sits at the bottom; a full-screen
+/// mode pushes itself on top while it runs and removes itself when done, so
+/// the keys go back to whoever had them before. Only the reader on top gets
+/// keys from `poll_input`/`poll_key`, and only the shell's are echoed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Focus {
+    Shell,
+    /// Log and dashboard virtual consoles (`vconsole`).
+    Views,
+    /// Memory and file viewer.
+    Viewer,
+    /// Text editor (`edit`).
+    Editor,
+    /// `--More--` prompt of paged shell output.
+    Pager,
+    /// Blanked or locked screen.
+    Lock,
+}
+
+const MAX_FOCUS: usize = 8;
+
+struct FocusStack {
+    owners: [Focus; MAX_FOCUS],
+    depth: usize,
+}
+
+impl FocusStack {
+    const fn new() -> Self {
+        FocusStack { owners: [Focus::Shell; MAX_FOCUS], depth: 1 }
+    }
+
+    fn top(&self) -> Focus {
+        self.owners[self.depth - 1]
+    }
+
+    /// Put `owner` on top, moving it there if it already holds a place.
+    fn push(&mut self, owner: Focus) {
+        self.remove(owner);
+        if self.depth < MAX_FOCUS {
+            self.owners[self.depth] = owner;
+            self.depth += 1;
+        }
+    }
+
+    /// Drop `owner` wherever it sits; the shell always stays.
+    fn remove(&mut self, owner: Focus) {
+        if owner == Focus::Shell {
+            return;
+        }
+        if let Some(i) = self.owners[..self.depth].iter().position(|&o| o == owner) {
+            self.owners.copy_within(i + 1..self.depth, i);
+            self.depth -= 1;
+        }
+    }
+}
+
+static FOCUS: Mutex<FocusStack> = Mutex::new(FocusStack::new());
+
+/// The reader keys currently go to.
+pub fn focus() -> Focus {
+    FOCUS.lock().top()
+}
+
+/// Take the keys until `release_focus(owner)`.
+pub fn take_focus(owner: Focus) {
+    FOCUS.lock().push(owner);
+}
+
+/// Give the keys back to whoever had them before `owner` took them.
+pub fn release_focus(owner: Focus) {
+    FOCUS.lock().remove(owner);
+}
+
+/// Focus held until the guard is dropped, for modes that run to completion.
+pub struct FocusGuard(Focus);
+
+pub fn grab_focus(owner: Focus) -> FocusGuard {
+    take_focus(owner);
+    FocusGuard(owner)
+}
+
+impl Drop for FocusGuard {
+    fn drop(&mut self) {
+        release_focus(self.0);
+    }
+}
+
+/// Decoded key for full-screen users (`poll_key`). Line-mode readers take
+/// raw `Input`s from `poll_input`, non-ASCII codes included; the shell drops
+/// anything that is not printable or an edit key.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Escape,
+    Up,
+    Down,
+    Left,
+    Right,
+    PageUp,
+    PageDown,
+    Home,
+    End,
+    Delete,
+    /// Ctrl with a letter, lower case. Ctrl+H and Ctrl+J arrive as
+    /// Backspace and Enter, and Ctrl+C/Ctrl+X are the shutdown combos.
+    Ctrl(char),
+}
+
+// Buffer codes for keys without an ASCII byte; printable input stays below 0x80.
+const CODE_ESCAPE: u8 = 0x1B;
+const CODE_UP: u8 = 0x80;
+const CODE_DOWN: u8 = 0x81;
+const CODE_LEFT: u8 = 0x82;
+const CODE_RIGHT: u8 = 0x83;
+const CODE_PAGE_UP: u8 = 0x84;
+const CODE_PAGE_DOWN: u8 = 0x85;
+const CODE_HOME: u8 = 0x86;
+const CODE_END: u8 = 0x87;
+const CODE_DELETE: u8 = 0x88;
 
 // Simple key buffer for shell input (ASCII), SPSC: ISR writes, shell reads
 const KBUF_CAP: usize = 256;
-static KBUF: [u8; KBUF_CAP] = [0; KBUF_CAP];
+#[allow(clippy::declare_interior_mutable_const)]
+const KBUF_INIT: AtomicU8 = AtomicU8::new(0);
+static KBUF: [AtomicU8; KBUF_CAP] = [KBUF_INIT; KBUF_CAP];
+/// TSC at which each buffered byte was pushed, for the input latency.
+static KTSC: [AtomicU64; KBUF_CAP] = [const { AtomicU64::new(0) }; KBUF_CAP];
 static KHEAD: AtomicUsize = AtomicUsize::new(0);
 static KTAIL: AtomicUsize = AtomicUsize::new(0);
+/// Keys echoed since boot.
+static ECHOED: AtomicU64 = AtomicU64::new(0);
+
+// Keypress-to-echo latency of the last keys, for a rolling p95: a sample
+// counts while it is at most `WINDOW_MS` old, so a slow spell is forgotten
+// once typing is fast again (or stops).
+const WINDOW_LEN: usize = 64;
+const WINDOW_MS: u64 = 10_000;
+
+struct Window {
+    /// TSC of each echo; 0 for an unused entry.
+    at: [u64; WINDOW_LEN],
+    us: [u32; WINDOW_LEN],
+    next: usize,
+}
+
+static WINDOW: Mutex<Window> = Mutex::new(Window { at: [0; WINDOW_LEN], us: [0; WINDOW_LEN], next: 0 });
 
 fn kbuf_push(b: u8) {
     let head = KHEAD.load(Ordering::Relaxed);
     let next = (head + 1) % KBUF_CAP;
-    let tail = KTAIL.load(Ordering::Relaxed);
+    let tail = KTAIL.load(Ordering::Acquire);
     if next != tail {
-        unsafe {
-            let p = KBUF.as_ptr().add(head) as *mut u8;
-            core::ptr::write(p, b);
-        }
-        KHEAD.store(next, Ordering::Relaxed);
+        KBUF[head].store(b, Ordering::Relaxed);
+        KTSC[head].store(time::rdtsc(), Ordering::Relaxed);
+        KHEAD.store(next, Ordering::Release);
     }
 }
 
-pub fn poll_char() -> Option<char> {
+/// A key taken from the input queue, tagged with the TSC of its keypress.
+#[derive(Copy, Clone, Debug)]
+pub struct Input {
+    pub ch: char,
+    pressed: u64,
+}
+
+/// Next key if `owner` has the focus; otherwise the key stays queued.
+pub fn poll_input(owner: Focus) -> Option<Input> {
+    if focus() != owner {
+        return None;
+    }
     let tail = KTAIL.load(Ordering::Relaxed);
-    let head = KHEAD.load(Ordering::Relaxed);
+    let head = KHEAD.load(Ordering::Acquire);
     if tail == head { return None; }
-    let b = unsafe { *KBUF.as_ptr().add(tail) };
-    KTAIL.store((tail + 1) % KBUF_CAP, Ordering::Relaxed);
-    Some(b as char)
+    let b = KBUF[tail].load(Ordering::Relaxed);
+    let pressed = KTSC[tail].load(Ordering::Relaxed);
+    KTAIL.store((tail + 1) % KBUF_CAP, Ordering::Release);
+    Some(Input { ch: b as char, pressed })
+}
+
+/// Next byte of the queue as a `char`, with the codes above 0x7F passed
+/// through for `decode`.
+pub fn poll_char(owner: Focus) -> Option<char> {
+    poll_input(owner).map(|input| input.ch)
+}
+
+/// Whether keys are waiting to be read.
+#[cfg(feature = "ai_agent")]
+pub fn has_input() -> bool {
+    KTAIL.load(Ordering::Relaxed) != KHEAD.load(Ordering::Acquire)
+}
+
+/// Write a key taken by a line-mode reader (the shell, `read` on fd 0) to
+/// the VGA console, and record how long it took from the keypress.
+pub fn echo(input: &Input) {
+    if focus() == Focus::Shell {
+        match input.ch {
+            '\x08' => vga::backspace(),
+            c if c.is_ascii_control() && c != '\n' => {}
+            c => vga::put_char(c),
+        }
+    }
+    let now = time::rdtsc();
+    let us = time::cycles_to_us(now.wrapping_sub(input.pressed)).unwrap_or(0);
+    ECHOED.fetch_add(1, Ordering::Relaxed);
+    let mut w = WINDOW.lock();
+    let i = w.next;
+    w.at[i] = now;
+    w.us[i] = us.min(u32::MAX as u64) as u32;
+    w.next = (i + 1) % WINDOW_LEN;
+}
+
+/// Nearest-rank percentile of `sorted` (ascending), 0 when empty.
+fn percentile(sorted: &[u32], pct: usize) -> u32 {
+    match sorted.len() {
+        0 => 0,
+        n => sorted[(pct * n).div_ceil(100).clamp(1, n) - 1],
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct LatencyStats {
+    /// Keys echoed since boot.
+    pub echoed: u64,
+    /// Samples in the rolling window the percentiles come from.
+    pub samples: usize,
+    pub p50_us: u32,
+    pub p95_us: u32,
+    pub max_us: u32,
+}
+
+/// Keypress-to-echo latency over the rolling window.
+pub fn input_latency() -> LatencyStats {
+    let oldest = time::rdtsc().saturating_sub(time::tsc_per_ms().saturating_mul(WINDOW_MS));
+    let mut sorted = [0u32; WINDOW_LEN];
+    let mut n = 0;
+    {
+        let w = WINDOW.lock();
+        for (&at, &us) in w.at.iter().zip(&w.us) {
+            if at != 0 && at >= oldest {
+                sorted[n] = us;
+                n += 1;
+            }
+        }
+    }
+    let sorted = &mut sorted[..n];
+    sorted.sort_unstable();
+    LatencyStats {
+        echoed: ECHOED.load(Ordering::Relaxed),
+        samples: n,
+        p50_us: percentile(sorted, 50),
+        p95_us: percentile(sorted, 95),
+        max_us: sorted.last().copied().unwrap_or(0),
+    }
+}
+
+pub fn poll_key(owner: Focus) -> Option<Key> {
+    Some(decode(poll_char(owner)? as u8))
+}
+
+/// Key for a byte of the input queue.
+fn decode(c: u8) -> Key {
+    match c {
+        b'\n' => Key::Enter,
+        8 => Key::Backspace,
+        CODE_ESCAPE => Key::Escape,
+        CODE_UP => Key::Up,
+        CODE_DOWN => Key::Down,
+        CODE_LEFT => Key::Left,
+        CODE_RIGHT => Key::Right,
+        CODE_PAGE_UP => Key::PageUp,
+        CODE_PAGE_DOWN => Key::PageDown,
+        CODE_HOME => Key::Home,
+        CODE_END => Key::End,
+        CODE_DELETE => Key::Delete,
+        c @ 1..=26 => Key::Ctrl((b'a' + c - 1) as char),
+        c => Key::Char(c as char),
+    }
+}
+
+pub fn last_input_tsc() -> u64 {
+    LAST_INPUT_TSC.load(Ordering::Relaxed)
+}
+
+/// Cursor-block keys sent as 0xE0-prefixed scancodes.
+fn extended_code(code: u8) -> Option<u8> {
+    Some(match code {
+        0x48 => CODE_UP,
+        0x50 => CODE_DOWN,
+        0x4B => CODE_LEFT,
+        0x4D => CODE_RIGHT,
+        0x49 => CODE_PAGE_UP,
+        0x51 => CODE_PAGE_DOWN,
+        0x47 => CODE_HOME,
+        0x4F => CODE_END,
+        0x53 => CODE_DELETE,
+        _ => return None,
+    })
 }
 
 // US QWERTY set-1 scancode to ASCII maps (partial but practical)
@@ -151,13 +430,26 @@ const MAP_SHIFT: [Option<char>; 0x3A] = {
 
 /// Handles a raw set-1 scancode; returns the combo description when a shutdown should be triggered.
 pub fn handle_scancode(scancode: u8) -> Option<&'static str> {
-    // Ignore extended prefix bytes for now.
     if scancode == 0xE0 {
+        EXTENDED.store(true, Ordering::Relaxed);
         return None;
     }
 
     let is_release = scancode & 0x80 != 0;
     let code = scancode & 0x7F;
+    if !is_release {
+        LAST_INPUT_TSC.store(crate::time::rdtsc(), Ordering::Relaxed);
+    }
+
+    if EXTENDED.swap(false, Ordering::Relaxed) {
+        if let Some(key) = extended_code(code) {
+            if !is_release {
+                kbuf_push(key);
+            }
+            return None;
+        }
+        // Right Ctrl and friends share their base scancode; fall through
+    }
 
     match code {
         // Left/Right Shift
@@ -169,6 +461,18 @@ pub fn handle_scancode(scancode: u8) -> Option<&'static str> {
             CTRL_HELD.store(!is_release, Ordering::Relaxed);
             None
         }
+        // Left Alt, or Right Alt behind the 0xE0 prefix
+        0x38 => {
+            ALT_HELD.store(!is_release, Ordering::Relaxed);
+            None
+        }
+        // Alt+F1..F3 pick a virtual console
+        0x3B..=0x3D => {
+            if !is_release && ALT_HELD.load(Ordering::Relaxed) {
+                crate::vconsole::request((code - 0x3B) as usize);
+            }
+            None
+        }
         0x2D => {
             if !is_release && CTRL_HELD.load(Ordering::Relaxed) {
                 Some("Ctrl+X")
@@ -183,10 +487,15 @@ pub fn handle_scancode(scancode: u8) -> Option<&'static str> {
                 None
             }
         }
+        0x01 => {
+            if !is_release {
+                kbuf_push(CODE_ESCAPE);
+            }
+            None
+        }
         0x0E => {
             // Backspace
             if !is_release {
-                vga::backspace();
                 kbuf_push(8); // ASCII backspace
             }
             None
@@ -194,7 +503,6 @@ pub fn handle_scancode(scancode: u8) -> Option<&'static str> {
         0x0F => {
             // Tab -> 4 spaces for simplicity
             if !is_release {
-                vga::write_str("    ");
                 kbuf_push(b' ');
                 kbuf_push(b' ');
                 kbuf_push(b' ');
@@ -205,7 +513,6 @@ pub fn handle_scancode(scancode: u8) -> Option<&'static str> {
         0x1C => {
             // Enter
             if !is_release {
-                vga::put_char('\n');
                 kbuf_push(b'\n');
             }
             None
@@ -218,9 +525,13 @@ pub fn handle_scancode(scancode: u8) -> Option<&'static str> {
                 } else {
                     MAP_NORMAL.get(code as usize).and_then(|c| *c)
                 };
-                if let Some(c) = ch {
-                    vga::put_char(c);
-                    kbuf_push(c as u8);
+                match ch {
+                    // Ctrl+letter as its ASCII control code
+                    Some(c) if c.is_ascii_alphabetic() && CTRL_HELD.load(Ordering::Relaxed) => {
+                        kbuf_push(c.to_ascii_lowercase() as u8 & 0x1F)
+                    }
+                    Some(c) => kbuf_push(c as u8),
+                    None => {}
                 }
             }
             None
@@ -228,6 +539,15 @@ pub fn handle_scancode(scancode: u8) -> Option<&'static str> {
     }
 }
 
+/// IRQ 1: one scancode from the controller.
+pub fn on_interrupt() -> bool {
+    let scancode: u8 = unsafe { x86_64::instructions::port::Port::new(0x60).read() };
+    if let Some(combo) = handle_scancode(scancode) {
+        shutdown_via_keyboard(combo);
+    }
+    true
+}
+
 pub fn shutdown_via_keyboard(combo: &str) -> ! {
     serial::write_fmt(format_args!("[KEYBOARD] {}\r\n", combo));
     crate::exit_qemu(0);
@@ -238,6 +558,15 @@ mod tests {
     use super::*;
     use core::sync::atomic::Ordering;
 
+    #[test]
+    fn nearest_rank_percentiles() {
+        let sorted: [u32; 20] = core::array::from_fn(|i| (i as u32 + 1) * 10);
+        assert_eq!(percentile(&sorted, 95), 190);
+        assert_eq!(percentile(&sorted, 50), 100);
+        assert_eq!(percentile(&sorted[..1], 95), 10);
+        assert_eq!(percentile(&[], 95), 0);
+    }
+
     #[test]
     fn ctrl_x_triggers_shutdown() {
         CTRL_HELD.store(false, Ordering::Relaxed);
@@ -258,6 +587,55 @@ mod tests {
         CTRL_HELD.store(false, Ordering::Relaxed);
     }
 
+    #[test]
+    fn extended_prefix_decodes_cursor_keys() {
+        assert_eq!(handle_scancode(0xE0), None);
+        assert_eq!(handle_scancode(0x48), None);
+        assert_eq!(handle_scancode(0xE0), None);
+        assert_eq!(handle_scancode(0xC8), None);
+        assert_eq!(poll_key(Focus::Shell), Some(Key::Up));
+        assert_eq!(poll_key(Focus::Shell), None);
+    }
+
+    #[test]
+    fn control_codes_decode_to_keys() {
+        assert_eq!(decode(b's' & 0x1F), Key::Ctrl('s'));
+        assert_eq!(decode(b'q' & 0x1F), Key::Ctrl('q'));
+        assert_eq!(decode(8), Key::Backspace);
+        assert_eq!(decode(b'\n'), Key::Enter);
+        assert_eq!(extended_code(0x53).map(decode), Some(Key::Delete));
+        assert_eq!(decode(b'S'), Key::Char('S'));
+    }
+
+    #[test]
+    fn focus_returns_to_the_previous_reader() {
+        let mut stack = FocusStack::new();
+        stack.push(Focus::Views);
+        stack.push(Focus::Lock);
+        assert_eq!(stack.top(), Focus::Lock);
+        // The views close while the screen is locked: the lock keeps the keys
+        stack.remove(Focus::Views);
+        assert_eq!(stack.top(), Focus::Lock);
+        stack.push(Focus::Viewer);
+        stack.push(Focus::Lock);
+        assert_eq!(stack.depth, 3);
+        stack.remove(Focus::Lock);
+        assert_eq!(stack.top(), Focus::Viewer);
+        stack.remove(Focus::Viewer);
+        stack.remove(Focus::Shell);
+        assert_eq!((stack.top(), stack.depth), (Focus::Shell, 1));
+    }
+
+    #[test]
+    fn alt_function_keys_request_a_console() {
+        handle_scancode(0x3C);
+        assert_eq!(crate::vconsole::take_request(), None);
+        handle_scancode(0x38);
+        handle_scancode(0x3C);
+        handle_scancode(0xB8);
+        assert_eq!(crate::vconsole::take_request(), Some(crate::vconsole::View::Log));
+    }
+
     #[test]
     fn release_clears_ctrl_state() {
         CTRL_HELD.store(false, Ordering::Relaxed);
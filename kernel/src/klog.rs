@@ -0,0 +1,94 @@
+//! Log verbosity. Messages at or below the current level go to the serial
+//! line; the level starts from `log.level` and can be changed at run time
+//! (by the agent through `SetLogLevel`, or the shell). The USB stack, driver
+//! probing and the agent log through `log`; boot progress and the crash
+//! path still write to the serial line directly.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{config, serial};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
+#[repr(u8)]
+pub enum Level {
+    Error = 0,
+    Warn = 1,
+    Info = 2,
+    Debug = 3,
+}
+
+impl Level {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        Some(match v {
+            0 => Level::Error,
+            1 => Level::Warn,
+            2 => Level::Info,
+            3 => Level::Debug,
+            _ => return None,
+        })
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+        }
+    }
+}
+
+static LEVEL: AtomicU8 = AtomicU8::new(Level::Info as u8);
+
+pub fn init() {
+    match config::with("log.level", Level::parse) {
+        Some(Some(level)) => set_level(level),
+        Some(None) => serial::write_str("[log] unknown log.level, keeping info\r\n"),
+        None => {}
+    }
+}
+
+pub fn level() -> Level {
+    Level::from_u8(LEVEL.load(Ordering::Relaxed)).unwrap_or(Level::Info)
+}
+
+pub fn set_level(level: Level) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: Level) -> bool {
+    level <= self::level()
+}
+
+/// Write `args` to the serial line if `level` is enabled.
+pub fn log(level: Level, args: fmt::Arguments) {
+    if enabled(level) {
+        serial::write_fmt(args);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_parse_and_order() {
+        for level in [Level::Error, Level::Warn, Level::Info, Level::Debug] {
+            assert_eq!(Level::parse(level.as_str()), Some(level));
+            assert_eq!(Level::from_u8(level as u8), Some(level));
+        }
+        assert_eq!(Level::parse("trace"), None);
+        assert!(Level::Warn < Level::Debug);
+    }
+}
@@ -0,0 +1,197 @@
+//! Kernel event trace: a fixed ring of binary events cheap enough to leave
+//! in IRQ handlers and the xHCI paths, where a serial line per event would
+//! change the timing being looked at.
+//!
+//! Tracing is off until `start`; a trace point then costs a TSC read and
+//! two atomic stores. The ring keeps the newest `RING_LEN` events and is
+//! lock-free, so trace points are safe in interrupt context; `stop` before
+//! reading it for a consistent dump.
+//!
+//! `trace dump <file>` writes the ring for host-side decoding
+//! (scripts/decode-ktrace.py), little-endian:
+//!
+//! ```text
+//! header  magic "KTR1", tsc_per_ms: u64, count: u32
+//! event   tsc: u64, kind: u16, a: u16, b: u32       (EVENT_LEN bytes)
+//! ```
+//!
+//! Events are oldest first; `tsc` is raw TSC cycles. `a` and `b` per kind:
+//! irq enter/exit: line, -; task switch: task slot, -; xhci submit: slot,
+//! doorbell target; xhci complete: completion code, 0 for a command and the
+//! endpoint id for a transfer; ai step start: -, -; ai step end: proposal
+//! outcome (0 accepted, 0xff when nothing was proposed), -; syscall enter
+//! (processes under `strace`): number, pid; syscall arg: argument index,
+//! plus `ARG_HIGH` for the upper half, which is only recorded when not
+//! zero, then that 32-bit half; syscall exit: number, return value cut to
+//! 32 bits (an errno stays negative).
+
+use core::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use crate::time;
+
+/// Events kept; a power of two so the index wraps with a mask.
+pub const RING_LEN: usize = 1024;
+pub const MAGIC: [u8; 4] = *b"KTR1";
+pub const HEADER_LEN: usize = 16;
+pub const EVENT_LEN: usize = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u16)]
+pub enum Kind {
+    IrqEnter = 1,
+    IrqExit = 2,
+    TaskSwitch = 3,
+    XhciSubmit = 4,
+    XhciComplete = 5,
+    AiStepStart = 6,
+    AiStepEnd = 7,
+    /// A physical allocation failed; b = KiB asked.
+    Oom = 8,
+    SyscallEnter = 9,
+    SyscallArg = 10,
+    SyscallExit = 11,
+}
+
+/// `a` flag of a `SyscallArg` event carrying the upper 32 bits.
+pub const ARG_HIGH: u16 = 0x100;
+
+impl Kind {
+    fn from_u16(v: u16) -> Option<Self> {
+        Some(match v {
+            1 => Kind::IrqEnter,
+            2 => Kind::IrqExit,
+            3 => Kind::TaskSwitch,
+            4 => Kind::XhciSubmit,
+            5 => Kind::XhciComplete,
+            6 => Kind::AiStepStart,
+            7 => Kind::AiStepEnd,
+            8 => Kind::Oom,
+            9 => Kind::SyscallEnter,
+            10 => Kind::SyscallArg,
+            11 => Kind::SyscallExit,
+            _ => return None,
+        })
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Kind::IrqEnter => "irq-enter",
+            Kind::IrqExit => "irq-exit",
+            Kind::TaskSwitch => "task",
+            Kind::XhciSubmit => "xhci-submit",
+            Kind::XhciComplete => "xhci-complete",
+            Kind::AiStepStart => "ai-start",
+            Kind::AiStepEnd => "ai-end",
+            Kind::Oom => "oom",
+            Kind::SyscallEnter => "sys-enter",
+            Kind::SyscallArg => "sys-arg",
+            Kind::SyscallExit => "sys-exit",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Event {
+    pub tsc: u64,
+    pub kind: Kind,
+    pub a: u16,
+    pub b: u32,
+}
+
+impl Event {
+    fn pack(kind: Kind, a: u16, b: u32) -> u64 {
+        kind as u64 | (a as u64) << 16 | (b as u64) << 32
+    }
+
+    fn unpack(tsc: u64, word: u64) -> Option<Self> {
+        Some(Event { tsc, kind: Kind::from_u16(word as u16)?, a: (word >> 16) as u16, b: (word >> 32) as u32 })
+    }
+
+    pub fn encode(&self) -> [u8; EVENT_LEN] {
+        let mut out = [0u8; EVENT_LEN];
+        out[..8].copy_from_slice(&self.tsc.to_le_bytes());
+        out[8..].copy_from_slice(&Self::pack(self.kind, self.a, self.b).to_le_bytes());
+        out
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// Events recorded since `start`; the next slot is this modulo `RING_LEN`.
+static NEXT: AtomicUsize = AtomicUsize::new(0);
+static TSC: [AtomicU64; RING_LEN] = [const { AtomicU64::new(0) }; RING_LEN];
+/// Kind and arguments, `Event::pack`ed; 0 marks a slot being written.
+static WORD: [AtomicU64; RING_LEN] = [const { AtomicU64::new(0) }; RING_LEN];
+
+/// Trace point: record an event if tracing is on.
+#[inline]
+pub fn event(kind: Kind, a: u16, b: u32) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let slot = NEXT.fetch_add(1, Ordering::Relaxed) & (RING_LEN - 1);
+    WORD[slot].store(0, Ordering::Relaxed);
+    TSC[slot].store(time::rdtsc(), Ordering::Relaxed);
+    WORD[slot].store(Event::pack(kind, a, b), Ordering::Release);
+}
+
+/// Clear the ring and start recording.
+pub fn start() {
+    ENABLED.store(false, Ordering::Release);
+    for word in WORD.iter() {
+        word.store(0, Ordering::Relaxed);
+    }
+    NEXT.store(0, Ordering::Relaxed);
+    ENABLED.store(true, Ordering::Release);
+}
+
+pub fn stop() {
+    ENABLED.store(false, Ordering::Release);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Events recorded since `start`, including those the ring dropped.
+pub fn recorded() -> usize {
+    NEXT.load(Ordering::Relaxed)
+}
+
+/// Events in the ring, oldest first. Slots caught mid-write are skipped.
+pub fn for_each(mut f: impl FnMut(&Event)) {
+    let next = NEXT.load(Ordering::Acquire);
+    for n in next.saturating_sub(RING_LEN)..next {
+        let slot = n & (RING_LEN - 1);
+        let word = WORD[slot].load(Ordering::Acquire);
+        if let Some(ev) = Event::unpack(TSC[slot].load(Ordering::Relaxed), word) {
+            f(&ev);
+        }
+    }
+}
+
+/// Dump header for `count` events.
+pub fn header(count: u32) -> [u8; HEADER_LEN] {
+    let mut out = [0u8; HEADER_LEN];
+    out[..4].copy_from_slice(&MAGIC);
+    out[4..12].copy_from_slice(&time::tsc_per_ms().to_le_bytes());
+    out[12..].copy_from_slice(&count.to_le_bytes());
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_pack_and_encode() {
+        let ev = Event { tsc: 0x1122_3344_5566_7788, kind: Kind::XhciSubmit, a: 3, b: 0xDEAD_BEEF };
+        let word = Event::pack(ev.kind, ev.a, ev.b);
+        assert_eq!(Event::unpack(ev.tsc, word), Some(ev));
+        assert_eq!(Event::unpack(1, 0), None);
+        let bytes = ev.encode();
+        assert_eq!(&bytes[..8], &0x1122_3344_5566_7788u64.to_le_bytes());
+        assert_eq!(&bytes[8..10], &4u16.to_le_bytes());
+        assert_eq!(&bytes[10..12], &3u16.to_le_bytes());
+        assert_eq!(&bytes[12..], &0xDEAD_BEEFu32.to_le_bytes());
+    }
+}
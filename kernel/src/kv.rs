@@ -0,0 +1,273 @@
+//! Small persistent key-value store.
+//!
+//! Backed for now by a RAM region reserved at the top of the pmm range,
+//! which survives a warm reset (QEMU `system_reset`, a triple fault) but not
+//! a power cycle. The region holds two banks; every update writes the full
+//! record list into the bank not in use with a higher generation and a
+//! checksum, so a write cut short leaves the previous bank valid. The bank
+//! logic works on plain byte slices, so a block-device backend only needs
+//! to read and write whole banks.
+
+use spin::Mutex;
+
+use crate::{addr, pmm, serial};
+use crate::status::{self, Health};
+
+pub const BANK_LEN: usize = 4096;
+const BANKS: usize = 2;
+const MAGIC: u32 = 0x4B56_5331; // "KVS1"
+const HEADER_LEN: usize = 16;
+pub const MAX_KEY: usize = 48;
+pub const MAX_VAL: usize = 512;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum KvError {
+    /// `init` has not run or found no memory.
+    NotReady,
+    KeyTooLong,
+    ValueTooLong,
+    Full,
+}
+
+impl KvError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KvError::NotReady => "store not ready",
+            KvError::KeyTooLong => "key too long",
+            KvError::ValueTooLong => "value too long",
+            KvError::Full => "store full",
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn write_u32(bytes: &mut [u8], at: usize, value: u32) {
+    bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn fnv1a(seed: u32, bytes: &[u8]) -> u32 {
+    bytes.iter().fold(seed, |h, &b| (h ^ b as u32).wrapping_mul(0x0100_0193))
+}
+
+/// FNV-1a over the generation, length and records.
+fn checksum(header: &[u8], records: &[u8]) -> u32 {
+    fnv1a(fnv1a(0x811C_9DC5, header), records)
+}
+
+/// Header: magic, generation, record bytes used, checksum of bytes 4..16+used.
+fn bank_generation(bank: &[u8]) -> Option<u32> {
+    if read_u32(bank, 0) != MAGIC {
+        return None;
+    }
+    let used = read_u32(bank, 8) as usize;
+    if used > bank.len() - HEADER_LEN {
+        return None;
+    }
+    let sum = checksum(&bank[4..12], &bank[HEADER_LEN..HEADER_LEN + used]);
+    (sum == read_u32(bank, 12)).then(|| read_u32(bank, 4))
+}
+
+fn body(bank: &[u8]) -> &[u8] {
+    &bank[HEADER_LEN..HEADER_LEN + read_u32(bank, 8) as usize]
+}
+
+/// Records are `[key_len u8][val_len u16 le][key][val]`, back to back.
+fn records(body: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut rest = body;
+    core::iter::from_fn(move || {
+        if rest.len() < 3 {
+            return None;
+        }
+        let klen = rest[0] as usize;
+        let vlen = u16::from_le_bytes([rest[1], rest[2]]) as usize;
+        let end = 3 + klen + vlen;
+        if end > rest.len() {
+            return None;
+        }
+        let rec = (&rest[3..3 + klen], &rest[3 + klen..end]);
+        rest = &rest[end..];
+        Some(rec)
+    })
+}
+
+/// Write `old`'s records into `dst` as generation `generation`, with `key`
+/// replaced by `val` (or dropped when `val` is `None`).
+fn rewrite(dst: &mut [u8], old: &[u8], generation: u32, key: &[u8], val: Option<&[u8]>) -> Result<(), KvError> {
+    let mut at = HEADER_LEN;
+    let mut push = |k: &[u8], v: &[u8], at: &mut usize| -> Result<(), KvError> {
+        let end = *at + 3 + k.len() + v.len();
+        if end > dst.len() {
+            return Err(KvError::Full);
+        }
+        dst[*at] = k.len() as u8;
+        dst[*at + 1..*at + 3].copy_from_slice(&(v.len() as u16).to_le_bytes());
+        dst[*at + 3..*at + 3 + k.len()].copy_from_slice(k);
+        dst[*at + 3 + k.len()..end].copy_from_slice(v);
+        *at = end;
+        Ok(())
+    };
+    for (k, v) in records(old).filter(|(k, _)| *k != key) {
+        push(k, v, &mut at)?;
+    }
+    if let Some(v) = val {
+        push(key, v, &mut at)?;
+    }
+    let used = at - HEADER_LEN;
+    write_u32(dst, 0, MAGIC);
+    write_u32(dst, 4, generation);
+    write_u32(dst, 8, used as u32);
+    let sum = checksum(&dst[4..12], &dst[HEADER_LEN..at]);
+    write_u32(dst, 12, sum);
+    Ok(())
+}
+
+struct Store {
+    /// Physical base of `BANKS` consecutive banks.
+    base: u64,
+    current: usize,
+    generation: u32,
+}
+
+static STORE: Mutex<Option<Store>> = Mutex::new(None);
+
+fn bank(base: u64, index: usize) -> &'static [u8] {
+    let ptr = addr::PhysAddr::new(base + (index * BANK_LEN) as u64).as_mut_ptr::<u8>();
+    unsafe { core::slice::from_raw_parts(ptr, BANK_LEN) }
+}
+
+/// Only called under the `STORE` lock, for the bank that is not current.
+fn bank_mut(base: u64, index: usize) -> &'static mut [u8] {
+    let ptr = addr::PhysAddr::new(base + (index * BANK_LEN) as u64).as_mut_ptr::<u8>();
+    unsafe { core::slice::from_raw_parts_mut(ptr, BANK_LEN) }
+}
+
+/// Reserve the region and pick the newest valid bank, formatting if none is.
+pub fn init() {
+    let Some(base) = pmm::reserve_top("kv", (BANKS * BANK_LEN) as u64) else {
+        serial::write_str("[kv] no memory for the store\r\n");
+        status::set("kv", Health::Failed, "no memory for the store");
+        return;
+    };
+    let newest = (0..BANKS)
+        .filter_map(|i| bank_generation(bank(base, i)).map(|g| (i, g)))
+        .max_by_key(|&(_, g)| g);
+    let store = match newest {
+        Some((current, generation)) => {
+            let count = records(body(bank(base, current))).count();
+            serial::write_fmt(format_args!("[kv] {} entries at {:#x} (generation {})\r\n", count, base, generation));
+            Store { base, current, generation }
+        }
+        None => {
+            let _ = rewrite(bank_mut(base, 0), &[], 1, &[], None);
+            serial::write_fmt(format_args!("[kv] formatted empty store at {:#x}\r\n", base));
+            Store { base, current: 0, generation: 1 }
+        }
+    };
+    *STORE.lock() = Some(store);
+}
+
+fn update(key: &str, val: Option<&[u8]>) -> Result<(), KvError> {
+    update_locked(&mut STORE.lock(), key, val)
+}
+
+fn update_locked(store: &mut Option<Store>, key: &str, val: Option<&[u8]>) -> Result<(), KvError> {
+    let store = store.as_mut().ok_or(KvError::NotReady)?;
+    let next = (store.current + 1) % BANKS;
+    let generation = store.generation.wrapping_add(1);
+    let old = body(bank(store.base, store.current));
+    rewrite(bank_mut(store.base, next), old, generation, key.as_bytes(), val)?;
+    store.current = next;
+    store.generation = generation;
+    Ok(())
+}
+
+fn check(key: &str, val: &[u8]) -> Result<(), KvError> {
+    if key.is_empty() || key.len() > MAX_KEY {
+        return Err(KvError::KeyTooLong);
+    }
+    if val.len() > MAX_VAL {
+        return Err(KvError::ValueTooLong);
+    }
+    Ok(())
+}
+
+pub fn set(key: &str, val: &[u8]) -> Result<(), KvError> {
+    check(key, val)?;
+    update(key, Some(val))
+}
+
+/// Remove `key`; returns whether it was present.
+pub fn remove(key: &str) -> Result<bool, KvError> {
+    let present = get(key, |_| ()).is_some();
+    if present {
+        update(key, None)?;
+    }
+    Ok(present)
+}
+
+/// Calls `f` with the value of `key` while the store is locked.
+pub fn get<R>(key: &str, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+    let guard = STORE.lock();
+    let store = guard.as_ref()?;
+    records(body(bank(store.base, store.current)))
+        .find(|(k, _)| *k == key.as_bytes())
+        .map(|(_, v)| f(v))
+}
+
+pub fn for_each(mut f: impl FnMut(&str, &[u8])) {
+    let guard = STORE.lock();
+    let Some(store) = guard.as_ref() else { return };
+    for (k, v) in records(body(bank(store.base, store.current))) {
+        if let Ok(k) = core::str::from_utf8(k) {
+            f(k, v);
+        }
+    }
+}
+
+/// (bytes used, bytes available) in the current bank.
+pub fn usage() -> Option<(usize, usize)> {
+    let guard = STORE.lock();
+    let store = guard.as_ref()?;
+    Some((read_u32(bank(store.base, store.current), 8) as usize, BANK_LEN - HEADER_LEN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rewrite_replaces_and_removes() {
+        let (mut a, mut b) = ([0u8; BANK_LEN], [0u8; BANK_LEN]);
+        rewrite(&mut a, &[], 1, b"x", Some(b"1")).unwrap();
+        rewrite(&mut b, body(&a), 2, b"y", Some(b"22")).unwrap();
+        rewrite(&mut a, body(&b), 3, b"x", Some(b"333")).unwrap();
+        let mut seen = [(&b""[..], &b""[..]); 2];
+        for (i, rec) in records(body(&a)).enumerate() {
+            seen[i] = rec;
+        }
+        assert_eq!(seen, [(&b"y"[..], &b"22"[..]), (&b"x"[..], &b"333"[..])]);
+        rewrite(&mut b, body(&a), 4, b"y", None).unwrap();
+        assert_eq!(records(body(&b)).count(), 1);
+        assert_eq!(bank_generation(&b), Some(4));
+    }
+
+    #[test]
+    fn torn_bank_is_rejected() {
+        let mut a = [0u8; BANK_LEN];
+        assert_eq!(bank_generation(&a), None);
+        rewrite(&mut a, &[], 7, b"key", Some(b"value")).unwrap();
+        assert_eq!(bank_generation(&a), Some(7));
+        a[HEADER_LEN + 4] ^= 0xFF;
+        assert_eq!(bank_generation(&a), None);
+    }
+
+    #[test]
+    fn full_bank_reports_full() {
+        let mut a = [0u8; BANK_LEN];
+        let big = [0u8; BANK_LEN];
+        assert_eq!(rewrite(&mut a, &[], 1, b"k", Some(&big)), Err(KvError::Full));
+    }
+}
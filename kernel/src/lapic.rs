@@ -0,0 +1,299 @@
+//! The local APIC: inter-processor interrupts and the tick.
+//!
+//! Device interrupts still come through the 8259 PICs (virtual wire mode,
+//! LINT0 as the firmware left it). The local APIC is enabled in x2APIC mode
+//! when the CPU has it, registers then being MSRs, and otherwise as an
+//! xAPIC with its registers mapped uncached in the MMIO window at the
+//! address IA32_APIC_BASE gives.
+//!
+//! `start_tick` moves the tick off the PIT: the APIC timer is calibrated
+//! against the TSC (itself calibrated against the PIT) and run periodic at
+//! `timer.hz`, its interrupt going through the same IRQ 0 handlers, and PIT
+//! line 0 is masked. `timer.source = pit`, no APIC or no TSC rate keep the
+//! PIT.
+
+use core::ptr::NonNull;
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use x86_64::registers::model_specific::Msr;
+
+use crate::mmio::MmioRegion;
+use crate::{config, pic, serial, time, vmm};
+
+const IA32_APIC_BASE: u32 = 0x1B;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_X2APIC: u64 = 1 << 10;
+const APIC_BASE_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+/// x2APIC MSR of the register at xAPIC offset 0: `X2APIC_MSR + offset / 16`.
+const X2APIC_MSR: u32 = 0x800;
+const REGS_LEN: usize = 0x400;
+
+const REG_ID: usize = 0x20;
+const REG_TPR: usize = 0x80;
+const REG_EOI: usize = 0xB0;
+const REG_SVR: usize = 0xF0;
+const REG_ICR_LOW: usize = 0x300;
+const REG_ICR_HIGH: usize = 0x310;
+const REG_LVT_TIMER: usize = 0x320;
+const REG_TIMER_INITIAL: usize = 0x380;
+const REG_TIMER_CURRENT: usize = 0x390;
+const REG_TIMER_DIVIDE: usize = 0x3E0;
+
+const SVR_ENABLE: u32 = 1 << 8;
+const ICR_PENDING: u32 = 1 << 12;
+const ICR_ASSERT: u32 = 1 << 14;
+const ICR_ALL_BUT_SELF: u32 = 0b11 << 18;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+/// Divide configuration for a timer clocked at bus / 16.
+const DIVIDE_BY_16: u32 = 0b0011;
+/// Polls of the delivery status before a send is given up on.
+const SEND_SPINS: u32 = 100_000;
+const CALIBRATE_MS: u64 = 10;
+const DEFAULT_HZ: u64 = 18;
+
+/// Vector of spurious interrupts: no handler work, no EOI.
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+/// Vector of the APIC timer once it drives the tick.
+pub const TIMER_VECTOR: u8 = 0xEF;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum Mode {
+    Off,
+    XApic,
+    X2Apic,
+}
+
+impl Mode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Mode::Off => "off",
+            Mode::XApic => "xapic",
+            Mode::X2Apic => "x2apic",
+        }
+    }
+}
+
+static MODE: AtomicU8 = AtomicU8::new(Mode::Off as u8);
+/// Virtual address of the xAPIC registers; 0 in x2APIC mode.
+static BASE: AtomicU64 = AtomicU64::new(0);
+/// APIC timer counts (at bus / 16) per millisecond; 0 until calibrated.
+static TIMER_PER_MS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Dest {
+    /// The CPU with this APIC id.
+    Cpu(u32),
+    AllButSelf,
+}
+
+/// ICR (high, low) words of a fixed-delivery IPI. The destination is the
+/// top byte of the high word for an xAPIC, the whole word for an x2APIC.
+pub fn icr(dest: Dest, vector: u8, x2apic: bool) -> (u32, u32) {
+    let low = vector as u32 | ICR_ASSERT;
+    match dest {
+        Dest::Cpu(id) if x2apic => (id, low),
+        Dest::Cpu(id) => (id << 24, low),
+        Dest::AllButSelf => (0, low | ICR_ALL_BUT_SELF),
+    }
+}
+
+/// Initial count for a periodic timer at `hz` given `per_ms` counts per
+/// millisecond, and the rate it gives in mHz.
+pub fn timer_count(per_ms: u64, hz: u64) -> Option<(u32, u64)> {
+    let count = per_ms.checked_mul(1000)?.checked_div(hz)?;
+    let count = u32::try_from(count).ok().filter(|&c| c > 0)?;
+    Some((count, per_ms * 1_000_000 / count as u64))
+}
+
+pub fn mode() -> Mode {
+    match MODE.load(Ordering::Acquire) {
+        1 => Mode::XApic,
+        2 => Mode::X2Apic,
+        _ => Mode::Off,
+    }
+}
+
+fn regs() -> Option<MmioRegion> {
+    let base = NonNull::new(BASE.load(Ordering::Acquire) as *mut u8)?;
+    Some(unsafe { MmioRegion::new(base, REGS_LEN) })
+}
+
+fn read(reg: usize) -> u32 {
+    match mode() {
+        Mode::X2Apic => unsafe { Msr::new(X2APIC_MSR + (reg >> 4) as u32).read() as u32 },
+        _ => regs().map_or(0, |r| r.read32(reg)),
+    }
+}
+
+fn write(reg: usize, value: u32) {
+    match mode() {
+        Mode::X2Apic => unsafe { Msr::new(X2APIC_MSR + (reg >> 4) as u32).write(value as u64) },
+        _ => {
+            if let Some(r) = regs() {
+                r.write32(reg, value);
+            }
+        }
+    }
+}
+
+/// CPUID.1: (local APIC, x2APIC).
+fn features() -> (bool, bool) {
+    let leaf = core::arch::x86_64::__cpuid(1);
+    (leaf.edx & (1 << 9) != 0, leaf.ecx & (1 << 21) != 0)
+}
+
+/// Enable the boot CPU's local APIC, in x2APIC mode if it has one.
+pub fn init() {
+    let (apic, x2apic) = features();
+    if !apic {
+        serial::write_str("[lapic] no local APIC\r\n");
+        return;
+    }
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let value = unsafe { msr.read() };
+    let phys = value & APIC_BASE_MASK;
+    if x2apic {
+        // xAPIC must be on before the switch to x2APIC
+        unsafe {
+            msr.write(value | APIC_BASE_ENABLE);
+            msr.write(value | APIC_BASE_ENABLE | APIC_BASE_X2APIC);
+        }
+        MODE.store(Mode::X2Apic as u8, Ordering::Release);
+    } else {
+        let virt = match vmm::map_mmio(phys, REGS_LEN as u64) {
+            Ok(virt) => virt,
+            Err(e) => {
+                serial::write_fmt(format_args!("[lapic] map {:#x}: {:?}\r\n", phys, e));
+                return;
+            }
+        };
+        if value & APIC_BASE_ENABLE == 0 {
+            unsafe { msr.write(value | APIC_BASE_ENABLE) };
+        }
+        BASE.store(virt, Ordering::Release);
+        MODE.store(Mode::XApic as u8, Ordering::Release);
+    }
+    write(REG_TPR, 0);
+    write(REG_SVR, SVR_ENABLE | SPURIOUS_VECTOR as u32);
+    percpu!(apic_id).store(id(), Ordering::Relaxed);
+    serial::write_fmt(format_args!("[lapic] cpu0 apic id {} ({})\r\n", id(), mode().as_str()));
+}
+
+pub fn ready() -> bool {
+    mode() != Mode::Off
+}
+
+/// This CPU's APIC id; 0 before `init`.
+pub fn id() -> u32 {
+    match mode() {
+        Mode::Off => 0,
+        Mode::XApic => read(REG_ID) >> 24,
+        Mode::X2Apic => read(REG_ID),
+    }
+}
+
+pub fn eoi() {
+    write(REG_EOI, 0);
+}
+
+/// Send `vector` to `dest`; false if the APIC is not up or the previous
+/// IPI never left.
+pub fn send_ipi(dest: Dest, vector: u8) -> bool {
+    match mode() {
+        Mode::Off => false,
+        Mode::X2Apic => {
+            // One 64-bit write; x2APIC has no delivery status to poll
+            let (high, low) = icr(dest, vector, true);
+            unsafe { Msr::new(X2APIC_MSR + (REG_ICR_LOW >> 4) as u32).write((high as u64) << 32 | low as u64) };
+            true
+        }
+        Mode::XApic => {
+            let idle = || (0..SEND_SPINS).any(|_| read(REG_ICR_LOW) & ICR_PENDING == 0);
+            if !idle() {
+                return false;
+            }
+            let (high, low) = icr(dest, vector, false);
+            write(REG_ICR_HIGH, high);
+            // Writing the low word sends it
+            write(REG_ICR_LOW, low);
+            idle()
+        }
+    }
+}
+
+/// Count the APIC timer against the TSC for `CALIBRATE_MS`.
+fn calibrate_timer() -> Option<u64> {
+    let tsc_per_ms = time::tsc_per_ms();
+    if tsc_per_ms == 0 {
+        return None;
+    }
+    write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_MASKED | TIMER_VECTOR as u32);
+    write(REG_TIMER_INITIAL, u32::MAX);
+    let start = time::rdtsc();
+    while time::rdtsc().wrapping_sub(start) < CALIBRATE_MS * tsc_per_ms {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - read(REG_TIMER_CURRENT);
+    write(REG_TIMER_INITIAL, 0);
+    Some(elapsed as u64 / CALIBRATE_MS).filter(|&per_ms| per_ms > 0)
+}
+
+/// Run the tick from the APIC timer at `timer.hz` instead of the PIT.
+pub fn start_tick() {
+    if config::with("timer.source", |s| s == "pit").unwrap_or(false) || !ready() {
+        return;
+    }
+    let Some(per_ms) = calibrate_timer() else {
+        serial::write_str("[lapic] timer not calibrated; tick stays on the PIT\r\n");
+        return;
+    };
+    TIMER_PER_MS.store(per_ms, Ordering::Relaxed);
+    let hz = config::get_u64("timer.hz").unwrap_or(DEFAULT_HZ);
+    let Some((count, millihz)) = timer_count(per_ms, hz) else {
+        serial::write_fmt(format_args!("[lapic] timer.hz = {} out of range; tick stays on the PIT\r\n", hz));
+        return;
+    };
+    write(REG_TIMER_DIVIDE, DIVIDE_BY_16);
+    write(REG_LVT_TIMER, LVT_PERIODIC | TIMER_VECTOR as u32);
+    write(REG_TIMER_INITIAL, count);
+    pic::mask_timer();
+    time::set_tick_millihz(millihz);
+    serial::write_fmt(format_args!(
+        "[lapic] tick on the APIC timer: {} counts/ms, {}.{:03} Hz\r\n",
+        per_ms,
+        millihz / 1000,
+        millihz % 1000
+    ));
+}
+
+/// APIC timer counts per millisecond, if it drives the tick.
+pub fn timer_per_ms() -> Option<u64> {
+    Some(TIMER_PER_MS.load(Ordering::Relaxed)).filter(|&n| n != 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn icr_encodes_destination_and_vector() {
+        assert_eq!(icr(Dest::Cpu(3), 0xF2, false), (3 << 24, 0xF2 | 1 << 14));
+        assert_eq!(icr(Dest::Cpu(300), 0xF2, true).0, 300);
+        let (high, low) = icr(Dest::AllButSelf, 0xF0, false);
+        assert_eq!(high, 0);
+        assert_eq!(low >> 18 & 0b11, 0b11);
+        assert_eq!(low & 0xFF, 0xF0);
+    }
+
+    #[test]
+    fn timer_count_for_the_configured_rate() {
+        // 62_500 counts/ms (1 GHz bus / 16) at 100 Hz
+        assert_eq!(timer_count(62_500, 100), Some((625_000, 100_000)));
+        assert_eq!(timer_count(62_500, 18), Some((3_472_222, 18_000)));
+        assert_eq!(timer_count(62_500, 0), None);
+        assert_eq!(timer_count(1, 10_000), None);
+        assert_eq!(timer_count(u64::MAX, 1), None);
+    }
+}
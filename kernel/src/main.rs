@@ -5,21 +5,92 @@
 #[cfg(all(test, not(target_os = "none")))]
 extern crate std;
 
+#[macro_use]
+mod kassert;
+#[macro_use]
+mod percpu;
+mod acpi;
+mod addr;
+mod base64;
+mod bigfont;
+mod bench;
+mod boost;
 mod bootinfo;
+mod bootreason;
+mod config;
+mod crash;
+mod dashboard;
+mod driver;
+mod editor;
+mod executor;
+mod fastmem;
+mod futex;
 mod gdt;
+mod hash;
 mod idt;
+mod init;
+mod ipi;
+mod irq;
+mod jobs;
+mod kaslr;
 mod keyboard;
+mod klog;
+mod ktrace;
+mod kv;
+mod lapic;
+#[cfg(feature = "debug_tools")]
+mod memdbg;
+mod memown;
+mod mmio;
+mod oom;
 mod pci;
 mod pic;
+mod pipe;
 mod pmm;
+mod power;
+mod process;
+mod procfs;
+mod pstore;
+mod rng;
+mod rtc;
+mod screenlock;
 mod serial;
+mod smbios;
+mod stack;
+mod status;
+mod stress;
+mod sync;
 mod syscall;
+mod telemetry;
+mod textutil;
+mod theme;
+mod time;
+mod usercopy;
+mod vconsole;
 mod vga;
+mod viewer;
+mod vmm;
 mod xhci;
-mod ai_action;
+#[cfg(any(test, feature = "xhci_debug"))]
+mod xhci_check;
+mod xhci_events;
+mod xhci_regs;
+mod xmodem;
+mod usb_class;
+mod usb_core;
+mod usb_hid;
+mod usb_known;
+mod usb_state;
 #[cfg(feature = "ai_agent")]
 mod ai_agent;
-mod ai_model;
+#[cfg(feature = "ai_agent")]
+mod ai_backend;
+#[cfg(feature = "ai_agent")]
+mod ai_canary;
+#[cfg(feature = "ai_agent")]
+mod ai_guard;
+#[cfg(feature = "ai_agent")]
+mod ai_heuristic;
 mod journal;
 mod apply_action;
 mod ai_link;
@@ -28,88 +99,121 @@ mod ai_initrd;
 #[cfg(feature = "ai_agent")]
 mod task;
 mod ramfs;
+mod remote;
 mod shell;
 
+// Host-testable parts that live in their own workspace crates, under the
+// names the rest of the kernel has always used
+use ai_core::action as ai_action;
+use ai_core::model as ai_model;
+#[cfg(feature = "ai_agent")]
+use ai_core::tree as ai_tree;
+use drivers_usb::desc as usb_desc;
+
 use bootinfo::BootInfo;
 use core::panic::PanicInfo;
 use x86_64::instructions::port::Port;
 use x86_64::instructions::{hlt, interrupts};
 
+/// Stage2 hands over on a small stack with nothing below it to catch an
+/// overflow; `_start` moves to this guarded one before anything else runs.
+const BOOT_STACK_SIZE: usize = 64 * 1024;
+static BOOT_STACK: stack::GuardedStack<BOOT_STACK_SIZE> = stack::GuardedStack::new();
+
+fn boot_stack() -> stack::StackBounds {
+    BOOT_STACK.bounds()
+}
+
 #[cfg(not(test))]
 #[no_mangle]
-pub extern "C" fn _start(boot_info_ptr: *const BootInfo) -> ! {
-    let boot_info = unsafe { boot_info_ptr.as_ref().expect("boot info pointer") };
+pub extern "C" fn _start(boot_info_ptr: *const u8) -> ! {
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            "call {entry}",
+            top = in(reg) boot_stack().top,
+            entry = sym boot_entry,
+            in("rdi") boot_info_ptr,
+            options(noreturn),
+        )
+    }
+}
+
+#[cfg(not(test))]
+extern "C" fn boot_entry(boot_info_ptr: *const u8) -> ! {
+    let boot_info = unsafe { BootInfo::init(boot_info_ptr) }.expect("boot info from the loader");
     kernel_main(boot_info)
 }
 
+/// Boot stages, in the order they were historically hand-written. `init::run`
+/// reorders them as needed to honour `deps`.
+static INITCALLS: &[init::Initcall] = &[
+    init::Initcall { name: "tsc", deps: &[], priority: 0, func: |_| time::calibrate() },
+    init::Initcall { name: "gdt", deps: &[], priority: 0, func: |_| gdt::init() },
+    init::Initcall { name: "serial", deps: &["gdt"], priority: 0, func: |_| serial::init() },
+    init::Initcall { name: "percpu", deps: &["gdt", "serial"], priority: 0, func: |_| percpu::init() },
+    init::Initcall { name: "initrd", deps: &[], priority: 0, func: init_initrd },
+    init::Initcall { name: "kv", deps: &["pmm", "serial"], priority: 0, func: |_| kv::init() },
+    // After kv, so the region sits right below the store at a fixed address
+    init::Initcall { name: "pstore", deps: &["pmm", "serial", "kv"], priority: 0, func: |_| pstore::init() },
+    init::Initcall { name: "config", deps: &["serial", "initrd", "kv"], priority: 0, func: |boot| config::init(boot.cmdline()) },
+    init::Initcall { name: "klog", deps: &["config"], priority: 0, func: |_| klog::init() },
+    init::Initcall { name: "panic", deps: &["config", "pstore"], priority: 0, func: |_| crash::init() },
+    init::Initcall { name: "bootreason", deps: &["serial", "config", "pstore"], priority: 0, func: |_| bootreason::init() },
+    init::Initcall { name: "journal", deps: &["pstore"], priority: 0, func: |_| journal::init() },
+    init::Initcall { name: "agent", deps: &["config", "bootreason", "journal"], priority: 0, func: init_agent },
+    init::Initcall { name: "idt", deps: &["gdt"], priority: 10, func: |_| idt::init() },
+    init::Initcall { name: "syscall", deps: &["idt"], priority: 10, func: |_| syscall::init() },
+    init::Initcall { name: "theme", deps: &["config"], priority: 0, func: |_| theme::init() },
+    init::Initcall { name: "vga", deps: &["serial", "theme"], priority: 10, func: init_banner },
+    init::Initcall { name: "pic", deps: &["idt", "config"], priority: 10, func: |_| pic::init() },
+    init::Initcall { name: "rtc", deps: &["pic", "tsc"], priority: 10, func: |_| rtc::init() },
+    init::Initcall { name: "pmm", deps: &[], priority: 20, func: |b| pmm::init(b) },
+    init::Initcall { name: "vmm", deps: &["pmm"], priority: 20, func: |_| vmm::init() },
+    init::Initcall { name: "kaslr", deps: &["vmm", "tsc"], priority: 20, func: |_| kaslr::init() },
+    init::Initcall { name: "lapic", deps: &["kaslr", "percpu", "idt"], priority: 20, func: |_| lapic::init() },
+    init::Initcall { name: "apic-timer", deps: &["lapic", "pic", "tsc", "config"], priority: 20, func: |_| lapic::start_tick() },
+    init::Initcall { name: "stack-guard", deps: &["kaslr", "gdt"], priority: 20, func: init_stack_guard },
+    init::Initcall { name: "memmap", deps: &["serial"], priority: 20, func: log_memory_map },
+    init::Initcall { name: "acpi", deps: &["pmm", "serial"], priority: 20, func: acpi::init },
+    init::Initcall { name: "smbios", deps: &["serial"], priority: 20, func: |_| smbios::init() },
+    init::Initcall { name: "pci", deps: &["serial"], priority: 30, func: |_| log_usb_controllers() },
+    init::Initcall { name: "usb-class", deps: &[], priority: 30, func: |_| usb_hid::register() },
+    init::Initcall { name: "pci-drivers", deps: &[], priority: 30, func: |_| usb_core::register() },
+    init::Initcall { name: "probe", deps: &["pci", "pmm", "kaslr", "pic", "usb-class", "pci-drivers"], priority: 30, func: init_probe },
+];
+// A stage past the cap would be dropped at boot, and every stage after it in
+// dependency order with it: raise `MAX_INITCALLS` instead.
+const _: () = assert!(INITCALLS.len() <= init::MAX_INITCALLS);
+
 #[no_mangle]
 pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
+    time::mark_boot();
     debug_out("kmain: entry\n");
 
-    gdt::init();
-    debug_out("kmain: gdt\n");
-
-    serial::init();
-    debug_out("kmain: serial\n");
-
-    // Early IA agent scheduling (before IDT/PIC): best-effort steps
-    #[cfg(feature = "ai_agent")]
-    {
-        extern "C" {
-            static mut AI_MODEL_ADDR: *const u8;
-            static mut INITRD_BASE: *const u8;
-            static mut INITRD_LEN: usize;
-        }
-        unsafe {
-            // Propagate initrd from BootInfo and try locating the model early
-            INITRD_BASE = boot_info.initrd_base() as *const u8;
-            INITRD_LEN = boot_info.initrd_len() as usize;
-            if !INITRD_BASE.is_null() && INITRD_LEN > 0 {
-                ai_initrd::try_set_model_from_initrd();
-            }
-            if !AI_MODEL_ADDR.is_null() {
-                serial::write_str("[ai] early scheduling agent task\r\n");
-                let _ = task::register(|| ai_agent::step());
-                // Give it a first step opportunity
-                task::run_once();
-            } else {
-                serial::write_str("[ai] model addr not set; agent inactive\r\n");
+    init::run(INITCALLS, boot_info, between_stages);
+    init::log_report();
+    if status::degraded() {
+        serial::write_str("[init] running degraded:");
+        status::for_each(|e| {
+            if e.health != status::Health::Ok {
+                serial::write_fmt(format_args!(" {} ({})", e.name, e.health.as_str()));
             }
-        }
+        });
+        serial::write_str("\r\n");
     }
 
-    idt::init();
-    debug_out("kmain: idt\n");
-    #[cfg(feature = "ai_agent")]
-    { task::run_once(); }
-
-    syscall::init();
-    debug_out("kmain: syscall\n");
-    #[cfg(feature = "ai_agent")]
-    { task::run_once(); }
-
-    serial::write_str("Hello Kernel\r\n");
-    debug_out("kmain: wrote serial\n");
-
-    vga::init();
-    vga::set_style(0x1f); // white on blue for headline
-    vga::write_line("Hello Kernel");
-    vga::set_style(0x0f);
-    debug_out("kmain: wrote vga\n");
-
-    pic::init();
-    debug_out("kmain: pic\n");
-    #[cfg(feature = "ai_agent")]
-    { task::run_once(); }
-
-    pmm::init(boot_info);
-    log_memory_map(boot_info);
-    log_usb_controllers();
-    #[cfg(feature = "ai_agent")]
-    { task::run_once(); }
-
     interrupts::enable();
     debug_out("kmain: interrupts on\n");
+
+    let report = journal::verify();
+    if report.dangling > 0 {
+        serial::write_fmt(format_args!(
+            "[journal] {} dangling intent(s), first seq={}\r\n",
+            report.dangling,
+            report.first_dangling.unwrap_or(0)
+        ));
+    }
     #[cfg(feature = "ai_agent")]
     {
         // Now the system is considered stable for transactional actions
@@ -124,17 +228,39 @@ pub extern "C" fn kernel_main(boot_info: &BootInfo) -> ! {
     trigger_breakpoint();
 
     #[cfg(feature = "qemu_exit")]
-    exit_qemu(0);
+    {
+        // Let boot-time tasks (USB enumeration) finish before the exit
+        let deadline = executor::deadline_after_ms(2000);
+        while executor::has_tasks() && !executor::expired(deadline) {
+            xhci::poll_events();
+            executor::run_ready();
+        }
+        exit_qemu(0);
+    }
 
     #[cfg(not(feature = "qemu_exit"))]
+    let usb = status::up("usb");
     loop {
-        xhci::poll_events();
+        if usb {
+            xhci::poll_events();
+            usb_core::recover_if_needed();
+        }
+        // A boosted shell reads pending keys before the round robin gets the CPU
         #[cfg(feature = "ai_agent")]
-        {
+        if !(boost::active(boost::SHELL) && keyboard::has_input()) {
             task::run_once();
         }
-        shell::step();
-        hlt();
+        executor::run_ready();
+        rtc::check_drift();
+        jobs::run_due();
+        if screenlock::step() && vconsole::step() {
+            shell::step();
+        }
+        stack::check_all();
+        // A task woken during this pass gets polled again without waiting for an IRQ
+        if !executor::has_ready() && !stress::cpu_active() {
+            hlt();
+        }
     }
 }
 
@@ -143,8 +269,94 @@ fn trigger_breakpoint() {
     interrupts::int3();
 }
 
+fn between_stages() {
+    #[cfg(feature = "ai_agent")]
+    task::run_once();
+}
+
+/// Propagate initrd from BootInfo: ramfs, config and the agent all read it.
+fn init_initrd(boot_info: &BootInfo) {
+    ai_link::set_initrd(boot_info.initrd_base() as *const u8, boot_info.initrd_len() as usize);
+}
+
+/// Probe every registered driver, and record USB as skipped when no
+/// controller answered.
+fn init_probe(_: &BootInfo) {
+    driver::probe_all();
+    // USB is the one subsystem the rest of the kernel leans on; say so when it is absent
+    if status::get("usb").is_none() {
+        status::set("usb", status::Health::Skipped, "no xHCI controller");
+    }
+}
+
+/// Early IA agent scheduling (before IDT/PIC): best-effort steps.
+fn init_agent(_: &BootInfo) {
+    #[cfg(not(feature = "ai_agent"))]
+    status::set("ai", status::Health::Skipped, "not built");
+    #[cfg(feature = "ai_agent")]
+    {
+        let mode = ai_agent::mode();
+        ai_canary::init();
+        // Try locating the model early, unless the agent is off
+        let (initrd, initrd_len) = ai_link::initrd();
+        if mode != ai_agent::Mode::Off && !initrd.is_null() && initrd_len > 0 {
+            ai_initrd::try_set_model_from_initrd();
+        }
+        if bootreason::crash_loop() {
+            serial::write_str("[ai] recent boots crashed; agent not scheduled\r\n");
+            status::set("ai", status::Health::Skipped, "recent boots crashed");
+        } else {
+            if mode != ai_agent::Mode::Off && ai_link::model().0.is_null() {
+                serial::write_str("[ai] model addr not set; heuristic controller only\r\n");
+            }
+            serial::write_fmt(format_args!("[ai] early scheduling agent task (mode={})\r\n", mode.as_str()));
+            if let Some(pct) = config::get_u64("ai.cpu_pct") {
+                apply_action::set_ai_cpu_pct(pct.min(100) as u32);
+            }
+            // Long inference steps must not hold up USB and keyboard polling
+            match task::register_background("ai", || ai_agent::step(), apply_action::get_ai_cpu_pct) {
+                Some(_) => {
+                    ai_agent::scheduled();
+                    power::register_hook("ai", ai_agent::park);
+                }
+                None => status::set("ai", status::Health::Failed, "task table full"),
+            }
+            // Its own task: the agent's checks are not the only ones
+            if task::register("ai-guard", ai_guard::run).is_none() {
+                status::set("ai-guard", status::Health::Failed, "task table full");
+            }
+        }
+    }
+}
+
+fn init_stack_guard(_: &BootInfo) {
+    let [df, pf, kernel] = gdt::stacks();
+    stack::init(&[("boot", boot_stack()), df, pf, kernel]);
+}
+
+fn init_banner(_: &BootInfo) {
+    serial::write_str("Hello Kernel\r\n");
+    vga::init();
+    vga::banner("Hello Kernel", vga::BannerStyle::Headline);
+}
+
 fn log_memory_map(boot_info: &BootInfo) {
-    debug_out("kmain: memmap\n");
+    serial::write_fmt(format_args!("[boot] BootInfo v{} ({} bytes)\r\n", boot_info.version, boot_info.size));
+    if boot_info.version > bootinfo::VERSION {
+        serial::write_fmt(format_args!("[boot] loader is newer than v{}, extra fields ignored\r\n", bootinfo::VERSION));
+    }
+    if let Some(rsdp) = boot_info.rsdp {
+        serial::write_fmt(format_args!("[boot] ACPI RSDP at {:#x}\r\n", rsdp));
+    }
+    if let Some(fb) = boot_info.framebuffer {
+        serial::write_fmt(format_args!(
+            "[boot] framebuffer {}x{}x{} at {:#x}, pitch {}\r\n",
+            fb.width, fb.height, fb.bpp, fb.addr, fb.pitch
+        ));
+    }
+    if let Some(cmdline) = boot_info.cmdline() {
+        serial::write_fmt(format_args!("[boot] cmdline: {}\r\n", cmdline));
+    }
 
     let mut regions = 0u64;
     let mut usable_bytes = 0u64;
@@ -192,108 +404,6 @@ pub fn log_usb_controllers() {
             "[pci] usb {} vendor={:04x} device={:04x} class={:02x} sub={:02x} if={:02x}\r\n",
             addr, vendor, device, class, subclass, prog_if
         ));
-
-        if prog_if == 0x30 {
-            match pci::bar(addr, 0) {
-                Some(bar) if bar.is_memory => unsafe {
-                    match xhci::inspect(bar.base) {
-                        Some(info) => {
-                            serial::write_fmt(format_args!(
-                                "[xhci] base={:#016x} caplen={} version={:04x} slots={} ports={} ctx={} dboff={:#x} rtsoff={:#x}\r\n",
-                                info.base,
-                                info.cap_length,
-                                info.hci_version,
-                                info.max_slots(),
-                                info.max_ports(),
-                                info.context_size(),
-                                info.dboff,
-                                info.rtsoff,
-                            ));
-                            match xhci::init_controller(info) {
-                                Ok(()) => {
-                                    serial::write_str("[xhci] controller initialized\r\n");
-                                    xhci::report_ports();
-                                    let _ = xhci::poll_events();
-                                    if !xhci::ensure_first_port_enabled() {
-                                        serial::write_str("[xhci] no enabled port\r\n");
-                                    }
-                                    if let Some(slot) = xhci::enable_slot() {
-                                        serial::write_fmt(format_args!(
-                                            "[xhci] slot {} enabled\r\n",
-                                            slot
-                                        ));
-                                        if xhci::address_device(slot) {
-                                            serial::write_str("[xhci] device addressed\r\n");
-                                            if let Some(dev_desc_phys) = xhci::get_device_descriptor(slot) {
-                                                serial::write_fmt(format_args!(
-                                                    "[xhci] device descriptor at {:#x}\r\n",
-                                                    dev_desc_phys
-                                                ));
-                                                if let Some((hdr_phys, total_len, cfg_val)) = xhci::get_configuration_descriptor_header(slot) {
-                                                    serial::write_fmt(format_args!(
-                                                        "[xhci] config header at {:#x} total_len={} cfg={}\r\n",
-                                                        hdr_phys, total_len, cfg_val
-                                                    ));
-                                                    if let Some(cfg_phys) = xhci::get_configuration_descriptor(slot, total_len) {
-                                                        serial::write_fmt(format_args!(
-                                                            "[xhci] config descriptor at {:#x}\r\n",
-                                                            cfg_phys
-                                                        ));
-                                                        if xhci::set_configuration(slot, cfg_val) {
-                                                            serial::write_str("[xhci] configuration set\r\n");
-                                                            if let Some((ep_addr, maxp, interval)) = xhci::parse_hid_keyboard_endpoint(cfg_phys, total_len) {
-                                                                serial::write_fmt(format_args!(
-                                                                    "[hid] keyboard ep={:#x} maxp={} interval={}\r\n",
-                                                                    ep_addr, maxp, interval
-                                                                ));
-                                                                if xhci::configure_interrupt_in_endpoint(slot, ep_addr, maxp, interval) {
-                                                                    serial::write_str("[hid] interrupt endpoint configured\r\n");
-                                                                    if xhci::start_hid_polling(slot, ep_addr, maxp) {
-                                                                        serial::write_str("[hid] polling started\r\n");
-                                                                    } else {
-                                                                        serial::write_str("[hid] failed to start polling\r\n");
-                                                                    }
-                                                                } else {
-                                                                    serial::write_str("[hid] configure endpoint failed\r\n");
-                                                                }
-                                                            } else {
-                                                                serial::write_str("[hid] no keyboard endpoint found\r\n");
-                                                            }
-                                                        } else {
-                                                            serial::write_str("[xhci] set configuration failed\r\n");
-                                                        }
-                                                    } else {
-                                                        serial::write_str("[xhci] failed to read full config descriptor\r\n");
-                                                    }
-                                                } else {
-                                                    serial::write_str("[xhci] failed to read config header\r\n");
-                                                }
-                                            } else {
-                                                serial::write_str("[xhci] failed to read device descriptor\r\n");
-                                            }
-                                        } else {
-                                            serial::write_str("[xhci] address device failed\r\n");
-                                        }
-                                    } else {
-                                        serial::write_str("[xhci] enable slot failed\r\n");
-                                    }
-                                    xhci::poll_events();
-                                }
-                                Err(err) => serial::write_fmt(format_args!(
-                                    "[xhci] init failed: {}\r\n",
-                                    err
-                                )),
-                            }
-                        }
-                        None => {
-                            serial::write_str("[xhci] failed to read capability registers\r\n");
-                        }
-                    }
-                },
-                Some(_) => serial::write_str("[xhci] bar0 is not memory-mapped\r\n"),
-                None => serial::write_str("[xhci] missing bar0\r\n"),
-            }
-        }
     });
 
     if found == 0 {
@@ -302,7 +412,7 @@ pub fn log_usb_controllers() {
     debug_out("kmain: pci scan done\n");
 }
 
-fn debug_out(msg: &str) {
+pub(crate) fn debug_out(msg: &str) {
     unsafe {
         let mut port = Port::new(0xE9);
         for byte in msg.bytes() {
@@ -312,6 +422,7 @@ fn debug_out(msg: &str) {
 }
 
 pub fn exit_qemu(code: u32) -> ! {
+    bootreason::record(bootreason::BootReason::Clean);
     unsafe {
         let mut port = Port::<u32>::new(0xF4);
         port.write((code << 1) | 1);
@@ -324,9 +435,8 @@ pub fn exit_qemu(code: u32) -> ! {
 
 #[cfg_attr(any(not(test), target_os = "none"), panic_handler)]
 fn panic(info: &PanicInfo) -> ! {
+    bootreason::record(bootreason::BootReason::Panic);
     serial::panic(info);
     vga::panic(info);
-    loop {
-        hlt();
-    }
+    crash::after_panic(info)
 }
@@ -0,0 +1,133 @@
+//! Raw physical memory and MMIO access behind the `peek`/`poke` shell
+//! commands. Only built with the `debug_tools` feature, and even then every
+//! access is refused until `debug unlock` has been typed at the shell.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use crate::{addr, vmm};
+
+/// Largest span a single `peek` or `poke` may touch.
+pub const MAX_LEN: usize = 4096;
+
+static UNLOCKED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AccessError {
+    Locked,
+    TooLong,
+    /// Outside the kernel mapping, or a page that is not present (e.g. a stack guard).
+    NotMapped(u64),
+}
+
+impl AccessError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AccessError::Locked => "locked (run `debug unlock` first)",
+            AccessError::TooLong => "too long",
+            AccessError::NotMapped(_) => "not mapped",
+        }
+    }
+}
+
+pub fn set_unlocked(unlocked: bool) {
+    UNLOCKED.store(unlocked, Ordering::Relaxed);
+}
+
+pub fn is_unlocked() -> bool {
+    UNLOCKED.load(Ordering::Relaxed)
+}
+
+/// Bus width used for a span: a 1/2/4/8-byte span at a matching alignment is
+/// one access of exactly that size (so a register read is a register read);
+/// longer spans go dword by dword when aligned, byte by byte otherwise.
+pub fn access_width(phys: u64, len: usize) -> usize {
+    if matches!(len, 1 | 2 | 4 | 8) && phys.is_multiple_of(len as u64) {
+        return len;
+    }
+    if phys.is_multiple_of(4) && len.is_multiple_of(4) {
+        4
+    } else {
+        1
+    }
+}
+
+fn check(phys: u64, len: usize) -> Result<*mut u8, AccessError> {
+    if !is_unlocked() {
+        return Err(AccessError::Locked);
+    }
+    if len > MAX_LEN {
+        return Err(AccessError::TooLong);
+    }
+    let end = phys.checked_add(len as u64).ok_or(AccessError::NotMapped(phys))?;
+    let virt = addr::PhysAddr::new(phys).to_virt().ok_or(AccessError::NotMapped(phys))?;
+    let mut page = phys & !(vmm::PAGE_SIZE - 1);
+    while page < end {
+        if vmm::translate_active(page).is_none() {
+            return Err(AccessError::NotMapped(page.max(phys)));
+        }
+        page += vmm::PAGE_SIZE;
+    }
+    Ok(virt.as_u64() as *mut u8)
+}
+
+/// Read `buf.len()` bytes at physical address `phys` with volatile loads.
+pub fn peek(phys: u64, buf: &mut [u8]) -> Result<(), AccessError> {
+    let ptr = check(phys, buf.len())?;
+    let width = access_width(phys, buf.len());
+    for (i, chunk) in buf.chunks_mut(width).enumerate() {
+        let p = unsafe { ptr.add(i * width) };
+        unsafe {
+            match width {
+                8 => chunk.copy_from_slice(&(p as *const u64).read_volatile().to_le_bytes()),
+                4 => chunk.copy_from_slice(&(p as *const u32).read_volatile().to_le_bytes()),
+                2 => chunk.copy_from_slice(&(p as *const u16).read_volatile().to_le_bytes()),
+                _ => chunk[0] = p.read_volatile(),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Write `bytes` at physical address `phys` with volatile stores.
+pub fn poke(phys: u64, bytes: &[u8]) -> Result<(), AccessError> {
+    let ptr = check(phys, bytes.len())?;
+    let width = access_width(phys, bytes.len());
+    for (i, chunk) in bytes.chunks(width).enumerate() {
+        let p = unsafe { ptr.add(i * width) };
+        unsafe {
+            match width {
+                8 => (p as *mut u64).write_volatile(u64::from_le_bytes(chunk.try_into().unwrap_or_default())),
+                4 => (p as *mut u32).write_volatile(u32::from_le_bytes(chunk.try_into().unwrap_or_default())),
+                2 => (p as *mut u16).write_volatile(u16::from_le_bytes(chunk.try_into().unwrap_or_default())),
+                _ => p.write_volatile(chunk[0]),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn register_sized_spans_are_single_accesses() {
+        assert_eq!(access_width(0x1000, 4), 4);
+        assert_eq!(access_width(0x1008, 8), 8);
+        assert_eq!(access_width(0x1002, 2), 2);
+        assert_eq!(access_width(0x1002, 4), 1);
+    }
+
+    #[test]
+    fn long_spans_use_dwords_when_aligned() {
+        assert_eq!(access_width(0x1000, 64), 4);
+        assert_eq!(access_width(0x1000, 6), 1);
+        assert_eq!(access_width(0x1001, 64), 1);
+    }
+
+    #[test]
+    fn locked_by_default() {
+        let mut buf = [0u8; 4];
+        assert_eq!(peek(0x1000, &mut buf), Err(AccessError::Locked));
+    }
+}
@@ -0,0 +1,219 @@
+//! Who owns each range of physical memory the pmm has handed out.
+//!
+//! Every allocation names its owner (`xhci.cmd_ring`, `ai.model`,
+//! `ramfs.file`, ...) and every free gives the range back. With the whole
+//! of RAM identity-mapped, a buffer used after its free or handed out twice
+//! is otherwise invisible until something else is corrupted; here an
+//! allocation overlapping an owned range, or a free of memory nobody owns,
+//! trips a `kassert!` (a panic in debug builds, an error line in release)
+//! at the call that did it. `memmap owners` lists the map.
+//!
+//! Only kept with the `debug_tools` feature. Adjacent ranges of the same
+//! owner merge, so a run of `alloc_page`s takes one slot; when the table is
+//! full, ranges go untracked and frees inside them are no longer checked.
+
+use spin::Mutex;
+
+use crate::serial;
+
+pub const ENABLED: bool = cfg!(feature = "debug_tools");
+pub const MAX_RANGES: usize = 256;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Range {
+    pub base: u64,
+    pub end: u64,
+    pub owner: &'static str,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OwnError {
+    /// The range overlaps one that is still owned.
+    Overlap(Range),
+    /// Nothing owns (all of) the range given back.
+    Unowned,
+    /// No slot left to record the range.
+    Full,
+}
+
+impl OwnError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            OwnError::Overlap(_) => "overlaps an owned range",
+            OwnError::Unowned => "range not owned",
+            OwnError::Full => "ownership map full",
+        }
+    }
+}
+
+pub struct Map {
+    ranges: [Option<Range>; MAX_RANGES],
+    /// A range could not be recorded: some memory is owned but untracked.
+    overflowed: bool,
+}
+
+impl Map {
+    pub const fn new() -> Self {
+        Map { ranges: [None; MAX_RANGES], overflowed: false }
+    }
+
+    fn insert(&mut self, range: Range) -> Result<(), OwnError> {
+        match self.ranges.iter_mut().find(|r| r.is_none()) {
+            Some(slot) => {
+                *slot = Some(range);
+                Ok(())
+            }
+            None => {
+                self.overflowed = true;
+                Err(OwnError::Full)
+            }
+        }
+    }
+
+    /// Record `[base, end)` as `owner`'s.
+    pub fn claim(&mut self, base: u64, end: u64, owner: &'static str) -> Result<(), OwnError> {
+        if let Some(hit) = self.ranges.iter().flatten().find(|r| r.base < end && base < r.end) {
+            return Err(OwnError::Overlap(*hit));
+        }
+        let mut merged = Range { base, end, owner };
+        // Fold in the same owner's neighbours on either side
+        for slot in self.ranges.iter_mut() {
+            if let Some(r) = *slot {
+                if r.owner == owner && (r.end == merged.base || r.base == merged.end) {
+                    merged = Range { base: r.base.min(merged.base), end: r.end.max(merged.end), owner };
+                    *slot = None;
+                }
+            }
+        }
+        self.insert(merged)
+    }
+
+    /// Give back `[base, end)`, which must lie inside one owned range;
+    /// returns its owner.
+    pub fn release(&mut self, base: u64, end: u64) -> Result<&'static str, OwnError> {
+        let Some(slot) = self.ranges.iter_mut().find(|r| r.is_some_and(|r| r.base <= base && end <= r.end)) else {
+            return Err(OwnError::Unowned);
+        };
+        let Some(r) = slot.take() else { return Err(OwnError::Unowned) };
+        if r.base < base {
+            *slot = Some(Range { end: base, ..r });
+        }
+        if end < r.end {
+            let rest = Range { base: end, ..r };
+            match slot {
+                None => *slot = Some(rest),
+                Some(_) => self.insert(rest)?,
+            }
+        }
+        Ok(r.owner)
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.overflowed
+    }
+
+    /// The owned range with the lowest base above `after` (any, if `None`).
+    pub fn next(&self, after: Option<u64>) -> Option<Range> {
+        self.ranges.iter().flatten().filter(|r| after.is_none_or(|a| r.base > a)).min_by_key(|r| r.base).copied()
+    }
+}
+
+static MAP: Mutex<Map> = Mutex::new(Map::new());
+
+/// Record an allocation of `len` bytes at `base` for `owner`.
+pub fn claim(base: u64, len: u64, owner: &'static str) -> Result<(), OwnError> {
+    if !ENABLED {
+        return Ok(());
+    }
+    let result = MAP.lock().claim(base, base + len, owner);
+    match result {
+        Err(OwnError::Overlap(hit)) => serial::write_fmt(format_args!(
+            "[memown] {} at {:#x}+{:#x} overlaps {} at {:#x}-{:#x}\r\n",
+            owner, base, len, hit.owner, hit.base, hit.end
+        )),
+        Err(e) => serial::write_fmt(format_args!("[memown] {} at {:#x}+{:#x} untracked: {}\r\n", owner, base, len, e.as_str())),
+        _ => {}
+    }
+    kassert!(!matches!(result, Err(OwnError::Overlap(_))), result.unwrap_err());
+    Ok(())
+}
+
+/// Record that `len` bytes at `base` were given back to the allocator.
+pub fn release(base: u64, len: u64) -> Result<(), OwnError> {
+    if !ENABLED {
+        return Ok(());
+    }
+    let mut map = MAP.lock();
+    let result = map.release(base, base + len);
+    let unowned = result == Err(OwnError::Unowned) && !map.overflowed();
+    drop(map);
+    if unowned {
+        serial::write_fmt(format_args!("[memown] free of {:#x}+{:#x}: {}\r\n", base, len, OwnError::Unowned.as_str()));
+    }
+    kassert!(!unowned, OwnError::Unowned);
+    Ok(())
+}
+
+/// Owned ranges in address order. The map is locked for one range at a
+/// time, so `f` may allocate (or wait on the pager).
+pub fn for_each(mut f: impl FnMut(&Range)) {
+    let mut after = None;
+    while let Some(range) = MAP.lock().next(after) {
+        f(&range);
+        after = Some(range.base);
+    }
+}
+
+/// Whether some allocations went untracked.
+pub fn overflowed() -> bool {
+    MAP.lock().overflowed()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::vec::Vec;
+
+    fn ranges(map: &Map) -> Vec<(u64, u64, &'static str)> {
+        let mut out = Vec::new();
+        let mut after = None;
+        while let Some(r) = map.next(after) {
+            out.push((r.base, r.end, r.owner));
+            after = Some(r.base);
+        }
+        out
+    }
+
+    #[test]
+    fn claims_merge_and_overlaps_are_refused() {
+        let mut map = Map::new();
+        map.claim(0x3000, 0x4000, "vmm.table").unwrap();
+        map.claim(0x1000, 0x2000, "xhci.cmd_ring").unwrap();
+        map.claim(0x2000, 0x3000, "vmm.table").unwrap();
+        map.claim(0x4000, 0x5000, "vmm.table").unwrap();
+        assert_eq!(ranges(&map), [(0x1000, 0x2000, "xhci.cmd_ring"), (0x2000, 0x5000, "vmm.table")]);
+        let hit = Range { base: 0x2000, end: 0x5000, owner: "vmm.table" };
+        assert_eq!(map.claim(0x4800, 0x6000, "ramfs.file"), Err(OwnError::Overlap(hit)));
+    }
+
+    #[test]
+    fn release_trims_splits_and_refuses_unowned() {
+        let mut map = Map::new();
+        map.claim(0x1000, 0x5000, "vmm.heap").unwrap();
+        assert_eq!(map.release(0x2000, 0x3000), Ok("vmm.heap"));
+        assert_eq!(ranges(&map), [(0x1000, 0x2000, "vmm.heap"), (0x3000, 0x5000, "vmm.heap")]);
+        assert_eq!(map.release(0x4000, 0x5000), Ok("vmm.heap"));
+        // Double free, and a free straddling owned and unowned memory
+        assert_eq!(map.release(0x2000, 0x3000), Err(OwnError::Unowned));
+        assert_eq!(map.release(0x1000, 0x3000), Err(OwnError::Unowned));
+        assert_eq!(ranges(&map), [(0x1000, 0x2000, "vmm.heap"), (0x3000, 0x4000, "vmm.heap")]);
+
+        let mut full = Map::new();
+        for i in 0..MAX_RANGES as u64 {
+            // Gaps between them, so nothing merges
+            full.claim(i * 0x2000, i * 0x2000 + 0x1000, "ai.model").unwrap();
+        }
+        assert_eq!(full.release(0x400, 0x800), Err(OwnError::Full));
+        assert!(full.overflowed());
+    }
+}
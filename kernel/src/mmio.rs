@@ -0,0 +1,183 @@
+//! Typed volatile access to device registers and DMA-shared structures.
+//!
+//! `MmioRegion` is a base pointer plus a length; every access checks the
+//! offset against that length and the access size's alignment, so a wrong
+//! offset (often one a device handed us) is an `MmioError` instead of a
+//! write somewhere else. `read32`/`write32` and the 64-bit pair turn the
+//! error into what an absent device looks like: reads return all ones and
+//! writes are dropped.
+//!
+//! Memory shared with a device by DMA follows an ownership protocol, with
+//! the barriers below at each hand-over:
+//! - producer: write every field of a descriptor, `dma_wmb`, then the field
+//!   that passes ownership (an xHCI TRB's cycle bit); `dma_wmb` again before
+//!   the MMIO write that tells the device to look (a doorbell);
+//! - consumer: read the ownership field, and only if it is ours, `dma_rmb`
+//!   before reading the rest.
+//!
+//! x86 already keeps ordinary write-back stores in order and loads in order,
+//! but not stores through write-combining mappings, and the compiler keeps
+//! neither unless told; the fences cover both.
+
+use core::cell::UnsafeCell;
+use core::mem::{align_of, size_of};
+use core::ptr::{read_volatile, write_volatile, NonNull};
+
+/// Order earlier stores to DMA memory before later stores, including MMIO
+/// writes. `sfence` also drains write-combining buffers.
+#[inline]
+pub fn dma_wmb() {
+    unsafe { core::arch::asm!("sfence", options(nostack, preserves_flags)) };
+}
+
+/// Order an earlier load of an ownership field before later loads of the
+/// fields it guards.
+#[inline]
+pub fn dma_rmb() {
+    unsafe { core::arch::asm!("lfence", options(nostack, preserves_flags)) };
+}
+
+/// A value that is only ever accessed with volatile loads and stores.
+#[repr(transparent)]
+pub struct Volatile<T: Copy>(UnsafeCell<T>);
+
+impl<T: Copy> Volatile<T> {
+    pub fn read(&self) -> T {
+        unsafe { read_volatile(self.0.get()) }
+    }
+
+    pub fn write(&self, value: T) {
+        unsafe { write_volatile(self.0.get(), value) }
+    }
+
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        self.write(f(self.read()));
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MmioError {
+    OutOfBounds,
+    Misaligned,
+}
+
+impl MmioError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MmioError::OutOfBounds => "register past the end of the region",
+            MmioError::Misaligned => "misaligned register access",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MmioRegion {
+    base: NonNull<u8>,
+    len: usize,
+}
+
+impl MmioRegion {
+    /// # Safety
+    /// `base..base+len` must be mapped, valid for volatile access for the
+    /// lifetime of the region, and not used through other typed references.
+    pub unsafe fn new(base: NonNull<u8>, len: usize) -> Self {
+        MmioRegion { base, len }
+    }
+
+    /// Sub-window of `len` bytes starting at `offset`.
+    pub fn subregion(&self, offset: usize, len: usize) -> Result<MmioRegion, MmioError> {
+        kensure!(offset.checked_add(len).is_some_and(|end| end <= self.len), MmioError::OutOfBounds);
+        Ok(MmioRegion { base: unsafe { NonNull::new_unchecked(self.base.as_ptr().add(offset)) }, len })
+    }
+
+    /// `subregion` cut down to what fits: for windows whose bounds the
+    /// region's length was computed from. What does not fit reads as all
+    /// ones.
+    pub fn window(&self, offset: usize, len: usize) -> MmioRegion {
+        let offset = offset.min(self.len);
+        let len = len.min(self.len - offset);
+        MmioRegion { base: unsafe { NonNull::new_unchecked(self.base.as_ptr().add(offset)) }, len }
+    }
+
+    /// Register of type `T` at `offset`.
+    pub fn reg<T: Copy>(&self, offset: usize) -> Result<&Volatile<T>, MmioError> {
+        kensure!(offset.checked_add(size_of::<T>()).is_some_and(|end| end <= self.len), MmioError::OutOfBounds);
+        let ptr = unsafe { self.base.as_ptr().add(offset) };
+        kensure!((ptr as usize).is_multiple_of(align_of::<T>()), MmioError::Misaligned);
+        Ok(unsafe { &*(ptr as *const Volatile<T>) })
+    }
+
+    /// All ones if `offset` is not a register of this region.
+    pub fn read32(&self, offset: usize) -> u32 {
+        self.reg::<u32>(offset).map_or(u32::MAX, |r| r.read())
+    }
+
+    /// Dropped if `offset` is not a register of this region.
+    pub fn write32(&self, offset: usize, value: u32) {
+        if let Ok(r) = self.reg::<u32>(offset) {
+            r.write(value);
+        }
+    }
+
+    /// 64-bit register read as two dwords, low first (xHCI allows 32-bit
+    /// access to every 64-bit register).
+    pub fn read64(&self, offset: usize) -> u64 {
+        let low = self.read32(offset) as u64;
+        let high = self.read32(offset + 4) as u64;
+        (high << 32) | low
+    }
+
+    /// 64-bit register written as two dwords, low first.
+    pub fn write64(&self, offset: usize, value: u64) {
+        self.write32(offset, value as u32);
+        self.write32(offset + 4, (value >> 32) as u32);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[repr(align(8))]
+    struct Buf([u8; 64]);
+
+    fn region(buf: &mut Buf) -> MmioRegion {
+        unsafe { MmioRegion::new(NonNull::new(buf.0.as_mut_ptr()).unwrap(), buf.0.len()) }
+    }
+
+    #[test]
+    fn read_write_and_subregion() {
+        let mut buf = Buf([0; 64]);
+        let r = region(&mut buf);
+        r.write64(8, 0x1122_3344_5566_7788);
+        assert_eq!(r.read32(8), 0x5566_7788);
+        assert_eq!(r.read32(12), 0x1122_3344);
+        let sub = r.subregion(8, 16).unwrap();
+        assert_eq!(sub.read64(0), 0x1122_3344_5566_7788);
+        sub.reg::<u32>(4).unwrap().update(|v| v | 1);
+        assert_eq!(r.read32(12), 0x1122_3345);
+    }
+
+    #[test]
+    fn out_of_bounds_is_an_error() {
+        let mut buf = Buf([0; 64]);
+        let r = region(&mut buf);
+        assert_eq!(r.reg::<u32>(62).err(), Some(MmioError::OutOfBounds));
+        assert_eq!(r.subregion(60, 8).err(), Some(MmioError::OutOfBounds));
+        assert_eq!(r.subregion(usize::MAX, 2).err(), Some(MmioError::OutOfBounds));
+        // Like a device that is not there
+        assert_eq!(r.read32(64), u32::MAX);
+        r.write32(64, 0);
+        assert_eq!(r.window(60, 8).read32(0), 0);
+        assert_eq!(r.window(60, 8).read32(4), u32::MAX);
+        assert_eq!(r.window(100, 8).read32(0), u32::MAX);
+    }
+
+    #[test]
+    fn misaligned_is_an_error() {
+        let mut buf = Buf([0; 64]);
+        let r = region(&mut buf);
+        assert_eq!(r.reg::<u32>(2).err(), Some(MmioError::Misaligned));
+        assert_eq!(r.read32(2), u32::MAX);
+    }
+}
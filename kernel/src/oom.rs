@@ -0,0 +1,96 @@
+//! What happens when the physical allocator runs dry (`pmm::alloc_for`).
+//!
+//! A failed allocation is logged with its owner and size, recorded as a
+//! trace event and counted, then the `TrimCache` path runs and the
+//! allocation is tried again. `TrimCache` is a no-op for now: there is no
+//! cache to trim, so that retry only helps when another CPU or an
+//! interrupt freed memory meanwhile. If it still fails the kernel degrades,
+//! once: the agent stops proposing (and so stops recording telemetry),
+//! event tracing and `stress` loads are stopped, the buffers of removed
+//! ramfs files go back to the pmm, and `status` shows `mem` as failed.
+//! Only then does the caller get `None`.
+//!
+//! There is no event bus: the `status` entry, the trace event and `stats`
+//! are how the rest of the kernel learns of it. The trace ring and the
+//! serial tail are static, so stopping the tracer frees nothing; freed
+//! ramfs pages serve later page allocations, not the contiguous one that
+//! failed.
+//!
+//! Nothing here takes a lock a caller of the allocator might hold, apart
+//! from the serial port and the status registry (ramfs is only tried).
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+
+use crate::status::{self, Health};
+use crate::{apply_action, ktrace, pmm, ramfs, serial};
+
+static FAILURES: AtomicU64 = AtomicU64::new(0);
+/// Failures the trim or degrading got past.
+static RECOVERED: AtomicU64 = AtomicU64::new(0);
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+static LAST: Mutex<Option<Failure>> = Mutex::new(None);
+
+#[derive(Copy, Clone, Debug)]
+pub struct Failure {
+    pub owner: &'static str,
+    pub size: u64,
+    pub free_kib: u64,
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct Stats {
+    pub failures: u64,
+    pub recovered: u64,
+    pub degraded: bool,
+    pub last: Option<Failure>,
+}
+
+pub fn stats() -> Stats {
+    Stats {
+        failures: FAILURES.load(Ordering::Relaxed),
+        recovered: RECOVERED.load(Ordering::Relaxed),
+        degraded: DEGRADED.load(Ordering::Relaxed),
+        last: *LAST.lock(),
+    }
+}
+
+/// `alloc` failed for `owner` asking `size` bytes: trim, retry, degrade,
+/// retry. Returns what the last `alloc` returned.
+pub fn recover(owner: &'static str, size: u64, alloc: impl Fn() -> Option<u64>) -> Option<u64> {
+    let free_kib = pmm::free_kib();
+    FAILURES.fetch_add(1, Ordering::Relaxed);
+    *LAST.lock() = Some(Failure { owner, size, free_kib });
+    serial::write_fmt(format_args!("[oom] {}: {} bytes failed, {} KiB free\r\n", owner, size, free_kib));
+    ktrace::event(ktrace::Kind::Oom, 0, size.div_ceil(1024).min(u32::MAX as u64) as u32);
+
+    apply_action::trim_cache(size);
+    if let Some(phys) = alloc() {
+        RECOVERED.fetch_add(1, Ordering::Relaxed);
+        return Some(phys);
+    }
+    if !DEGRADED.swap(true, Ordering::Relaxed) {
+        degrade();
+        if let Some(phys) = alloc() {
+            RECOVERED.fetch_add(1, Ordering::Relaxed);
+            return Some(phys);
+        }
+    }
+    serial::write_fmt(format_args!("[oom] {}: giving up on {} bytes\r\n", owner, size));
+    None
+}
+
+/// Switch off what only adds to memory demand. Stays off until reboot.
+fn degrade() {
+    #[cfg(feature = "ai_agent")]
+    {
+        crate::ai_agent::stop();
+        status::set("ai", Health::Skipped, "stopped: out of memory");
+    }
+    ktrace::stop();
+    crate::stress::stop();
+    if let Some(freed) = ramfs::try_reclaim().filter(|&n| n > 0) {
+        serial::write_fmt(format_args!("[oom] {} KiB of removed files given back\r\n", freed / 1024));
+    }
+    status::set("mem", Health::Failed, "out of memory: agent, tracing and stress stopped");
+}
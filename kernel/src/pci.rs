@@ -1,7 +1,7 @@
 use core::fmt;
 use x86_64::instructions::port::Port;
 
-#[derive(Clone, Copy)]
+#[derive(Clone, Copy, Debug)]
 pub struct PciAddress {
     pub bus: u8,
     pub device: u8,
@@ -0,0 +1,206 @@
+#![allow(dead_code)]
+
+//! Per-CPU data, reached through the GS base.
+//!
+//! Each CPU owns one cache-line aligned `PerCpu` block; `init_cpu` points
+//! its GS base at it, and the block's first word holds its own address, so
+//! `this()` is a single `mov` from `gs:0` with no lock and no CPU id lookup.
+//! `percpu!(field)` is the accessor. KERNEL_GS_BASE is left with the user
+//! value (0) for the `swapgs` a ring-3 entry path will need.
+//!
+//! Only the boot CPU is brought up today, so everything here runs on block
+//! 0; state that moves in (the current pid and task, the syscall counter)
+//! is what an SMP scheduler will need per CPU instead of behind a global.
+//! Before `init`, and in host tests, `this()` is block 0 too.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::serial;
+
+pub const MAX_CPUS: usize = 8;
+/// Task slots a run queue holds.
+pub const RUN_QUEUE_LEN: usize = 16;
+
+/// Slots of `task` waiting for this CPU, first in first out.
+pub struct RunQueue {
+    slots: [u8; RUN_QUEUE_LEN],
+    head: usize,
+    len: usize,
+}
+
+impl RunQueue {
+    pub const fn new() -> Self {
+        RunQueue { slots: [0; RUN_QUEUE_LEN], head: 0, len: 0 }
+    }
+
+    /// Queue `slot`; false if the queue is full.
+    pub fn push(&mut self, slot: u8) -> bool {
+        if self.len == RUN_QUEUE_LEN {
+            return false;
+        }
+        self.slots[(self.head + self.len) % RUN_QUEUE_LEN] = slot;
+        self.len += 1;
+        true
+    }
+
+    pub fn pop(&mut self) -> Option<u8> {
+        if self.len == 0 {
+            return None;
+        }
+        let slot = self.slots[self.head];
+        self.head = (self.head + 1) % RUN_QUEUE_LEN;
+        self.len -= 1;
+        Some(slot)
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+/// A function for another CPU to run, with its argument (`ipi::call_on`).
+pub type Call = (fn(u64), u64);
+
+#[repr(C, align(64))]
+pub struct PerCpu {
+    /// The block's own address; must stay first (`this` reads `gs:0`).
+    self_ptr: AtomicU64,
+    pub id: AtomicU32,
+    /// `process::current`.
+    pub current_pid: AtomicU32,
+    /// Slot of the `task` on this CPU, `usize::MAX` between tasks.
+    pub current_task: AtomicUsize,
+    pub syscalls: AtomicU64,
+    pub run_queue: Mutex<RunQueue>,
+    /// Set by `lapic::init` on this CPU.
+    pub apic_id: AtomicU32,
+    /// IPIs taken (`ipi`).
+    pub ipis: AtomicU64,
+    /// A reschedule IPI asked this CPU to pick its next task.
+    pub need_resched: AtomicBool,
+    /// Mailbox of `ipi::call_on`.
+    pub call: Mutex<Option<Call>>,
+    /// Calls run from the mailbox, for the sender to see its own finish.
+    pub calls_done: AtomicU64,
+}
+
+impl PerCpu {
+    const fn new() -> Self {
+        PerCpu {
+            self_ptr: AtomicU64::new(0),
+            id: AtomicU32::new(0),
+            current_pid: AtomicU32::new(0),
+            current_task: AtomicUsize::new(usize::MAX),
+            syscalls: AtomicU64::new(0),
+            run_queue: Mutex::new(RunQueue::new()),
+            apic_id: AtomicU32::new(0),
+            ipis: AtomicU64::new(0),
+            need_resched: AtomicBool::new(false),
+            call: Mutex::new(None),
+            calls_done: AtomicU64::new(0),
+        }
+    }
+}
+
+static BLOCKS: [PerCpu; MAX_CPUS] = [const { PerCpu::new() }; MAX_CPUS];
+/// Set once the boot CPU's GS base points at its block.
+static READY: AtomicBool = AtomicBool::new(false);
+static ONLINE: AtomicU32 = AtomicU32::new(0);
+
+/// Field `$field` of this CPU's block.
+macro_rules! percpu {
+    ($field:ident) => {
+        $crate::percpu::this().$field
+    };
+}
+
+/// This CPU's block.
+#[inline]
+pub fn this() -> &'static PerCpu {
+    #[cfg(not(test))]
+    if READY.load(Ordering::Relaxed) {
+        let ptr: u64;
+        unsafe { core::arch::asm!("mov {}, gs:[0]", out(reg) ptr, options(nostack, readonly, preserves_flags)) };
+        return unsafe { &*(ptr as *const PerCpu) };
+    }
+    &BLOCKS[0]
+}
+
+/// Point this CPU's GS base at block `id`. A CPU calls it before touching
+/// `percpu!`, after its GDT is loaded (loading GS clears the base).
+pub fn init_cpu(id: usize) -> Result<(), &'static str> {
+    let block = BLOCKS.get(id).ok_or("cpu id past MAX_CPUS")?;
+    if block.self_ptr.load(Ordering::Acquire) != 0 {
+        return Err("cpu already online");
+    }
+    let addr = block as *const PerCpu as u64;
+    block.self_ptr.store(addr, Ordering::Release);
+    block.id.store(id as u32, Ordering::Relaxed);
+    #[cfg(not(test))]
+    {
+        use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+        GsBase::write(x86_64::VirtAddr::new(addr));
+        KernelGsBase::write(x86_64::VirtAddr::new(0));
+    }
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    Ok(())
+}
+
+/// Bring up the boot CPU's block.
+pub fn init() {
+    match init_cpu(0) {
+        Ok(()) => {
+            READY.store(true, Ordering::Release);
+            serial::write_fmt(format_args!("[percpu] cpu0 block at {:#x}\r\n", &BLOCKS[0] as *const PerCpu as u64));
+        }
+        Err(e) => serial::write_fmt(format_args!("[percpu] {}\r\n", e)),
+    }
+}
+
+/// The blocks of the CPUs brought up so far.
+pub fn for_each(mut f: impl FnMut(&PerCpu)) {
+    BLOCKS.iter().filter(|b| b.self_ptr.load(Ordering::Acquire) != 0).for_each(&mut f);
+}
+
+/// Block of CPU `id`, if it is online.
+pub fn get(id: usize) -> Option<&'static PerCpu> {
+    BLOCKS.get(id).filter(|b| b.self_ptr.load(Ordering::Acquire) != 0)
+}
+
+pub fn online() -> u32 {
+    ONLINE.load(Ordering::Acquire)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_queue_is_fifo_and_bounded() {
+        let mut q = RunQueue::new();
+        assert_eq!(q.pop(), None);
+        for slot in 0..RUN_QUEUE_LEN as u8 {
+            assert!(q.push(slot));
+        }
+        assert!(!q.push(99));
+        assert_eq!((q.pop(), q.pop()), (Some(0), Some(1)));
+        // Wraps around the end of the ring
+        assert!(q.push(20) && q.push(21));
+        let rest: std::vec::Vec<u8> = core::iter::from_fn(|| q.pop()).collect();
+        assert_eq!(rest.len(), RUN_QUEUE_LEN);
+        assert_eq!(rest[RUN_QUEUE_LEN - 2..], [20, 21]);
+        assert!(q.is_empty());
+    }
+
+    #[test]
+    fn blocks_start_at_gs_zero() {
+        assert_eq!(core::mem::offset_of!(PerCpu, self_ptr), 0);
+        assert_eq!(core::mem::align_of::<PerCpu>(), 64);
+        assert!(init_cpu(MAX_CPUS).is_err());
+    }
+}
@@ -1,20 +1,123 @@
+use core::sync::atomic::{AtomicU16, Ordering};
 use pic8259::ChainedPics;
 use spin::Mutex;
 
+use crate::{config, serial};
+
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
+/// Lines unmasked when `irq.unmask` is absent or invalid: timer and keyboard.
+pub const DEFAULT_LINES: u16 = 0b11;
+const CASCADE_LINE: u8 = 2;
+
+pub const LINE_NAMES: [&str; 16] = [
+    "timer", "keyboard", "cascade", "com2", "com1", "lpt2", "floppy", "lpt1",
+    "rtc", "acpi", "free1", "free2", "mouse", "fpu", "ata1", "ata2",
+];
+
 static PICS: Mutex<ChainedPics> =
     Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// Lines actually unmasked, bit n = IRQ n.
+static ENABLED: AtomicU16 = AtomicU16::new(0);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum IrqCfgError {
+    /// Not a line number.
+    BadLine,
+    /// A line above 15.
+    OutOfRange,
+    /// The timer drives `hlt` wake-ups and scheduling; it cannot be masked.
+    TimerMasked,
+}
+
+/// Parse an `irq.unmask` value: line numbers separated by commas or spaces.
+pub fn parse_lines(text: &str) -> Result<u16, IrqCfgError> {
+    let mut lines = 0u16;
+    for tok in text.split([',', ' ']).filter(|t| !t.is_empty()) {
+        let line: u8 = tok.parse().map_err(|_| IrqCfgError::BadLine)?;
+        if line > 15 {
+            return Err(IrqCfgError::OutOfRange);
+        }
+        lines |= 1 << line;
+    }
+    if lines & 1 == 0 {
+        return Err(IrqCfgError::TimerMasked);
+    }
+    Ok(lines)
+}
+
+/// Lines as they will be programmed: the cascade is added whenever a slave
+/// line is enabled, since nothing from the slave gets through without it.
+pub fn effective_lines(lines: u16) -> u16 {
+    if lines & 0xFF00 != 0 {
+        lines | (1 << CASCADE_LINE)
+    } else {
+        lines
+    }
+}
+
+/// (master, slave) mask registers for `lines`; a set bit masks the line.
+pub fn masks_for(lines: u16) -> (u8, u8) {
+    (!(lines as u8), !((lines >> 8) as u8))
+}
+
+fn configured_lines() -> u16 {
+    match config::with("irq.unmask", parse_lines) {
+        Some(Ok(lines)) => lines,
+        Some(Err(e)) => {
+            serial::write_fmt(format_args!("[pic] irq.unmask rejected ({:?}), using defaults\r\n", e));
+            DEFAULT_LINES
+        }
+        None => DEFAULT_LINES,
+    }
+}
+
 pub fn init() {
+    let lines = effective_lines(configured_lines());
+    let (master, slave) = masks_for(lines);
     let mut pics = PICS.lock();
     unsafe {
         pics.initialize();
-        pics.write_masks(0b1111_1100, 0xFF);
+        pics.write_masks(master, slave);
     }
+    ENABLED.store(lines, Ordering::Relaxed);
+    serial::write_fmt(format_args!("[pic] masks master={:#04x} slave={:#04x}\r\n", master, slave));
+}
+
+/// Lines unmasked by `init`.
+pub fn enabled_lines() -> u16 {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Mask IRQ 0 once the APIC timer drives the tick instead.
+pub fn mask_timer() {
+    let lines = ENABLED.fetch_and(!1, Ordering::Relaxed) & !1;
+    let (master, slave) = masks_for(lines);
+    unsafe { PICS.lock().write_masks(master, slave) };
 }
 
 pub fn notify_end_of_interrupt(irq: u8) {
     unsafe { PICS.lock().notify_end_of_interrupt(irq) };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_line_lists() {
+        assert_eq!(parse_lines("0,1"), Ok(0b11));
+        assert_eq!(parse_lines("0, 1 8"), Ok(0x103));
+        assert_eq!(parse_lines("0,16"), Err(IrqCfgError::OutOfRange));
+        assert_eq!(parse_lines("0,com1"), Err(IrqCfgError::BadLine));
+        assert_eq!(parse_lines("1,4"), Err(IrqCfgError::TimerMasked));
+    }
+
+    #[test]
+    fn slave_lines_pull_in_the_cascade() {
+        assert_eq!(masks_for(effective_lines(DEFAULT_LINES)), (0b1111_1100, 0xFF));
+        assert_eq!(masks_for(effective_lines(0x101)), (0b1111_1010, 0xFE));
+    }
+}
@@ -0,0 +1,231 @@
+#![allow(dead_code)]
+
+//! Byte-stream pipes between processes.
+//!
+//! A pipe is a `CAPACITY`-byte ring with a read end and a write end, each
+//! counted: every fd naming an end holds one reference, and the pipe is
+//! freed when the last one is closed. Reads and writes never block, since
+//! there is no scheduler to park a process on: an empty pipe with a writer
+//! left and a full one with a reader left are `WouldBlock` (EAGAIN), and the
+//! caller polls. Once the writers are gone a reader drains what is buffered
+//! and then sees end of file (0); once the readers are gone a write is
+//! `Broken` (EPIPE).
+//!
+//! Processes name ends through their fd tables (`process::fd_install`). The
+//! `PIPE` syscall puts both ends in the caller's; `connect` is the kernel
+//! side of `a | b` between two processes, for the shell once it can start
+//! user programs.
+
+use spin::Mutex;
+
+use crate::process::{self, Pid, ProcError};
+
+pub const CAPACITY: usize = 1024;
+pub const MAX_PIPES: usize = 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PipeError {
+    /// Every pipe is in use.
+    NoSlot,
+    /// Not an open end, or the wrong end for the call.
+    BadEnd,
+    WouldBlock,
+    Broken,
+}
+
+impl PipeError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PipeError::NoSlot => "too many pipes",
+            PipeError::BadEnd => "not an open pipe end",
+            PipeError::WouldBlock => "would block",
+            PipeError::Broken => "no reader left",
+        }
+    }
+}
+
+/// One end of pipe `pipe`, as kept in a process's fd table.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct End {
+    pub pipe: u8,
+    pub write: bool,
+}
+
+/// The bytes in flight.
+struct Ring {
+    buf: [u8; CAPACITY],
+    head: usize,
+    len: usize,
+}
+
+impl Ring {
+    const fn new() -> Self {
+        Ring { buf: [0; CAPACITY], head: 0, len: 0 }
+    }
+
+    /// Append what fits of `data`; returns how much did.
+    fn push(&mut self, data: &[u8]) -> usize {
+        let n = data.len().min(CAPACITY - self.len);
+        for (i, &b) in data[..n].iter().enumerate() {
+            self.buf[(self.head + self.len + i) % CAPACITY] = b;
+        }
+        self.len += n;
+        n
+    }
+
+    /// Take the oldest bytes into `out`; returns how many.
+    fn pop(&mut self, out: &mut [u8]) -> usize {
+        let n = out.len().min(self.len);
+        for (i, b) in out[..n].iter_mut().enumerate() {
+            *b = self.buf[(self.head + i) % CAPACITY];
+        }
+        self.head = (self.head + n) % CAPACITY;
+        self.len -= n;
+        n
+    }
+}
+
+struct Pipe {
+    ring: Ring,
+    readers: u32,
+    writers: u32,
+}
+
+static PIPES: Mutex<[Option<Pipe>; MAX_PIPES]> = Mutex::new([const { None }; MAX_PIPES]);
+
+/// A new pipe; the caller holds one reference to each end.
+pub fn create() -> Result<(End, End), PipeError> {
+    let mut pipes = PIPES.lock();
+    let slot = pipes.iter().position(|p| p.is_none()).ok_or(PipeError::NoSlot)?;
+    pipes[slot] = Some(Pipe { ring: Ring::new(), readers: 1, writers: 1 });
+    Ok((End { pipe: slot as u8, write: false }, End { pipe: slot as u8, write: true }))
+}
+
+fn with_pipe<R>(end: End, f: impl FnOnce(&mut Pipe) -> Result<R, PipeError>) -> Result<R, PipeError> {
+    let mut pipes = PIPES.lock();
+    let pipe = pipes.get_mut(end.pipe as usize).and_then(|p| p.as_mut()).ok_or(PipeError::BadEnd)?;
+    f(pipe)
+}
+
+/// One more reference to `end`, for a second fd naming it.
+pub fn dup(end: End) -> Result<(), PipeError> {
+    with_pipe(end, |p| {
+        match end.write {
+            true => p.writers += 1,
+            false => p.readers += 1,
+        }
+        Ok(())
+    })
+}
+
+/// Drop one reference to `end`; the pipe goes when both ends have none.
+pub fn close(end: End) {
+    let mut pipes = PIPES.lock();
+    let Some(slot) = pipes.get_mut(end.pipe as usize) else { return };
+    let Some(pipe) = slot.as_mut() else { return };
+    let count = if end.write { &mut pipe.writers } else { &mut pipe.readers };
+    *count = count.saturating_sub(1);
+    if pipe.readers == 0 && pipe.writers == 0 {
+        *slot = None;
+    }
+}
+
+/// Bytes read into `out`; 0 is end of file.
+pub fn read(end: End, out: &mut [u8]) -> Result<usize, PipeError> {
+    if end.write {
+        return Err(PipeError::BadEnd);
+    }
+    with_pipe(end, |p| match p.ring.pop(out) {
+        0 if p.writers > 0 && !out.is_empty() => Err(PipeError::WouldBlock),
+        n => Ok(n),
+    })
+}
+
+/// Bytes of `data` taken, possibly fewer than offered.
+pub fn write(end: End, data: &[u8]) -> Result<usize, PipeError> {
+    if !end.write {
+        return Err(PipeError::BadEnd);
+    }
+    with_pipe(end, |p| {
+        if p.readers == 0 {
+            return Err(PipeError::Broken);
+        }
+        match p.ring.push(data) {
+            0 if !data.is_empty() => Err(PipeError::WouldBlock),
+            n => Ok(n),
+        }
+    })
+}
+
+/// Pipe `writer`'s output into `reader`: a new pipe whose write end goes
+/// in `writer`'s fd table and read end in `reader`'s. Returns the two fds.
+pub fn connect(writer: Pid, reader: Pid) -> Result<(u64, u64), ProcError> {
+    let (r, w) = create().map_err(|_| ProcError::TooManyFiles)?;
+    let w_fd = process::fd_install(writer, w);
+    let r_fd = w_fd.and_then(|_| process::fd_install(reader, r));
+    match (w_fd, r_fd) {
+        (Ok(w_fd), Ok(r_fd)) => Ok((w_fd, r_fd)),
+        (w_fd, r_fd) => {
+            // Whatever did not make it into a table is closed here
+            if let Ok(fd) = w_fd {
+                process::fd_close(writer, fd);
+            } else {
+                close(w);
+            }
+            close(r);
+            Err(r_fd.err().or(w_fd.err()).unwrap_or(ProcError::TooManyFiles))
+        }
+    }
+}
+
+/// (pipe, bytes buffered, readers, writers) of each open pipe.
+pub fn for_each(mut f: impl FnMut(usize, usize, u32, u32)) {
+    let pipes = PIPES.lock();
+    for (i, p) in pipes.iter().enumerate() {
+        if let Some(p) = p {
+            f(i, p.ring.len, p.readers, p.writers);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_wraps_and_stops_when_full() {
+        let mut ring = Ring::new();
+        assert_eq!(ring.push(&[1; CAPACITY - 2]), CAPACITY - 2);
+        let mut out = [0u8; CAPACITY];
+        assert_eq!(ring.pop(&mut out[..CAPACITY - 4]), CAPACITY - 4);
+        // Two left, then around the end of the buffer
+        assert_eq!(ring.push(&[1, 2, 3, 4, 5, 6]), 6);
+        assert_eq!(ring.pop(&mut out), 8);
+        assert_eq!(out[..8], [1, 1, 1, 2, 3, 4, 5, 6]);
+        assert_eq!(ring.push(&[7; CAPACITY + 5]), CAPACITY);
+        assert_eq!(ring.push(&[8]), 0);
+    }
+
+    #[test]
+    fn ends_see_eof_and_broken_pipe() {
+        let (r, w) = create().unwrap();
+        let mut out = [0u8; 8];
+        assert_eq!(read(r, &mut out), Err(PipeError::WouldBlock));
+        assert_eq!(write(w, b"abc"), Ok(3));
+        assert_eq!(write(r, b"x"), Err(PipeError::BadEnd));
+        dup(w).unwrap();
+        close(w);
+        assert_eq!(read(r, &mut out[..2]), Ok(2));
+        close(w);
+        // Writers gone: what is left, then end of file
+        assert_eq!(read(r, &mut out), Ok(1));
+        assert_eq!(read(r, &mut out), Ok(0));
+        close(r);
+        assert_eq!(read(r, &mut out), Err(PipeError::BadEnd));
+
+        let (r, w) = create().unwrap();
+        close(r);
+        assert_eq!(write(w, b"abc"), Err(PipeError::Broken));
+        close(w);
+    }
+}
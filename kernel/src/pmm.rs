@@ -1,13 +1,24 @@
 use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 
+use crate::{addr, memown};
 use crate::bootinfo::BootInfo;
 use crate::serial;
 
 static NEXT_FREE: AtomicU64 = AtomicU64::new(0);
 static LIMIT: AtomicU64 = AtomicU64::new(0);
 
+/// Regions handed over after boot (`add_region`), used once the boot region
+/// is exhausted: (next free, limit) each, bumped like the boot region.
+const MAX_EXTRA_REGIONS: usize = 8;
+static EXTRA: Mutex<[(u64, u64); MAX_EXTRA_REGIONS]> = Mutex::new([(0, 0); MAX_EXTRA_REGIONS]);
+
 const PAGE_SIZE: u64 = 4096;
 
+/// Pages given back with `free_page`, linked through their first word:
+/// (head, count). `alloc_page` takes from here before bumping.
+static FREE_PAGES: Mutex<(u64, u64)> = Mutex::new((0, 0));
+
 pub fn init(boot: &BootInfo) {
     let mut best_base = 0u64;
     let mut best_len = 0u64;
@@ -17,11 +28,9 @@ pub fn init(boot: &BootInfo) {
             if !entry.is_usable() {
                 continue;
             }
-            let start = align_up(entry.base_addr, PAGE_SIZE);
-            let end = align_down(entry.base_addr + entry.length, PAGE_SIZE);
-            if end <= start {
+            let Some((start, end)) = usable_span(entry.base_addr, entry.length) else {
                 continue;
-            }
+            };
             let usable = end - start;
             if usable > best_len {
                 best_base = start;
@@ -43,11 +52,25 @@ pub fn init(boot: &BootInfo) {
     }
 }
 
-pub fn alloc_aligned(size: u64, align: u64) -> Option<u64> {
+/// `alloc_aligned` for a subsystem: a failure goes through the emergency
+/// path (`oom`) before `None` is returned. Only load generators and
+/// benchmarks, which expect to run out, call `alloc_aligned` directly.
+pub fn alloc_for(owner: &'static str, size: u64, align: u64) -> Option<u64> {
+    alloc_aligned(owner, size, align).or_else(|| crate::oom::recover(owner, size, || alloc_aligned(owner, size, align)))
+}
+
+/// `size` bytes at `align`, recorded as `owner`'s in the ownership map.
+pub fn alloc_aligned(owner: &'static str, size: u64, align: u64) -> Option<u64> {
     if align == 0 || align & (align - 1) != 0 {
         return None;
     }
     let adj_size = align_up(size, PAGE_SIZE.max(align));
+    let base = alloc_boot_region(adj_size, align).or_else(|| alloc_extra(adj_size, align))?;
+    let _ = memown::claim(base, adj_size, owner);
+    Some(base)
+}
+
+fn alloc_boot_region(adj_size: u64, align: u64) -> Option<u64> {
     loop {
         let current = NEXT_FREE.load(Ordering::SeqCst);
         let limit = LIMIT.load(Ordering::SeqCst);
@@ -68,9 +91,107 @@ pub fn alloc_aligned(size: u64, align: u64) -> Option<u64> {
     }
 }
 
-#[allow(dead_code)]
-pub fn alloc_page() -> Option<u64> {
-    alloc_aligned(PAGE_SIZE, PAGE_SIZE)
+fn alloc_extra(adj_size: u64, align: u64) -> Option<u64> {
+    let mut extra = EXTRA.lock();
+    extra.iter_mut().find_map(|(next, limit)| {
+        let aligned = align_up(*next, align);
+        let end = aligned.checked_add(adj_size)?;
+        if *limit == 0 || end > *limit {
+            return None;
+        }
+        *next = end;
+        Some(aligned)
+    })
+}
+
+/// Hand `len` bytes at `base` to the allocator, e.g. firmware memory that is
+/// no longer needed. Only whole pages inside the kernel's mapping are used;
+/// returns the bytes actually added.
+pub fn add_region(base: u64, len: u64) -> u64 {
+    let Some((start, end)) = usable_span(base, len) else {
+        return 0;
+    };
+    let mut extra = EXTRA.lock();
+    match extra.iter_mut().find(|(_, limit)| *limit == 0) {
+        Some(slot) => {
+            *slot = (start, end);
+            end - start
+        }
+        None => 0,
+    }
+}
+
+/// Take `size` bytes (page-rounded) off the top of the region, for memory
+/// that must sit at the same address on every boot with the same memory map.
+pub fn reserve_top(owner: &'static str, size: u64) -> Option<u64> {
+    let size = align_up(size, PAGE_SIZE);
+    loop {
+        let next = NEXT_FREE.load(Ordering::SeqCst);
+        let limit = LIMIT.load(Ordering::SeqCst);
+        if next == 0 || limit < next.checked_add(size)? {
+            return None;
+        }
+        if LIMIT
+            .compare_exchange(limit, limit - size, Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            let _ = memown::claim(limit - size, size, owner);
+            return Some(limit - size);
+        }
+    }
+}
+
+/// Give back an allocation made by `alloc_aligned`. Only the most recent one
+/// can be returned, since the allocator just moves a cursor; returns whether
+/// the memory was released.
+pub fn free_last(base: u64, size: u64) -> bool {
+    let size = align_up(size, PAGE_SIZE);
+    let freed = NEXT_FREE
+        .compare_exchange(base + size, base, Ordering::SeqCst, Ordering::SeqCst)
+        .is_ok();
+    if freed {
+        let _ = memown::release(base, size);
+    }
+    freed
+}
+
+pub fn alloc_page(owner: &'static str) -> Option<u64> {
+    match pop_free_page() {
+        Some(page) => {
+            let _ = memown::claim(page, PAGE_SIZE, owner);
+            Some(page)
+        }
+        None => alloc_for(owner, PAGE_SIZE, PAGE_SIZE),
+    }
+}
+
+fn pop_free_page() -> Option<u64> {
+    let mut free = FREE_PAGES.lock();
+    let page = free.0;
+    if page == 0 {
+        return None;
+    }
+    free.0 = unsafe { addr::PhysAddr::new(page).as_mut_ptr::<u64>().read() };
+    free.1 -= 1;
+    Some(page)
+}
+
+/// Give back one page from `alloc_page`, e.g. a frame of an address space
+/// being torn down. The next `alloc_page` reuses it; its contents are not
+/// kept.
+pub fn free_page(page: u64) {
+    let _ = memown::release(page, PAGE_SIZE);
+    let mut free = FREE_PAGES.lock();
+    unsafe { addr::PhysAddr::new(page).as_mut_ptr::<u64>().write(free.0) };
+    *free = (page, free.1 + 1);
+}
+
+/// The whole pages of `[base, base + len)` the kernel can reach: memory
+/// past `addr::IDENTITY_LIMIT` is not identity-mapped, so it is cut off.
+fn usable_span(base: u64, len: u64) -> Option<(u64, u64)> {
+    let start = align_up(base, PAGE_SIZE);
+    let end = align_down(base.saturating_add(len).min(addr::IDENTITY_LIMIT), PAGE_SIZE);
+    (end > start).then_some((start, end))
 }
 
 fn align_up(value: u64, align: u64) -> u64 {
@@ -84,5 +205,24 @@ fn align_down(value: u64, align: u64) -> u64 {
 pub fn free_kib() -> u64 {
     let next = NEXT_FREE.load(Ordering::SeqCst);
     let limit = LIMIT.load(Ordering::SeqCst);
-    if next == 0 || limit <= next { 0 } else { (limit - next) / 1024 }
+    let boot = if next == 0 || limit <= next { 0 } else { limit - next };
+    let extra: u64 = EXTRA.lock().iter().map(|(next, limit)| limit.saturating_sub(*next)).sum();
+    let freed = FREE_PAGES.lock().1 * PAGE_SIZE;
+    (boot + extra + freed) / 1024
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn regions_stop_at_the_identity_limit() {
+        assert_eq!(usable_span(0x1800, 0x3000), Some((0x2000, 0x4000)));
+        let (start, end) = usable_span(1 << 30, 8 << 30).unwrap();
+        assert_eq!((start, end), (1 << 30, addr::IDENTITY_LIMIT));
+        assert!(addr::PhysAddr::new(end - PAGE_SIZE).to_virt().is_some());
+        // Wholly above the limit, or less than a page
+        assert_eq!(usable_span(addr::IDENTITY_LIMIT, 1 << 30), None);
+        assert_eq!(usable_span(0x1100, 0x800), None);
+    }
 }
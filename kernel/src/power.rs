@@ -0,0 +1,90 @@
+//! Orderly power off and reboot.
+//!
+//! Subsystems that hold state worth keeping or drive a device register a
+//! teardown hook; `shutdown` runs them newest first (the agent stops
+//! proposing before the journal is saved, the serial port drains last),
+//! records a clean end for `bootreason`, then powers off or resets. A hook
+//! that fails is logged and the next one still runs.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::{acpi, bootreason, serial};
+
+pub const MAX_HOOKS: usize = 8;
+
+pub type Hook = fn() -> Result<(), &'static str>;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Mode {
+    PowerOff,
+    Reboot,
+}
+
+static HOOKS: Mutex<[Option<(&'static str, Hook)>; MAX_HOOKS]> = Mutex::new([None; MAX_HOOKS]);
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Run `hook` on shutdown; false when the table is full.
+pub fn register_hook(name: &'static str, hook: Hook) -> bool {
+    let mut hooks = HOOKS.lock();
+    match hooks.iter_mut().find(|h| h.is_none()) {
+        Some(slot) => {
+            *slot = Some((name, hook));
+            true
+        }
+        None => {
+            serial::write_fmt(format_args!("[power] no room for the {} hook\r\n", name));
+            false
+        }
+    }
+}
+
+pub fn for_each_hook(mut f: impl FnMut(&'static str)) {
+    for (name, _) in HOOKS.lock().iter().flatten() {
+        f(name);
+    }
+}
+
+/// Tear everything down, then power off or reset. Only the first caller
+/// runs the hooks; a hook that ends up here again goes straight to the end.
+pub fn shutdown(mode: Mode) -> ! {
+    if !SHUTTING_DOWN.swap(true, Ordering::AcqRel) {
+        let hooks = *HOOKS.lock();
+        for (name, hook) in hooks.iter().rev().flatten() {
+            match hook() {
+                Ok(()) => serial::write_fmt(format_args!("[power] {} stopped\r\n", name)),
+                Err(e) => serial::write_fmt(format_args!("[power] {}: {}\r\n", name, e)),
+            }
+        }
+    }
+    bootreason::record(bootreason::BootReason::Clean);
+    match mode {
+        Mode::PowerOff => power_off(),
+        Mode::Reboot => reset(),
+    }
+    serial::write_str("[power] still running, halting\r\n");
+    loop {
+        x86_64::instructions::hlt();
+    }
+}
+
+/// QEMU's debug exit device when present, then ACPI S5.
+fn power_off() {
+    unsafe { Port::<u32>::new(0xF4).write(1) };
+    if let Err(e) = acpi::enter_s5() {
+        serial::write_fmt(format_args!("[power] acpi power off: {}\r\n", e));
+    }
+}
+
+/// Reset through the chipset's reset control register, then the keyboard
+/// controller if that did nothing.
+pub fn reset() {
+    unsafe {
+        let mut cf9 = Port::<u8>::new(0xCF9);
+        // System reset armed, then reset the CPU
+        cf9.write(0x02);
+        cf9.write(0x06);
+        Port::<u8>::new(0x64).write(0xFE);
+    }
+}
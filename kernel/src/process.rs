@@ -0,0 +1,327 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::pipe::{self, End};
+use crate::vmm::{self, AddressSpace, LazyRegion, MapError};
+use crate::{futex, serial, syscall};
+
+pub type Pid = u32;
+
+/// Pid 0 is the kernel itself (boot page tables).
+pub const KERNEL_PID: Pid = 0;
+
+const MAX_PROCS: usize = 16;
+/// Open files per process, past the console's fds 0-2.
+pub const MAX_FDS: usize = 8;
+/// The fd of `fds[0]`.
+pub const FIRST_FD: u64 = 3;
+
+// Per-process user layout: stack grows down from the top of the user half, heap above 1 TiB.
+pub const USER_STACK_TOP: u64 = vmm::USER_END - vmm::PAGE_SIZE;
+pub const USER_STACK_MAX: u64 = 1024 * 1024;
+pub const USER_HEAP_BASE: u64 = 0x0000_0100_0000_0000;
+pub const USER_HEAP_MAX: u64 = 64 * 1024 * 1024;
+
+/// Exit status of a process ended by `kill`, as a shell reports a SIGKILL.
+pub const KILLED_STATUS: i32 = 128 + 9;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum State {
+    Ready,
+    Running,
+    /// Parked in `futex::wait` until a wake.
+    Blocked,
+    /// Exited with this status; its memory is already given back and the
+    /// slot waits for the parent's `wait`.
+    Zombie(i32),
+}
+
+impl State {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            State::Ready => "ready",
+            State::Running => "running",
+            State::Blocked => "blocked",
+            State::Zombie(_) => "zombie",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Process {
+    pub pid: Pid,
+    /// Who created it; the kernel for processes it started itself or whose
+    /// parent exited first.
+    pub parent: Pid,
+    pub state: State,
+    /// Pipe ends open as fds `FIRST_FD..`; closed when the process exits.
+    pub fds: [Option<End>; MAX_FDS],
+    /// Freed once the process exits: only touch it through `with_process`,
+    /// which does not hand out zombies.
+    pub aspace: AddressSpace,
+}
+
+impl Process {
+    pub fn alive(&self) -> bool {
+        !matches!(self.state, State::Zombie(_))
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ProcError {
+    /// No live process has this pid.
+    NoProcess,
+    /// Pid 0 cannot exit or be killed.
+    Kernel,
+    /// The caller has no child to wait for (or not this one).
+    NoChild,
+    /// Its fd table (or the pipe pool) is full.
+    TooManyFiles,
+}
+
+impl ProcError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ProcError::NoProcess => "no such process",
+            ProcError::Kernel => "the kernel cannot exit",
+            ProcError::NoChild => "no such child",
+            ProcError::TooManyFiles => "too many open files",
+        }
+    }
+}
+
+static TABLE: Mutex<[Option<Process>; MAX_PROCS]> = Mutex::new([None; MAX_PROCS]);
+static NEXT_PID: AtomicU32 = AtomicU32::new(1);
+
+/// Creates a process with a fresh address space and lazily-backed stack and heap.
+pub fn create() -> Result<Pid, MapError> {
+    let mut aspace = AddressSpace::new()?;
+    let user_rw = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    aspace.add_lazy_region(LazyRegion {
+        start: USER_STACK_TOP - USER_STACK_MAX,
+        end: USER_STACK_TOP,
+        flags: user_rw,
+    })?;
+    aspace.add_lazy_region(LazyRegion {
+        start: USER_HEAP_BASE,
+        end: USER_HEAP_BASE + USER_HEAP_MAX,
+        flags: user_rw,
+    })?;
+
+    let mut table = TABLE.lock();
+    let slot = table.iter_mut().find(|p| p.is_none()).ok_or(MapError::NoSlot)?;
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    *slot = Some(Process { pid, parent: current(), state: State::Ready, fds: [None; MAX_FDS], aspace });
+    serial::write_fmt(format_args!(
+        "[proc] created pid={} pml4={:#x}\r\n",
+        pid,
+        aspace.pml4_phys()
+    ));
+    Ok(pid)
+}
+
+pub fn current() -> Pid {
+    percpu!(current_pid).load(Ordering::Acquire)
+}
+
+/// Context-switch hook: loads the target's CR3 and marks it running.
+pub fn switch_to(pid: Pid) -> bool {
+    let mut table = TABLE.lock();
+    let prev = current();
+    if pid == KERNEL_PID {
+        vmm::activate_kernel();
+    } else {
+        match table.iter_mut().flatten().find(|p| p.pid == pid && p.alive() && p.state != State::Blocked) {
+            Some(p) => {
+                p.aspace.activate();
+                p.state = State::Running;
+            }
+            None => return false,
+        }
+    }
+    if prev != pid {
+        if let Some(p) = table.iter_mut().flatten().find(|p| p.pid == prev && p.state == State::Running) {
+            p.state = State::Ready;
+        }
+    }
+    percpu!(current_pid).store(pid, Ordering::Release);
+    true
+}
+
+/// Runs `f` as a task in `pid`'s address space (`task::register_in`);
+/// `None` if there is no such live process or the run queue is full.
+#[cfg(feature = "ai_agent")]
+pub fn spawn_task(pid: Pid, name: &'static str, f: fn()) -> Option<usize> {
+    with_process(pid, |_| ())?;
+    crate::task::register_in(pid, name, f)
+}
+
+/// Runs `f` on the live process `pid`; `None` if there is none (or it is a
+/// zombie).
+pub fn with_process<R>(pid: Pid, f: impl FnOnce(&mut Process) -> R) -> Option<R> {
+    let mut table = TABLE.lock();
+    table.iter_mut().flatten().find(|p| p.pid == pid && p.alive()).map(f)
+}
+
+pub fn for_each(mut f: impl FnMut(&Process)) {
+    let table = TABLE.lock();
+    for p in table.iter().flatten() {
+        f(p);
+    }
+}
+
+/// Ends `pid` with `status`: its address space goes back to the allocator
+/// and its fds are closed at once, whoever asked. If it was running, the
+/// CPU is switched to the kernel's tables first. Its children pass to the
+/// kernel, which reaps them (and `pid` itself, if it is the parent) on exit
+/// since nothing in the kernel waits; otherwise it stays a zombie until its
+/// parent's `wait`. Returns the pages freed.
+pub fn exit(pid: Pid, status: i32) -> Result<u64, ProcError> {
+    if pid == KERNEL_PID {
+        return Err(ProcError::Kernel);
+    }
+    let mut table = TABLE.lock();
+    let slot = table
+        .iter_mut()
+        .find(|p| p.is_some_and(|p| p.pid == pid && p.alive()))
+        .ok_or(ProcError::NoProcess)?;
+    let proc = slot.as_mut().unwrap();
+    if current() == pid {
+        vmm::activate_kernel();
+        percpu!(current_pid).store(KERNEL_PID, Ordering::Release);
+    }
+    let freed = proc.aspace.destroy();
+    let fds = core::mem::replace(&mut proc.fds, [None; MAX_FDS]);
+    if proc.parent == KERNEL_PID {
+        *slot = None;
+    } else {
+        proc.state = State::Zombie(status);
+    }
+    for child in table.iter_mut() {
+        match child {
+            Some(c) if c.parent == pid && !c.alive() => *child = None,
+            Some(c) if c.parent == pid => c.parent = KERNEL_PID,
+            _ => {}
+        }
+    }
+    drop(table);
+    fds.into_iter().flatten().for_each(pipe::close);
+    futex::forget(pid);
+    let _ = syscall::set_traced(pid, false);
+    serial::write_fmt(format_args!("[proc] pid={} exited status={} freed={} pages\r\n", pid, status, freed));
+    Ok(freed)
+}
+
+/// Parks `pid` (see `futex`); if it was running the CPU goes back to the
+/// kernel's tables.
+pub fn block(pid: Pid) {
+    let mut table = TABLE.lock();
+    if let Some(p) = table.iter_mut().flatten().find(|p| p.pid == pid && p.alive()) {
+        p.state = State::Blocked;
+        if current() == pid {
+            vmm::activate_kernel();
+            percpu!(current_pid).store(KERNEL_PID, Ordering::Release);
+        }
+    }
+}
+
+/// Makes a parked `pid` runnable again.
+pub fn unblock(pid: Pid) {
+    let mut table = TABLE.lock();
+    if let Some(p) = table.iter_mut().flatten().find(|p| p.pid == pid && p.state == State::Blocked) {
+        p.state = State::Ready;
+    }
+}
+
+/// Whether `pid` may take the CPU (always, for the kernel); `None` once it
+/// has exited.
+pub fn runnable(pid: Pid) -> Option<bool> {
+    if pid == KERNEL_PID {
+        return Some(true);
+    }
+    with_process(pid, |p| p.state != State::Blocked)
+}
+
+/// Puts `end` in `pid`'s fd table, taking over the caller's reference;
+/// returns its fd.
+pub fn fd_install(pid: Pid, end: End) -> Result<u64, ProcError> {
+    with_process(pid, |p| {
+        let i = p.fds.iter().position(|f| f.is_none()).ok_or(ProcError::TooManyFiles)?;
+        p.fds[i] = Some(end);
+        Ok(FIRST_FD + i as u64)
+    })
+    .unwrap_or(Err(ProcError::NoProcess))
+}
+
+/// The pipe end `fd` names in `pid`'s table.
+pub fn fd_get(pid: Pid, fd: u64) -> Option<End> {
+    let i = usize::try_from(fd.checked_sub(FIRST_FD)?).ok()?;
+    with_process(pid, |p| p.fds.get(i).copied().flatten()).flatten()
+}
+
+/// Closes `fd` in `pid`'s table; false if it was not open.
+pub fn fd_close(pid: Pid, fd: u64) -> bool {
+    let Some(i) = fd.checked_sub(FIRST_FD).and_then(|i| usize::try_from(i).ok()) else { return false };
+    let end = with_process(pid, |p| p.fds.get_mut(i).and_then(|f| f.take())).flatten();
+    end.map(pipe::close).is_some()
+}
+
+/// Ends `pid` from outside, with `KILLED_STATUS`.
+pub fn kill(pid: Pid) -> Result<u64, ProcError> {
+    exit(pid, KILLED_STATUS)
+}
+
+/// Reaps an exited child of `parent` (`child` or any), returning its pid
+/// and status; `Ok(None)` while the children asked about are all running.
+pub fn wait(parent: Pid, child: Option<Pid>) -> Result<Option<(Pid, i32)>, ProcError> {
+    let mut table = TABLE.lock();
+    let mut found = false;
+    for slot in table.iter_mut() {
+        let Some(p) = slot else { continue };
+        if p.parent != parent || child.is_some_and(|c| c != p.pid) {
+            continue;
+        }
+        found = true;
+        if let State::Zombie(status) = p.state {
+            let pid = p.pid;
+            *slot = None;
+            return Ok(Some((pid, status)));
+        }
+    }
+    if found { Ok(None) } else { Err(ProcError::NoChild) }
+}
+
+/// A process with no page tables of its own, for host tests of the state
+/// machine; never switch to it or end it.
+#[cfg(test)]
+pub(crate) fn insert_detached() -> Pid {
+    let mut table = TABLE.lock();
+    let slot = table.iter_mut().find(|p| p.is_none()).expect("process table full");
+    let pid = NEXT_PID.fetch_add(1, Ordering::Relaxed);
+    let aspace = AddressSpace::detached();
+    *slot = Some(Process { pid, parent: KERNEL_PID, state: State::Ready, fds: [None; MAX_FDS], aspace });
+    pid
+}
+
+/// Called from the page fault handler: backs lazily-mapped user pages of the current process.
+pub fn handle_page_fault(addr: u64, not_present: bool) -> bool {
+    let pid = current();
+    if pid == KERNEL_PID || !not_present {
+        return false;
+    }
+    // Never spin in the fault path: a fault while the table is held is not recoverable here.
+    let mut table = match TABLE.try_lock() {
+        Some(t) => t,
+        None => return false,
+    };
+    match table.iter_mut().flatten().find(|p| p.pid == pid && p.alive()) {
+        Some(p) => p.aspace.fault_in(addr),
+        None => false,
+    }
+}
@@ -0,0 +1,234 @@
+//! `proc/` pseudo-files: kernel state rendered as text each time it is read,
+//! so `cat`, `grep`, `head` and pipelines work on it like on initrd files.
+//! Paths share the ramfs namespace; anything under `proc/` is served here.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::Ordering;
+use spin::Mutex;
+
+use crate::{acpi, executor, idt, journal, kaslr, lapic, percpu, pipe, pmm, rtc, serial, time, usb_state, xhci};
+
+pub const PREFIX: &str = "proc/";
+const BUF_LEN: usize = 4096;
+
+/// Output of one render. Text past `BUF_LEN` is dropped.
+struct Buf {
+    data: [u8; BUF_LEN],
+    len: usize,
+}
+
+impl Write for Buf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(BUF_LEN - self.len);
+        self.data[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+type Render = fn(&mut Buf) -> fmt::Result;
+
+static FILES: &[(&str, Render)] = &[
+    ("proc/meminfo", meminfo),
+    ("proc/interrupts", interrupts),
+    ("proc/uptime", uptime),
+    ("proc/usb", usb),
+    ("proc/tasks", tasks),
+    ("proc/pipes", pipes),
+    ("proc/ai/last_action", last_action),
+    ("proc/log", log),
+    ("proc/journal", journal_csv),
+];
+
+static BUF: Mutex<Buf> = Mutex::new(Buf { data: [0; BUF_LEN], len: 0 });
+
+pub fn is_proc(path: &str) -> bool {
+    path.starts_with(PREFIX)
+}
+
+fn lookup(path: &str) -> Option<Render> {
+    FILES.iter().find(|(name, _)| *name == path).map(|(_, render)| *render)
+}
+
+/// Render `path` and hand its contents to `f`; `None` if there is no such file.
+pub fn read<R>(path: &str, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+    let render = lookup(path)?;
+    let mut buf = BUF.lock();
+    buf.len = 0;
+    let _ = render(&mut buf);
+    Some(f(&buf.data[..buf.len]))
+}
+
+/// Visit every file with its current size (each one is rendered to measure it).
+pub fn for_each(mut f: impl FnMut(&str, usize)) {
+    for (name, _) in FILES {
+        if let Some(len) = read(name, |bytes| bytes.len()) {
+            f(name, len);
+        }
+    }
+}
+
+fn meminfo(out: &mut Buf) -> fmt::Result {
+    writeln!(out, "free_kib {}", pmm::free_kib())?;
+    let (reclaimable, reclaimed) = acpi::reclaim_stats();
+    writeln!(out, "acpi_reclaimable_kib {}", reclaimable / 1024)?;
+    writeln!(out, "acpi_reclaimed_kib {}", reclaimed / 1024)?;
+    for region in kaslr::Region::ALL {
+        writeln!(out, "{}_base {:#x}", region.name(), kaslr::base(region))?;
+    }
+    Ok(())
+}
+
+fn interrupts(out: &mut Buf) -> fmt::Result {
+    writeln!(out, "timer_ticks {}", idt::timer_ticks())?;
+    match lapic::timer_per_ms() {
+        Some(per_ms) => writeln!(out, "tick apic {} counts_per_ms {}", lapic::mode().as_str(), per_ms)?,
+        None => writeln!(out, "tick pit")?,
+    }
+    writeln!(out, "irqs {}", idt::irq_count())?;
+    writeln!(out, "page_faults {}", idt::page_faults())?;
+    let (nested, depth) = idt::irq_nesting();
+    writeln!(out, "nested {} max_depth {}", nested, depth)?;
+    let mut res = Ok(());
+    percpu::for_each(|cpu| {
+        if res.is_ok() {
+            res = writeln!(
+                out,
+                "cpu{} syscalls {} ipis {}",
+                cpu.id.load(Ordering::Relaxed),
+                cpu.syscalls.load(Ordering::Relaxed),
+                cpu.ipis.load(Ordering::Relaxed)
+            );
+        }
+    });
+    res?;
+    let mut res = Ok(());
+    idt::for_each_irq_latency(|s| {
+        if res.is_ok() {
+            res = writeln!(out, "irq{} count {} mean_cycles {} max_cycles {}", s.irq, s.count, s.mean_cycles, s.max_cycles);
+        }
+    });
+    res
+}
+
+fn pipes(out: &mut Buf) -> fmt::Result {
+    let mut res = Ok(());
+    pipe::for_each(|id, buffered, readers, writers| {
+        if res.is_ok() {
+            res = writeln!(out, "pipe{} buffered {} readers {} writers {}", id, buffered, readers, writers);
+        }
+    });
+    res
+}
+
+fn uptime(out: &mut Buf) -> fmt::Result {
+    writeln!(out, "ticks {}", idt::timer_ticks())?;
+    writeln!(out, "tick_millihz {}", time::tick_millihz())?;
+    writeln!(out, "rtc_ticks {}", rtc::ticks())?;
+    match time::cycles_to_us(time::since_boot()) {
+        Some(us) => writeln!(out, "ms {}", us / 1000),
+        None => writeln!(out, "cycles {}", time::since_boot()),
+    }
+}
+
+fn speed_name(speed: u8) -> &'static str {
+    match speed {
+        1 => "full",
+        2 => "low",
+        3 => "high",
+        4 => "super",
+        _ => "unknown",
+    }
+}
+
+fn usb(out: &mut Buf) -> fmt::Result {
+    let Some(info) = xhci::device_info() else {
+        return writeln!(out, "no controller");
+    };
+    writeln!(out, "xhci version {:04x} ports {}", info.hci_version, info.ports)?;
+    match info.slot {
+        Some(slot) => writeln!(out, "device slot {} port {} speed {}", slot, info.port, speed_name(info.speed))?,
+        None => writeln!(out, "no device")?,
+    }
+    let mut res = Ok(());
+    usb_state::for_each(|port, _, state| {
+        if res.is_ok() {
+            res = writeln!(out, "port{} {}", port, state.as_str());
+        }
+    });
+    res
+}
+
+fn tasks(out: &mut Buf) -> fmt::Result {
+    let mut res = Ok(());
+    executor::for_each_task(|id, name| {
+        if res.is_ok() {
+            res = writeln!(out, "{} {}", id.index(), name);
+        }
+    });
+    res
+}
+
+fn last_action(out: &mut Buf) -> fmt::Result {
+    let mut last = None;
+    journal::for_each(|rec| last = Some(*rec));
+    match last {
+        Some(rec) => writeln!(
+            out,
+            "seq {} action {} {:?} code {} at {}",
+            rec.seq,
+            rec.action,
+            rec.kind,
+            rec.code,
+            time::Duration(rec.tsc)
+        ),
+        None => writeln!(out, "none"),
+    }
+}
+
+/// The end of the serial log.
+fn log(out: &mut Buf) -> fmt::Result {
+    let mut tail = [0u8; BUF_LEN];
+    let len = serial::tail(&mut tail);
+    for chunk in tail[..len].utf8_chunks() {
+        out.write_str(chunk.valid())?;
+    }
+    Ok(())
+}
+
+/// Journal records oldest first, one per line, with the TSC rate needed to
+/// turn the timestamps into time offline.
+fn journal_csv(out: &mut Buf) -> fmt::Result {
+    writeln!(out, "# tsc_per_ms {}", time::tsc_per_ms())?;
+    writeln!(out, "tsc,seq,kind,action,code,source")?;
+    let mut res = Ok(());
+    journal::for_each(|rec| {
+        if res.is_ok() {
+            res = writeln!(out, "{},{},{},{},{},{}", rec.tsc, rec.seq, rec.kind.as_str(), rec.action, rec.code, rec.source());
+        }
+    });
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_truncates_at_buffer_end() {
+        let mut buf = Buf { data: [0; BUF_LEN], len: BUF_LEN - 3 };
+        write!(buf, "hello").unwrap();
+        assert_eq!(buf.len, BUF_LEN);
+        assert_eq!(&buf.data[BUF_LEN - 3..], b"hel");
+    }
+
+    #[test]
+    fn only_known_paths_resolve() {
+        assert!(is_proc("proc/meminfo"));
+        assert!(!is_proc("cfg/kernel.toml"));
+        assert!(lookup("proc/ai/last_action").is_some());
+        assert!(lookup("proc/nope").is_none());
+        assert_eq!(read("proc/journal", |b| b.starts_with(b"# tsc_per_ms ")), Some(true));
+        assert_eq!(read("proc/usb", |b| b == b"no controller\n"), Some(true));
+    }
+}
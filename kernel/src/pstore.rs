@@ -0,0 +1,302 @@
+//! Persistent RAM: a fixed region that outlives a warm reboot.
+//!
+//! Two pages are taken off the top of the pmm range, right below the kv
+//! store, so they land at the same physical address every boot and nothing
+//! else is ever handed them. Firmware leaves RAM alone across a warm reset
+//! (QEMU `system_reset`, a triple fault, the 0xCF9 reset), so what was
+//! written before it is still there; a power cycle loses it.
+//!
+//! The region starts with a header (magic, warm boots survived, CRC-32) and
+//! holds one slot per `Section` at a fixed offset, each with the length and
+//! CRC-32 of what it holds. A slot written halfway when the machine went
+//! down fails its CRC and reads as corrupt; it does not take the others
+//! with it. The crash record and serial tail, the journal ring and a copy of
+//! the boot-reason record live here. `try_write` and `try_fill` never wait, for
+//! the panic and fault paths.
+
+use spin::Mutex;
+
+use crate::status::{self, Health};
+use crate::{addr, hash, journal, pmm, serial};
+
+pub const REGION_LEN: usize = 8192;
+const MAGIC: u32 = 0x5053_5432; // "PST2"
+const HEADER_LEN: usize = 16;
+/// Length and CRC-32 ahead of each slot's bytes.
+const SLOT_HEADER_LEN: usize = 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Section {
+    /// `bootreason`'s record, mirrored from CMOS.
+    BootReason,
+    /// Panic message of the last `panic = dump`.
+    Crash,
+    /// The journal ring, oldest record first.
+    Journal,
+    /// Serial log tail saved with the crash; takes the rest of the region.
+    Log,
+}
+
+pub const SECTIONS: [Section; 4] = [Section::BootReason, Section::Crash, Section::Journal, Section::Log];
+
+const BOOT_REASON_CAP: usize = 8;
+const CRASH_CAP: usize = 512;
+const JOURNAL_CAP: usize = journal::IMAGE_LEN;
+
+impl Section {
+    pub fn parse(name: &str) -> Option<Self> {
+        SECTIONS.into_iter().find(|s| s.as_str() == name)
+    }
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Section::BootReason => "bootreason",
+            Section::Crash => "crash",
+            Section::Journal => "journal",
+            Section::Log => "log",
+        }
+    }
+
+    /// (offset of the slot in the region, bytes it can hold).
+    const fn span(self) -> (usize, usize) {
+        const BOOT_REASON: usize = HEADER_LEN;
+        const CRASH: usize = BOOT_REASON + SLOT_HEADER_LEN + BOOT_REASON_CAP;
+        const JOURNAL: usize = CRASH + SLOT_HEADER_LEN + CRASH_CAP;
+        const LOG: usize = JOURNAL + SLOT_HEADER_LEN + JOURNAL_CAP;
+        match self {
+            Section::BootReason => (BOOT_REASON, BOOT_REASON_CAP),
+            Section::Crash => (CRASH, CRASH_CAP),
+            Section::Journal => (JOURNAL, JOURNAL_CAP),
+            Section::Log => (LOG, REGION_LEN - LOG - SLOT_HEADER_LEN),
+        }
+    }
+
+    pub const fn capacity(self) -> usize {
+        self.span().1
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PstoreError {
+    /// `init` has not run or found no memory.
+    NotReady,
+    /// `try_write` found the region locked.
+    Busy,
+}
+
+impl PstoreError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            PstoreError::NotReady => "pstore not ready",
+            PstoreError::Busy => "pstore busy",
+        }
+    }
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+fn write_u32(bytes: &mut [u8], at: usize, value: u32) {
+    bytes[at..at + 4].copy_from_slice(&value.to_le_bytes());
+}
+
+/// Header: magic, warm boots survived, reserved, CRC-32 of bytes 0..12.
+/// Returns the boot count of a valid header.
+fn header_boots(region: &[u8]) -> Option<u32> {
+    let valid = read_u32(region, 0) == MAGIC && read_u32(region, 12) == hash::crc32(&region[..12]);
+    valid.then(|| read_u32(region, 4))
+}
+
+fn write_header(region: &mut [u8], boots: u32) {
+    write_u32(region, 0, MAGIC);
+    write_u32(region, 4, boots);
+    write_u32(region, 8, 0);
+    let crc = hash::crc32(&region[..12]);
+    write_u32(region, 12, crc);
+}
+
+/// Wipe the region to a valid header and empty slots.
+fn format(region: &mut [u8]) {
+    region.fill(0);
+    write_header(region, 0);
+}
+
+fn slot(region: &[u8], section: Section) -> &[u8] {
+    let (at, cap) = section.span();
+    &region[at..at + SLOT_HEADER_LEN + cap]
+}
+
+fn slot_mut(region: &mut [u8], section: Section) -> &mut [u8] {
+    let (at, cap) = section.span();
+    &mut region[at..at + SLOT_HEADER_LEN + cap]
+}
+
+/// A slot's bytes (empty if never written); `None` if its length or
+/// CRC-32 does not check out.
+fn open(slot: &[u8]) -> Option<&[u8]> {
+    let len = read_u32(slot, 0) as usize;
+    let data = slot.get(SLOT_HEADER_LEN..SLOT_HEADER_LEN + len)?;
+    (read_u32(slot, 4) == hash::crc32(data)).then_some(data)
+}
+
+/// Let `fill` write the slot's bytes, then seal them with their length and
+/// CRC-32. Returns the length kept, at most the slot's capacity.
+fn seal(slot: &mut [u8], fill: impl FnOnce(&mut [u8]) -> usize) -> usize {
+    let (head, data) = slot.split_at_mut(SLOT_HEADER_LEN);
+    let len = fill(data).min(data.len());
+    write_u32(head, 4, hash::crc32(&data[..len]));
+    write_u32(head, 0, len as u32);
+    len
+}
+
+/// Physical base of the region, once `init` has reserved it.
+static BASE: Mutex<Option<u64>> = Mutex::new(None);
+
+fn region(base: u64) -> &'static mut [u8] {
+    let ptr = addr::PhysAddr::new(base).as_mut_ptr::<u8>();
+    unsafe { core::slice::from_raw_parts_mut(ptr, REGION_LEN) }
+}
+
+/// Reserve the region and count one more boot on it, formatting it if the
+/// header is not ours (cold boot).
+pub fn init() {
+    let Some(base) = pmm::reserve_top("pstore", REGION_LEN as u64) else {
+        serial::write_str("[pstore] no memory for the region\r\n");
+        status::set("pstore", Health::Failed, "no memory for the region");
+        return;
+    };
+    let r = region(base);
+    match header_boots(r) {
+        Some(boots) => {
+            let boots = boots.wrapping_add(1);
+            write_header(r, boots);
+            serial::write_fmt(format_args!("[pstore] region at {:#x}, {} warm boot(s) survived\r\n", base, boots));
+        }
+        None => {
+            format(r);
+            serial::write_fmt(format_args!("[pstore] formatted region at {:#x}\r\n", base));
+        }
+    }
+    *BASE.lock() = Some(base);
+}
+
+/// Physical address of the region.
+pub fn base() -> Option<u64> {
+    *BASE.lock()
+}
+
+/// Warm boots the region has survived since it was formatted.
+pub fn boots() -> Option<u32> {
+    BASE.lock().and_then(|base| header_boots(region(base)))
+}
+
+fn fill_locked(base: Option<u64>, section: Section, fill: impl FnOnce(&mut [u8]) -> usize) -> Result<usize, PstoreError> {
+    let base = base.ok_or(PstoreError::NotReady)?;
+    Ok(seal(slot_mut(region(base), section), fill))
+}
+
+/// Replace `section` with what `fill` writes into its slot; returns the
+/// bytes kept.
+pub fn fill(section: Section, fill: impl FnOnce(&mut [u8]) -> usize) -> Result<usize, PstoreError> {
+    let base = BASE.lock();
+    fill_locked(*base, section, fill)
+}
+
+/// `fill` that gives up instead of waiting for the lock.
+pub fn try_fill(section: Section, fill: impl FnOnce(&mut [u8]) -> usize) -> Result<usize, PstoreError> {
+    let base = BASE.try_lock().ok_or(PstoreError::Busy)?;
+    fill_locked(*base, section, fill)
+}
+
+fn copy(data: &[u8]) -> impl FnOnce(&mut [u8]) -> usize + '_ {
+    move |slot| {
+        let n = data.len().min(slot.len());
+        slot[..n].copy_from_slice(&data[..n]);
+        n
+    }
+}
+
+/// Replace `section` with `data`, cut to the section's capacity; gives up
+/// instead of waiting for the lock.
+pub fn try_write(section: Section, data: &[u8]) -> Result<usize, PstoreError> {
+    try_fill(section, copy(data))
+}
+
+/// Run `f` on what `section` holds; `None` if it is empty, corrupt or the
+/// region is not up. The lock is not held while `f` runs (the shell pages
+/// through it), so a writer may change the bytes under it.
+pub fn read<R>(section: Section, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+    let base = (*BASE.lock())?;
+    let data = open(slot(region(base), section)).filter(|d| !d.is_empty())?;
+    Some(f(data))
+}
+
+/// Empty `section`, or every section and the boot count.
+pub fn clear(section: Option<Section>) -> Result<(), PstoreError> {
+    let base = BASE.lock().ok_or(PstoreError::NotReady)?;
+    match section {
+        Some(section) => {
+            seal(slot_mut(region(base), section), |_| 0);
+        }
+        None => format(region(base)),
+    }
+    Ok(())
+}
+
+/// Each section with its length, or `None` where the CRC fails.
+pub fn for_each(mut f: impl FnMut(Section, Option<usize>)) {
+    let Some(base) = base() else { return };
+    for section in SECTIONS {
+        f(section, open(slot(region(base), section)).map(|d| d.len()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_fit_the_region_back_to_back() {
+        let mut end = HEADER_LEN;
+        for section in SECTIONS {
+            let (at, cap) = section.span();
+            assert_eq!(at, end);
+            end = at + SLOT_HEADER_LEN + cap;
+            assert_eq!(Section::parse(section.as_str()), Some(section));
+        }
+        assert_eq!(end, REGION_LEN);
+        assert!(Section::Log.capacity() >= 4096);
+    }
+
+    #[test]
+    fn slots_round_trip_and_reject_torn_writes() {
+        let mut region = [0xAAu8; REGION_LEN];
+        assert_eq!(header_boots(&region), None);
+        format(&mut region);
+        assert_eq!(header_boots(&region), Some(0));
+        assert_eq!(open(slot(&region, Section::Crash)), Some(&[][..]));
+
+        let n = seal(slot_mut(&mut region, Section::Crash), copy(b"kernel panic"));
+        assert_eq!(n, 12);
+        assert_eq!(open(slot(&region, Section::Crash)), Some(&b"kernel panic"[..]));
+        // Too long for the slot: cut to its capacity
+        let big = [7u8; 600];
+        assert_eq!(seal(slot_mut(&mut region, Section::Crash), copy(&big)), CRASH_CAP);
+
+        // Bytes changed under the seal, as when the reset lands mid-write
+        let (at, _) = Section::Journal.span();
+        seal(slot_mut(&mut region, Section::Journal), copy(&[1, 2, 3]));
+        region[at + SLOT_HEADER_LEN] = 9;
+        assert_eq!(open(slot(&region, Section::Journal)), None);
+        // A length past the slot is corrupt too, and neighbours are untouched
+        write_u32(&mut region, at, u32::MAX);
+        assert_eq!(open(slot(&region, Section::Journal)), None);
+        assert_eq!(open(slot(&region, Section::Crash)).map(|d| d.len()), Some(CRASH_CAP));
+
+        write_header(&mut region, 4);
+        assert_eq!(header_boots(&region), Some(4));
+        region[5] ^= 1;
+        assert_eq!(header_boots(&region), None);
+    }
+}
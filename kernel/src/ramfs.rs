@@ -1,8 +1,6 @@
-// Import initrd symbols from the global linkage (defined in ai_link.rs)
-extern "C" {
-    static mut INITRD_BASE: *const u8;
-    static mut INITRD_LEN: usize;
-}
+use spin::Mutex;
+
+use crate::{ai_link, pmm};
 
 pub struct Entry<'a> {
     pub name: &'a [u8],
@@ -10,10 +8,162 @@ pub struct Entry<'a> {
     pub size: usize,
 }
 
+/// Files created at run time. They shadow initrd files of the same name and
+/// live in pmm memory; a removed file's buffer is reused by the next file
+/// that fits in it, unless `oom` hands it back to the pmm first.
+const MAX_WRITABLE: usize = 8;
+pub const MAX_NAME: usize = 48;
+
+#[derive(Copy, Clone)]
+struct WritableFile {
+    name: [u8; MAX_NAME],
+    name_len: usize,
+    base: u64,
+    cap: usize,
+    len: usize,
+    live: bool,
+}
+
+impl WritableFile {
+    fn name(&self) -> &[u8] {
+        &self.name[..self.name_len]
+    }
+
+    fn data(&self) -> *mut u8 {
+        crate::addr::PhysAddr::new(self.base).as_mut_ptr::<u8>()
+    }
+}
+
+static WRITABLE: Mutex<[Option<WritableFile>; MAX_WRITABLE]> = Mutex::new([None; MAX_WRITABLE]);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum WriteError {
+    BadName,
+    NoSlot,
+    NoMemory,
+    NotFound,
+    /// The file reached the capacity given to `create`.
+    Full,
+}
+
+impl WriteError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            WriteError::BadName => "bad file name",
+            WriteError::NoSlot => "too many files",
+            WriteError::NoMemory => "out of memory",
+            WriteError::NotFound => "no such file",
+            WriteError::Full => "file full",
+        }
+    }
+}
+
+/// Create (or truncate) the writable file `path`, able to grow to `capacity` bytes.
+pub fn create(path: &str, capacity: usize) -> Result<(), WriteError> {
+    if path.is_empty() || path.len() > MAX_NAME {
+        return Err(WriteError::BadName);
+    }
+    let mut files = WRITABLE.lock();
+    let existing = files.iter().position(|f| f.is_some_and(|f| f.live && f.name() == path.as_bytes()));
+    if let Some(f) = existing.and_then(|i| files[i].as_mut()).filter(|f| f.cap >= capacity) {
+        f.len = 0;
+        return Ok(());
+    }
+    if let Some(i) = existing {
+        if let Some(f) = files[i].as_mut() {
+            f.live = false;
+        }
+    }
+    let reuse = files.iter().position(|f| f.is_some_and(|f| !f.live && f.cap >= capacity));
+    let index = match reuse.or_else(|| files.iter().position(Option::is_none)) {
+        Some(i) => i,
+        None => return Err(WriteError::NoSlot),
+    };
+    let (base, cap) = match files[index] {
+        Some(f) => (f.base, f.cap),
+        None => {
+            let base = pmm::alloc_for("ramfs.file", capacity.max(1) as u64, 4096).ok_or(WriteError::NoMemory)?;
+            (base, capacity)
+        }
+    };
+    let mut name = [0u8; MAX_NAME];
+    name[..path.len()].copy_from_slice(path.as_bytes());
+    files[index] = Some(WritableFile { name, name_len: path.len(), base, cap, len: 0, live: true });
+    Ok(())
+}
+
+/// Append to a file made by `create`; all of `bytes` is written or nothing.
+pub fn append(path: &str, bytes: &[u8]) -> Result<(), WriteError> {
+    let mut files = WRITABLE.lock();
+    let f = files
+        .iter_mut()
+        .flatten()
+        .find(|f| f.live && f.name() == path.as_bytes())
+        .ok_or(WriteError::NotFound)?;
+    if f.cap - f.len < bytes.len() {
+        return Err(WriteError::Full);
+    }
+    unsafe { crate::fastmem::copy(f.data().add(f.len), bytes.as_ptr(), bytes.len()) };
+    f.len += bytes.len();
+    Ok(())
+}
+
+/// Remove a writable file; initrd files cannot be removed.
+pub fn remove(path: &str) -> bool {
+    let mut files = WRITABLE.lock();
+    match files.iter_mut().flatten().find(|f| f.live && f.name() == path.as_bytes()) {
+        Some(f) => {
+            f.live = false;
+            true
+        }
+        None => false,
+    }
+}
+
+/// Give the buffers kept for reuse by removed files back to the pmm, page
+/// by page; returns the bytes freed. `None` when the table is locked: the
+/// allocation that ran dry (`oom`) may be `create`'s own.
+pub fn try_reclaim() -> Option<u64> {
+    let mut files = WRITABLE.try_lock()?;
+    let mut freed = 0;
+    for slot in files.iter_mut().filter(|f| f.is_some_and(|f| !f.live)) {
+        let Some(f) = slot.take() else { continue };
+        // As `create` allocated it
+        let len = (f.cap.max(1) as u64).div_ceil(4096) * 4096;
+        for page in (f.base..f.base + len).step_by(4096) {
+            pmm::free_page(page);
+        }
+        freed += len;
+    }
+    Some(freed)
+}
+
+fn find_writable(path: &str) -> Option<(*const u8, usize)> {
+    let files = WRITABLE.lock();
+    files
+        .iter()
+        .flatten()
+        .find(|f| f.live && f.name() == path.as_bytes())
+        .map(|f| (f.data() as *const u8, f.len))
+}
+
+/// Visits writable files, then initrd files not shadowed by one.
 pub fn for_each(mut f: impl FnMut(Entry)) {
+    let files = *WRITABLE.lock();
+    for w in files.iter().flatten().filter(|w| w.live) {
+        f(Entry { name: w.name(), data: w.data(), size: w.len });
+    }
+    let shadowed = |name: &[u8]| files.iter().flatten().any(|w| w.live && w.name() == name);
+    for_each_initrd(|e| {
+        if !shadowed(e.name.strip_prefix(b"./").unwrap_or(e.name)) {
+            f(e);
+        }
+    });
+}
+
+fn for_each_initrd(mut f: impl FnMut(Entry)) {
+    let (base, len) = ai_link::initrd();
     unsafe {
-        let base = INITRD_BASE;
-        let len = INITRD_LEN;
         if base.is_null() || len < 110 { return; }
         // Verify header magic 'AIRD' + length at header sector
         // stage2 places 'AIRD'+len at sector before initrd data; INITRD_BASE points to data start.
@@ -45,11 +195,15 @@ pub fn for_each(mut f: impl FnMut(Entry)) {
 }
 
 pub fn find(path: &str) -> Option<(*const u8, usize)> {
+    if let Some(found) = find_writable(path) {
+        return Some(found);
+    }
     let mut out: Option<(*const u8, usize)> = None;
-    for_each(|e| {
+    for_each_initrd(|e| {
         if out.is_some() { return; }
         let want = path.as_bytes();
-        if e.name == want {
+        let name = e.name.strip_prefix(b"./").unwrap_or(e.name);
+        if name == want {
             out = Some((e.data, e.size));
         }
     });
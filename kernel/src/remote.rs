@@ -0,0 +1,172 @@
+//! Authenticated remote commands: the protocol half of a UDP monitoring
+//! channel, kept transport-free until a network stack exists to carry it.
+//!
+//! A request is `MRC1`, a sequence number (u64 LE), the command text and
+//! an HMAC-SHA256 tag over everything before it, keyed with config
+//! `remote.key`. The reply is `MRR1`, the same sequence number, a status
+//! byte, the command output and a tag over all of that. Only the
+//! read-only commands in `ALLOWED` run. Sequence numbers must increase so
+//! a captured request cannot be played again; requests with a bad tag or
+//! an old number get no reply at all. With no key set nothing is accepted.
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::hash::{self, SHA256_LEN};
+use crate::{config, serial, shell};
+
+pub const REQUEST_MAGIC: &[u8; 4] = b"MRC1";
+pub const REPLY_MAGIC: &[u8; 4] = b"MRR1";
+const HEADER_LEN: usize = 12;
+/// Longest command text a request may carry.
+pub const COMMAND_CAP: usize = 64;
+
+/// Remote command names and the shell line each one runs.
+const ALLOWED: &[(&str, &str)] = &[
+    ("stats", "stats tasks"),
+    ("stats input", "stats input"),
+    ("stats stacks", "stats stacks"),
+    ("stats irq-latency", "stats irq-latency"),
+    ("ai status", "ai"),
+    ("ai history", "ai history"),
+    ("dmesg", "cat proc/log"),
+    ("mem", "mem"),
+    ("uptime", "uptime"),
+];
+
+#[repr(u8)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Status {
+    Ok = 0,
+    /// Authenticated, but not a whitelisted command.
+    Denied = 1,
+    /// Output did not fit the reply.
+    Truncated = 2,
+}
+
+/// Why a request was dropped without a reply.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Reject {
+    Malformed,
+    BadTag,
+    Replayed,
+    NoKey,
+}
+
+impl Reject {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Reject::Malformed => "malformed",
+            Reject::BadTag => "bad tag",
+            Reject::Replayed => "replayed",
+            Reject::NoKey => "no key",
+        }
+    }
+}
+
+/// Highest sequence number accepted since boot.
+static LAST_SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Check framing and tag; returns the sequence number and command.
+fn parse<'a>(request: &'a [u8], key: &[u8]) -> Result<(u64, &'a str), Reject> {
+    if request.len() < HEADER_LEN + SHA256_LEN || &request[..4] != REQUEST_MAGIC {
+        return Err(Reject::Malformed);
+    }
+    let (body, tag) = request.split_at(request.len() - SHA256_LEN);
+    if !hash::tags_equal(tag, &hash::hmac_sha256(key, &[body])) {
+        return Err(Reject::BadTag);
+    }
+    let seq = u64::from_le_bytes(body[4..HEADER_LEN].try_into().unwrap());
+    let command = &body[HEADER_LEN..];
+    if command.len() > COMMAND_CAP {
+        return Err(Reject::Malformed);
+    }
+    let command = core::str::from_utf8(command).map_err(|_| Reject::Malformed)?;
+    Ok((seq, command.trim()))
+}
+
+fn shell_line(command: &str) -> Option<&'static str> {
+    ALLOWED.iter().find(|(name, _)| *name == command).map(|&(_, line)| line)
+}
+
+/// Answer one request datagram into `reply`; returns the reply length, or
+/// None when the request is dropped.
+pub fn handle(request: &[u8], reply: &mut [u8]) -> Option<usize> {
+    if reply.len() < HEADER_LEN + 1 + SHA256_LEN {
+        return None;
+    }
+    let mut key = [0u8; config::VAL_LEN];
+    let key_len = config::with("remote.key", |k| {
+        key[..k.len()].copy_from_slice(k.as_bytes());
+        k.len()
+    })
+    .unwrap_or(0);
+    let key = &key[..key_len];
+    let accepted = match key.is_empty() {
+        true => Err(Reject::NoKey),
+        false => parse(request, key).and_then(|(seq, command)| {
+            match LAST_SEQ.fetch_max(seq, Ordering::Relaxed) < seq {
+                true => Ok((seq, command)),
+                false => Err(Reject::Replayed),
+            }
+        }),
+    };
+    let (seq, command) = match accepted {
+        Ok(r) => r,
+        Err(e) => {
+            serial::write_fmt(format_args!("[remote] dropped request: {}\r\n", e.as_str()));
+            return None;
+        }
+    };
+    reply[..4].copy_from_slice(REPLY_MAGIC);
+    reply[4..HEADER_LEN].copy_from_slice(&seq.to_le_bytes());
+    let room = reply.len() - HEADER_LEN - 1 - SHA256_LEN;
+    let (status, len) = match shell_line(command) {
+        Some(line) => shell::run_captured(line, |out, cut| {
+            let n = out.len().min(room);
+            reply[HEADER_LEN + 1..HEADER_LEN + 1 + n].copy_from_slice(&out[..n]);
+            (if cut || n < out.len() { Status::Truncated } else { Status::Ok }, n)
+        }),
+        None => (Status::Denied, 0),
+    };
+    serial::write_fmt(format_args!("[remote] seq {} '{}': {:?}\r\n", seq, command, status));
+    reply[HEADER_LEN] = status as u8;
+    let end = HEADER_LEN + 1 + len;
+    let tag = hash::hmac_sha256(key, &[&reply[..end]]);
+    reply[end..end + SHA256_LEN].copy_from_slice(&tag);
+    Some(end + SHA256_LEN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(seq: u64, command: &str, key: &[u8], out: &mut [u8]) -> usize {
+        out[..4].copy_from_slice(REQUEST_MAGIC);
+        out[4..HEADER_LEN].copy_from_slice(&seq.to_le_bytes());
+        let end = HEADER_LEN + command.len();
+        out[HEADER_LEN..end].copy_from_slice(command.as_bytes());
+        let tag = hash::hmac_sha256(key, &[&out[..end]]);
+        out[end..end + SHA256_LEN].copy_from_slice(&tag);
+        end + SHA256_LEN
+    }
+
+    #[test]
+    fn parses_signed_requests_only() {
+        let mut buf = [0u8; 128];
+        let n = request(7, "ai status", b"secret", &mut buf);
+        assert_eq!(parse(&buf[..n], b"secret"), Ok((7, "ai status")));
+        assert_eq!(parse(&buf[..n], b"Secret"), Err(Reject::BadTag));
+        buf[HEADER_LEN] ^= 1;
+        assert_eq!(parse(&buf[..n], b"secret"), Err(Reject::BadTag));
+        assert_eq!(parse(&buf[..20], b"secret"), Err(Reject::Malformed));
+    }
+
+    #[test]
+    fn whitelist_is_exact() {
+        assert_eq!(shell_line("dmesg"), Some("cat proc/log"));
+        assert_eq!(shell_line("ai status"), Some("ai"));
+        assert_eq!(shell_line("ai replay x"), None);
+        assert_eq!(shell_line("stats | rm cfg"), None);
+    }
+}
@@ -0,0 +1,97 @@
+//! Kernel random numbers: RDRAND when the CPU has it, otherwise a splitmix64
+//! stream reseeded with TSC jitter. Good enough for layout randomization, not
+//! for key material.
+
+use core::arch::x86_64::{__cpuid, _rdrand64_step};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+
+use crate::time;
+
+const RDRAND_UNKNOWN: u8 = 0;
+const RDRAND_ABSENT: u8 = 1;
+const RDRAND_PRESENT: u8 = 2;
+/// Intel recommends retrying a failed RDRAND up to ten times before giving up.
+const RDRAND_RETRIES: usize = 10;
+
+static RDRAND: AtomicU8 = AtomicU8::new(RDRAND_UNKNOWN);
+static STATE: AtomicU64 = AtomicU64::new(0);
+
+fn has_rdrand() -> bool {
+    match RDRAND.load(Ordering::Relaxed) {
+        RDRAND_PRESENT => true,
+        RDRAND_ABSENT => false,
+        _ => {
+            // CPUID.01H:ECX bit 30
+            let present = __cpuid(1).ecx & (1 << 30) != 0;
+            RDRAND.store(if present { RDRAND_PRESENT } else { RDRAND_ABSENT }, Ordering::Relaxed);
+            present
+        }
+    }
+}
+
+#[target_feature(enable = "rdrand")]
+unsafe fn rdrand() -> Option<u64> {
+    let mut value = 0u64;
+    for _ in 0..RDRAND_RETRIES {
+        if _rdrand64_step(&mut value) == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn splitmix(state: u64) -> u64 {
+    let mut z = state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+fn fallback() -> u64 {
+    let step = 0x9E37_79B9_7F4A_7C15 ^ time::rdtsc();
+    let state = STATE.fetch_add(step, Ordering::Relaxed).wrapping_add(step);
+    splitmix(state)
+}
+
+/// Whether `next_u64` is backed by the hardware generator.
+pub fn is_hardware() -> bool {
+    has_rdrand()
+}
+
+pub fn next_u64() -> u64 {
+    if has_rdrand() {
+        if let Some(value) = unsafe { rdrand() } {
+            return value;
+        }
+    }
+    fallback()
+}
+
+/// Uniform-enough value in `0..bound` (modulo bias is irrelevant at the bounds we use).
+pub fn below(bound: u64) -> u64 {
+    if bound == 0 {
+        return 0;
+    }
+    next_u64() % bound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splitmix_spreads_adjacent_states() {
+        let a = splitmix(1);
+        let b = splitmix(2);
+        assert_ne!(a, b);
+        assert!((a ^ b).count_ones() > 16);
+    }
+
+    #[test]
+    fn below_stays_in_range() {
+        for _ in 0..64 {
+            assert!(below(7) < 7);
+        }
+        assert_eq!(below(0), 0);
+    }
+}
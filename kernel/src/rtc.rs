@@ -0,0 +1,180 @@
+//! CMOS RTC: register access, plus its periodic interrupt used as a second
+//! clock. Every few seconds the PIT tick count and the TSC are compared with
+//! the RTC's crystal; the measured PIT rate replaces the assumed one in
+//! `time`, and a large disagreement with `timer.hz` or the TSC calibration
+//! is logged.
+
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::{interrupts, port::Port};
+
+use crate::{config, idt, irq, pic, serial, time};
+use crate::status::{self, Health};
+
+/// Setting bit 7 of the index keeps NMIs masked while we touch CMOS.
+const NMI_DISABLE: u8 = 0x80;
+const REG_A: u8 = 0x0A;
+const REG_B: u8 = 0x0B;
+const REG_C: u8 = 0x0C;
+/// Periodic interrupt enable in register B.
+const REG_B_PIE: u8 = 0x40;
+/// Periodic interrupt flag in register C.
+const REG_C_PF: u8 = 0x40;
+/// Rate select: 32768 >> (rate - 1) Hz, so 13 gives 8 Hz.
+const RATE: u8 = 13;
+pub const HZ: u64 = 8;
+const IRQ_LINE: u8 = 8;
+/// RTC ticks per comparison window (4 s).
+const WINDOW_TICKS: u64 = 4 * HZ;
+/// Disagreement worth logging, in parts per million.
+const DRIFT_WARN_PPM: u64 = 20_000;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static WINDOW_PIT: AtomicU64 = AtomicU64::new(0);
+static WINDOW_TSC: AtomicU64 = AtomicU64::new(0);
+static SAMPLE_PIT: AtomicU64 = AtomicU64::new(0);
+static SAMPLE_TSC: AtomicU64 = AtomicU64::new(0);
+static SAMPLE_READY: AtomicBool = AtomicBool::new(false);
+static MEASURED: AtomicBool = AtomicBool::new(false);
+
+pub fn cmos_read(reg: u8) -> u8 {
+    unsafe {
+        Port::<u8>::new(0x70).write(NMI_DISABLE | reg);
+        Port::<u8>::new(0x71).read()
+    }
+}
+
+pub fn cmos_write(reg: u8, value: u8) {
+    unsafe {
+        Port::<u8>::new(0x70).write(NMI_DISABLE | reg);
+        Port::<u8>::new(0x71).write(value);
+    }
+}
+
+/// Start the periodic interrupt if `irq.unmask` lets IRQ 8 through.
+pub fn init() {
+    if pic::enabled_lines() & (1 << IRQ_LINE) == 0 {
+        serial::write_str("[rtc] irq 8 masked, drift check disabled\r\n");
+        status::set("rtc", Health::Skipped, "irq 8 masked");
+        return;
+    }
+    if let Err(e) = irq::register_handler(IRQ_LINE, "rtc", on_interrupt) {
+        serial::write_fmt(format_args!("[rtc] irq 8: {}\r\n", e.as_str()));
+        status::set("rtc", Health::Failed, e.as_str());
+        return;
+    }
+    interrupts::without_interrupts(|| {
+        let a = cmos_read(REG_A);
+        cmos_write(REG_A, (a & 0xF0) | RATE);
+        let b = cmos_read(REG_B);
+        cmos_write(REG_B, b | REG_B_PIE);
+        // Drop any pending flag so the first interrupt is a fresh period
+        cmos_read(REG_C);
+    });
+    serial::write_fmt(format_args!("[rtc] periodic interrupt at {} Hz\r\n", HZ));
+}
+
+/// IRQ 8 handler. Register C must be read or the RTC stops interrupting;
+/// the interrupt is the RTC's if it flags a periodic one.
+fn on_interrupt() -> bool {
+    if cmos_read(REG_C) & REG_C_PF == 0 {
+        return false;
+    }
+    let ticks = TICKS.fetch_add(1, Ordering::Relaxed) + 1;
+    if !ticks.is_multiple_of(WINDOW_TICKS) {
+        return true;
+    }
+    let pit = idt::timer_ticks();
+    let tsc = time::rdtsc();
+    let start_tsc = WINDOW_TSC.swap(tsc, Ordering::Relaxed);
+    let start_pit = WINDOW_PIT.swap(pit, Ordering::Relaxed);
+    // The first window only sets the starting point
+    if start_tsc != 0 {
+        SAMPLE_PIT.store(pit.wrapping_sub(start_pit), Ordering::Relaxed);
+        SAMPLE_TSC.store(tsc.wrapping_sub(start_tsc), Ordering::Relaxed);
+        SAMPLE_READY.store(true, Ordering::Release);
+    }
+    true
+}
+
+/// One window measured against the RTC.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct Measurement {
+    pit_millihz: u64,
+    tsc_per_ms: u64,
+}
+
+impl Measurement {
+    fn from_window(pit_ticks: u64, tsc_cycles: u64) -> Self {
+        let window_ms = WINDOW_TICKS * 1000 / HZ;
+        Measurement {
+            pit_millihz: pit_ticks * 1_000_000 / window_ms,
+            tsc_per_ms: tsc_cycles / window_ms,
+        }
+    }
+}
+
+/// |measured - expected| in ppm of `expected` (0 when nothing is expected).
+fn drift_ppm(measured: u64, expected: u64) -> u64 {
+    if expected == 0 {
+        return 0;
+    }
+    measured.abs_diff(expected).saturating_mul(1_000_000) / expected
+}
+
+/// Called from the idle loop: apply a finished window and report drift.
+pub fn check_drift() {
+    if !SAMPLE_READY.swap(false, Ordering::Acquire) {
+        return;
+    }
+    let m = Measurement::from_window(SAMPLE_PIT.load(Ordering::Relaxed), SAMPLE_TSC.load(Ordering::Relaxed));
+    let previous = time::tick_millihz();
+    time::set_tick_millihz(m.pit_millihz);
+    let first = !MEASURED.swap(true, Ordering::Relaxed);
+    if first || drift_ppm(m.pit_millihz, previous) >= DRIFT_WARN_PPM {
+        serial::write_fmt(format_args!(
+            "[rtc] timer measured at {}.{:03} Hz, calibration factor {} ppm of the assumed {}.{:03} Hz\r\n",
+            m.pit_millihz / 1000,
+            m.pit_millihz % 1000,
+            m.pit_millihz.saturating_mul(1_000_000) / previous.max(1),
+            previous / 1000,
+            previous % 1000
+        ));
+    }
+    let nominal = config::get_u64("timer.hz").unwrap_or(0) * 1000;
+    if first && drift_ppm(m.pit_millihz, nominal) >= DRIFT_WARN_PPM {
+        serial::write_fmt(format_args!("[rtc] timer.hz = {} does not match the measured rate\r\n", nominal / 1000));
+    }
+    let calibrated = time::tsc_per_ms();
+    let tsc_drift = drift_ppm(m.tsc_per_ms, calibrated);
+    if calibrated != 0 && tsc_drift >= DRIFT_WARN_PPM {
+        serial::write_fmt(format_args!(
+            "[rtc] TSC drift: {} cycles/ms vs calibrated {} ({} ppm)\r\n",
+            m.tsc_per_ms, calibrated, tsc_drift
+        ));
+    }
+}
+
+/// RTC ticks since `init`.
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_gives_rates() {
+        // 4 s window: 73 PIT ticks and 8e9 cycles
+        let m = Measurement::from_window(73, 8_000_000_000);
+        assert_eq!(m, Measurement { pit_millihz: 18_250, tsc_per_ms: 2_000_000 });
+    }
+
+    #[test]
+    fn drift_is_relative_to_expected() {
+        assert_eq!(drift_ppm(18_206, 18_000), 11_444);
+        assert_eq!(drift_ppm(1_000, 1_000), 0);
+        assert_eq!(drift_ppm(5, 0), 0);
+        assert!(drift_ppm(18_206, 1_000_000) >= DRIFT_WARN_PPM);
+    }
+}
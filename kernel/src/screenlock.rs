@@ -0,0 +1,188 @@
+//! Inactivity blanking and passphrase lock for the VGA/PS/2 console.
+//!
+//! After `console.blank_min` minutes without a key press the VGA output is
+//! switched off (text memory is kept, so nothing needs redrawing). A key
+//! brings it back. If `console.lock` holds a passphrase, waking leads to a
+//! lock screen instead, and the shell gets no input until it is typed.
+//! The serial line is not affected.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+use crate::keyboard::{self, Focus, Key};
+use crate::theme::{self, Slot};
+use crate::{config, serial, time, vga};
+
+const STATE_ACTIVE: u8 = 0;
+const STATE_BLANKED: u8 = 1;
+const STATE_LOCKED: u8 = 2;
+const MAX_PASS: usize = 64;
+
+static STATE: AtomicU8 = AtomicU8::new(STATE_ACTIVE);
+
+struct LockScreen {
+    saved: Option<vga::Snapshot>,
+    typed: [u8; MAX_PASS],
+    len: usize,
+}
+
+static LOCK: Mutex<LockScreen> = Mutex::new(LockScreen { saved: None, typed: [0; MAX_PASS], len: 0 });
+
+fn blank_after_ms() -> Option<u64> {
+    match config::get_u64("console.blank_min") {
+        Some(0) | None => None,
+        Some(min) => Some(min.saturating_mul(60_000)),
+    }
+}
+
+fn has_passphrase() -> bool {
+    config::with("console.lock", |v| !v.is_empty()).unwrap_or(false)
+}
+
+/// Comparison that does not stop at the first differing byte.
+fn passphrase_matches(typed: &[u8], expected: &[u8]) -> bool {
+    let mut diff = typed.len() ^ expected.len();
+    for (i, &b) in expected.iter().enumerate() {
+        diff |= (b ^ typed.get(i).copied().unwrap_or(0)) as usize;
+    }
+    diff == 0
+}
+
+fn idle_expired(idle_ms: u64, limit_ms: Option<u64>) -> bool {
+    limit_ms.is_some_and(|limit| idle_ms >= limit)
+}
+
+fn blank() {
+    keyboard::take_focus(Focus::Lock);
+    vga::set_enabled(false);
+    STATE.store(STATE_BLANKED, Ordering::Relaxed);
+}
+
+fn unblank() {
+    vga::set_enabled(true);
+    keyboard::release_focus(Focus::Lock);
+    STATE.store(STATE_ACTIVE, Ordering::Relaxed);
+}
+
+fn draw_lock_prompt(lock: &LockScreen) {
+    let row = vga::ROWS / 2;
+    vga::clear_row(row, theme::get(Slot::Headline));
+    let label = b" console locked - passphrase: ";
+    vga::write_at(row, 0, label, theme::get(Slot::Headline));
+    let mut stars = [b'*'; MAX_PASS];
+    stars[lock.len..].fill(b' ');
+    vga::write_at(row, label.len(), &stars[..lock.len], theme::get(Slot::Headline));
+}
+
+/// Lock right away (the `lock` shell command). False if no passphrase is set.
+pub fn lock_now() -> bool {
+    if !has_passphrase() {
+        return false;
+    }
+    enter_lock();
+    true
+}
+
+fn enter_lock() {
+    keyboard::take_focus(Focus::Lock);
+    vga::set_enabled(true);
+    let mut lock = LOCK.lock();
+    if lock.saved.is_none() {
+        lock.saved = Some(vga::snapshot());
+    }
+    lock.len = 0;
+    for row in 0..vga::ROWS {
+        vga::clear_row(row, 0x00);
+    }
+    draw_lock_prompt(&lock);
+    STATE.store(STATE_LOCKED, Ordering::Relaxed);
+}
+
+fn step_locked() {
+    let mut lock = LOCK.lock();
+    while let Some(key) = keyboard::poll_key(Focus::Lock) {
+        match key {
+            Key::Enter => {
+                let ok = config::with("console.lock", |v| passphrase_matches(&lock.typed[..lock.len], v.as_bytes()))
+                    .unwrap_or(true);
+                lock.len = 0;
+                if ok {
+                    if let Some(saved) = lock.saved.take() {
+                        vga::restore(&saved);
+                    }
+                    serial::write_str("[console] unlocked\r\n");
+                    unblank();
+                    return;
+                }
+                serial::write_str("[console] wrong passphrase\r\n");
+            }
+            Key::Backspace => lock.len = lock.len.saturating_sub(1),
+            Key::Escape => lock.len = 0,
+            Key::Char(c) if lock.len < MAX_PASS && c.is_ascii() => {
+                let at = lock.len;
+                lock.typed[at] = c as u8;
+                lock.len += 1;
+            }
+            _ => {}
+        }
+    }
+    draw_lock_prompt(&lock);
+}
+
+/// Called from the idle loop. Returns whether the shell may consume input.
+pub fn step() -> bool {
+    match STATE.load(Ordering::Relaxed) {
+        STATE_ACTIVE => {
+            let per_ms = time::tsc_per_ms();
+            if per_ms == 0 {
+                return true;
+            }
+            // Before the first key press, idle time counts from boot
+            let idle = time::rdtsc().wrapping_sub(keyboard::last_input_tsc()).min(time::since_boot());
+            let idle_ms = idle / per_ms;
+            if idle_expired(idle_ms, blank_after_ms()) {
+                serial::write_str("[console] idle, blanking screen\r\n");
+                blank();
+                return false;
+            }
+            true
+        }
+        STATE_BLANKED => {
+            if keyboard::poll_key(Focus::Lock).is_none() {
+                return false;
+            }
+            // The wake-up key is swallowed
+            while keyboard::poll_key(Focus::Lock).is_some() {}
+            if has_passphrase() {
+                enter_lock();
+            } else {
+                unblank();
+            }
+            false
+        }
+        _ => {
+            step_locked();
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn passphrase_needs_exact_match() {
+        assert!(passphrase_matches(b"open sesame", b"open sesame"));
+        assert!(!passphrase_matches(b"open", b"open sesame"));
+        assert!(!passphrase_matches(b"open sesame!", b"open sesame"));
+        assert!(!passphrase_matches(b"", b"x"));
+    }
+
+    #[test]
+    fn zero_timeout_never_blanks() {
+        assert!(!idle_expired(u64::MAX, None));
+        assert!(idle_expired(60_000, Some(60_000)));
+        assert!(!idle_expired(59_999, Some(60_000)));
+    }
+}
@@ -1,10 +1,16 @@
 use core::fmt::{self, Write};
 use core::panic::PanicInfo;
-use core::sync::atomic::{AtomicBool, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use spin::Mutex;
 use x86_64::instructions::port::Port;
 
+use crate::{power, time, xmodem};
+
 const COM1_BASE: u16 = 0x3F8;
+/// Bytes kept from the end of the output, for crash dumps and the log view.
+pub const TAIL_LEN: usize = 8192;
+/// Assumed TSC rate for receive timeouts when calibration never ran (1 GHz).
+const FALLBACK_TSC_PER_MS: u64 = 1_000_000;
 
 pub fn init() {
     dbg_str("serial: init start\n");
@@ -15,9 +21,23 @@ pub fn init() {
         }
     }
     INITIALIZED.store(true, Ordering::Release);
+    power::register_hook("serial", flush);
     dbg_str("serial: init done\n");
 }
 
+/// Shutdown hook: wait until the transmitter has sent its last byte, so a
+/// power off does not cut the final lines short.
+fn flush() -> Result<(), &'static str> {
+    let mut serial = SERIAL.lock();
+    for _ in 0..1_000_000 {
+        if serial.transmitter_empty() {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err("transmitter still busy")
+}
+
 pub fn write_str(message: &str) {
     dbg_str("serial: write_str\n");
     if !is_ready() {
@@ -49,6 +69,91 @@ pub fn panic(info: &PanicInfo) {
     let _ = writeln!(serial, "panic: {info}");
 }
 
+/// Copy the last bytes written that fit in `out` (oldest first, without
+/// `\r`). Gives up rather than wait if the port is locked, so the panic
+/// path can call it.
+pub fn tail(out: &mut [u8]) -> usize {
+    SERIAL.try_lock().map_or(0, |serial| serial.tail.copy_to(out))
+}
+
+/// Bytes written since boot, to tell whether the tail has changed.
+pub fn written() -> u64 {
+    WRITTEN.load(Ordering::Relaxed)
+}
+
+/// The port as a binary link for file transfers. While one exists, text
+/// output goes to debugcon instead so it cannot corrupt the stream.
+pub struct Raw(());
+
+impl Raw {
+    pub fn begin() -> Raw {
+        RAW.store(true, Ordering::Release);
+        Raw(())
+    }
+}
+
+impl Drop for Raw {
+    fn drop(&mut self) {
+        RAW.store(false, Ordering::Release);
+    }
+}
+
+impl xmodem::Link for Raw {
+    fn send(&mut self, bytes: &[u8]) {
+        let mut serial = SERIAL.lock();
+        for &b in bytes {
+            serial.transmit(b);
+        }
+    }
+
+    fn recv(&mut self, timeout_ms: u64) -> Option<u8> {
+        let per_ms = match time::tsc_per_ms() {
+            0 => FALLBACK_TSC_PER_MS,
+            per_ms => per_ms,
+        };
+        let start = time::rdtsc();
+        while time::rdtsc().wrapping_sub(start) < timeout_ms.saturating_mul(per_ms) {
+            if let Some(b) = SERIAL.lock().receive() {
+                return Some(b);
+            }
+            core::hint::spin_loop();
+        }
+        None
+    }
+}
+
+/// Ring of the most recent output bytes.
+struct Tail {
+    bytes: [u8; TAIL_LEN],
+    next: usize,
+    wrapped: bool,
+}
+
+impl Tail {
+    const fn new() -> Self {
+        Self { bytes: [0; TAIL_LEN], next: 0, wrapped: false }
+    }
+
+    fn push(&mut self, byte: u8) {
+        self.bytes[self.next] = byte;
+        self.next = (self.next + 1) % TAIL_LEN;
+        self.wrapped |= self.next == 0;
+        WRITTEN.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn copy_to(&self, out: &mut [u8]) -> usize {
+        let older = if self.wrapped { &self.bytes[self.next..] } else { &self.bytes[..0] };
+        let newer = &self.bytes[..self.next];
+        let len = out.len().min(older.len() + newer.len());
+        // Newest bytes first, then what fits of the older part
+        let from_newer = len.min(newer.len());
+        let from_older = len - from_newer;
+        out[..from_older].copy_from_slice(&older[older.len() - from_older..]);
+        out[from_older..len].copy_from_slice(&newer[newer.len() - from_newer..]);
+        len
+    }
+}
+
 struct SerialPort {
     data: Port<u8>,
     interrupt_enable: Port<u8>,
@@ -56,6 +161,7 @@ struct SerialPort {
     line_control: Port<u8>,
     modem_control: Port<u8>,
     line_status: Port<u8>,
+    tail: Tail,
 }
 
 impl SerialPort {
@@ -67,6 +173,7 @@ impl SerialPort {
             line_control: Port::new(base + 3),
             modem_control: Port::new(base + 4),
             line_status: Port::new(base + 5),
+            tail: Tail::new(),
         }
     }
 
@@ -85,7 +192,15 @@ impl SerialPort {
         if byte == b'\n' {
             self.write_byte(b'\r');
         }
+        if byte != b'\r' {
+            self.tail.push(byte);
+        }
+        self.transmit(byte);
+        dbg_str("serial: byte sent\n");
+    }
 
+    /// Send `byte` as is once the transmitter has room.
+    fn transmit(&mut self, byte: u8) {
         let mut spins: usize = 0;
         loop {
             let status = unsafe { self.line_status.read() };
@@ -106,7 +221,17 @@ impl SerialPort {
         unsafe {
             self.data.write(byte);
         }
-        dbg_str("serial: byte sent\n");
+    }
+
+    /// Holding register and shift register both empty (LSR TEMT).
+    fn transmitter_empty(&mut self) -> bool {
+        let status = unsafe { self.line_status.read() };
+        status & 0x40 != 0
+    }
+
+    fn receive(&mut self) -> Option<u8> {
+        let ready = unsafe { self.line_status.read() } & 0x01 != 0;
+        ready.then(|| unsafe { self.data.read() })
     }
 }
 
@@ -121,9 +246,12 @@ impl Write for SerialPort {
 
 static SERIAL: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1_BASE));
 static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static RAW: AtomicBool = AtomicBool::new(false);
+static WRITTEN: AtomicU64 = AtomicU64::new(0);
 
+/// Whether text output may use the port.
 fn is_ready() -> bool {
-    INITIALIZED.load(Ordering::Acquire)
+    INITIALIZED.load(Ordering::Acquire) && !RAW.load(Ordering::Acquire)
 }
 
 fn dbg_str(msg: &str) {
@@ -166,3 +294,30 @@ fn dbg_hex(prefix: &str, value: u8) {
         port.write(b'\n');
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tail_keeps_the_newest_bytes_in_order() {
+        let mut tail = Tail::new();
+        let mut out = [0u8; TAIL_LEN];
+        tail.push(b'a');
+        tail.push(b'b');
+        let len = tail.copy_to(&mut out);
+        assert_eq!(&out[..len], b"ab");
+        for i in 0..TAIL_LEN + 3 {
+            tail.push(b'0' + (i % 10) as u8);
+        }
+        assert_eq!(tail.copy_to(&mut out), TAIL_LEN);
+        // "ab" and the first three digits fell out
+        assert_eq!(out[0], b'3');
+        assert_eq!(out[TAIL_LEN - 1], b'0' + ((TAIL_LEN + 2) % 10) as u8);
+        // A short buffer gets the newest bytes
+        let mut short = [0u8; 4];
+        assert_eq!(tail.copy_to(&mut short), 4);
+        assert_eq!(short[3], out[TAIL_LEN - 1]);
+        assert_eq!(short[0], out[TAIL_LEN - 4]);
+    }
+}
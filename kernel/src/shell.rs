@@ -1,105 +1,1004 @@
 use crate::{serial, vga};
-use crate::keyboard;
+use crate::keyboard::{self, Key};
 use crate::ramfs;
+use crate::procfs;
 use crate::pmm;
 use crate::idt;
+use crate::pic;
 use crate::apply_action;
+use crate::journal;
+use crate::config;
+use crate::kv;
+use crate::pstore;
+use crate::ktrace;
+use crate::process;
+use crate::syscall;
+use crate::bootreason;
+use crate::bootinfo::{self, BootInfo, MemoryRegionKind};
+use crate::driver;
+use crate::time;
+use crate::xhci;
+use crate::usb_class;
+use crate::usb_state;
+use crate::status;
+use crate::kaslr;
+use crate::smbios;
+use crate::xmodem;
+use crate::viewer;
+use crate::editor;
+#[cfg(feature = "debug_tools")]
+use crate::memdbg;
+use crate::textutil;
+use crate::theme;
+use crate::hash;
+use crate::base64;
+use crate::bench;
+use crate::fastmem;
+use crate::jobs;
+use crate::sync::IrqMutex;
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use spin::Mutex;
 
-static mut LINE: [u8; 256] = [0; 256];
-static mut LEN: usize = 0;
+const LINE_CAP: usize = 256;
+
+/// Printable characters typed since the last prompt.
+struct LineBuf {
+    bytes: [u8; LINE_CAP],
+    len: usize,
+}
+
+/// Interrupts stay off while it is held, so keyboard input delivered from
+/// the IRQ handler can never see a half-done edit.
+static LINE: IrqMutex<LineBuf> = IrqMutex::new(LineBuf { bytes: [0; LINE_CAP], len: 0 });
 
 pub fn step() {
-    while let Some(c) = keyboard::poll_char() {
-        match c {
-            '\n' => { execute_line(); clear_line(); prompt(); }
-            '\x08' => { if unsafe { LEN } > 0 { unsafe { LEN -= 1; } } }
+    while let Some(input) = keyboard::poll_input(keyboard::Focus::Shell) {
+        match input.ch {
+            '\n' => {
+                keyboard::echo(&input);
+                // Run the command on a copy: it may take a while and the lock
+                // keeps interrupts off
+                let (bytes, len) = {
+                    let mut line = LINE.lock();
+                    let taken = (line.bytes, line.len);
+                    line.len = 0;
+                    taken
+                };
+                execute_line(&bytes[..len]);
+                prompt();
+            }
+            '\x08' => {
+                let mut line = LINE.lock();
+                if line.len > 0 {
+                    line.len -= 1;
+                    keyboard::echo(&input);
+                }
+            }
             ch if (ch as u32) >= 32 && (ch as u32) < 127 => {
-                if unsafe { LEN } < unsafe { LINE.len() } { unsafe { LINE[LEN] = ch as u8; LEN += 1; } }
+                let mut line = LINE.lock();
+                if line.len < LINE_CAP {
+                    let at = line.len;
+                    line.bytes[at] = ch as u8;
+                    line.len += 1;
+                    keyboard::echo(&input);
+                }
             }
             _ => {}
         }
     }
 }
 
+/// Device lifecycle states, then the transitions that led there.
+/// `usb remember` lists the devices remembered per port, `usb forget`
+/// drops entries so the next enumeration takes the full path.
+fn usb_known_cmd(verb: &str, rest: &str) {
+    if verb == "remember" {
+        let mut any = false;
+        crate::usb_known::for_each(|port, k| {
+            any = true;
+            match machine() {
+                true => write_fmt(format_args!(
+                    "usb_known port={} vendor={:04x} product={:04x} cfg={} cfg_len={} ep={:#04x} interval={}\n",
+                    port, k.vendor, k.product, k.config_value, k.config_len, k.endpoint, k.interval
+                )),
+                false => write_fmt(format_args!(
+                    "port {}: {:04x}:{:04x} cfg {} ({} bytes), ep {:#04x} every {}\n",
+                    port, k.vendor, k.product, k.config_value, k.config_len, k.endpoint, k.interval
+                )),
+            }
+        });
+        if !any && !machine() { writeln("no remembered usb devices"); }
+        return;
+    }
+    if rest == "all" {
+        write_fmt(format_args!("forgot {} port(s)\n", crate::usb_known::forget_all()));
+        return;
+    }
+    let Some(port) = parse_u64(rest).and_then(|p| u8::try_from(p).ok()) else {
+        writeln("usage: usb forget <port|all>");
+        return;
+    };
+    match crate::usb_known::forget(port) {
+        Ok(true) => write_fmt(format_args!("port {} forgotten\n", port)),
+        Ok(false) => write_fmt(format_args!("nothing remembered on port {}\n", port)),
+        Err(e) => writeln(e.as_str()),
+    }
+}
+
+fn usb_info() {
+    let mut any = false;
+    usb_state::for_each(|port, slot, state| {
+        any = true;
+        match (machine(), slot) {
+            (true, _) => write_fmt(format_args!("usb_device port={} slot={} state={}\n", port, slot.unwrap_or(0), state.as_str())),
+            (false, Some(slot)) => write_fmt(format_args!("port{} slot {} {}\n", port, slot, state.as_str())),
+            (false, None) => write_fmt(format_args!("port{} {}\n", port, state.as_str())),
+        }
+    });
+    if !any && !machine() { writeln("no usb devices"); }
+    let now = time::rdtsc();
+    usb_state::for_each_event(|e| {
+        let ago_ms = time::cycles_to_us(now.saturating_sub(e.tsc)).unwrap_or(0) / 1000;
+        match machine() {
+            true => write_fmt(format_args!(
+                "usb_event port={} from={} to={} ago_ms={}\n", e.port, e.from.as_str(), e.to.as_str(), ago_ms
+            )),
+            false => write_fmt(format_args!(
+                "  {} ms ago: port{} {} -> {}\n", ago_ms, e.port, e.from.as_str(), e.to.as_str()
+            )),
+        }
+    });
+}
+
+/// Run a command scheduled with `at` or `every`.
+pub fn run_job(id: u32, command: &str) {
+    write_fmt(format_args!("[job {}] {}\n", id, command));
+    execute_line(command.as_bytes());
+}
+
+/// Run `line` with its output collected instead of printed, for callers
+/// other than the console. `f` gets the output and whether it was cut.
+pub fn run_captured<R>(line: &str, f: impl FnOnce(&[u8], bool) -> R) -> R {
+    *PIPES[0].lock() = PipeBuf::new();
+    CAPTURE.store(1, Ordering::Relaxed);
+    execute(line, None);
+    CAPTURE.store(0, Ordering::Relaxed);
+    let pipe = PIPES[0].lock();
+    f(&pipe.data[..pipe.len], pipe.truncated)
+}
+
 fn prompt() {
     serial::write_str("$ ");
     vga::write_str("$ ");
 }
 
-fn clear_line() { unsafe { LEN = 0; } }
+/// Largest output one pipeline stage can hand to the next.
+const PIPE_CAP: usize = 4096;
 
-fn execute_line() {
-    let (cmd, arg) = unsafe {
-        let s = core::str::from_utf8_unchecked(&LINE[..LEN]);
-        split1(s)
-    };
+struct PipeBuf {
+    data: [u8; PIPE_CAP],
+    len: usize,
+    truncated: bool,
+}
+
+impl PipeBuf {
+    const fn new() -> Self {
+        Self { data: [0; PIPE_CAP], len: 0, truncated: false }
+    }
+
+    fn push(&mut self, bytes: &[u8]) {
+        let n = bytes.len().min(PIPE_CAP - self.len);
+        self.data[self.len..self.len + n].copy_from_slice(&bytes[..n]);
+        self.len += n;
+        self.truncated |= n < bytes.len();
+    }
+}
+
+impl fmt::Write for PipeBuf {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.push(s.as_bytes());
+        Ok(())
+    }
+}
+
+/// Stages alternate between the two buffers: one is being read while the other fills.
+static PIPES: [Mutex<PipeBuf>; 2] = [Mutex::new(PipeBuf::new()), Mutex::new(PipeBuf::new())];
+/// 1 + index of the pipe buffer receiving output, 0 when output goes to the console.
+static CAPTURE: AtomicUsize = AtomicUsize::new(0);
+
+fn capture() -> Option<&'static Mutex<PipeBuf>> {
+    match CAPTURE.load(Ordering::Relaxed) {
+        0 => None,
+        n => Some(&PIPES[n - 1]),
+    }
+}
+
+fn execute_line(line: &[u8]) {
+    // `step` only stores printable ASCII
+    let line = core::str::from_utf8(line).unwrap_or("");
+    let stages = line.split('|').count();
+    let mut input: Option<usize> = None;
+    for (i, stage) in line.split('|').enumerate() {
+        let output = (i + 1 < stages).then_some(i % 2);
+        if let Some(o) = output {
+            *PIPES[o].lock() = PipeBuf::new();
+        }
+        CAPTURE.store(output.map_or(0, |o| o + 1), Ordering::Relaxed);
+        match input {
+            Some(idx) => {
+                let pipe = PIPES[idx].lock();
+                execute(stage, Some(&pipe.data[..pipe.len]));
+            }
+            None => execute(stage, None),
+        }
+        CAPTURE.store(0, Ordering::Relaxed);
+        if let Some(o) = output {
+            if PIPES[o].lock().truncated {
+                write_fmt(format_args!("pipe: stage {} output cut at {} bytes\n", i + 1, PIPE_CAP));
+            }
+        }
+        input = output;
+    }
+}
+
+/// Commands that can print `<record> key=value ...` lines for scripts.
+const MACHINE_COMMANDS: &[&str] = &["mem", "ai", "stats", "usb", "pci", "status"];
+/// Set while a command runs in machine mode (`--kv` or `shell.output = machine`).
+static MACHINE: AtomicBool = AtomicBool::new(false);
+
+fn machine() -> bool {
+    MACHINE.load(Ordering::Relaxed)
+}
+
+/// Run one command. `input` is the previous pipeline stage's output, if any.
+fn execute(line: &str, input: Option<&[u8]>) {
+    let (cmd, mut arg) = split1(line);
+    let mut kv = false;
+    if MACHINE_COMMANDS.contains(&cmd) {
+        if let ("--kv", rest) = split1(arg) {
+            arg = rest;
+            kv = true;
+        }
+        kv |= config::with("shell.output", |v| v == "machine").unwrap_or(false);
+    }
+    MACHINE.store(kv, Ordering::Relaxed);
     match cmd {
         "" => {}
         "help" => {
-            writeln("Commands: help, ls, cat <path>, hexdump <path> [len], mem, uptime, ai, pci, reboot, sleep <ms>, yield");
+            writeln("Commands: help, ls, cat <path> (incl. proc/*), rm <path>, hexdump <path> [len] (paged: space, enter, q), sx <path>, log export [serial|journal], grep [-ivnc] <pat> [path], head|tail [-n N] [path], wc [path], crc32|sha256 [path], base64 [-d] <path>, view <path|addr>, edit <path> (^S save, ^Q quit), mem, memmap owners, uptime, ai [history|mode [off|observe|act]|canary [<file> <s>|promote|abort]|replay <file>|record <file> <s>] (mem/ai/stats/usb/pci/status take --kv for key=value output), journal verify, config list|get|set|save|forget, kv list|get|set|rm, pstore [read <section>|clear [section]], bootinfo, dmi, boottime, status, stats irq-latency [reset]|stacks|tasks|input|usb, xhci imod [us]|pace [ms], usb info|drivers|regs|suspend|resume|remember|forget <port|all>, lsdrv, irqcfg, theme [<slot> <colour>], trace start|stop|dump [file], strace [<pid> on|off], kill <pid>, bench [pmm|memcpy [KiB]|memset [KiB]|matmul [n]|switch], pci, stress mem <MiB> [s]|pf <rate> [s]|cpu <n> [s]|stop, at|every <ms> <cmd>, jobs, cancel <id>, lock, reboot, poweroff, sleep <ms>, yield (chain with `|`, e.g. `ls | grep cfg`)");
+            #[cfg(feature = "debug_tools")]
+            writeln("Debug: debug unlock|lock, peek <addr> [len], poke <addr> <byte..>");
         }
         "ls" => {
             ramfs::for_each(|e| {
                 if let Ok(name) = core::str::from_utf8(e.name) {
-                    serial::write_fmt(format_args!("{} {}\r\n", name, e.size));
-                    vga::write_str(name);
-                    vga::put_char(' ');
-                    print_num(e.size as u64);
-                    vga::put_char('\n');
+                    write_fmt(format_args!("{} {}\n", name, e.size));
                 }
             });
+            procfs::for_each(|name, size| write_fmt(format_args!("{} {}\n", name, size)));
+        }
+        "rm" => {
+            if arg.is_empty() { writeln("usage: rm <path>"); return; }
+            if !ramfs::remove(arg) { writeln("rm: not a writable file"); }
         }
         "cat" => {
-            if arg.is_empty() { writeln("usage: cat <path>"); return; }
-            if let Some((ptr, size)) = ramfs::find(arg) {
-                unsafe {
-                    let bytes = core::slice::from_raw_parts(ptr, size.min(1024));
-                    if let Ok(s) = core::str::from_utf8(bytes) { write_str(s); }
-                    else { writeln("(binary)" ); }
+            if arg.is_empty() {
+                match input { Some(text) => write_bytes(text), None => writeln("usage: cat <path>") }
+                return;
+            }
+            let found = with_file(arg, |bytes| {
+                if core::str::from_utf8(bytes).is_err() { writeln("(binary, try hexdump)"); return; }
+                let mut pager = Pager::new();
+                for line in bytes.split_inclusive(|&b| b == b'\n') {
+                    if !pager.rows(line.len().div_ceil(vga::COLUMNS).max(1)) { break; }
+                    write_bytes(line);
                 }
-            } else { writeln("not found"); }
+            });
+            if found.is_none() { writeln("not found"); }
         }
         "hexdump" => {
             if arg.is_empty() { writeln("usage: hexdump <path> [len]"); return; }
             let (path, rest) = split1(arg);
-            let mut dump_len: usize = 256;
+            let mut dump_len = usize::MAX;
             if !rest.is_empty() { if let Some(v) = parse_u64(rest) { dump_len = v as usize; } }
-            if let Some((ptr, size)) = ramfs::find(path) {
-                let n = core::cmp::min(size, dump_len);
-                unsafe {
-                    let bytes = core::slice::from_raw_parts(ptr, n);
-                    hex_dump(bytes);
+            if with_file(path, |bytes| hex_dump(&bytes[..bytes.len().min(dump_len)])).is_none() {
+                writeln("not found");
+            }
+        }
+        "grep" => {
+            let mut opts = textutil::GrepOptions::default();
+            let (mut numbers, mut count_only) = (false, false);
+            let mut rest = arg;
+            loop {
+                let (tok, tail) = split1(rest);
+                match tok {
+                    "-i" => opts.ignore_case = true,
+                    "-v" => opts.invert = true,
+                    "-n" => numbers = true,
+                    "-c" => count_only = true,
+                    _ => break,
+                }
+                rest = tail;
+            }
+            let (pattern, path) = split1(rest);
+            if pattern.is_empty() { writeln("usage: grep [-i] [-v] [-n] [-c] <pattern> [path]"); return; }
+            with_text(path, input, "usage: grep [-i] [-v] [-n] [-c] <pattern> [path]", |text| {
+                let count = textutil::grep(text, pattern.as_bytes(), opts, |n, line| {
+                    if count_only { return; }
+                    if numbers { write_fmt(format_args!("{}:", n)); }
+                    write_bytes(line);
+                    write_str("\n");
+                });
+                if count_only { write_fmt(format_args!("{}\n", count)); }
+            });
+        }
+        "head" | "tail" => {
+            let (lines, path) = match split1(arg) {
+                ("-n", rest) => {
+                    let (n, path) = split1(rest);
+                    match parse_u64(n) { Some(n) => (n as usize, path), None => { writeln("usage: head|tail [-n N] [path]"); return; } }
+                }
+                _ => (10, arg),
+            };
+            with_text(path, input, "usage: head|tail [-n N] [path]", |text| {
+                write_bytes(if cmd == "head" { textutil::head(text, lines) } else { textutil::tail(text, lines) });
+            });
+        }
+        "crc32" | "sha256" => {
+            // Same layout as crc32(1) and sha256sum, `-` naming piped input
+            let name = if arg.is_empty() { "-" } else { arg };
+            with_text(arg, input, "usage: crc32|sha256 [path]", |bytes| {
+                if cmd == "crc32" {
+                    write_fmt(format_args!("{:08x}  {}\n", hash::crc32(bytes), name));
+                } else {
+                    write_fmt(format_args!("{}  {}\n", hash::Hex(&hash::sha256(bytes)), name));
+                }
+            });
+        }
+        "base64" => match split1(arg) {
+            ("-d", rest) => {
+                let (path, size) = split1(rest);
+                let cap = if size.is_empty() { Some(B64_DEFAULT_CAP) } else { parse_u64(size).map(|v| v as usize) };
+                match (path, cap) {
+                    ("", _) | (_, None) => writeln("usage: base64 -d <path> [max bytes]"),
+                    (path, Some(cap)) => base64_decode(path, cap, input),
                 }
-            } else { writeln("not found"); }
+            }
+            _ => with_text(arg, input, "usage: base64 [path] | base64 -d <path> [max bytes]", base64_encode),
+        },
+        "wc" => {
+            with_text(arg, input, "usage: wc [path]", |text| {
+                let c = textutil::wc(text);
+                write_fmt(format_args!("{} {} {}\n", c.lines, c.words, c.bytes));
+            });
+        }
+        "view" => {
+            if arg.is_empty() { writeln("usage: view <path|addr>"); return; }
+            let shown = with_file(arg, |bytes| {
+                viewer::run(viewer::Source::File { data: bytes.as_ptr(), len: bytes.len() }, arg)
+            });
+            if shown.is_some() { return; }
+            #[cfg(feature = "debug_tools")]
+            if let Some(base) = parse_u64(arg) {
+                if !memdbg::is_unlocked() { writeln("view: memory is locked (run `debug unlock` first)"); return; }
+                viewer::run(viewer::Source::Memory { base }, arg);
+                return;
+            }
+            writeln("not found");
         }
+        "edit" => {
+            if arg.is_empty() { writeln("usage: edit <path>"); return; }
+            if let Err(e) = editor::run(arg) {
+                write_fmt(format_args!("edit: {}\n", e.as_str()));
+            }
+        }
+        "memmap" => match arg {
+            "owners" => {
+                if !crate::memown::ENABLED { writeln("memmap: ownership tracking needs the debug_tools feature"); return; }
+                let (mut count, mut bytes) = (0, 0);
+                let mut pager = Pager::new();
+                crate::memown::for_each(|r| {
+                    count += 1;
+                    bytes += r.end - r.base;
+                    if pager.rows(1) {
+                        write_fmt(format_args!("{:#012x}-{:#012x} {:>8} KiB  {}\n", r.base, r.end, (r.end - r.base) / 1024, r.owner));
+                    }
+                });
+                write_fmt(format_args!("{} ranges, {} KiB owned\n", count, bytes / 1024));
+                if crate::memown::overflowed() { writeln("map full: some allocations are untracked"); }
+            }
+            _ => writeln("usage: memmap owners"),
+        },
         "mem" => {
             let kib = pmm::free_kib();
+            let oom = crate::oom::stats();
+            if machine() {
+                write_fmt(format_args!("mem free_kib={}", kib));
+                for region in kaslr::Region::ALL {
+                    write_fmt(format_args!(" {}={:#x}", region.name(), kaslr::base(region)));
+                }
+                write_fmt(format_args!(" oom_failures={} oom_recovered={} oom_degraded={}\n", oom.failures, oom.recovered, oom.degraded as u8));
+                return;
+            }
             writeln_num("free_kib=", kib);
+            for region in kaslr::Region::ALL {
+                write_fmt(format_args!("{}={:#x}\n", region.name(), kaslr::base(region)));
+            }
+            if let Some(last) = oom.last {
+                write_fmt(format_args!(
+                    "allocation failures: {} ({} recovered){}; last: {} asked {} bytes with {} KiB free\n",
+                    oom.failures, oom.recovered, if oom.degraded { ", running degraded" } else { "" }, last.owner, last.size, last.free_kib
+                ));
+            }
         }
         "uptime" => {
             let t = idt::timer_ticks();
             writeln_num("ticks=", t);
         }
         "ai" => {
-            unsafe {
-                extern "C" { static mut AI_MODEL_ADDR: *const u8; static mut AI_MODEL_LEN: usize; }
-                let addr = AI_MODEL_ADDR as u64;
-                let len = AI_MODEL_LEN as u64;
-                writeln_num("ai_model_addr=", addr);
-                writeln_num("ai_model_len=", len);
+            let (sub, rest) = split1(arg);
+            if sub == "replay" {
+                ai_replay(rest);
+                return;
+            }
+            if sub == "record" {
+                ai_record(rest);
+                return;
+            }
+            if sub == "history" {
+                ai_history();
+                return;
+            }
+            if sub == "mode" {
+                ai_mode(rest);
+                return;
             }
+            if sub == "canary" {
+                ai_canary(rest);
+                return;
+            }
+            let (addr, len) = crate::ai_link::model();
+            if machine() {
+                write_fmt(format_args!(
+                    "ai model_addr={:#x} model_len={} system_ready={} quantum_us={} log_level={} interval_ms={}\n",
+                    addr as u64, len, apply_action::is_system_ready() as u8, apply_action::get_quantum_us(),
+                    crate::klog::level().as_str(), apply_action::get_ai_interval_ms()
+                ));
+                #[cfg(feature = "ai_agent")]
+                {
+                    let controller = crate::ai_agent::controller().map_or("none", |c| c.as_str());
+                    let backend = crate::ai_agent::backend().unwrap_or("none");
+                    let st = crate::ai_agent::reward_stats();
+                    write_fmt(format_args!(
+                        "ai_steps controller={} backend={} steps={} accepted={} rejected={} rolled_back={} errors={} over_budget={}\n",
+                        controller, backend, st.steps, st.accepted, st.rejected, st.rolled_back, st.errors, crate::ai_agent::budget_violations()
+                    ));
+                    if let Some(info) = crate::ai_agent::model_info() {
+                        let [major, minor, patch] = info.version;
+                        write_fmt(format_args!(
+                            "ai_model name={} version={}.{}.{} train_hash={}\n",
+                            info.name(), major, minor, patch, info.hash()
+                        ));
+                    }
+                    let st = crate::ai_agent::heuristic_stats();
+                    write_fmt(format_args!(
+                        "ai_heuristic steps={} accepted={} rejected={} rolled_back={} errors={}\n",
+                        st.steps, st.accepted, st.rejected, st.rolled_back, st.errors
+                    ));
+                }
+                return;
+            }
+            writeln_num("ai_model_addr=", addr as u64);
+            writeln_num("ai_model_len=", len as u64);
             let ready = apply_action::is_system_ready();
             serial::write_fmt(format_args!("system_ready={}\r\n", ready as u8));
             vga::write_line(if ready { "system_ready=1" } else { "system_ready=0" });
             let q = apply_action::get_quantum_us() as u64;
             writeln_num("quantum_us=", q);
+            write_fmt(format_args!(
+                "log_level={} ai_interval_ms={}\n",
+                crate::klog::level().as_str(),
+                apply_action::get_ai_interval_ms()
+            ));
+            #[cfg(feature = "ai_agent")]
+            {
+                let controller = crate::ai_agent::controller().map_or("none", |c| c.as_str());
+                let backend = crate::ai_agent::backend().unwrap_or("none");
+                write_fmt(format_args!("controller={} backend={}\n", controller, backend));
+                if let Some(info) = crate::ai_agent::model_info() {
+                    write_fmt(format_args!("model: {}\ntrain_hash={}\n", info, info.hash()));
+                }
+                let st = crate::ai_agent::reward_stats();
+                write_fmt(format_args!(
+                    "steps={} accepted={} rejected={} rolled_back={} errors={} over_budget={}\n",
+                    st.steps, st.accepted, st.rejected, st.rolled_back, st.errors, crate::ai_agent::budget_violations()
+                ));
+                let st = crate::ai_agent::heuristic_stats();
+                write_fmt(format_args!(
+                    "heuristic: steps={} accepted={} rejected={} rolled_back={} errors={}\n",
+                    st.steps, st.accepted, st.rejected, st.rolled_back, st.errors
+                ));
+            }
+        }
+        "journal" => {
+            if arg != "verify" { writeln("usage: journal verify"); return; }
+            let r = journal::verify();
+            write_fmt(format_args!(
+                "intents={} ok={} fail={} dangling={} corrupt={} unclean_total={}\n",
+                r.intents, r.committed, r.failed, r.dangling, r.corrupt, journal::dangling_intents()
+            ));
+            if let Some(seq) = r.first_dangling {
+                writeln_num("first_dangling_seq=", seq);
+            }
+        }
+        "config" => {
+            let (sub, rest) = split1(arg);
+            match sub {
+                "list" => config::for_each(|k, v| write_fmt(format_args!("{}={}\n", k, v))),
+                "get" => {
+                    if rest.is_empty() { writeln("usage: config get <key>"); return; }
+                    if config::with(rest, |v| write_fmt(format_args!("{}={}\n", rest, v))).is_none() {
+                        writeln("not set");
+                    }
+                }
+                "set" => {
+                    let (key, val) = split1(rest);
+                    if key.is_empty() { writeln("usage: config set <key> <value>"); return; }
+                    match config::set(key, val) {
+                        Ok(()) => write_fmt(format_args!("{}={}\n", key, val)),
+                        Err(e) => write_fmt(format_args!("config: {:?}\n", e)),
+                    }
+                }
+                "save" | "forget" => {
+                    if rest.is_empty() { write_fmt(format_args!("usage: config {} <key>\n", sub)); return; }
+                    let res = if sub == "save" { config::save(rest) } else { config::forget(rest) };
+                    match res {
+                        Ok(true) => {}
+                        Ok(false) => writeln("not set"),
+                        Err(e) => write_fmt(format_args!("config: {}\n", e.as_str())),
+                    }
+                }
+                _ => writeln("usage: config list|get <key>|set <key> <value>|save <key>|forget <key>"),
+            }
+        }
+        "kv" => {
+            let (sub, rest) = split1(arg);
+            let (key, val) = split1(rest);
+            match sub {
+                "list" => {
+                    kv::for_each(|k, v| match core::str::from_utf8(v) {
+                        Ok(text) if !v.iter().any(|b| b.is_ascii_control()) => write_fmt(format_args!("{}={}\n", k, text)),
+                        _ => write_fmt(format_args!("{}=<{} bytes>\n", k, v.len())),
+                    });
+                    if let Some((used, cap)) = kv::usage() {
+                        write_fmt(format_args!("{}/{} bytes used\n", used, cap));
+                    } else {
+                        writeln("kv: store not ready");
+                    }
+                }
+                "get" if !key.is_empty() => {
+                    if kv::get(key, hex_dump).is_none() { writeln("not set"); }
+                }
+                "set" if !key.is_empty() => {
+                    if let Err(e) = kv::set(key, val.as_bytes()) { write_fmt(format_args!("kv: {}\n", e.as_str())); }
+                }
+                "rm" if !key.is_empty() => match kv::remove(key) {
+                    Ok(true) => {}
+                    Ok(false) => writeln("not set"),
+                    Err(e) => write_fmt(format_args!("kv: {}\n", e.as_str())),
+                },
+                _ => writeln("usage: kv list|get <key>|set <key> <value>|rm <key>"),
+            }
+        }
+        "pstore" => match split1(arg) {
+            ("" | "list", _) => {
+                let Some(base) = pstore::base() else { writeln("pstore: not ready"); return };
+                write_fmt(format_args!("region {:#x}, {} warm boot(s) survived\n", base, pstore::boots().unwrap_or(0)));
+                pstore::for_each(|section, len| match len {
+                    Some(len) => write_fmt(format_args!("{:<10} {}/{} bytes\n", section.as_str(), len, section.capacity())),
+                    None => write_fmt(format_args!("{:<10} corrupt\n", section.as_str())),
+                });
+            }
+            ("read", name) => match pstore::Section::parse(name) {
+                Some(section @ (pstore::Section::Crash | pstore::Section::Log)) => {
+                    if pstore::read(section, write_bytes).is_none() { writeln("empty"); }
+                    writeln("");
+                }
+                Some(section) => {
+                    if pstore::read(section, hex_dump).is_none() { writeln("empty"); }
+                }
+                None => writeln("usage: pstore read bootreason|crash|journal|log"),
+            },
+            ("clear", name) => {
+                let section = match name {
+                    "" => None,
+                    name => match pstore::Section::parse(name) {
+                        Some(section) => Some(section),
+                        None => { writeln("usage: pstore clear [bootreason|crash|journal|log]"); return; }
+                    },
+                };
+                if let Err(e) = pstore::clear(section) { write_fmt(format_args!("pstore: {}\n", e.as_str())); }
+            }
+            _ => writeln("usage: pstore [list|read <section>|clear [section]]"),
+        },
+        "sx" => {
+            if arg.is_empty() { writeln("usage: sx <path>"); return; }
+            send_file(arg);
+        }
+        "log" => match split1(arg) {
+            ("export", "" | "serial") => send_file("proc/log"),
+            ("export", "journal") => send_file("proc/journal"),
+            _ => writeln("usage: log export [serial|journal]"),
+        },
+        "dmi" => write_dmi(),
+        "bootinfo" => {
+            if let Some(boot) = BootInfo::get() {
+                write_boot_info(boot);
+            }
+            write_fmt(format_args!(
+                "last boot: {}\nconsecutive crashes: {}\nai policy: {}\n",
+                bootreason::previous().as_str(),
+                bootreason::consecutive_crashes(),
+                if bootreason::crash_loop() { "disabled (crash loop)" } else { "enabled" }
+            ));
+        }
+        "boottime" => {
+            crate::init::write_report(write_fmt);
+        }
+        "stats" => {
+            let (sub, rest) = split1(arg);
+            if sub == "stacks" && machine() {
+                crate::stack::for_each_stack(|name, b, intact| write_fmt(format_args!(
+                    "stack name={} bottom={:#x} top={:#x} canary={}\n",
+                    name, b.bottom, b.top, if intact { "ok" } else { "smashed" }
+                )));
+                return;
+            }
+            if sub == "stacks" {
+                crate::stack::for_each_stack(|name, b, intact| write_fmt(format_args!(
+                    "{:<12} {:#x}..{:#x} {}K canary={}\n",
+                    name, b.bottom, b.top, (b.top - b.bottom) / 1024, if intact { "ok" } else { "SMASHED" }
+                )));
+                return;
+            }
+            if sub == "input" {
+                let st = keyboard::input_latency();
+                if machine() {
+                    write_fmt(format_args!(
+                        "input echoed={} samples={} p50_us={} p95_us={} max_us={}\n",
+                        st.echoed, st.samples, st.p50_us, st.p95_us, st.max_us
+                    ));
+                    return;
+                }
+                write_fmt(format_args!(
+                    "keypress-to-echo: {} keys echoed; last {} in window: p50 {} us p95 {} us max {} us\n",
+                    st.echoed, st.samples, st.p50_us, st.p95_us, st.max_us
+                ));
+                return;
+            }
+            if sub == "tasks" && machine() {
+                crate::executor::for_each_task(|id, name| write_fmt(format_args!("task id={} name={}\n", id.index(), name)));
+                #[cfg(feature = "ai_agent")]
+                write_fmt(format_args!("rr_slice us={}\n", crate::task::slice_us()));
+                #[cfg(feature = "ai_agent")]
+                crate::task::for_each(|slot, name, cycles, runs| write_fmt(format_args!(
+                    "rr slot={} name={} cpu_us={} runs={} boosted={}\n",
+                    slot, name, crate::time::cycles_to_us(cycles).unwrap_or(0), runs, crate::boost::active(name) as u8
+                )));
+                #[cfg(feature = "ai_agent")]
+                crate::task::for_each_background(|slot, name, pct, throttled| write_fmt(format_args!(
+                    "rr_budget slot={} name={} cpu_pct={} throttled={}\n", slot, name, pct, throttled
+                )));
+                crate::boost::for_each(|name, left| write_fmt(format_args!("boost name={} left_ms={}\n", name, left)));
+                return;
+            }
+            if sub == "tasks" {
+                if !crate::executor::has_tasks() { writeln("no async tasks"); }
+                crate::executor::for_each_task(|id, name| write_fmt(format_args!("task{} {}\n", id.index(), name)));
+                #[cfg(feature = "ai_agent")]
+                write_fmt(format_args!("rr slice {} us\n", crate::task::slice_us()));
+                #[cfg(feature = "ai_agent")]
+                crate::task::for_each(|slot, name, cycles, runs| {
+                    let us = crate::time::cycles_to_us(cycles).unwrap_or(0);
+                    let boosted = if crate::boost::active(name) { " (boosted)" } else { "" };
+                    write_fmt(format_args!("rr{} {} cpu {}.{:03} ms over {} runs{}\n", slot, name, us / 1000, us % 1000, runs, boosted));
+                });
+                #[cfg(feature = "ai_agent")]
+                crate::task::for_each_background(|slot, name, pct, throttled| write_fmt(format_args!(
+                    "rr{} {} background: {}% of each second, {} turns skipped over budget\n", slot, name, pct, throttled
+                )));
+                crate::boost::for_each(|name, left| write_fmt(format_args!("boost {} {} ms left\n", name, left)));
+                return;
+            }
+            if sub == "usb" {
+                let st = xhci::error_stats();
+                let pending = xhci::needs_recovery() as u8;
+                match machine() {
+                    true => write_fmt(format_args!(
+                        "usb_errors ring_full={} host_errors={} resets={} reset_failures={} reset_pending={}\n",
+                        st.ring_full, st.host_errors, st.resets, st.reset_failures, pending
+                    )),
+                    false => write_fmt(format_args!(
+                        "command ring full: {}  host errors: {}  controller resets: {} ({} failed){}\n",
+                        st.ring_full, st.host_errors, st.resets, st.reset_failures,
+                        if pending != 0 { ", reset pending" } else { "" }
+                    )),
+                }
+                return;
+            }
+            if sub != "irq-latency" { writeln("usage: stats irq-latency [reset] | stats stacks | stats tasks | stats input | stats usb"); return; }
+            if rest == "reset" {
+                idt::reset_irq_latency();
+                return;
+            }
+            if machine() {
+                idt::for_each_irq_latency(|s| write_fmt(format_args!(
+                    "irq line={} count={} mean_cyc={} max_cyc={} mean_ns={} max_ns={}\n",
+                    s.irq, s.count, s.mean_cycles, s.max_cycles,
+                    time::cycles_to_ns(s.mean_cycles).unwrap_or(0), time::cycles_to_ns(s.max_cycles).unwrap_or(0)
+                )));
+                let (nested, depth) = idt::irq_nesting();
+                write_fmt(format_args!("irq_nesting nested={} max_depth={}\n", nested, depth));
+                return;
+            }
+            let mut any = false;
+            idt::for_each_irq_latency(|s| {
+                any = true;
+                match (time::cycles_to_ns(s.mean_cycles), time::cycles_to_ns(s.max_cycles)) {
+                    (Some(mean), Some(max)) => write_fmt(format_args!(
+                        "irq{:<2} count={} mean_ns={} max_ns={}\n", s.irq, s.count, mean, max
+                    )),
+                    _ => write_fmt(format_args!(
+                        "irq{:<2} count={} mean_cyc={} max_cyc={}\n",
+                        s.irq, s.count, s.mean_cycles, s.max_cycles
+                    )),
+                }
+            });
+            if !any { writeln("no irqs recorded"); }
+            let (nested, depth) = idt::irq_nesting();
+            write_fmt(format_args!("nested={} max_depth={}\n", nested, depth));
+        }
+        "xhci" => {
+            let (sub, rest) = split1(arg);
+            let value = if rest.is_empty() { None } else {
+                match parse_u64(rest) {
+                    Some(v) => Some(v.min(u32::MAX as u64) as u32),
+                    None => { writeln("usage: xhci imod [us] | xhci pace [ms]"); return; }
+                }
+            };
+            match (sub, value) {
+                ("imod", None) => match xhci::interrupt_moderation() {
+                    Some(us) => writeln_num("imod_us=", us as u64),
+                    None => writeln("xhci: not initialized"),
+                },
+                ("imod", Some(us)) => match xhci::set_interrupt_moderation(us) {
+                    Ok(eff) => writeln_num("imod_us=", eff as u64),
+                    Err(e) => writeln(e),
+                },
+                ("pace", None) => match xhci::hid_pacing() {
+                    Some(ms) => writeln_num("pace_ms=", ms as u64),
+                    None => writeln("xhci: not initialized"),
+                },
+                ("pace", Some(ms)) => {
+                    if xhci::set_hid_pacing(ms) { writeln_num("pace_ms=", ms as u64); }
+                    else { writeln("xhci: not initialized"); }
+                }
+                _ => writeln("usage: xhci imod [us] | xhci pace [ms]"),
+            }
+        }
+        "usb" => {
+            let (verb, rest) = split1(arg);
+            if verb == "remember" || verb == "forget" {
+                usb_known_cmd(verb, rest);
+                return;
+            }
+            let result = match arg {
+                "drivers" => {
+                    usb_class::for_each_driver(|d| match machine() {
+                        true => write_fmt(format_args!("usb_driver name={}\n", d.name)),
+                        false => writeln(d.name),
+                    });
+                    return;
+                }
+                "info" => {
+                    usb_info();
+                    return;
+                }
+                "regs" => xhci::write_regs(write_fmt),
+                "suspend" | "resume" if !status::up("usb") => {
+                    let reason = status::get("usb").map_or("not probed", |e| e.reason);
+                    write_fmt(format_args!("usb: unavailable ({})\n", reason));
+                    return;
+                }
+                "suspend" => xhci::suspend(),
+                "resume" => xhci::resume(),
+                _ => {
+                    writeln("usage: usb info|drivers|regs|suspend|resume|remember|forget <port|all>");
+                    return;
+                }
+            };
+            if machine() {
+                write_fmt(format_args!("usb op={} ok={}\n", arg, result.is_ok() as u8));
+            }
+            if let Err(e) = result {
+                writeln(e);
+            }
+        }
+        "trace" => trace(arg),
+        "strace" => strace(arg),
+        "kill" => kill(arg),
+        "theme" => theme_cmd(arg),
+        "bench" => bench(arg),
+        "irqcfg" => {
+            config::with("irq.unmask", |v| write_fmt(format_args!("irq.unmask = \"{}\"\n", v)));
+            let lines = pic::enabled_lines();
+            let (master, slave) = pic::masks_for(lines);
+            write_fmt(format_args!("masks master={:#04x} slave={:#04x}\nunmasked:", master, slave));
+            for (line, name) in pic::LINE_NAMES.iter().enumerate() {
+                if lines & (1 << line) != 0 { write_fmt(format_args!(" {}({})", line, name)); }
+            }
+            writeln("");
+            crate::irq::for_each(|line, names, unclaimed| {
+                write_fmt(format_args!("irq{:<2} {:<9}", line, pic::LINE_NAMES[line as usize]));
+                for name in names {
+                    write_fmt(format_args!(" {}", name));
+                }
+                write_fmt(format_args!(" (unclaimed {})\n", unclaimed));
+            });
+        }
+        "stress" => {
+            let (sub, rest) = split1(arg);
+            let (amount, secs) = split1(rest);
+            let secs = if secs.is_empty() { Some(crate::stress::DEFAULT_SECONDS) } else { parse_u64(secs) };
+            let res = match (sub, parse_u64(amount), secs) {
+                ("mem", Some(mib), Some(secs)) => crate::stress::mem(mib, secs),
+                ("pf", Some(rate), Some(secs)) => crate::stress::page_faults(rate.min(u32::MAX as u64) as u32, secs),
+                #[cfg(feature = "ai_agent")]
+                ("cpu", Some(n), Some(secs)) => match crate::stress::cpu(n as usize, secs) {
+                    Ok(started) => {
+                        write_fmt(format_args!("{} busy tasks\n", started));
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                },
+                #[cfg(not(feature = "ai_agent"))]
+                ("cpu", ..) => {
+                    writeln("stress cpu needs the ai_agent feature (task scheduler)");
+                    return;
+                }
+                ("stop", ..) => {
+                    crate::stress::stop();
+                    Ok(())
+                }
+                _ => {
+                    let (mem, pf, cpu) = crate::stress::active();
+                    write_fmt(format_args!("mem={} pf={} cpu={}\n", mem as u8, pf as u8, cpu as u8));
+                    writeln("usage: stress mem <MiB> [seconds] | stress pf <rate> [seconds] | stress cpu <n> [seconds] | stress stop");
+                    return;
+                }
+            };
+            if let Err(e) = res { write_fmt(format_args!("stress: {}\n", e.as_str())); }
+        }
+        "lsdrv" => {
+            let mut any = false;
+            driver::for_each_binding(|b| {
+                any = true;
+                write_fmt(format_args!(
+                    "{} {:04x}:{:04x} class={:02x}.{:02x}.{:02x} -> {}\n",
+                    b.addr, b.ids.vendor, b.ids.device, b.ids.class, b.ids.subclass, b.ids.prog_if, b.driver
+                ));
+            });
+            if !any { writeln("no devices bound"); }
+            driver::for_each_driver(|d| {
+                let mut used = false;
+                driver::for_each_binding(|b| used |= b.driver == d.name);
+                if !used { write_fmt(format_args!("(unbound driver) {}\n", d.name)); }
+            });
+        }
+        "pci" if machine() => {
+            crate::pci::find_usb_controllers(|addr| write_fmt(format_args!(
+                "pci addr={} vendor={:04x} device={:04x} class={:02x} sub={:02x} if={:02x}\n",
+                addr, crate::pci::vendor_id(addr), crate::pci::device_id(addr), crate::pci::class_code(addr),
+                crate::pci::subclass(addr), crate::pci::prog_if(addr)
+            )));
         }
         "pci" => {
             crate::log_usb_controllers();
         }
-        "reboot" => {
-            crate::exit_qemu(0);
+        #[cfg(feature = "debug_tools")]
+        "debug" => match arg {
+            "unlock" => { memdbg::set_unlocked(true); writeln("peek/poke unlocked"); }
+            "lock" => { memdbg::set_unlocked(false); writeln("peek/poke locked"); }
+            _ => writeln(if memdbg::is_unlocked() { "unlocked" } else { "locked" }),
+        },
+        #[cfg(feature = "debug_tools")]
+        "peek" => {
+            let (addr_s, rest) = split1(arg);
+            let Some(phys) = parse_u64(addr_s) else { writeln("usage: peek <addr> [len]"); return; };
+            let len = if rest.is_empty() { 16 } else { parse_u64(rest).unwrap_or(0) as usize };
+            let mut buf = [0u8; 256];
+            let mut done = 0usize;
+            while done < len {
+                let n = (len - done).min(buf.len());
+                let at = phys + done as u64;
+                if let Err(e) = memdbg::peek(at, &mut buf[..n]) {
+                    write_fmt(format_args!("peek {:#x}: {}\n", at, e.as_str()));
+                    return;
+                }
+                hex_dump_at(at, &buf[..n]);
+                done += n;
+            }
+        }
+        #[cfg(feature = "debug_tools")]
+        "poke" => {
+            let (addr_s, mut rest) = split1(arg);
+            let Some(phys) = parse_u64(addr_s) else { writeln("usage: poke <addr> <byte..>"); return; };
+            let mut bytes = [0u8; 64];
+            let mut n = 0usize;
+            while !rest.is_empty() {
+                let (tok, tail) = split1(rest);
+                rest = tail;
+                let tok = tok.trim_start_matches("0x");
+                match u8::from_str_radix(tok, 16) {
+                    Ok(b) if n < bytes.len() => { bytes[n] = b; n += 1; }
+                    _ => { writeln("poke: bytes are hex, at most 64"); return; }
+                }
+            }
+            if n == 0 { writeln("usage: poke <addr> <byte..>"); return; }
+            match memdbg::poke(phys, &bytes[..n]) {
+                Ok(()) => write_fmt(format_args!("wrote {} byte(s) at {:#x}\n", n, phys)),
+                Err(e) => write_fmt(format_args!("poke {:#x}: {}\n", phys, e.as_str())),
+            }
+        }
+        "lock" => {
+            if !crate::screenlock::lock_now() { writeln("lock: set console.lock to a passphrase first"); }
+        }
+        "reboot" => crate::power::shutdown(crate::power::Mode::Reboot),
+        "poweroff" => crate::power::shutdown(crate::power::Mode::PowerOff),
+        "at" | "every" => {
+            let (ms, command) = split1(arg);
+            let Some(ms) = parse_u64(ms).filter(|_| !command.is_empty()) else {
+                write_fmt(format_args!("usage: {} <ms> <command>\n", cmd));
+                return;
+            };
+            match jobs::schedule(ms, command, cmd == "every") {
+                Ok(id) => write_fmt(format_args!("job {}\n", id)),
+                Err(e) => write_fmt(format_args!("{}: {}\n", cmd, e.as_str())),
+            }
+        }
+        "status" => {
+            status::for_each(|e| match machine() {
+                true => write_fmt(format_args!("status name={} state={} reason=\"{}\"\n", e.name, e.health.as_str(), e.reason)),
+                false if e.reason.is_empty() => write_fmt(format_args!("{:<12} {}\n", e.name, e.health.as_str())),
+                false => write_fmt(format_args!("{:<12} {:<8} {}\n", e.name, e.health.as_str(), e.reason)),
+            });
+            if !machine() { write_fmt(format_args!("shutdown hooks (last runs first):")); }
+            crate::power::for_each_hook(|name| match machine() {
+                true => write_fmt(format_args!("hook name={}\n", name)),
+                false => write_fmt(format_args!(" {}", name)),
+            });
+            if !machine() { writeln(""); }
+        }
+        "jobs" => {
+            let mut any = false;
+            jobs::for_each(|id, left, period, command| {
+                any = true;
+                match period {
+                    Some(p) => write_fmt(format_args!(
+                        "{:>3} every {} ms, next in {} ms: {}\n",
+                        id, time::ms_for_ticks(p), time::ms_for_ticks(left), command
+                    )),
+                    None => write_fmt(format_args!("{:>3} at +{} ms: {}\n", id, time::ms_for_ticks(left), command)),
+                }
+            });
+            if !any { writeln("no jobs"); }
         }
+        "cancel" => match arg.parse::<u32>() {
+            Ok(id) if jobs::cancel(id) => {}
+            Ok(_) => writeln("cancel: no such job"),
+            Err(_) => writeln("usage: cancel <job id>"),
+        },
         "sleep" => {
             if arg.is_empty() { writeln("usage: sleep <ms>"); return; }
             if let Some(ms) = parse_u64(arg) {
@@ -119,14 +1018,602 @@ fn split1(s: &str) -> (&str, &str) {
     if let Some(sp) = s.find(' ') { (&s[..sp], s[sp+1..].trim()) } else { (s, "") }
 }
 
+/// `ai replay <file>` runs the model over a recorded telemetry trace and
+/// prints each decision, then a count per action kind.
+#[cfg(feature = "ai_agent")]
+fn ai_replay(path: &str) {
+    use crate::ai_agent::{self, ReplayError};
+    if path.is_empty() { writeln("usage: ai replay <file>"); return; }
+    let mut kinds = [0u32; 8];
+    let res = with_file(path, |trace| ai_agent::replay(trace, |i, tel, action| {
+        kinds[(action.kind as usize).min(kinds.len() - 1)] += 1;
+        write_fmt(format_args!(
+            "{} runq={} irq_rate={} free_kb={} pf_rate={} -> kind={} p1={} p2={}\n",
+            i, tel.runq, tel.irq_rate, tel.free_kb, tel.pf_rate, action.kind, action.param1, action.param2
+        ));
+    }));
+    match res {
+        None => writeln("not found"),
+        Some(Err(ReplayError::NoModel)) => writeln("ai replay: no model loaded"),
+        Some(Err(ReplayError::BadTrace)) => writeln("ai replay: not a telemetry trace"),
+        Some(Err(ReplayError::TooSlow)) => writeln("ai replay: a sample ran over the step budget"),
+        Some(Ok(n)) => {
+            write_fmt(format_args!("{} samples:", n));
+            for (kind, count) in kinds.iter().enumerate().filter(|(_, c)| **c > 0) {
+                write_fmt(format_args!(" kind{}={}", kind, count));
+            }
+            writeln("");
+        }
+    }
+}
+
+#[cfg(not(feature = "ai_agent"))]
+fn ai_replay(_path: &str) {
+    writeln("ai replay needs the ai_agent feature");
+}
+
+#[cfg(feature = "ai_agent")]
+fn ai_record(arg: &str) {
+    let (path, secs) = split1(arg);
+    if path.is_empty() {
+        let active = crate::ai_agent::recording(|path, n| write_fmt(format_args!("recording to {}: {} samples\n", path, n)));
+        if !active { writeln("usage: ai record <file> <seconds>"); }
+        return;
+    }
+    let Some(secs) = parse_u64(secs).filter(|s| *s > 0) else {
+        writeln("usage: ai record <file> <seconds>");
+        return;
+    };
+    match crate::ai_agent::record(path, secs) {
+        Ok(()) => write_fmt(format_args!("recording to {} for {} s\n", path, secs)),
+        Err(e) => write_fmt(format_args!("ai record: {}\n", e.as_str())),
+    }
+}
+
+#[cfg(not(feature = "ai_agent"))]
+fn ai_record(_arg: &str) {
+    writeln("ai record needs the ai_agent feature");
+}
+
+/// `ai mode` shows the agent's mode, `ai mode off|observe|act` switches and
+/// saves it.
+#[cfg(feature = "ai_agent")]
+fn ai_mode(arg: &str) {
+    use crate::ai_agent::{self, Mode};
+    if arg.is_empty() {
+        match machine() {
+            true => write_fmt(format_args!("ai_mode mode={}\n", ai_agent::mode().as_str())),
+            false => writeln(ai_agent::mode().as_str()),
+        }
+        return;
+    }
+    let Some(mode) = Mode::parse(arg) else {
+        writeln("usage: ai mode [off|observe|act]");
+        return;
+    };
+    match ai_agent::set_mode(mode) {
+        Ok(()) => write_fmt(format_args!("ai mode {} (saved)\n", mode.as_str())),
+        Err(e) => write_fmt(format_args!("ai mode: {}\n", e)),
+    }
+}
+
+#[cfg(not(feature = "ai_agent"))]
+fn ai_mode(_arg: &str) {
+    writeln("ai mode needs the ai_agent feature");
+}
+
+/// `ai canary <file> <s>` puts a model on trial, `promote` keeps it,
+/// `abort` reverts; alone, shows the trial or how the last one ended.
+#[cfg(feature = "ai_agent")]
+fn ai_canary(arg: &str) {
+    use crate::ai_canary::{self, End, Metrics};
+    fn line(label: &str, m: &Metrics) {
+        write_fmt(format_args!(
+            "{} steps={} accepted={} selftest_failed={} rolled_back={} errors={} pf_mean={}\n",
+            label, m.steps, m.accepted, m.selftest_failures, m.rolled_back, m.errors, m.pf_mean()
+        ));
+    }
+    let (sub, rest) = split1(arg);
+    let end = match sub {
+        "" => {
+            if let Some((before, during)) = ai_canary::progress() {
+                write_fmt(format_args!("trial: {} s left\n", ai_canary::remaining_s().unwrap_or(0)));
+                line("previous", &before);
+                line("canary  ", &during);
+            } else if let Some(r) = ai_canary::last() {
+                write_fmt(format_args!("last trial: {}\n", r.end.as_str()));
+                line("previous", &r.before);
+                line("canary  ", &r.during);
+                write_fmt(format_args!("regressed: selftest={} pf_rate={}\n", r.regressions.selftest as u8, r.regressions.pf_rate as u8));
+            } else {
+                writeln("no trial");
+            }
+            return;
+        }
+        "promote" => End::Promoted,
+        "abort" => End::Aborted,
+        path => {
+            let Some(secs) = parse_u64(rest).filter(|s| *s > 0) else {
+                writeln("usage: ai canary [<file> <seconds>|promote|abort]");
+                return;
+            };
+            match with_file(path, |model| ai_canary::start(path, model, secs)) {
+                None => writeln("not found"),
+                Some(Err(e)) => write_fmt(format_args!("ai canary: {}\n", e)),
+                Some(Ok(())) => write_fmt(format_args!("{} on trial for {} s; `ai canary promote` keeps it\n", path, secs)),
+            }
+            return;
+        }
+    };
+    match ai_canary::finish(end) {
+        Some(r) => write_fmt(format_args!(
+            "{}: pf_mean {} (was {}), selftest_failed {} (was {}){}\n",
+            r.end.as_str(),
+            r.during.pf_mean(),
+            r.before.pf_mean(),
+            r.during.selftest_failures,
+            r.before.selftest_failures,
+            if r.regressions.any() { ", regressed" } else { "" }
+        )),
+        None => writeln("no trial running"),
+    }
+}
+
+#[cfg(not(feature = "ai_agent"))]
+fn ai_canary(_arg: &str) {
+    writeln("ai canary needs the ai_agent feature");
+}
+
+/// Journal records oldest first, stamped with the time since boot and since
+/// the record before.
+fn ai_history() {
+    let mut prev = None;
+    journal::for_each(|rec| {
+        let delta = rec.tsc.saturating_sub(prev.unwrap_or(rec.tsc));
+        write_fmt(format_args!(
+            "{:>14} +{:<12} seq={} {} kind={} code={} src={}\n",
+            time::Duration(rec.tsc),
+            time::Duration(delta),
+            rec.seq,
+            rec.kind.as_str(),
+            rec.action,
+            rec.code,
+            rec.source()
+        ));
+        prev = Some(rec.tsc);
+    });
+    if prev.is_none() {
+        writeln("journal empty");
+    }
+}
+
+/// XMODEM send of `path` over the serial line, for the host to save.
+fn send_file(path: &str) {
+    let sent = with_file(path, |bytes| {
+        write_fmt(format_args!("sx: {} bytes, start the XMODEM receiver now\n", bytes.len()));
+        let mut link = serial::Raw::begin();
+        xmodem::send(&mut link, bytes)
+    });
+    match sent {
+        None => writeln("not found"),
+        Some(Ok(blocks)) => write_fmt(format_args!("sx: {} blocks sent\n", blocks)),
+        Some(Err(e)) => write_fmt(format_args!("sx: {}\n", e.as_str())),
+    }
+}
+
+/// Room made for `base64 -d` when no size is given.
+const B64_DEFAULT_CAP: usize = 64 * 1024;
+/// Bytes per line of `base64` output: 76 characters, as MIME wraps them.
+const B64_LINE_BYTES: usize = 57;
+/// Silence on the serial line that ends a `base64 -d` paste.
+const B64_IDLE_MS: u64 = 30_000;
+
+/// `bench [pmm|memcpy [KiB]|memset [KiB]|matmul [n]|switch]`, all of them without an
+/// argument. One line per benchmark, in the format `bench` documents.
+fn bench(arg: &str) {
+    let (name, size) = split1(arg);
+    let size = if size.is_empty() { None } else { parse_u64(size) };
+    match name {
+        "" | "all" => {
+            for name in ["pmm", "matmul", "switch"] {
+                bench_one(name, None);
+            }
+            bench_mem("memcpy", None);
+            bench_mem("memset", None);
+        }
+        "memcpy" | "memset" => bench_mem(name, size),
+        "pmm" | "matmul" | "switch" => bench_one(name, size),
+        _ => writeln("usage: bench [pmm|memcpy [KiB]|memset [KiB]|matmul [n]|switch]"),
+    }
+}
+
+/// `memcpy` or `memset`, once per `fastmem` strategy the CPU has.
+fn bench_mem(name: &str, size: Option<u64>) {
+    let kib = size.unwrap_or(bench::DEFAULT_COPY_KIB);
+    let run = if name == "memcpy" { bench::memcpy } else { bench::memset };
+    for strategy in fastmem::Strategy::ALL {
+        if strategy == fastmem::Strategy::RepErms && !fastmem::has_erms() {
+            continue;
+        }
+        match run(kib, strategy) {
+            Ok(s) => {
+                write_fmt(format_args!(
+                    "bench {} impl={} kib={} bytes={} cycles={}",
+                    name, strategy.as_str(), kib, s.ops, s.cycles
+                ));
+                if let Some(rate) = s.per_second(1, time::tsc_per_ms()) { write_fmt(format_args!(" mib_per_s={}", rate >> 20)); }
+                writeln("");
+            }
+            Err(e) => {
+                write_fmt(format_args!("bench {} error=\"{}\"\n", name, e.as_str()));
+                return;
+            }
+        }
+    }
+}
+
+fn bench_one(name: &str, size: Option<u64>) {
+    let per_ms = time::tsc_per_ms();
+    let result = match name {
+        "pmm" => bench::pmm_alloc().map(|s| {
+            write_fmt(format_args!("bench pmm iters={} cycles={} cycles_per_op={}", s.ops, s.cycles, s.cycles_per_op()));
+            if let Some(rate) = s.per_second(1, per_ms) { write_fmt(format_args!(" ops_per_s={}", rate)); }
+        }),
+        "matmul" => {
+            let n = size.map_or(bench::DEFAULT_MATMUL_N, |n| n.min(usize::MAX as u64) as usize);
+            bench::matmul(n).map(|s| {
+                write_fmt(format_args!("bench matmul n={} macs={} cycles={}", n, s.ops, s.cycles));
+                // A multiply-add is two operations
+                if let Some(rate) = s.per_second(2, per_ms) {
+                    let mops = rate / 1_000_000;
+                    write_fmt(format_args!(" gops={}.{:03}", mops / 1000, mops % 1000));
+                }
+            })
+        }
+        _ => bench::switch().map(|s| {
+            write_fmt(format_args!("bench switch iters={} cycles={} cycles_per_op={}", s.ops, s.cycles, s.cycles_per_op()));
+            if let Some(ns) = time::cycles_to_ns(s.cycles_per_op()) { write_fmt(format_args!(" ns_per_op={}", ns)); }
+        }),
+    };
+    match result {
+        Ok(()) => writeln(""),
+        Err(e) => write_fmt(format_args!("bench {} error=\"{}\"\n", name, e.as_str())),
+    }
+}
+
+/// `theme` lists the console colours; `theme <slot> <colour>` changes one
+/// and sets its `theme.*` key, which `config save` then keeps.
+fn theme_cmd(arg: &str) {
+    let (name, value) = split1(arg);
+    if name.is_empty() {
+        for slot in theme::Slot::ALL {
+            let style = theme::get(slot);
+            let (fg, bg) = theme::describe(style);
+            write_fmt(format_args!("{:<9} {:#04x} {}/{}\n", slot.name(), style, fg, bg));
+        }
+        return;
+    }
+    let (Some(slot), Some(style)) = (theme::Slot::parse(name), theme::parse_style(value)) else {
+        writeln("usage: theme [text|headline|panic|bar|error|warn|info <0xNN|fg/bg>]");
+        return;
+    };
+    theme::set(slot, style);
+    if slot == theme::Slot::Text {
+        vga::set_style(style);
+    }
+    if let Err(e) = config::set(slot.key(), value) {
+        write_fmt(format_args!("theme: {:?}\n", e));
+    }
+}
+
+/// `trace start|stop|dump [file]`: the ktrace ring as text, or in the
+/// binary format scripts/decode-ktrace.py reads.
+fn trace(arg: &str) {
+    let (sub, path) = split1(arg);
+    match sub {
+        "start" => {
+            ktrace::start();
+            writeln("trace: recording");
+        }
+        "stop" => {
+            ktrace::stop();
+            write_fmt(format_args!("trace: stopped, {} events\n", ktrace::recorded()));
+        }
+        "dump" if path.is_empty() => {
+            let per_ms = time::tsc_per_ms().max(1);
+            let mut first = None;
+            ktrace::for_each(|ev| {
+                let t0 = *first.get_or_insert(ev.tsc);
+                let us = ev.tsc.wrapping_sub(t0) * 1000 / per_ms;
+                write_fmt(format_args!("{:>10} {:<13} {} {}\n", us, ev.kind.as_str(), ev.a, ev.b));
+            });
+        }
+        "dump" if ktrace::enabled() => writeln("trace: stop it before dumping to a file"),
+        "dump" => {
+            let mut count = 0u32;
+            ktrace::for_each(|_| count += 1);
+            let write = || -> Result<(), ramfs::WriteError> {
+                ramfs::create(path, ktrace::HEADER_LEN + count as usize * ktrace::EVENT_LEN)?;
+                ramfs::append(path, &ktrace::header(count))?;
+                let mut result = Ok(());
+                ktrace::for_each(|ev| result = result.and_then(|()| ramfs::append(path, &ev.encode())));
+                result
+            };
+            match write() {
+                Ok(()) => write_fmt(format_args!("trace: {} events in {}\n", count, path)),
+                Err(e) => write_fmt(format_args!("trace: {}\n", e.as_str())),
+            }
+        }
+        "" => write_fmt(format_args!(
+            "trace: {}, {} events recorded\n",
+            if ktrace::enabled() { "on" } else { "off" },
+            ktrace::recorded()
+        )),
+        _ => writeln("usage: trace start|stop|dump [file]"),
+    }
+}
+
+/// `strace <pid> on|off`: that process's syscalls (number, arguments,
+/// return value) in the trace ring; alone, lists the traced pids.
+fn strace(arg: &str) {
+    let (pid, switch) = split1(arg);
+    if pid.is_empty() {
+        let mut any = false;
+        syscall::for_each_traced(|pid| {
+            write_fmt(format_args!("strace: pid {}\n", pid));
+            any = true;
+        });
+        if !any { writeln("strace: no process traced"); }
+        return;
+    }
+    let on = match switch {
+        "on" => Some(true),
+        "off" => Some(false),
+        _ => None,
+    };
+    let (Some(pid), Some(on)) = (parse_u64(pid).and_then(|p| u32::try_from(p).ok()), on) else {
+        writeln("usage: strace [<pid> on|off]");
+        return;
+    };
+    if on && pid != process::KERNEL_PID && process::with_process(pid, |_| ()).is_none() {
+        write_fmt(format_args!("strace: no process {}\n", pid));
+        return;
+    }
+    if let Err(e) = syscall::set_traced(pid, on) {
+        write_fmt(format_args!("strace: {}\n", e));
+        return;
+    }
+    if on && !ktrace::enabled() {
+        ktrace::start();
+        writeln("trace: recording");
+    }
+    write_fmt(format_args!("strace: pid {} {}\n", pid, if on { "on" } else { "off" }));
+}
+
+/// `kill <pid>`: end a process and give its memory back at once.
+fn kill(arg: &str) {
+    let Some(pid) = parse_u64(arg).and_then(|p| u32::try_from(p).ok()) else {
+        writeln("usage: kill <pid>");
+        return;
+    };
+    match process::kill(pid) {
+        Ok(pages) => write_fmt(format_args!("kill: pid {} ended, {} KiB freed\n", pid, pages * 4)),
+        Err(e) => write_fmt(format_args!("kill: pid {}: {}\n", pid, e.as_str())),
+    }
+}
+
+fn base64_encode(bytes: &[u8]) {
+    let mut line = [0u8; base64::encoded_len(B64_LINE_BYTES) + 1];
+    for chunk in bytes.chunks(B64_LINE_BYTES) {
+        let n = base64::encode(chunk, &mut line);
+        line[n] = b'\n';
+        write_bytes(&line[..n + 1]);
+    }
+}
+
+/// Decode base64 into the new file `path`: piped text, or else lines pasted
+/// on the serial line up to one holding a single `.`.
+fn base64_decode(path: &str, cap: usize, input: Option<&[u8]>) {
+    if let Err(e) = ramfs::create(path, cap) {
+        write_fmt(format_args!("base64: {}\n", e.as_str()));
+        return;
+    }
+    let mut decoder = base64::Decoder::new();
+    let mut sink = |text: &[u8]| -> Result<usize, &'static str> {
+        let mut out = [0u8; 3 * 64 + 3];
+        let mut written = 0;
+        for piece in text.chunks(4 * 64) {
+            let n = decoder.feed(piece, &mut out).map_err(base64::Error::as_str)?;
+            ramfs::append(path, &out[..n]).map_err(|e| e.as_str())?;
+            written += n;
+        }
+        Ok(written)
+    };
+    let result = match input {
+        Some(text) => sink(text),
+        None => {
+            writeln("base64: paste the text on the serial line, then a line with a single `.`");
+            base64_from_serial(&mut sink)
+        }
+    };
+    match result.and_then(|n| decoder.finish().map(|_| n).map_err(base64::Error::as_str)) {
+        Ok(n) => write_fmt(format_args!("base64: {} bytes into {}\n", n, path)),
+        Err(e) => {
+            ramfs::remove(path);
+            write_fmt(format_args!("base64: {}\n", e));
+        }
+    }
+}
+
+/// Feed serial lines to `sink` until a `.` line or `B64_IDLE_MS` of silence.
+fn base64_from_serial(sink: &mut impl FnMut(&[u8]) -> Result<usize, &'static str>) -> Result<usize, &'static str> {
+    use xmodem::Link;
+    let mut link = serial::Raw::begin();
+    let mut line = [0u8; LINE_CAP];
+    let mut len = 0;
+    let mut total = 0;
+    loop {
+        let Some(b) = link.recv(B64_IDLE_MS) else { return Err("timed out waiting for input") };
+        if b != b'\n' && b != b'\r' {
+            if len == line.len() {
+                total += sink(&line[..len])?;
+                len = 0;
+            }
+            line[len] = b;
+            len += 1;
+            continue;
+        }
+        if line[..len].trim_ascii() == b"." {
+            return Ok(total);
+        }
+        total += sink(&line[..len])?;
+        len = 0;
+    }
+}
+
+/// SMBIOS identity and memory devices.
+fn write_dmi() {
+    let Some(dmi) = smbios::get() else {
+        writeln("dmi: no SMBIOS tables");
+        return;
+    };
+    write_fmt(format_args!(
+        "smbios {}.{} at {:#x} ({} bytes)\nsystem: {} {} {}\nbios: {} {} ({})\n",
+        dmi.version.0,
+        dmi.version.1,
+        dmi.table,
+        dmi.table_len,
+        dmi.manufacturer,
+        dmi.product,
+        dmi.product_version,
+        dmi.bios_vendor,
+        dmi.bios_version,
+        dmi.bios_date
+    ));
+    for m in dmi.memory.iter().flatten() {
+        match m.size_mib {
+            Some(mib) => write_fmt(format_args!("memory: {:<12} {:>6} MiB", m.locator.as_str(), mib)),
+            None => write_fmt(format_args!("memory: {:<12}      ? MiB", m.locator.as_str())),
+        }
+        if m.speed != 0 {
+            write_fmt(format_args!(" {} MT/s", m.speed));
+        }
+        write_fmt(format_args!(" {} {}\n", m.manufacturer, m.part));
+    }
+    if dmi.memory_dropped != 0 {
+        write_fmt(format_args!("memory: {} more devices not kept\n", dmi.memory_dropped));
+    }
+    write_fmt(format_args!("memory total: {} MiB\n", dmi.memory_mib()));
+}
+
+/// Boot protocol, initrd and the firmware memory map with totals per kind.
+fn write_boot_info(boot: &BootInfo) {
+    write_fmt(format_args!(
+        "boot protocol: stage2 BootInfo v{}{} ({} bytes)\n",
+        boot.version,
+        if boot.version == 1 { ", legacy layout" } else { "" },
+        boot.size
+    ));
+    match boot.initrd_len() {
+        0 => writeln("initrd: none"),
+        len => write_fmt(format_args!(
+            "initrd: {:#x}-{:#x} ({} KiB)\n",
+            boot.initrd_base(),
+            boot.initrd_base() + len,
+            len.div_ceil(1024)
+        )),
+    }
+    if let Some(rsdp) = boot.rsdp {
+        write_fmt(format_args!("acpi rsdp: {:#x}\n", rsdp));
+    }
+    let mut tables = 0;
+    crate::acpi::for_each_table(|t| {
+        if tables == 0 {
+            write_str("acpi tables:");
+        }
+        write_fmt(format_args!(" {}", core::str::from_utf8(&t.signature).unwrap_or("????")));
+        tables += 1;
+    });
+    if tables != 0 {
+        write_str("\n");
+    }
+    writeln("memory map:");
+    for region in unsafe { boot.memory_map() } {
+        write_fmt(format_args!(
+            "  {:#014x}-{:#014x} {}\n",
+            region.base_addr,
+            region.base_addr.saturating_add(region.length),
+            region.kind().as_str()
+        ));
+    }
+    let totals = bootinfo::region_totals(unsafe { boot.memory_map() });
+    for (kind, (count, bytes)) in MemoryRegionKind::ALL.iter().zip(totals) {
+        if count != 0 {
+            write_fmt(format_args!("  {:<17} {:>3} entries {:>10} KiB\n", kind.as_str(), count, bytes / 1024));
+        }
+    }
+}
+
+/// Hand `f` the contents of `path`: a `proc/` file or an initrd file.
+fn with_file<R>(path: &str, f: impl FnOnce(&[u8]) -> R) -> Option<R> {
+    if procfs::is_proc(path) {
+        return procfs::read(path, f);
+    }
+    let (ptr, size) = ramfs::find(path)?;
+    Some(f(unsafe { core::slice::from_raw_parts(ptr, size) }))
+}
+
+/// Hand `f` the contents of `path`, or the piped input when no path is given.
+fn with_text(path: &str, input: Option<&[u8]>, usage: &str, f: impl FnOnce(&[u8])) {
+    if !path.is_empty() {
+        if with_file(path, f).is_none() {
+            writeln("not found");
+        }
+        return;
+    }
+    match input {
+        Some(text) => f(text),
+        None => writeln(usage),
+    }
+}
+
 fn write_str(s: &str) {
+    if let Some(pipe) = capture() {
+        pipe.lock().push(s.as_bytes());
+        return;
+    }
     serial::write_str(s);
     vga::write_str(s);
 }
 
+/// Text that may not be UTF-8 (file contents); invalid sequences print as `?`.
+fn write_bytes(bytes: &[u8]) {
+    if let Some(pipe) = capture() {
+        pipe.lock().push(bytes);
+        return;
+    }
+    for chunk in bytes.utf8_chunks() {
+        write_str(chunk.valid());
+        if !chunk.invalid().is_empty() { write_str("?"); }
+    }
+}
+
 fn writeln(s: &str) { write_str(s); write_str("\r\n"); }
 
+fn write_fmt(args: fmt::Arguments) {
+    if let Some(pipe) = capture() {
+        let _ = fmt::Write::write_fmt(&mut *pipe.lock(), args);
+        return;
+    }
+    serial::write_fmt(args);
+    vga::fmt(args);
+}
+
 fn writeln_num(prefix: &str, n: u64) {
+    if capture().is_some() {
+        write_fmt(format_args!("{}{}\n", prefix, n));
+        return;
+    }
     serial::write_fmt(format_args!("{}{}\r\n", prefix, n));
     vga::write_str(prefix);
     let mut buf = [0u8; 20];
@@ -143,6 +1630,9 @@ pub fn start() {
 }
 
 fn parse_u64(s: &str) -> Option<u64> {
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u64::from_str_radix(hex, 16).ok();
+    }
     let mut v: u64 = 0;
     for c in s.bytes() {
         if c < b'0' || c > b'9' { return None; }
@@ -151,46 +1641,79 @@ fn parse_u64(s: &str) -> Option<u64> {
     Some(v)
 }
 
-fn print_num(n: u64) {
-    let mut buf = [0u8; 20];
-    let mut x = n;
-    if x == 0 { vga::put_char('0'); return; }
-    let mut i = buf.len();
-    while x > 0 { i -= 1; buf[i] = b'0' + (x % 10) as u8; x /= 10; }
-    for &b in &buf[i..] { vga::put_char(b as char); }
+fn hex_dump(bytes: &[u8]) {
+    hex_dump_at(0, bytes);
 }
 
-fn hex_dump(bytes: &[u8]) {
-    const HEX: &[u8; 16] = b"0123456789abcdef";
-    let mut off = 0usize;
-    while off < bytes.len() {
-        // offset
-        serial::write_fmt(format_args!("{:08x}  ", off));
-        for i in 0..16 {
-            if off + i < bytes.len() {
-                let b = bytes[off + i];
-                serial::write_fmt(format_args!("{:02x} ", b));
-            } else {
-                serial::write_str("   ");
-            }
+/// `hex_dump` with offsets labelled from `base` (e.g. a physical address).
+fn hex_dump_at(base: u64, bytes: &[u8]) {
+    let mut pager = Pager::new();
+    let mut line = [0u8; textutil::HEX_LINE_CAP];
+    for (i, chunk) in bytes.chunks(16).enumerate() {
+        if !pager.rows(1) { break; }
+        let len = textutil::hex_line(base + (i * 16) as u64, chunk, &mut line);
+        write_bytes(&line[..len]);
+    }
+}
+
+/// Console rows per page: a screen less the `--More--` line.
+const PAGE_ROWS: usize = vga::ROWS - 1;
+
+enum More {
+    Page,
+    Line,
+    Quit,
+}
+
+/// Stops console output after each screenful until a key says to go on:
+/// space for a page, Enter for a line, q or Esc to stop. Output going into
+/// a pipe is never held.
+struct Pager {
+    left: usize,
+}
+
+impl Pager {
+    fn new() -> Self {
+        Pager { left: PAGE_ROWS }
+    }
+
+    /// Room for `rows` more rows; false once the reader quit.
+    fn rows(&mut self, rows: usize) -> bool {
+        if capture().is_some() {
+            return true;
         }
-        serial::write_str(" |");
-        for i in 0..16 {
-            if off + i < bytes.len() {
-                let ch = bytes[off + i];
-                let c = if ch >= 32 && ch < 127 { ch as char } else { '.' };
-                serial::write_fmt(format_args!("{}", c));
+        while self.left < rows {
+            match more() {
+                More::Page => self.left += PAGE_ROWS,
+                More::Line => self.left += 1,
+                More::Quit => return false,
             }
         }
-        serial::write_str("|\r\n");
-        off += 16;
+        self.left -= rows;
+        true
     }
 }
 
-// Approximate sleep using timer ticks (assumes ~1 kHz timer)
+fn more() -> More {
+    const PROMPT: &str = "--More-- (space: page, enter: line, q: quit)";
+    vga::write_str(PROMPT);
+    let _focus = keyboard::grab_focus(keyboard::Focus::Pager);
+    let choice = loop {
+        match keyboard::poll_key(keyboard::Focus::Pager) {
+            Some(Key::Char(' ')) => break More::Page,
+            Some(Key::Enter) => break More::Line,
+            Some(Key::Char('q')) | Some(Key::Escape) => break More::Quit,
+            Some(_) => {}
+            None => unsafe { core::arch::asm!("hlt") },
+        }
+    };
+    vga::fmt(format_args!("\r{:1$}\r", "", PROMPT.len()));
+    choice
+}
+
 fn sleep_ms(ms: u64) {
     let start = idt::timer_ticks();
-    let target = start.saturating_add(ms);
+    let target = start.saturating_add(time::ticks_for_ms(ms));
     while idt::timer_ticks() < target {
         unsafe { core::arch::asm!("hlt"); }
     }
@@ -0,0 +1,388 @@
+//! SMBIOS (DMI) tables: which machine this is.
+//!
+//! `init` finds the entry point in the BIOS area (`_SM3_` preferred over
+//! `_SM_`), walks the structure table once and keeps what the logs need:
+//! BIOS vendor/version/date (type 0), system manufacturer/product (type 1)
+//! and the memory devices (type 17). Strings are copied, so nothing points
+//! into firmware memory afterwards. Firmware that only publishes SMBIOS
+//! through UEFI configuration tables is not found yet.
+
+use core::fmt;
+use spin::Mutex;
+
+use crate::{addr, serial};
+use crate::status::{self, Health};
+
+pub const MAX_TEXT: usize = 48;
+pub const MAX_MEMORY_DEVICES: usize = 8;
+const BIOS_AREA: (u64, u64) = (0xF_0000, 0x10_0000);
+const ENTRY32_LEN: usize = 31;
+const ENTRY64_LEN: usize = 24;
+/// Tables larger than this are taken as corrupt.
+const MAX_TABLE_LEN: u32 = 1 << 20;
+
+const TYPE_BIOS: u8 = 0;
+const TYPE_SYSTEM: u8 = 1;
+const TYPE_MEMORY_DEVICE: u8 = 17;
+const TYPE_END: u8 = 127;
+
+/// A DMI string, cut at `MAX_TEXT` bytes.
+#[derive(Clone, Copy)]
+pub struct Text {
+    bytes: [u8; MAX_TEXT],
+    len: u8,
+}
+
+impl Text {
+    pub const EMPTY: Text = Text { bytes: [0; MAX_TEXT], len: 0 };
+
+    fn new(src: &[u8]) -> Self {
+        let mut t = Text::EMPTY;
+        // Firmware pads with spaces; non-ASCII bytes would break `as_str`
+        let src = src.trim_ascii();
+        for (dst, &b) in t.bytes.iter_mut().zip(src) {
+            *dst = if b.is_ascii_graphic() || b == b' ' { b } else { b'?' };
+        }
+        t.len = src.len().min(MAX_TEXT) as u8;
+        t
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len as usize]).unwrap_or("?")
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl fmt::Display for Text {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(if self.is_empty() { "unknown" } else { self.as_str() })
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct MemoryDevice {
+    pub locator: Text,
+    /// `None` when the firmware does not know.
+    pub size_mib: Option<u32>,
+    /// MT/s, 0 if unknown.
+    pub speed: u16,
+    pub manufacturer: Text,
+    pub part: Text,
+}
+
+#[derive(Clone, Copy)]
+pub struct Dmi {
+    pub version: (u8, u8),
+    pub table: u64,
+    pub table_len: u32,
+    pub bios_vendor: Text,
+    pub bios_version: Text,
+    pub bios_date: Text,
+    pub manufacturer: Text,
+    pub product: Text,
+    pub product_version: Text,
+    pub memory: [Option<MemoryDevice>; MAX_MEMORY_DEVICES],
+    /// Populated slots past `MAX_MEMORY_DEVICES`.
+    pub memory_dropped: usize,
+}
+
+impl Dmi {
+    const fn empty() -> Self {
+        Dmi {
+            version: (0, 0),
+            table: 0,
+            table_len: 0,
+            bios_vendor: Text::EMPTY,
+            bios_version: Text::EMPTY,
+            bios_date: Text::EMPTY,
+            manufacturer: Text::EMPTY,
+            product: Text::EMPTY,
+            product_version: Text::EMPTY,
+            memory: [None; MAX_MEMORY_DEVICES],
+            memory_dropped: 0,
+        }
+    }
+
+    /// Installed memory the firmware knows the size of.
+    pub fn memory_mib(&self) -> u64 {
+        self.memory.iter().flatten().filter_map(|m| m.size_mib).map(u64::from).sum()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct EntryPoint {
+    version: (u8, u8),
+    table: u64,
+    /// Exact length for `_SM_`, an upper bound for `_SM3_`.
+    len: u32,
+}
+
+static DMI: Mutex<Option<Dmi>> = Mutex::new(None);
+
+fn read_u16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes([*bytes.get(at)?, *bytes.get(at + 1)?]))
+}
+
+fn read_u32(bytes: &[u8], at: usize) -> Option<u32> {
+    Some(read_u16(bytes, at)? as u32 | (read_u16(bytes, at + 2)? as u32) << 16)
+}
+
+fn read_u64(bytes: &[u8], at: usize) -> Option<u64> {
+    Some(read_u32(bytes, at)? as u64 | (read_u32(bytes, at + 4)? as u64) << 32)
+}
+
+fn checksum_ok(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+fn parse_entry(bytes: &[u8]) -> Option<EntryPoint> {
+    if bytes.starts_with(b"_SM3_") {
+        let len = (*bytes.get(6)? as usize).max(ENTRY64_LEN);
+        if !checksum_ok(bytes.get(..len)?) {
+            return None;
+        }
+        return Some(EntryPoint { version: (bytes[7], bytes[8]), table: read_u64(bytes, 16)?, len: read_u32(bytes, 12)? });
+    }
+    if bytes.starts_with(b"_SM_") {
+        let len = (*bytes.get(5)? as usize).max(ENTRY32_LEN);
+        // The entry point checksum, then the intermediate "_DMI_" one
+        if !checksum_ok(bytes.get(..len)?) || &bytes[16..21] != b"_DMI_" || !checksum_ok(&bytes[16..31]) {
+            return None;
+        }
+        return Some(EntryPoint {
+            version: (bytes[6], bytes[7]),
+            table: read_u32(bytes, 24)? as u64,
+            len: read_u16(bytes, 22)? as u32,
+        });
+    }
+    None
+}
+
+/// String `index` (1-based, 0 means none) of a structure's string set.
+fn string(set: &[u8], index: u8) -> &[u8] {
+    match index {
+        0 => &[],
+        i => set.split(|&b| b == 0).nth(i as usize - 1).unwrap_or(&[]),
+    }
+}
+
+/// Each structure as (formatted area, string set), up to the end-of-table marker.
+fn structures(table: &[u8]) -> impl Iterator<Item = (&[u8], &[u8])> {
+    let mut at = 0;
+    core::iter::from_fn(move || {
+        let len = *table.get(at + 1)? as usize;
+        if len < 4 || at + len > table.len() || table[at] == TYPE_END {
+            return None;
+        }
+        let formatted = &table[at..at + len];
+        // The string set ends with two NULs, also when it is empty
+        let set_len = table[at + len..].windows(2).position(|w| w == [0, 0])?;
+        let set = &table[at + len..at + len + set_len];
+        at += len + set_len + 2;
+        Some((formatted, set))
+    })
+}
+
+fn memory_size_mib(formatted: &[u8]) -> Option<u32> {
+    match read_u16(formatted, 0x0C)? {
+        0xFFFF => None,
+        0x7FFF => read_u32(formatted, 0x1C).map(|mib| mib & 0x7FFF_FFFF),
+        kib if kib & 0x8000 != 0 => Some(u32::from(kib & 0x7FFF) / 1024),
+        mib => Some(u32::from(mib)),
+    }
+}
+
+fn parse_table(table: &[u8], dmi: &mut Dmi) {
+    let field = |f: &[u8], at: usize| f.get(at).copied().unwrap_or(0);
+    for (f, set) in structures(table) {
+        let text = |at: usize| Text::new(string(set, field(f, at)));
+        match f[0] {
+            TYPE_BIOS => {
+                dmi.bios_vendor = text(0x04);
+                dmi.bios_version = text(0x05);
+                dmi.bios_date = text(0x08);
+            }
+            TYPE_SYSTEM => {
+                dmi.manufacturer = text(0x04);
+                dmi.product = text(0x05);
+                dmi.product_version = text(0x06);
+            }
+            // A size of 0 is an empty slot
+            TYPE_MEMORY_DEVICE if read_u16(f, 0x0C).is_some_and(|s| s != 0) => {
+                let device = MemoryDevice {
+                    locator: text(0x10),
+                    size_mib: memory_size_mib(f),
+                    speed: read_u16(f, 0x15).unwrap_or(0),
+                    manufacturer: text(0x17),
+                    part: text(0x1A),
+                };
+                match dmi.memory.iter_mut().find(|m| m.is_none()) {
+                    Some(slot) => *slot = Some(device),
+                    None => dmi.memory_dropped += 1,
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn find_entry() -> Option<EntryPoint> {
+    let area = addr::phys_slice(BIOS_AREA.0, (BIOS_AREA.1 - BIOS_AREA.0) as usize)?;
+    let scan = |prefix: &[u8]| {
+        (0..area.len() - ENTRY32_LEN)
+            .step_by(16)
+            .filter(|&at| area[at..].starts_with(prefix))
+            .find_map(|at| parse_entry(&area[at..]))
+    };
+    scan(b"_SM3_").or_else(|| scan(b"_SM_"))
+}
+
+pub fn init() {
+    let Some(entry) = find_entry() else {
+        serial::write_str("[smbios] no entry point\r\n");
+        status::set("smbios", Health::Skipped, "no entry point");
+        return;
+    };
+    let Some(table) = addr::phys_slice(entry.table, entry.len.min(MAX_TABLE_LEN) as usize) else {
+        serial::write_fmt(format_args!("[smbios] table at {:#x} is out of reach\r\n", entry.table));
+        status::set("smbios", Health::Failed, "table out of reach");
+        return;
+    };
+    let mut dmi = Dmi::empty();
+    dmi.version = entry.version;
+    dmi.table = entry.table;
+    dmi.table_len = entry.len;
+    parse_table(table, &mut dmi);
+    serial::write_fmt(format_args!(
+        "[smbios] v{}.{}: {}, {} MiB in {} devices\r\n",
+        dmi.version.0,
+        dmi.version.1,
+        Summary(&dmi),
+        dmi.memory_mib(),
+        dmi.memory.iter().flatten().count() + dmi.memory_dropped
+    ));
+    *DMI.lock() = Some(dmi);
+}
+
+pub fn get() -> Option<Dmi> {
+    *DMI.lock()
+}
+
+/// One line naming the machine and its firmware.
+pub struct Summary<'a>(pub &'a Dmi);
+
+impl fmt::Display for Summary<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let d = self.0;
+        write!(f, "{} {}", d.manufacturer, d.product)?;
+        if !d.product_version.is_empty() {
+            write!(f, " ({})", d.product_version)?;
+        }
+        write!(f, ", BIOS {} {} {}", d.bios_vendor, d.bios_version, d.bios_date)
+    }
+}
+
+/// `Summary` for the panic path: nothing if the tables are unknown or the
+/// lock is held.
+pub fn try_write_summary(out: &mut impl fmt::Write) -> fmt::Result {
+    match DMI.try_lock().as_deref() {
+        Some(Some(dmi)) => write!(out, "{}", Summary(dmi)),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_checksum(bytes: &mut [u8], at: usize, len: usize) {
+        bytes[at] = 0;
+        let sum = bytes[..len].iter().fold(0u8, |s, &b| s.wrapping_add(b));
+        bytes[at] = 0u8.wrapping_sub(sum);
+    }
+
+    #[test]
+    fn entry_points() {
+        let mut e32 = [0u8; ENTRY32_LEN];
+        e32[..4].copy_from_slice(b"_SM_");
+        e32[5] = ENTRY32_LEN as u8;
+        e32[6..8].copy_from_slice(&[2, 8]);
+        e32[16..21].copy_from_slice(b"_DMI_");
+        e32[22..24].copy_from_slice(&0x1A3u16.to_le_bytes());
+        e32[24..28].copy_from_slice(&0xF_5A80u32.to_le_bytes());
+        with_checksum(&mut e32[16..], 5, 15);
+        with_checksum(&mut e32, 4, ENTRY32_LEN);
+        assert_eq!(parse_entry(&e32), Some(EntryPoint { version: (2, 8), table: 0xF_5A80, len: 0x1A3 }));
+        e32[24] ^= 1;
+        assert_eq!(parse_entry(&e32), None);
+
+        let mut e64 = [0u8; ENTRY64_LEN];
+        e64[..5].copy_from_slice(b"_SM3_");
+        e64[6] = ENTRY64_LEN as u8;
+        e64[7..9].copy_from_slice(&[3, 0]);
+        e64[12..16].copy_from_slice(&0x200u32.to_le_bytes());
+        e64[16..24].copy_from_slice(&0x7FE_0000u64.to_le_bytes());
+        with_checksum(&mut e64, 5, ENTRY64_LEN);
+        assert_eq!(parse_entry(&e64), Some(EntryPoint { version: (3, 0), table: 0x7FE_0000, len: 0x200 }));
+    }
+
+    #[test]
+    fn table_walk_keeps_identity_and_memory() {
+        let mut table = std::vec::Vec::new();
+        // Type 0: vendor 1, version 2, date 3
+        table.extend_from_slice(&[0, 0x12, 0, 0, 1, 2, 0, 0, 3]);
+        table.resize(0x12, 0);
+        table.extend_from_slice(b"SeaBIOS\0 1.16.3 \0" as &[u8]);
+        table.extend_from_slice(b"04/01/2014\0\0");
+        // Type 1: manufacturer 1, product 2, no version
+        let start = table.len();
+        table.extend_from_slice(&[1, 0x1B, 1, 0, 1, 2, 0]);
+        table.resize(start + 0x1B, 0);
+        table.extend_from_slice(b"QEMU\0Standard PC\0\0");
+        // Type 17: an empty slot, then 2 GiB at 3200 MT/s
+        for size in [0u16, 2048] {
+            let start = table.len();
+            table.resize(start + 0x28, 0);
+            table[start] = TYPE_MEMORY_DEVICE;
+            table[start + 1] = 0x28;
+            table[start + 0x0C..start + 0x0E].copy_from_slice(&size.to_le_bytes());
+            table[start + 0x10] = 1;
+            table[start + 0x15..start + 0x17].copy_from_slice(&3200u16.to_le_bytes());
+            table.extend_from_slice(b"DIMM 0\0\0");
+        }
+        table.extend_from_slice(&[TYPE_END, 4, 0, 0, 0, 0]);
+
+        let mut dmi = Dmi::empty();
+        parse_table(&table, &mut dmi);
+        assert_eq!(dmi.bios_vendor.as_str(), "SeaBIOS");
+        assert_eq!(dmi.bios_version.as_str(), "1.16.3");
+        assert_eq!(dmi.bios_date.as_str(), "04/01/2014");
+        assert_eq!(dmi.product.as_str(), "Standard PC");
+        assert!(dmi.product_version.is_empty());
+        let dimm = dmi.memory[0].unwrap();
+        assert!(dmi.memory[1].is_none());
+        assert_eq!((dimm.locator.as_str(), dimm.size_mib, dimm.speed), ("DIMM 0", Some(2048), 3200));
+        assert_eq!(dmi.memory_mib(), 2048);
+        assert_eq!(
+            std::format!("{}", Summary(&dmi)),
+            "QEMU Standard PC, BIOS SeaBIOS 1.16.3 04/01/2014"
+        );
+    }
+
+    #[test]
+    fn memory_size_encodings() {
+        let with_size = |size: u16, extended: u32| {
+            let mut f = [0u8; 0x20];
+            f[0x0C..0x0E].copy_from_slice(&size.to_le_bytes());
+            f[0x1C..0x20].copy_from_slice(&extended.to_le_bytes());
+            memory_size_mib(&f)
+        };
+        assert_eq!(with_size(0xFFFF, 0), None);
+        assert_eq!(with_size(0x8000 | 512, 0), Some(0));
+        assert_eq!(with_size(0x7FFF, 65536), Some(65536));
+        assert_eq!(with_size(4096, 0), Some(4096));
+    }
+}
@@ -0,0 +1,160 @@
+//! Kernel stack protection. Every kernel stack sits directly above an unmapped
+//! guard page and carries a canary in its lowest word. Running off the end
+//! faults on the guard page; a frame large enough to skip over it is caught
+//! when the canary is checked on the next task switch. Either way the report
+//! names the stack instead of ending in an anonymous double fault.
+
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::{hlt, interrupts};
+
+use crate::{rng, serial, vmm};
+
+pub const GUARD_SIZE: usize = vmm::PAGE_SIZE as usize;
+const MAX_STACKS: usize = 16;
+/// Placeholder until `init` draws a random value.
+const BOOT_CANARY: u64 = 0x57AC_C0DE_DEAD_BEEF;
+
+/// Statically allocated stack with its guard page in front (below) of it.
+/// The memory is only ever touched through `rsp`, never through Rust
+/// references, so a plain (non-`mut`) static is enough; the cells keep it
+/// out of read-only sections.
+#[repr(C, align(4096))]
+pub struct GuardedStack<const SIZE: usize> {
+    guard: UnsafeCell<[u8; GUARD_SIZE]>,
+    stack: UnsafeCell<[u8; SIZE]>,
+}
+
+unsafe impl<const SIZE: usize> Sync for GuardedStack<SIZE> {}
+
+impl<const SIZE: usize> GuardedStack<SIZE> {
+    pub const fn new() -> Self {
+        Self { guard: UnsafeCell::new([0; GUARD_SIZE]), stack: UnsafeCell::new([0; SIZE]) }
+    }
+
+    pub fn bounds(&self) -> StackBounds {
+        let guard = self as *const Self as u64;
+        let bottom = guard + GUARD_SIZE as u64;
+        StackBounds { guard, bottom, top: bottom + SIZE as u64 }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub struct StackBounds {
+    /// First byte of the guard page (unmapped once `init` has run).
+    pub guard: u64,
+    pub bottom: u64,
+    pub top: u64,
+}
+
+#[derive(Copy, Clone)]
+struct Entry {
+    name: &'static str,
+    bounds: StackBounds,
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RegisterError {
+    Full,
+}
+
+static CANARY: AtomicU64 = AtomicU64::new(BOOT_CANARY);
+static STACKS: Mutex<[Option<Entry>; MAX_STACKS]> = Mutex::new([None; MAX_STACKS]);
+
+fn canary_slot(bounds: &StackBounds) -> *mut u64 {
+    bounds.bottom as *mut u64
+}
+
+/// Track `bounds` under `name` and plant its canary.
+pub fn register(name: &'static str, bounds: StackBounds) -> Result<(), RegisterError> {
+    let mut stacks = STACKS.lock();
+    let slot = stacks.iter_mut().find(|s| s.is_none()).ok_or(RegisterError::Full)?;
+    unsafe { canary_slot(&bounds).write_volatile(CANARY.load(Ordering::Relaxed)) };
+    *slot = Some(Entry { name, bounds });
+    Ok(())
+}
+
+/// Whether the canary of the stack at `bounds` is intact.
+pub fn canary_intact(bounds: &StackBounds) -> bool {
+    unsafe { canary_slot(bounds).read_volatile() == CANARY.load(Ordering::Relaxed) }
+}
+
+/// Verify every registered canary; a smashed one is fatal.
+pub fn check_all() {
+    let stacks = STACKS.lock();
+    for entry in stacks.iter().flatten() {
+        if !canary_intact(&entry.bounds) {
+            let name = entry.name;
+            let bounds = entry.bounds;
+            drop(stacks);
+            smashed(name, &bounds);
+        }
+    }
+}
+
+/// Report a smashed canary and stop: whatever sat below the stack is gone.
+pub fn smashed(name: &str, bounds: &StackBounds) -> ! {
+    interrupts::disable();
+    serial::write_fmt(format_args!(
+        "[stack] canary smashed on '{}' stack {:#x}..{:#x}: found {:#018x}\r\n",
+        name,
+        bounds.bottom,
+        bounds.top,
+        unsafe { canary_slot(bounds).read_volatile() },
+    ));
+    loop {
+        hlt();
+    }
+}
+
+/// Name of the stack whose guard page contains `addr`. Used from the page
+/// fault handler, so it never waits on the registry lock.
+pub fn guard_owner(addr: u64) -> Option<&'static str> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .flatten()
+        .find(|e| addr >= e.bounds.guard && addr < e.bounds.bottom)
+        .map(|e| e.name)
+}
+
+pub fn for_each_stack(mut f: impl FnMut(&'static str, &StackBounds, bool)) {
+    let stacks = STACKS.lock();
+    for entry in stacks.iter().flatten() {
+        f(entry.name, &entry.bounds, canary_intact(&entry.bounds));
+    }
+}
+
+/// Pick the boot's canary, then unmap the guard pages of the static stacks
+/// and start tracking them. Stacks registered earlier keep working: their
+/// canaries are rewritten with the new value.
+pub fn init(statics: &[(&'static str, StackBounds)]) {
+    let canary = rng::next_u64() | 1;
+    CANARY.store(canary, Ordering::Relaxed);
+    for slot in STACKS.lock().iter().flatten() {
+        unsafe { canary_slot(&slot.bounds).write_volatile(canary) };
+    }
+    for &(name, bounds) in statics {
+        if let Err(e) = vmm::unmap_identity_page(bounds.guard) {
+            serial::write_fmt(format_args!("[stack] {} guard: {:?}\r\n", name, e));
+        }
+        if register(name, bounds).is_err() {
+            serial::write_fmt(format_args!("[stack] registry full, '{}' untracked\r\n", name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bounds_put_guard_below_stack() {
+        static S: GuardedStack<8192> = GuardedStack::new();
+        let b = S.bounds();
+        assert_eq!(b.guard % 4096, 0);
+        assert_eq!(b.bottom, b.guard + 4096);
+        assert_eq!(b.top - b.bottom, 8192);
+    }
+}
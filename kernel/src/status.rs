@@ -0,0 +1,89 @@
+//! Subsystem health: what came up at boot, what failed and what was left
+//! out, so the rest of the kernel can run degraded instead of assuming
+//! every device is there.
+//!
+//! Init stages are recorded as they run; a stage or driver that fails or
+//! finds nothing to drive says so with `set` under its own name (`usb`,
+//! `ai`, `acpi`...). The first report for a name wins over the stage's
+//! default "ok". `status` lists the registry.
+
+use spin::Mutex;
+
+use crate::init::MAX_INITCALLS;
+use crate::serial;
+
+/// Init stages plus subsystems that are not stages of their own.
+pub const MAX_ENTRIES: usize = MAX_INITCALLS + 8;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Health {
+    Ok,
+    Failed,
+    /// Not started: nothing to drive, disabled, or a dependency is missing.
+    Skipped,
+}
+
+impl Health {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Health::Ok => "ok",
+            Health::Failed => "failed",
+            Health::Skipped => "skipped",
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+pub struct Entry {
+    pub name: &'static str,
+    pub health: Health,
+    pub reason: &'static str,
+}
+
+static REGISTRY: Mutex<[Option<Entry>; MAX_ENTRIES]> = Mutex::new([None; MAX_ENTRIES]);
+
+/// Record `name`'s health, replacing an earlier report.
+pub fn set(name: &'static str, health: Health, reason: &'static str) {
+    let mut reg = REGISTRY.lock();
+    let slot = match reg.iter().position(|e| e.is_some_and(|e| e.name == name)) {
+        Some(i) => i,
+        None => match reg.iter().position(Option::is_none) {
+            Some(i) => i,
+            None => {
+                serial::write_str("[status] registry full\r\n");
+                return;
+            }
+        },
+    };
+    reg[slot] = Some(Entry { name, health, reason });
+    if health != Health::Ok {
+        serial::write_fmt(format_args!("[status] {} {}: {}\r\n", name, health.as_str(), reason));
+    }
+}
+
+/// A stage returned: "ok" unless it already reported otherwise.
+pub fn ran(name: &'static str) {
+    if get(name).is_none() {
+        set(name, Health::Ok, "");
+    }
+}
+
+pub fn get(name: &str) -> Option<Entry> {
+    REGISTRY.lock().iter().flatten().find(|e| e.name == name).copied()
+}
+
+/// Whether `name` came up. Unknown names count as down.
+pub fn up(name: &str) -> bool {
+    get(name).is_some_and(|e| e.health == Health::Ok)
+}
+
+/// Whether anything failed or was skipped.
+pub fn degraded() -> bool {
+    REGISTRY.lock().iter().flatten().any(|e| e.health != Health::Ok)
+}
+
+pub fn for_each(mut f: impl FnMut(&Entry)) {
+    for e in REGISTRY.lock().iter().flatten() {
+        f(e);
+    }
+}
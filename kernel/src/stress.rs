@@ -0,0 +1,271 @@
+//! Load generators for exercising the agent and its thresholds by hand:
+//! `stress mem` holds a block of physical memory for a while, `stress pf`
+//! produces page faults at a fixed rate. Both run as executor tasks so the
+//! shell and the agent keep running while the load is applied. `stress cpu`
+//! instead adds busy tasks to the agent's round robin (`task`), so they show
+//! up in the run queue length the agent sees.
+
+use core::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+
+use crate::executor::{self, SpawnError};
+use crate::process::{self, Pid};
+use crate::{idt, pmm, serial, vmm};
+
+pub const MAX_MEM_MIB: u64 = 1024;
+pub const MAX_PF_RATE: u32 = 10_000;
+pub const DEFAULT_SECONDS: u64 = 10;
+/// Faults are issued in batches this often.
+const PF_PERIOD_MS: u64 = 100;
+/// Never fault in a page when the pmm is this close to empty.
+const PF_RESERVE_KIB: u64 = 256;
+/// User page touched by the fault generator; unmapped again after each fault.
+const FAULT_PAGE: u64 = process::USER_HEAP_BASE;
+
+static MEM_ACTIVE: AtomicBool = AtomicBool::new(false);
+static PF_ACTIVE: AtomicBool = AtomicBool::new(false);
+static STOP: AtomicBool = AtomicBool::new(false);
+/// Process whose lazy heap takes the faults (0 until first use).
+static PF_PID: AtomicU32 = AtomicU32::new(0);
+/// End of the CPU load (executor deadline), 0 when none is running.
+static CPU_DEADLINE: AtomicU64 = AtomicU64::new(0);
+#[cfg(feature = "ai_agent")]
+pub const MAX_CPU_TASKS: usize = 4;
+/// `task` slots of the busy tasks.
+#[cfg(feature = "ai_agent")]
+static CPU_TASKS: spin::Mutex<[Option<usize>; MAX_CPU_TASKS]> = spin::Mutex::new([None; MAX_CPU_TASKS]);
+/// How long one busy task keeps the CPU each time it is scheduled.
+#[cfg(feature = "ai_agent")]
+const CPU_SLICE_MS: u64 = 5;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum StressError {
+    Busy,
+    OutOfRange,
+    NoMemory,
+    NoProcess,
+    Spawn(SpawnError),
+}
+
+impl StressError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            StressError::Busy => "already running",
+            StressError::OutOfRange => "value out of range",
+            StressError::NoMemory => "not enough free memory",
+            StressError::NoProcess => "cannot create the fault process",
+            StressError::Spawn(_) => "no free task slot",
+        }
+    }
+}
+
+/// Allocate `mib` MiB, write to every page, and release it after `seconds`.
+pub fn mem(mib: u64, seconds: u64) -> Result<(), StressError> {
+    if mib == 0 || mib > MAX_MEM_MIB {
+        return Err(StressError::OutOfRange);
+    }
+    if MEM_ACTIVE.swap(true, Ordering::AcqRel) {
+        return Err(StressError::Busy);
+    }
+    let size = mib * 1024 * 1024;
+    let Some(base) = pmm::alloc_aligned("stress.mem", size, vmm::PAGE_SIZE) else {
+        MEM_ACTIVE.store(false, Ordering::Release);
+        return Err(StressError::NoMemory);
+    };
+    for page in (0..size).step_by(vmm::PAGE_SIZE as usize) {
+        unsafe { crate::addr::PhysAddr::new(base + page).as_mut_ptr::<u8>().write_volatile(0xA5) };
+    }
+    serial::write_fmt(format_args!("[stress] holding {} MiB at {:#x} for {} s\r\n", mib, base, seconds));
+    STOP.store(false, Ordering::Relaxed);
+    executor::spawn("stress-mem", hold(base, size, seconds)).map_err(|e| {
+        release(base, size);
+        StressError::Spawn(e)
+    })?;
+    Ok(())
+}
+
+async fn hold(base: u64, size: u64, seconds: u64) {
+    let mut left = seconds * 1000;
+    while left > 0 && !STOP.load(Ordering::Relaxed) {
+        let step = left.min(PF_PERIOD_MS);
+        executor::sleep_ms(step).await;
+        left -= step;
+    }
+    release(base, size);
+}
+
+fn release(base: u64, size: u64) {
+    if pmm::free_last(base, size) {
+        serial::write_fmt(format_args!("[stress] released {} MiB\r\n", size / (1024 * 1024)));
+    } else {
+        serial::write_fmt(format_args!(
+            "[stress] {} MiB at {:#x} kept: allocated over since\r\n",
+            size / (1024 * 1024),
+            base
+        ));
+    }
+    MEM_ACTIVE.store(false, Ordering::Release);
+}
+
+/// Faults to raise in batch `n` (0-based) of a second split into
+/// `batches`, spreading the remainder so each second totals `rate`.
+fn batch_size(rate: u32, batches: u32, n: u32) -> u32 {
+    let n = n % batches;
+    let (rate, batches, n) = (rate as u64, batches as u64, n as u64);
+    (rate * (n + 1) / batches - rate * n / batches) as u32
+}
+
+fn fault_pid() -> Result<Pid, StressError> {
+    match PF_PID.load(Ordering::Relaxed) {
+        // Still there unless `kill` ended it
+        pid if pid != 0 && process::with_process(pid, |_| ()).is_some() => Ok(pid),
+        _ => {
+            let pid = process::create().map_err(|_| StressError::NoProcess)?;
+            PF_PID.store(pid, Ordering::Relaxed);
+            Ok(pid)
+        }
+    }
+}
+
+/// Touch the unmapped page in `pid`'s heap, let the fault handler back it,
+/// then unmap it and hand the frame back so the next touch faults again.
+fn fault_once(pid: Pid) -> bool {
+    if pmm::free_kib() < PF_RESERVE_KIB {
+        return false;
+    }
+    interrupts::without_interrupts(|| {
+        let prev = process::current();
+        if !process::switch_to(pid) {
+            return false;
+        }
+        let before = idt::page_faults();
+        unsafe { (FAULT_PAGE as *mut u8).write_volatile(1) };
+        let frame = process::with_process(pid, |p| p.aspace.unmap_page(FAULT_PAGE)).flatten();
+        process::switch_to(prev);
+        if let Some(frame) = frame {
+            pmm::free_last(frame, vmm::PAGE_SIZE);
+        }
+        idt::page_faults() > before
+    })
+}
+
+/// Raise `rate` page faults per second for `seconds`.
+pub fn page_faults(rate: u32, seconds: u64) -> Result<(), StressError> {
+    if rate == 0 || rate > MAX_PF_RATE {
+        return Err(StressError::OutOfRange);
+    }
+    if PF_ACTIVE.swap(true, Ordering::AcqRel) {
+        return Err(StressError::Busy);
+    }
+    let pid = match fault_pid() {
+        Ok(pid) => pid,
+        Err(e) => {
+            PF_ACTIVE.store(false, Ordering::Release);
+            return Err(e);
+        }
+    };
+    serial::write_fmt(format_args!("[stress] {} page faults/s for {} s\r\n", rate, seconds));
+    STOP.store(false, Ordering::Relaxed);
+    executor::spawn("stress-pf", fault_loop(pid, rate, seconds)).map_err(|e| {
+        PF_ACTIVE.store(false, Ordering::Release);
+        StressError::Spawn(e)
+    })?;
+    Ok(())
+}
+
+async fn fault_loop(pid: Pid, rate: u32, seconds: u64) {
+    let batches = (1000 / PF_PERIOD_MS) as u32;
+    let total = seconds * batches as u64;
+    let mut raised = 0u64;
+    for n in 0..total {
+        if STOP.load(Ordering::Relaxed) {
+            break;
+        }
+        for _ in 0..batch_size(rate, batches, n as u32) {
+            if !fault_once(pid) {
+                serial::write_str("[stress] fault generation stopped: low memory\r\n");
+                PF_ACTIVE.store(false, Ordering::Release);
+                return;
+            }
+            raised += 1;
+        }
+        executor::sleep_ms(PF_PERIOD_MS).await;
+    }
+    serial::write_fmt(format_args!("[stress] raised {} page faults\r\n", raised));
+    PF_ACTIVE.store(false, Ordering::Release);
+}
+
+/// Register `n` busy tasks for `seconds`; returns how many got a slot.
+#[cfg(feature = "ai_agent")]
+pub fn cpu(n: usize, seconds: u64) -> Result<usize, StressError> {
+    if n == 0 || n > MAX_CPU_TASKS {
+        return Err(StressError::OutOfRange);
+    }
+    let mut slots = CPU_TASKS.lock();
+    if slots.iter().any(Option::is_some) {
+        return Err(StressError::Busy);
+    }
+    CPU_DEADLINE.store(executor::deadline_after_ms(seconds * 1000).max(1), Ordering::Relaxed);
+    let mut started = 0;
+    for slot in slots.iter_mut().take(n) {
+        *slot = crate::task::register("busy", busy);
+        started += slot.is_some() as usize;
+    }
+    if started == 0 {
+        CPU_DEADLINE.store(0, Ordering::Relaxed);
+        return Err(StressError::Spawn(SpawnError::Full));
+    }
+    serial::write_fmt(format_args!("[stress] {} busy tasks for {} s\r\n", started, seconds));
+    Ok(started)
+}
+
+/// Spin for one slice, or remove all busy tasks once the load is over.
+#[cfg(feature = "ai_agent")]
+fn busy() {
+    let deadline = CPU_DEADLINE.load(Ordering::Relaxed);
+    if deadline == 0 || executor::expired(deadline) {
+        let mut slots = CPU_TASKS.lock();
+        for index in slots.iter_mut().filter_map(Option::take) {
+            crate::task::unregister(index);
+        }
+        CPU_DEADLINE.store(0, Ordering::Relaxed);
+        serial::write_str("[stress] busy tasks done\r\n");
+        return;
+    }
+    let slice_end = executor::deadline_after_ms(CPU_SLICE_MS);
+    while !executor::expired(slice_end) {
+        core::hint::spin_loop();
+    }
+}
+
+/// Ask running generators to finish early.
+pub fn stop() {
+    STOP.store(true, Ordering::Relaxed);
+    CPU_DEADLINE.store(0, Ordering::Relaxed);
+}
+
+/// Whether a CPU load is running; the idle loop does not halt meanwhile.
+pub fn cpu_active() -> bool {
+    CPU_DEADLINE.load(Ordering::Relaxed) != 0
+}
+
+/// (memory held, fault generator running, CPU load running)
+pub fn active() -> (bool, bool, bool) {
+    (MEM_ACTIVE.load(Ordering::Relaxed), PF_ACTIVE.load(Ordering::Relaxed), cpu_active())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn batches_sum_to_rate() {
+        for rate in [1, 7, 10, 95, 1234] {
+            let total: u32 = (0..10).map(|n| batch_size(rate, 10, n)).sum();
+            assert_eq!(total, rate);
+        }
+        assert_eq!(batch_size(5, 10, 0), 0);
+        assert_eq!(batch_size(5, 10, 1), 1);
+        assert_eq!(batch_size(5, 10, 11), 1);
+    }
+}
@@ -1,24 +1,408 @@
-use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
-use x86_64::PrivilegeLevel;
+use core::sync::atomic::{AtomicU32, Ordering};
+use spin::Mutex;
+use x86_64::structures::idt::InterruptDescriptorTable;
+use x86_64::{PrivilegeLevel, VirtAddr};
 
-use crate::serial;
+use crate::ai_action::{Action, ActionOutcome};
+use crate::apply_action::{self, Caller};
+use crate::telemetry::{self, Telemetry};
+use crate::usercopy::{self, UserResult};
+use crate::futex;
+use crate::pipe::{self, PipeError};
+use crate::process::{self, Pid};
+use crate::klog::{self, Level};
+use crate::{keyboard, ktrace, serial, vga};
 
 pub const SYSCALL_VECTOR: usize = 0x80;
 
+// ABI: `int 0x80` with rax = number, rdi/rsi/rdx = arguments; result in rax
+// (negative errno-style on failure). Other general purpose registers are preserved.
+pub mod nr {
+    pub const GET_TELEMETRY: u64 = 1;
+    pub const PROPOSE_ACTION: u64 = 2;
+    pub const WRITE: u64 = 3;
+    pub const READ: u64 = 4;
+    pub const EXIT: u64 = 5;
+    pub const WAIT: u64 = 6;
+    pub const PIPE: u64 = 7;
+    pub const CLOSE: u64 = 8;
+    pub const FUTEX: u64 = 9;
+}
+
+pub const EINVAL: i64 = -22;
+pub const ECHILD: i64 = -10;
+pub const EAGAIN: i64 = -11;
+pub const EMFILE: i64 = -24;
+pub const EPIPE: i64 = -32;
+pub const ENOSYS: i64 = -38;
+pub const EBADF: i64 = -9;
+
+// Console I/O goes through a bounce buffer so user memory is only touched via usercopy.
+const IO_CHUNK: usize = 256;
+
+// Saved by the entry stub (lowest address first), followed by the CPU interrupt frame.
+#[repr(C)]
+struct SyscallFrame {
+    r11: u64,
+    r10: u64,
+    r9: u64,
+    r8: u64,
+    rdi: u64,
+    rsi: u64,
+    rdx: u64,
+    rcx: u64,
+    rax: u64,
+    rip: u64,
+    cs: u64,
+    rflags: u64,
+    rsp: u64,
+    ss: u64,
+}
+
+core::arch::global_asm!(
+    ".global syscall_int80_entry",
+    "syscall_int80_entry:",
+    "push rax",
+    "push rcx",
+    "push rdx",
+    "push rsi",
+    "push rdi",
+    "push r8",
+    "push r9",
+    "push r10",
+    "push r11",
+    "mov rdi, rsp",
+    "cld",
+    "call syscall_dispatch",
+    "pop r11",
+    "pop r10",
+    "pop r9",
+    "pop r8",
+    "pop rdi",
+    "pop rsi",
+    "pop rdx",
+    "pop rcx",
+    "pop rax",
+    "iretq",
+);
+
+extern "C" {
+    fn syscall_int80_entry();
+}
+
 pub fn configure_idt(idt: &mut InterruptDescriptorTable, user_level: PrivilegeLevel) {
-    idt[SYSCALL_VECTOR]
-        .set_handler_fn(syscall_entry)
-        .set_privilege_level(user_level);
+    unsafe {
+        idt[SYSCALL_VECTOR]
+            .set_handler_addr(VirtAddr::new(syscall_int80_entry as *const () as u64))
+            .set_privilege_level(user_level);
+    }
+}
+
+/// Processes whose syscalls go to the trace ring (`strace <pid> on`).
+/// Checked on every syscall, so lock-free; `UNTRACED` marks a free slot.
+const MAX_TRACED: usize = 8;
+const UNTRACED: u32 = u32::MAX;
+static TRACED: [AtomicU32; MAX_TRACED] = [const { AtomicU32::new(UNTRACED) }; MAX_TRACED];
+
+pub fn traced(pid: Pid) -> bool {
+    TRACED.iter().any(|slot| slot.load(Ordering::Relaxed) == pid)
+}
+
+/// Start or stop tracing `pid`'s syscalls.
+pub fn set_traced(pid: Pid, on: bool) -> Result<(), &'static str> {
+    if traced(pid) == on {
+        return Ok(());
+    }
+    if !on {
+        for slot in TRACED.iter() {
+            let _ = slot.compare_exchange(pid, UNTRACED, Ordering::Relaxed, Ordering::Relaxed);
+        }
+        return Ok(());
+    }
+    TRACED
+        .iter()
+        .find(|slot| slot.compare_exchange(UNTRACED, pid, Ordering::Relaxed, Ordering::Relaxed).is_ok())
+        .map(|_| ())
+        .ok_or("too many traced processes")
+}
+
+pub fn for_each_traced(mut f: impl FnMut(Pid)) {
+    TRACED.iter().map(|slot| slot.load(Ordering::Relaxed)).filter(|&pid| pid != UNTRACED).for_each(&mut f);
+}
+
+/// Enter event and the arguments, in 32-bit halves; an upper half that is
+/// zero is left out.
+fn trace_enter(nr: u16, pid: Pid, args: [u64; 3]) {
+    ktrace::event(ktrace::Kind::SyscallEnter, nr, pid);
+    for (i, arg) in args.into_iter().enumerate() {
+        ktrace::event(ktrace::Kind::SyscallArg, i as u16, arg as u32);
+        if arg >> 32 != 0 {
+            ktrace::event(ktrace::Kind::SyscallArg, i as u16 | ktrace::ARG_HIGH, (arg >> 32) as u32);
+        }
+    }
+}
+
+#[no_mangle]
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    let caller = if frame.cs & 3 == 3 { Caller::User } else { Caller::Kernel };
+    percpu!(syscalls).fetch_add(1, Ordering::Relaxed);
+    let pid = process::current();
+    let nr = frame.rax.min(u16::MAX as u64) as u16;
+    let trace = traced(pid);
+    if trace {
+        trace_enter(nr, pid, [frame.rdi, frame.rsi, frame.rdx]);
+    }
+    let ret = match frame.rax {
+        nr::GET_TELEMETRY => sys_get_telemetry(frame.rdi, frame.rsi as usize, caller),
+        nr::PROPOSE_ACTION => sys_propose_action(frame.rdi, frame.rsi, caller),
+        nr::WRITE => sys_write(pid, frame.rdi, frame.rsi, frame.rdx as usize, caller),
+        nr::READ => sys_read(pid, frame.rdi, frame.rsi, frame.rdx as usize, caller),
+        nr::EXIT => sys_exit(pid, frame.rdi as i32, caller),
+        nr::WAIT => sys_wait(pid, frame.rdi, frame.rsi, caller),
+        nr::PIPE => sys_pipe(pid, frame.rdi, caller),
+        nr::CLOSE => Ok(if process::fd_close(pid, frame.rdi) { 0 } else { EBADF }),
+        nr::FUTEX => sys_futex(pid, frame.rdi, frame.rsi, frame.rdx, caller),
+        _ => Ok(ENOSYS),
+    };
+    let ret = ret.unwrap_or_else(|e| e.errno());
+    if trace {
+        ktrace::event(ktrace::Kind::SyscallExit, nr, ret as i32 as u32);
+    }
+    frame.rax = ret as u64;
+}
+
+// Rate baselines for syscall callers, independent from the in-kernel agent's.
+static BASELINE: Mutex<telemetry::Baseline> = Mutex::new(telemetry::Baseline::boot());
+
+fn sys_get_telemetry(buf: u64, len: usize, caller: Caller) -> UserResult<i64> {
+    if len < core::mem::size_of::<Telemetry>() {
+        return Ok(EINVAL);
+    }
+    let tel = telemetry::gather(&mut BASELINE.lock());
+    usercopy::write_user(caller, buf, &tel)?;
+    Ok(0)
+}
+
+fn sys_propose_action(action: u64, outcome: u64, caller: Caller) -> UserResult<i64> {
+    let a: Action = usercopy::read_user(caller, action)?;
+    let mut out: ActionOutcome = usercopy::read_user(caller, outcome)?;
+    out.result = apply_action::propose(&a, caller);
+    usercopy::write_user(caller, outcome, &out)?;
+    Ok(0)
+}
+
+// rdi = address of a u32, rsi = futex::WAIT or WAKE, rdx = the value WAIT
+// expects or how many WAKE wakes. WAKE returns the number woken. WAIT parks
+// a kernel-mode caller and returns 0 at once: the scheduler passes over its
+// tasks until the wake. From ring 3 it is ENOSYS, since `iretq` would go
+// back into the blocked process with the kernel's tables loaded; a lock
+// falls back to spinning.
+fn sys_futex(pid: Pid, addr: u64, op: u64, val: u64, caller: Caller) -> UserResult<i64> {
+    if pid == process::KERNEL_PID || addr & 3 != 0 {
+        return Ok(EINVAL);
+    }
+    if op == futex::WAIT && caller == Caller::User {
+        return Ok(ENOSYS);
+    }
+    // Touching the word backs a lazy user page before it is translated
+    let word: u32 = usercopy::read_user(caller, addr)?;
+    let key = match caller {
+        Caller::Kernel => addr,
+        Caller::User => match crate::vmm::translate_active(addr) {
+            Some(phys) => phys,
+            None => return Ok(EINVAL),
+        },
+    };
+    match op {
+        // The lock moved on, or too many waiters already
+        futex::WAIT if word as u64 != val || !futex::wait(key, pid) => Ok(EAGAIN),
+        futex::WAIT => Ok(0),
+        futex::WAKE => Ok(futex::wake(key, val.min(usize::MAX as u64) as usize) as i64),
+        _ => Ok(EINVAL),
+    }
+}
+
+fn pipe_errno(e: PipeError) -> i64 {
+    match e {
+        PipeError::NoSlot => EMFILE,
+        PipeError::BadEnd => EBADF,
+        PipeError::WouldBlock => EAGAIN,
+        PipeError::Broken => EPIPE,
+    }
+}
+
+// Both ends go in the caller's fd table; `fds` gets [read fd, write fd] as u32.
+fn sys_pipe(pid: Pid, fds: u64, caller: Caller) -> UserResult<i64> {
+    let (r, w) = match pipe::create() {
+        Ok(ends) => ends,
+        Err(e) => return Ok(pipe_errno(e)),
+    };
+    let r_fd = process::fd_install(pid, r);
+    let w_fd = r_fd.and_then(|_| process::fd_install(pid, w));
+    let (Ok(r_fd), Ok(w_fd)) = (r_fd, w_fd) else {
+        if let Ok(fd) = r_fd {
+            process::fd_close(pid, fd);
+        } else {
+            pipe::close(r);
+        }
+        pipe::close(w);
+        return Ok(EMFILE);
+    };
+    if let Err(e) = usercopy::write_user(caller, fds, &[r_fd as u32, w_fd as u32]) {
+        process::fd_close(pid, r_fd);
+        process::fd_close(pid, w_fd);
+        return Err(e);
+    }
+    Ok(0)
+}
+
+// Bytes written to a pipe fd; stops at the first chunk the pipe does not
+// take in full, so a partial count is possible. EAGAIN if nothing fit.
+fn pipe_write(end: pipe::End, buf: u64, len: usize, caller: Caller) -> UserResult<i64> {
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut done = 0usize;
+    while done < len {
+        let n = (len - done).min(IO_CHUNK);
+        usercopy::copy_from_user(caller, &mut chunk[..n], buf + done as u64)?;
+        match pipe::write(end, &chunk[..n]) {
+            Ok(taken) => {
+                done += taken;
+                if taken < n {
+                    break;
+                }
+            }
+            Err(e) if done == 0 => return Ok(pipe_errno(e)),
+            Err(_) => break,
+        }
+    }
+    Ok(done as i64)
 }
 
-extern "x86-interrupt" fn syscall_entry(stack: InterruptStackFrame) {
-    serial::write_str("[syscall] entry\r\n");
-    serial::write_fmt(format_args!(
-        "  user_stack: {:#018x}\r\n",
-        stack.stack_pointer.as_u64()
-    ));
+// Up to IO_CHUNK bytes from a pipe fd; 0 at end of file, EAGAIN if empty.
+fn pipe_read(end: pipe::End, buf: u64, len: usize, caller: Caller) -> UserResult<i64> {
+    let mut chunk = [0u8; IO_CHUNK];
+    let n = match pipe::read(end, &mut chunk[..len.min(IO_CHUNK)]) {
+        Ok(n) => n,
+        Err(e) => return Ok(pipe_errno(e)),
+    };
+    // A fault loses these bytes, as it would for the keyboard's
+    usercopy::copy_to_user(caller, buf, &chunk[..n])?;
+    Ok(n as i64)
+}
+
+// fd 1/2: console (serial + VGA); fds from `PIPE`. Returns the number of bytes written.
+fn sys_write(pid: Pid, fd: u64, buf: u64, len: usize, caller: Caller) -> UserResult<i64> {
+    if let Some(end) = process::fd_get(pid, fd) {
+        return pipe_write(end, buf, len, caller);
+    }
+    if fd != 1 && fd != 2 {
+        return Ok(EBADF);
+    }
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut done = 0usize;
+    while done < len {
+        let n = (len - done).min(IO_CHUNK);
+        usercopy::copy_from_user(caller, &mut chunk[..n], buf + done as u64)?;
+        for b in chunk[..n].iter_mut() {
+            if !b.is_ascii() {
+                *b = b'?';
+            }
+        }
+        let s = core::str::from_utf8(&chunk[..n]).unwrap_or("");
+        serial::write_str(s);
+        vga::write_str(s);
+        done += n;
+    }
+    Ok(done as i64)
+}
+
+// fd 0: keyboard queue, non-blocking, echoed to VGA like the shell's input. Returns the number of bytes read (0 if none pending).
+// Pipe fds: see `pipe_read`.
+fn sys_read(pid: Pid, fd: u64, buf: u64, len: usize, caller: Caller) -> UserResult<i64> {
+    if let Some(end) = process::fd_get(pid, fd) {
+        return pipe_read(end, buf, len, caller);
+    }
+    if fd != 0 {
+        return Ok(EBADF);
+    }
+    let mut chunk = [0u8; IO_CHUNK];
+    let mut n = 0usize;
+    while n < len.min(IO_CHUNK) {
+        match keyboard::poll_input(keyboard::Focus::Shell) {
+            Some(input) => {
+                keyboard::echo(&input);
+                chunk[n] = input.ch as u8;
+                n += 1;
+            }
+            None => break,
+        }
+    }
+    usercopy::copy_to_user(caller, buf, &chunk[..n])?;
+    Ok(n as i64)
+}
+
+// Status in the low 32 bits. The address space is gone when this returns:
+// only a kernel-mode caller comes back, to the kernel's tables, with 0.
+// TODO(user exit): a ring-3 caller gets ENOSYS and keeps running. Nothing
+// enters ring 3 with a kernel context saved to return to, so there is no
+// kernel to resume instead of the `iretq` into freed tables; see README.
+fn sys_exit(pid: Pid, status: i32, caller: Caller) -> UserResult<i64> {
+    if caller == Caller::User {
+        klog::log(Level::Warn, format_args!("[sys] exit from ring 3 (pid={}) is not supported yet\r\n", pid));
+        return Ok(ENOSYS);
+    }
+    Ok(match process::exit(pid, status) {
+        Ok(_) => 0,
+        Err(_) => EINVAL,
+    })
+}
+
+// pid 0 waits for any child; a nonzero `status` gets the child's exit status
+// (i32). Returns the pid reaped, 0 while the children are still running.
+fn sys_wait(parent: Pid, child: u64, status: u64, caller: Caller) -> UserResult<i64> {
+    let child = match child {
+        0 => None,
+        pid => match u32::try_from(pid) {
+            Ok(pid) => Some(pid),
+            Err(_) => return Ok(ECHILD),
+        },
+    };
+    match process::wait(parent, child) {
+        Ok(Some((pid, code))) => {
+            if status != 0 {
+                usercopy::write_user(caller, status, &code)?;
+            }
+            Ok(pid as i64)
+        }
+        Ok(None) => Ok(0),
+        Err(_) => Ok(ECHILD),
+    }
 }
 
 pub fn init() {
     serial::write_str("syscall: ready\r\n");
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tracing_is_per_pid_and_bounded() {
+        assert!(!traced(3));
+        set_traced(3, true).unwrap();
+        set_traced(3, true).unwrap();
+        assert!(traced(3) && !traced(4));
+        for pid in 10..10 + MAX_TRACED as u32 - 1 {
+            set_traced(pid, true).unwrap();
+        }
+        assert_eq!(set_traced(99, true), Err("too many traced processes"));
+        let mut n = 0;
+        for_each_traced(|_| n += 1);
+        assert_eq!(n, MAX_TRACED);
+        set_traced(3, false).unwrap();
+        assert!(!traced(3));
+        set_traced(99, true).unwrap();
+        assert!(traced(99));
+    }
+}
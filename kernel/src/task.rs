@@ -1,39 +1,406 @@
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use spin::Mutex;
 
+use crate::process::{self, Pid};
+use crate::stack::{self, StackBounds};
+use crate::{apply_action, boost, ktrace, time, vmm};
+
 type TaskFn = fn();
+/// Percent of each second a background task may use.
+type ShareFn = fn() -> u32;
+
+/// Pages per task stack (a guard page sits below each).
+const TASK_STACK_PAGES: u64 = 8;
+
+#[derive(Copy, Clone)]
+struct Task {
+    /// For `stats tasks` and `BoostTask`.
+    name: &'static str,
+    func: TaskFn,
+    /// Allocated on first run once the stacks window exists; until then the
+    /// task borrows the caller's stack.
+    stack: Option<StackBounds>,
+    /// Background tasks only get turns while under their share of the CPU.
+    share: Option<ShareFn>,
+    /// Process whose address space the task runs in (`KERNEL_PID` for the
+    /// boot tables).
+    pid: Pid,
+}
+
+/// CPU use of a background task over one-second windows. Tasks are not
+/// preempted, so a run can overshoot its share; the excess is paid back
+/// out of the following windows.
+#[derive(Copy, Clone)]
+struct Budget {
+    start: u64,
+    used: u64,
+}
+
+impl Budget {
+    const fn new() -> Self {
+        Budget { start: 0, used: 0 }
+    }
+
+    /// Whether the task may run at `now` with `allowance` cycles per
+    /// `window`. A window of 0 (the TSC was never calibrated) enforces
+    /// nothing.
+    fn allows(&mut self, now: u64, window: u64, allowance: u64) -> bool {
+        if window == 0 {
+            return true;
+        }
+        let elapsed = now.wrapping_sub(self.start);
+        if elapsed >= window {
+            let windows = elapsed / window;
+            self.used = self.used.saturating_sub(allowance.saturating_mul(windows));
+            self.start = self.start.wrapping_add(windows.wrapping_mul(window));
+        }
+        self.used < allowance
+    }
+
+    fn charge(&mut self, cycles: u64) {
+        self.used = self.used.saturating_add(cycles);
+    }
+}
+
+const MAX_TASKS: usize = 8;
+/// A task whose last run took at least this long still has work; one that
+/// returns sooner is only checking its own clock.
+const RUNNABLE_US: u64 = 20;
+const NOT_RUNNING: usize = usize::MAX;
+/// Bounds of a turn's time slice, whatever `SetQuantum` asked for.
+pub const MIN_SLICE_US: u64 = 100;
+pub const MAX_SLICE_US: u64 = 50_000;
 
-static TASKS: Mutex<[Option<TaskFn>; 8]> = Mutex::new([None; 8]);
+static TASKS: Mutex<[Option<Task>; MAX_TASKS]> = Mutex::new([None; MAX_TASKS]);
 static NEXT_INDEX: Mutex<usize> = Mutex::new(0);
+/// Stacks of unregistered tasks, reused before allocating new ones (the
+/// stacks window is never freed).
+static SPARE_STACKS: Mutex<[Option<StackBounds>; 8]> = Mutex::new([None; 8]);
+
+// CPU accounting, kept in atomics so the timer interrupt can read it
+// without taking `TASKS`.
+/// TSC cycles spent in each slot's task since it was registered.
+static CPU_CYCLES: [AtomicU64; MAX_TASKS] = [const { AtomicU64::new(0) }; MAX_TASKS];
+/// Length of each slot's last run in cycles; 0 for a free slot.
+static LAST_RUN: [AtomicU64; MAX_TASKS] = [const { AtomicU64::new(0) }; MAX_TASKS];
+static RUNS: [AtomicU64; MAX_TASKS] = [const { AtomicU64::new(0) }; MAX_TASKS];
+/// Turns a background task lost to its budget.
+static THROTTLED: [AtomicU64; MAX_TASKS] = [const { AtomicU64::new(0) }; MAX_TASKS];
+static BUDGETS: Mutex<[Budget; MAX_TASKS]> = Mutex::new([Budget::new(); MAX_TASKS]);
+/// Whether this pass may go to a boosted task.
+static BOOST_TURN: AtomicBool = AtomicBool::new(true);
+
+/// Add `task` to the round robin; returns its slot, or `None` when full.
+pub fn register(name: &'static str, task: TaskFn) -> Option<usize> {
+    add(Task { name, func: task, stack: None, share: None, pid: process::KERNEL_PID })
+}
+
+/// `register` for a task running in `pid`'s address space: its turns load
+/// that CR3 and go to other tasks while the process is blocked. The task
+/// is dropped once the process has exited.
+pub fn register_in(pid: Pid, name: &'static str, task: TaskFn) -> Option<usize> {
+    add(Task { name, func: task, stack: None, share: None, pid })
+}
 
-pub fn register(task: TaskFn) -> bool {
+/// Add `task` as a background task: it is skipped once it has used
+/// `share()` percent of the current second, so long runs (inference) cannot
+/// starve the main loop's polling.
+pub fn register_background(name: &'static str, task: TaskFn, share: ShareFn) -> Option<usize> {
+    add(Task { name, func: task, stack: None, share: Some(share), pid: process::KERNEL_PID })
+}
+
+fn add(task: Task) -> Option<usize> {
     let mut slots = TASKS.lock();
-    for slot in slots.iter_mut() {
-        if slot.is_none() {
-            *slot = Some(task);
-            return true;
+    let index = slots.iter().position(|s| s.is_none())?;
+    slots[index] = Some(task);
+    CPU_CYCLES[index].store(0, Ordering::Relaxed);
+    RUNS[index].store(0, Ordering::Relaxed);
+    THROTTLED[index].store(0, Ordering::Relaxed);
+    BUDGETS.lock()[index] = Budget::new();
+    // Runnable until its first run says otherwise
+    LAST_RUN[index].store(u64::MAX, Ordering::Relaxed);
+    Some(index)
+}
+
+/// Remove the task in `index`; its stack is kept for the next task.
+pub fn unregister(index: usize) -> bool {
+    let Some(task) = TASKS.lock().get_mut(index).and_then(Option::take) else {
+        return false;
+    };
+    LAST_RUN[index].store(0, Ordering::Relaxed);
+    if let Some(bounds) = task.stack {
+        if let Some(spare) = SPARE_STACKS.lock().iter_mut().find(|s| s.is_none()) {
+            *spare = Some(bounds);
+        }
+    }
+    true
+}
+
+fn task_stack() -> Option<StackBounds> {
+    if let Some(bounds) = SPARE_STACKS.lock().iter_mut().find_map(Option::take) {
+        return Some(bounds);
+    }
+    let ks = vmm::alloc_kernel_stack(TASK_STACK_PAGES).ok()?;
+    let bounds = StackBounds { guard: ks.bottom - vmm::PAGE_SIZE, bottom: ks.bottom, top: ks.top };
+    stack::register("task", bounds).ok()?;
+    Some(bounds)
+}
+
+extern "C" fn trampoline(f: *const TaskFn) {
+    unsafe { (*f)() };
+}
+
+/// Run `f` with `rsp` at `top`, then come back to the current stack.
+unsafe fn call_on_stack(f: TaskFn, top: u64) {
+    core::arch::asm!(
+        // rax is caller-saved, so free to hold the old rsp until it is pushed
+        "mov rax, rsp",
+        "mov rsp, rsi",
+        "push rax",
+        "sub rsp, 8",
+        "call {entry}",
+        "add rsp, 8",
+        "pop rsp",
+        entry = sym trampoline,
+        in("rsi") top,
+        in("rdi") &f as *const TaskFn,
+        clobber_abi("C"),
+    );
+}
+
+/// Cycles `iters` switches onto a task stack and back take (`bench
+/// switch`); `None` if no stack can be had.
+pub fn switch_cycles(iters: u64) -> Option<u64> {
+    let bounds = task_stack()?;
+    let start = time::rdtsc();
+    for _ in 0..iters {
+        unsafe { call_on_stack(|| {}, bounds.top) };
+    }
+    let cycles = time::rdtsc().wrapping_sub(start);
+    if let Some(spare) = SPARE_STACKS.lock().iter_mut().find(|s| s.is_none()) {
+        *spare = Some(bounds);
+    }
+    Some(cycles)
+}
+
+/// Every other pass goes to a boosted task, if one is registered; the
+/// others follow the round robin. Tasks `eligible` turns down are passed
+/// over, boosted or not.
+fn pick(slots: &[Option<Task>; MAX_TASKS], next: &mut usize, mut eligible: impl FnMut(usize, &Task) -> bool) -> Option<usize> {
+    if BOOST_TURN.fetch_xor(true, Ordering::Relaxed) {
+        let boosted = (0..MAX_TASKS).find(|&i| slots[i].is_some_and(|t| boost::active(t.name) && eligible(i, &t)));
+        if boosted.is_some() {
+            return boosted;
+        }
+    }
+    for _ in 0..MAX_TASKS {
+        let i = *next % MAX_TASKS;
+        *next = (i + 1) % MAX_TASKS;
+        if slots[i].is_some_and(|t| eligible(i, &t)) {
+            return Some(i);
         }
     }
-    false
+    None
+}
+
+/// Whether the task's process can run: a task of a blocked process is
+/// passed over until a wake. One whose process is gone still gets picked,
+/// so `run_once` drops it.
+fn ready(task: &Task) -> bool {
+    process::runnable(task.pid) != Some(false)
+}
+
+/// Cycles `share` percent of a second is worth.
+fn allowance(share: ShareFn, per_ms: u64) -> u64 {
+    (share().min(100) as u64) * per_ms * 10
+}
+
+/// Whether the task in slot `i` is within its budget (always true for a
+/// task without one).
+fn within_budget(i: usize, task: &Task, now: u64, per_ms: u64) -> bool {
+    let Some(share) = task.share else { return true };
+    let ok = BUDGETS.lock()[i].allows(now, per_ms * 1000, allowance(share, per_ms));
+    if !ok {
+        THROTTLED[i].fetch_add(1, Ordering::Relaxed);
+    }
+    ok
+}
+
+/// Time slice of a turn: the quantum set by `SetQuantum`, within bounds.
+pub fn slice_us() -> u64 {
+    (apply_action::get_quantum_us() as u64).clamp(MIN_SLICE_US, MAX_SLICE_US)
+}
+
+/// Whether a task that just ran for `last` cycles, `used` into a slice of
+/// `slice`, runs again before the next task's turn.
+fn keeps_cpu(last: u64, runnable: u64, used: u64, slice: u64) -> bool {
+    last >= runnable && used < slice
 }
 
+/// One turn: the next task runs, and runs again while it has work left,
+/// its time slice is not used up and, for a background task, its share of
+/// the second is not either.
 pub fn run_once() {
+    let per_ms = time::tsc_per_ms();
     let mut idx = NEXT_INDEX.lock();
     let mut slots = TASKS.lock();
-    let len = slots.len();
-    for _ in 0..len {
-        let i = *idx % len;
-        *idx = (*idx + 1) % len;
-        if let Some(f) = slots[i] {
-            drop(slots);
-            drop(idx);
-            f();
-            return;
+    let now = time::rdtsc();
+    let Some(i) = pick(&slots, &mut idx, |i, t| ready(t) && within_budget(i, t, now, per_ms)) else { return };
+    let Some(task) = slots[i].as_mut() else { return };
+    if task.stack.is_none() && crate::kaslr::base(crate::kaslr::Region::Stacks) != 0 {
+        task.stack = task_stack();
+    }
+    let task = *task;
+    drop(slots);
+    drop(idx);
+    let prev = process::current();
+    if task.pid != prev && !process::switch_to(task.pid) {
+        // Exited (or blocked since `pick`): the task goes with the process
+        if process::with_process(task.pid, |_| ()).is_none() {
+            unregister(i);
+        }
+        return;
+    }
+    let (slice, runnable) = (slice_us() * per_ms / 1000, RUNNABLE_US * per_ms / 1000);
+    let turn = time::rdtsc();
+    loop {
+        let cycles = run(i, task);
+        // A task that unregistered itself keeps its slot free
+        if LAST_RUN[i].load(Ordering::Relaxed) == 0 {
+            break;
+        }
+        LAST_RUN[i].store(cycles, Ordering::Relaxed);
+        let now = time::rdtsc();
+        if let Some(share) = task.share {
+            let mut budgets = BUDGETS.lock();
+            budgets[i].charge(cycles);
+            if !budgets[i].allows(now, per_ms * 1000, allowance(share, per_ms)) {
+                break;
+            }
+        }
+        // Exited or blocked itself (`exit` already loaded the kernel tables)
+        if process::current() != task.pid || !keeps_cpu(cycles, runnable, now.wrapping_sub(turn), slice) {
+            break;
+        }
+    }
+    if process::current() != prev && !process::switch_to(prev) {
+        process::switch_to(process::KERNEL_PID);
+    }
+}
+
+/// Run `task` in slot `i` once; returns the cycles it took.
+fn run(i: usize, task: Task) -> u64 {
+    percpu!(current_task).store(i, Ordering::Relaxed);
+    ktrace::event(ktrace::Kind::TaskSwitch, i as u16, 0);
+    let start = time::rdtsc();
+    match task.stack {
+        Some(bounds) => {
+            unsafe { call_on_stack(task.func, bounds.top) };
+            if !stack::canary_intact(&bounds) {
+                stack::smashed("task", &bounds);
+            }
         }
+        None => (task.func)(),
     }
+    let cycles = time::rdtsc().wrapping_sub(start).max(1);
+    percpu!(current_task).store(NOT_RUNNING, Ordering::Relaxed);
+    CPU_CYCLES[i].fetch_add(cycles, Ordering::Relaxed);
+    RUNS[i].fetch_add(1, Ordering::Relaxed);
+    cycles
 }
 
-pub fn runqueue_len() -> usize {
+/// Tasks with work left: the one on the CPU and those whose last run was
+/// long enough to count. Safe to call from an interrupt handler.
+pub fn runnable() -> usize {
+    let threshold = time::tsc_per_ms() * RUNNABLE_US / 1000;
+    let current = percpu!(current_task).load(Ordering::Relaxed);
+    (0..MAX_TASKS)
+        .filter(|&i| {
+            let last = LAST_RUN[i].load(Ordering::Relaxed);
+            last != 0 && (i == current || last >= threshold)
+        })
+        .count()
+}
+
+/// Registered tasks with their slot, name, CPU time in cycles and run count.
+pub fn for_each(mut f: impl FnMut(usize, &'static str, u64, u64)) {
     let slots = TASKS.lock();
-    slots.iter().filter(|t| t.is_some()).count()
+    for (i, task) in slots.iter().enumerate() {
+        if let Some(task) = task {
+            f(i, task.name, CPU_CYCLES[i].load(Ordering::Relaxed), RUNS[i].load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// Background tasks with their slot, name, share in percent and the turns
+/// their budget cost them.
+pub fn for_each_background(mut f: impl FnMut(usize, &'static str, u32, u64)) {
+    let slots = TASKS.lock();
+    for (i, task) in slots.iter().enumerate() {
+        if let Some(Task { name, share: Some(share), .. }) = task {
+            f(i, name, share(), THROTTLED[i].load(Ordering::Relaxed));
+        }
+    }
+}
+
+pub fn find(name: &str) -> bool {
+    TASKS.lock().iter().flatten().any(|t| t.name == name)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn busy_task_keeps_cpu_until_slice_ends() {
+        assert!(keeps_cpu(500, 100, 600, 1_000));
+        assert!(!keeps_cpu(500, 100, 1_000, 1_000));
+        // Only checked its clock: next task
+        assert!(!keeps_cpu(50, 100, 50, 1_000));
+    }
+
+    #[test]
+    fn overshoot_is_paid_back_in_later_windows() {
+        let mut budget = Budget::new();
+        assert!(budget.allows(5_000, 1_000, 100));
+        // One long run: 2.5 windows' worth
+        budget.charge(250);
+        assert!(!budget.allows(5_500, 1_000, 100));
+        assert!(!budget.allows(6_000, 1_000, 100));
+        assert!(budget.allows(7_000, 1_000, 100));
+        budget.charge(40);
+        assert!(budget.allows(7_999, 1_000, 100));
+        budget.charge(20);
+        assert!(!budget.allows(7_999, 1_000, 100));
+        // Idle for a while: no credit is banked
+        assert!(budget.allows(20_000, 1_000, 100));
+        budget.charge(100);
+        assert!(!budget.allows(20_500, 1_000, 100));
+    }
+
+    #[test]
+    fn blocked_process_task_is_skipped_until_wake() {
+        let pid = process::insert_detached();
+        let mut slots = [None; MAX_TASKS];
+        slots[2] = Some(Task { name: "waiter", func: || {}, stack: None, share: None, pid });
+        slots[5] = Some(Task { name: "other", func: || {}, stack: None, share: None, pid: process::KERNEL_PID });
+        let mut next = 0;
+        assert_eq!(pick(&slots, &mut next, |_, t| ready(t)), Some(2));
+        let key = 0x7000_0000 + pid as u64;
+        assert!(crate::futex::wait(key, pid));
+        for _ in 0..3 {
+            assert_eq!(pick(&slots, &mut next, |_, t| ready(t)), Some(5));
+        }
+        assert_eq!(crate::futex::wake(key, 1), 1);
+        next = 0;
+        assert_eq!(pick(&slots, &mut next, |_, t| ready(t)), Some(2));
+    }
+
+    #[test]
+    fn uncalibrated_tsc_enforces_no_budget() {
+        let mut budget = Budget::new();
+        budget.charge(1_000_000);
+        assert!(budget.allows(5_000, 0, 0));
+        assert!(budget.allows(0, 0, 100));
+    }
+}
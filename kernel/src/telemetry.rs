@@ -0,0 +1,173 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use crate::{executor, idt, keyboard, pmm};
+
+// Shared by the in-kernel agent and the syscall interface (layout is part of the user ABI).
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Telemetry {
+    pub irq_errors: u32,
+    /// Mean ready-queue depth per timer tick since the previous sample.
+    pub runq: u32,
+    pub irq_rate: u32,   // approx ticks per loop
+    pub free_kb: u32,
+    pub pf_rate: u32,
+    /// Rolling p95 of the keypress-to-echo latency in µs (last 10 s of
+    /// keys), 0 when none was typed.
+    pub input_latency_us: u32,
+}
+
+/// Ready-queue depth summed over every timer tick since boot.
+static RUNQ_SUM: AtomicU64 = AtomicU64::new(0);
+
+/// Work waiting for the CPU right now: woken async tasks plus round-robin
+/// tasks that still have work.
+fn ready_depth() -> u64 {
+    #[cfg(feature = "ai_agent")]
+    let tasks = crate::task::runnable();
+    #[cfg(not(feature = "ai_agent"))]
+    let tasks = 0;
+    (executor::ready_count() + tasks) as u64
+}
+
+/// Called from the timer interrupt.
+pub fn sample_runq() {
+    RUNQ_SUM.fetch_add(ready_depth(), Ordering::Relaxed);
+}
+
+/// Mean depth over `ticks` samples summing to `sum`, rounded to nearest;
+/// `now` stands in when no tick has passed.
+fn mean_depth(sum: u64, ticks: u64, now: u64) -> u32 {
+    match ticks {
+        0 => now as u32,
+        n => ((sum + n / 2) / n) as u32,
+    }
+}
+
+/// Counters at the previous `gather`; rates and the run queue are measured
+/// from there. Each telemetry consumer keeps its own.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Baseline {
+    ticks: u64,
+    pf: u64,
+    runq_sum: u64,
+}
+
+impl Baseline {
+    /// Zero counters: the first `gather` measures from boot.
+    pub const fn boot() -> Self {
+        Baseline { ticks: 0, pf: 0, runq_sum: 0 }
+    }
+
+    pub fn now() -> Self {
+        Baseline { ticks: idt::timer_ticks(), pf: idt::page_faults(), runq_sum: RUNQ_SUM.load(Ordering::Relaxed) }
+    }
+}
+
+pub fn gather(prev: &mut Baseline) -> Telemetry {
+    let now = Baseline::now();
+    let ticks = now.ticks.saturating_sub(prev.ticks);
+    let pf_rate = now.pf.saturating_sub(prev.pf) as u32;
+    let runq = mean_depth(now.runq_sum.saturating_sub(prev.runq_sum), ticks, ready_depth());
+    let input_latency_us = keyboard::input_latency().p95_us;
+    *prev = now;
+    let free_kb = pmm::free_kib() as u32;
+    Telemetry { irq_errors: 0, runq, irq_rate: ticks as u32, free_kb, pf_rate, input_latency_us }
+}
+
+/// Telemetry traces (`ai record` writes them, `ai replay` reads them): the
+/// magic, then one `TRACE_RECORD_LEN`-byte sample per agent step, fields in
+/// declaration order as little-endian u32. Version 1 traces (`TLT1`) lack
+/// the input latency and still replay, with it at 0.
+pub const TRACE_MAGIC: [u8; 4] = *b"TLT2";
+pub const TRACE_RECORD_LEN: usize = 24;
+const TRACE_V1_MAGIC: [u8; 4] = *b"TLT1";
+const TRACE_V1_RECORD_LEN: usize = 20;
+
+impl Telemetry {
+    fn fields(&self) -> [u32; TRACE_RECORD_LEN / 4] {
+        [self.irq_errors, self.runq, self.irq_rate, self.free_kb, self.pf_rate, self.input_latency_us]
+    }
+
+    pub fn encode(&self) -> [u8; TRACE_RECORD_LEN] {
+        let mut out = [0u8; TRACE_RECORD_LEN];
+        for (chunk, v) in out.chunks_mut(4).zip(self.fields()) {
+            chunk.copy_from_slice(&v.to_le_bytes());
+        }
+        out
+    }
+
+    /// A current or version 1 record; fields it lacks are 0.
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != TRACE_RECORD_LEN && bytes.len() != TRACE_V1_RECORD_LEN {
+            return None;
+        }
+        let at = |i: usize| {
+            bytes.get(i * 4..i * 4 + 4).map_or(0, |b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        Some(Telemetry {
+            irq_errors: at(0),
+            runq: at(1),
+            irq_rate: at(2),
+            free_kb: at(3),
+            pf_rate: at(4),
+            input_latency_us: at(5),
+        })
+    }
+}
+
+/// Samples of a trace, or `None` if `bytes` does not start with a known
+/// magic. A partial sample at the end (a recording cut short) is ignored.
+pub fn trace_samples(bytes: &[u8]) -> Option<impl Iterator<Item = Telemetry> + '_> {
+    let (body, len) = match bytes.strip_prefix(&TRACE_MAGIC[..]) {
+        Some(body) => (body, TRACE_RECORD_LEN),
+        None => (bytes.strip_prefix(&TRACE_V1_MAGIC[..])?, TRACE_V1_RECORD_LEN),
+    };
+    Some(body.chunks_exact(len).filter_map(Telemetry::decode))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runq_is_the_rounded_mean() {
+        assert_eq!(mean_depth(0, 10, 5), 0);
+        assert_eq!(mean_depth(15, 10, 0), 2);
+        assert_eq!(mean_depth(14, 10, 0), 1);
+        assert_eq!(mean_depth(0, 0, 3), 3);
+    }
+
+    #[test]
+    fn trace_round_trip() {
+        let a = Telemetry { irq_errors: 0, runq: 3, irq_rate: 18, free_kb: 65_536, pf_rate: 7, input_latency_us: 900 };
+        let mut trace = [0u8; 4 + 2 * TRACE_RECORD_LEN + 5];
+        trace[..4].copy_from_slice(&TRACE_MAGIC);
+        trace[4..28].copy_from_slice(&a.encode());
+        trace[28..52].copy_from_slice(&Telemetry { runq: 9, ..a }.encode());
+        let mut samples = trace_samples(&trace).unwrap();
+        assert_eq!(
+            samples.next().map(|t| (t.runq, t.free_kb, t.pf_rate, t.input_latency_us)),
+            Some((3, 65_536, 7, 900))
+        );
+        assert_eq!(samples.next().map(|t| t.runq), Some(9));
+        assert!(samples.next().is_none());
+        assert!(trace_samples(b"nope").is_none());
+    }
+
+    #[test]
+    fn version_1_traces_still_replay() {
+        let mut trace = [0u8; 4 + 2 * TRACE_V1_RECORD_LEN];
+        trace[..4].copy_from_slice(&TRACE_V1_MAGIC);
+        trace[8..12].copy_from_slice(&3u32.to_le_bytes());
+        trace[24 + 16..24 + 20].copy_from_slice(&7u32.to_le_bytes());
+        let samples: [Telemetry; 2] = {
+            let mut it = trace_samples(&trace).unwrap();
+            [it.next().unwrap(), it.next().unwrap()]
+        };
+        assert_eq!((samples[0].runq, samples[0].input_latency_us), (3, 0));
+        assert_eq!(samples[1].pf_rate, 7);
+    }
+}
@@ -0,0 +1,193 @@
+//! Line-oriented helpers behind the `grep`, `head`, `tail`, `wc` and
+//! `hexdump` shell builtins. They work on byte slices (a ramfs file or piped
+//! shell output) and never allocate: matches are handed to a callback.
+
+/// Lines of `text` without their terminator (`\n` or `\r\n`). A final line
+/// without a newline still counts; a trailing newline does not add an empty one.
+pub fn lines(text: &[u8]) -> impl Iterator<Item = &[u8]> {
+    let body = text.strip_suffix(b"\n").unwrap_or(text);
+    body.split(|&b| b == b'\n')
+        .filter(move |_| !text.is_empty())
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+}
+
+#[derive(Copy, Clone, Default)]
+pub struct GrepOptions {
+    pub ignore_case: bool,
+    pub invert: bool,
+}
+
+fn contains(line: &[u8], pattern: &[u8], ignore_case: bool) -> bool {
+    if pattern.is_empty() {
+        return true;
+    }
+    line.windows(pattern.len()).any(|w| {
+        if ignore_case {
+            w.eq_ignore_ascii_case(pattern)
+        } else {
+            w == pattern
+        }
+    })
+}
+
+/// Calls `emit(line_number, line)` (1-based) for each selected line and
+/// returns how many were selected.
+pub fn grep(text: &[u8], pattern: &[u8], opts: GrepOptions, mut emit: impl FnMut(usize, &[u8])) -> usize {
+    let mut count = 0;
+    for (i, line) in lines(text).enumerate() {
+        if contains(line, pattern, opts.ignore_case) != opts.invert {
+            count += 1;
+            emit(i + 1, line);
+        }
+    }
+    count
+}
+
+/// The first `n` lines of `text`, terminators included.
+pub fn head(text: &[u8], n: usize) -> &[u8] {
+    if n == 0 {
+        return &text[..0];
+    }
+    let mut seen = 0;
+    for (i, &b) in text.iter().enumerate() {
+        if b == b'\n' {
+            seen += 1;
+            if seen == n {
+                return &text[..=i];
+            }
+        }
+    }
+    text
+}
+
+/// The last `n` lines of `text`, terminators included.
+pub fn tail(text: &[u8], n: usize) -> &[u8] {
+    if n == 0 {
+        return &text[text.len()..];
+    }
+    // A trailing newline ends the last line rather than starting a new one
+    let body = text.strip_suffix(b"\n").unwrap_or(text);
+    let mut seen = 0;
+    for (i, &b) in body.iter().enumerate().rev() {
+        if b == b'\n' {
+            seen += 1;
+            if seen == n {
+                return &text[i + 1..];
+            }
+        }
+    }
+    text
+}
+
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct Counts {
+    pub lines: usize,
+    pub words: usize,
+    pub bytes: usize,
+}
+
+/// `wc` semantics: lines are newline characters, words are runs of non-blank bytes.
+pub fn wc(text: &[u8]) -> Counts {
+    let mut counts = Counts { bytes: text.len(), ..Counts::default() };
+    let mut in_word = false;
+    for &b in text {
+        if b == b'\n' {
+            counts.lines += 1;
+        }
+        if b.is_ascii_whitespace() {
+            in_word = false;
+        } else if !in_word {
+            in_word = true;
+            counts.words += 1;
+        }
+    }
+    counts
+}
+
+/// Room for a `hex_line`: offset, 16 bytes in hex, the ASCII column and CRLF.
+pub const HEX_LINE_CAP: usize = 8 + 2 + 16 * 3 + 1 + 16 + 1 + 2;
+
+/// One `hexdump` line for up to 16 bytes at `offset`; returns its length.
+pub fn hex_line(offset: u64, chunk: &[u8], out: &mut [u8; HEX_LINE_CAP]) -> usize {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    let mut n = 0;
+    let mut put = |b: u8| {
+        out[n] = b;
+        n += 1;
+    };
+    for shift in (0..8).rev() {
+        put(HEX[(offset >> (shift * 4)) as usize & 0xf]);
+    }
+    put(b' ');
+    put(b' ');
+    for i in 0..16 {
+        match chunk.get(i) {
+            Some(&b) => {
+                put(HEX[(b >> 4) as usize]);
+                put(HEX[(b & 0xf) as usize]);
+            }
+            None => {
+                put(b' ');
+                put(b' ');
+            }
+        }
+        put(b' ');
+    }
+    put(b'|');
+    for &b in chunk {
+        put(if (32..127).contains(&b) { b } else { b'.' });
+    }
+    put(b'|');
+    put(b'\r');
+    put(b'\n');
+    n
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &[u8] = b"alpha one\r\nBeta two\ngamma alpha\n";
+
+    #[test]
+    fn grep_selects_and_numbers_lines() {
+        let mut hits = [0usize; 4];
+        let mut n = 0;
+        let count = grep(TEXT, b"alpha", GrepOptions::default(), |line, _| {
+            hits[n] = line;
+            n += 1;
+        });
+        assert_eq!(count, 2);
+        assert_eq!(&hits[..n], &[1, 3]);
+
+        let opts = GrepOptions { ignore_case: true, invert: true };
+        assert_eq!(grep(TEXT, b"BETA", opts, |_, line| assert_ne!(line, b"Beta two")), 2);
+    }
+
+    #[test]
+    fn head_and_tail_keep_terminators() {
+        assert_eq!(head(TEXT, 1), b"alpha one\r\n");
+        assert_eq!(head(TEXT, 9), TEXT);
+        assert_eq!(tail(TEXT, 1), b"gamma alpha\n");
+        assert_eq!(tail(TEXT, 2), b"Beta two\ngamma alpha\n");
+        assert_eq!(tail(b"no newline", 1), b"no newline");
+    }
+
+    #[test]
+    fn wc_counts_lines_words_bytes() {
+        assert_eq!(wc(TEXT), Counts { lines: 3, words: 6, bytes: TEXT.len() });
+        assert_eq!(lines(b"").count(), 0);
+    }
+
+    #[test]
+    fn hex_lines_pad_short_chunks() {
+        let mut out = [0u8; HEX_LINE_CAP];
+        let n = hex_line(0x1230, b"AB\x00", &mut out);
+        let line = &out[..n];
+        assert!(line.starts_with(b"00001230  41 42 00    "));
+        // The ASCII column lines up whatever the chunk length
+        assert_eq!(line[8 + 2 + 16 * 3], b'|');
+        assert!(line.ends_with(b" |AB.|\r\n"));
+        assert_eq!(hex_line(0, &[0xff; 16], &mut out), HEX_LINE_CAP);
+    }
+}
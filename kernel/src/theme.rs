@@ -0,0 +1,146 @@
+//! Console colours, from the `[theme]` section of the boot config or the
+//! `theme` shell command.
+//!
+//! A colour is a VGA attribute byte, given as a number (`0x1f`) or as
+//! `fg/bg` colour names (`white/blue`; the background takes the first eight
+//! names only, bit 7 being blink). An invalid value is logged and the
+//! default kept.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::{config, serial};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Slot {
+    /// Shell console and file viewer text.
+    Text,
+    /// Boot banner, view titles, viewer title bar, lock prompt.
+    Headline,
+    /// Panic message and banner.
+    Panic,
+    /// Virtual console status bar.
+    Bar,
+    /// Log view and dashboard lines, by the level a line looks like. The
+    /// serial tail keeps no levels, so debug lines show as info.
+    Error,
+    Warn,
+    Info,
+}
+
+impl Slot {
+    pub const ALL: [Slot; 7] = [Slot::Text, Slot::Headline, Slot::Panic, Slot::Bar, Slot::Error, Slot::Warn, Slot::Info];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Slot::Text => "text",
+            Slot::Headline => "headline",
+            Slot::Panic => "panic",
+            Slot::Bar => "bar",
+            Slot::Error => "error",
+            Slot::Warn => "warn",
+            Slot::Info => "info",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Self> {
+        Slot::ALL.into_iter().find(|s| s.name() == name)
+    }
+
+    /// Config key, `theme.<name>`.
+    pub fn key(self) -> &'static str {
+        match self {
+            Slot::Text => "theme.text",
+            Slot::Headline => "theme.headline",
+            Slot::Panic => "theme.panic",
+            Slot::Bar => "theme.bar",
+            Slot::Error => "theme.error",
+            Slot::Warn => "theme.warn",
+            Slot::Info => "theme.info",
+        }
+    }
+
+    const fn default_style(self) -> u8 {
+        match self {
+            Slot::Text => 0x0f,
+            Slot::Headline => 0x1f,
+            Slot::Panic => 0x4f,
+            Slot::Bar => 0x70,
+            Slot::Error => 0x0c,
+            Slot::Warn => 0x0e,
+            Slot::Info => 0x07,
+        }
+    }
+}
+
+static STYLES: [AtomicU8; Slot::ALL.len()] = {
+    let mut styles = [const { AtomicU8::new(0) }; Slot::ALL.len()];
+    let mut i = 0;
+    while i < styles.len() {
+        styles[i] = AtomicU8::new(Slot::ALL[i].default_style());
+        i += 1;
+    }
+    styles
+};
+
+/// VGA colour names, in attribute order.
+const COLOURS: [&str; 16] = [
+    "black", "blue", "green", "cyan", "red", "magenta", "brown", "lightgray",
+    "darkgray", "lightblue", "lightgreen", "lightcyan", "lightred", "pink", "yellow", "white",
+];
+
+/// Attribute byte for `0x1f`, `31` or `white/blue`.
+pub fn parse_style(text: &str) -> Option<u8> {
+    if let Some(hex) = text.strip_prefix("0x") {
+        return u8::from_str_radix(hex, 16).ok();
+    }
+    if let Ok(n) = text.parse::<u8>() {
+        return Some(n);
+    }
+    let (fg, bg) = text.split_once('/').unwrap_or((text, "black"));
+    let fg = COLOURS.iter().position(|&c| c == fg)?;
+    let bg = COLOURS[..8].iter().position(|&c| c == bg)?;
+    Some((bg << 4 | fg) as u8)
+}
+
+/// `fg/bg` names of an attribute (blink ignored).
+pub fn describe(style: u8) -> (&'static str, &'static str) {
+    (COLOURS[(style & 0x0f) as usize], COLOURS[((style >> 4) & 0x07) as usize])
+}
+
+pub fn get(slot: Slot) -> u8 {
+    STYLES[slot as usize].load(Ordering::Relaxed)
+}
+
+pub fn set(slot: Slot, style: u8) {
+    STYLES[slot as usize].store(style, Ordering::Relaxed);
+}
+
+/// Take every `theme.*` key from the config.
+pub fn init() {
+    for slot in Slot::ALL {
+        match config::with(slot.key(), parse_style) {
+            Some(Some(style)) => set(slot, style),
+            Some(None) => serial::write_fmt(format_args!("[theme] bad colour for {}, keeping default\r\n", slot.key())),
+            None => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn styles_parse_from_numbers_and_names() {
+        assert_eq!(parse_style("0x1f"), Some(0x1f));
+        assert_eq!(parse_style("79"), Some(0x4f));
+        assert_eq!(parse_style("white/blue"), Some(0x1f));
+        assert_eq!(parse_style("yellow"), Some(0x0e));
+        assert_eq!(parse_style("white/yellow"), None);
+        assert_eq!(parse_style("0x100"), None);
+        assert_eq!(parse_style("teal"), None);
+        assert_eq!(describe(0x4f), ("white", "red"));
+        assert_eq!(Slot::parse("bar"), Some(Slot::Bar));
+        assert!(Slot::ALL.iter().all(|s| s.key().strip_prefix("theme.") == Some(s.name())));
+    }
+}
@@ -0,0 +1,174 @@
+//! Time sources: the raw TSC, calibrated once against PIT channel 2 so that
+//! cycle counts (boot profiling, latency stats) can be shown in real units.
+
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+const PIT_HZ: u64 = 1_193_182;
+const CALIBRATE_MS: u64 = 10;
+/// Rate of PIT channel 0 as the BIOS leaves it (divisor 65536), in mHz.
+const PIT_DEFAULT_MILLIHZ: u64 = PIT_HZ * 1000 / 65536;
+
+static TSC_PER_MS: AtomicU64 = AtomicU64::new(0);
+static TICK_MILLIHZ: AtomicU64 = AtomicU64::new(PIT_DEFAULT_MILLIHZ);
+static BOOT_TSC: AtomicU64 = AtomicU64::new(0);
+
+/// Read the time-stamp counter. Monotonic on every CPU we target under QEMU;
+/// values are cycles, not a wall-clock unit.
+#[inline]
+pub fn rdtsc() -> u64 {
+    unsafe { core::arch::x86_64::_rdtsc() }
+}
+
+/// Remember the TSC at kernel entry; boot durations are measured from here.
+pub fn mark_boot() {
+    BOOT_TSC.store(rdtsc(), Ordering::Relaxed);
+}
+
+/// Cycles elapsed since `mark_boot`.
+pub fn since_boot() -> u64 {
+    rdtsc().wrapping_sub(BOOT_TSC.load(Ordering::Relaxed))
+}
+
+/// Measure the TSC rate with a one-shot count on PIT channel 2 (the speaker
+/// channel, so the system timer on channel 0 is left alone).
+pub fn calibrate() {
+    let latch = PIT_HZ * CALIBRATE_MS / 1000;
+    unsafe {
+        let mut gate = Port::<u8>::new(0x61);
+        let mut cmd = Port::<u8>::new(0x43);
+        let mut ch2 = Port::<u8>::new(0x42);
+        let saved = gate.read();
+        // Gate high, speaker output off
+        gate.write((saved & !0x02) | 0x01);
+        // Channel 2, lobyte/hibyte, mode 0, binary
+        cmd.write(0xB0);
+        ch2.write((latch & 0xFF) as u8);
+        ch2.write((latch >> 8) as u8);
+        let start = rdtsc();
+        let mut spins = 0u32;
+        // OUT2 goes high on terminal count; bound the wait in case there is no PIT
+        while gate.read() & 0x20 == 0 {
+            spins += 1;
+            if spins > 10_000_000 {
+                gate.write(saved);
+                return;
+            }
+        }
+        let cycles = rdtsc().wrapping_sub(start);
+        gate.write(saved);
+        TSC_PER_MS.store(cycles / CALIBRATE_MS, Ordering::Relaxed);
+    }
+}
+
+/// TSC cycles per millisecond, or 0 if calibration has not run or failed.
+pub fn tsc_per_ms() -> u64 {
+    TSC_PER_MS.load(Ordering::Relaxed)
+}
+
+/// Timer interrupt rate in mHz: the BIOS default until the RTC cross-check
+/// has measured it.
+pub fn tick_millihz() -> u64 {
+    TICK_MILLIHZ.load(Ordering::Relaxed)
+}
+
+pub fn set_tick_millihz(millihz: u64) {
+    if millihz != 0 {
+        TICK_MILLIHZ.store(millihz, Ordering::Relaxed);
+    }
+}
+
+/// Timer ticks covering at least `ms` milliseconds.
+pub fn ticks_for_ms(ms: u64) -> u64 {
+    ticks_at_rate(ms, tick_millihz())
+}
+
+/// Milliseconds `ticks` timer ticks take at the current rate.
+pub fn ms_for_ticks(ticks: u64) -> u64 {
+    ticks.saturating_mul(1_000_000) / tick_millihz().max(1)
+}
+
+fn ticks_at_rate(ms: u64, millihz: u64) -> u64 {
+    // ms * mHz / 1e6, rounded up so a short sleep still waits one tick
+    ms.saturating_mul(millihz).div_ceil(1_000_000)
+}
+
+/// Convert a cycle count to microseconds (None before calibration).
+pub fn cycles_to_us(cycles: u64) -> Option<u64> {
+    match tsc_per_ms() {
+        0 => None,
+        per_ms => Some(cycles.saturating_mul(1000) / per_ms),
+    }
+}
+
+/// Convert a cycle count to nanoseconds (None before calibration).
+pub fn cycles_to_ns(cycles: u64) -> Option<u64> {
+    match tsc_per_ms() {
+        0 => None,
+        per_ms => Some(cycles.saturating_mul(1_000_000) / per_ms),
+    }
+}
+
+/// Cycles shown as milliseconds once the TSC is calibrated.
+pub struct Duration(pub u64);
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let text = match cycles_to_us(self.0) {
+            Some(us) => Fmt::new().args(format_args!("{}.{:03} ms", us / 1000, us % 1000)),
+            None => Fmt::new().args(format_args!("{} cyc", self.0)),
+        };
+        f.pad(text.as_str())
+    }
+}
+
+/// Small stack buffer so `Duration` can honour width/alignment via `pad`.
+struct Fmt {
+    buf: [u8; 32],
+    len: usize,
+}
+
+impl Fmt {
+    fn new() -> Self {
+        Fmt { buf: [0; 32], len: 0 }
+    }
+
+    fn args(mut self, args: fmt::Arguments) -> Self {
+        let _ = fmt::write(&mut self, args);
+        self
+    }
+
+    fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.buf[..self.len]).unwrap_or("")
+    }
+}
+
+impl fmt::Write for Fmt {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sleep_ticks_follow_the_measured_rate() {
+        assert_eq!(ticks_at_rate(1000, 1_000_000), 1000);
+        assert_eq!(ticks_at_rate(1000, PIT_DEFAULT_MILLIHZ), 19);
+        assert_eq!(ticks_at_rate(1, PIT_DEFAULT_MILLIHZ), 1);
+        assert_eq!(ticks_at_rate(0, PIT_DEFAULT_MILLIHZ), 0);
+    }
+
+    #[test]
+    fn duration_pads_and_falls_back_to_cycles() {
+        // Tests never calibrate, so the raw count is shown
+        let text = Fmt::new().args(format_args!("[{:>8}]", Duration(42)));
+        assert_eq!(text.as_str(), "[  42 cyc]");
+    }
+}
@@ -0,0 +1,177 @@
+//! USB class-driver registry.
+//!
+//! Class drivers register a match function over interface
+//! class/subclass/protocol. After SET_CONFIGURATION the enumeration path
+//! hands the configuration descriptor to `bind`, which offers every
+//! interface to the registered drivers in registration order; the first
+//! driver whose `attach` succeeds owns the interface.
+
+use crate::klog::{self, Level};
+use crate::usb_desc::{self, Parsed};
+use spin::Mutex;
+
+pub use crate::usb_desc::EndpointDescriptor as EndpointInfo;
+
+pub const MAX_DRIVERS: usize = 8;
+pub const MAX_ENDPOINTS: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InterfaceInfo {
+    pub number: u8,
+    pub alternate: u8,
+    pub class: u8,
+    pub subclass: u8,
+    pub protocol: u8,
+    pub endpoints: [Option<EndpointInfo>; MAX_ENDPOINTS],
+}
+
+impl InterfaceInfo {
+    /// First endpoint satisfying `pred`, in descriptor order.
+    pub fn find_endpoint(&self, pred: impl Fn(&EndpointInfo) -> bool) -> Option<EndpointInfo> {
+        self.endpoints.iter().flatten().copied().find(|ep| pred(ep))
+    }
+}
+
+/// What a driver gets to talk to its device.
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceHandle {
+    pub slot: u8,
+    pub config_value: u8,
+}
+
+pub struct ClassDriver {
+    pub name: &'static str,
+    pub matches: fn(&InterfaceInfo) -> bool,
+    /// Called after SET_CONFIGURATION; return false to let another driver try.
+    pub attach: fn(&DeviceHandle, &InterfaceInfo) -> bool,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RegisterError {
+    Full,
+    Duplicate,
+}
+
+static DRIVERS: Mutex<[Option<&'static ClassDriver>; MAX_DRIVERS]> = Mutex::new([None; MAX_DRIVERS]);
+
+pub fn register(driver: &'static ClassDriver) -> Result<(), RegisterError> {
+    let mut drivers = DRIVERS.lock();
+    if drivers.iter().flatten().any(|d| d.name == driver.name) {
+        return Err(RegisterError::Duplicate);
+    }
+    let slot = drivers.iter_mut().find(|d| d.is_none()).ok_or(RegisterError::Full)?;
+    *slot = Some(driver);
+    Ok(())
+}
+
+pub fn for_each_driver(mut f: impl FnMut(&ClassDriver)) {
+    for d in DRIVERS.lock().iter().flatten() {
+        f(d);
+    }
+}
+
+/// Walk a configuration descriptor and report each interface together with
+/// the endpoints that follow it. Malformed trailing data stops the walk.
+pub fn parse_interfaces(config: &[u8], mut f: impl FnMut(&InterfaceInfo)) {
+    let mut current: Option<InterfaceInfo> = None;
+    let mut ep_count = 0usize;
+    for desc in usb_desc::iter(config) {
+        match desc.parse() {
+            Parsed::Interface(d) => {
+                if let Some(iface) = current.take() {
+                    f(&iface);
+                }
+                current = Some(InterfaceInfo {
+                    number: d.number,
+                    alternate: d.alternate,
+                    class: d.class,
+                    subclass: d.subclass,
+                    protocol: d.protocol,
+                    endpoints: [None; MAX_ENDPOINTS],
+                });
+                ep_count = 0;
+            }
+            Parsed::Endpoint(ep) => {
+                if let Some(iface) = current.as_mut() {
+                    if ep_count < MAX_ENDPOINTS {
+                        iface.endpoints[ep_count] = Some(ep);
+                        ep_count += 1;
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(iface) = current {
+        f(&iface);
+    }
+}
+
+/// Offer every interface of `config` to the registered drivers. Returns the
+/// number of interfaces that were claimed.
+pub fn bind(dev: &DeviceHandle, config: &[u8]) -> usize {
+    let drivers = *DRIVERS.lock();
+    let mut bound = 0;
+    parse_interfaces(config, |iface| {
+        // Only the default alternate setting is active after SET_CONFIGURATION
+        if iface.alternate != 0 {
+            return;
+        }
+        let claimed = drivers
+            .iter()
+            .flatten()
+            .find(|d| (d.matches)(iface) && (d.attach)(dev, iface));
+        match claimed {
+            Some(d) => {
+                bound += 1;
+                klog::log(Level::Info, format_args!(
+                    "[usb] slot {} cfg {} if{} bound to {}\r\n",
+                    dev.slot, dev.config_value, iface.number, d.name
+                ));
+            }
+            None => klog::log(Level::Info, format_args!(
+                "[usb] slot {} if{} class={:02x}/{:02x}/{:02x} has no driver\r\n",
+                dev.slot, iface.number, iface.class, iface.subclass, iface.protocol
+            )),
+        }
+    });
+    bound
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Config descriptor of a boot keyboard plus a second vendor interface
+    const CONFIG: &[u8] = &[
+        9, 2, 41, 0, 2, 1, 0, 0xA0, 50, // configuration
+        9, 4, 0, 0, 1, 3, 1, 1, 0, // interface 0: HID boot keyboard
+        9, 0x21, 0x11, 1, 0, 1, 0x22, 63, 0, // HID descriptor
+        7, 5, 0x81, 3, 8, 0, 10, // EP1 IN interrupt
+        9, 4, 1, 0, 1, 0xFF, 0, 0, 0, // interface 1: vendor
+        7, 5, 0x02, 2, 64, 0, 0, // EP2 OUT bulk
+    ];
+
+    #[test]
+    fn parses_interfaces_and_endpoints() {
+        let mut seen = [InterfaceInfo::default(); 2];
+        let mut n = 0;
+        parse_interfaces(CONFIG, |iface| {
+            seen[n] = *iface;
+            n += 1;
+        });
+        assert_eq!(n, 2);
+        assert_eq!((seen[0].class, seen[0].subclass, seen[0].protocol), (3, 1, 1));
+        let ep = seen[0].find_endpoint(|e| e.is_in() && e.is_interrupt()).unwrap();
+        assert_eq!((ep.address, ep.max_packet, ep.interval), (0x81, 8, 10));
+        assert_eq!(seen[1].class, 0xFF);
+        assert!(seen[1].find_endpoint(|e| e.is_in()).is_none());
+    }
+
+    #[test]
+    fn truncated_descriptor_stops_walk() {
+        let mut n = 0;
+        parse_interfaces(&CONFIG[..12], |_| n += 1);
+        assert_eq!(n, 0);
+    }
+}
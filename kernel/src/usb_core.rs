@@ -0,0 +1,276 @@
+//! USB enumeration: bring up an xHCI controller found on PCI and walk its
+//! first attached device through addressing, descriptor reads and
+//! SET_CONFIGURATION before handing it to the class drivers.
+//!
+//! Controller bring-up runs in the probe; device enumeration is an async
+//! task on the executor, so boot does not stall on command completions.
+//! A device remembered on its port (`usb_known`) skips the configuration
+//! header read.
+
+use core::fmt;
+use spin::Mutex;
+
+use crate::addr;
+use crate::executor;
+use crate::klog::{self, Level};
+use crate::driver::{self, Match, PciDriver, PciIds};
+use crate::pci::{self, PciAddress};
+use crate::usb_class;
+use crate::usb_desc::DeviceDescriptor;
+use crate::usb_known::{self, Known};
+use crate::usb_state::{self, DeviceState};
+use crate::power;
+use crate::xhci::{self, XhciInfo};
+use crate::status::{self, Health};
+use crate::vmm;
+
+/// PCI programming interface of an xHCI controller (class 0x0C, subclass 0x03).
+pub const PROG_IF_XHCI: u8 = 0x30;
+/// Reset recovery time (USB 2.0 §7.1.7.3) before the first request to a port.
+const RESET_RECOVERY_MS: u64 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnumError {
+    NotXhci,
+    MissingBar0,
+    Bar0NotMemory,
+    CapRead,
+    Init(&'static str),
+    NoEnabledPort,
+    EnableSlot,
+    AddressDevice,
+    DeviceDescriptor,
+    ConfigHeader,
+    ConfigDescriptor,
+    SetConfiguration,
+}
+
+impl EnumError {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EnumError::NotXhci => "not an xHCI controller",
+            EnumError::MissingBar0 => "missing bar0",
+            EnumError::Bar0NotMemory => "bar0 is not memory-mapped",
+            EnumError::CapRead => "failed to read capability registers",
+            EnumError::Init(e) => e,
+            EnumError::NoEnabledPort => "no enabled port",
+            EnumError::EnableSlot => "enable slot failed",
+            EnumError::AddressDevice => "address device failed",
+            EnumError::DeviceDescriptor => "failed to read device descriptor",
+            EnumError::ConfigHeader => "failed to read config header",
+            EnumError::ConfigDescriptor => "failed to read full config descriptor",
+            EnumError::SetConfiguration => "set configuration failed",
+        }
+    }
+}
+
+impl fmt::Display for EnumError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct DeviceReport {
+    pub slot: u8,
+    pub device_desc_phys: u64,
+    pub config_phys: u64,
+    pub config_len: u16,
+    pub config_value: u8,
+    /// Interfaces claimed by a class driver.
+    pub bound: usize,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ControllerReport {
+    pub addr: PciAddress,
+    pub info: XhciInfo,
+}
+
+/// Initialize the xHCI controller at `addr`; `enumerate_device` takes it from there.
+pub fn enumerate_controller(addr: PciAddress) -> Result<ControllerReport, EnumError> {
+    if pci::prog_if(addr) != PROG_IF_XHCI {
+        return Err(EnumError::NotXhci);
+    }
+    let bar = pci::bar(addr, 0).ok_or(EnumError::MissingBar0)?;
+    if !bar.is_memory {
+        return Err(EnumError::Bar0NotMemory);
+    }
+    let mut info = unsafe { xhci::inspect(bar.base) }.ok_or(EnumError::CapRead)?;
+    // Move the registers into the uncached MMIO window; the identity map stays as a fallback
+    match vmm::map_mmio(bar.base, info.mmio_len() as u64) {
+        Ok(virt) => info.base = virt,
+        Err(e) => klog::log(Level::Warn, format_args!("[xhci] mmio remap failed: {:?}\r\n", e)),
+    }
+    klog::log(Level::Info, format_args!(
+        "[xhci] base={:#016x} caplen={} version={:04x} slots={} ports={} ctx={} dboff={:#x} rtsoff={:#x}\r\n",
+        info.base,
+        info.cap_length,
+        info.hci_version,
+        info.max_slots(),
+        info.max_ports(),
+        info.context_size(),
+        info.dboff,
+        info.rtsoff,
+    ));
+    unsafe { xhci::init_controller(info) }.map_err(EnumError::Init)?;
+    klog::log(Level::Info, format_args!("[xhci] controller initialized\r\n"));
+    xhci::report_ports();
+    let _ = xhci::poll_events();
+    Ok(ControllerReport { addr, info })
+}
+
+async fn enumerate_first_device() -> Result<DeviceReport, EnumError> {
+    if !xhci::ensure_first_port_enabled() {
+        return Err(EnumError::NoEnabledPort);
+    }
+    executor::sleep_ms(RESET_RECOVERY_MS).await;
+    let slot = xhci::enable_slot().await.ok_or(EnumError::EnableSlot)?;
+    klog::log(Level::Debug, format_args!("[xhci] slot {} enabled\r\n", slot));
+    if !xhci::address_device(slot).await {
+        return Err(EnumError::AddressDevice);
+    }
+    klog::log(Level::Debug, format_args!("[xhci] device addressed\r\n"));
+    let device_desc_phys = xhci::get_device_descriptor(slot).await.ok_or(EnumError::DeviceDescriptor)?;
+    let device_desc = unsafe {
+        core::slice::from_raw_parts(addr::PhysAddr::new(device_desc_phys).as_mut_ptr::<u8>(), DeviceDescriptor::LEN)
+    };
+    let d = DeviceDescriptor::parse(device_desc).ok_or(EnumError::DeviceDescriptor)?;
+    klog::log(Level::Info, format_args!(
+        "[xhci] device {:04x}:{:04x} usb {:x}.{:02x} class={:02x} ep0 maxp={}\r\n",
+        d.vendor, d.product, d.usb_version >> 8, d.usb_version & 0xFF, d.class, d.max_packet0
+    ));
+    let port = xhci::device_info().map_or(0, |d| d.port);
+    let known = usb_known::lookup(port).filter(|k| k.vendor == d.vendor && k.product == d.product);
+    let remembered = match known {
+        Some(known) => remembered_config(slot, port, &known).await,
+        None => None,
+    };
+    let (config_phys, config_len, config_value) = match remembered {
+        Some(config) => config,
+        None => {
+            let (hdr_phys, config_len, config_value) =
+                xhci::get_configuration_descriptor_header(slot).await.ok_or(EnumError::ConfigHeader)?;
+            klog::log(Level::Debug, format_args!(
+                "[xhci] config header at {:#x} total_len={} cfg={}\r\n",
+                hdr_phys, config_len, config_value
+            ));
+            let config_phys =
+                xhci::get_configuration_descriptor(slot, config_len).await.ok_or(EnumError::ConfigDescriptor)?;
+            (config_phys, config_len, config_value)
+        }
+    };
+    klog::log(Level::Debug, format_args!("[xhci] config descriptor at {:#x}\r\n", config_phys));
+    if !xhci::set_configuration(slot, config_value).await {
+        return Err(EnumError::SetConfiguration);
+    }
+    klog::log(Level::Debug, format_args!("[xhci] configuration set\r\n"));
+    let _ = usb_state::transition(port, DeviceState::Configured);
+
+    let dev = usb_class::DeviceHandle { slot, config_value };
+    let config = unsafe { config_bytes(config_phys, config_len) };
+    let bound = usb_class::bind(&dev, config);
+    if bound > 0 {
+        let _ = usb_state::transition(port, DeviceState::ClassBound);
+        // What worked is what the next boot tries first
+        if let Some(known) = Known::from_config(d.vendor, d.product, config) {
+            if let Err(e) = usb_known::remember(port, known) {
+                klog::log(Level::Warn, format_args!("[usb] port {}: not remembered: {}\r\n", port, e.as_str()));
+            }
+        }
+    }
+    Ok(DeviceReport { slot, device_desc_phys, config_phys, config_len, config_value, bound })
+}
+
+/// # Safety
+/// `phys` must hold a configuration descriptor buffer of `len` bytes.
+unsafe fn config_bytes(phys: u64, len: u16) -> &'static [u8] {
+    core::slice::from_raw_parts(addr::PhysAddr::new(phys).as_mut_ptr::<u8>(), len as usize)
+}
+
+/// Read the whole configuration `known` describes in one request. `None`
+/// if the request fails or the device answers with something else, in
+/// which case the entry is dropped and the caller enumerates as usual.
+async fn remembered_config(slot: u8, port: u8, known: &Known) -> Option<(u64, u16, u8)> {
+    if let Some(phys) = xhci::get_configuration_descriptor(slot, known.config_len).await {
+        if known.confirms(unsafe { config_bytes(phys, known.config_len) }) {
+            klog::log(Level::Info, format_args!(
+                "[usb] port {}: {:04x}:{:04x} remembered, cfg {} read in one request\r\n",
+                port, known.vendor, known.product, known.config_value
+            ));
+            return Some((phys, known.config_len, known.config_value));
+        }
+    }
+    klog::log(Level::Info, format_args!("[usb] port {}: device differs from the remembered one, full enumeration\r\n", port));
+    let _ = usb_known::forget(port);
+    None
+}
+
+pub static XHCI_DRIVER: PciDriver = PciDriver {
+    name: "xhci",
+    matches: &[Match::Class { class: 0x0C, subclass: 0x03, prog_if: Some(PROG_IF_XHCI) }],
+    probe: probe_xhci,
+};
+
+pub fn register() {
+    if let Err(e) = driver::register(&XHCI_DRIVER) {
+        klog::log(Level::Warn, format_args!("[xhci] register failed: {:?}\r\n", e));
+    }
+}
+
+/// The controller in use, kept to enumerate again after a reset.
+static CONTROLLER: Mutex<Option<ControllerReport>> = Mutex::new(None);
+
+/// Called from the idle loop: reset a controller that hit a fault and walk
+/// its device again.
+pub fn recover_if_needed() {
+    if !xhci::needs_recovery() {
+        return;
+    }
+    let Some(report) = *CONTROLLER.lock() else { return };
+    if xhci::recover().is_err() {
+        return;
+    }
+    if let Err(e) = executor::spawn("usb-enum", enumerate_device(report)) {
+        klog::log(Level::Warn, format_args!("[xhci] {}: cannot re-enumerate: {:?}\r\n", report.addr, e));
+    }
+}
+
+/// The controller is bound once it runs; a device that fails to enumerate
+/// is logged but does not unbind the controller.
+fn probe_xhci(addr: PciAddress, _ids: &PciIds) -> Result<(), &'static str> {
+    let report = match enumerate_controller(addr) {
+        Ok(report) => report,
+        Err(e) => {
+            status::set("usb", Health::Failed, e.as_str());
+            return Err(e.as_str());
+        }
+    };
+    *CONTROLLER.lock() = Some(report);
+    status::set("usb", Health::Ok, "");
+    power::register_hook("xhci", xhci::shutdown);
+    if let Err(e) = executor::spawn("usb-enum", enumerate_device(report)) {
+        klog::log(Level::Warn, format_args!("[xhci] {}: cannot start enumeration: {:?}\r\n", addr, e));
+    }
+    Ok(())
+}
+
+/// Walk the controller's first device on the executor and log the outcome.
+async fn enumerate_device(report: ControllerReport) {
+    let device = enumerate_first_device().await;
+    xhci::poll_events();
+    match device {
+        Ok(dev) => klog::log(Level::Info, format_args!(
+            "[usb] {} xhci {:04x}: slot {} dev@{:#x} cfg {} ({} bytes @{:#x}), {} interface(s) bound\r\n",
+            report.addr,
+            report.info.hci_version,
+            dev.slot,
+            dev.device_desc_phys,
+            dev.config_value,
+            dev.config_len,
+            dev.config_phys,
+            dev.bound
+        )),
+        Err(e) => klog::log(Level::Warn, format_args!("[xhci] {}: {}\r\n", report.addr, e)),
+    }
+}
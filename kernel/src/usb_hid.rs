@@ -0,0 +1,51 @@
+//! HID class drivers. Only the boot-protocol keyboard is implemented; it
+//! polls the first interrupt-IN endpoint through the xHCI driver.
+
+use crate::usb_class::{self, ClassDriver, DeviceHandle, InterfaceInfo};
+use crate::klog::{self, Level};
+use crate::xhci;
+
+const CLASS_HID: u8 = 3;
+const SUBCLASS_BOOT: u8 = 1;
+const PROTOCOL_KEYBOARD: u8 = 1;
+
+pub static BOOT_KEYBOARD: ClassDriver = ClassDriver {
+    name: "hid-kbd",
+    matches: keyboard_matches,
+    attach: keyboard_attach,
+};
+
+pub fn register() {
+    if let Err(e) = usb_class::register(&BOOT_KEYBOARD) {
+        klog::log(Level::Warn, format_args!("[hid] register failed: {:?}\r\n", e));
+    }
+}
+
+fn keyboard_matches(iface: &InterfaceInfo) -> bool {
+    iface.class == CLASS_HID && iface.subclass == SUBCLASS_BOOT && iface.protocol == PROTOCOL_KEYBOARD
+}
+
+fn keyboard_attach(dev: &DeviceHandle, iface: &InterfaceInfo) -> bool {
+    let ep = match iface.find_endpoint(|e| e.is_in() && e.is_interrupt()) {
+        Some(ep) => ep,
+        None => {
+            klog::log(Level::Warn, format_args!("[hid] no keyboard endpoint found\r\n"));
+            return false;
+        }
+    };
+    klog::log(Level::Debug, format_args!(
+        "[hid] keyboard ep={:#x} maxp={} interval={}\r\n",
+        ep.address, ep.packet_size(), ep.interval
+    ));
+    if !xhci::configure_interrupt_in_endpoint(dev.slot, &ep) {
+        klog::log(Level::Warn, format_args!("[hid] configure endpoint failed\r\n"));
+        return false;
+    }
+    klog::log(Level::Debug, format_args!("[hid] interrupt endpoint configured\r\n"));
+    if !xhci::start_hid_polling(dev.slot, ep.address, ep.packet_size()) {
+        klog::log(Level::Warn, format_args!("[hid] failed to start polling\r\n"));
+        return false;
+    }
+    klog::log(Level::Info, format_args!("[hid] polling started\r\n"));
+    true
+}
@@ -0,0 +1,179 @@
+//! Devices remembered per root port, in the kv store (`usb.port<N>`): the
+//! vendor/product seen there and the configuration that worked (value,
+//! total length, interrupt-IN endpoint and its interval).
+//!
+//! On the next enumeration a device with the same ids gets its full
+//! configuration descriptor in one request instead of header then body.
+//! If what it returns does not match the entry, the entry is dropped and
+//! enumeration takes the usual path. Entries are written after a class
+//! driver bound the device, and only when they change.
+
+use crate::usb_class;
+use crate::usb_desc::ConfigDescriptor;
+use crate::klog::{self, Level};
+use crate::kv;
+
+/// Root port numbers are a byte (`xhci::DeviceInfo::port`).
+const MAX_PORT: u8 = u8::MAX;
+const KEY_PREFIX: &str = "usb.port";
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Known {
+    pub vendor: u16,
+    pub product: u16,
+    pub config_value: u8,
+    pub config_len: u16,
+    /// Interrupt-IN endpoint address and bInterval of the first interface
+    /// that has one; 0 and 0 if none does.
+    pub endpoint: u8,
+    pub interval: u8,
+}
+
+impl Known {
+    const LEN: usize = 9;
+
+    fn encode(&self) -> [u8; Self::LEN] {
+        let mut out = [0u8; Self::LEN];
+        out[0..2].copy_from_slice(&self.vendor.to_le_bytes());
+        out[2..4].copy_from_slice(&self.product.to_le_bytes());
+        out[4] = self.config_value;
+        out[5..7].copy_from_slice(&self.config_len.to_le_bytes());
+        out[7] = self.endpoint;
+        out[8] = self.interval;
+        out
+    }
+
+    fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::LEN {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        Some(Known {
+            vendor: u16_at(0),
+            product: u16_at(2),
+            config_value: bytes[4],
+            config_len: u16_at(5),
+            endpoint: bytes[7],
+            interval: bytes[8],
+        })
+    }
+
+    /// Entry for a device that enumerated with `config`.
+    pub fn from_config(vendor: u16, product: u16, config: &[u8]) -> Option<Self> {
+        let header = ConfigDescriptor::parse(config)?;
+        let (endpoint, interval) = interrupt_in(config);
+        Some(Known { vendor, product, config_value: header.value, config_len: header.total_length, endpoint, interval })
+    }
+
+    /// Whether `config`, read with the remembered length, is the
+    /// configuration this entry describes.
+    pub fn confirms(&self, config: &[u8]) -> bool {
+        Known::from_config(self.vendor, self.product, config) == Some(*self)
+    }
+}
+
+/// Address and bInterval of the first interrupt-IN endpoint, as the HID
+/// driver picks it.
+fn interrupt_in(config: &[u8]) -> (u8, u8) {
+    let mut found = None;
+    usb_class::parse_interfaces(config, |iface| {
+        if found.is_none() && iface.alternate == 0 {
+            found = iface.find_endpoint(|e| e.is_in() && e.is_interrupt()).map(|e| (e.address, e.interval));
+        }
+    });
+    found.unwrap_or((0, 0))
+}
+
+fn key(port: u8, buf: &mut [u8; 12]) -> &str {
+    let mut n = KEY_PREFIX.len();
+    buf[..n].copy_from_slice(KEY_PREFIX.as_bytes());
+    let digits = [port / 100, port / 10 % 10, port % 10];
+    let first = digits.iter().position(|&d| d != 0).unwrap_or(2);
+    for &d in &digits[first..] {
+        buf[n] = b'0' + d;
+        n += 1;
+    }
+    core::str::from_utf8(&buf[..n]).unwrap_or(KEY_PREFIX)
+}
+
+pub fn lookup(port: u8) -> Option<Known> {
+    let mut buf = [0u8; 12];
+    kv::get(key(port, &mut buf), Known::decode).flatten()
+}
+
+/// Keep `known` for `port`; the store is not written if it already has it.
+pub fn remember(port: u8, known: Known) -> Result<(), kv::KvError> {
+    if lookup(port) == Some(known) {
+        return Ok(());
+    }
+    let mut buf = [0u8; 12];
+    kv::set(key(port, &mut buf), &known.encode())
+}
+
+/// Drop the entry for `port`; returns whether there was one.
+pub fn forget(port: u8) -> Result<bool, kv::KvError> {
+    let mut buf = [0u8; 12];
+    kv::remove(key(port, &mut buf))
+}
+
+/// Remembered entries, in store order.
+pub fn for_each(mut f: impl FnMut(u8, &Known)) {
+    kv::for_each(|k, v| {
+        let port = k.strip_prefix(KEY_PREFIX).and_then(|n| n.parse::<u8>().ok());
+        if let (Some(port), Some(known)) = (port, Known::decode(v)) {
+            f(port, &known);
+        }
+    });
+}
+
+/// Drop every entry; returns how many there were.
+pub fn forget_all() -> usize {
+    // The store stays locked while `for_each` runs, so collect first
+    let mut ports = [false; MAX_PORT as usize + 1];
+    for_each(|port, _| ports[port as usize] = true);
+    let mut forgotten = 0;
+    for port in (0..=MAX_PORT).filter(|&p| ports[p as usize]) {
+        match forget(port) {
+            Ok(true) => forgotten += 1,
+            Ok(false) => {}
+            Err(e) => klog::log(Level::Warn, format_args!("[usb] forgetting port {}: {}\r\n", port, e.as_str())),
+        }
+    }
+    forgotten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Boot keyboard: one interface, interrupt-IN endpoint 0x81 every 10 ms
+    const CONFIG: [u8; 25] = [
+        9, 2, 25, 0, 1, 1, 0, 0xA0, 50,
+        9, 4, 0, 0, 1, 3, 1, 1, 0,
+        7, 5, 0x81, 3, 8, 0, 10,
+    ];
+
+    #[test]
+    fn entry_round_trips_and_checks_the_configuration() {
+        let known = Known::from_config(0x0627, 0x0001, &CONFIG).unwrap();
+        assert_eq!((known.config_value, known.config_len, known.endpoint, known.interval), (1, 25, 0x81, 10));
+        assert_eq!(Known::decode(&known.encode()), Some(known));
+        assert_eq!(Known::decode(&known.encode()[..8]), None);
+        assert!(known.confirms(&CONFIG));
+        let mut other = CONFIG;
+        other[24] = 8;
+        assert!(!known.confirms(&other));
+        other = CONFIG;
+        other[5] = 2;
+        assert!(!known.confirms(&other));
+        assert!(!known.confirms(&CONFIG[..9]));
+    }
+
+    #[test]
+    fn keys_carry_the_port_number() {
+        let mut buf = [0u8; 12];
+        assert_eq!(key(1, &mut buf), "usb.port1");
+        assert_eq!(key(40, &mut buf), "usb.port40");
+        assert_eq!(key(255, &mut buf), "usb.port255");
+    }
+}
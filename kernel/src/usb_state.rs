@@ -0,0 +1,229 @@
+//! USB device lifecycle, one state machine per root hub port:
+//! Detected → Reset → Addressed → Configured → ClassBound, with Suspended
+//! on the side and Removed from anywhere.
+//!
+//! Enumeration, suspend/resume and port change events drive it; a
+//! transition the table does not allow is logged and refused, so the
+//! recorded state never skips a step. Transitions are also kept in a small
+//! ring of events for `usb info`.
+
+use spin::Mutex;
+
+use crate::klog::{self, Level};
+use crate::time;
+
+pub const MAX_DEVICES: usize = 8;
+pub const EVENT_LEN: usize = 16;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeviceState {
+    /// Connect status seen on the port.
+    Detected,
+    /// Port reset done, port enabled.
+    Reset,
+    /// Has a slot and a USB address.
+    Addressed,
+    /// SET_CONFIGURATION accepted.
+    Configured,
+    /// At least one interface claimed by a class driver.
+    ClassBound,
+    Suspended,
+    Removed,
+}
+
+impl DeviceState {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            DeviceState::Detected => "detected",
+            DeviceState::Reset => "reset",
+            DeviceState::Addressed => "addressed",
+            DeviceState::Configured => "configured",
+            DeviceState::ClassBound => "class-bound",
+            DeviceState::Suspended => "suspended",
+            DeviceState::Removed => "removed",
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TransitionError {
+    Illegal,
+    UnknownPort,
+    Full,
+}
+
+impl TransitionError {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            TransitionError::Illegal => "illegal transition",
+            TransitionError::UnknownPort => "no device on port",
+            TransitionError::Full => "device table full",
+        }
+    }
+}
+
+fn legal(from: DeviceState, to: DeviceState) -> bool {
+    use DeviceState::*;
+    match (from, to) {
+        (Removed, Removed) => false,
+        (_, Removed) | (Removed, Detected) => true,
+        (Detected, Reset) | (Reset, Addressed) | (Addressed, Configured) | (Configured, ClassBound) => true,
+        (Addressed | Configured | ClassBound, Suspended) => true,
+        // Back to wherever the suspend found it
+        (Suspended, Addressed | Configured | ClassBound) => true,
+        _ => false,
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Device {
+    /// Root hub port, 1-based.
+    port: u8,
+    slot: Option<u8>,
+    state: DeviceState,
+    /// State to go back to on resume.
+    resume_to: DeviceState,
+}
+
+#[derive(Copy, Clone)]
+pub struct Event {
+    pub port: u8,
+    pub from: DeviceState,
+    pub to: DeviceState,
+    pub tsc: u64,
+}
+
+struct Table {
+    devices: [Option<Device>; MAX_DEVICES],
+    events: [Option<Event>; EVENT_LEN],
+    next_event: usize,
+}
+
+static TABLE: Mutex<Table> = Mutex::new(Table { devices: [None; MAX_DEVICES], events: [None; EVENT_LEN], next_event: 0 });
+
+impl Table {
+    fn find(&mut self, port: u8) -> Option<&mut Device> {
+        self.devices.iter_mut().flatten().find(|d| d.port == port)
+    }
+
+    fn set(&mut self, port: u8, to: DeviceState) -> Result<(), TransitionError> {
+        let dev = self.find(port).ok_or(TransitionError::UnknownPort)?;
+        let from = dev.state;
+        if !legal(from, to) || (from == DeviceState::Suspended && to != dev.resume_to && to != DeviceState::Removed) {
+            klog::log(Level::Warn, format_args!(
+                "[usb] port{}: refused {} -> {}\r\n", port, from.as_str(), to.as_str()
+            ));
+            return Err(TransitionError::Illegal);
+        }
+        if to == DeviceState::Suspended {
+            dev.resume_to = from;
+        }
+        if to == DeviceState::Removed {
+            dev.slot = None;
+        }
+        dev.state = to;
+        self.record(Event { port, from, to, tsc: time::rdtsc() });
+        klog::log(Level::Debug, format_args!("[usb] port{}: {} -> {}\r\n", port, from.as_str(), to.as_str()));
+        Ok(())
+    }
+
+    fn record(&mut self, event: Event) {
+        self.events[self.next_event] = Some(event);
+        self.next_event = (self.next_event + 1) % EVENT_LEN;
+    }
+}
+
+/// A connect on `port`: a new device, or the same port plugged again after
+/// a removal. Nothing changes for a device already known there.
+pub fn detect(port: u8) -> Result<(), TransitionError> {
+    let mut table = TABLE.lock();
+    match table.find(port) {
+        Some(dev) if dev.state == DeviceState::Removed => return table.set(port, DeviceState::Detected),
+        Some(_) => return Ok(()),
+        None => {}
+    }
+    let slot = table.devices.iter().position(Option::is_none).ok_or(TransitionError::Full)?;
+    table.devices[slot] = Some(Device { port, slot: None, state: DeviceState::Detected, resume_to: DeviceState::Detected });
+    table.record(Event { port, from: DeviceState::Removed, to: DeviceState::Detected, tsc: time::rdtsc() });
+    klog::log(Level::Info, format_args!("[usb] port{}: detected\r\n", port));
+    Ok(())
+}
+
+pub fn transition(port: u8, to: DeviceState) -> Result<(), TransitionError> {
+    TABLE.lock().set(port, to)
+}
+
+/// `Reset -> Addressed`, recording the slot the controller assigned.
+pub fn addressed(port: u8, slot: u8) -> Result<(), TransitionError> {
+    let mut table = TABLE.lock();
+    table.set(port, DeviceState::Addressed)?;
+    if let Some(dev) = table.find(port) {
+        dev.slot = Some(slot);
+    }
+    Ok(())
+}
+
+/// The controller is saving its state: every addressed device goes with it.
+pub fn suspend_all() {
+    each_port(|state| matches!(state, DeviceState::Addressed | DeviceState::Configured | DeviceState::ClassBound), |_| {
+        DeviceState::Suspended
+    });
+}
+
+pub fn resume_all() {
+    each_port(|state| state == DeviceState::Suspended, |dev| dev.resume_to);
+}
+
+/// The controller lost its state: the devices are gone until re-enumerated.
+pub fn remove_all() {
+    each_port(|state| state != DeviceState::Removed, |_| DeviceState::Removed);
+}
+
+fn each_port(pick: impl Fn(DeviceState) -> bool, to: impl Fn(&Device) -> DeviceState) {
+    let mut table = TABLE.lock();
+    for i in 0..MAX_DEVICES {
+        let Some(dev) = table.devices[i].filter(|d| pick(d.state)) else { continue };
+        let _ = table.set(dev.port, to(&dev));
+    }
+}
+
+pub fn state(port: u8) -> Option<DeviceState> {
+    TABLE.lock().find(port).map(|d| d.state)
+}
+
+/// Devices with their port, slot and state.
+pub fn for_each(mut f: impl FnMut(u8, Option<u8>, DeviceState)) {
+    for dev in TABLE.lock().devices.iter().flatten() {
+        f(dev.port, dev.slot, dev.state);
+    }
+}
+
+/// Recorded transitions, oldest first.
+pub fn for_each_event(mut f: impl FnMut(&Event)) {
+    let table = TABLE.lock();
+    for i in 0..EVENT_LEN {
+        if let Some(e) = &table.events[(table.next_event + i) % EVENT_LEN] {
+            f(e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use DeviceState::*;
+
+    #[test]
+    fn enumeration_order_is_enforced() {
+        assert!(legal(Detected, Reset));
+        assert!(legal(Configured, ClassBound));
+        assert!(!legal(Detected, Addressed));
+        assert!(!legal(Reset, Configured));
+        assert!(!legal(Removed, Reset));
+        assert!(legal(Removed, Detected));
+        assert!(legal(ClassBound, Removed));
+        assert!(!legal(Removed, Removed));
+        assert!(!legal(Detected, Suspended));
+        assert!(legal(Suspended, ClassBound));
+    }
+}
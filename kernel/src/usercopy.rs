@@ -0,0 +1,184 @@
+#![allow(dead_code)]
+
+use core::mem::{size_of, MaybeUninit};
+use x86_64::structures::paging::PageTableFlags;
+
+use crate::addr;
+use crate::fastmem;
+use crate::ai_action::{Action, ActionOutcome};
+use crate::apply_action::Caller;
+use crate::process;
+use crate::telemetry::Telemetry;
+use crate::vmm::{self, AddressSpace, PAGE_SIZE};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UserCopyError {
+    /// Null, kernel-half or overflowing range.
+    BadAddress,
+    /// Page neither mapped nor lazily backed.
+    NotMapped,
+    /// Destination page is not writable.
+    ReadOnly,
+    /// No process context to resolve the pointer against.
+    NoProcess,
+}
+
+pub type UserResult<T> = core::result::Result<T, UserCopyError>;
+
+pub const EFAULT: i64 = -14;
+
+impl UserCopyError {
+    pub fn errno(self) -> i64 {
+        EFAULT
+    }
+}
+
+/// Plain-old-data types that may be filled from arbitrary user bytes.
+///
+/// # Safety
+/// Every bit pattern of `size_of::<Self>()` bytes must be a valid value.
+pub unsafe trait UserPod: Copy {}
+
+unsafe impl UserPod for Action {}
+unsafe impl UserPod for ActionOutcome {}
+unsafe impl UserPod for Telemetry {}
+unsafe impl UserPod for u64 {}
+unsafe impl UserPod for u32 {}
+unsafe impl UserPod for i32 {}
+unsafe impl UserPod for [u32; 2] {}
+
+// Resolves one user page to the kernel's pointer to it, faulting in lazy pages up front so the
+// copy itself can never take a page fault.
+fn resolve(aspace: &mut AddressSpace, addr: u64, write: bool) -> UserResult<*mut u8> {
+    let (phys, flags) = match aspace.translate(addr) {
+        Some(t) => t,
+        None => {
+            if !aspace.fault_in(addr) {
+                return Err(UserCopyError::NotMapped);
+            }
+            aspace.translate(addr).ok_or(UserCopyError::NotMapped)?
+        }
+    };
+    if !flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        return Err(UserCopyError::BadAddress);
+    }
+    if write && !flags.contains(PageTableFlags::WRITABLE) {
+        return Err(UserCopyError::ReadOnly);
+    }
+    // The frame is whatever the entry says; only a mapped one is copied through
+    addr::PhysAddr::new(phys).to_virt().map(|v| v.as_mut_ptr()).ok_or(UserCopyError::BadAddress)
+}
+
+// Walks [addr, addr+len) page by page, handing `f` the kernel's pointer to each chunk.
+fn for_each_chunk(
+    addr: u64,
+    len: usize,
+    write: bool,
+    mut f: impl FnMut(*mut u8, usize, usize),
+) -> UserResult<()> {
+    if len == 0 {
+        return Ok(());
+    }
+    if !vmm::is_user_range(addr, len as u64) {
+        return Err(UserCopyError::BadAddress);
+    }
+    let pid = process::current();
+    if pid == process::KERNEL_PID {
+        return Err(UserCopyError::NoProcess);
+    }
+    process::with_process(pid, |p| {
+        let mut done = 0usize;
+        while done < len {
+            let va = addr + done as u64;
+            let in_page = (PAGE_SIZE - (va & (PAGE_SIZE - 1))) as usize;
+            let chunk = in_page.min(len - done);
+            let ptr = resolve(&mut p.aspace, va, write)?;
+            f(ptr, done, chunk);
+            done += chunk;
+        }
+        Ok(())
+    })
+    .unwrap_or(Err(UserCopyError::NoProcess))
+}
+
+pub fn copy_from_user(caller: Caller, dst: &mut [u8], src: u64) -> UserResult<()> {
+    if src == 0 {
+        return Err(UserCopyError::BadAddress);
+    }
+    if caller == Caller::Kernel {
+        unsafe { core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len()) };
+        return Ok(());
+    }
+    let out = dst.as_mut_ptr();
+    for_each_chunk(src, dst.len(), false, |ptr, off, n| unsafe {
+        fastmem::copy(out.add(off), ptr, n);
+    })
+}
+
+pub fn copy_to_user(caller: Caller, dst: u64, src: &[u8]) -> UserResult<()> {
+    if dst == 0 {
+        return Err(UserCopyError::BadAddress);
+    }
+    if caller == Caller::Kernel {
+        unsafe { core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len()) };
+        return Ok(());
+    }
+    for_each_chunk(dst, src.len(), true, |ptr, off, n| unsafe {
+        fastmem::copy(ptr, src.as_ptr().add(off), n);
+    })
+}
+
+pub fn read_user<T: UserPod>(caller: Caller, src: u64) -> UserResult<T> {
+    let mut val = MaybeUninit::<T>::uninit();
+    let bytes =
+        unsafe { core::slice::from_raw_parts_mut(val.as_mut_ptr() as *mut u8, size_of::<T>()) };
+    copy_from_user(caller, bytes, src)?;
+    Ok(unsafe { val.assume_init() })
+}
+
+pub fn write_user<T: UserPod>(caller: Caller, dst: u64, val: &T) -> UserResult<()> {
+    let bytes = unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) };
+    copy_to_user(caller, dst, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::mem::offset_of;
+    use vmm::{USER_BASE, USER_END};
+
+    fn read(src: u64, len: usize) -> UserResult<()> {
+        let mut buf = [0u8; 16];
+        copy_from_user(Caller::User, &mut buf[..len], src)
+    }
+
+    #[test]
+    fn ranges_outside_user_space_are_refused() {
+        assert_eq!(read(0, 8), Err(UserCopyError::BadAddress));
+        assert_eq!(read(USER_BASE - 8, 8), Err(UserCopyError::BadAddress));
+        // Straddling the top of user space, or the kernel half
+        assert_eq!(read(USER_END - 4, 8), Err(UserCopyError::BadAddress));
+        assert_eq!(read(0xFFFF_8000_0000_0000, 8), Err(UserCopyError::BadAddress));
+        // addr + len wraps past zero
+        assert_eq!(read(u64::MAX - 3, 8), Err(UserCopyError::BadAddress));
+        assert_eq!(copy_to_user(Caller::User, u64::MAX - 3, &[0; 8]), Err(UserCopyError::BadAddress));
+        assert_eq!(copy_to_user(Caller::User, 0, &[0; 8]), Err(UserCopyError::BadAddress));
+    }
+
+    #[test]
+    fn user_ranges_need_a_process() {
+        assert_eq!(read(USER_END - 8, 8), Err(UserCopyError::NoProcess));
+        assert_eq!(read(USER_BASE, 16), Err(UserCopyError::NoProcess));
+        assert_eq!(read(USER_BASE, 0), Ok(()));
+        assert_eq!(write_user(Caller::User, USER_BASE, &7u64), Err(UserCopyError::NoProcess));
+    }
+
+    #[test]
+    fn structs_copied_out_have_no_padding() {
+        assert_eq!(offset_of!(Action, param1), 8);
+        assert_eq!(size_of::<Action>(), 8 + 3 * 8);
+        assert_eq!(offset_of!(ActionOutcome, selftest_code), 4);
+        assert_eq!(size_of::<ActionOutcome>(), 16);
+        assert_eq!(size_of::<Telemetry>(), 6 * 4);
+    }
+}
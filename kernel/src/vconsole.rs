@@ -0,0 +1,320 @@
+//! Virtual consoles on the VGA text screen.
+//!
+//! Alt+F1 is the shell, Alt+F2 a live view of the serial log (PgUp/PgDn
+//! scroll, End follows the newest line again), Alt+F3 the AI dashboard. The
+//! shell keeps writing into its console while another one is on screen; the
+//! views are redrawn from kernel state: the log from the serial tail ring
+//! whenever it grows, the dashboard on each of its once-a-second samples.
+//! The keyboard ISR only records the request, the idle loop switches.
+
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
+
+use crate::keyboard::{self, Focus, Key};
+use crate::theme::{self, Slot};
+use crate::{dashboard, serial, vga};
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum View {
+    Shell = 0,
+    Log = 1,
+    Dashboard = 2,
+}
+
+impl View {
+    fn from_index(i: usize) -> Option<Self> {
+        Some(match i {
+            0 => View::Shell,
+            1 => View::Log,
+            2 => View::Dashboard,
+            _ => return None,
+        })
+    }
+}
+
+const NO_REQUEST: u8 = u8::MAX;
+/// Rows above the status bar.
+const BODY_ROWS: usize = vga::ROWS - 1;
+
+static ACTIVE: AtomicU8 = AtomicU8::new(View::Shell as u8);
+static REQUEST: AtomicU8 = AtomicU8::new(NO_REQUEST);
+/// Display lines the log view is scrolled up from the newest one.
+static SCROLL: AtomicUsize = AtomicUsize::new(0);
+/// `serial::written()` at the last redraw of the log view.
+static LOG_DRAWN_AT: AtomicU64 = AtomicU64::new(0);
+
+/// Shown in the status bar, in the panic colours, once set.
+static ALERT: Mutex<Option<&'static str>> = Mutex::new(None);
+
+/// Copy of the serial tail the log view is drawn from.
+static LOG: Mutex<[u8; serial::TAIL_LEN]> = Mutex::new([0; serial::TAIL_LEN]);
+
+/// Called from the keyboard ISR on Alt+F1..F3.
+pub fn request(index: usize) {
+    if let Some(view) = View::from_index(index) {
+        REQUEST.store(view as u8, Ordering::Relaxed);
+    }
+}
+
+pub fn take_request() -> Option<View> {
+    View::from_index(REQUEST.swap(NO_REQUEST, Ordering::Relaxed) as usize)
+}
+
+/// Put `text` in the views' status bar until reboot.
+#[cfg(feature = "ai_agent")]
+pub fn alert(text: &'static str) {
+    *ALERT.lock() = Some(text);
+    let view = active();
+    if view != View::Shell {
+        draw_bar(view);
+    }
+}
+
+pub fn active() -> View {
+    View::from_index(ACTIVE.load(Ordering::Relaxed) as usize).unwrap_or(View::Shell)
+}
+
+/// Called from the idle loop. Returns whether the shell is on screen and
+/// may consume input.
+pub fn step() -> bool {
+    let sampled = dashboard::tick();
+    if let Some(view) = take_request() {
+        switch(view);
+    }
+    match active() {
+        View::Shell => true,
+        view => {
+            handle_keys(view);
+            let due = match view {
+                View::Log => serial::written() != LOG_DRAWN_AT.load(Ordering::Relaxed),
+                _ => sampled,
+            };
+            if due {
+                redraw(view);
+            }
+            false
+        }
+    }
+}
+
+fn switch(to: View) {
+    let from = active();
+    if from == to {
+        return;
+    }
+    if from == View::Shell {
+        vga::hide_console();
+        keyboard::take_focus(Focus::Views);
+    }
+    ACTIVE.store(to as u8, Ordering::Relaxed);
+    match to {
+        View::Shell => {
+            keyboard::release_focus(Focus::Views);
+            vga::show_console();
+        }
+        view => {
+            SCROLL.store(0, Ordering::Relaxed);
+            redraw(view);
+        }
+    }
+}
+
+fn handle_keys(view: View) {
+    while let Some(key) = keyboard::poll_key(Focus::Views) {
+        if view != View::Log {
+            continue;
+        }
+        let scroll = SCROLL.load(Ordering::Relaxed);
+        let scroll = match key {
+            Key::PageUp => scroll + BODY_ROWS - 1,
+            Key::Up => scroll + 1,
+            Key::PageDown => scroll.saturating_sub(BODY_ROWS - 1),
+            Key::Down => scroll.saturating_sub(1),
+            Key::End => 0,
+            _ => continue,
+        };
+        SCROLL.store(scroll, Ordering::Relaxed);
+        redraw(view);
+    }
+}
+
+fn redraw(view: View) {
+    match view {
+        View::Log => {
+            LOG_DRAWN_AT.store(serial::written(), Ordering::Relaxed);
+            draw_log();
+        }
+        View::Dashboard => dashboard::draw(),
+        View::Shell => return,
+    }
+    draw_bar(view);
+}
+
+/// One screen row, cut at the screen width and padded when drawn.
+struct Row {
+    bytes: [u8; vga::COLUMNS],
+    len: usize,
+}
+
+impl Row {
+    fn new() -> Self {
+        Row { bytes: [b' '; vga::COLUMNS], len: 0 }
+    }
+
+    fn draw(&self, row: usize, style: u8) {
+        vga::write_at(row, 0, &self.bytes, style);
+    }
+}
+
+impl Write for Row {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for b in s.bytes() {
+            if self.len < vga::COLUMNS {
+                self.bytes[self.len] = if b.is_ascii_graphic() { b } else { b' ' };
+                self.len += 1;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn draw_row(row: usize, style: u8, args: fmt::Arguments) {
+    let mut r = Row::new();
+    let _ = r.write_fmt(args);
+    r.draw(row, style);
+}
+
+fn draw_bar(view: View) {
+    if let Some(alert) = *ALERT.lock() {
+        draw_row(BODY_ROWS, theme::get(Slot::Panic), format_args!(" {}  | Alt+F1 shell  Alt+F2 log  Alt+F3 ai", alert));
+        return;
+    }
+    let hint = match view {
+        View::Log if SCROLL.load(Ordering::Relaxed) != 0 => "PgUp/PgDn scroll, End follow (scrolled)",
+        View::Log => "PgUp/PgDn scroll, End follow",
+        _ => "",
+    };
+    draw_row(BODY_ROWS, theme::get(Slot::Bar), format_args!(" Alt+F1 shell  Alt+F2 log  Alt+F3 ai  | {}", hint));
+}
+
+/// Up to `out.len()` display lines (wrapped at `width`) of `text`, ending
+/// `skip` lines above its last one. Returns how many were written; they go
+/// to the end of `out`, oldest first.
+fn last_lines<'a>(text: &'a [u8], width: usize, skip: usize, out: &mut [&'a [u8]]) -> usize {
+    let text = text.strip_suffix(b"\n").unwrap_or(text);
+    let mut seen = 0;
+    let mut n = 0;
+    for line in text.split(|&b| b == b'\n').rev() {
+        let pieces = line.len().div_ceil(width).max(1);
+        for i in (0..pieces).rev() {
+            if seen >= skip {
+                if n == out.len() {
+                    return n;
+                }
+                out[out.len() - 1 - n] = &line[i * width..line.len().min((i + 1) * width)];
+                n += 1;
+            }
+            seen += 1;
+        }
+    }
+    n
+}
+
+fn draw_log() {
+    let mut log = LOG.lock();
+    let len = serial::tail(&mut log[..]);
+    let mut lines: [&[u8]; BODY_ROWS] = [&[]; BODY_ROWS];
+    let scroll = SCROLL.load(Ordering::Relaxed);
+    let mut shown = last_lines(&log[..len], vga::COLUMNS, scroll, &mut lines);
+    if shown < BODY_ROWS && scroll != 0 {
+        // Scrolled past the oldest line: keep a full screen
+        let scroll = scroll.saturating_sub(BODY_ROWS - shown);
+        SCROLL.store(scroll, Ordering::Relaxed);
+        shown = last_lines(&log[..len], vga::COLUMNS, scroll, &mut lines);
+    }
+    for (row, line) in lines.iter().enumerate() {
+        let mut r = Row::new();
+        if row >= BODY_ROWS - shown {
+            for chunk in line.utf8_chunks() {
+                let _ = r.write_str(chunk.valid());
+            }
+        }
+        r.draw(row, theme::get(line_level(line)));
+    }
+}
+
+/// Theme slot of a log line: the serial tail keeps no levels, so they are
+/// told apart by their words.
+fn line_level(line: &[u8]) -> Slot {
+    let has = |word: &[u8]| line.windows(word.len()).any(|w| w.eq_ignore_ascii_case(word));
+    if has(b"panic") || has(b"error") || has(b"fail") || has(b"exception") {
+        Slot::Error
+    } else if has(b"warn") || has(b"timeout") || has(b"degraded") {
+        Slot::Warn
+    } else {
+        Slot::Info
+    }
+}
+
+/// Rows of a view, filled top down.
+pub struct Lines {
+    row: usize,
+}
+
+impl Lines {
+    pub fn new() -> Self {
+        Lines { row: 0 }
+    }
+
+    /// The next row to draw on, if any is left.
+    pub fn next_row(&mut self) -> Option<usize> {
+        let row = self.row;
+        (row < BODY_ROWS).then(|| {
+            self.row += 1;
+            row
+        })
+    }
+
+    pub fn line(&mut self, style: u8, args: fmt::Arguments) {
+        if let Some(row) = self.next_row() {
+            draw_row(row, style, args);
+        }
+    }
+
+    /// Blank the rows left.
+    pub fn finish(&mut self) {
+        while self.row < BODY_ROWS {
+            self.line(theme::get(Slot::Info), format_args!(""));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn last_lines_wraps_and_scrolls() {
+        let text = b"one\n0123456789abc\n\nlast\n";
+        let mut out: [&[u8]; 3] = [&[]; 3];
+        assert_eq!(last_lines(text, 10, 0, &mut out), 3);
+        assert_eq!(out, [b"abc" as &[u8], b"", b"last"]);
+        assert_eq!(last_lines(text, 10, 2, &mut out), 3);
+        assert_eq!(out, [b"one" as &[u8], b"0123456789", b"abc"]);
+        let mut out: [&[u8]; 3] = [&[]; 3];
+        assert_eq!(last_lines(text, 10, 4, &mut out), 1);
+        assert_eq!(out[2], b"one");
+        assert_eq!(last_lines(text, 10, 5, &mut out), 0);
+    }
+
+    #[test]
+    fn log_lines_are_styled_by_their_words() {
+        assert_eq!(line_level(b"[EXCEPTION] Page Fault"), Slot::Error);
+        assert_eq!(line_level(b"[xhci] recovery failed: halt timeout"), Slot::Error);
+        assert_eq!(line_level(b"[init] running degraded: usb"), Slot::Warn);
+        assert_eq!(line_level(b"[pmm] using region"), Slot::Info);
+        assert_eq!(line_level(b""), Slot::Info);
+    }
+}
@@ -2,16 +2,38 @@ use core::fmt::{self, Write};
 use core::panic::PanicInfo;
 use core::ptr::{read_volatile, write_volatile};
 use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+use crate::bigfont;
+use crate::theme::{self, Slot};
 
 const VGA_BUFFER_ADDRESS: usize = 0xb8000;
 const BUFFER_HEIGHT: usize = 25;
 const BUFFER_WIDTH: usize = 80;
+/// Until `init` applies the theme.
 const DEFAULT_STYLE: u8 = 0x0f; // white on black
 
+pub const ROWS: usize = BUFFER_HEIGHT;
+pub const COLUMNS: usize = BUFFER_WIDTH;
+const CELLS: usize = BUFFER_HEIGHT * BUFFER_WIDTH;
+
+/// The shell's console. While another virtual console is on screen it keeps
+/// writing into its back buffer; `write_at`, `clear_row`, `snapshot` and
+/// `restore` always work on the screen itself.
 static CONSOLE: Mutex<Console> = Mutex::new(Console::new());
 
+/// Copy of the text buffer and cursor, for full-screen modes to put back.
+pub struct Snapshot {
+    cells: [u16; CELLS],
+    row: usize,
+    column: usize,
+    style: u8,
+}
+
 pub fn init() {
-    CONSOLE.lock().clear();
+    let mut console = CONSOLE.lock();
+    console.style = theme::get(Slot::Text);
+    console.clear();
 }
 
 pub fn write_str(message: &str) {
@@ -35,6 +57,31 @@ pub fn backspace() {
     console.backspace();
 }
 
+/// Colours of a `banner`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BannerStyle {
+    /// The theme's headline colours, for the boot headline.
+    Headline,
+    /// The theme's panic colours, for the panic screen.
+    Alert,
+}
+
+impl BannerStyle {
+    fn attr(self) -> u8 {
+        match self {
+            BannerStyle::Headline => theme::get(Slot::Headline),
+            BannerStyle::Alert => theme::get(Slot::Panic),
+        }
+    }
+}
+
+/// `text` in large letters (`bigfont`) on rows of their own, across the
+/// full width; up to 13 characters fit.
+pub fn banner(text: &str, style: BannerStyle) {
+    CONSOLE.lock().banner(text, style);
+}
+
+/// Style of the console text written from now on.
 pub fn set_style(style: u8) {
     CONSOLE.lock().style = style;
 }
@@ -44,18 +91,112 @@ pub fn fmt(args: fmt::Arguments) {
     let _ = console.write_fmt(args);
 }
 
+/// Turn the display off or back on via the sequencer's screen-disable bit.
+/// Text memory is untouched and keeps receiving writes while off.
+pub fn set_enabled(enabled: bool) {
+    const SEQ_INDEX: u16 = 0x3C4;
+    const SEQ_DATA: u16 = 0x3C5;
+    const CLOCKING_MODE: u8 = 0x01;
+    const SCREEN_DISABLE: u8 = 1 << 5;
+    unsafe {
+        let mut index = Port::<u8>::new(SEQ_INDEX);
+        let mut data = Port::<u8>::new(SEQ_DATA);
+        index.write(CLOCKING_MODE);
+        let value = data.read();
+        let value = if enabled { value & !SCREEN_DISABLE } else { value | SCREEN_DISABLE };
+        data.write(value);
+    }
+}
+
+/// Write `text` at a fixed cell without moving the console cursor; clipped
+/// at the end of the row, the rest of the row is left alone.
+pub fn write_at(row: usize, col: usize, text: &[u8], style: u8) {
+    if row >= BUFFER_HEIGHT {
+        return;
+    }
+    let _console = CONSOLE.lock();
+    for (i, &b) in text.iter().take(BUFFER_WIDTH.saturating_sub(col)).enumerate() {
+        screen_write(entry(b, style), row * BUFFER_WIDTH + col + i);
+    }
+}
+
+/// Blank one row in `style`.
+pub fn clear_row(row: usize, style: u8) {
+    if row >= BUFFER_HEIGHT {
+        return;
+    }
+    let _console = CONSOLE.lock();
+    for col in 0..BUFFER_WIDTH {
+        screen_write(entry(b' ', style), row * BUFFER_WIDTH + col);
+    }
+}
+
+pub fn snapshot() -> Snapshot {
+    let console = CONSOLE.lock();
+    let mut cells = [0u16; CELLS];
+    for (i, cell) in cells.iter_mut().enumerate() {
+        *cell = screen_read(i);
+    }
+    Snapshot { cells, row: console.row_position, column: console.column_position, style: console.style }
+}
+
+pub fn restore(snapshot: &Snapshot) {
+    let mut console = CONSOLE.lock();
+    for (i, &cell) in snapshot.cells.iter().enumerate() {
+        screen_write(cell, i);
+    }
+    console.row_position = snapshot.row;
+    console.column_position = snapshot.column;
+    console.style = snapshot.style;
+}
+
+/// Move the shell console off screen: the screen is copied to its back
+/// buffer, which takes its writes from now on.
+pub fn hide_console() {
+    let mut console = CONSOLE.lock();
+    if console.visible {
+        for i in 0..CELLS {
+            console.back[i] = screen_read(i);
+        }
+        console.visible = false;
+    }
+}
+
+/// Put the shell console back on screen.
+pub fn show_console() {
+    CONSOLE.lock().show();
+}
+
 pub fn panic(info: &PanicInfo) {
     let mut console = CONSOLE.lock();
+    // Whatever view is up, the panic goes on the shell console, on screen
+    console.show();
     let saved_style = console.style;
-    console.style = 0x4f; // white on red for panic
+    console.style = theme::get(Slot::Panic);
+    console.banner("PANIC", BannerStyle::Alert);
     let _ = writeln!(console, "panic: {info}");
     console.style = saved_style;
 }
 
+fn entry(byte: u8, style: u8) -> u16 {
+    ((style as u16) << 8) | byte as u16
+}
+
+fn screen_write(value: u16, index: usize) {
+    unsafe { write_volatile((VGA_BUFFER_ADDRESS as *mut u16).add(index), value) }
+}
+
+fn screen_read(index: usize) -> u16 {
+    unsafe { read_volatile((VGA_BUFFER_ADDRESS as *const u16).add(index)) }
+}
+
 struct Console {
     column_position: usize,
     row_position: usize,
     style: u8,
+    visible: bool,
+    /// Contents while not visible.
+    back: [u16; CELLS],
 }
 
 impl Console {
@@ -64,6 +205,17 @@ impl Console {
             column_position: 0,
             row_position: 0,
             style: DEFAULT_STYLE,
+            visible: true,
+            back: [0; CELLS],
+        }
+    }
+
+    fn show(&mut self) {
+        if !self.visible {
+            for (i, &cell) in self.back.iter().enumerate() {
+                screen_write(cell, i);
+            }
+            self.visible = true;
         }
     }
 
@@ -116,6 +268,18 @@ impl Console {
         }
     }
 
+    fn banner(&mut self, text: &str, style: BannerStyle) {
+        if self.column_position > 0 {
+            self.new_line();
+        }
+        for line in bigfont::render::<BUFFER_WIDTH>(text.as_bytes()) {
+            for (col, &byte) in line.iter().enumerate() {
+                self.write_entry_at(byte, style.attr(), self.row_position, col);
+            }
+            self.new_line();
+        }
+    }
+
     fn backspace(&mut self) {
         if self.column_position > 0 {
             self.column_position -= 1;
@@ -128,19 +292,22 @@ impl Console {
         }
     }
 
-    fn buffer_ptr(row: usize, col: usize) -> *mut u16 {
-        (VGA_BUFFER_ADDRESS + (row * BUFFER_WIDTH + col) * 2) as *mut u16
-    }
-
-    fn write_entry_at(&self, byte: u8, style: u8, row: usize, col: usize) {
-        let value = ((style as u16) << 8) | byte as u16;
-        unsafe {
-            write_volatile(Self::buffer_ptr(row, col), value);
+    fn write_entry_at(&mut self, byte: u8, style: u8, row: usize, col: usize) {
+        let index = row * BUFFER_WIDTH + col;
+        if self.visible {
+            screen_write(entry(byte, style), index);
+        } else {
+            self.back[index] = entry(byte, style);
         }
     }
 
     fn read_entry_at(&self, row: usize, col: usize) -> u16 {
-        unsafe { read_volatile(Self::buffer_ptr(row, col)) }
+        let index = row * BUFFER_WIDTH + col;
+        if self.visible {
+            screen_read(index)
+        } else {
+            self.back[index]
+        }
     }
 }
 
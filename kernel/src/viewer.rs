@@ -0,0 +1,394 @@
+//! Full-screen hex viewer on the VGA console (`view <file|addr>`). Works from
+//! the PS/2 keyboard alone, so it is usable on machines without a serial line.
+//!
+//! Keys: arrows scroll by a row (up/down) or a byte (left/right), PgUp/PgDn
+//! by a screen, Home/End jump to the ends, `/` searches (text, or `x:` then
+//! hex bytes), `n` repeats the search, `g` goes to an offset, `q`/Esc quits.
+
+use x86_64::instructions::hlt;
+
+use crate::keyboard::{self, Focus, Key};
+use crate::theme::{self, Slot};
+use crate::vga;
+use crate::xhci;
+#[cfg(feature = "debug_tools")]
+use crate::memdbg;
+
+const BYTES_PER_ROW: usize = 16;
+const FIRST_DATA_ROW: usize = 1;
+const DATA_ROWS: usize = vga::ROWS - 2;
+const PAGE: u64 = (DATA_ROWS * BYTES_PER_ROW) as u64;
+const MAX_PATTERN: usize = 32;
+/// Memory has no end; searches give up after this many bytes.
+#[cfg(feature = "debug_tools")]
+const MEMORY_SEARCH_LIMIT: u64 = 16 * 1024 * 1024;
+
+const STYLE_HIT: u8 = 0x70;
+
+pub enum Source {
+    File { data: *const u8, len: usize },
+    /// Physical memory read through `memdbg`, so the peek unlock applies.
+    #[cfg(feature = "debug_tools")]
+    Memory { base: u64 },
+}
+
+impl Source {
+    fn len(&self) -> u64 {
+        match self {
+            Source::File { len, .. } => *len as u64,
+            #[cfg(feature = "debug_tools")]
+            Source::Memory { base } => u64::MAX - base,
+        }
+    }
+
+    /// Address shown for offset 0.
+    fn origin(&self) -> u64 {
+        match self {
+            Source::File { .. } => 0,
+            #[cfg(feature = "debug_tools")]
+            Source::Memory { base } => *base,
+        }
+    }
+
+    fn search_limit(&self) -> u64 {
+        match self {
+            Source::File { len, .. } => *len as u64,
+            #[cfg(feature = "debug_tools")]
+            Source::Memory { .. } => MEMORY_SEARCH_LIMIT,
+        }
+    }
+
+    /// Fill `buf` from `offset`; returns how many bytes were readable.
+    fn read(&self, offset: u64, buf: &mut [u8]) -> usize {
+        match self {
+            Source::File { data, len } => {
+                let start = (offset as usize).min(*len);
+                let n = buf.len().min(*len - start);
+                let bytes = unsafe { core::slice::from_raw_parts(data.add(start), n) };
+                buf[..n].copy_from_slice(bytes);
+                n
+            }
+            #[cfg(feature = "debug_tools")]
+            Source::Memory { base } => match memdbg::peek(base + offset, buf) {
+                Ok(()) => buf.len(),
+                Err(_) => 0,
+            },
+        }
+    }
+}
+
+/// Text of one dump row: `00000000  xx .. xx  xx .. xx |ascii|`.
+fn format_row(label: u64, bytes: &[u8], out: &mut [u8; vga::COLUMNS]) -> usize {
+    const HEX: &[u8; 16] = b"0123456789abcdef";
+    out.fill(b' ');
+    for i in 0..8 {
+        out[i] = HEX[((label >> (28 - 4 * i)) & 0xF) as usize];
+    }
+    for i in 0..BYTES_PER_ROW {
+        let col = hex_col(i);
+        match bytes.get(i) {
+            Some(&b) => {
+                out[col] = HEX[(b >> 4) as usize];
+                out[col + 1] = HEX[(b & 0xF) as usize];
+                out[ascii_col(i)] = if (32..127).contains(&b) { b } else { b'.' };
+            }
+            None => {
+                out[col] = b'?';
+                out[col + 1] = b'?';
+                out[ascii_col(i)] = b' ';
+            }
+        }
+    }
+    out[ascii_col(0) - 1] = b'|';
+    out[ascii_col(BYTES_PER_ROW)] = b'|';
+    ascii_col(BYTES_PER_ROW) + 1
+}
+
+fn hex_col(i: usize) -> usize {
+    10 + i * 3 + i / 8
+}
+
+fn ascii_col(i: usize) -> usize {
+    hex_col(BYTES_PER_ROW) + 2 + i
+}
+
+/// First offset in `from..limit` where `pattern` occurs.
+fn find(read: impl Fn(u64, &mut [u8]) -> usize, from: u64, limit: u64, pattern: &[u8]) -> Option<u64> {
+    const CHUNK: usize = 512;
+    if pattern.is_empty() || pattern.len() > CHUNK {
+        return None;
+    }
+    let mut buf = [0u8; CHUNK];
+    let mut at = from;
+    while at < limit {
+        let want = (CHUNK as u64).min(limit - at) as usize;
+        let n = read(at, &mut buf[..want]);
+        if n < pattern.len() {
+            return None;
+        }
+        if let Some(i) = buf[..n].windows(pattern.len()).position(|w| w == pattern) {
+            return Some(at + i as u64);
+        }
+        if n < want {
+            return None;
+        }
+        // Overlap so a match straddling two chunks is still seen
+        at += (n - pattern.len() + 1) as u64;
+    }
+    None
+}
+
+/// `x:de ad` is hex bytes, anything else is literal text.
+fn parse_pattern(input: &str, out: &mut [u8; MAX_PATTERN]) -> Option<usize> {
+    let Some(hex) = input.strip_prefix("x:") else {
+        let bytes = input.as_bytes();
+        if bytes.is_empty() || bytes.len() > MAX_PATTERN {
+            return None;
+        }
+        out[..bytes.len()].copy_from_slice(bytes);
+        return Some(bytes.len());
+    };
+    let mut n = 0;
+    for tok in hex.split(' ').filter(|t| !t.is_empty()) {
+        if n == MAX_PATTERN {
+            return None;
+        }
+        out[n] = u8::from_str_radix(tok, 16).ok()?;
+        n += 1;
+    }
+    (n > 0).then_some(n)
+}
+
+struct View<'a> {
+    source: &'a Source,
+    name: &'a str,
+    top: u64,
+    pattern: [u8; MAX_PATTERN],
+    pattern_len: usize,
+    hit: Option<u64>,
+}
+
+impl View<'_> {
+    fn last_page(&self) -> u64 {
+        let len = self.source.len();
+        len.saturating_sub(PAGE).div_ceil(BYTES_PER_ROW as u64) * BYTES_PER_ROW as u64
+    }
+
+    fn scroll(&mut self, delta: i64) {
+        let top = if delta < 0 {
+            self.top.saturating_sub(delta.unsigned_abs())
+        } else {
+            self.top.saturating_add(delta as u64)
+        };
+        self.top = top.min(self.last_page());
+    }
+
+    fn render(&self, status: &str) {
+        vga::clear_row(0, theme::get(Slot::Headline));
+        let mut title = [0u8; vga::COLUMNS];
+        let n = fmt_into(&mut title, format_args!(
+            " view {}  {:#x}..{:#x}",
+            self.name,
+            self.source.origin() + self.top,
+            self.source.origin().saturating_add(self.source.len()),
+        ));
+        vga::write_at(0, 0, &title[..n], theme::get(Slot::Headline));
+
+        let mut bytes = [0u8; BYTES_PER_ROW];
+        let mut line = [0u8; vga::COLUMNS];
+        for row in 0..DATA_ROWS {
+            let offset = self.top + (row * BYTES_PER_ROW) as u64;
+            let screen_row = FIRST_DATA_ROW + row;
+            vga::clear_row(screen_row, theme::get(Slot::Text));
+            if offset >= self.source.len() {
+                continue;
+            }
+            let want = (BYTES_PER_ROW as u64).min(self.source.len() - offset) as usize;
+            let got = self.source.read(offset, &mut bytes[..want]);
+            let n = format_row(self.source.origin() + offset, &bytes[..got], &mut line);
+            vga::write_at(screen_row, 0, &line[..n], theme::get(Slot::Text));
+            self.highlight(screen_row, offset, &line);
+        }
+
+        vga::clear_row(vga::ROWS - 1, theme::get(Slot::Headline));
+        vga::write_at(vga::ROWS - 1, 0, status.as_bytes(), theme::get(Slot::Headline));
+    }
+
+    fn highlight(&self, screen_row: usize, offset: u64, line: &[u8; vga::COLUMNS]) {
+        let Some(hit) = self.hit else { return };
+        for i in 0..BYTES_PER_ROW {
+            let at = offset + i as u64;
+            if at >= hit && at < hit + self.pattern_len as u64 {
+                let col = hex_col(i);
+                vga::write_at(screen_row, col, &line[col..col + 2], STYLE_HIT);
+                vga::write_at(screen_row, ascii_col(i), &line[ascii_col(i)..ascii_col(i) + 1], STYLE_HIT);
+            }
+        }
+    }
+
+    fn search(&mut self, from: u64) -> bool {
+        let pattern = &self.pattern[..self.pattern_len];
+        let limit = from.saturating_add(self.source.search_limit()).min(self.source.len());
+        match find(|at, buf| self.source.read(at, buf), from, limit, pattern) {
+            Some(at) => {
+                self.hit = Some(at);
+                self.top = (at - at % BYTES_PER_ROW as u64).min(self.last_page());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Minimal `fmt::Write` into a fixed buffer; returns bytes written.
+pub fn fmt_into(buf: &mut [u8], args: core::fmt::Arguments) -> usize {
+    struct Cursor<'a> {
+        buf: &'a mut [u8],
+        len: usize,
+    }
+    impl core::fmt::Write for Cursor<'_> {
+        fn write_str(&mut self, s: &str) -> core::fmt::Result {
+            let n = s.len().min(self.buf.len() - self.len);
+            self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+            self.len += n;
+            Ok(())
+        }
+    }
+    let mut cursor = Cursor { buf, len: 0 };
+    let _ = core::fmt::write(&mut cursor, args);
+    cursor.len
+}
+
+/// Block until `owner` gets a key; USB keyboards are polled meanwhile.
+pub fn wait_key(owner: Focus) -> Key {
+    loop {
+        if let Some(key) = keyboard::poll_key(owner) {
+            return key;
+        }
+        xhci::poll_events();
+        hlt();
+    }
+}
+
+/// Line editor on the status row; `None` if Escape was pressed.
+fn prompt<'a>(label: &str, buf: &'a mut [u8; 64]) -> Option<&'a str> {
+    let mut len = 0;
+    loop {
+        vga::clear_row(vga::ROWS - 1, theme::get(Slot::Headline));
+        vga::write_at(vga::ROWS - 1, 0, label.as_bytes(), theme::get(Slot::Headline));
+        vga::write_at(vga::ROWS - 1, label.len(), &buf[..len], theme::get(Slot::Headline));
+        match wait_key(Focus::Viewer) {
+            Key::Enter => break,
+            Key::Escape => return None,
+            Key::Backspace => len = len.saturating_sub(1),
+            Key::Char(c) if c.is_ascii() && !c.is_ascii_control() && len < buf.len() => {
+                buf[len] = c as u8;
+                len += 1;
+            }
+            _ => {}
+        }
+    }
+    core::str::from_utf8(&buf[..len]).ok()
+}
+
+const HELP: &str = " arrows/PgUp/PgDn/Home/End move  / search  n next  g goto  q quit";
+
+/// Take over the screen until the user quits, then put the console back.
+pub fn run(source: Source, name: &str) {
+    let saved = vga::snapshot();
+    let focus = keyboard::grab_focus(Focus::Viewer);
+    let mut view = View { source: &source, name, top: 0, pattern: [0; MAX_PATTERN], pattern_len: 0, hit: None };
+    let mut status = HELP;
+    loop {
+        view.render(status);
+        status = HELP;
+        match wait_key(Focus::Viewer) {
+            Key::Char('q') | Key::Escape => break,
+            Key::Up => view.scroll(-(BYTES_PER_ROW as i64)),
+            Key::Down => view.scroll(BYTES_PER_ROW as i64),
+            Key::Left => view.scroll(-1),
+            Key::Right => view.scroll(1),
+            Key::PageUp => view.scroll(-(PAGE as i64)),
+            Key::PageDown => view.scroll(PAGE as i64),
+            Key::Home => view.top = 0,
+            Key::End => view.top = view.last_page(),
+            Key::Char('/') => {
+                let mut input = [0u8; 64];
+                let Some(text) = prompt("/", &mut input) else { continue };
+                match parse_pattern(text, &mut view.pattern) {
+                    Some(n) => {
+                        view.pattern_len = n;
+                        if !view.search(view.top) {
+                            status = " not found";
+                        }
+                    }
+                    None => status = " bad pattern (text, or x: then hex bytes)",
+                }
+            }
+            Key::Char('n') if view.pattern_len > 0 => {
+                let from = view.hit.map_or(view.top, |h| h + 1);
+                if !view.search(from) {
+                    status = " no further match";
+                }
+            }
+            Key::Char('g') => {
+                let mut input = [0u8; 64];
+                let Some(text) = prompt("goto offset (hex): ", &mut input) else { continue };
+                match u64::from_str_radix(text.trim_start_matches("0x"), 16) {
+                    Ok(off) => view.top = (off - off % BYTES_PER_ROW as u64).min(view.last_page()),
+                    Err(_) => status = " bad offset",
+                }
+            }
+            _ => {}
+        }
+    }
+    drop(focus);
+    vga::restore(&saved);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reader(data: &'static [u8]) -> impl Fn(u64, &mut [u8]) -> usize {
+        move |at, buf| {
+            let start = (at as usize).min(data.len());
+            let n = buf.len().min(data.len() - start);
+            buf[..n].copy_from_slice(&data[start..start + n]);
+            n
+        }
+    }
+
+    #[test]
+    fn row_layout_has_hex_and_ascii_columns() {
+        let mut out = [0u8; vga::COLUMNS];
+        let n = format_row(0x1230, b"AB\x00", &mut out);
+        let line = core::str::from_utf8(&out[..n]).unwrap();
+        assert!(line.starts_with("00001230  41 42 00 ?? "));
+        assert!(line.ends_with("|AB.             |"));
+        assert_eq!(n, 79);
+    }
+
+    #[test]
+    fn find_sees_matches_across_chunks() {
+        static DATA: [u8; 1200] = {
+            let mut d = [0u8; 1200];
+            d[510] = b'x';
+            d[511] = b'y';
+            d[512] = b'z';
+            d
+        };
+        assert_eq!(find(reader(&DATA), 0, 1200, b"xyz"), Some(510));
+        assert_eq!(find(reader(&DATA), 511, 1200, b"xyz"), None);
+    }
+
+    #[test]
+    fn patterns_are_text_or_hex() {
+        let mut p = [0u8; MAX_PATTERN];
+        assert_eq!(parse_pattern("abc", &mut p), Some(3));
+        assert_eq!(&p[..3], b"abc");
+        assert_eq!(parse_pattern("x:de ad", &mut p), Some(2));
+        assert_eq!(&p[..2], &[0xde, 0xad]);
+        assert_eq!(parse_pattern("x:zz", &mut p), None);
+    }
+}
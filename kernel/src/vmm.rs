@@ -0,0 +1,582 @@
+#![allow(dead_code)]
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::paging::{PageTable, PageTableFlags, PhysFrame};
+use x86_64::{PhysAddr, VirtAddr};
+
+use crate::{addr, fastmem, ipi, kaslr, pmm, serial};
+
+pub const PAGE_SIZE: u64 = 4096;
+
+// Stage2 identity-maps the first 4 GiB through PML4[0]; that slot (and the higher half) is shared
+// by every address space. User mappings live in PML4[1..256).
+pub const USER_BASE: u64 = 0x0000_0080_0000_0000;
+pub const USER_END: u64 = 0x0000_8000_0000_0000;
+pub const KERNEL_BASE: u64 = 0xFFFF_8000_0000_0000;
+
+const MAX_LAZY: usize = 8;
+
+static KERNEL_PML4: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MapError {
+    OutOfMemory,
+    NotUserRange,
+    HugePage,
+    AlreadyMapped,
+    NoSlot,
+    NotKernelRange,
+    WindowFull,
+}
+
+/// A kernel stack in the stacks window; the page just below `bottom` is left
+/// unmapped so an overflow faults instead of scribbling on its neighbour.
+#[derive(Copy, Clone, Debug)]
+pub struct KernelStack {
+    pub bottom: u64,
+    pub top: u64,
+}
+
+/// Bytes handed out so far in the stacks and MMIO windows.
+static STACK_CURSOR: AtomicU64 = AtomicU64::new(0);
+static MMIO_CURSOR: AtomicU64 = AtomicU64::new(0);
+
+/// Virtual range whose pages are allocated and zeroed on first touch.
+#[derive(Copy, Clone, Debug)]
+pub struct LazyRegion {
+    pub start: u64,
+    pub end: u64,
+    pub flags: PageTableFlags,
+}
+
+#[derive(Copy, Clone)]
+pub struct AddressSpace {
+    pml4: u64,
+    lazy: [Option<LazyRegion>; MAX_LAZY],
+}
+
+pub fn init() {
+    // stage2 only sets LME; user mappings carry NO_EXECUTE, which is a
+    // reserved bit (and faults) unless NXE is on
+    unsafe { Efer::update(|f| f.insert(EferFlags::NO_EXECUTE_ENABLE)) };
+    let (frame, _) = Cr3::read();
+    KERNEL_PML4.store(frame.start_address().as_u64(), Ordering::Release);
+    serial::write_fmt(format_args!(
+        "[vmm] kernel pml4={:#x}\r\n",
+        frame.start_address().as_u64()
+    ));
+}
+
+pub fn kernel_pml4() -> u64 {
+    KERNEL_PML4.load(Ordering::Acquire)
+}
+
+/// Switches back to the boot page tables.
+pub fn activate_kernel() {
+    let pml4 = kernel_pml4();
+    if pml4 != 0 {
+        load_cr3(pml4);
+    }
+}
+
+fn load_cr3(pml4: u64) {
+    let (current, _) = Cr3::read();
+    if current.start_address().as_u64() == pml4 {
+        return;
+    }
+    unsafe { Cr3::write(PhysFrame::containing_address(PhysAddr::new(pml4)), Cr3Flags::empty()) };
+}
+
+pub fn is_user_range(addr: u64, len: u64) -> bool {
+    match addr.checked_add(len) {
+        Some(end) => addr >= USER_BASE && end <= USER_END,
+        None => false,
+    }
+}
+
+unsafe fn table_at(phys: u64) -> &'static mut PageTable {
+    &mut *addr::PhysAddr::new(phys).as_mut_ptr::<PageTable>()
+}
+
+fn translate_in(pml4: u64, virt: u64) -> Option<(u64, PageTableFlags)> {
+    let idx = indices(virt);
+    let mut table = unsafe { table_at(pml4) };
+    for (level, &i) in idx.iter().enumerate() {
+        let entry = &table[i];
+        if !entry.flags().contains(PageTableFlags::PRESENT) {
+            return None;
+        }
+        let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        if level == 3 || (huge && level > 0) {
+            let page_size = 1u64 << (12 + 9 * (3 - level));
+            let offset = virt & (page_size - 1);
+            return Some((entry.addr().as_u64() + offset, entry.flags()));
+        }
+        table = unsafe { table_at(entry.addr().as_u64()) };
+    }
+    None
+}
+
+/// Frees the table at `phys`, `level` levels above the page tables, with
+/// everything it maps.
+fn free_table(phys: u64, level: u8) -> u64 {
+    let table = unsafe { table_at(phys) };
+    let mut freed = 0;
+    for entry in table.iter().filter(|e| !e.is_unused()) {
+        let child = entry.addr().as_u64();
+        if level == 0 || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            // map_page never builds huge pages; a huge entry here is not ours to free
+            if level == 0 {
+                pmm::free_page(child);
+                freed += 1;
+            }
+        } else {
+            freed += free_table(child, level - 1);
+        }
+    }
+    pmm::free_page(phys);
+    freed + 1
+}
+
+/// Walks (creating as needed) the kernel tables down to the PML4 entry of
+/// `virt`, so address spaces created afterwards share it.
+pub fn reserve_kernel_slot(virt: u64) -> Result<(), MapError> {
+    if virt < KERNEL_BASE || kernel_pml4() == 0 {
+        return Err(MapError::NotKernelRange);
+    }
+    let table = unsafe { table_at(kernel_pml4()) };
+    let entry = &mut table[indices(virt)[0]];
+    if entry.is_unused() {
+        let next = alloc_table().ok_or(MapError::OutOfMemory)?;
+        entry.set_addr(PhysAddr::new(next), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+    }
+    Ok(())
+}
+
+/// Maps one 4 KiB page in the kernel half of the boot tables.
+pub fn map_kernel_page(virt: u64, phys: u64, flags: PageTableFlags) -> Result<(), MapError> {
+    if virt < KERNEL_BASE || kernel_pml4() == 0 {
+        return Err(MapError::NotKernelRange);
+    }
+    let idx = indices(virt);
+    let parent_flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    let mut table = unsafe { table_at(kernel_pml4()) };
+    for &i in &idx[..3] {
+        let entry = &mut table[i];
+        if entry.is_unused() {
+            let next = alloc_table().ok_or(MapError::OutOfMemory)?;
+            entry.set_addr(PhysAddr::new(next), parent_flags);
+        } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Err(MapError::HugePage);
+        }
+        table = unsafe { table_at(entry.addr().as_u64()) };
+    }
+    let leaf = &mut table[idx[3]];
+    if !leaf.is_unused() {
+        return Err(MapError::AlreadyMapped);
+    }
+    leaf.set_addr(PhysAddr::new(phys), flags | PageTableFlags::PRESENT);
+    ipi::flush_tlb(Some(virt));
+    Ok(())
+}
+
+/// Unmaps one 4 KiB page of the shared identity map, splitting the 2 MiB
+/// page that covers it. Every address space sees the hole.
+pub fn unmap_identity_page(virt: u64) -> Result<(), MapError> {
+    if virt >= addr::IDENTITY_LIMIT || kernel_pml4() == 0 {
+        return Err(MapError::NotKernelRange);
+    }
+    let idx = indices(virt);
+    let mut table = unsafe { table_at(kernel_pml4()) };
+    for &i in &idx[..2] {
+        let entry = &table[i];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return Err(MapError::HugePage);
+        }
+        table = unsafe { table_at(entry.addr().as_u64()) };
+    }
+    let pde = &mut table[idx[2]];
+    if pde.is_unused() {
+        return Err(MapError::NotKernelRange);
+    }
+    if pde.flags().contains(PageTableFlags::HUGE_PAGE) {
+        let flags = pde.flags() - PageTableFlags::HUGE_PAGE;
+        let base = pde.addr().as_u64();
+        let pt_phys = alloc_table().ok_or(MapError::OutOfMemory)?;
+        let pt = unsafe { table_at(pt_phys) };
+        for (i, entry) in pt.iter_mut().enumerate() {
+            entry.set_addr(PhysAddr::new(base + i as u64 * PAGE_SIZE), flags);
+        }
+        pde.set_addr(PhysAddr::new(pt_phys), flags);
+    }
+    let pt = unsafe { table_at(pde.addr().as_u64()) };
+    pt[idx[3]].set_unused();
+    ipi::flush_tlb(None);
+    Ok(())
+}
+
+/// `len` rounded up to whole pages; `None` past the top of the address space.
+fn window_len(len: u64) -> Option<u64> {
+    Some(len.checked_add(PAGE_SIZE - 1)? & !(PAGE_SIZE - 1))
+}
+
+/// Bump-allocates `len` bytes (page granular) from a kaslr window. The
+/// cursor only moves when the bytes fit, so a full window stays full
+/// instead of wrapping.
+fn window_alloc(cursor: &AtomicU64, len: u64) -> Result<u64, MapError> {
+    let len = window_len(len).ok_or(MapError::WindowFull)?;
+    cursor
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |offset| {
+            offset.checked_add(len).filter(|&end| end <= kaslr::WINDOW_SPAN)
+        })
+        .map_err(|_| MapError::WindowFull)
+}
+
+/// Give back a `window_alloc` that failed halfway. Only the latest one can
+/// be: behind another allocation the bytes stay used.
+fn window_free(cursor: &AtomicU64, offset: u64, len: u64) {
+    let Some(end) = window_len(len).and_then(|len| offset.checked_add(len)) else { return };
+    let _ = cursor.compare_exchange(end, offset, Ordering::Relaxed, Ordering::Relaxed);
+}
+
+/// A zeroed page from the pmm for `owner`.
+fn zeroed_page(owner: &'static str) -> Option<u64> {
+    let frame = pmm::alloc_page(owner)?;
+    unsafe { fastmem::fill(addr::PhysAddr::new(frame).as_mut_ptr::<u8>(), 0, PAGE_SIZE as usize) };
+    Some(frame)
+}
+
+/// Removes a 4 KiB mapping from the kernel half of the boot tables and
+/// returns the frame it pointed to.
+fn unmap_kernel_page(virt: u64) -> Option<u64> {
+    if virt < KERNEL_BASE || kernel_pml4() == 0 {
+        return None;
+    }
+    let idx = indices(virt);
+    let mut table = unsafe { table_at(kernel_pml4()) };
+    for &i in &idx[..3] {
+        let entry = &table[i];
+        if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return None;
+        }
+        table = unsafe { table_at(entry.addr().as_u64()) };
+    }
+    let leaf = &mut table[idx[3]];
+    if leaf.is_unused() {
+        return None;
+    }
+    let phys = leaf.addr().as_u64();
+    leaf.set_unused();
+    ipi::flush_tlb(Some(virt));
+    Some(phys)
+}
+
+/// Allocates a zeroed kernel stack of `pages` pages with an unmapped guard page below it.
+/// On failure the pages mapped so far and the window space are given back.
+pub fn alloc_kernel_stack(pages: u64) -> Result<KernelStack, MapError> {
+    let len = pages.checked_add(1).and_then(|n| n.checked_mul(PAGE_SIZE)).ok_or(MapError::WindowFull)?;
+    let offset = window_alloc(&STACK_CURSOR, len)?;
+    let bottom = kaslr::base(kaslr::Region::Stacks) + offset + PAGE_SIZE;
+    for i in 0..pages {
+        let virt = bottom + i * PAGE_SIZE;
+        let mapped = back_page(
+            || zeroed_page("vmm.stack"),
+            |frame| map_kernel_page(virt, frame, PageTableFlags::WRITABLE),
+            pmm::free_page,
+        );
+        if let Err(e) = mapped {
+            for page in (0..i).map(|j| bottom + j * PAGE_SIZE) {
+                if let Some(frame) = unmap_kernel_page(page) {
+                    pmm::free_page(frame);
+                }
+            }
+            window_free(&STACK_CURSOR, offset, len);
+            return Err(e);
+        }
+    }
+    Ok(KernelStack { bottom, top: bottom + pages * PAGE_SIZE })
+}
+
+/// First page and length in bytes of the pages `phys..phys+len` touches;
+/// `None` if the range runs past the top of the address space.
+fn mmio_span(phys: u64, len: u64) -> Option<(u64, u64)> {
+    let start = phys & !(PAGE_SIZE - 1);
+    let span = (phys - start).checked_add(len)?;
+    start.checked_add(window_len(span)?)?;
+    Some((start, span))
+}
+
+/// Maps the device registers at `phys..phys+len` uncached into the MMIO window
+/// and returns the virtual address of `phys`. On failure the pages mapped
+/// so far and the window space are given back.
+pub fn map_mmio(phys: u64, len: u64) -> Result<u64, MapError> {
+    let (start, span) = mmio_span(phys, len).ok_or(MapError::WindowFull)?;
+    let offset = window_alloc(&MMIO_CURSOR, span)?;
+    let virt = kaslr::base(kaslr::Region::Mmio) + offset;
+    let flags = PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE | PageTableFlags::WRITE_THROUGH;
+    let mut page = 0;
+    while page < span {
+        if let Err(e) = map_kernel_page(virt + page, start + page, flags) {
+            // Device memory: the frames are not the pmm's to free
+            for done in (0..page).step_by(PAGE_SIZE as usize) {
+                unmap_kernel_page(virt + done);
+            }
+            window_free(&MMIO_CURSOR, offset, span);
+            return Err(e);
+        }
+        page += PAGE_SIZE;
+    }
+    Ok(virt + (phys - start))
+}
+
+/// Physical address of `virt` in whichever address space CR3 points at.
+pub fn translate_active(virt: u64) -> Option<u64> {
+    let (frame, _) = Cr3::read();
+    translate_in(frame.start_address().as_u64(), virt).map(|(phys, _)| phys)
+}
+
+fn alloc_table() -> Option<u64> {
+    let phys = pmm::alloc_page("vmm.table")?;
+    unsafe { table_at(phys).zero() };
+    Some(phys)
+}
+
+fn indices(virt: u64) -> [usize; 4] {
+    let v = VirtAddr::new(virt);
+    [
+        usize::from(v.p4_index()),
+        usize::from(v.p3_index()),
+        usize::from(v.p2_index()),
+        usize::from(v.p1_index()),
+    ]
+}
+
+impl AddressSpace {
+    /// Creates an address space sharing the kernel's identity map and higher half.
+    pub fn new() -> Result<Self, MapError> {
+        let kernel = kernel_pml4();
+        let pml4 = alloc_table().ok_or(MapError::OutOfMemory)?;
+        if kernel != 0 {
+            let src = unsafe { table_at(kernel) };
+            let dst = unsafe { table_at(pml4) };
+            dst[0] = src[0].clone();
+            for i in 256..512 {
+                dst[i] = src[i].clone();
+            }
+        }
+        Ok(Self { pml4, lazy: [None; MAX_LAZY] })
+    }
+
+    /// No tables at all: only for tests that never activate it.
+    #[cfg(test)]
+    pub(crate) const fn detached() -> Self {
+        Self { pml4: 0, lazy: [None; MAX_LAZY] }
+    }
+
+    pub fn pml4_phys(&self) -> u64 {
+        self.pml4
+    }
+
+    pub fn activate(&self) {
+        load_cr3(self.pml4);
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0.start_address().as_u64() == self.pml4
+    }
+
+    /// Maps one 4 KiB user page; intermediate tables are created on demand.
+    pub fn map_page(&mut self, virt: u64, phys: u64, flags: PageTableFlags) -> Result<(), MapError> {
+        if !is_user_range(virt, PAGE_SIZE) {
+            return Err(MapError::NotUserRange);
+        }
+        let idx = indices(virt);
+        let parent_flags = PageTableFlags::PRESENT
+            | PageTableFlags::WRITABLE
+            | PageTableFlags::USER_ACCESSIBLE;
+        let mut table = unsafe { table_at(self.pml4) };
+        for &i in &idx[..3] {
+            let entry = &mut table[i];
+            if entry.is_unused() {
+                let next = alloc_table().ok_or(MapError::OutOfMemory)?;
+                entry.set_addr(PhysAddr::new(next), parent_flags);
+            } else if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return Err(MapError::HugePage);
+            }
+            table = unsafe { table_at(entry.addr().as_u64()) };
+        }
+        let leaf = &mut table[idx[3]];
+        if !leaf.is_unused() {
+            return Err(MapError::AlreadyMapped);
+        }
+        leaf.set_addr(PhysAddr::new(phys), flags | PageTableFlags::PRESENT);
+        if self.is_active() {
+            ipi::flush_tlb(Some(virt));
+        }
+        Ok(())
+    }
+
+    /// Removes a user page mapping and returns the physical frame it pointed to.
+    pub fn unmap_page(&mut self, virt: u64) -> Option<u64> {
+        if !is_user_range(virt, PAGE_SIZE) {
+            return None;
+        }
+        let idx = indices(virt);
+        let mut table = unsafe { table_at(self.pml4) };
+        for &i in &idx[..3] {
+            let entry = &table[i];
+            if entry.is_unused() || entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+                return None;
+            }
+            table = unsafe { table_at(entry.addr().as_u64()) };
+        }
+        let leaf = &mut table[idx[3]];
+        if leaf.is_unused() {
+            return None;
+        }
+        let phys = leaf.addr().as_u64();
+        leaf.set_unused();
+        if self.is_active() {
+            ipi::flush_tlb(Some(virt));
+        }
+        Some(phys)
+    }
+
+    /// Frees every user page and table, then the PML4 itself; returns the
+    /// pages given back. User mappings own their frames (they come from
+    /// `fault_in` or a caller's `alloc_page`); the shared kernel entries are
+    /// left alone. Must not be called on the active address space.
+    pub fn destroy(self) -> u64 {
+        debug_assert!(!self.is_active());
+        let pml4 = unsafe { table_at(self.pml4) };
+        let mut freed = 0;
+        for entry in pml4.iter_mut().take(256).skip(indices(USER_BASE)[0]) {
+            if !entry.is_unused() {
+                freed += free_table(entry.addr().as_u64(), 2);
+                entry.set_unused();
+            }
+        }
+        pmm::free_page(self.pml4);
+        freed + 1
+    }
+
+    /// Walks the tables (including the shared huge-page identity map).
+    pub fn translate(&self, virt: u64) -> Option<(u64, PageTableFlags)> {
+        translate_in(self.pml4, virt)
+    }
+
+    pub fn add_lazy_region(&mut self, region: LazyRegion) -> Result<(), MapError> {
+        if !is_user_range(region.start, region.end.saturating_sub(region.start)) {
+            return Err(MapError::NotUserRange);
+        }
+        let slot = self.lazy.iter_mut().find(|r| r.is_none()).ok_or(MapError::NoSlot)?;
+        *slot = Some(region);
+        Ok(())
+    }
+
+    pub fn lazy_region(&self, addr: u64) -> Option<LazyRegion> {
+        self.lazy
+            .iter()
+            .flatten()
+            .find(|r| addr >= r.start && addr < r.end)
+            .copied()
+    }
+
+    /// Backs the page containing `addr` if it lies in a lazy region. Returns false otherwise.
+    pub fn fault_in(&mut self, addr: u64) -> bool {
+        let region = match self.lazy_region(addr) {
+            Some(r) => r,
+            None => return false,
+        };
+        let page = addr & !(PAGE_SIZE - 1);
+        if self.translate(page).is_some() {
+            return false;
+        }
+        back_page(|| zeroed_page("vmm.heap"), |frame| self.map_page(page, frame, region.flags), pmm::free_page).is_ok()
+    }
+}
+
+/// Map a frame from `alloc` with `map`; a frame that could not be mapped
+/// goes back through `free` instead of leaking. Returns the frame.
+fn back_page(
+    alloc: impl FnOnce() -> Option<u64>,
+    map: impl FnOnce(u64) -> Result<(), MapError>,
+    free: impl FnOnce(u64),
+) -> Result<u64, MapError> {
+    let frame = alloc().ok_or(MapError::OutOfMemory)?;
+    match map(frame) {
+        Ok(()) => Ok(frame),
+        Err(e) => {
+            free(frame);
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::Cell;
+
+    fn region(start: u64, end: u64) -> LazyRegion {
+        LazyRegion { start, end, flags: PageTableFlags::WRITABLE }
+    }
+
+    #[test]
+    fn lazy_region_covers_start_but_not_end() {
+        let mut lazy = [None; MAX_LAZY];
+        lazy[0] = Some(region(0x1000, 0x3000));
+        lazy[2] = Some(region(0x8000, 0x9000));
+        let aspace = AddressSpace { pml4: 0, lazy };
+        assert_eq!(aspace.lazy_region(0x1000).map(|r| r.start), Some(0x1000));
+        assert_eq!(aspace.lazy_region(0x2fff).map(|r| r.end), Some(0x3000));
+        assert_eq!(aspace.lazy_region(0x8800).map(|r| r.start), Some(0x8000));
+        assert!(aspace.lazy_region(0x3000).is_none());
+        assert!(aspace.lazy_region(0xfff).is_none());
+        assert!(aspace.lazy_region(0x5000).is_none());
+    }
+
+    #[test]
+    fn frame_that_cannot_be_mapped_is_freed() {
+        let freed = Cell::new(None);
+        let result = back_page(|| Some(0x5000), |_| Err(MapError::OutOfMemory), |f| freed.set(Some(f)));
+        assert_eq!(result, Err(MapError::OutOfMemory));
+        assert_eq!(freed.get(), Some(0x5000));
+
+        freed.set(None);
+        assert_eq!(back_page(|| Some(0x6000), |_| Ok(()), |f| freed.set(Some(f))), Ok(0x6000));
+        assert_eq!(freed.get(), None);
+        // Nothing allocated: nothing to map or free
+        let result = back_page(|| None, |_| panic!("mapped without a frame"), |f| freed.set(Some(f)));
+        assert_eq!(result, Err(MapError::OutOfMemory));
+        assert_eq!(freed.get(), None);
+    }
+
+    #[test]
+    fn full_window_does_not_move_the_cursor() {
+        let cursor = AtomicU64::new(0);
+        assert_eq!(window_alloc(&cursor, 1), Ok(0));
+        assert_eq!(window_alloc(&cursor, 3 * PAGE_SIZE), Ok(PAGE_SIZE));
+        assert_eq!(window_alloc(&cursor, kaslr::WINDOW_SPAN), Err(MapError::WindowFull));
+        assert_eq!(window_alloc(&cursor, u64::MAX - PAGE_SIZE), Err(MapError::WindowFull));
+        assert_eq!(window_alloc(&cursor, u64::MAX), Err(MapError::WindowFull));
+        assert_eq!(cursor.load(Ordering::Relaxed), 4 * PAGE_SIZE);
+
+        // Only the latest allocation goes back
+        window_free(&cursor, 0, PAGE_SIZE);
+        assert_eq!(cursor.load(Ordering::Relaxed), 4 * PAGE_SIZE);
+        window_free(&cursor, PAGE_SIZE, 3 * PAGE_SIZE);
+        assert_eq!(cursor.load(Ordering::Relaxed), PAGE_SIZE);
+        window_free(&cursor, PAGE_SIZE, u64::MAX);
+        assert_eq!(cursor.load(Ordering::Relaxed), PAGE_SIZE);
+    }
+
+    #[test]
+    fn bogus_bar_lengths_do_not_wrap() {
+        assert_eq!(mmio_span(0xFEB0_0010, 0x20), Some((0xFEB0_0000, 0x30)));
+        assert_eq!(mmio_span(0xFEB0_0010, u64::MAX - 8), None);
+        assert_eq!(mmio_span(!0xFFF, PAGE_SIZE), None);
+    }
+}
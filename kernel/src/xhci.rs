@@ -1,13 +1,30 @@
+use crate::addr;
+use crate::config;
+use crate::executor::{self, Event};
+use crate::fastmem;
+use crate::klog::{self, Level};
+use crate::ktrace;
+use crate::mmio::{dma_wmb, MmioError, MmioRegion};
+use crate::xhci_events::{Consumer, Cursor};
+#[cfg(feature = "xhci_debug")]
+use crate::xhci_check;
+use crate::xhci_regs::{Crcr, EventStatus, ExtCap, Iman, Portsc, TransferStatus, TrbControl, UsbLegCtlSts, UsbLegSup};
 use crate::pmm;
 use crate::vga;
 use crate::serial;
+use crate::time;
+use crate::usb_state;
+use crate::usb_desc::{ConfigDescriptor, EndpointDescriptor, TRANSFER_BULK, TRANSFER_CONTROL, TRANSFER_INTERRUPT, TRANSFER_ISOCHRONOUS};
 use bitflags::bitflags;
+use core::fmt;
+use core::future::poll_fn;
 use core::hint::spin_loop;
 use core::marker::PhantomData;
 use core::mem::size_of;
 use core::ptr::{read_volatile, write_volatile, NonNull};
 use core::slice;
-use core::sync::atomic::{compiler_fence, Ordering as FenceOrdering};
+use core::sync::atomic::{AtomicU32, AtomicU64, AtomicU8, Ordering};
+use core::task::Poll;
 use spin::{Mutex, Once};
 
 bitflags! {
@@ -17,6 +34,8 @@ bitflags! {
         const INTERRUPTER_ENABLE = 1 << 2;
         const HOST_SYSTEM_ERROR_ENABLE = 1 << 3;
         const LIGHT_HOST_CONTROLLER_RESET = 1 << 7;
+        const CONTROLLER_SAVE_STATE = 1 << 8;
+        const CONTROLLER_RESTORE_STATE = 1 << 9;
     }
 }
 
@@ -26,6 +45,10 @@ bitflags! {
         const HOST_SYSTEM_ERROR = 1 << 2;
         const EVENT_INTERRUPT = 1 << 3;
         const PORT_CHANGE_DETECT = 1 << 4;
+        const SAVE_STATE_STATUS = 1 << 8;
+        const RESTORE_STATE_STATUS = 1 << 9;
+        const SAVE_RESTORE_ERROR = 1 << 10;
+        const HOST_CONTROLLER_ERROR = 1 << 12;
     }
 }
 
@@ -41,9 +64,41 @@ pub struct XhciInfo {
     pub hccparams1: u32,
     pub dboff: u32,
     pub rtsoff: u32,
+    /// Byte offset of the USB Legacy Support capability, 0 if there is none.
+    pub legacy_cap: usize,
 }
 
 impl XhciInfo {
+    pub fn max_interrupters(&self) -> u16 {
+        ((self.hcsparams1 >> 8) & 0x7FF) as u16
+    }
+
+    /// The BAR size is not recorded; this covers exactly the register blocks we use.
+    pub fn mmio_len(&self) -> usize {
+        self.operational_end().max(self.runtime_end()).max(self.doorbell_end()).max(self.legacy_end())
+    }
+
+    fn legacy_end(&self) -> usize {
+        if self.legacy_cap == 0 { 0 } else { self.legacy_cap + LEGACY_REGS_LEN }
+    }
+
+    /// Byte offset of the first extended capability (xECP), 0 if none.
+    fn xecp(&self) -> usize {
+        (self.hccparams1 >> 16) as usize * 4
+    }
+
+    fn operational_end(&self) -> usize {
+        self.cap_length as usize + PORT_REGS_OFFSET + self.max_ports() as usize * PORT_REGS_STRIDE
+    }
+
+    fn runtime_end(&self) -> usize {
+        self.rtsoff as usize + IRS_OFFSET + (self.max_interrupters().max(1) as usize) * IRS_STRIDE
+    }
+
+    fn doorbell_end(&self) -> usize {
+        self.dboff as usize + (self.max_slots() as usize + 1) * 4
+    }
+
     pub fn max_slots(&self) -> u8 {
         (self.hcsparams1 & 0xFF) as u8
     }
@@ -69,9 +124,6 @@ struct ControllerState {
     command_ring_enqueue: usize,
     command_ring_cycle: bool,
     event_ring_phys: u64,
-    event_ring_len: usize,
-    event_ring_dequeue: usize,
-    event_ring_cycle: bool,
     dcbaa_phys: u64,
     erst_phys: u64,
     last_completion_code: Option<u8>,
@@ -93,21 +145,220 @@ struct ControllerState {
     intr_cycle: bool,
     hid_buf_phys: u64,
     hid_buf_len: usize,
+    /// PORTSC speed code of the addressed device (1=FS, 2=LS, 3=HS, 4+=SS).
+    device_speed: u8,
+    /// Root hub port number (1-based) of the addressed device.
+    device_port: u8,
+    /// Minimum delay between interrupt-IN re-posts, 0 = re-post on completion.
+    hid_pace_ms: u32,
+    hid_last_post_tsc: u64,
+    hid_repost_pending: bool,
+    /// Set between `suspend` and `resume`.
+    suspended: Option<SavedRegs>,
+    /// Commands queued and not completed; each holds a command ring TRB.
+    commands_pending: usize,
+}
+
+impl ControllerState {
+    /// Empty rings at `rings`, no device.
+    fn new(info: XhciInfo, rings: &Rings, hid_pace_ms: u32) -> Self {
+        ControllerState {
+            info,
+            command_ring_phys: rings.command,
+            command_ring_len: CMD_RING_TRBS,
+            command_ring_enqueue: 0,
+            command_ring_cycle: true,
+            event_ring_phys: rings.event,
+            dcbaa_phys: rings.dcbaa,
+            erst_phys: rings.erst,
+            last_completion_code: None,
+            last_completed_slot: None,
+            last_transfer_code: None,
+            last_transfer_len: None,
+            last_transfer_ep: None,
+            last_transfer_slot: None,
+            active_slot: None,
+            ep0_ring_phys: 0,
+            ep0_ring_len: 0,
+            ep0_enqueue: 0,
+            ep0_cycle: true,
+            intr_ep_addr: 0,
+            intr_ep_id: 0,
+            intr_ring_phys: 0,
+            intr_ring_len: 0,
+            intr_enqueue: 0,
+            intr_cycle: true,
+            hid_buf_phys: 0,
+            hid_buf_len: 0,
+            device_speed: 0,
+            device_port: 0,
+            hid_pace_ms,
+            hid_last_post_tsc: 0,
+            hid_repost_pending: false,
+            suspended: None,
+            commands_pending: 0,
+        }
+    }
+
+    fn rings(&self) -> Rings {
+        Rings { command: self.command_ring_phys, dcbaa: self.dcbaa_phys, event: self.event_ring_phys, erst: self.erst_phys }
+    }
+}
+
+/// Conditions the driver cannot carry on from; `recover` resets the controller.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Fault {
+    /// Commands stopped completing until no command ring TRB was left.
+    RingFull = 1,
+    /// USBSTS reported a host system or host controller error.
+    HostError = 2,
+}
+
+impl Fault {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Fault::RingFull => "command ring full",
+            Fault::HostError => "host controller error",
+        }
+    }
+}
+
+/// The pending `Fault`, 0 when there is none.
+static FAULT: AtomicU8 = AtomicU8::new(0);
+static RING_FULL: AtomicU32 = AtomicU32::new(0);
+static HOST_ERRORS: AtomicU32 = AtomicU32::new(0);
+static RESETS: AtomicU32 = AtomicU32::new(0);
+static RESET_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ErrorStats {
+    pub ring_full: u32,
+    pub host_errors: u32,
+    pub resets: u32,
+    pub reset_failures: u32,
+}
+
+pub fn error_stats() -> ErrorStats {
+    ErrorStats {
+        ring_full: RING_FULL.load(Ordering::Relaxed),
+        host_errors: HOST_ERRORS.load(Ordering::Relaxed),
+        resets: RESETS.load(Ordering::Relaxed),
+        reset_failures: RESET_FAILURES.load(Ordering::Relaxed),
+    }
+}
+
+/// Record `fault` once until `recover` has dealt with it.
+fn fault(fault: Fault) {
+    if FAULT.compare_exchange(0, fault as u8, Ordering::AcqRel, Ordering::Relaxed).is_err() {
+        return;
+    }
+    match fault {
+        Fault::RingFull => RING_FULL.fetch_add(1, Ordering::Relaxed),
+        Fault::HostError => HOST_ERRORS.fetch_add(1, Ordering::Relaxed),
+    };
+    klog::log(Level::Warn, format_args!("[xhci] {}, controller reset pending\r\n", fault.as_str()));
+}
+
+pub fn needs_recovery() -> bool {
+    FAULT.load(Ordering::Acquire) != 0
+}
+
+/// Reset the controller after a fault and rebuild its rings in the memory
+/// they already use. Every device is dropped and must be enumerated again;
+/// their contexts and transfer rings are not reclaimed.
+pub fn recover() -> Result<(), &'static str> {
+    if !needs_recovery() {
+        return Ok(());
+    }
+    let state_lock = CONTROLLER_STATE.get().ok_or("xhci: not initialized")?;
+    let result = reset_and_restart(&mut state_lock.lock());
+    FAULT.store(0, Ordering::Release);
+    usb_state::remove_all();
+    match result {
+        Ok(()) => {
+            RESETS.fetch_add(1, Ordering::Relaxed);
+            klog::log(Level::Info, format_args!("[xhci] controller reset, rings rebuilt\r\n"));
+        }
+        Err(e) => {
+            RESET_FAILURES.fetch_add(1, Ordering::Relaxed);
+            klog::log(Level::Error, format_args!("[xhci] recovery failed: {}\r\n", e));
+        }
+    }
+    result
+}
+
+/// Shutdown hook: halt and reset the controller so it stops DMA into
+/// memory the next kernel will reuse.
+pub fn shutdown() -> Result<(), &'static str> {
+    let state_lock = CONTROLLER_STATE.get().ok_or("xhci: not initialized")?;
+    let info = state_lock.lock().info;
+    let controller = unsafe { Xhci::new(info) }.ok_or("xhci: null base")?;
+    halt_and_reset(&controller.operational())?;
+    usb_state::remove_all();
+    Ok(())
+}
+
+fn reset_and_restart(st: &mut ControllerState) -> Result<(), &'static str> {
+    let controller = unsafe { Xhci::new(st.info) }.ok_or("xhci: null base")?;
+    // Keep a moderation interval changed at run time
+    let imod = controller.runtime().interrupter_register_set(0).imod();
+    halt_and_reset(&controller.operational())?;
+    let rings = st.rings();
+    let cmd_ring = unsafe { phys_to_slice_mut::<Trb>(rings.command, CMD_RING_TRBS) };
+    zero_trbs(cmd_ring);
+    init_link_trb(cmd_ring, rings.command, true);
+    zero_trbs(unsafe { phys_to_slice_mut::<Trb>(rings.event, EVENT_RING_TRBS) });
+    zero_phys(rings.dcbaa, (st.info.max_slots() as usize + 1) * size_of::<u64>());
+    *st = ControllerState::new(st.info, &rings, st.hid_pace_ms);
+    EVENTS.reset();
+    start(&controller, &rings, imod)
+}
+
+/// Registers the controller's save/restore does not cover and that
+/// `ControllerState` does not already record (xHCI 4.23.2).
+#[derive(Clone, Copy, Debug)]
+struct SavedRegs {
+    config: u32,
+    imod: u32,
 }
 
 static CONTROLLER_STATE: Once<Mutex<ControllerState>> = Once::new();
+/// Dequeue position in the event ring, outside `CONTROLLER_STATE`.
+static EVENTS: Consumer = Consumer::new();
+/// What `poll_events` needs before it may take the controller lock; fixed
+/// once the controller is initialized.
+static INFO: Once<XhciInfo> = Once::new();
+static EVENT_RING_PHYS: AtomicU64 = AtomicU64::new(0);
+/// Events copied out of the ring per dispatch.
+const EVENT_BATCH: usize = 16;
 
 #[allow(dead_code)]
 pub struct Xhci {
     cap: XhciInfo,
-    base: NonNull<u8>,
-}
+    regs: MmioRegion,
+}
+
+/// Size of the capability register block read by `inspect`.
+const CAP_REGS_LEN: usize = 0x20;
+const PORT_REGS_OFFSET: usize = 0x400;
+const PORT_REGS_STRIDE: usize = 0x10;
+const IRS_OFFSET: usize = 0x20;
+const IRS_STRIDE: usize = 0x20;
+/// USBLEGSUP and USBLEGCTLSTS.
+const LEGACY_REGS_LEN: usize = 8;
+/// Extended capabilities are only looked for in the first 64 KiB of the BAR,
+/// the smallest size controllers use.
+const XECP_SPAN: usize = 0x10000;
+const MAX_EXT_CAPS: usize = 64;
+/// How long the BIOS gets to release the controller (xHCI 4.22.1 names no
+/// limit; 1 s is what other drivers allow).
+const BIOS_HANDOFF_MS: u64 = 1000;
 
 #[allow(dead_code)]
 impl Xhci {
     pub unsafe fn new(info: XhciInfo) -> Option<Self> {
         let base = NonNull::new(info.base as *mut u8)?;
-        Some(Self { cap: info, base })
+        Some(Self { cap: info, regs: MmioRegion::new(base, info.mmio_len()) })
     }
 
     pub fn info(&self) -> &XhciInfo {
@@ -116,197 +367,164 @@ impl Xhci {
 
     pub fn operational(&self) -> OperationalRegs {
         let offset = self.cap.cap_length as usize;
-        unsafe { OperationalRegs::new(self.base, offset) }
+        OperationalRegs { regs: self.regs.window(offset, self.cap.operational_end() - offset) }
     }
 
     pub fn runtime(&self) -> RuntimeRegs {
         let offset = self.cap.rtsoff as usize;
-        unsafe { RuntimeRegs::new(self.base, offset) }
+        RuntimeRegs { regs: self.regs.window(offset, self.cap.runtime_end() - offset) }
     }
 
     pub fn doorbells(&self) -> DoorbellRegs {
         let offset = self.cap.dboff as usize;
-        unsafe { DoorbellRegs::new(self.base, offset) }
+        DoorbellRegs { regs: self.regs.window(offset, self.cap.doorbell_end() - offset) }
     }
 }
 
 #[allow(dead_code)]
 pub struct OperationalRegs {
-    base: NonNull<u8>,
+    regs: MmioRegion,
 }
 
 #[allow(dead_code)]
 impl OperationalRegs {
-    pub unsafe fn new(base: NonNull<u8>, offset: usize) -> Self {
-        let ptr = NonNull::new_unchecked(base.as_ptr().add(offset));
-        Self { base: ptr }
-    }
-
-    fn reg_ptr(&self, offset: usize) -> *mut u32 {
-        unsafe { self.base.as_ptr().add(offset) as *mut u32 }
-    }
-
     pub fn usbcmd(&self) -> UsbCmd {
-        UsbCmd::from_bits_truncate(unsafe { read_volatile(self.reg_ptr(0x00)) })
+        UsbCmd::from_bits_truncate(self.regs.read32(0x00))
     }
 
     pub fn set_usbcmd(&self, value: UsbCmd) {
-        unsafe { write_volatile(self.reg_ptr(0x00), value.bits()) };
+        self.regs.write32(0x00, value.bits());
     }
 
     pub fn usbsts(&self) -> UsbSts {
-        UsbSts::from_bits_truncate(unsafe { read_volatile(self.reg_ptr(0x04)) })
+        UsbSts::from_bits_truncate(self.regs.read32(0x04))
     }
 
     pub fn clear_usbsts(&self, value: UsbSts) {
-        unsafe { write_volatile(self.reg_ptr(0x04), value.bits()) };
+        self.regs.write32(0x04, value.bits());
     }
 
-    pub fn crcr(&self) -> u64 {
-        let low = unsafe { read_volatile(self.reg_ptr(0x18)) } as u64;
-        let high = unsafe { read_volatile(self.reg_ptr(0x1C)) } as u64;
-        (high << 32) | (low & !0xF)
+    pub fn crcr(&self) -> Crcr {
+        Crcr(self.regs.read64(0x18))
     }
 
-    pub fn set_crcr(&self, value: u64) {
-        unsafe {
-            write_volatile(self.reg_ptr(0x18), value as u32);
-            write_volatile(self.reg_ptr(0x1C), (value >> 32) as u32);
-        }
+    pub fn set_crcr(&self, value: Crcr) {
+        self.regs.write64(0x18, value.0);
     }
 
     pub fn dcbaap(&self) -> u64 {
-        let low = unsafe { read_volatile(self.reg_ptr(0x30)) } as u64;
-        let high = unsafe { read_volatile(self.reg_ptr(0x34)) } as u64;
-        (high << 32) | (low & !0x3F)
+        self.regs.read64(0x30) & !0x3F
     }
 
     pub fn set_dcbaap(&self, value: u64) {
-        unsafe {
-            write_volatile(self.reg_ptr(0x30), value as u32);
-            write_volatile(self.reg_ptr(0x34), (value >> 32) as u32);
-        }
+        self.regs.write64(0x30, value);
     }
 
     pub fn config(&self) -> u32 {
-        unsafe { read_volatile(self.reg_ptr(0x38)) }
+        self.regs.read32(0x38)
     }
 
     pub fn set_config(&self, value: u32) {
-        unsafe { write_volatile(self.reg_ptr(0x38), value) };
+        self.regs.write32(0x38, value);
     }
 
-    pub fn port(&self, index: usize) -> PortRegs {
-        let offset = 0x400 + index * 0x10;
-        unsafe { PortRegs::new(self.base, offset) }
+    /// Registers of port `index` (0-based); the region ends after the
+    /// last of MaxPorts, so a port the controller does not have is an error.
+    pub fn port(&self, index: usize) -> Result<PortRegs, MmioError> {
+        let offset = index.checked_mul(PORT_REGS_STRIDE).and_then(|o| o.checked_add(PORT_REGS_OFFSET)).ok_or(MmioError::OutOfBounds)?;
+        Ok(PortRegs { regs: self.regs.subregion(offset, PORT_REGS_STRIDE)? })
+    }
+
+    /// Read-modify-write PORTSC of `port`: `f` gets the current value with
+    /// every RW1C, RW1S and reserved bit cleared, and only what it sets on
+    /// top of that is written.
+    pub fn portsc_modify(&self, port: usize, f: impl FnOnce(Portsc) -> Portsc) {
+        let Ok(regs) = self.port(port) else { return };
+        regs.write_portsc(f(regs.portsc().for_write()));
+    }
+
+    /// Acknowledge the change bits in `bits` (e.g. `Portsc::PRC`) on `port`,
+    /// leaving the others pending.
+    pub fn clear_changes(&self, port: usize, bits: u32) {
+        self.portsc_modify(port, |sc| sc.with_ack(bits));
     }
 }
 
 #[allow(dead_code)]
 pub struct RuntimeRegs {
-    base: NonNull<u8>,
+    regs: MmioRegion,
 }
 
 #[allow(dead_code)]
 impl RuntimeRegs {
-    pub unsafe fn new(base: NonNull<u8>, offset: usize) -> Self {
-        let ptr = NonNull::new_unchecked(base.as_ptr().add(offset));
-        Self { base: ptr }
-    }
-
     pub fn interrupter_register_set(&self, index: usize) -> InterrupterRegs {
-        let stride = 32; // each IRS is 32 bytes
-        let offset = 0x20 + index * stride;
-        unsafe { InterrupterRegs::new(NonNull::new_unchecked(self.base.as_ptr().add(offset))) }
+        let offset = IRS_OFFSET + index * IRS_STRIDE;
+        InterrupterRegs { regs: self.regs.window(offset, IRS_STRIDE) }
     }
 }
 
 #[allow(dead_code)]
 pub struct InterrupterRegs {
-    base: NonNull<u8>,
+    regs: MmioRegion,
 }
 
 #[allow(dead_code)]
 impl InterrupterRegs {
-    unsafe fn new(base: NonNull<u8>) -> Self {
-        Self { base }
-    }
-
-    fn reg_ptr(&self, offset: usize) -> *mut u32 {
-        unsafe { self.base.as_ptr().add(offset) as *mut u32 }
-    }
-
-    pub fn iman(&self) -> u32 {
-        unsafe { read_volatile(self.reg_ptr(0x00)) }
+    pub fn iman(&self) -> Iman {
+        Iman(self.regs.read32(0x00))
     }
 
-    pub fn set_iman(&self, value: u32) {
-        unsafe { write_volatile(self.reg_ptr(0x00), value) };
+    pub fn set_iman(&self, value: Iman) {
+        self.regs.write32(0x00, value.0);
     }
 
     pub fn imod(&self) -> u32 {
-        unsafe { read_volatile(self.reg_ptr(0x04)) }
+        self.regs.read32(0x04)
     }
 
     pub fn set_imod(&self, value: u32) {
-        unsafe { write_volatile(self.reg_ptr(0x04), value) };
+        self.regs.write32(0x04, value);
     }
 
     pub fn erstsz(&self) -> u16 {
-        unsafe { read_volatile(self.reg_ptr(0x08)) as u16 }
+        self.regs.read32(0x08) as u16
     }
 
     pub fn set_erstsz(&self, value: u16) {
-        unsafe {
-            let current = read_volatile(self.reg_ptr(0x08));
-            let new = (current & !0xFFFF) | (value as u32);
-            write_volatile(self.reg_ptr(0x08), new);
+        if let Ok(reg) = self.regs.reg::<u32>(0x08) {
+            reg.update(|current| (current & !0xFFFF) | (value as u32));
         }
     }
 
     pub fn erstba(&self) -> u64 {
-        let low = unsafe { read_volatile(self.reg_ptr(0x10)) } as u64;
-        let high = unsafe { read_volatile(self.reg_ptr(0x14)) } as u64;
-        (high << 32) | (low & !0x3)
+        self.regs.read64(0x10) & !0x3
     }
 
     pub fn set_erstba(&self, value: u64) {
-        unsafe {
-            write_volatile(self.reg_ptr(0x10), value as u32);
-            write_volatile(self.reg_ptr(0x14), (value >> 32) as u32);
-        }
+        self.regs.write64(0x10, value);
     }
 
     pub fn erdp(&self) -> u64 {
-        let low = unsafe { read_volatile(self.reg_ptr(0x18)) } as u64;
-        let high = unsafe { read_volatile(self.reg_ptr(0x1C)) } as u64;
-        (high << 32) | low
+        self.regs.read64(0x18)
     }
 
     pub fn set_erdp(&self, value: u64) {
-        unsafe {
-            write_volatile(self.reg_ptr(0x18), value as u32);
-            write_volatile(self.reg_ptr(0x1C), (value >> 32) as u32);
-        }
+        self.regs.write64(0x18, value);
     }
 }
 
 #[allow(dead_code)]
 pub struct DoorbellRegs {
-    base: NonNull<u8>,
+    regs: MmioRegion,
 }
 
 #[allow(dead_code)]
 impl DoorbellRegs {
-    pub unsafe fn new(base: NonNull<u8>, offset: usize) -> Self {
-        let ptr = NonNull::new_unchecked(base.as_ptr().add(offset));
-        Self { base: ptr }
-    }
-
     pub fn ring(&self, index: usize, target: u32) {
-        compiler_fence(FenceOrdering::SeqCst);
-        let ptr = unsafe { self.base.as_ptr().add(index * 4) as *mut u32 };
-        unsafe { write_volatile(ptr, target) };
+        // TRBs queued before the doorbell must be visible when it is seen
+        dma_wmb();
+        ktrace::event(ktrace::Kind::XhciSubmit, index as u16, target);
+        self.regs.write32(index * 4, target);
     }
 }
 
@@ -319,6 +537,37 @@ pub struct Trb {
     pub control: u32,
 }
 
+/// Hand `trb` to the controller at `slot`: parameter and status must be
+/// visible before the control dword, whose cycle bit gives the TRB away.
+fn write_trb(slot: &mut Trb, trb: Trb) {
+    unsafe {
+        write_volatile(&mut slot.parameter, trb.parameter);
+        write_volatile(&mut slot.status, trb.status);
+        dma_wmb();
+        write_volatile(&mut slot.control, trb.control);
+    }
+}
+
+/// Producer side of a ring ending in a toggle-cycle link TRB: write `trb`
+/// with the producer cycle bit at `*enqueue`, and on wrap-around give the
+/// link TRB away under the same cycle before flipping it (xHCI 4.9.2).
+/// Returns the index written, or `None` when the ring has no usable TRB.
+fn ring_push(ring: &mut [Trb], enqueue: &mut usize, cycle: &mut bool, trb: Trb) -> Option<usize> {
+    let usable = ring.len().checked_sub(1).filter(|&n| n > 0)?;
+    let index = *enqueue % usable;
+    let control = TrbControl(trb.control).with_cycle(*cycle);
+    write_trb(&mut ring[index], Trb { control: control.0, ..trb });
+    *enqueue = (index + 1) % usable;
+    if *enqueue == 0 {
+        let link = &mut ring[usable];
+        let control = TrbControl(unsafe { read_volatile(&link.control) }).with_cycle(*cycle);
+        dma_wmb();
+        unsafe { write_volatile(&mut link.control, control.0) };
+        *cycle = !*cycle;
+    }
+    Some(index)
+}
+
 #[allow(dead_code)]
 pub struct CommandRing<'a> {
     pub trbs: &'a mut [Trb],
@@ -342,7 +591,7 @@ impl<'a> CommandRing<'a> {
 
     pub fn enqueue_slot(&mut self, trb: Trb) {
         let index = self.enqueue % self.trbs.len();
-        self.trbs[index] = trb;
+        write_trb(&mut self.trbs[index], trb);
         self.enqueue = (self.enqueue + 1) % self.trbs.len();
         if self.enqueue == 0 {
             self.cycle_state = !self.cycle_state;
@@ -371,18 +620,18 @@ pub unsafe fn inspect(base: u64) -> Option<XhciInfo> {
         return None;
     }
 
-    let ptr = NonNull::new(base as *mut u32)?;
-    let cap = read(ptr, 0);
+    let regs = MmioRegion::new(NonNull::new(base as *mut u8)?, CAP_REGS_LEN);
+    let cap = regs.read32(0x00);
     let cap_length = (cap & 0xFF) as u8;
     let hci_version = ((cap >> 16) & 0xFFFF) as u16;
-    let hcsparams1 = read(ptr, 1);
-    let hcsparams2 = read(ptr, 2);
-    let hcsparams3 = read(ptr, 3);
-    let hccparams1 = read(ptr, 4);
-    let dboff = read(ptr, 5);
-    let rtsoff = read(ptr, 6);
-
-    Some(XhciInfo {
+    let hcsparams1 = regs.read32(0x04);
+    let hcsparams2 = regs.read32(0x08);
+    let hcsparams3 = regs.read32(0x0C);
+    let hccparams1 = regs.read32(0x10);
+    let dboff = regs.read32(0x14) & !0x3;
+    let rtsoff = regs.read32(0x18) & !0x1F;
+
+    let mut info = XhciInfo {
         base,
         cap_length,
         hci_version,
@@ -392,29 +641,96 @@ pub unsafe fn inspect(base: u64) -> Option<XhciInfo> {
         hccparams1,
         dboff,
         rtsoff,
-    })
+        legacy_cap: 0,
+    };
+    let ext = MmioRegion::new(NonNull::new(base as *mut u8)?, XECP_SPAN);
+    let read = |off: usize| if off + 4 <= XECP_SPAN { ext.read32(off) } else { 0 };
+    info.legacy_cap = find_ext_cap(info.xecp(), ExtCap::USB_LEGACY_SUPPORT, read).unwrap_or(0);
+    Some(info)
+}
+
+/// Byte offset of the first extended capability with `id`, walking the list
+/// from `first` (the xECP offset). Bounded so a corrupt list cannot loop.
+fn find_ext_cap(first: usize, id: u8, read: impl Fn(usize) -> u32) -> Option<usize> {
+    let mut off = first;
+    for _ in 0..MAX_EXT_CAPS {
+        if off == 0 {
+            return None;
+        }
+        let cap = ExtCap(read(off));
+        if cap.id() == id {
+            return Some(off);
+        }
+        match cap.next_offset() {
+            0 => return None,
+            next => off += next,
+        }
+    }
+    None
 }
 
-unsafe fn read(ptr: NonNull<u32>, index: usize) -> u32 {
-    let addr = ptr.as_ptr().add(index);
-    read_volatile(addr)
+/// Take the controller from the BIOS (xHCI 4.22.1) and turn off the SMIs it
+/// may have left enabled; a BIOS still holding the controller can make the
+/// reset hang. `regs` covers USBLEGSUP and USBLEGCTLSTS.
+fn claim_from_bios(regs: &MmioRegion) {
+    let bios_owned = || UsbLegSup(regs.read32(0)).bios_owned();
+    let had_bios = bios_owned();
+    let Ok(os_owned) = regs.reg::<u8>(UsbLegSup::OS_OWNED_BYTE) else { return };
+    os_owned.write(1);
+    if had_bios {
+        let deadline = executor::deadline_after_ms(BIOS_HANDOFF_MS);
+        while bios_owned() && !executor::expired(deadline) {
+            spin_loop();
+        }
+        if bios_owned() {
+            klog::log(Level::Warn, format_args!("[xhci] BIOS did not release the controller, taking it\r\n"));
+            if let Ok(bios_owned) = regs.reg::<u8>(UsbLegSup::BIOS_OWNED_BYTE) {
+                bios_owned.write(0);
+            }
+        } else if UsbLegSup(regs.read32(0)).os_owned() {
+            klog::log(Level::Info, format_args!("[xhci] BIOS handoff done\r\n"));
+        }
+    }
+    let ctl = UsbLegCtlSts(regs.read32(4));
+    if ctl.smi_enables() != 0 {
+        klog::log(Level::Debug, format_args!("[xhci] disabling legacy SMIs {:#x}\r\n", ctl.smi_enables()));
+    }
+    regs.write32(4, ctl.without_smis().0);
 }
 
 const CMD_RING_TRBS: usize = 256;
+/// ERDP Event Handler Busy, RW1C: cleared when software advances the dequeue pointer.
+const ERDP_EHB: u64 = 1 << 3;
 const EVENT_RING_TRBS: usize = 256;
 
-const TRB_TYPE_LINK: u32 = 6;
+pub(crate) const TRB_TYPE_LINK: u8 = 6;
 const TRB_TYPE_COMMAND_COMPLETION: u8 = 0x21;
 const TRB_TYPE_TRANSFER_EVENT: u8 = 0x20;
 const TRB_TYPE_PORT_STATUS_CHANGE: u8 = 0x22;
-const TRB_TYPE_NO_OP_COMMAND: u32 = 23;
-const TRB_TYPE_NORMAL: u32 = 1;
-const TRB_TYPE_CONFIGURE_ENDPOINT: u32 = 12;
-const TRB_TYPE_ENABLE_SLOT: u32 = 9;
-const TRB_TYPE_ADDRESS_DEVICE: u32 = 11;
-const TRB_TYPE_SETUP_STAGE: u32 = 2;
-const TRB_TYPE_DATA_STAGE: u32 = 3;
-const TRB_TYPE_STATUS_STAGE: u32 = 4;
+const TRB_TYPE_NO_OP_COMMAND: u8 = 23;
+const TRB_TYPE_NORMAL: u8 = 1;
+const TRB_TYPE_CONFIGURE_ENDPOINT: u8 = 12;
+const TRB_TYPE_STOP_ENDPOINT: u8 = 15;
+const TRB_TYPE_ENABLE_SLOT: u8 = 9;
+const TRB_TYPE_ADDRESS_DEVICE: u8 = 11;
+const TRB_TYPE_SETUP_STAGE: u8 = 2;
+const TRB_TYPE_DATA_STAGE: u8 = 3;
+const TRB_TYPE_STATUS_STAGE: u8 = 4;
+/// Setup stage Transfer Type values.
+const TRT_NO_DATA: u8 = 0;
+const TRT_IN_DATA: u8 = 3;
+
+/// IMODI counts in 250 ns units (xHCI 5.5.2.2).
+const IMOD_UNIT_NS: u32 = 250;
+/// 1 ms between interrupts: plenty for HID, bounds the rate for bulk devices.
+const DEFAULT_IMOD_US: u32 = 1000;
+
+/// IMOD register value for a minimum inter-interrupt interval of `us`
+/// microseconds, clamped to the 16-bit IMODI field. 0 disables moderation.
+fn imod_interval(us: u32) -> u32 {
+    let units = us.saturating_mul(1000) / IMOD_UNIT_NS;
+    units.min(0xFFFF)
+}
 
 #[repr(C, align(16))]
 #[derive(Clone, Copy, Debug, Default)]
@@ -424,33 +740,63 @@ struct ErstEntry {
     reserved: u32,
 }
 
-pub unsafe fn init_controller(info: XhciInfo) -> Result<(), &'static str> {
-    let controller = Xhci::new(info).ok_or("xhci: null base")?;
-    let op = controller.operational();
-
-    // Stop the controller if it is already running
+/// Stop the controller if it is running, then reset it (xHCI 4.2).
+fn halt_and_reset(op: &OperationalRegs) -> Result<(), &'static str> {
     let mut cmd = op.usbcmd();
     if cmd.contains(UsbCmd::RUN_STOP) {
         cmd.remove(UsbCmd::RUN_STOP);
         op.set_usbcmd(cmd);
-        if !wait_for(|| op.usbsts().contains(UsbSts::HOST_CONTROLLER_HALTED)) {
-            return Err("xhci: halt timeout");
-        }
+        kensure!(wait_for(|| op.usbsts().contains(UsbSts::HOST_CONTROLLER_HALTED)), "xhci: halt timeout");
     }
-
-    // Reset host controller
     cmd = op.usbcmd();
     cmd.insert(UsbCmd::HOST_CONTROLLER_RESET);
     op.set_usbcmd(cmd);
-    if !wait_for(|| !op.usbcmd().contains(UsbCmd::HOST_CONTROLLER_RESET)) {
-        return Err("xhci: reset bit stuck");
-    }
-    if !wait_for(|| op.usbsts().contains(UsbSts::HOST_CONTROLLER_HALTED)) {
-        return Err("xhci: reset halt timeout");
+    kensure!(wait_for(|| !op.usbcmd().contains(UsbCmd::HOST_CONTROLLER_RESET)), "xhci: reset bit stuck");
+    kensure!(wait_for(|| op.usbsts().contains(UsbSts::HOST_CONTROLLER_HALTED)), "xhci: reset halt timeout");
+    Ok(())
+}
+
+/// Physical addresses of the controller-wide structures.
+struct Rings {
+    command: u64,
+    dcbaa: u64,
+    event: u64,
+    erst: u64,
+}
+
+/// Point a freshly reset controller at its rings, set up interrupter 0 with
+/// IMOD register value `imod`, and run it.
+fn start(controller: &Xhci, rings: &Rings, imod: u32) -> Result<(), &'static str> {
+    let op = controller.operational();
+    op.set_dcbaap(dma(rings.dcbaa));
+    op.set_crcr(Crcr::new(dma(rings.command), true));
+    op.set_config((controller.info().max_slots() as u32) & 0xFF);
+
+    let ir0 = controller.runtime().interrupter_register_set(0);
+    ir0.set_erstsz(1);
+    ir0.set_erstba(dma(rings.erst));
+    ir0.set_erdp(dma(rings.event));
+    ir0.set_iman(ir0.iman().with_enable(true));
+    ir0.set_imod(imod);
+
+    op.clear_usbsts(
+        UsbSts::EVENT_INTERRUPT | UsbSts::PORT_CHANGE_DETECT | UsbSts::HOST_SYSTEM_ERROR,
+    );
+    op.set_usbcmd(op.usbcmd() | UsbCmd::RUN_STOP | UsbCmd::INTERRUPTER_ENABLE);
+    kensure!(wait_for(|| !op.usbsts().contains(UsbSts::HOST_CONTROLLER_HALTED)), "xhci: run timeout");
+    Ok(())
+}
+
+pub unsafe fn init_controller(info: XhciInfo) -> Result<(), &'static str> {
+    let controller = Xhci::new(info).ok_or("xhci: null base")?;
+    if info.legacy_cap != 0 {
+        claim_from_bios(&controller.regs.window(info.legacy_cap, LEGACY_REGS_LEN));
     }
+    let op = controller.operational();
+    halt_and_reset(&op)?;
 
     // Allocate command ring
-    let cmd_ring_phys = pmm::alloc_aligned((CMD_RING_TRBS * size_of::<Trb>()) as u64, 64)
+    let cmd_ring_phys = pmm::alloc_for("xhci.cmd_ring", (CMD_RING_TRBS * size_of::<Trb>()) as u64, 64)
         .ok_or("xhci: no memory for command ring")?;
     let cmd_ring = unsafe { phys_to_slice_mut::<Trb>(cmd_ring_phys, CMD_RING_TRBS) };
     zero_trbs(cmd_ring);
@@ -459,85 +805,36 @@ pub unsafe fn init_controller(info: XhciInfo) -> Result<(), &'static str> {
     // Allocate DCBAA (slot count + 1 entries)
     let slots = controller.info().max_slots() as usize + 1;
     let dcbaa_size = (slots * size_of::<u64>()) as u64;
-    let dcbaa_phys = pmm::alloc_aligned(dcbaa_size, 64).ok_or("xhci: no dcbaa")?;
+    let dcbaa_phys = pmm::alloc_for("xhci.dcbaa", dcbaa_size, 64).ok_or("xhci: no dcbaa")?;
     zero_phys(dcbaa_phys, dcbaa_size as usize);
 
     // Allocate event ring and ERST
-    let event_ring_phys = pmm::alloc_aligned((EVENT_RING_TRBS * size_of::<Trb>()) as u64, 64)
+    let event_ring_phys = pmm::alloc_for("xhci.event_ring", (EVENT_RING_TRBS * size_of::<Trb>()) as u64, 64)
         .ok_or("xhci: no event ring")?;
     let event_ring = unsafe { phys_to_slice_mut::<Trb>(event_ring_phys, EVENT_RING_TRBS) };
     zero_trbs(event_ring);
 
-    let erst_phys = pmm::alloc_aligned(size_of::<ErstEntry>() as u64, 64).ok_or("xhci: no erst")?;
+    let erst_phys = pmm::alloc_for("xhci.erst", size_of::<ErstEntry>() as u64, 64).ok_or("xhci: no erst")?;
     let erst = unsafe { phys_to_slice_mut::<ErstEntry>(erst_phys, 1) };
     zero_erst(erst);
-    erst[0].segment_base = event_ring_phys;
+    erst[0].segment_base = dma(event_ring_phys);
     erst[0].segment_size = EVENT_RING_TRBS as u32;
 
-    op.set_dcbaap(dcbaa_phys);
-    op.set_crcr((cmd_ring_phys & !0xF) | 1); // RCS = 1
-    op.set_config((controller.info().max_slots() as u32) & 0xFF);
-
-    // Set up interrupter 0
-    let runtime = controller.runtime();
-    let ir0 = runtime.interrupter_register_set(0);
-    ir0.set_erstsz(1);
-    ir0.set_erstba(erst_phys);
-    ir0.set_erdp(event_ring_phys);
-    ir0.set_iman(ir0.iman() | 1); // enable interrupts
-    ir0.set_imod(0);
-
-    // Clear status flags
-    op.clear_usbsts(
-        UsbSts::EVENT_INTERRUPT | UsbSts::PORT_CHANGE_DETECT | UsbSts::HOST_SYSTEM_ERROR,
-    );
-
-    // Start controller
-    cmd = op.usbcmd();
-    cmd.insert(UsbCmd::RUN_STOP | UsbCmd::INTERRUPTER_ENABLE);
-    op.set_usbcmd(cmd);
-    if !wait_for(|| !op.usbsts().contains(UsbSts::HOST_CONTROLLER_HALTED)) {
-        return Err("xhci: run timeout");
-    }
-
-    CONTROLLER_STATE.call_once(|| {
-        Mutex::new(ControllerState {
-            info,
-            command_ring_phys: cmd_ring_phys,
-            command_ring_len: CMD_RING_TRBS,
-            command_ring_enqueue: 0,
-            command_ring_cycle: true,
-            event_ring_phys,
-            event_ring_len: EVENT_RING_TRBS,
-            event_ring_dequeue: 0,
-            event_ring_cycle: true,
-            dcbaa_phys,
-            erst_phys,
-            last_completion_code: None,
-            last_completed_slot: None,
-            last_transfer_code: None,
-            last_transfer_len: None,
-            last_transfer_ep: None,
-            last_transfer_slot: None,
-            active_slot: None,
-            ep0_ring_phys: 0,
-            ep0_ring_len: 0,
-            ep0_enqueue: 0,
-            ep0_cycle: true,
-            intr_ep_addr: 0,
-            intr_ep_id: 0,
-            intr_ring_phys: 0,
-            intr_ring_len: 0,
-            intr_enqueue: 0,
-            intr_cycle: true,
-            hid_buf_phys: 0,
-            hid_buf_len: 0,
-        })
-    });
-
-    serial::write_fmt(format_args!(
-        "[xhci] runtime ready cr={:#x} erst={:#x} erdp={:#x}\r\n",
-        op.crcr(),
+    let imod_us = config::get_u64("xhci.imod_us").unwrap_or(DEFAULT_IMOD_US as u64);
+    let rings = Rings { command: cmd_ring_phys, dcbaa: dcbaa_phys, event: event_ring_phys, erst: erst_phys };
+    start(&controller, &rings, imod_interval(imod_us.min(u32::MAX as u64) as u32))?;
+    let ir0 = controller.runtime().interrupter_register_set(0);
+
+    let hid_pace_ms = config::get_u64("hid.pace_ms").unwrap_or(0).min(u32::MAX as u64) as u32;
+    EVENTS.reset();
+    EVENT_RING_PHYS.store(event_ring_phys, Ordering::Release);
+    INFO.call_once(|| info);
+    CONTROLLER_STATE.call_once(|| Mutex::new(ControllerState::new(info, &rings, hid_pace_ms)));
+
+    klog::log(Level::Debug, format_args!(
+        "[xhci] runtime ready crr={} ie={} erst={:#x} erdp={:#x}\r\n",
+        op.crcr().running() as u8,
+        ir0.iman().enabled() as u8,
         erst_phys,
         event_ring_phys
     ));
@@ -546,18 +843,122 @@ pub unsafe fn init_controller(info: XhciInfo) -> Result<(), &'static str> {
     ring_doorbell(0, 0);
 
     match wait_for_command_completion(1_000_000) {
-        Some((code, slot)) => serial::write_fmt(format_args!(
+        Some((code, slot)) => klog::log(Level::Debug, format_args!(
             "[xhci] command completed code={:#x} slot={}\r\n",
             code, slot
         )),
-        None => serial::write_str("[xhci] command timeout\r\n"),
+        None => klog::log(Level::Warn, format_args!("[xhci] command timeout\r\n")),
     }
 
-    serial::write_fmt(format_args!("[xhci] usbsts={:#x}\r\n", op.usbsts().bits()));
+    klog::log(Level::Debug, format_args!("[xhci] usbsts={:#x}\r\n", op.usbsts().bits()));
 
     Ok(())
 }
 
+/// Control in the Endpoint Context EP Type field; the other types are the
+/// descriptor's transfer type, plus 4 for IN endpoints.
+const EP_TYPE_CONTROL: u32 = 4;
+
+/// Endpoint Context fields (xHCI 6.2.3) other than the dequeue pointer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct EndpointContext {
+    ep_type: u32,
+    max_packet: u32,
+    max_burst: u32,
+    /// CErr: retries before a transaction error halts the endpoint; must be
+    /// 0 for isochronous endpoints.
+    error_count: u32,
+    /// Exponent from `interrupt_interval_exponent`, periodic endpoints only.
+    interval: u32,
+    max_esit_payload: u32,
+    average_trb_length: u32,
+}
+
+impl EndpointContext {
+    fn control(max_packet: u32) -> Self {
+        // Average TRB Length 8 as recommended for control endpoints (4.14.1.1)
+        EndpointContext { ep_type: EP_TYPE_CONTROL, max_packet, error_count: 3, average_trb_length: 8, ..Default::default() }
+    }
+
+    fn from_descriptor(desc: &EndpointDescriptor, interval: u8) -> Self {
+        let transfer = desc.transfer_type();
+        let max_packet = desc.packet_size() as u32;
+        if transfer == TRANSFER_CONTROL {
+            return Self::control(max_packet);
+        }
+        let periodic = transfer == TRANSFER_INTERRUPT || transfer == TRANSFER_ISOCHRONOUS;
+        // High-speed periodic endpoints give their burst in wMaxPacketSize
+        // 12:11; SuperSpeed bursts live in the companion descriptor, which we
+        // do not read, and those bits are zero there.
+        let max_burst = if periodic { desc.additional_transactions() as u32 } else { 0 };
+        EndpointContext {
+            ep_type: transfer as u32 + if desc.is_in() { 4 } else { 0 },
+            max_packet,
+            max_burst,
+            error_count: if transfer == TRANSFER_ISOCHRONOUS { 0 } else { 3 },
+            interval: if periodic { interval as u32 } else { 0 },
+            max_esit_payload: if periodic { max_packet * (max_burst + 1) } else { 0 },
+            // Suggested starting values (4.14.1.1)
+            average_trb_length: match transfer {
+                TRANSFER_BULK | TRANSFER_ISOCHRONOUS => 3072,
+                _ => 1024,
+            },
+        }
+    }
+
+    /// DW0, DW1 and DW4.
+    fn dwords(&self) -> [u32; 3] {
+        [
+            // Max ESIT Payload Hi 31:24, Interval 23:16
+            ((self.max_esit_payload >> 16) << 24) | (self.interval << 16),
+            // Max Packet Size 31:16, Max Burst Size 15:8, EP Type 5:3, CErr 2:1
+            (self.max_packet << 16) | (self.max_burst << 8) | (self.ep_type << 3) | (self.error_count << 1),
+            // Max ESIT Payload Lo 31:16, Average TRB Length 15:0
+            ((self.max_esit_payload & 0xFFFF) << 16) | self.average_trb_length,
+        ]
+    }
+}
+
+/// Input context layout (xHCI 6.2.5): Input Control Context at index 0,
+/// Slot Context at 1, then the endpoint context for DCI n at 1 + n.
+struct InputContext {
+    regs: MmioRegion,
+    ctx_size: usize,
+}
+
+impl InputContext {
+    /// # Safety
+    /// `phys` must point at `entries * ctx_size` bytes owned by the caller.
+    unsafe fn new(phys: u64, ctx_size: usize, entries: usize) -> Self {
+        let base = NonNull::new_unchecked(phys_to_mut_ptr(phys));
+        InputContext { regs: MmioRegion::new(base, ctx_size * entries), ctx_size }
+    }
+
+    fn set_add_flags(&self, flags: u32) {
+        // Input Control Context DW1
+        self.regs.write32(0x04, flags);
+    }
+
+    /// Slot Context DW0 (Context Entries 31:27, Speed 23:20, route string 0)
+    /// and DW1 (Root Hub Port Number 23:16).
+    fn write_slot(&self, context_entries: u8, speed: u8, root_port: u8) {
+        let slot = self.regs.window(self.ctx_size, self.ctx_size);
+        slot.write32(0x00, ((context_entries as u32) << 27) | (((speed & 0xF) as u32) << 20));
+        slot.write32(0x04, (root_port as u32) << 16);
+    }
+
+    /// Endpoint context for DCI `dci`, with its TR Dequeue Pointer at
+    /// `ring_phys` and DCS = 1 to match a fresh ring's cycle.
+    fn write_endpoint(&self, dci: u8, ctx: &EndpointContext, ring_phys: u64) {
+        let ep = self.regs.window(self.ctx_size * (1 + dci as usize), self.ctx_size);
+        let [dw0, dw1, dw4] = ctx.dwords();
+        ep.write32(0x00, dw0);
+        ep.write32(0x04, dw1);
+        ep.write64(0x08, (dma(ring_phys) & !0xF) | 1);
+        ep.write32(0x10, dw4);
+    }
+}
+
 fn zero_trbs(trbs: &mut [Trb]) {
     for trb in trbs.iter_mut() {
         *trb = Trb::default();
@@ -566,27 +967,107 @@ fn zero_trbs(trbs: &mut [Trb]) {
 
 fn init_link_trb(trbs: &mut [Trb], base_phys: u64, toggle: bool) {
     if let Some(link) = trbs.last_mut() {
-        link.parameter = base_phys;
-        let mut control = (TRB_TYPE_LINK & 0x3F) << 10;
-        control |= 1; // cycle bit
+        link.parameter = dma(base_phys);
+        let mut control = TrbControl::new(TRB_TYPE_LINK).with_cycle(true);
         if toggle {
-            control |= 1 << 1;
+            control = control.with_toggle_cycle();
+        }
+        link.control = control.0;
+    }
+}
+
+/// Rings a violation was already dumped for, one bit each (`xhci_debug`).
+#[cfg(feature = "xhci_debug")]
+static RINGS_DUMPED: AtomicU8 = AtomicU8::new(0);
+
+/// Check the producer rings after an enqueue or a batch of completions
+/// (`xhci_debug` builds; nothing otherwise).
+#[cfg(feature = "xhci_debug")]
+fn check_rings(state: &ControllerState) {
+    let rings = [
+        ("command", state.command_ring_phys, state.command_ring_len, state.command_ring_enqueue, state.command_ring_cycle, Some(state.commands_pending)),
+        ("ep0", state.ep0_ring_phys, state.ep0_ring_len, state.ep0_enqueue, state.ep0_cycle, None),
+        ("intr", state.intr_ring_phys, state.intr_ring_len, state.intr_enqueue, state.intr_cycle, None),
+    ];
+    for (bit, (name, phys, len, enqueue, cycle, pending)) in rings.into_iter().enumerate() {
+        if phys == 0 || len == 0 {
+            continue;
+        }
+        let ring = unsafe { phys_to_slice::<Trb>(phys, len) };
+        if let Err(v) = xhci_check::producer(ring, dma(phys), enqueue, cycle, pending) {
+            ring_violation(bit, name, ring, enqueue, cycle, v);
         }
-        link.control = control;
     }
 }
 
+#[cfg(not(feature = "xhci_debug"))]
+fn check_rings(_state: &ControllerState) {}
+
+/// Check the event ring behind the consumer (`xhci_debug` builds).
+#[cfg(feature = "xhci_debug")]
+fn check_event_ring(ring: &[Trb], cursor: Cursor) {
+    if let Err(v) = xhci_check::consumer(ring, cursor) {
+        ring_violation(3, "event", ring, cursor.index, cursor.cycle, v);
+    }
+}
+
+#[cfg(not(feature = "xhci_debug"))]
+fn check_event_ring(_ring: &[Trb], _cursor: Cursor) {}
+
+/// Log a broken invariant and, the first time for this ring, every TRB in
+/// it that is not zero. Later violations of the same ring are only logged.
+#[cfg(feature = "xhci_debug")]
+fn ring_violation(bit: usize, name: &str, ring: &[Trb], index: usize, cycle: bool, v: xhci_check::Violation) {
+    klog::log(Level::Error, format_args!(
+        "[xhci] {} ring invariant: {} (index={} cycle={} trb={:?})\r\n",
+        name,
+        v.as_str(),
+        index,
+        cycle as u8,
+        v.index()
+    ));
+    if RINGS_DUMPED.fetch_or(1 << bit, Ordering::Relaxed) & (1 << bit) != 0 {
+        return;
+    }
+    for (i, trb) in ring.iter().enumerate() {
+        let trb = unsafe { read_volatile(trb) };
+        if trb.parameter == 0 && trb.status == 0 && trb.control == 0 {
+            continue;
+        }
+        let control = TrbControl(trb.control);
+        klog::log(Level::Error, format_args!(
+            "[xhci]   {}[{}] type={} c={} param={:#x} status={:#x} control={:#x}\r\n",
+            name,
+            i,
+            control.trb_type(),
+            control.cycle() as u8,
+            trb.parameter,
+            trb.status,
+            trb.control
+        ));
+    }
+}
+
+unsafe fn phys_to_slice<T>(phys: u64, entries: usize) -> &'static [T] {
+    slice::from_raw_parts(addr::PhysAddr::new(phys).as_mut_ptr::<T>(), entries)
+}
+
 unsafe fn phys_to_slice_mut<T>(phys: u64, entries: usize) -> &'static mut [T] {
-    slice::from_raw_parts_mut(phys as *mut T, entries)
+    slice::from_raw_parts_mut(addr::PhysAddr::new(phys).as_mut_ptr::<T>(), entries)
 }
 
 unsafe fn phys_to_mut_ptr(phys: u64) -> *mut u8 {
-    phys as *mut u8
+    addr::PhysAddr::new(phys).as_mut_ptr::<u8>()
+}
+
+/// Bus address to program into the controller for a pmm buffer.
+fn dma(phys: u64) -> u64 {
+    addr::PhysAddr::new(phys).to_dma().as_u64()
 }
 
 fn zero_phys(phys: u64, size: usize) {
     unsafe {
-        core::ptr::write_bytes(phys_to_mut_ptr(phys), 0, size);
+        fastmem::fill(phys_to_mut_ptr(phys), 0, size);
     }
 }
 
@@ -606,20 +1087,17 @@ pub fn report_ports() {
             if let Some(controller) = Xhci::new(info) {
                 let op = controller.operational();
                 for port in 0..info.max_ports() {
-                    let regs = op.port(port as usize);
+                    let Ok(regs) = op.port(port as usize) else { continue };
                     let sc = regs.portsc();
-                    let ccs = (sc & 0x1) != 0;
-                    let ped = (sc & 0x2) != 0;
-                    let speed = (sc >> 10) & 0xF;
-                    let pls = (sc >> 5) & 0xF;
-                    serial::write_fmt(format_args!(
-                        "[xhci] port{} sc={:#010x} ccs={} ped={} speed={} pls={}\r\n",
+                    klog::log(Level::Info, format_args!(
+                        "[xhci] port{} sc={:#010x} pp={} ccs={} ped={} speed={} pls={}\r\n",
                         port + 1,
-                        sc,
-                        ccs as u8,
-                        ped as u8,
-                        speed,
-                        pls
+                        sc.0,
+                        sc.powered() as u8,
+                        sc.connected() as u8,
+                        sc.enabled() as u8,
+                        sc.speed(),
+                        sc.link_state()
                     ));
                 }
             }
@@ -627,44 +1105,140 @@ pub fn report_ports() {
     }
 }
 
-pub fn poll_events() -> bool {
-    if let Some(state_lock) = CONTROLLER_STATE.get() {
-        let mut state = state_lock.lock();
-        unsafe {
-            if let Some(controller) = Xhci::new(state.info) {
-                let runtime = controller.runtime();
-                let ir0 = runtime.interrupter_register_set(0);
-                let ring = phys_to_slice_mut::<Trb>(state.event_ring_phys, state.event_ring_len);
-                let trb_size = size_of::<Trb>() as u64;
-                let mut processed = false;
-
-                loop {
-                    let index = state.event_ring_dequeue;
-                    let trb = read_volatile(&ring[index]);
-                    let cycle = (trb.control & 1) != 0;
-                    if cycle != state.event_ring_cycle {
-                        break;
-                    }
+/// Format the controller registers and the driver's ring positions as one
+/// block for bug reports, a line per call to `out`: capability registers,
+/// USBCMD/USBSTS/CRCR/DCBAAP/CONFIG, interrupter 0, every PORTSC, then
+/// each ring's base, enqueue or dequeue index and cycle state.
+pub fn write_regs(mut out: impl FnMut(fmt::Arguments)) -> Result<(), &'static str> {
+    let state_lock = CONTROLLER_STATE.get().ok_or("xhci: not initialized")?;
+    // Copied out so no output is written under the controller lock
+    let (info, rings, pending, slot, suspended) = {
+        let st = state_lock.lock();
+        let rings = [
+            ("command", st.command_ring_phys, st.command_ring_len, st.command_ring_enqueue, st.command_ring_cycle),
+            ("ep0", st.ep0_ring_phys, st.ep0_ring_len, st.ep0_enqueue, st.ep0_cycle),
+            ("intr", st.intr_ring_phys, st.intr_ring_len, st.intr_enqueue, st.intr_cycle),
+        ];
+        (st.info, rings, st.commands_pending, st.active_slot, st.suspended.is_some())
+    };
+    let controller = unsafe { Xhci::new(info) }.ok_or("xhci: null base")?;
+    let cap = controller.regs.window(0, CAP_REGS_LEN);
+    let op = controller.operational();
+    let ir0 = controller.runtime().interrupter_register_set(0);
+
+    out(format_args!("--- xhci registers (base {:#x}) ---\n", info.base));
+    out(format_args!(
+        "cap caplength={:#04x} hciversion={:#06x} hcsparams1={:#010x} hcsparams2={:#010x} hcsparams3={:#010x}\n",
+        cap.read32(0x00) & 0xFF,
+        cap.read32(0x00) >> 16,
+        cap.read32(0x04),
+        cap.read32(0x08),
+        cap.read32(0x0C)
+    ));
+    out(format_args!(
+        "cap hccparams1={:#010x} dboff={:#010x} rtsoff={:#010x} hccparams2={:#010x}\n",
+        cap.read32(0x10),
+        cap.read32(0x14),
+        cap.read32(0x18),
+        cap.read32(0x1C)
+    ));
+    out(format_args!(
+        "op usbcmd={:#010x} usbsts={:#010x} pagesize={:#010x} dnctrl={:#010x}\n",
+        op.regs.read32(0x00),
+        op.regs.read32(0x04),
+        op.regs.read32(0x08),
+        op.regs.read32(0x14)
+    ));
+    out(format_args!("op crcr={:#018x} dcbaap={:#018x} config={:#010x}\n", op.crcr().0, op.dcbaap(), op.config()));
+    out(format_args!(
+        "ir0 iman={:#010x} imod={:#010x} erstsz={} erstba={:#018x} erdp={:#018x}\n",
+        ir0.iman().0,
+        ir0.imod(),
+        ir0.erstsz(),
+        ir0.erstba(),
+        ir0.erdp()
+    ));
+    for port in 0..info.max_ports() as usize {
+        let Ok(regs) = op.port(port) else { continue };
+        let sc = regs.portsc();
+        out(format_args!(
+            "port{} portsc={:#010x} ccs={} ped={} pp={} speed={} pls={}\n",
+            port + 1,
+            sc.0,
+            sc.connected() as u8,
+            sc.enabled() as u8,
+            sc.powered() as u8,
+            sc.speed(),
+            sc.link_state()
+        ));
+    }
+    for (name, phys, len, enqueue, cycle) in rings {
+        out(format_args!("ring {} phys={:#x} len={} enqueue={} cycle={}\n", name, phys, len, enqueue, cycle as u8));
+    }
+    let events = EVENTS.cursor();
+    out(format_args!(
+        "ring event phys={:#x} len={} dequeue={} cycle={}\n",
+        EVENT_RING_PHYS.load(Ordering::Acquire),
+        EVENT_RING_TRBS,
+        events.index,
+        events.cycle as u8
+    ));
+    out(format_args!(
+        "driver commands_pending={} slot={} suspended={} fault={}\n",
+        pending,
+        slot.unwrap_or(0),
+        suspended as u8,
+        FAULT.load(Ordering::Acquire)
+    ));
+    out(format_args!("--- end ---\n"));
+    Ok(())
+}
 
-                    let trb_type = ((trb.control >> 10) & 0x3F) as u8;
-                    handle_event(&mut state, trb_type, &trb);
+/// Drain the event ring: copy out what the controller wrote (no lock),
+/// then dispatch it under the controller lock, a batch at a time. A caller
+/// arriving while another drains (a wait loop inside dispatch, an IRQ)
+/// returns false straight away; see `xhci_events`.
+pub fn poll_events() -> bool {
+    let Some(state_lock) = CONTROLLER_STATE.get() else { return false };
+    let Some(info) = INFO.get().copied() else { return false };
+    let Some(controller) = (unsafe { Xhci::new(info) }) else { return false };
+    let ring_phys = EVENT_RING_PHYS.load(Ordering::Acquire);
+    EVENTS
+        .exclusive(|cursor| {
+            let ring = unsafe { phys_to_slice::<Trb>(ring_phys, EVENT_RING_TRBS) };
+            let ir0 = controller.runtime().interrupter_register_set(0);
+            let trb_size = size_of::<Trb>() as u64;
+            let mut processed = false;
+            loop {
+                let mut batch = [Trb::default(); EVENT_BATCH];
+                let n = cursor.drain(ring, &mut batch);
+                let mut state = state_lock.lock();
+                for trb in &batch[..n] {
+                    handle_event(&mut state, TrbControl(trb.control).trb_type(), trb);
+                }
+                if n > 0 {
                     processed = true;
-
-                    state.event_ring_dequeue =
-                        (state.event_ring_dequeue + 1) % state.event_ring_len;
-                    if state.event_ring_dequeue == 0 {
-                        state.event_ring_cycle = !state.event_ring_cycle;
-                    }
-
-                    let new_erdp =
-                        state.event_ring_phys + state.event_ring_dequeue as u64 * trb_size;
-                    ir0.set_erdp(new_erdp | (1 << 3));
+                    ir0.set_erdp(dma(ring_phys + cursor.index as u64 * trb_size) | ERDP_EHB);
+                    check_event_ring(ring, *cursor);
+                    check_rings(&state);
+                }
+                if n == EVENT_BATCH {
+                    continue;
+                }
+                let iman = ir0.iman();
+                if iman.pending() {
+                    ir0.set_iman(iman.with_ack());
+                }
+                if controller.operational().usbsts().intersects(UsbSts::HOST_SYSTEM_ERROR | UsbSts::HOST_CONTROLLER_ERROR) {
+                    fault(Fault::HostError);
+                }
+                if state.hid_repost_pending && state.suspended.is_none() && pace_elapsed(&state) {
+                    repost_hid_transfer(&mut state);
                 }
                 return processed;
             }
-        }
-    }
-    false
+        })
+        .unwrap_or(false)
 }
 
 pub fn wait_for_command_completion(iterations: usize) -> Option<(u8, u8)> {
@@ -686,101 +1260,125 @@ pub fn wait_for_command_completion(iterations: usize) -> Option<(u8, u8)> {
     None
 }
 
-fn enqueue_noop_command() {
-    if let Some(state_lock) = CONTROLLER_STATE.get() {
-        let mut state = state_lock.lock();
-        let usable = state.command_ring_len.saturating_sub(1);
-        if usable == 0 {
-            serial::write_str("[xhci] command ring unusable\r\n");
-            return;
-        }
+/// Signalled by `handle_event`; async waiters park on these instead of spinning.
+static COMMAND_EVENT: Event = Event::new();
+static TRANSFER_EVENT: Event = Event::new();
+/// How long an async command or control transfer may take before it fails.
+const COMPLETION_TIMEOUT_MS: u64 = 1000;
 
-        let index = state.command_ring_enqueue % usable;
-        let trbs =
-            unsafe { phys_to_slice_mut::<Trb>(state.command_ring_phys, state.command_ring_len) };
-        let cycle_bit = if state.command_ring_cycle { 1 } else { 0 };
-        trbs[index] = Trb {
-            parameter: 0,
-            status: 0,
-            control: ((TRB_TYPE_NO_OP_COMMAND & 0x3F) << 10) | (1 << 5) | cycle_bit,
-        };
-        serial::write_fmt(format_args!(
-            "[xhci] queued noop index={} cycle={}\r\n",
-            index, cycle_bit
-        ));
-        compiler_fence(FenceOrdering::SeqCst);
+fn take_command_completion() -> Option<(u8, u8)> {
+    let mut state = CONTROLLER_STATE.get()?.lock();
+    let code = state.last_completion_code.take()?;
+    Some((code, state.last_completed_slot.take().unwrap_or(0)))
+}
+
+fn take_transfer_completion() -> Option<(u8, u32)> {
+    let mut state = CONTROLLER_STATE.get()?.lock();
+    let code = state.last_transfer_code.take()?;
+    Some((code, state.last_transfer_len.take().unwrap_or(0)))
+}
 
-        state.command_ring_enqueue = (state.command_ring_enqueue + 1) % usable;
-        if state.command_ring_enqueue == 0 {
-            state.command_ring_cycle = !state.command_ring_cycle;
+/// Resolve with whatever `take` finds after `event` fires, or `None` once
+/// `timeout_ms` has passed. Each poll drains the event ring itself, so the
+/// wait also works before the idle loop is running.
+async fn completion<T>(event: &'static Event, timeout_ms: u64, mut take: impl FnMut() -> Option<T>) -> Option<T> {
+    CONTROLLER_STATE.get()?;
+    let deadline = executor::deadline_after_ms(timeout_ms);
+    poll_fn(|cx| {
+        event.take();
+        let _ = poll_events();
+        if let Some(done) = take() {
+            return Poll::Ready(Some(done));
         }
-    }
+        if executor::expired(deadline) {
+            return Poll::Ready(None);
+        }
+        event.register(cx.waker());
+        executor::wake_at(deadline, cx.waker());
+        Poll::Pending
+    })
+    .await
 }
 
-fn enqueue_command_trb(trb_type: u32, parameter: u64, status: u32) {
+/// Await the next command completion as `(code, slot)`.
+pub async fn command_completion(timeout_ms: u64) -> Option<(u8, u8)> {
+    completion(&COMMAND_EVENT, timeout_ms, take_command_completion).await
+}
+
+/// Await the next transfer event as `(code, residual length)`.
+pub async fn transfer_completion(timeout_ms: u64) -> Option<(u8, u32)> {
+    completion(&TRANSFER_EVENT, timeout_ms, take_transfer_completion).await
+}
+
+fn enqueue_noop_command() {
     if let Some(state_lock) = CONTROLLER_STATE.get() {
-        let mut state = state_lock.lock();
-        let usable = state.command_ring_len.saturating_sub(1);
-        if usable == 0 {
-            serial::write_str("[xhci] command ring unusable\r\n");
+        let mut guard = state_lock.lock();
+        let state = &mut *guard;
+        let cycle_bit = state.command_ring_cycle as u8;
+        if !command_room(state) {
             return;
         }
-
-        let index = state.command_ring_enqueue % usable;
-        let trbs = unsafe {
-            phys_to_slice_mut::<Trb>(state.command_ring_phys, state.command_ring_len)
-        };
-        let cycle_bit = if state.command_ring_cycle { 1 } else { 0 };
-        trbs[index] = Trb {
-            parameter,
-            status,
-            control: ((trb_type & 0x3F) << 10) | (1 << 5) | cycle_bit,
-        };
-        compiler_fence(FenceOrdering::SeqCst);
-
-        state.command_ring_enqueue = (state.command_ring_enqueue + 1) % usable;
-        if state.command_ring_enqueue == 0 {
-            state.command_ring_cycle = !state.command_ring_cycle;
+        let trbs = unsafe { phys_to_slice_mut::<Trb>(state.command_ring_phys, state.command_ring_len) };
+        let trb = Trb { parameter: 0, status: 0, control: TrbControl::new(TRB_TYPE_NO_OP_COMMAND).with_ioc().0 };
+        match ring_push(trbs, &mut state.command_ring_enqueue, &mut state.command_ring_cycle, trb) {
+            Some(index) => {
+                state.commands_pending += 1;
+                check_rings(state);
+                klog::log(Level::Debug, format_args!("[xhci] queued noop index={} cycle={}\r\n", index, cycle_bit));
+            }
+            None => klog::log(Level::Warn, format_args!("[xhci] command ring unusable\r\n")),
         }
     }
 }
 
-fn enqueue_command_trb_slot(trb_type: u32, parameter: u64, status: u32, slot_id: u8) {
+fn enqueue_command_trb(trb_type: u8, parameter: u64, status: u32) {
+    push_command(TrbControl::new(trb_type), parameter, status);
+}
+
+fn enqueue_command_trb_slot(trb_type: u8, parameter: u64, status: u32, slot_id: u8) {
+    push_command(TrbControl::new(trb_type).with_slot_id(slot_id), parameter, status);
+}
+
+fn enqueue_command_trb_ep(trb_type: u8, slot_id: u8, ep_id: u8) {
+    push_command(TrbControl::new(trb_type).with_slot_id(slot_id).with_endpoint_id(ep_id), 0, 0);
+}
+
+/// Queue a command TRB; IOC and the ring's cycle bit are added to `control`.
+fn push_command(control: TrbControl, parameter: u64, status: u32) {
     if let Some(state_lock) = CONTROLLER_STATE.get() {
-        let mut state = state_lock.lock();
-        let usable = state.command_ring_len.saturating_sub(1);
-        if usable == 0 {
-            serial::write_str("[xhci] command ring unusable\r\n");
+        let mut guard = state_lock.lock();
+        let state = &mut *guard;
+        if !command_room(state) {
             return;
         }
-
-        let index = state.command_ring_enqueue % usable;
-        let trbs = unsafe {
-            phys_to_slice_mut::<Trb>(state.command_ring_phys, state.command_ring_len)
-        };
-        let cycle_bit = if state.command_ring_cycle { 1 } else { 0 };
-        let mut control = ((trb_type & 0x3F) << 10) | (1 << 5) | cycle_bit;
-        control |= (slot_id as u32) << 16;
-        trbs[index] = Trb {
-            parameter,
-            status,
-            control,
-        };
-        compiler_fence(FenceOrdering::SeqCst);
-
-        state.command_ring_enqueue = (state.command_ring_enqueue + 1) % usable;
-        if state.command_ring_enqueue == 0 {
-            state.command_ring_cycle = !state.command_ring_cycle;
+        let trbs = unsafe { phys_to_slice_mut::<Trb>(state.command_ring_phys, state.command_ring_len) };
+        let trb = Trb { parameter, status, control: control.with_ioc().0 };
+        match ring_push(trbs, &mut state.command_ring_enqueue, &mut state.command_ring_cycle, trb) {
+            Some(_) => {
+                state.commands_pending += 1;
+                check_rings(state);
+            }
+            None => klog::log(Level::Warn, format_args!("[xhci] command ring unusable\r\n")),
         }
     }
 }
 
-pub fn enable_slot() -> Option<u8> {
+/// Whether another command fits: one the controller has not completed may
+/// not be read yet, and overwriting it would lose it. A full ring is a fault.
+fn command_room(state: &ControllerState) -> bool {
+    if state.commands_pending + 1 < state.command_ring_len {
+        return true;
+    }
+    fault(Fault::RingFull);
+    false
+}
+
+pub async fn enable_slot() -> Option<u8> {
     // Queue Enable Slot Command and ring DB0
     enqueue_command_trb(TRB_TYPE_ENABLE_SLOT, 0, 0);
     ring_doorbell(0, 0);
-    if let Some((code, slot)) = wait_for_command_completion(1_000_000) {
-        serial::write_fmt(format_args!(
+    if let Some((code, slot)) = command_completion(COMPLETION_TIMEOUT_MS).await {
+        klog::log(Level::Debug, format_args!(
             "[xhci] enable slot completion code={:#x} slot={}\r\n",
             code, slot
         ));
@@ -791,7 +1389,18 @@ pub fn enable_slot() -> Option<u8> {
     None
 }
 
-pub fn address_device(slot_id: u8) -> bool {
+/// Point the DCBAA entry of `slot_id`, as the controller handed it out, at
+/// a device context.
+fn install_device_context(dcbaa_phys: u64, max_slots: u8, slot_id: u8, dc_phys: u64) -> Result<(), &'static str> {
+    kensure!(slot_id != 0 && slot_id <= max_slots, "xhci: slot id out of range");
+    unsafe {
+        let dcbaa = phys_to_slice_mut::<u64>(dcbaa_phys, max_slots as usize + 1);
+        dcbaa[slot_id as usize] = dma(dc_phys);
+    }
+    Ok(())
+}
+
+pub async fn address_device(slot_id: u8) -> bool {
     // Allocate and hook Device Context in DCBAA
     if let Some(state_lock) = CONTROLLER_STATE.get() {
         let state_info;
@@ -806,28 +1415,26 @@ pub fn address_device(slot_id: u8) -> bool {
 
         let dc_entries = 1 /* slot */ + 31; // endpoints
         let dc_bytes = context_size * dc_entries;
-        let dc_phys = match pmm::alloc_aligned(dc_bytes as u64, 64) {
+        let dc_phys = match pmm::alloc_for("xhci.dev_ctx", dc_bytes as u64, 64) {
             Some(p) => p,
             None => {
-                serial::write_str("[xhci] no memory for device context\r\n");
+                klog::log(Level::Warn, format_args!("[xhci] no memory for device context\r\n"));
                 return false;
             }
         };
         zero_phys(dc_phys, dc_bytes);
 
-        // Install into DCBAA
-        let dcbaa_entries = state_info.max_slots() as usize + 1;
-        unsafe {
-            let dcbaa = phys_to_slice_mut::<u64>(dcbaa_phys, dcbaa_entries);
-            dcbaa[slot_id as usize] = dc_phys;
+        if let Err(e) = install_device_context(dcbaa_phys, state_info.max_slots(), slot_id, dc_phys) {
+            klog::log(Level::Warn, format_args!("[xhci] {}\r\n", e));
+            return false;
         }
 
         // Allocate EP0 transfer ring and set it into EP0 context later
         let ep0_trbs = 64usize;
-        let ep0_ring_phys = match pmm::alloc_aligned((ep0_trbs * size_of::<Trb>()) as u64, 64) {
+        let ep0_ring_phys = match pmm::alloc_for("xhci.ep0_ring", (ep0_trbs * size_of::<Trb>()) as u64, 64) {
             Some(p) => p,
             None => {
-                serial::write_str("[xhci] no memory for ep0 ring\r\n");
+                klog::log(Level::Warn, format_args!("[xhci] no memory for ep0 ring\r\n"));
                 return false;
             }
         };
@@ -840,73 +1447,35 @@ pub fn address_device(slot_id: u8) -> bool {
         // Allocate Input Context (ICC + Slot + EP0)
         let ic_entries = 1 /* ICC */ + 1 /* slot */ + 1 /* ep0 */;
         let ic_bytes = context_size * ic_entries;
-        let ic_phys = match pmm::alloc_aligned(ic_bytes as u64, 64) {
+        let ic_phys = match pmm::alloc_for("xhci.input_ctx", ic_bytes as u64, 64) {
             Some(p) => p,
             None => {
-                serial::write_str("[xhci] no memory for input context\r\n");
+                klog::log(Level::Warn, format_args!("[xhci] no memory for input context\r\n"));
                 return false;
             }
         };
         zero_phys(ic_phys, ic_bytes);
 
-        // Set Add Context Flags: slot + ep0
-        unsafe {
-            let ic_ptr = phys_to_mut_ptr(ic_phys) as *mut u32;
-            // dword1 at offset 4: Add Context Flags
-            write_volatile(ic_ptr.add(1), 0b11);
-        }
+        let (port_index, speed_code) = match find_first_connected_port() {
+            Some(idx) => {
+                let sc = unsafe { Xhci::new(state_info) }.and_then(|c| c.operational().port(idx).ok()).map_or(Portsc(0), |p| p.portsc());
+                (idx, sc.speed())
+            }
+            None => (0, 0),
+        };
+        let ic = unsafe { InputContext::new(ic_phys, context_size, ic_entries) };
+        // Add Context Flags: slot + ep0
+        ic.set_add_flags(0b11);
+        ic.write_slot(1, speed_code, port_index as u8 + 1);
 
-        // Fill minimal Slot Context and EP0 Context fields
-        unsafe {
-            let dwords_per_ctx = context_size / 4;
-            let slot_ctx = (phys_to_mut_ptr(ic_phys) as *mut u32).add(dwords_per_ctx);
-            let ep0_ctx = slot_ctx.add(dwords_per_ctx);
-
-            // Slot Context: set Context Entries = 1 (EP0), RouteString=0
-            // DW0 = Route String -> 0
-            write_volatile(slot_ctx.add(0), 0);
-            // DW1: set speed if available from first connected port
-            let speed_code = {
-                let mut sp = 0u32;
-                if let Some(idx) = find_first_connected_port() {
-                    if let Some(state_lock) = CONTROLLER_STATE.get() {
-                        let info = { state_lock.lock().info };
-                        if let Some(controller) = Xhci::new(info) {
-                            let op = controller.operational();
-                            let sc = op.port(idx).portsc();
-                            sp = ((sc >> 10) & 0xF) as u32;
-                        }
-                    }
-                }
-                sp
-            };
-            // Put speed in a reasonable location (implementation-defined here)
-            write_volatile(slot_ctx.add(1), speed_code << 20);
-            // DW2: Context Entries = 1 in bits 31:27 (approximate)
-            write_volatile(slot_ctx.add(2), 1 << 27);
-
-            // EP0 Context
-            // DW0/DW1: set EP Type=Control, MPS per speed
-            let mps = match speed_code {
-                4 /* SS */ => 512u32,
-                3 /* HS */ => 64u32,
-                1 /* FS */ | 2 /* LS */ => 8u32,
-                _ => 64u32,
-            };
-            // Store MPS in DW1 upper half (approximate placement)
-            write_volatile(ep0_ctx.add(1), mps << 16);
-            // Set Dequeue Pointer (DW2/DW3)
-            let deq_low = (ep0_ring_phys as u32) & !0xF;
-            let deq_high = (ep0_ring_phys >> 32) as u32;
-            write_volatile(ep0_ctx.add(2), deq_low);
-            write_volatile(ep0_ctx.add(3), deq_high);
-        }
+        // EP0 Context: Control, MPS per speed
+        ic.write_endpoint(1, &EndpointContext::control(ep0_max_packet(speed_code)), ep0_ring_phys);
 
         // Queue Address Device command
-        enqueue_command_trb_slot(TRB_TYPE_ADDRESS_DEVICE, ic_phys, 0, slot_id);
+        enqueue_command_trb_slot(TRB_TYPE_ADDRESS_DEVICE, dma(ic_phys), 0, slot_id);
         ring_doorbell(0, 0);
-        if let Some((code, slot)) = wait_for_command_completion(1_000_000) {
-            serial::write_fmt(format_args!(
+        if let Some((code, slot)) = command_completion(COMPLETION_TIMEOUT_MS).await {
+            klog::log(Level::Debug, format_args!(
                 "[xhci] address device completion code={:#x} slot={}\r\n",
                 code, slot
             ));
@@ -918,7 +1487,10 @@ pub fn address_device(slot_id: u8) -> bool {
                     state.ep0_ring_len = ep0_trbs;
                     state.ep0_enqueue = 0;
                     state.ep0_cycle = true;
+                    state.device_speed = speed_code;
+                    state.device_port = port_index as u8 + 1;
                 }
+                let _ = usb_state::addressed(port_index as u8 + 1, slot_id);
                 return true;
             }
         }
@@ -936,168 +1508,153 @@ struct UsbSetupPacket {
     wLength: u16,
 }
 
+/// Default EP0 max packet size for a PORTSC speed code.
+fn ep0_max_packet(speed: u8) -> u32 {
+    match speed {
+        4 /* SS */ => 512,
+        3 /* HS */ => 64,
+        1 /* FS */ | 2 /* LS */ => 8,
+        _ => 64,
+    }
+}
+
+/// One TRB's share of a data stage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct DataChunk {
+    phys: u64,
+    len: u32,
+    /// Packets left in the TD after this TRB (TD Size, xHCI 4.11.2.4).
+    td_size: u32,
+}
+
+/// Split `len` bytes at `phys` into TRB buffers. A TRB buffer may not cross
+/// a 64 KiB boundary (xHCI 6.4.1), which also keeps each length within the
+/// 17-bit field.
+fn data_chunks(phys: u64, len: u32, max_packet: u32) -> impl Iterator<Item = DataChunk> {
+    let end = phys + len as u64;
+    let mut at = phys;
+    core::iter::from_fn(move || {
+        if at >= end {
+            return None;
+        }
+        let chunk_end = end.min((at | 0xFFFF) + 1);
+        let left = end - chunk_end;
+        let td_size = left.div_ceil(max_packet.max(1) as u64).min(TransferStatus::TD_SIZE_MAX as u64) as u32;
+        let chunk = DataChunk { phys: at, len: (chunk_end - at) as u32, td_size };
+        at = chunk_end;
+        Some(chunk)
+    })
+}
+
 fn ep0_enqueue_trb(trb: Trb) {
     if let Some(state_lock) = CONTROLLER_STATE.get() {
         let mut state = state_lock.lock();
         if state.ep0_ring_len == 0 {
-            serial::write_str("[xhci] ep0 ring not ready\r\n");
+            klog::log(Level::Warn, format_args!("[xhci] ep0 ring not ready\r\n"));
             return;
         }
-        let usable = state.ep0_ring_len.saturating_sub(1);
-        if usable == 0 { return; }
-        let index = state.ep0_enqueue % usable;
+        let state = &mut *state;
         let ring = unsafe { phys_to_slice_mut::<Trb>(state.ep0_ring_phys, state.ep0_ring_len) };
-        ring[index] = trb;
-        state.ep0_enqueue = (state.ep0_enqueue + 1) % usable;
-        if state.ep0_enqueue == 0 {
-            state.ep0_cycle = !state.ep0_cycle;
-        }
+        ring_push(ring, &mut state.ep0_enqueue, &mut state.ep0_cycle, trb);
+        check_rings(state);
     }
 }
 
-fn ep0_cycle_bit() -> u32 {
-    if let Some(state_lock) = CONTROLLER_STATE.get() {
-        let state = state_lock.lock();
-        return if state.ep0_cycle { 1 } else { 0 };
-    }
-    1
-}
-
 fn ring_ep0(slot_id: u8) {
     ring_doorbell(slot_id, 1);
 }
 
-pub fn control_in(slot_id: u8, request_type: u8, request: u8, value: u16, index: u16, length: u16, data_phys: u64) -> bool {
+pub async fn control_in(slot_id: u8, request_type: u8, request: u8, value: u16, index: u16, length: u16, data_phys: u64) -> bool {
     // Setup stage (IDT, length=8)
     let setup = UsbSetupPacket { bmRequestType: request_type, bRequest: request, wValue: value, wIndex: index, wLength: length };
     let setup_param: u64 = unsafe { core::mem::transmute::<UsbSetupPacket, u64>(setup) };
-    let setup_trb = Trb { parameter: setup_param, status: 8, control: ((TRB_TYPE_SETUP_STAGE & 0x3F) << 10) | (1 << 5) | ep0_cycle_bit() };
+    let setup_trb = Trb { parameter: setup_param, status: 8, control: TrbControl::new(TRB_TYPE_SETUP_STAGE).with_immediate_data().with_transfer_type(TRT_IN_DATA).0 };
     ep0_enqueue_trb(setup_trb);
 
-    // Data stage (IN)
-    let data_trb = Trb { parameter: data_phys, status: length as u32, control: ((TRB_TYPE_DATA_STAGE & 0x3F) << 10) | (1 << 16) | (1 << 5) | ep0_cycle_bit() };
-    ep0_enqueue_trb(data_trb);
+    // Data stage (IN): a Data Stage TRB, then chained Normal TRBs for the
+    // parts of the buffer past a 64 KiB boundary
+    let max_packet = CONTROLLER_STATE.get().map(|s| ep0_max_packet(s.lock().device_speed)).unwrap_or(64);
+    let mut chunks = data_chunks(data_phys, length as u32, max_packet).peekable();
+    let mut first = true;
+    while let Some(chunk) = chunks.next() {
+        let mut control = if first {
+            TrbControl::new(TRB_TYPE_DATA_STAGE).with_dir_in()
+        } else {
+            TrbControl::new(TRB_TYPE_NORMAL)
+        };
+        if chunks.peek().is_some() {
+            control = control.with_chain();
+        }
+        let status = TransferStatus::new(chunk.len, chunk.td_size).0;
+        ep0_enqueue_trb(Trb { parameter: dma(chunk.phys), status, control: control.0 });
+        first = false;
+    }
 
     // Status stage (OUT)
-    let status_trb = Trb { parameter: 0, status: 0, control: ((TRB_TYPE_STATUS_STAGE & 0x3F) << 10) | (0 << 16) | (1 << 5) | ep0_cycle_bit() };
+    let status_trb = Trb { parameter: 0, status: 0, control: TrbControl::new(TRB_TYPE_STATUS_STAGE).with_ioc().0 };
     ep0_enqueue_trb(status_trb);
 
     ring_ep0(slot_id);
 
-    // Wait for a transfer event
-    for _ in 0..1_000_000 {
-        let _ = poll_events();
-        if let Some(lock) = CONTROLLER_STATE.get() {
-            let mut state = lock.lock();
-            if let Some(code) = state.last_transfer_code.take() {
-                let len = state.last_transfer_len.take().unwrap_or(0);
-                serial::write_fmt(format_args!("[xhci] control_in done code={:#x} len={}\r\n", code, len));
-                return code == 1; // Success
-            }
+    match transfer_completion(COMPLETION_TIMEOUT_MS).await {
+        Some((code, len)) => {
+            klog::log(Level::Debug, format_args!("[xhci] control_in done code={:#x} len={}\r\n", code, len));
+            code == 1 // Success
         }
-        spin_loop();
+        None => false,
     }
-    false
 }
 
-pub fn get_device_descriptor(slot_id: u8) -> Option<u64> {
-    let buf_phys = match pmm::alloc_aligned(256, 64) { Some(p) => p, None => { serial::write_str("[xhci] no mem for dev desc\r\n"); return None; } };
+pub async fn get_device_descriptor(slot_id: u8) -> Option<u64> {
+    let buf_phys = pmm::alloc_for("xhci.desc", 256, 64)?;
     zero_phys(buf_phys, 256);
-    let ok = control_in(slot_id, 0x80, 6, (1u16 << 8) | 0, 0, 18, buf_phys);
+    let ok = control_in(slot_id, 0x80, 6, (1u16 << 8) | 0, 0, 18, buf_phys).await;
     if ok { Some(buf_phys) } else { None }
 }
 
-pub fn control_no_data(slot_id: u8, request_type: u8, request: u8, value: u16, index: u16) -> bool {
+pub async fn control_no_data(slot_id: u8, request_type: u8, request: u8, value: u16, index: u16) -> bool {
     // Setup only, then Status with IN direction
     let setup = UsbSetupPacket { bmRequestType: request_type, bRequest: request, wValue: value, wIndex: index, wLength: 0 };
     let setup_param: u64 = unsafe { core::mem::transmute::<UsbSetupPacket, u64>(setup) };
-    let setup_trb = Trb { parameter: setup_param, status: 8, control: ((TRB_TYPE_SETUP_STAGE & 0x3F) << 10) | (1 << 5) | ep0_cycle_bit() };
+    let setup_trb = Trb { parameter: setup_param, status: 8, control: TrbControl::new(TRB_TYPE_SETUP_STAGE).with_immediate_data().with_transfer_type(TRT_NO_DATA).0 };
     ep0_enqueue_trb(setup_trb);
 
     // Status stage (IN)
-    let status_trb = Trb { parameter: 0, status: 0, control: ((TRB_TYPE_STATUS_STAGE & 0x3F) << 10) | (1 << 16) | (1 << 5) | ep0_cycle_bit() };
+    let status_trb = Trb { parameter: 0, status: 0, control: TrbControl::new(TRB_TYPE_STATUS_STAGE).with_dir_in().with_ioc().0 };
     ep0_enqueue_trb(status_trb);
 
     ring_ep0(slot_id);
 
-    for _ in 0..1_000_000 {
-        let _ = poll_events();
-        if let Some(lock) = CONTROLLER_STATE.get() {
-            let mut state = lock.lock();
-            if let Some(code) = state.last_transfer_code.take() {
-                serial::write_fmt(format_args!("[xhci] control_out(no-data) done code={:#x}\r\n", code));
-                return code == 1;
-            }
+    match transfer_completion(COMPLETION_TIMEOUT_MS).await {
+        Some((code, _)) => {
+            klog::log(Level::Debug, format_args!("[xhci] control_out(no-data) done code={:#x}\r\n", code));
+            code == 1
         }
-        spin_loop();
+        None => false,
     }
-    false
 }
 
-pub fn get_configuration_descriptor_header(slot_id: u8) -> Option<(u64, u16, u8)> {
+pub async fn get_configuration_descriptor_header(slot_id: u8) -> Option<(u64, u16, u8)> {
     // Read first 9 bytes to get wTotalLength and bConfigurationValue
-    let buf_phys = match pmm::alloc_aligned(64, 64) { Some(p) => p, None => { serial::write_str("[xhci] no mem for cfg head\r\n"); return None; } };
+    let buf_phys = pmm::alloc_for("xhci.desc", 64, 64)?;
     zero_phys(buf_phys, 64);
-    let ok = control_in(slot_id, 0x80, 6, (2u16 << 8) | 0, 0, 9, buf_phys);
+    let ok = control_in(slot_id, 0x80, 6, (2u16 << 8) | 0, 0, 9, buf_phys).await;
     if !ok { return None; }
-    unsafe {
-        let hdr = phys_to_slice_mut::<u8>(buf_phys, 9);
-        let total = (hdr[2] as u16) | ((hdr[3] as u16) << 8);
-        let cfg_val = hdr[5];
-        Some((buf_phys, total, cfg_val))
-    }
+    let hdr = unsafe { phys_to_slice_mut::<u8>(buf_phys, ConfigDescriptor::LEN) };
+    let cfg = ConfigDescriptor::parse(hdr)?;
+    Some((buf_phys, cfg.total_length, cfg.value))
 }
 
-pub fn get_configuration_descriptor(slot_id: u8, total_len: u16) -> Option<u64> {
+pub async fn get_configuration_descriptor(slot_id: u8, total_len: u16) -> Option<u64> {
     let len = total_len as usize;
-    let buf_phys = match pmm::alloc_aligned(len as u64, 64) { Some(p) => p, None => { serial::write_str("[xhci] no mem for cfg desc\r\n"); return None; } };
+    let buf_phys = pmm::alloc_for("xhci.desc", len as u64, 64)?;
     zero_phys(buf_phys, len);
-    let ok = control_in(slot_id, 0x80, 6, (2u16 << 8) | 0, 0, total_len, buf_phys);
+    let ok = control_in(slot_id, 0x80, 6, (2u16 << 8) | 0, 0, total_len, buf_phys).await;
     if ok { Some(buf_phys) } else { None }
 }
 
-pub fn parse_hid_keyboard_endpoint(cfg_phys: u64, total_len: u16) -> Option<(u8, u16, u8)> {
-    unsafe {
-        let bytes = phys_to_slice_mut::<u8>(cfg_phys, total_len as usize);
-        let mut i = 0usize;
-        let mut in_hid_iface = false;
-        while i + 2 <= bytes.len() {
-            let b_len = bytes[i] as usize;
-            if b_len == 0 { break; }
-            if i + b_len > bytes.len() { break; }
-            let dtype = bytes[i + 1];
-            match dtype {
-                4 => {
-                    // Interface
-                    if b_len >= 9 {
-                        let class = bytes[i + 5];
-                        let subclass = bytes[i + 6];
-                        let proto = bytes[i + 7];
-                        in_hid_iface = class == 3 && subclass == 1 && proto == 1; // HID boot keyboard
-                    }
-                }
-                5 => {
-                    // Endpoint
-                    if in_hid_iface && b_len >= 7 {
-                        let addr = bytes[i + 2];
-                        let attrs = bytes[i + 3] & 0x3;
-                        let maxp = (bytes[i + 4] as u16) | ((bytes[i + 5] as u16) << 8);
-                        let interval = bytes[i + 6];
-                        if (addr & 0x80) != 0 && attrs == 3 {
-                            return Some((addr, maxp, interval));
-                        }
-                    }
-                }
-                _ => {}
-            }
-            i += b_len;
-        }
-    }
-    None
-}
-
-pub fn set_configuration(slot_id: u8, cfg_value: u8) -> bool {
-    control_no_data(slot_id, 0x00, 9, cfg_value as u16, 0)
+pub async fn set_configuration(slot_id: u8, cfg_value: u8) -> bool {
+    control_no_data(slot_id, 0x00, 9, cfg_value as u16, 0).await
 }
 
 fn endpoint_id_from_addr(addr: u8) -> u8 {
@@ -1106,15 +1663,26 @@ fn endpoint_id_from_addr(addr: u8) -> u8 {
     (ep * 2) + if dir_in { 1 } else { 0 }
 }
 
-pub fn configure_interrupt_in_endpoint(slot_id: u8, ep_addr: u8, maxp: u16, _interval: u8) -> bool {
+pub fn configure_interrupt_in_endpoint(slot_id: u8, desc: &EndpointDescriptor) -> bool {
+    let (ep_addr, interval) = (desc.address, desc.interval);
     let ep_id = endpoint_id_from_addr(ep_addr);
-    let ctx_size = if let Some(lock) = CONTROLLER_STATE.get() { lock.lock().info.context_size() as usize } else { return false };
+    let (ctx_size, speed, port) = if let Some(lock) = CONTROLLER_STATE.get() {
+        let st = lock.lock();
+        (st.info.context_size() as usize, st.device_speed, st.device_port)
+    } else {
+        return false;
+    };
+    let interval_exp = interrupt_interval_exponent(speed, interval);
+    klog::log(Level::Debug, format_args!(
+        "[xhci] ep {:#x} bInterval={} speed={} -> interval exp {} ({} us)\r\n",
+        ep_addr, interval, speed, interval_exp, 125u32 << interval_exp
+    ));
 
     // Allocate interrupt ring
     let ring_trbs = 128usize;
-    let ring_phys = match pmm::alloc_aligned((ring_trbs * size_of::<Trb>()) as u64, 64) {
+    let ring_phys = match pmm::alloc_for("xhci.int_ring", (ring_trbs * size_of::<Trb>()) as u64, 64) {
         Some(p) => p,
-        None => { serial::write_str("[xhci] no memory for intr ring\r\n"); return false; }
+        None => { klog::log(Level::Warn, format_args!("[xhci] no memory for intr ring\r\n")); return false; }
     };
     unsafe {
         let ring = phys_to_slice_mut::<Trb>(ring_phys, ring_trbs);
@@ -1123,41 +1691,22 @@ pub fn configure_interrupt_in_endpoint(slot_id: u8, ep_addr: u8, maxp: u16, _int
     }
 
     // Allocate Input Context for Configure Endpoint: ICC + Slot + endpoints up to ep_id
-    let ic_entries = 1 + 1 + (ep_id as usize); // rough sizing
+    let ic_entries = 1 + 1 + (ep_id as usize); // ICC + slot + DCI 1..=ep_id
     let ic_bytes = ctx_size * ic_entries;
-    let ic_phys = match pmm::alloc_aligned(ic_bytes as u64, 64) { Some(p) => p, None => { serial::write_str("[xhci] no memory for conf ic\r\n"); return false; } };
+    let Some(ic_phys) = pmm::alloc_for("xhci.input_ctx", ic_bytes as u64, 64) else { return false };
     zero_phys(ic_phys, ic_bytes);
 
-    unsafe {
-        let base = phys_to_mut_ptr(ic_phys) as *mut u32;
-        // Add Context Flags: set bit for target endpoint id
-        // Also keep slot context flagged
-        let add_flags = (1u32 << 0) | (1u32 << ep_id);
-        write_volatile(base.add(1), add_flags);
-
-        let dwords = ctx_size / 4;
-        let slot_ctx = base.add(dwords);
-        // Context Entries >= ep_id
-        write_volatile(slot_ctx.add(2), (ep_id as u32) << 27);
-
-        // Endpoint context index in array: for EP1 IN -> index 3
-        let ep_ctx = slot_ctx.add(dwords * (ep_id as usize));
-
-        // Fill minimal EP context: type=interrupt IN, MaxPacket, Dequeue Ptr
-        // DW1: Max Packet Size in bits 31:16 (approx), Interval bits etc ignored here
-        let mps = maxp as u32;
-        write_volatile(ep_ctx.add(1), mps << 16);
-        // DW2/DW3: TR Dequeue Pointer
-        let deq_low = (ring_phys as u32) & !0xF;
-        let deq_high = (ring_phys >> 32) as u32;
-        write_volatile(ep_ctx.add(2), deq_low);
-        write_volatile(ep_ctx.add(3), deq_high);
-    }
-
-    enqueue_command_trb_slot(TRB_TYPE_CONFIGURE_ENDPOINT, ic_phys, 0, slot_id);
+    let ic = unsafe { InputContext::new(ic_phys, ctx_size, ic_entries) };
+    // Add Context Flags: slot (Context Entries changes) + target endpoint
+    ic.set_add_flags((1u32 << 0) | (1u32 << ep_id));
+    ic.write_slot(ep_id, speed, port);
+
+    ic.write_endpoint(ep_id, &EndpointContext::from_descriptor(desc, interval_exp), ring_phys);
+
+    enqueue_command_trb_slot(TRB_TYPE_CONFIGURE_ENDPOINT, dma(ic_phys), 0, slot_id);
     ring_doorbell(0, 0);
     if let Some((code, slot)) = wait_for_command_completion(1_000_000) {
-        serial::write_fmt(format_args!("[xhci] configure ep completion code={:#x} slot={}\r\n", code, slot));
+        klog::log(Level::Debug, format_args!("[xhci] configure ep completion code={:#x} slot={}\r\n", code, slot));
         if code == 1 && slot == slot_id {
             if let Some(lock) = CONTROLLER_STATE.get() {
                 let mut st = lock.lock();
@@ -1174,29 +1723,22 @@ pub fn configure_interrupt_in_endpoint(slot_id: u8, ep_addr: u8, maxp: u16, _int
     false
 }
 
-fn intr_cycle_bit() -> u32 {
-    if let Some(lock) = CONTROLLER_STATE.get() { let st = lock.lock(); if st.intr_cycle { 1 } else { 0 } } else { 1 }
-}
-
 fn intr_enqueue_trb(trb: Trb) {
     if let Some(lock) = CONTROLLER_STATE.get() {
-        let mut st = lock.lock();
-        let usable = st.intr_ring_len.saturating_sub(1);
-        if usable == 0 { return; }
-        let idx = st.intr_enqueue % usable;
+        let mut guard = lock.lock();
+        let st = &mut *guard;
         let ring = unsafe { phys_to_slice_mut::<Trb>(st.intr_ring_phys, st.intr_ring_len) };
-        ring[idx] = trb;
-        st.intr_enqueue = (st.intr_enqueue + 1) % usable;
-        if st.intr_enqueue == 0 { st.intr_cycle = !st.intr_cycle; }
+        ring_push(ring, &mut st.intr_enqueue, &mut st.intr_cycle, trb);
+        check_rings(st);
     }
 }
 
 pub fn request_hid_report_once(slot_id: u8, ep_addr: u8, maxp: u16) -> Option<u64> {
     let ep_id = endpoint_id_from_addr(ep_addr);
     let buf_len = maxp as usize;
-    let buf_phys = match pmm::alloc_aligned(buf_len as u64, 64) { Some(p) => p, None => { serial::write_str("[xhci] no mem for hid buf\r\n"); return None; } };
+    let buf_phys = pmm::alloc_for("xhci.hid_buf", buf_len as u64, 64)?;
     zero_phys(buf_phys, buf_len);
-    let trb = Trb { parameter: buf_phys, status: maxp as u32, control: ((TRB_TYPE_NORMAL & 0x3F) << 10) | (1 << 5) | intr_cycle_bit() };
+    let trb = Trb { parameter: dma(buf_phys), status: maxp as u32, control: TrbControl::new(TRB_TYPE_NORMAL).with_ioc().0 };
     intr_enqueue_trb(trb);
     ring_doorbell(slot_id, ep_id as u32);
 
@@ -1206,7 +1748,7 @@ pub fn request_hid_report_once(slot_id: u8, ep_addr: u8, maxp: u16) -> Option<u6
             let mut st = lock.lock();
             if let Some(code) = st.last_transfer_code.take() {
                 let len = st.last_transfer_len.take().unwrap_or(0) as usize;
-                serial::write_fmt(format_args!("[hid] report event code={:#x} len={}\r\n", code, len));
+                klog::log(Level::Debug, format_args!("[hid] report event code={:#x} len={}\r\n", code, len));
                 if code == 1 { return Some(buf_phys); }
                 break;
             }
@@ -1218,34 +1760,36 @@ pub fn request_hid_report_once(slot_id: u8, ep_addr: u8, maxp: u16) -> Option<u6
 
 pub fn start_hid_polling(slot_id: u8, ep_addr: u8, maxp: u16) -> bool {
     if let Some(lock) = CONTROLLER_STATE.get() {
-        let mut st = lock.lock();
+        let mut guard = lock.lock();
+        let st = &mut *guard;
         if st.intr_ring_len == 0 { return false; }
         if st.hid_buf_phys == 0 {
-            let buf_phys = match pmm::alloc_aligned(maxp as u64, 64) { Some(p) => p, None => return false };
+            let buf_phys = match pmm::alloc_for("xhci.hid_buf", maxp as u64, 64) { Some(p) => p, None => return false };
             zero_phys(buf_phys, maxp as usize);
             st.hid_buf_phys = buf_phys;
             st.hid_buf_len = maxp as usize;
         }
-        let cycle = if st.intr_cycle { 1 } else { 0 };
-        let trb = Trb { parameter: st.hid_buf_phys, status: maxp as u32, control: ((TRB_TYPE_NORMAL & 0x3F) << 10) | (1 << 5) | cycle };
-        let usable = st.intr_ring_len.saturating_sub(1);
-        if usable == 0 { return false; }
-        let idx = st.intr_enqueue % usable;
-        unsafe { let ring = phys_to_slice_mut::<Trb>(st.intr_ring_phys, st.intr_ring_len); ring[idx] = trb; }
-        st.intr_enqueue = (st.intr_enqueue + 1) % usable;
-        if st.intr_enqueue == 0 { st.intr_cycle = !st.intr_cycle; }
+        let control = TrbControl::new(TRB_TYPE_NORMAL).with_ioc();
+        let trb = Trb { parameter: dma(st.hid_buf_phys), status: maxp as u32, control: control.0 };
+        let ring = unsafe { phys_to_slice_mut::<Trb>(st.intr_ring_phys, st.intr_ring_len) };
+        if ring_push(ring, &mut st.intr_enqueue, &mut st.intr_cycle, trb).is_none() { return false; }
+        check_rings(st);
         ring_doorbell(slot_id, endpoint_id_from_addr(ep_addr) as u32);
         return true;
     }
     false
 }
 
-pub fn decode_hid_report(buf_phys: u64, len: usize) {
+pub fn decode_hid_report(buf_phys: u64, len: usize) -> Result<(), &'static str> {
+    // Boot protocol report: modifiers, reserved, then key codes
+    kensure!(len >= 3, "hid: short report");
     unsafe {
         let data = phys_to_slice_mut::<u8>(buf_phys, len);
-        serial::write_fmt(format_args!("[hid] data: "));
-        for i in 0..len { serial::write_fmt(format_args!("{:02x} ", data[i])); }
-        serial::write_str("\r\n");
+        if klog::enabled(Level::Debug) {
+            serial::write_str("[hid] data: ");
+            for i in 0..len { serial::write_fmt(format_args!("{:02x} ", data[i])); }
+            serial::write_str("\r\n");
+        }
 
         // Very small decoder: first key only, ASCII for letters and digits
         let modifiers = data[0];
@@ -1255,6 +1799,7 @@ pub fn decode_hid_report(buf_phys: u64, len: usize) {
             vga::put_char(ch);
         }
     }
+    Ok(())
 }
 
 fn hid_usage_to_ascii(usage: u8, shift: bool) -> Option<char> {
@@ -1288,6 +1833,115 @@ fn hid_usage_to_ascii(usage: u8, shift: bool) -> Option<char> {
     }
 }
 
+/// Program interrupter 0's moderation interval. Returns the effective
+/// interval in microseconds after clamping to the register's resolution.
+pub fn set_interrupt_moderation(us: u32) -> Result<u32, &'static str> {
+    let state_lock = CONTROLLER_STATE.get().ok_or("xhci: not initialized")?;
+    let info = { state_lock.lock().info };
+    let controller = unsafe { Xhci::new(info) }.ok_or("xhci: null base")?;
+    let ir0 = controller.runtime().interrupter_register_set(0);
+    let value = imod_interval(us);
+    // Writing IMODC (high half) as 0 lets the new interval take effect at once
+    ir0.set_imod(value);
+    Ok(value * IMOD_UNIT_NS / 1000)
+}
+
+/// Current moderation interval of interrupter 0, in microseconds.
+pub fn interrupt_moderation() -> Option<u32> {
+    let info = { CONTROLLER_STATE.get()?.lock().info };
+    let controller = unsafe { Xhci::new(info) }?;
+    let imodi = controller.runtime().interrupter_register_set(0).imod() & 0xFFFF;
+    Some(imodi * IMOD_UNIT_NS / 1000)
+}
+
+/// Stop the controller and have it save its internal state (xHCI 4.23.2),
+/// for a power-management path that may cut the controller's power. Rings,
+/// DCBAA and ERST stay in memory; `resume` points the controller back at them.
+pub fn suspend() -> Result<(), &'static str> {
+    let state_lock = CONTROLLER_STATE.get().ok_or("xhci: not initialized")?;
+    let (info, slot, intr_ep) = {
+        let st = state_lock.lock();
+        if st.suspended.is_some() {
+            return Err("xhci: already suspended");
+        }
+        (st.info, st.active_slot, st.intr_ep_id)
+    };
+    // No transfer may be in flight across the save: stop the interrupt
+    // endpoint, its pending TRB stays queued for the doorbell in `resume`
+    if let (Some(slot), true) = (slot, intr_ep != 0) {
+        enqueue_command_trb_ep(TRB_TYPE_STOP_ENDPOINT, slot, intr_ep);
+        ring_doorbell(0, 0);
+        match wait_for_command_completion(1_000_000) {
+            Some((1, _)) => {}
+            other => klog::log(Level::Warn, format_args!("[xhci] stop endpoint {}: {:?}\r\n", intr_ep, other)),
+        }
+    }
+    let controller = unsafe { Xhci::new(info) }.ok_or("xhci: null base")?;
+    let op = controller.operational();
+    let ir0 = controller.runtime().interrupter_register_set(0);
+    let saved = SavedRegs { config: op.config(), imod: ir0.imod() };
+
+    op.set_usbcmd(op.usbcmd() - UsbCmd::RUN_STOP);
+    kensure!(wait_for(|| op.usbsts().contains(UsbSts::HOST_CONTROLLER_HALTED)), "xhci: halt timeout");
+    op.set_usbcmd(op.usbcmd() | UsbCmd::CONTROLLER_SAVE_STATE);
+    kensure!(wait_for(|| !op.usbsts().contains(UsbSts::SAVE_STATE_STATUS)), "xhci: save state timeout");
+    if op.usbsts().contains(UsbSts::SAVE_RESTORE_ERROR) {
+        op.clear_usbsts(UsbSts::SAVE_RESTORE_ERROR);
+        return Err("xhci: save state failed");
+    }
+    state_lock.lock().suspended = Some(saved);
+    klog::log(Level::Info, format_args!("[xhci] suspended\r\n"));
+    usb_state::suspend_all();
+    Ok(())
+}
+
+/// Undo `suspend`: re-program DCBAAP, CONFIG and interrupter 0, restore the
+/// saved state, point CRCR at the next command slot and run again.
+pub fn resume() -> Result<(), &'static str> {
+    let state_lock = CONTROLLER_STATE.get().ok_or("xhci: not initialized")?;
+    let (slot, intr_ep) = {
+        let mut st = state_lock.lock();
+        let saved = st.suspended.ok_or("xhci: not suspended")?;
+        let controller = unsafe { Xhci::new(st.info) }.ok_or("xhci: null base")?;
+        let op = controller.operational();
+        let ir0 = controller.runtime().interrupter_register_set(0);
+        let trb_size = size_of::<Trb>() as u64;
+
+        // Order from xHCI 4.23.2: DCBAAP, CONFIG, then the interrupter
+        op.set_dcbaap(dma(st.dcbaa_phys));
+        op.set_config(saved.config);
+        ir0.set_erstsz(1);
+        ir0.set_erstba(dma(st.erst_phys));
+        ir0.set_erdp(dma(st.event_ring_phys + EVENTS.cursor().index as u64 * trb_size));
+        ir0.set_iman(ir0.iman().with_enable(true));
+        ir0.set_imod(saved.imod);
+
+        op.set_usbcmd(op.usbcmd() | UsbCmd::CONTROLLER_RESTORE_STATE);
+        kensure!(wait_for(|| !op.usbsts().contains(UsbSts::RESTORE_STATE_STATUS)), "xhci: restore state timeout");
+        if op.usbsts().contains(UsbSts::SAVE_RESTORE_ERROR) {
+            // The saved image is gone (power was lost); only a full
+            // re-initialisation and re-enumeration would bring the bus back
+            op.clear_usbsts(UsbSts::SAVE_RESTORE_ERROR);
+            st.suspended = None;
+            usb_state::remove_all();
+            return Err("xhci: restore failed, controller needs a reset");
+        }
+        let cmd_next = st.command_ring_phys + st.command_ring_enqueue as u64 * trb_size;
+        op.set_crcr(Crcr::new(dma(cmd_next), st.command_ring_cycle));
+
+        op.set_usbcmd(op.usbcmd() | UsbCmd::RUN_STOP | UsbCmd::INTERRUPTER_ENABLE);
+        kensure!(wait_for(|| !op.usbsts().contains(UsbSts::HOST_CONTROLLER_HALTED)), "xhci: run timeout");
+        st.suspended = None;
+        (st.active_slot, st.intr_ep_id)
+    };
+    if let (Some(slot), true) = (slot, intr_ep != 0) {
+        ring_doorbell(slot, intr_ep as u32);
+    }
+    klog::log(Level::Info, format_args!("[xhci] resumed\r\n"));
+    usb_state::resume_all();
+    Ok(())
+}
+
 fn ring_doorbell(slot_id: u8, target: u32) {
     if let Some(state_lock) = CONTROLLER_STATE.get() {
         let state = state_lock.lock();
@@ -1299,27 +1953,38 @@ fn ring_doorbell(slot_id: u8, target: u32) {
     }
 }
 
+/// Whether a port id from an event TRB names one of `max_ports` ports
+/// (1-based).
+fn valid_port_id(port_id: u8, max_ports: u8) -> bool {
+    (1..=max_ports).contains(&port_id)
+}
+
 fn handle_event(state: &mut ControllerState, trb_type: u8, trb: &Trb) {
     match trb_type {
         TRB_TYPE_COMMAND_COMPLETION => {
-            let completion_code = ((trb.status >> 24) & 0xFF) as u8;
-            let slot_id = (trb.parameter & 0xFF) as u8;
+            let completion_code = EventStatus(trb.status).completion_code();
+            let slot_id = TrbControl(trb.control).slot_id();
             state.last_completion_code = Some(completion_code);
             state.last_completed_slot = Some(slot_id);
-            serial::write_fmt(format_args!(
+            state.commands_pending = state.commands_pending.saturating_sub(1);
+            ktrace::event(ktrace::Kind::XhciComplete, completion_code as u16, 0);
+            COMMAND_EVENT.signal();
+            klog::log(Level::Debug, format_args!(
                 "[xhci] command completion code={:#x} slot={}\r\n",
                 completion_code, slot_id
             ));
         }
         TRB_TYPE_TRANSFER_EVENT => {
-            let completion_code = ((trb.status >> 24) & 0xFF) as u8;
-            let trb_len = trb.status & 0x00FF_FFFF;
-            let ep_id = ((trb.control >> 16) & 0x1F) as u8;
+            let completion_code = EventStatus(trb.status).completion_code();
+            let trb_len = EventStatus(trb.status).transfer_length();
+            let ep_id = TrbControl(trb.control).endpoint_id();
             state.last_transfer_code = Some(completion_code);
             state.last_transfer_len = Some(trb_len);
             state.last_transfer_ep = Some(ep_id);
             state.last_transfer_slot = state.active_slot; // best effort
-            serial::write_fmt(format_args!(
+            ktrace::event(ktrace::Kind::XhciComplete, completion_code as u16, ep_id as u32);
+            TRANSFER_EVENT.signal();
+            klog::log(Level::Debug, format_args!(
                 "[xhci] transfer event ep={} code={:#x} len={} param={:#x}\r\n",
                 ep_id, completion_code, trb_len, trb.parameter
             ));
@@ -1332,82 +1997,158 @@ fn handle_event(state: &mut ControllerState, trb_type: u8, trb: &Trb) {
             {
                 let len = (trb_len as usize).min(state.hid_buf_len);
                 // Decode current buffer
-                decode_hid_report(state.hid_buf_phys, len);
-                // Re-post new normal TRB on interrupt ring
-                let cycle = if state.intr_cycle { 1 } else { 0 };
-                let trb = Trb {
-                    parameter: state.hid_buf_phys,
-                    status: state.hid_buf_len as u32,
-                    control: ((TRB_TYPE_NORMAL & 0x3F) << 10) | (1 << 5) | cycle,
-                };
-                let usable = state.intr_ring_len.saturating_sub(1);
-                if usable > 0 {
-                    let idx = state.intr_enqueue % usable;
-                    unsafe {
-                        let ring = phys_to_slice_mut::<Trb>(state.intr_ring_phys, state.intr_ring_len);
-                        ring[idx] = trb;
-                    }
-                    state.intr_enqueue = (state.intr_enqueue + 1) % usable;
-                    if state.intr_enqueue == 0 {
-                        state.intr_cycle = !state.intr_cycle;
-                    }
-                    if let Some(slot) = state.active_slot {
-                        ring_doorbell(slot, state.intr_ep_id as u32);
-                    }
+                let _ = decode_hid_report(state.hid_buf_phys, len);
+                if pace_elapsed(state) {
+                    repost_hid_transfer(state);
+                } else {
+                    state.hid_repost_pending = true;
                 }
             }
         }
         TRB_TYPE_PORT_STATUS_CHANGE => {
             let port_id = ((trb.parameter >> 24) & 0xFF) as u8;
-            serial::write_fmt(format_args!(
+            klog::log(Level::Debug, format_args!(
                 "[xhci] port status change: port={} status={:#x}\r\n",
                 port_id, trb.status
             ));
+            // The port id comes from the controller: 1..=MaxPorts, or ignore it
+            if !valid_port_id(port_id, state.info.max_ports()) {
+                klog::log(Level::Warn, format_args!("[xhci] port status change for unknown port {}, ignored\r\n", port_id));
+                return;
+            }
             unsafe {
                 if let Some(controller) = Xhci::new(state.info) {
                     let op = controller.operational();
-                    let regs = op.port((port_id.saturating_sub(1)) as usize);
+                    let index = port_id as usize - 1;
+                    let Ok(regs) = op.port(index) else { return };
                     let sc = regs.portsc();
-                    let ccs = (sc & 0x1) != 0;
-                    let ped = (sc & 0x2) != 0;
-                    let speed = (sc >> 10) & 0xF;
-                    let pls = (sc >> 5) & 0xF;
-                    serial::write_fmt(format_args!(
+                    klog::log(Level::Debug, format_args!(
                         "[xhci] port{} sc={:#010x} ccs={} ped={} speed={} pls={}\r\n",
-                        port_id, sc, ccs as u8, ped as u8, speed, pls
+                        port_id, sc.0, sc.connected() as u8, sc.enabled() as u8, sc.speed(), sc.link_state()
                     ));
+                    // Acknowledge the change bits so the port can report the next one
+                    op.clear_changes(index, sc.changes());
+                    if sc.connected() {
+                        if let Err(e) = usb_state::detect(port_id) {
+                            klog::log(Level::Warn, format_args!("[usb] port{}: {}\r\n", port_id, e.as_str()));
+                        }
+                    } else if usb_state::state(port_id).is_some_and(|s| s != usb_state::DeviceState::Removed) {
+                        let _ = usb_state::transition(port_id, usb_state::DeviceState::Removed);
+                        if state.device_port == port_id {
+                            // Nothing left to post interrupt transfers to
+                            state.active_slot = None;
+                            state.hid_repost_pending = false;
+                        }
+                    }
                 }
             }
         }
-        _ => serial::write_fmt(format_args!(
+        _ => klog::log(Level::Debug, format_args!(
             "[xhci] event type={} status={:#x} param={:#x}\r\n",
             trb_type, trb.status, trb.parameter
         )),
     }
 }
 
-pub struct PortRegs {
-    base: NonNull<u8>,
+fn pace_elapsed(state: &ControllerState) -> bool {
+    let per_ms = time::tsc_per_ms();
+    if state.hid_pace_ms == 0 || per_ms == 0 {
+        return true;
+    }
+    let elapsed = time::rdtsc().wrapping_sub(state.hid_last_post_tsc);
+    elapsed >= per_ms.saturating_mul(state.hid_pace_ms as u64)
 }
 
-impl PortRegs {
-    unsafe fn new(base: NonNull<u8>, offset: usize) -> Self {
-        Self {
-            base: NonNull::new_unchecked(base.as_ptr().add(offset)),
+/// Queue the next interrupt-IN transfer for the HID buffer. Rings the
+/// doorbell directly: the caller already holds the controller state lock.
+fn repost_hid_transfer(state: &mut ControllerState) {
+    if state.hid_buf_phys == 0 {
+        return;
+    }
+    let trb = Trb {
+        parameter: dma(state.hid_buf_phys),
+        status: state.hid_buf_len as u32,
+        control: TrbControl::new(TRB_TYPE_NORMAL).with_ioc().0,
+    };
+    let ring = unsafe { phys_to_slice_mut::<Trb>(state.intr_ring_phys, state.intr_ring_len) };
+    if ring_push(ring, &mut state.intr_enqueue, &mut state.intr_cycle, trb).is_none() {
+        return;
+    }
+    check_rings(state);
+    state.hid_repost_pending = false;
+    state.hid_last_post_tsc = time::rdtsc();
+    if let Some(slot) = state.active_slot {
+        unsafe {
+            if let Some(controller) = Xhci::new(state.info) {
+                controller.doorbells().ring(slot as usize, state.intr_ep_id as u32);
+            }
+        }
+    }
+}
+
+/// What the controller knows about its addressed device.
+#[derive(Copy, Clone, Debug)]
+pub struct DeviceInfo {
+    pub hci_version: u16,
+    pub ports: u8,
+    pub slot: Option<u8>,
+    pub port: u8,
+    pub speed: u8,
+}
+
+/// `None` until a controller has been initialized.
+pub fn device_info() -> Option<DeviceInfo> {
+    let state = CONTROLLER_STATE.get()?.lock();
+    Some(DeviceInfo {
+        hci_version: state.info.hci_version,
+        ports: state.info.max_ports(),
+        slot: state.active_slot,
+        port: state.device_port,
+        speed: state.device_speed,
+    })
+}
+
+/// Set the driver-side HID pacing interval (0 disables pacing).
+pub fn set_hid_pacing(ms: u32) -> bool {
+    match CONTROLLER_STATE.get() {
+        Some(lock) => {
+            lock.lock().hid_pace_ms = ms;
+            true
         }
+        None => false,
     }
+}
 
-    fn sc_ptr(&self) -> *mut u32 {
-        self.base.as_ptr() as *mut u32
+pub fn hid_pacing() -> Option<u32> {
+    CONTROLLER_STATE.get().map(|lock| lock.lock().hid_pace_ms)
+}
+
+/// Endpoint context Interval field (period = 2^Interval * 125 us) for an
+/// interrupt endpoint's bInterval. FS/LS express bInterval in frames (ms);
+/// HS/SS already use an exponent, 2^(bInterval-1) microframes.
+fn interrupt_interval_exponent(speed: u8, b_interval: u8) -> u8 {
+    match speed {
+        1 | 2 => {
+            let microframes = (b_interval.max(1) as u32) * 8;
+            let exp = 31 - microframes.leading_zeros();
+            exp.clamp(3, 10) as u8
+        }
+        _ => b_interval.clamp(1, 16) - 1,
     }
+}
 
-    pub fn portsc(&self) -> u32 {
-        unsafe { read_volatile(self.sc_ptr()) }
+pub struct PortRegs {
+    regs: MmioRegion,
+}
+
+impl PortRegs {
+    pub fn portsc(&self) -> Portsc {
+        Portsc(self.regs.read32(0x00))
     }
 
-    #[allow(dead_code)]
-    pub fn write_portsc(&self, value: u32) {
-        unsafe { write_volatile(self.sc_ptr(), value) };
+    /// Only through `OperationalRegs::portsc_modify`, which masks the read.
+    fn write_portsc(&self, value: Portsc) {
+        self.regs.write32(0x00, value.0);
     }
 }
 
@@ -1427,8 +2168,7 @@ pub fn find_first_connected_port() -> Option<usize> {
             if let Some(controller) = Xhci::new(info) {
                 let op = controller.operational();
                 for i in 0..info.max_ports() as usize {
-                    let sc = op.port(i).portsc();
-                    if (sc & 0x1) != 0 {
+                    if op.port(i).is_ok_and(|p| p.portsc().connected()) {
                         return Some(i);
                     }
                 }
@@ -1444,24 +2184,26 @@ pub fn reset_port(index: usize) -> bool {
         unsafe {
             if let Some(controller) = Xhci::new(info) {
                 let op = controller.operational();
-                let regs = op.port(index);
+                let regs = match op.port(index) {
+                    Ok(regs) => regs,
+                    Err(e) => {
+                        klog::log(Level::Warn, format_args!("[xhci] reset port{}: {}\r\n", index + 1, e.as_str()));
+                        return false;
+                    }
+                };
                 let sc = regs.portsc();
-                serial::write_fmt(format_args!("[xhci] resetting port{} sc={:#x}\r\n", index + 1, sc));
-                regs.write_portsc(sc | (1 << 4));
-                let _ = wait_for(|| {
-                    let now = regs.portsc();
-                    (now & (1 << 4)) == 0
-                });
-                let ok = wait_for(|| {
-                    let now = regs.portsc();
-                    (now & 0x2) != 0
-                });
+                klog::log(Level::Info, format_args!("[xhci] resetting port{} sc={:#x}\r\n", index + 1, sc.0));
+                op.portsc_modify(index, Portsc::with_reset);
+                let _ = wait_for(|| !regs.portsc().in_reset());
+                let ok = wait_for(|| regs.portsc().enabled());
                 let final_sc = regs.portsc();
-                serial::write_fmt(format_args!(
+                // The reset completion is reported here, not through an event handler
+                op.clear_changes(index, Portsc::PRC);
+                klog::log(Level::Info, format_args!(
                     "[xhci] port{} reset done ok={} sc={:#x}\r\n",
                     index + 1,
                     ok as u8,
-                    final_sc
+                    final_sc.0
                 ));
                 return ok;
             }
@@ -1472,19 +2214,124 @@ pub fn reset_port(index: usize) -> bool {
 
 pub fn ensure_first_port_enabled() -> bool {
     if let Some(idx) = find_first_connected_port() {
-        unsafe {
-            if let Some(state_lock) = CONTROLLER_STATE.get() {
-                let info = { state_lock.lock().info };
-                if let Some(controller) = Xhci::new(info) {
-                    let op = controller.operational();
-                    let sc = op.port(idx).portsc();
-                    if (sc & 0x2) != 0 {
-                        return true;
-                    }
-                }
-            }
+        let port = idx as u8 + 1;
+        let _ = usb_state::detect(port);
+        let enabled = unsafe {
+            CONTROLLER_STATE.get().and_then(|lock| Xhci::new(lock.lock().info)).is_some_and(|c| c.operational().port(idx).is_ok_and(|p| p.portsc().enabled()))
+        };
+        if enabled || reset_port(idx) {
+            let _ = usb_state::transition(port, usb_state::DeviceState::Reset);
+            return true;
         }
-        return reset_port(idx);
     }
     false
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ring_push_hands_over_the_link_trb_on_wrap() {
+        let mut ring = [Trb::default(); 4];
+        init_link_trb(&mut ring, 0x1000, true);
+        let (mut enqueue, mut cycle) = (0, true);
+        let normal = Trb { parameter: 0xAB00, status: 8, control: TrbControl::new(TRB_TYPE_NORMAL).0 };
+        for expected in [0, 1, 2] {
+            assert_eq!(ring_push(&mut ring, &mut enqueue, &mut cycle, normal), Some(expected));
+        }
+        // First lap: link TRB given away with cycle 1, producer now on 0
+        assert!(!cycle);
+        assert!(TrbControl(ring[3].control).cycle());
+        for _ in 0..3 {
+            ring_push(&mut ring, &mut enqueue, &mut cycle, normal);
+        }
+        assert!(cycle);
+        assert!(!TrbControl(ring[3].control).cycle());
+        assert_eq!(TrbControl(ring[3].control).trb_type(), TRB_TYPE_LINK);
+        assert!(!TrbControl(ring[0].control).cycle());
+        assert_eq!(ring_push(&mut [Trb::default(); 1], &mut 0, &mut true, normal), None);
+    }
+
+    #[test]
+    fn endpoint_context_from_descriptor() {
+        // Full-speed boot keyboard: EP1 IN interrupt, 8 bytes, exponent 6
+        let kbd = EndpointDescriptor { address: 0x81, attributes: 3, max_packet: 8, interval: 10 };
+        let ctx = EndpointContext::from_descriptor(&kbd, 6);
+        assert_eq!((ctx.ep_type, ctx.error_count, ctx.max_esit_payload), (7, 3, 8));
+        assert_eq!(ctx.dwords(), [6 << 16, (8 << 16) | (7 << 3) | (3 << 1), (8 << 16) | 1024]);
+        // High-speed isochronous OUT, 3 x 1024 bytes per microframe
+        let iso = EndpointDescriptor { address: 0x02, attributes: 1, max_packet: (2 << 11) | 1024, interval: 1 };
+        let ctx = EndpointContext::from_descriptor(&iso, 0);
+        assert_eq!((ctx.ep_type, ctx.max_packet, ctx.max_burst, ctx.error_count), (1, 1024, 2, 0));
+        assert_eq!(ctx.max_esit_payload, 3072);
+        // Bulk IN has no interval or ESIT payload
+        let bulk = EndpointDescriptor { address: 0x83, attributes: 2, max_packet: 512, interval: 0 };
+        let ctx = EndpointContext::from_descriptor(&bulk, 5);
+        assert_eq!((ctx.ep_type, ctx.interval, ctx.max_esit_payload), (6, 0, 0));
+        assert_eq!(EndpointContext::control(64).dwords()[1], (64 << 16) | (4 << 3) | (3 << 1));
+    }
+
+    #[test]
+    fn full_speed_interval_is_frames() {
+        // 10 ms -> 80 microframes -> 2^6 = 64 (8 ms), the largest not above it
+        assert_eq!(interrupt_interval_exponent(1, 10), 6);
+        assert_eq!(interrupt_interval_exponent(2, 1), 3);
+        assert_eq!(interrupt_interval_exponent(1, 255), 10);
+        assert_eq!(interrupt_interval_exponent(1, 0), 3);
+    }
+
+    #[test]
+    fn ext_cap_walk_finds_legacy_support() {
+        // protocol caps at 0x500 and 0x520, legacy support at 0x540
+        let read = |off: usize| match off {
+            0x500 => 0x0800_0802,
+            0x520 => 0x0000_0802 | (8 << 8),
+            0x540 => 0x0001_0001,
+            _ => 0,
+        };
+        assert_eq!(find_ext_cap(0x500, ExtCap::USB_LEGACY_SUPPORT, read), Some(0x540));
+        assert_eq!(find_ext_cap(0x500, 0x0A, read), None);
+        assert_eq!(find_ext_cap(0, ExtCap::USB_LEGACY_SUPPORT, read), None);
+        // a zero next pointer ends the walk
+        assert_eq!(find_ext_cap(0x500, 1, |_| 0x0000_0002), None);
+    }
+
+    #[test]
+    fn data_stage_splits_at_64k_boundaries() {
+        let mut chunks = data_chunks(0x1_FF00, 0x300, 64);
+        assert_eq!(chunks.next(), Some(DataChunk { phys: 0x1_FF00, len: 0x100, td_size: 8 }));
+        assert_eq!(chunks.next(), Some(DataChunk { phys: 0x2_0000, len: 0x200, td_size: 0 }));
+        assert_eq!(chunks.next(), None);
+        // a buffer inside one 64 KiB block stays one TRB
+        assert_eq!(data_chunks(0x1000, 18, 8).count(), 1);
+        assert_eq!(data_chunks(0x1000, 0, 8).count(), 0);
+        // TD Size saturates at 31 packets
+        assert_eq!(data_chunks(0xF000, 0x2_0000, 8).next().map(|c| c.td_size), Some(31));
+    }
+
+    #[test]
+    fn high_speed_interval_is_exponent() {
+        assert_eq!(interrupt_interval_exponent(3, 4), 3);
+        assert_eq!(interrupt_interval_exponent(4, 1), 0);
+        assert_eq!(interrupt_interval_exponent(3, 20), 15);
+    }
+
+    #[test]
+    fn port_ids_past_max_ports_are_refused() {
+        assert!(valid_port_id(1, 4) && valid_port_id(4, 4));
+        assert!(!valid_port_id(0, 4) && !valid_port_id(5, 4) && !valid_port_id(1, 0));
+
+        // Operational registers of a controller with two ports
+        #[repr(align(16))]
+        struct Regs([u8; PORT_REGS_OFFSET + 2 * PORT_REGS_STRIDE]);
+        let mut buf = Regs([0; PORT_REGS_OFFSET + 2 * PORT_REGS_STRIDE]);
+        let base = core::ptr::NonNull::new(buf.0.as_mut_ptr()).unwrap();
+        let op = OperationalRegs { regs: unsafe { MmioRegion::new(base, buf.0.len()) } };
+        assert!(op.port(1).is_ok());
+        assert_eq!(op.port(2).err(), Some(MmioError::OutOfBounds));
+        assert_eq!(op.port(usize::MAX).err(), Some(MmioError::OutOfBounds));
+        // No register to touch: a no-op, not a panic
+        op.clear_changes(7, u32::MAX);
+    }
+}
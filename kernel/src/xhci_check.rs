@@ -0,0 +1,162 @@
+//! Ring invariants, checked after every enqueue and event batch when the
+//! kernel is built with `xhci_debug`. A ring that breaks them stalls the
+//! controller without an error, which otherwise shows up only as a
+//! command or transfer timing out much later.
+//!
+//! Producer rings (command, ep0, interrupt) end in a link TRB pointing back
+//! at the ring with Toggle Cycle set. TRBs the producer wrote this lap, the
+//! ones before the enqueue index, carry its cycle bit; the rest still
+//! carry last lap's, so the controller stops at the enqueue index. On the
+//! event ring the controller produces and `xhci_events` consumes: every TRB
+//! before the dequeue index must have been written this lap, or the
+//! controller lapped the consumer.
+
+use crate::xhci::{Trb, TRB_TYPE_LINK};
+use crate::xhci_events::Cursor;
+use crate::xhci_regs::TrbControl;
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Violation {
+    /// The ring is too short to hold a TRB and its link.
+    TooShort,
+    /// The last TRB is not a link TRB.
+    NoLink,
+    /// The link TRB does not point at the start of the ring.
+    LinkTarget,
+    /// The link TRB does not toggle the cycle.
+    NoToggle,
+    /// The enqueue or dequeue index is outside the ring.
+    OutOfRange,
+    /// A TRB written this lap does not carry the current cycle.
+    StaleCycle(usize),
+    /// A TRB past the enqueue index already carries the current cycle: the
+    /// controller would run past the enqueue index.
+    AheadOfEnqueue(usize),
+    /// More TRBs outstanding than the ring holds: enqueue passed dequeue.
+    Overrun,
+}
+
+impl Violation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Violation::TooShort => "ring too short",
+            Violation::NoLink => "last TRB is not a link TRB",
+            Violation::LinkTarget => "link TRB does not point at the ring",
+            Violation::NoToggle => "link TRB does not toggle the cycle",
+            Violation::OutOfRange => "index outside the ring",
+            Violation::StaleCycle(_) => "TRB written this lap has the old cycle",
+            Violation::AheadOfEnqueue(_) => "TRB past enqueue has the current cycle",
+            Violation::Overrun => "enqueue passed dequeue",
+        }
+    }
+
+    /// The TRB the violation is about, if it is about one.
+    pub fn index(&self) -> Option<usize> {
+        match *self {
+            Violation::StaleCycle(i) | Violation::AheadOfEnqueue(i) => Some(i),
+            _ => None,
+        }
+    }
+}
+
+fn control(trb: &Trb) -> TrbControl {
+    TrbControl(unsafe { core::ptr::read_volatile(&trb.control) })
+}
+
+/// Check a producer ring: `link_target` is what its link TRB's parameter
+/// must hold, `pending` the TRBs the controller has not completed yet when
+/// the ring keeps count (the command ring does).
+pub fn producer(ring: &[Trb], link_target: u64, enqueue: usize, cycle: bool, pending: Option<usize>) -> Result<(), Violation> {
+    let usable = ring.len().checked_sub(1).filter(|&n| n > 0).ok_or(Violation::TooShort)?;
+    let link = &ring[usable];
+    let link_control = control(link);
+    if link_control.trb_type() != TRB_TYPE_LINK {
+        return Err(Violation::NoLink);
+    }
+    if unsafe { core::ptr::read_volatile(&link.parameter) } != link_target {
+        return Err(Violation::LinkTarget);
+    }
+    if link_control.with_toggle_cycle().0 != link_control.0 {
+        return Err(Violation::NoToggle);
+    }
+    if enqueue >= usable {
+        return Err(Violation::OutOfRange);
+    }
+    if pending.is_some_and(|n| n >= usable) {
+        return Err(Violation::Overrun);
+    }
+    for (i, trb) in ring[..usable].iter().enumerate() {
+        match (i < enqueue, control(trb).cycle() == cycle) {
+            (true, false) => return Err(Violation::StaleCycle(i)),
+            (false, true) => return Err(Violation::AheadOfEnqueue(i)),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Check the event ring behind the consumer's `cursor`. TRBs at and after
+/// it are not looked at: the controller may be writing them.
+pub fn consumer(ring: &[Trb], cursor: Cursor) -> Result<(), Violation> {
+    if cursor.index >= ring.len() {
+        return Err(Violation::OutOfRange);
+    }
+    match ring[..cursor.index].iter().position(|trb| control(trb).cycle() != cursor.cycle) {
+        Some(i) => Err(Violation::StaleCycle(i)),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASE: u64 = 0x1000;
+
+    fn trb(cycle: bool) -> Trb {
+        Trb { parameter: 0, status: 0, control: TrbControl(0).with_cycle(cycle).0 }
+    }
+
+    fn ring(cycles: [bool; 3], link_cycle: bool) -> [Trb; 4] {
+        let link = TrbControl::new(TRB_TYPE_LINK).with_cycle(link_cycle).with_toggle_cycle();
+        [trb(cycles[0]), trb(cycles[1]), trb(cycles[2]), Trb { parameter: BASE, status: 0, control: link.0 }]
+    }
+
+    #[test]
+    fn producer_ring_follows_its_cycle() {
+        // Fresh ring, one TRB queued
+        let first = ring([true, false, false], true);
+        assert_eq!(producer(&first, BASE, 1, true, Some(1)), Ok(()));
+        // Second lap: producer cycle 0, two TRBs written over last lap's
+        let second = ring([false, false, true], true);
+        assert_eq!(producer(&second, BASE, 2, false, None), Ok(()));
+
+        assert_eq!(producer(&first, BASE, 2, true, None), Err(Violation::StaleCycle(1)));
+        assert_eq!(producer(&first, BASE, 0, true, None), Err(Violation::AheadOfEnqueue(0)));
+        assert_eq!(producer(&first, BASE, 3, true, None), Err(Violation::OutOfRange));
+        assert_eq!(producer(&first, BASE, 1, true, Some(3)), Err(Violation::Overrun));
+        assert_eq!(producer(&first[..1], BASE, 0, true, None), Err(Violation::TooShort));
+    }
+
+    #[test]
+    fn producer_ring_needs_its_link() {
+        let mut r = ring([true, false, false], true);
+        assert_eq!(producer(&r, BASE + 64, 1, true, None), Err(Violation::LinkTarget));
+        r[3].control = TrbControl::new(TRB_TYPE_LINK).with_cycle(true).0;
+        assert_eq!(producer(&r, BASE, 1, true, None), Err(Violation::NoToggle));
+        r[3] = trb(true);
+        assert_eq!(producer(&r, BASE, 1, true, None), Err(Violation::NoLink));
+        assert_eq!(Violation::StaleCycle(2).index(), Some(2));
+        assert_eq!(Violation::NoLink.index(), None);
+        assert_eq!(Violation::Overrun.as_str(), "enqueue passed dequeue");
+    }
+
+    #[test]
+    fn event_ring_behind_the_consumer() {
+        let events = [trb(false), trb(false), trb(true), trb(true)];
+        assert_eq!(consumer(&events, Cursor { index: 2, cycle: false }), Ok(()));
+        assert_eq!(consumer(&events, Cursor { index: 3, cycle: false }), Err(Violation::StaleCycle(2)));
+        assert_eq!(consumer(&events, Cursor::START), Ok(()));
+        assert_eq!(consumer(&events, Cursor { index: 4, cycle: true }), Err(Violation::OutOfRange));
+    }
+}
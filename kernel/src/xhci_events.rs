@@ -0,0 +1,173 @@
+//! Consumer side of the xHCI event ring, kept apart from the controller
+//! state so events can be dequeued without its mutex.
+//!
+//! `xhci::poll_events` runs in two stages: it copies the TRBs the
+//! controller handed over into a local batch (this module, no lock), then
+//! takes the controller lock to dispatch them. Only one caller consumes at
+//! a time: the main loop, a wait loop, a handler that polls again from
+//! inside dispatch, or an interrupt handler arriving mid-poll all go
+//! through `Consumer::exclusive`, and a caller that finds it taken returns
+//! at once, leaving the events to the one already draining the ring.
+
+use core::ptr::read_volatile;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use crate::mmio::dma_rmb;
+use crate::xhci::Trb;
+use crate::xhci_regs::TrbControl;
+
+const CYCLE_BIT: u64 = 1 << 63;
+
+/// Where the next event is and which cycle bit marks it as written.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Cursor {
+    pub index: usize,
+    pub cycle: bool,
+}
+
+impl Cursor {
+    /// A fresh ring: first TRB, cycle 1.
+    pub const START: Cursor = Cursor { index: 0, cycle: true };
+
+    fn pack(self) -> u64 {
+        self.index as u64 | if self.cycle { CYCLE_BIT } else { 0 }
+    }
+
+    fn unpack(word: u64) -> Self {
+        Cursor { index: (word & !CYCLE_BIT) as usize, cycle: word & CYCLE_BIT != 0 }
+    }
+
+    /// The next event the controller has written to `ring`, if any; moves
+    /// past it, flipping the cycle at the end of the ring.
+    pub fn pop(&mut self, ring: &[Trb]) -> Option<Trb> {
+        let slot = ring.get(self.index)?;
+        let control = TrbControl(unsafe { read_volatile(&slot.control) });
+        if control.cycle() != self.cycle {
+            return None;
+        }
+        // The cycle bit says the TRB is ours; read the rest after it
+        dma_rmb();
+        let trb = unsafe { read_volatile(slot) };
+        self.index += 1;
+        if self.index == ring.len() {
+            self.index = 0;
+            self.cycle = !self.cycle;
+        }
+        Some(trb)
+    }
+
+    /// Pop into `out` until the ring is empty or `out` is full; returns how
+    /// many were taken.
+    pub fn drain(&mut self, ring: &[Trb], out: &mut [Trb]) -> usize {
+        let mut n = 0;
+        while n < out.len() {
+            let Some(trb) = self.pop(ring) else { break };
+            out[n] = trb;
+            n += 1;
+        }
+        n
+    }
+}
+
+pub struct Consumer {
+    busy: AtomicBool,
+    cursor: AtomicU64,
+}
+
+impl Consumer {
+    pub const fn new() -> Self {
+        Consumer { busy: AtomicBool::new(false), cursor: AtomicU64::new(CYCLE_BIT) }
+    }
+
+    /// Start over on a fresh ring (init, controller reset). Only called
+    /// with no consumer running.
+    pub fn reset(&self) {
+        self.cursor.store(Cursor::START.pack(), Ordering::Release);
+    }
+
+    pub fn cursor(&self) -> Cursor {
+        Cursor::unpack(self.cursor.load(Ordering::Acquire))
+    }
+
+    /// Run `f` as the only consumer of the ring, with the cursor it may
+    /// move. `None` if another caller is consuming, `f` not run.
+    pub fn exclusive<R>(&self, f: impl FnOnce(&mut Cursor) -> R) -> Option<R> {
+        if self.busy.compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed).is_err() {
+            return None;
+        }
+        let mut cursor = self.cursor();
+        let result = f(&mut cursor);
+        self.cursor.store(cursor.pack(), Ordering::Release);
+        self.busy.store(false, Ordering::Release);
+        Some(result)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+    use std::vec::Vec;
+
+    fn event(tag: u64, cycle: bool) -> Trb {
+        Trb { parameter: tag, status: 0, control: TrbControl(0).with_cycle(cycle).0 }
+    }
+
+    #[test]
+    fn cursor_follows_the_cycle_across_laps() {
+        let mut ring = [Trb::default(); 4];
+        let mut cursor = Cursor::START;
+        assert!(cursor.pop(&ring).is_none());
+        for (i, slot) in ring.iter_mut().enumerate() {
+            *slot = event(i as u64, true);
+        }
+        let mut out = [Trb::default(); 8];
+        assert_eq!(cursor.drain(&ring, &mut out), 4);
+        assert_eq!(cursor, Cursor { index: 0, cycle: false });
+        // Last lap's TRBs are not events of this one
+        assert!(cursor.pop(&ring).is_none());
+        ring[0] = event(10, false);
+        assert_eq!(cursor.pop(&ring).map(|t| t.parameter), Some(10));
+        assert_eq!(Cursor::unpack(cursor.pack()), cursor);
+    }
+
+    #[test]
+    fn reentrant_caller_is_turned_away() {
+        let consumer = Consumer::new();
+        let nested = consumer.exclusive(|cursor| {
+            cursor.index = 3;
+            consumer.exclusive(|_| ())
+        });
+        assert_eq!(nested, Some(None));
+        assert_eq!(consumer.cursor().index, 3);
+        assert_eq!(consumer.exclusive(|_| ()), Some(()));
+        consumer.reset();
+        assert_eq!(consumer.cursor(), Cursor::START);
+    }
+
+    #[test]
+    fn concurrent_callers_see_each_event_once() {
+        const EVENTS: usize = 256;
+        let ring: Vec<Trb> = (0..EVENTS as u64).map(|i| event(i, true)).collect();
+        let consumer = Consumer::new();
+        let seen = Mutex::new(Vec::new());
+        std::thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| loop {
+                    let _ = consumer.exclusive(|cursor| {
+                        let mut batch = [Trb::default(); 8];
+                        let n = cursor.drain(&ring, &mut batch);
+                        seen.lock().unwrap().extend(batch[..n].iter().map(|t| t.parameter));
+                    });
+                    if seen.lock().unwrap().len() == EVENTS {
+                        break;
+                    }
+                    std::thread::yield_now();
+                });
+            }
+        });
+        let seen = seen.into_inner().unwrap();
+        assert_eq!(seen, (0..EVENTS as u64).collect::<Vec<_>>());
+        assert_eq!(consumer.cursor(), Cursor { index: 0, cycle: false });
+    }
+}
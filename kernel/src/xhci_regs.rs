@@ -0,0 +1,360 @@
+//! Typed views of xHCI register and TRB bitfields (xHCI 1.2, chapters 5 and 6).
+//!
+//! Each type wraps the raw value; getters decode fields and `with_*`
+//! builders encode them, so driver code never shifts magic constants.
+
+/// PORTSC (5.4.8).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Portsc(pub u32);
+
+impl Portsc {
+    const CCS: u32 = 1 << 0;
+    const PED: u32 = 1 << 1;
+    const PR: u32 = 1 << 4;
+    const PLS_SHIFT: u32 = 5;
+    const PP: u32 = 1 << 9;
+    const SPEED_SHIFT: u32 = 10;
+    /// Port indicator control (RW) and wake-on enables (RWS).
+    const PIC_MASK: u32 = 0x3 << 14;
+    const WAKE_MASK: u32 = 0x7 << 25;
+    /// Change bits CSC..CEC (17..=23), all RW1C.
+    const CHANGE_MASK: u32 = 0x7F << 17;
+    pub const PRC: u32 = 1 << 21;
+
+    pub fn connected(self) -> bool {
+        self.0 & Self::CCS != 0
+    }
+
+    pub fn enabled(self) -> bool {
+        self.0 & Self::PED != 0
+    }
+
+    pub fn in_reset(self) -> bool {
+        self.0 & Self::PR != 0
+    }
+
+    pub fn powered(self) -> bool {
+        self.0 & Self::PP != 0
+    }
+
+    pub fn link_state(self) -> u8 {
+        ((self.0 >> Self::PLS_SHIFT) & 0xF) as u8
+    }
+
+    pub fn speed(self) -> u8 {
+        ((self.0 >> Self::SPEED_SHIFT) & 0xF) as u8
+    }
+
+    pub fn changes(self) -> u32 {
+        self.0 & Self::CHANGE_MASK
+    }
+
+    /// Value safe to write back: only the plain RW bits are kept. PED and
+    /// the change bits are RW1C, so echoing a read would disable the port or
+    /// ack pending changes; PR and WPR are RW1S, PLS only takes effect with
+    /// LWS, and the rest is read-only or reserved.
+    pub fn for_write(self) -> Self {
+        Portsc(self.0 & (Self::PP | Self::PIC_MASK | Self::WAKE_MASK))
+    }
+
+    pub fn with_reset(self) -> Self {
+        Portsc(self.0 | Self::PR)
+    }
+
+    /// Acknowledge the given change bits (as returned by `changes`).
+    pub fn with_ack(self, changes: u32) -> Self {
+        Portsc(self.0 | (changes & Self::CHANGE_MASK))
+    }
+}
+
+/// IMAN (5.5.2.1).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Iman(pub u32);
+
+impl Iman {
+    const IP: u32 = 1 << 0;
+    const IE: u32 = 1 << 1;
+
+    pub fn pending(self) -> bool {
+        self.0 & Self::IP != 0
+    }
+
+    pub fn enabled(self) -> bool {
+        self.0 & Self::IE != 0
+    }
+
+    /// Set IE without acknowledging a pending interrupt (IP is RW1C).
+    pub fn with_enable(self, on: bool) -> Self {
+        let base = self.0 & !Self::IP;
+        Iman(if on { base | Self::IE } else { base & !Self::IE })
+    }
+
+    pub fn with_ack(self) -> Self {
+        Iman(self.0 | Self::IP)
+    }
+}
+
+/// CRCR (5.4.5).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Crcr(pub u64);
+
+impl Crcr {
+    const RCS: u64 = 1 << 0;
+    const CRR: u64 = 1 << 3;
+    const PTR_MASK: u64 = !0x3F;
+
+    /// Command ring pointer (64-byte aligned) and initial consumer cycle state.
+    /// The pointer field always reads back as zero, so only CRR is decoded.
+    pub fn new(ring: u64, cycle: bool) -> Self {
+        Crcr((ring & Self::PTR_MASK) | if cycle { Self::RCS } else { 0 })
+    }
+
+    pub fn running(self) -> bool {
+        self.0 & Self::CRR != 0
+    }
+}
+
+/// TRB control dword (6.4), shared by transfer, command, event and link TRBs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TrbControl(pub u32);
+
+impl TrbControl {
+    const CYCLE: u32 = 1 << 0;
+    /// Toggle Cycle on link TRBs, Evaluate Next TRB elsewhere.
+    const TOGGLE: u32 = 1 << 1;
+    const CHAIN: u32 = 1 << 4;
+    const IOC: u32 = 1 << 5;
+    const IDT: u32 = 1 << 6;
+    const TYPE_SHIFT: u32 = 10;
+    const DIR_IN: u32 = 1 << 16;
+    const EP_SHIFT: u32 = 16;
+    const SLOT_SHIFT: u32 = 24;
+
+    pub fn new(trb_type: u8) -> Self {
+        TrbControl(((trb_type as u32) & 0x3F) << Self::TYPE_SHIFT)
+    }
+
+    pub fn trb_type(self) -> u8 {
+        ((self.0 >> Self::TYPE_SHIFT) & 0x3F) as u8
+    }
+
+    pub fn cycle(self) -> bool {
+        self.0 & Self::CYCLE != 0
+    }
+
+    /// Endpoint ID (DCI) of a transfer event.
+    pub fn endpoint_id(self) -> u8 {
+        ((self.0 >> Self::EP_SHIFT) & 0x1F) as u8
+    }
+
+    pub fn slot_id(self) -> u8 {
+        (self.0 >> Self::SLOT_SHIFT) as u8
+    }
+
+    pub fn with_cycle(self, cycle: bool) -> Self {
+        TrbControl(if cycle { self.0 | Self::CYCLE } else { self.0 & !Self::CYCLE })
+    }
+
+    pub fn with_toggle_cycle(self) -> Self {
+        TrbControl(self.0 | Self::TOGGLE)
+    }
+
+    /// The next TRB belongs to the same TD.
+    pub fn with_chain(self) -> Self {
+        TrbControl(self.0 | Self::CHAIN)
+    }
+
+    pub fn with_ioc(self) -> Self {
+        TrbControl(self.0 | Self::IOC)
+    }
+
+    pub fn with_immediate_data(self) -> Self {
+        TrbControl(self.0 | Self::IDT)
+    }
+
+    /// Data/status stage direction: device-to-host.
+    pub fn with_dir_in(self) -> Self {
+        TrbControl(self.0 | Self::DIR_IN)
+    }
+
+    /// Setup stage Transfer Type (0 = no data, 2 = OUT, 3 = IN).
+    pub fn with_transfer_type(self, trt: u8) -> Self {
+        TrbControl((self.0 & !(0x3 << 16)) | (((trt & 0x3) as u32) << 16))
+    }
+
+    /// Endpoint ID (DCI) targeted by an endpoint command.
+    pub fn with_endpoint_id(self, ep: u8) -> Self {
+        TrbControl((self.0 & !(0x1F << Self::EP_SHIFT)) | (((ep & 0x1F) as u32) << Self::EP_SHIFT))
+    }
+
+    pub fn with_slot_id(self, slot: u8) -> Self {
+        TrbControl((self.0 & 0x00FF_FFFF) | ((slot as u32) << Self::SLOT_SHIFT))
+    }
+}
+
+/// Transfer TRB status dword (6.4.1.1): buffer length and TD Size, the
+/// number of packets the TD still has to move after this TRB.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TransferStatus(pub u32);
+
+impl TransferStatus {
+    const LEN_MASK: u32 = 0x1_FFFF;
+    const TD_SIZE_SHIFT: u32 = 17;
+    pub const TD_SIZE_MAX: u32 = 31;
+
+    pub fn new(length: u32, td_size: u32) -> Self {
+        TransferStatus((length & Self::LEN_MASK) | (td_size.min(Self::TD_SIZE_MAX) << Self::TD_SIZE_SHIFT))
+    }
+}
+
+/// Event TRB status dword: completion code and residual/transfer length.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventStatus(pub u32);
+
+impl EventStatus {
+    pub fn completion_code(self) -> u8 {
+        (self.0 >> 24) as u8
+    }
+
+    pub fn transfer_length(self) -> u32 {
+        self.0 & 0x00FF_FFFF
+    }
+}
+
+/// Extended capability header (7.0): ID and the offset of the next one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ExtCap(pub u32);
+
+impl ExtCap {
+    pub const USB_LEGACY_SUPPORT: u8 = 1;
+
+    pub fn id(self) -> u8 {
+        self.0 as u8
+    }
+
+    /// Offset of the next capability in bytes, 0 at the end of the list.
+    pub fn next_offset(self) -> usize {
+        ((self.0 >> 8) & 0xFF) as usize * 4
+    }
+}
+
+/// USBLEGSUP (7.1.1): the BIOS/OS ownership semaphores.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsbLegSup(pub u32);
+
+impl UsbLegSup {
+    const BIOS_OWNED: u32 = 1 << 16;
+    const OS_OWNED: u32 = 1 << 24;
+    /// Byte offsets of the two semaphores; each lives alone in its byte so
+    /// a byte write touches nothing else.
+    pub const BIOS_OWNED_BYTE: usize = 2;
+    pub const OS_OWNED_BYTE: usize = 3;
+
+    pub fn bios_owned(self) -> bool {
+        self.0 & Self::BIOS_OWNED != 0
+    }
+
+    pub fn os_owned(self) -> bool {
+        self.0 & Self::OS_OWNED != 0
+    }
+}
+
+/// USBLEGCTLSTS (7.1.2), right after USBLEGSUP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsbLegCtlSts(pub u32);
+
+impl UsbLegCtlSts {
+    /// SMI enables: USB SMI, host system error, OS ownership change, PCI
+    /// command and BAR writes.
+    const SMI_ENABLES: u32 = (1 << 0) | (1 << 4) | (1 << 13) | (1 << 14) | (1 << 15);
+    /// SMI status on ownership change, PCI command and BAR (RW1C).
+    const SMI_EVENTS: u32 = 0x7 << 29;
+
+    /// All SMIs disabled and the pending events acknowledged.
+    pub fn without_smis(self) -> Self {
+        UsbLegCtlSts((self.0 & !Self::SMI_ENABLES) | Self::SMI_EVENTS)
+    }
+
+    pub fn smi_enables(self) -> u32 {
+        self.0 & Self::SMI_ENABLES
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn legacy_support_fields() {
+        let cap = ExtCap(0x0101_0401);
+        assert_eq!(cap.id(), ExtCap::USB_LEGACY_SUPPORT);
+        assert_eq!(cap.next_offset(), 16);
+        assert!(UsbLegSup(cap.0).bios_owned());
+        assert!(UsbLegSup(cap.0).os_owned());
+        let ctl = UsbLegCtlSts(0xE000_E011).without_smis();
+        assert_eq!(ctl.smi_enables(), 0);
+        assert_eq!(ctl.0, 0xE000_0000);
+    }
+
+    #[test]
+    fn portsc_decode_and_safe_write() {
+        // connected, enabled, powered, U0, high speed, CSC + PRC pending
+        let sc = Portsc(0x1 | 0x2 | (1 << 9) | (3 << 10) | (1 << 17) | (1 << 21));
+        assert!(sc.connected() && sc.enabled() && sc.powered());
+        assert_eq!(sc.speed(), 3);
+        assert_eq!(sc.link_state(), 0);
+        let w = sc.for_write().with_reset();
+        assert!(!w.enabled());
+        assert_eq!(w.changes(), 0);
+        assert!(w.in_reset());
+        assert_eq!(sc.for_write().with_ack(sc.changes()).changes(), (1 << 17) | (1 << 21));
+        assert_eq!(sc.for_write().0, 1 << 9);
+        // PR and WPR read as set mid-reset must not be written back
+        assert_eq!(Portsc((1 << 4) | (1 << 31) | (1 << 26)).for_write().0, 1 << 26);
+    }
+
+    #[test]
+    fn iman_enable_does_not_ack() {
+        let v = Iman(0x1).with_enable(true);
+        assert!(v.enabled());
+        assert!(!v.pending());
+        assert!(Iman(0).with_ack().pending());
+    }
+
+    #[test]
+    fn crcr_encodes_pointer_and_cycle() {
+        let c = Crcr::new(0x1234_5640, true);
+        assert_eq!(c.0, 0x1234_5641);
+        assert!(!c.running());
+    }
+
+    #[test]
+    fn trb_control_round_trips() {
+        let c = TrbControl::new(11).with_ioc().with_cycle(true).with_slot_id(5);
+        assert_eq!(c.trb_type(), 11);
+        assert_eq!(c.slot_id(), 5);
+        assert!(c.cycle());
+        assert_eq!(c.0, (11 << 10) | (1 << 5) | 1 | (5 << 24));
+        assert!(!c.with_cycle(false).cycle());
+        let setup = TrbControl::new(2).with_immediate_data().with_transfer_type(3);
+        assert_eq!(setup.0, (2 << 10) | (1 << 6) | (3 << 16));
+        let ev = TrbControl((0x20 << 10) | (3 << 16) | 1);
+        assert_eq!(ev.endpoint_id(), 3);
+        let stop = TrbControl::new(15).with_slot_id(1).with_endpoint_id(3);
+        assert_eq!((stop.slot_id(), stop.endpoint_id()), (1, 3));
+    }
+
+    #[test]
+    fn transfer_status_packs_td_size() {
+        assert_eq!(TransferStatus::new(64, 2).0, 64 | (2 << 17));
+        assert_eq!(TransferStatus::new(0x1_0000, 40).0, 0x1_0000 | (31 << 17));
+        assert_eq!(TrbControl::new(1).with_chain().0, (1 << 10) | (1 << 4));
+    }
+
+    #[test]
+    fn event_status_fields() {
+        let s = EventStatus((1 << 24) | 8);
+        assert_eq!(s.completion_code(), 1);
+        assert_eq!(s.transfer_length(), 8);
+    }
+}
@@ -0,0 +1,185 @@
+//! XMODEM sender, for pulling files and logs off the target over the serial
+//! line with any terminal program (`rx`, `sz -X`, minicom, ...).
+//!
+//! 128-byte blocks padded with SUB. The receiver picks the check: `C` asks
+//! for CRC-16, NAK for the original 8-bit sum. Each block is retried until
+//! ACKed, up to `MAX_RETRIES`; a failed transfer ends with CAN CAN so the
+//! receiver does not wait for its own timeout.
+
+const SOH: u8 = 0x01;
+const EOT: u8 = 0x04;
+const ACK: u8 = 0x06;
+const NAK: u8 = 0x15;
+const CAN: u8 = 0x18;
+const SUB: u8 = 0x1A;
+const CRC_START: u8 = b'C';
+
+pub const BLOCK_LEN: usize = 128;
+const MAX_RETRIES: u32 = 10;
+/// How long the receiver has to get started, in 1 s polls.
+const START_POLLS: u32 = 60;
+const POLL_MS: u64 = 1000;
+const REPLY_MS: u64 = 10_000;
+
+/// The byte pipe a transfer runs over.
+pub trait Link {
+    fn send(&mut self, bytes: &[u8]);
+    /// Next received byte, or `None` after `timeout_ms` of silence.
+    fn recv(&mut self, timeout_ms: u64) -> Option<u8>;
+}
+
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Error {
+    /// No receiver showed up.
+    NoReceiver,
+    Cancelled,
+    TooManyRetries,
+}
+
+impl Error {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Error::NoReceiver => "no receiver",
+            Error::Cancelled => "cancelled by receiver",
+            Error::TooManyRetries => "too many retries",
+        }
+    }
+}
+
+/// CRC-16/XMODEM (polynomial 0x1021, initial value 0).
+pub fn crc16(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |mut crc, &b| {
+        crc ^= (b as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+/// Header, padded payload and check of block `number` (1-based, wraps at 256).
+fn packet(number: u8, chunk: &[u8], crc: bool, out: &mut [u8; BLOCK_LEN + 5]) -> usize {
+    out[0] = SOH;
+    out[1] = number;
+    out[2] = !number;
+    let payload = &mut out[3..3 + BLOCK_LEN];
+    payload[..chunk.len()].copy_from_slice(chunk);
+    payload[chunk.len()..].fill(SUB);
+    if crc {
+        let sum = crc16(&out[3..3 + BLOCK_LEN]);
+        out[3 + BLOCK_LEN..].copy_from_slice(&sum.to_be_bytes());
+        BLOCK_LEN + 5
+    } else {
+        out[3 + BLOCK_LEN] = out[3..3 + BLOCK_LEN].iter().fold(0u8, |s, &b| s.wrapping_add(b));
+        BLOCK_LEN + 4
+    }
+}
+
+/// Wait for the receiver's start byte; returns whether it wants CRC-16.
+fn start(link: &mut impl Link) -> Result<bool, Error> {
+    for _ in 0..START_POLLS {
+        match link.recv(POLL_MS) {
+            Some(CRC_START) => return Ok(true),
+            Some(NAK) => return Ok(false),
+            Some(CAN) => return Err(Error::Cancelled),
+            // Line noise or a keystroke left over from typing the command
+            _ => {}
+        }
+    }
+    Err(Error::NoReceiver)
+}
+
+/// Send `frame` until the receiver ACKs it.
+fn deliver(link: &mut impl Link, frame: &[u8]) -> Result<(), Error> {
+    for _ in 0..MAX_RETRIES {
+        link.send(frame);
+        match link.recv(REPLY_MS) {
+            Some(ACK) => return Ok(()),
+            Some(CAN) => return Err(Error::Cancelled),
+            // NAK, a repeated start byte, garbage or silence: send it again
+            _ => {}
+        }
+    }
+    Err(Error::TooManyRetries)
+}
+
+/// Send `data` as one file; returns the number of blocks sent.
+pub fn send(link: &mut impl Link, data: &[u8]) -> Result<usize, Error> {
+    let result = start(link).and_then(|crc| {
+        let mut frame = [0u8; BLOCK_LEN + 5];
+        for (i, chunk) in data.chunks(BLOCK_LEN).enumerate() {
+            let len = packet((i + 1) as u8, chunk, crc, &mut frame);
+            deliver(link, &frame[..len])?;
+        }
+        deliver(link, &[EOT])?;
+        Ok(data.len().div_ceil(BLOCK_LEN))
+    });
+    if matches!(result, Err(Error::TooManyRetries)) {
+        link.send(&[CAN, CAN]);
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::vec::Vec;
+
+    /// A receiver that replies from a script and records what it was sent.
+    struct Scripted {
+        replies: VecDeque<Option<u8>>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    impl Link for Scripted {
+        fn send(&mut self, bytes: &[u8]) {
+            self.sent.push(bytes.to_vec());
+        }
+
+        fn recv(&mut self, _timeout_ms: u64) -> Option<u8> {
+            self.replies.pop_front().flatten()
+        }
+    }
+
+    fn scripted(replies: &[Option<u8>]) -> Scripted {
+        Scripted { replies: replies.iter().copied().collect(), sent: Vec::new() }
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        assert_eq!(crc16(b"123456789"), 0x31C3);
+    }
+
+    #[test]
+    fn crc_transfer_with_a_retry() {
+        let data: Vec<u8> = (0..130u8).collect();
+        // Noise, then 'C'; block 1 NAKed once, silence on block 2 once
+        let mut link = scripted(&[Some(b'x'), Some(b'C'), Some(NAK), Some(ACK), None, Some(ACK), Some(ACK)]);
+        assert_eq!(send(&mut link, &data), Ok(2));
+        assert_eq!(link.sent.len(), 5);
+        assert_eq!(link.sent[0], link.sent[1]);
+        let b2 = &link.sent[2];
+        assert_eq!(&b2[..3], &[SOH, 2, 0xFD]);
+        assert_eq!(&b2[3..5], &[128, 129]);
+        assert!(b2[5..3 + BLOCK_LEN].iter().all(|&b| b == SUB));
+        assert_eq!(u16::from_be_bytes([b2[131], b2[132]]), crc16(&b2[3..131]));
+        assert_eq!(link.sent[4], [EOT]);
+    }
+
+    #[test]
+    fn checksum_mode_and_failures() {
+        let mut link = scripted(&[Some(NAK), Some(ACK), Some(ACK)]);
+        assert_eq!(send(&mut link, b"hi"), Ok(1));
+        let block = &link.sent[0];
+        assert_eq!(block.len(), BLOCK_LEN + 4);
+        assert_eq!(block[131], (b'h' as u32 + b'i' as u32 + 126 * SUB as u32) as u8);
+
+        assert_eq!(send(&mut scripted(&[]), b"hi"), Err(Error::NoReceiver));
+        assert_eq!(send(&mut scripted(&[Some(b'C'), Some(CAN)]), b"hi"), Err(Error::Cancelled));
+        let mut deaf = scripted(&[Some(b'C')]);
+        assert_eq!(send(&mut deaf, b"hi"), Err(Error::TooManyRetries));
+        assert_eq!(deaf.sent.len(), MAX_RETRIES as usize + 1);
+        assert_eq!(deaf.sent.last().unwrap(), &[CAN, CAN]);
+    }
+}
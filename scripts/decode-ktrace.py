@@ -0,0 +1,106 @@
+#!/usr/bin/env python3
+"""
+Decode a ktrace dump (`trace dump <file>` in the shell, fetched with `sx`).
+
+Layout (LE):
+  header, 16 bytes:
+    [0x00..0x03] magic b"KTR1"
+    [0x04..0x0B] tsc_per_ms (u64), 0 if the TSC was never calibrated
+    [0x0C..0x0F] count      (u32)
+  then count events, 16 bytes each, oldest first:
+    [0x00..0x07] tsc  (u64, raw cycles)
+    [0x08..0x09] kind (u16)
+    [0x0A..0x0B] a    (u16)
+    [0x0C..0x0F] b    (u32)
+
+Kinds and their arguments (a, b):
+  1 irq-enter      line, -
+  2 irq-exit       line, -
+  3 task           task slot, -
+  4 xhci-submit    doorbell slot, doorbell target
+  5 xhci-complete  completion code, 0 (command) or endpoint id (transfer)
+  6 ai-start       -, -
+  7 ai-end         outcome (0 accepted, 0xff nothing proposed), -
+  8 oom            -, KiB asked
+  9 sys-enter      syscall number, pid      (processes under `strace`)
+ 10 sys-arg        argument index (+0x100: upper half, sent only if not zero), 32-bit half
+ 11 sys-exit       syscall number, return value (i32)
+
+With --strace only syscalls are printed, one line each, with the
+arguments put back together: `pid 3: 3(1, 0x10000, 12) = 12`.
+"""
+import argparse, struct, sys
+
+KINDS = {
+    1: "irq-enter",
+    2: "irq-exit",
+    3: "task",
+    4: "xhci-submit",
+    5: "xhci-complete",
+    6: "ai-start",
+    7: "ai-end",
+    8: "oom",
+    9: "sys-enter",
+    10: "sys-arg",
+    11: "sys-exit",
+}
+
+ARG_HIGH = 0x100
+
+def decode(data:bytes):
+    if len(data) < 16 or data[:4] != b"KTR1":
+        raise SystemExit("not a ktrace dump (bad magic)")
+    tsc_per_ms, count = struct.unpack_from("<QI", data, 4)
+    events = []
+    for i in range(count):
+        off = 16 + i * 16
+        if off + 16 > len(data):
+            print(f"warning: dump cut short after {i} of {count} events", file=sys.stderr)
+            break
+        events.append(struct.unpack_from("<QHHI", data, off))
+    return tsc_per_ms, events
+
+def main():
+    ap = argparse.ArgumentParser()
+    ap.add_argument("dump", type=str)
+    ap.add_argument("--cycles", action="store_true", help="print raw TSC deltas instead of microseconds")
+    ap.add_argument("--strace", action="store_true", help="print reassembled syscalls only")
+    args = ap.parse_args()
+    with open(args.dump, "rb") as f:
+        tsc_per_ms, events = decode(f.read())
+    if not events:
+        return
+    if args.strace:
+        strace(events)
+        return
+    t0 = events[0][0]
+    use_us = tsc_per_ms > 0 and not args.cycles
+    prev = t0
+    for tsc, kind, a, b in events:
+        if use_us:
+            t, dt = (tsc - t0) * 1000 / tsc_per_ms, (tsc - prev) * 1000 / tsc_per_ms
+            stamp = f"{t:12.3f} +{dt:9.3f}"
+        else:
+            stamp = f"{tsc - t0:14d} +{tsc - prev:10d}"
+        print(f"{stamp} {KINDS.get(kind, f'kind{kind}'):<13} {a} {b}")
+        prev = tsc
+
+def strace(events):
+    call = None
+    for _, kind, a, b in events:
+        if kind == 9:
+            call = {"nr": a, "pid": b, "args": [0, 0, 0]}
+        elif kind == 10 and call is not None and (a & 0xFF) < 3:
+            i = a & 0xFF
+            call["args"][i] |= b << 32 if a & ARG_HIGH else b
+        elif kind == 11:
+            ret = b - (1 << 32) if b & 0x8000_0000 else b
+            if call is None or call["nr"] != a:
+                print(f"?: {a}(...) = {ret}")
+            else:
+                args = ", ".join(hex(v) if v > 0xFFFF else str(v) for v in call["args"])
+                print(f"pid {call['pid']}: {a}({args}) = {ret}")
+            call = None
+
+if __name__ == "__main__":
+    main()
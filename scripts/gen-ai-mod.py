@@ -8,9 +8,15 @@ Header layout (LE, 16 bytes):
   [0x06..0x07] hidden   (u16)
   [0x08..0x0B] vocab    (u32)
   [0x0C]      dtype    (u8) 0=int8
-  [0x0D..0x0F] reserved (3x u8) = 0
+  [0x0D]      version  (u8) 0 = no metadata, 1 = metadata block follows
+  [0x0E..0x0F] reserved (2x u8) = 0
 
-Weights (contiguous, row-major per layer):
+Metadata (version 1 only, 64 bytes):
+  [0x10..0x27] name (UTF-8, NUL padded, 24 bytes max)
+  [0x28..0x2F] major, minor, patch (u16 each), reserved (u16)
+  [0x30..0x4F] training-run hash (32 bytes, e.g. SHA-256)
+
+Weights (after the header, or the metadata from version 1; contiguous, row-major per layer):
   For each layer l in [0..n_layers-1]:
     in_dim  = hidden
     out_dim = hidden for l < n_layers-1, else (vocab if >0 else hidden)
@@ -18,7 +24,20 @@ Weights (contiguous, row-major per layer):
 """
 import argparse, os, struct, random
 
-def gen_ai_mod(layers:int, hidden:int, vocab:int, dtype:str, out_path:str, seed:int|None):
+def metadata(name:str, version:str, train_hash:str) -> bytes:
+    raw = name.encode()
+    if len(raw) > 24:
+        raise SystemExit("--name: 24 bytes at most")
+    try:
+        major, minor, patch = (int(v) for v in version.split("."))
+        digest = bytes.fromhex(train_hash)
+    except ValueError:
+        raise SystemExit("--version is MAJOR.MINOR.PATCH, --train-hash hex")
+    if len(digest) > 32:
+        raise SystemExit("--train-hash: 32 bytes at most")
+    return raw.ljust(24, b"\0") + struct.pack("<HHHH", major, minor, patch, 0) + digest.ljust(32, b"\0")
+
+def gen_ai_mod(layers:int, hidden:int, vocab:int, dtype:str, out_path:str, seed:int|None, meta:bytes|None=None):
     if dtype.lower() not in ("int8",):
         raise SystemExit("Only int8 supported in this generator")
     if layers < 1 or hidden < 1:
@@ -33,7 +52,10 @@ def gen_ai_mod(layers:int, hidden:int, vocab:int, dtype:str, out_path:str, seed:
         f.write(struct.pack("<H", hidden))
         f.write(struct.pack("<I", vocab))
         f.write(struct.pack("<B", 0))  # dtype=0 (int8)
-        f.write(b"\x00\x00\x00")      # reserved
+        f.write(struct.pack("<B", 1 if meta else 0))  # header version
+        f.write(b"\x00\x00")          # reserved
+        if meta:
+            f.write(meta)
 
         # weights
         for l in range(layers):
@@ -54,9 +76,13 @@ def main():
     ap.add_argument("--dtype", type=str, default="int8")
     ap.add_argument("--out", type=str, default="ai.mod")
     ap.add_argument("--seed", type=int, default=None)
+    ap.add_argument("--name", type=str, default=None, help="model name; writes a version 1 header")
+    ap.add_argument("--version", type=str, default="0.0.0", help="MAJOR.MINOR.PATCH (with --name)")
+    ap.add_argument("--train-hash", type=str, default="", help="training-run hash in hex (with --name)")
     args = ap.parse_args()
+    meta = metadata(args.name, args.version, args.train_hash) if args.name is not None else None
     os.makedirs(os.path.dirname(args.out) or ".", exist_ok=True)
-    gen_ai_mod(args.layers, args.hidden, args.vocab, args.dtype, args.out, args.seed)
+    gen_ai_mod(args.layers, args.hidden, args.vocab, args.dtype, args.out, args.seed, meta)
     print(f"Wrote {args.out}")
 
 if __name__ == "__main__":
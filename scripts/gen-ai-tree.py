@@ -0,0 +1,103 @@
+#!/usr/bin/env python3
+"""
+Convert a decision forest written as JSON into an AIDT model file (ai.mod),
+or print an AIDT file back as readable rules (--dump).
+
+Input: {"trees": [tree, ...]} where a tree is either a leaf {"leaf": <score>}
+or a split {"if": "<feature> <= <threshold>", "then": tree, "else": tree}.
+Features: runq, irq_rate, free_kb, pf_rate, input_latency_us, irq_errors.
+The kernel sums the leaves the trees reach and turns the sum into an action
+like the MLP's score (see kernel/crates/ai-core/src/tree.rs).
+
+File layout (LE):
+  [0x00..0x03] magic b"AIDT"
+  [0x04..0x05] n_nodes (u16), all trees together
+  [0x06]       n_trees (u8)
+  [0x07]       version (u8) = 1
+  [0x08..0x0F] reserved = 0
+  roots: u16 per tree, index of its first node
+  nodes: 12 bytes each: feature u8 (0xFF = leaf), 0, left u16, right u16,
+         0 u16, value i32 (threshold of a split, score of a leaf)
+Children are written after their parent, as the kernel requires.
+"""
+import argparse, json, struct, sys
+
+FEATURES = ["runq", "irq_rate", "free_kb", "pf_rate", "input_latency_us", "irq_errors"]
+LEAF = 0xFF
+VERSION = 1
+MAX_TREES, MAX_NODES, MAX_DEPTH = 16, 512, 16
+
+def flatten(tree, nodes, depth=0):
+    """Append `tree` to `nodes` in pre-order; returns its index."""
+    if depth >= MAX_DEPTH:
+        raise SystemExit(f"tree deeper than {MAX_DEPTH}")
+    index = len(nodes)
+    if "leaf" in tree:
+        nodes.append((LEAF, 0, 0, int(tree["leaf"])))
+        return index
+    try:
+        feature, op, threshold = tree["if"].split()
+    except (KeyError, ValueError):
+        raise SystemExit(f"split needs \"if\": \"<feature> <= <n>\": {tree}")
+    if op != "<=" or feature not in FEATURES:
+        raise SystemExit(f"bad split {tree['if']!r} (features: {', '.join(FEATURES)})")
+    nodes.append(None)
+    left = flatten(tree["then"], nodes, depth + 1)
+    right = flatten(tree["else"], nodes, depth + 1)
+    nodes[index] = (FEATURES.index(feature), left, right, int(threshold))
+    return index
+
+def encode(forest):
+    trees = forest["trees"]
+    if not 1 <= len(trees) <= MAX_TREES:
+        raise SystemExit(f"need 1 to {MAX_TREES} trees")
+    nodes, roots = [], []
+    for tree in trees:
+        roots.append(flatten(tree, nodes))
+    if len(nodes) > MAX_NODES:
+        raise SystemExit(f"{len(nodes)} nodes, at most {MAX_NODES}")
+    out = b"AIDT" + struct.pack("<HBB", len(nodes), len(roots), VERSION) + bytes(8)
+    out += b"".join(struct.pack("<H", r) for r in roots)
+    for feature, left, right, value in nodes:
+        out += struct.pack("<BBHHHi", feature, 0, left, right, 0, value)
+    return out
+
+def dump(data):
+    if data[:4] != b"AIDT":
+        raise SystemExit("not an AIDT file")
+    n_nodes, n_trees, version = struct.unpack_from("<HBB", data, 4)
+    roots = struct.unpack_from(f"<{n_trees}H", data, 16)
+    base = 16 + 2 * n_trees
+    node = lambda i: struct.unpack_from("<BBHHHi", data, base + 12 * i)
+    def walk(i, indent):
+        feature, _, left, right, _, value = node(i)
+        if feature == LEAF:
+            print(f"{indent}score {value}")
+            return
+        print(f"{indent}if {FEATURES[feature]} <= {value}:")
+        walk(left, indent + "    ")
+        print(f"{indent}else:")
+        walk(right, indent + "    ")
+    print(f"# AIDT v{version}, {n_trees} tree(s), {n_nodes} nodes")
+    for t, root in enumerate(roots):
+        print(f"tree {t}:")
+        walk(root, "    ")
+
+def main():
+    ap = argparse.ArgumentParser()
+    ap.add_argument("input", help="forest JSON, or an AIDT file with --dump")
+    ap.add_argument("--out", type=str, default="ai.mod")
+    ap.add_argument("--dump", action="store_true", help="print an AIDT file as rules")
+    args = ap.parse_args()
+    if args.dump:
+        with open(args.input, "rb") as f:
+            dump(f.read())
+        return
+    with open(args.input) as f:
+        data = encode(json.load(f))
+    with open(args.out, "wb") as f:
+        f.write(data)
+    print(f"Wrote {args.out} ({len(data)} bytes)")
+
+if __name__ == "__main__":
+    main()
@@ -20,6 +20,11 @@ ORG STAGE2_LOAD_ADDR
 %define E820_BUFFER_PTR         0x00000510
 %define E820_ENTRY_SIZE         24
 %define E820_MAX_ENTRIES        64
+; BootInfo v2 header (see kernel/src/bootinfo.rs)
+%define BOOTINFO_MAGIC          0x4F464E49544F4F42 ; "BOOTINFO"
+%define BOOTINFO_VERSION        2
+%define BOOTINFO_FLAG_INITRD    (1 << 0)
+%define BOOTINFO_FLAG_RSDP      (1 << 2)
 
 section .text
 
@@ -33,6 +38,7 @@ stage2_start:
     call setup_gdt
     call build_page_tables
     call load_memory_map
+    call find_rsdp
 
     mov eax, cr4
     or eax, (1 << 5) | (1 << 7) | (1 << 9) | (1 << 10)
@@ -110,6 +116,7 @@ load_initrd:
     mov edx, [esi+8]
     mov [boot_info_initrd_len], eax
     mov [boot_info_initrd_len+4], edx
+    or dword [boot_info_flags], BOOTINFO_FLAG_INITRD
 
 .done:
     popa
@@ -439,6 +446,26 @@ load_memory_map:
     popad
     ret
 
+; --- Find the ACPI RSDP: "RSD PTR " on a 16-byte boundary in the BIOS area ---
+find_rsdp:
+    pushad
+    mov esi, 0x000E0000
+.next:
+    cmp dword [esi], 0x20445352     ; 'RSD '
+    jne .skip
+    cmp dword [esi+4], 0x20525450   ; 'PTR '
+    jne .skip
+    mov [boot_info_rsdp], esi
+    or dword [boot_info_flags], BOOTINFO_FLAG_RSDP
+    jmp .done
+.skip:
+    add esi, 16
+    cmp esi, 0x00100000
+    jb .next
+.done:
+    popad
+    ret
+
 load_fail_high:
     mov esi, msg_fail_high
     call debug_write
@@ -724,6 +751,11 @@ ph_remaining:              dw 0
 
 align 8
 boot_info:
+    dq BOOTINFO_MAGIC
+    dd BOOTINFO_VERSION
+    dd boot_info_end - boot_info
+boot_info_flags:
+    dq 0
     dq boot_memory_map
 boot_info_entry_count:
     dq 0
@@ -733,6 +765,14 @@ boot_info_initrd_base:
     dq 0
 boot_info_initrd_len:
     dq 0
+boot_info_cmdline:
+    dq 0, 0                     ; pointer, length: no command line yet
+boot_info_rsdp:
+    dq 0
+boot_info_framebuffer:
+    dq 0                        ; VGA text mode, no framebuffer
+    dd 0, 0, 0, 0               ; width, height, pitch, bpp
+boot_info_end:
 
 align 8
 boot_memory_map: